@@ -1,7 +1,14 @@
+// a one-off repair kept as it was written, so it isn't held to clippy
+#![allow(
+    clippy::empty_line_after_doc_comments,
+    clippy::needless_borrow,
+    clippy::needless_borrows_for_generic_args,
+    clippy::ptr_arg
+)]
+
 use anyhow::*;
 use clap::Parser;
 use std::fs::File;
-use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::path::Path;
 
@@ -140,46 +147,51 @@ fn fix_block2(csum_type: BtrfsCsumType, data: &mut Vec<u8>) {
    checks that the data at the locations matches the data we think is there.
 */
 fn check_physical_locations_match(
-    physical_locations: &Vec<(u64, &Path)>,
-    corrupt_data: &Vec<u8>,
+    fs: &FsInfo,
+    physical_locations: &Vec<(u64, u64)>,
+    corrupt_data: &[u8],
 ) -> anyhow::Result<()> {
-    for (offset, path) in physical_locations {
-        println!("checking {}...", path.display());
-        let mut file = File::open(path)?;
-
-        let mut phys_data = vec![0_u8; corrupt_data.len()];
-
-        println!("seeking to {offset}...");
-        file.seek(std::io::SeekFrom::Start(*offset))?;
-        println!("reading...");
-        file.read(&mut phys_data)?;
+    for (devid, physical) in physical_locations {
+        let dev = &fs.devid_map[devid];
+        println!("checking {} at {physical}...", dev.path.display());
+        let phys_data = dev.read_at(*physical, corrupt_data.len())?;
 
-        assert_eq!(*corrupt_data, phys_data);
+        assert_eq!(corrupt_data, phys_data);
     }
     println!("physical data as expected. ✔️");
     Ok(())
 }
 
 fn write_block_to_physical(
-    data: &Vec<u8>,
-    physical_locations: &Vec<(u64, &Path)>,
+    fs: &FsInfo,
+    data: &[u8],
+    physical_locations: &Vec<(u64, u64)>,
 ) -> anyhow::Result<()> {
-    for (offset, path) in physical_locations {
-        let mut file = OpenOptions::new()
-            .read(false)
-            .write(true)
-            .create(false)
-            .truncate(false)
-            .open(path)?;
-
-        file.seek(std::io::SeekFrom::Start(*offset))?;
-        file.write_all(data)?;
-        println!("block written to {}", path.display());
+    for (devid, physical) in physical_locations {
+        let dev = &fs.devid_map[devid];
+        dev.write_at(fs, *physical, data)?;
+        println!("block written to {}", dev.path.display());
     }
     Ok(())
 }
 
-fn fix_issue_1( fs: &FsInfo) -> anyhow::Result<()> {
+/// the (devid, physical) of each copy of the block at logical on the
+/// devices present
+fn physical_locations(fs: &FsInfo, logical: u64) -> anyhow::Result<Vec<(u64, u64)>> {
+    let nodesize = fs.master_sb.nodesize as u64;
+    let locations: Vec<(u64, u64)> = block_copies(fs, logical, nodesize)?
+        .iter()
+        .filter(|copy| fs.devid_map.contains_key(&copy.devid))
+        .map(|copy| (copy.devid, copy.physical))
+        .collect();
+    ensure!(
+        !locations.is_empty(),
+        "no device containing stripe copy is present"
+    );
+    Ok(locations)
+}
+
+fn fix_issue_1(fs: &FsInfo) -> anyhow::Result<()> {
     /* scan the root tree for the extent tree root */
     let extent_tree_root = tree_root_offset(&fs, BTRFS_EXTENT_TREE_OBJECTID)
         .ok_or_else(|| anyhow!("couldn't find extent tree root"))?;
@@ -224,7 +236,7 @@ fn fix_issue_1( fs: &FsInfo) -> anyhow::Result<()> {
     //obtain a read-only slice of this block in memory
     let corrupt_block = load_virt_block(&fs, corrupt_offset)?;
     let mut corrupt_vec = Vec::new();
-    corrupt_vec.extend_from_slice(corrupt_block);
+    corrupt_vec.extend_from_slice(&corrupt_block);
     assert_eq!(corrupt_vec.len(), fs.master_sb.nodesize as usize);
 
     let backup_filename = format!("offset_{corrupt_offset}_backup.bin");
@@ -240,7 +252,7 @@ fn fix_issue_1( fs: &FsInfo) -> anyhow::Result<()> {
     println!("original block at virtual {corrupt_offset} saved at {backup_filename}. fixed 🤞 block saved at {fixed_filename}");
 
     //find devices/offsets that the block's virtual address maps to
-    let physical_locations: Vec<(u64, &Path)> = virtual_offset_to_physical(&fs, corrupt_offset)?;
+    let physical_locations = physical_locations(fs, corrupt_offset)?;
 
     println!(
         "found {} physical locs: {:?}",
@@ -249,11 +261,11 @@ fn fix_issue_1( fs: &FsInfo) -> anyhow::Result<()> {
     );
 
     //safety first
-    check_physical_locations_match(&physical_locations, &corrupt_vec)?;
+    check_physical_locations_match(fs, &physical_locations, &corrupt_vec)?;
     //write block back to all copies.
 
     // EXTREMELY IMPORTANT NOTE: Rather than applying the fix directly to the filesystem, do this frst with a qcow2 backed disc under KVM, so that the fix can be tested, and reverted if there's a problem
-    write_block_to_physical(&fixed_vec, &physical_locations)?;
+    write_block_to_physical(fs, &fixed_vec, &physical_locations)?;
 
     println!("correct block written to physical location(s)");
     Ok(())
@@ -304,7 +316,7 @@ fn fix_issue_2( fs: &FsInfo) -> anyhow::Result<()> {
     //obtain a read-only slice of this block in memory
     let corrupt_block = load_virt_block(&fs, corrupt_offset)?;
     let mut corrupt_vec = Vec::new();
-    corrupt_vec.extend_from_slice(corrupt_block);
+    corrupt_vec.extend_from_slice(&corrupt_block);
     assert_eq!(corrupt_vec.len(), fs.master_sb.nodesize as usize);
 
     let backup_filename = format!("offset_{corrupt_offset}_backup.bin");
@@ -320,7 +332,7 @@ fn fix_issue_2( fs: &FsInfo) -> anyhow::Result<()> {
     println!("original block at virtual {corrupt_offset} saved at {backup_filename}. fixed 🤞 block saved at {fixed_filename}");
 
     //find devices/offsets that the block's virtual address maps to
-    let physical_locations: Vec<(u64, &Path)> = virtual_offset_to_physical(&fs, corrupt_offset)?;
+    let physical_locations = physical_locations(fs, corrupt_offset)?;
 
     println!(
         "found {} physical locs: {:?}",
@@ -329,11 +341,11 @@ fn fix_issue_2( fs: &FsInfo) -> anyhow::Result<()> {
     );
 
     //safety first
-    check_physical_locations_match(&physical_locations, &corrupt_vec)?;
+    check_physical_locations_match(fs, &physical_locations, &corrupt_vec)?;
     //write block back to all copies.
 
     // EXTREMELY IMPORTANT NOTE: Rather than applying the fix directly to the filesystem, do this frst with a qcow2 backed disc under KVM, so that the fix can be tested, and reverted if there's a problem
-    write_block_to_physical(&fixed_vec, &physical_locations)?;
+    write_block_to_physical(fs, &fixed_vec, &physical_locations)?;
 
     println!("correct block written to physical location(s)");
     Ok(())
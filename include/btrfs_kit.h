@@ -0,0 +1,84 @@
+/*
+ * C interface to btrfs_kit, built with the capi feature, e.g.
+ *
+ *     cargo rustc --lib --release --features capi --crate-type cdylib
+ *
+ * for target/release/libbtrfs_kit.so (or --crate-type staticlib for
+ * libbtrfs_kit.a).
+ *
+ * Functions returning int return 0 (or a count) on success and -1 on error,
+ * and those returning a pointer NULL; btrfs_kit_last_error() then describes
+ * the error.
+ */
+
+#ifndef BTRFS_KIT_H
+#define BTRFS_KIT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct BtrfsKitFs btrfs_kit_fs;
+
+/* a key; item_type is the on-disc item type byte */
+typedef struct {
+	uint64_t objectid;
+	uint8_t item_type;
+	uint64_t offset;
+} btrfs_kit_key;
+
+/* one copy of a logical address on a device */
+typedef struct {
+	uint64_t devid;
+	uint64_t physical;
+} btrfs_kit_copy;
+
+/* called with each item; a nonzero return stops the iteration and is
+ * returned from btrfs_kit_iterate_tree. key and data are only valid during
+ * the call. */
+typedef int (*btrfs_kit_item_fn)(void *ctx, const btrfs_kit_key *key,
+				 const uint8_t *data, uint32_t len);
+
+/* the message of the last error on this thread, valid until the next call
+ * which fails */
+const char *btrfs_kit_last_error(void);
+
+/* open the filesystem on npaths devices or images */
+btrfs_kit_fs *btrfs_kit_open(const char *const *paths, size_t npaths);
+
+/* close a filesystem; NULL is ignored */
+void btrfs_kit_close(btrfs_kit_fs *fs);
+
+/* the size of a tree block, 0 if fs is NULL */
+uint32_t btrfs_kit_nodesize(const btrfs_kit_fs *fs);
+
+/* the logical address of the root block of a tree */
+int btrfs_kit_tree_root(const btrfs_kit_fs *fs, uint64_t tree_id,
+			uint64_t *root);
+
+/* call callback with each item of a tree whose key lies between min and max
+ * inclusive, in key order */
+int btrfs_kit_iterate_tree(const btrfs_kit_fs *fs, uint64_t tree_id,
+			   const btrfs_kit_key *min, const btrfs_kit_key *max,
+			   btrfs_kit_item_fn callback, void *ctx);
+
+/* store the first ncopies copies of a logical address in copies, returning
+ * how many there are, which may be more than ncopies. Striped chunks aren't
+ * supported. */
+int btrfs_kit_logical_to_physical(const btrfs_kit_fs *fs, uint64_t logical,
+				  btrfs_kit_copy *copies, size_t ncopies);
+
+/* copy the tree block at logical, nodesize bytes from the first device
+ * holding a copy, into buf, which holds len bytes. The block isn't
+ * verified. */
+int btrfs_kit_read_block(const btrfs_kit_fs *fs, uint64_t logical,
+			 uint8_t *buf, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
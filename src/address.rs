@@ -1,11 +1,17 @@
+use crate::block_cache::CachedBlock;
 use crate::btrfs::*;
+use crate::flags::fmt_block_group_type;
+use crate::mapped_file::Advice;
+use crate::raid56::{full_stripe, parity_strips, regenerate_strip, StripRole, RAID56_PROFILES};
+use crate::raid_stripe::{stripe_extent, uses_raid_stripe_tree};
 use crate::structures::*;
 use crate::tree::*;
 
 use anyhow::*;
-use log::debug;
+use log::{debug, warn};
 use more_asserts::*;
 use std::path::Path;
+use std::sync::Arc;
 
 struct ChunkStripeIter<'a> {
     index: usize,
@@ -14,7 +20,7 @@ struct ChunkStripeIter<'a> {
 }
 
 impl<'a> ChunkStripeIter<'a> {
-    pub fn new(buffer: &'a [u8], num_stripes: usize) -> ChunkStripeIter {
+    pub fn new(buffer: &'a [u8], num_stripes: usize) -> ChunkStripeIter<'a> {
         ChunkStripeIter {
             index: 0,
             total: num_stripes,
@@ -44,9 +50,9 @@ impl<'a> Iterator for ChunkStripeIter<'a> {
     }
 }
 
-/// returns reference to the structure of a specified type at a particular virtual address
+/// returns a copy of the structure of a specified type at a particular virtual address
 /// first check bootstrap chunks from superblock, if not found search chunk tree
-pub fn load_virt<T>(fs: &FsInfo, virt_offset: u64) -> Result<&T> {
+pub fn load_virt<T: Copy>(fs: &FsInfo, virt_offset: u64) -> Result<T> {
     let block_offset = virt_offset % fs.master_sb.nodesize as u64;
     let block_start = virt_offset - block_offset;
     assert_le!(
@@ -55,95 +61,571 @@ pub fn load_virt<T>(fs: &FsInfo, virt_offset: u64) -> Result<&T> {
     );
 
     let block = load_virt_block(fs, block_start)?;
-    Ok(unsafe { &*(block.as_ptr().add(block_offset as usize) as *const T) })
+    Ok(unsafe { std::ptr::read_unaligned(block.as_ptr().add(block_offset as usize) as *const T) })
 }
 
-pub fn load_virt_block(fs: &FsInfo, virt_offset: u64) -> Result<&[u8]> {
-    let node_length = fs.master_sb.nodesize as u64;
-    debug!("load_virt_block: {virt_offset} length {node_length}");
-    assert_eq!(virt_offset % node_length, 0);
+/// returns the chunk containing virt_offset.
+/// bootstrap chunks from the superblock are checked first, then chunks previously
+/// found in the chunk tree, and finally the chunk tree itself is searched (adding
+/// the result to the chunk cache). With a chunk map the cache is all there is.
+pub fn find_chunk(fs: &FsInfo, virt_offset: u64) -> Option<ChunkInfo> {
     for chunk in &fs.bootstrap_chunks {
         let start = chunk.0.offset;
         let length = chunk.1.length;
         if virt_offset >= start && virt_offset < start + length {
-            for stripe in &chunk.2 {
-                let devid = stripe.devid;
-                if let Some(dev) = fs.devid_map.get(&devid) {
-                    return Ok(dev.file.slice(
-                        (virt_offset - start + stripe.offset) as usize,
-                        node_length as usize,
-                    ));
-                }
-            }
-            return Err(anyhow!("no device containing stripe copy is present"));
+            return Some(chunk.clone());
+        }
+    }
+
+    if let Some((_, chunk)) = fs
+        .chunk_cache
+        .read()
+        .unwrap()
+        .range(..=virt_offset)
+        .next_back()
+    {
+        let start = chunk.0.offset;
+        let length = chunk.1.length;
+        if virt_offset < start + length {
+            return Some(chunk.clone());
         }
     }
+    if fs.chunk_map {
+        return None;
+    }
 
-    /* obtain leaf node structure + data slice */
+    /* obtain leaf node structure + data slice of the chunk at or left of virt_offset */
     for leaf_item in fs.search_node(
         fs.master_sb.chunk_root,
-        &NodeSearchOption {
-            min_key: btrfs_disk_key {
-                objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
-                item_type: BtrfsItemType::CHUNK_ITEM,
-                offset: virt_offset,
-            },
-            max_key: btrfs_disk_key {
-                objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
-                item_type: BtrfsItemType::CHUNK_ITEM,
-                offset: virt_offset,
-            },
-            min_match: std::cmp::Ordering::Equal,
-            max_match: std::cmp::Ordering::Equal,
-        },
+        &SearchKey::floor(btrfs_disk_key {
+            objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+            item_type: BtrfsItemType::CHUNK_ITEM,
+            offset: virt_offset,
+        })
+        .into(),
     ) {
-        let size = leaf_item.0.size;
-        let chunk =
-            unsafe { &*std::mem::transmute::<*const u8, *const btrfs_chunk>(leaf_item.1.as_ptr()) };
-        let length = chunk.length;
-        let owner = chunk.owner;
-        let num_stripes = chunk.num_stripes;
-        let start = leaf_item.0.key.offset;
-        debug!(
-            "Found leaf chunk item: key: {:?} length: {}, owner: {}, num_stripes {}",
-            leaf_item.0.key, length, owner, num_stripes
-        );
-        assert_eq!(
-            size as usize,
-            std::mem::size_of::<btrfs_chunk>()
-                + chunk.num_stripes as usize * std::mem::size_of::<btrfs_stripe>()
-        );
-        for stripe in ChunkStripeIter::new(
-            unsafe {
-                std::slice::from_raw_parts::<'_, u8>(
-                    leaf_item.1.as_ptr().add(std::mem::size_of::<btrfs_chunk>()),
-                    size as usize,
-                )
-            },
-            num_stripes.into(),
-        ) {
-            let devid = stripe.devid;
-            let offset = stripe.offset;
+        if leaf_item.0.key.item_type != BtrfsItemType::CHUNK_ITEM {
+            continue;
+        }
+        let chunk_info = chunk_item_info(&leaf_item.0, &leaf_item.1);
+        let start = chunk_info.0.offset;
+        let length = chunk_info.1.length;
+        if virt_offset < start || virt_offset >= start + length {
+            continue;
+        }
+        fs.chunk_cache
+            .write()
+            .unwrap()
+            .insert(start, chunk_info.clone());
+        return Some(chunk_info);
+    }
+    None
+}
 
-            debug!(
-                "stripe devid {devid} offset {offset}, virt_offset {virt_offset}, start {start}"
-            );
-            if let Some(dev) = fs.devid_map.get(&devid) {
-                return Ok(dev.file.slice(
-                    (virt_offset - start + stripe.offset) as usize,
-                    node_length as usize,
-                ));
-            }
+/// decode a CHUNK_ITEM from the chunk tree
+pub(crate) fn chunk_item_info(item: &btrfs_item, data: &[u8]) -> ChunkInfo {
+    let size = item.size;
+    let chunk = unsafe { &*std::mem::transmute::<*const u8, *const btrfs_chunk>(data.as_ptr()) };
+    let length = chunk.length;
+    let owner = chunk.owner;
+    let num_stripes = chunk.num_stripes;
+    debug!(
+        "Found leaf chunk item: key: {:?} length: {}, owner: {}, num_stripes {}",
+        item.key, length, owner, num_stripes
+    );
+    assert_eq!(
+        size as usize,
+        std::mem::size_of::<btrfs_chunk>()
+            + chunk.num_stripes as usize * std::mem::size_of::<btrfs_stripe>()
+    );
+    let stripes = ChunkStripeIter::new(
+        &data[std::mem::size_of::<btrfs_chunk>()..],
+        num_stripes.into(),
+    )
+    .copied()
+    .collect();
+    ChunkInfo(item.key, *chunk, stripes)
+}
+
+/// every chunk in the chunk tree, in logical address order. All of them are
+/// added to the chunk cache. With a chunk map, the chunks of the map.
+pub fn all_chunks(fs: &FsInfo) -> Vec<ChunkInfo> {
+    if fs.chunk_map {
+        return fs.chunk_cache.read().unwrap().values().cloned().collect();
+    }
+    let search = NodeSearchOption {
+        min_key: btrfs_disk_key {
+            objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+            item_type: BtrfsItemType::CHUNK_ITEM,
+            offset: 0,
+        },
+        max_key: btrfs_disk_key {
+            objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+            item_type: BtrfsItemType::CHUNK_ITEM,
+            offset: u64::MAX,
+        },
+        min_match: std::cmp::Ordering::Equal,
+        max_match: std::cmp::Ordering::Equal,
+    };
+    let mut chunks = Vec::new();
+    for (item, data, _block_offset, _slot) in fs.search_node(fs.master_sb.chunk_root, &search) {
+        if item.key.item_type != BtrfsItemType::CHUNK_ITEM {
+            continue;
         }
+        let chunk_info = chunk_item_info(&item, &data);
+        fs.chunk_cache
+            .write()
+            .unwrap()
+            .insert(chunk_info.0.offset, chunk_info.clone());
+        chunks.push(chunk_info);
     }
+    chunks
+}
 
+/// the chunk and logical address stored at a physical offset on a device.
+/// Striped profiles (raid0, raid10, raid5/6) are not handled, in keeping with
+/// virtual_offset_to_physical.
+pub fn physical_to_logical(fs: &FsInfo, devid: u64, physical: u64) -> Result<(ChunkInfo, u64)> {
+    for chunk in all_chunks(fs) {
+        let start = chunk.0.offset;
+        let length = chunk.1.length;
+        let chunk_type = chunk.1.r#type;
+        for stripe in &chunk.2 {
+            let stripe_devid = stripe.devid;
+            let stripe_offset = stripe.offset;
+            if stripe_devid != devid
+                || physical < stripe_offset
+                || physical >= stripe_offset + length
+            {
+                continue;
+            }
+            ensure!(
+                chunk_type & STRIPED_PROFILES == 0,
+                "physical {physical} on devid {devid} is in striped chunk {start} ({}), which is not supported",
+                fmt_block_group_type(chunk_type)
+            );
+            let logical = start + (physical - stripe_offset);
+            return Ok((chunk, logical));
+        }
+    }
     Err(anyhow!(
-        "virt address {virt_offset} not found among available chunks/devices"
+        "physical {physical} on devid {devid} is not within any chunk"
     ))
 }
 
+pub(crate) const STRIPED_PROFILES: u64 = BTRFS_BLOCK_GROUP_RAID0
+    | BTRFS_BLOCK_GROUP_RAID10
+    | BTRFS_BLOCK_GROUP_RAID5
+    | BTRFS_BLOCK_GROUP_RAID6;
+
+/// the tree block at virt_offset, its checksum verified as the filesystem's
+/// csum_policy says. Copies with a bad checksum are passed over for the
+/// next mirror (of RAID1, DUP or RAID10). The block is kept in the block cache with
+/// the copy it came from, and taken from there while that copy is still
+/// readable rather than read and verified again.
+pub fn load_virt_block(fs: &FsInfo, virt_offset: u64) -> Result<Arc<[u8]>> {
+    let copies = tree_block_copies(fs, virt_offset)?;
+    if let Some(cached) = fs.block_cache.get(virt_offset) {
+        if copies
+            .get(cached.copy)
+            .is_some_and(|copy| copy.readable(fs))
+        {
+            return Ok(cached.block);
+        }
+        fs.block_cache.remove(virt_offset);
+    }
+    let cache = |copy: usize, block: Vec<u8>| {
+        let block: Arc<[u8]> = Arc::from(block);
+        let cached = CachedBlock {
+            block: Arc::clone(&block),
+            copy,
+            checked: false,
+        };
+        fs.block_cache.insert(virt_offset, cached);
+        block
+    };
+    if fs.csum_policy == CsumPolicy::Skip {
+        let (index, block) = first_readable_copy(fs, virt_offset, &copies)?;
+        return Ok(cache(index, block));
+    }
+    let mut first = None;
+    let mut failures = Vec::new();
+    for (index, copy) in copies.iter().enumerate() {
+        let block = match copy.read(fs) {
+            None => continue,
+            Some(Err(e)) => {
+                failures.push(format!("devid {}: {e:#}", copy.devid));
+                continue;
+            }
+            Some(Result::Ok(block)) => block,
+        };
+        let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+        if header.csum == csum_data(&block[BTRFS_CSUM_SIZE..], fs.master_sb.csum_type) {
+            if !failures.is_empty() {
+                warn!(
+                    "tree block {virt_offset}: read the copy on devid {} at {} instead ({})",
+                    copy.devid,
+                    copy.physical,
+                    failures.join("; ")
+                );
+            }
+            return Ok(cache(index, block));
+        }
+        failures.push(format!(
+            "devid {} at {} has a bad checksum",
+            copy.devid, copy.physical
+        ));
+        first.get_or_insert(block);
+    }
+    let Some(block) = first else {
+        bail!("tree block {virt_offset} is lost: {}", failures.join("; "));
+    };
+    match fs.csum_policy {
+        CsumPolicy::Strict => bail!(
+            "tree block {virt_offset} has no copy with a good checksum: {}",
+            failures.join("; ")
+        ),
+        _ => warn!("tree block {virt_offset} has a bad checksum, reading it anyway"),
+    }
+    // not cached, so that a good copy is looked for again
+    Ok(Arc::from(block))
+}
+
+/// the tree block at virt_offset from the first device holding a copy
+/// outside the quarantine, as it is, for callers which check blocks
+/// themselves or report damage. It is always read, not taken from the block
+/// cache, which may hold another copy.
+pub fn load_virt_block_unverified(fs: &FsInfo, virt_offset: u64) -> Result<Arc<[u8]>> {
+    let copies = tree_block_copies(fs, virt_offset)?;
+    let (_, block) = first_readable_copy(fs, virt_offset, &copies)?;
+    Ok(Arc::from(block))
+}
+
+/// the first of copies which can be read, with its index
+fn first_readable_copy(
+    fs: &FsInfo,
+    virt_offset: u64,
+    copies: &[TreeBlockCopy],
+) -> Result<(usize, Vec<u8>)> {
+    let mut failures = Vec::new();
+    for (index, copy) in copies.iter().enumerate() {
+        match copy.read(fs) {
+            None => {}
+            Some(Result::Ok(block)) => return Ok((index, block)),
+            Some(Err(e)) => failures.push(format!("devid {}: {e:#}", copy.devid)),
+        }
+    }
+    if !failures.is_empty() {
+        bail!("tree block {virt_offset} is lost: {}", failures.join("; "));
+    }
+    Err(anyhow!("no device containing stripe copy is present"))
+}
+
+/// where one copy of a tree block lies, one per mirror of its chunk
+pub(crate) struct TreeBlockCopy {
+    logical: u64,
+    pub devid: u64,
+    pub physical: u64,
+}
+
+impl TreeBlockCopy {
+    /// whether the copy's device is present and it is outside the
+    /// quarantine, without reading it
+    fn readable(&self, fs: &FsInfo) -> bool {
+        let node_length = fs.master_sb.nodesize as u64;
+        fs.devid_map.contains_key(&self.devid)
+            && fs
+                .quarantine
+                .physical(self.devid, self.physical, node_length)
+                .is_none()
+    }
+
+    /// the copy, or why it can't be read, None if its device is missing
+    pub fn read(&self, fs: &FsInfo) -> Option<Result<Vec<u8>>> {
+        let (devid, physical) = (self.devid, self.physical);
+        let node_length = fs.master_sb.nodesize as u64;
+        let dev = fs.devid_map.get(&devid)?;
+        if let Some(bad) = fs.quarantine.physical(devid, physical, node_length) {
+            debug!("copy on devid {devid} at {physical} is in quarantined range {bad:?}");
+            return Some(Err(anyhow!("quarantined range {bad:?}")));
+        }
+        let block = dev
+            .file
+            .checked_read(physical as usize, node_length as usize);
+        if let Err(e) = &block {
+            warn!(
+                "tree block {} on devid {devid} at {physical}: {e:#}",
+                self.logical
+            );
+        }
+        Some(block)
+    }
+}
+
+/// the copies of the tree block at virt_offset: one per mirror of SINGLE,
+/// DUP and RAID1*, and of a striped chunk the strip holding it, once per
+/// sub-stripe for RAID10
+pub(crate) fn tree_block_copies(fs: &FsInfo, virt_offset: u64) -> Result<Vec<TreeBlockCopy>> {
+    let node_length = fs.master_sb.nodesize as u64;
+    debug!("load_virt_block: {virt_offset} length {node_length}");
+    ensure!(
+        virt_offset.is_multiple_of(node_length),
+        "tree block {virt_offset} is not aligned to the node size {node_length}"
+    );
+    let chunk = find_chunk(fs, virt_offset).ok_or_else(|| {
+        anyhow!("virt address {virt_offset} not found among available chunks/devices")
+    })?;
+    if let Some(bad) = fs.quarantine.logical(virt_offset, node_length) {
+        bail!("tree block {virt_offset} is lost: quarantined range {bad:?}");
+    }
+    let start = chunk.0.offset;
+    let locations = if chunk.1.r#type & STRIPED_PROFILES != 0 {
+        let (locations, length) = strip_copies(&chunk, virt_offset, node_length);
+        ensure!(
+            length == node_length,
+            "tree block {virt_offset} runs past the end of its strip in chunk {start}"
+        );
+        locations
+    } else {
+        chunk
+            .2
+            .iter()
+            .map(|stripe| (stripe.devid, virt_offset - start + stripe.offset))
+            .collect()
+    };
+    Ok(locations
+        .into_iter()
+        .map(|(devid, physical)| TreeBlockCopy {
+            logical: virt_offset,
+            devid,
+            physical,
+        })
+        .collect())
+}
+
+/// where the bytes from logical on in a striped chunk (RAID0, RAID10 or
+/// RAID5/6) lie, as the (devid, physical) of each copy of their strip, one
+/// per sub-stripe for RAID10 and otherwise one, with how many of length
+/// bytes there are before the end of the strip
+fn strip_copies(chunk: &ChunkInfo, logical: u64, length: u64) -> (Vec<(u64, u64)>, u64) {
+    let ChunkInfo(key, chunk, stripes) = chunk;
+    let chunk_type = chunk.r#type;
+    let stripe_len = chunk.stripe_len.max(1);
+    let num_stripes = stripes.len() as u64;
+    let (strip, within) = (
+        (logical - key.offset) / stripe_len,
+        (logical - key.offset) % stripe_len,
+    );
+    let length = length.min(stripe_len - within);
+    if chunk_type & RAID56_PROFILES != 0 {
+        // data strip i of full stripe nr is on stripe nr + i, rotating
+        let data_strips = num_stripes.saturating_sub(parity_strips(chunk_type)).max(1);
+        let (nr, i) = (strip / data_strips, strip % data_strips);
+        let location = stripes
+            .get(((nr + i) % num_stripes.max(1)) as usize)
+            .map(|stripe| (stripe.devid, stripe.offset + nr * stripe_len + within));
+        return (location.into_iter().collect(), length);
+    }
+    // RAID0 and RAID10: strip n is on device group n modulo the groups, of
+    // sub_stripes mirrors each for RAID10
+    let sub_stripes = if chunk_type & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+        (chunk.sub_stripes as u64).clamp(1, num_stripes.max(1))
+    } else {
+        1
+    };
+    let groups = (num_stripes / sub_stripes).max(1);
+    let physical = strip / groups * stripe_len + within;
+    let first = strip % groups * sub_stripes;
+    let locations = stripes
+        .iter()
+        .skip(first as usize)
+        .take(sub_stripes as usize)
+        .map(|stripe| (stripe.devid, stripe.offset + physical))
+        .collect();
+    (locations, length)
+}
+
+/// pass advice on how length bytes at logical will be read on to the devices
+/// holding them: every copy, or with first_copy only the one load_virt_block
+/// reads. It is only a hint, so addresses in striped chunks or in no chunk
+/// are left alone.
+pub fn advise_logical(fs: &FsInfo, logical: u64, length: u64, advice: Advice, first_copy: bool) {
+    let Some(ChunkInfo(key, chunk, stripes)) = find_chunk(fs, logical) else {
+        return;
+    };
+    if chunk.r#type & STRIPED_PROFILES != 0 || uses_raid_stripe_tree(fs, chunk.r#type) {
+        return;
+    }
+    let start = key.offset;
+    let length = length.min(start + chunk.length - logical);
+    for stripe in &stripes {
+        let Some(dev) = fs.devid_map.get(&{ stripe.devid }) else {
+            continue;
+        };
+        let physical = (stripe.offset + (logical - start)) as usize;
+        if let Err(e) = dev
+            .file
+            .advise(physical..physical + length as usize, advice)
+        {
+            debug!("advise {logical}+{length}: {e}");
+        }
+        if first_copy {
+            return;
+        }
+    }
+}
+
+/// one copy of a range of a mirrored (or SINGLE) chunk on a device
+pub struct BlockCopy {
+    pub devid: u64,
+    pub physical: u64,
+    /// None if the device is missing or too short, or the copy is
+    /// quarantined or can't be read
+    pub data: Option<Vec<u8>>,
+}
+
+/// every copy of length bytes at logical, one per stripe of its chunk, or
+/// for data the raid stripe tree maps, one per stride of its stripe
+/// extent. Striped profiles are otherwise not supported.
+pub fn block_copies(fs: &FsInfo, logical: u64, length: u64) -> Result<Vec<BlockCopy>> {
+    let ChunkInfo(key, chunk, stripes) = find_chunk(fs, logical)
+        .ok_or_else(|| anyhow!("logical {logical} is not within any chunk"))?;
+    let start = key.offset;
+    let chunk_length = chunk.length;
+    let chunk_type = chunk.r#type;
+    ensure!(
+        logical + length <= start + chunk_length,
+        "{logical}+{length} runs past the end of chunk {start}"
+    );
+    let copy = |devid: u64, physical: u64| read_copy(fs, logical, length, devid, physical);
+    if uses_raid_stripe_tree(fs, chunk_type) {
+        let extent = stripe_extent(fs, logical)?
+            .ok_or_else(|| anyhow!("logical {logical} is in no raid stripe extent"))?;
+        ensure!(
+            logical + length <= extent.logical + extent.length,
+            "{logical}+{length} runs past the end of raid stripe extent {}",
+            extent.logical
+        );
+        return Ok(extent
+            .strides
+            .iter()
+            .map(|&(devid, physical)| copy(devid, physical + (logical - extent.logical)))
+            .collect());
+    }
+    ensure!(
+        chunk_type & STRIPED_PROFILES == 0,
+        "logical {logical} is in striped chunk {start} ({}), which is not supported",
+        fmt_block_group_type(chunk_type)
+    );
+    Ok(stripes
+        .iter()
+        .map(|stripe| copy(stripe.devid, stripe.offset + (logical - start)))
+        .collect())
+}
+
+/// the copy of length bytes at logical which is at physical on devid
+fn read_copy(fs: &FsInfo, logical: u64, length: u64, devid: u64, physical: u64) -> BlockCopy {
+    let data = fs
+        .devid_map
+        .get(&devid)
+        .filter(|_| fs.quarantine.logical(logical, length).is_none())
+        .filter(|_| fs.quarantine.physical(devid, physical, length).is_none())
+        .and_then(|dev| {
+            dev.file
+                .checked_read(physical as usize, length as usize)
+                .map_err(|e| warn!("logical {logical} on devid {devid} at {physical}: {e:#}"))
+                .ok()
+        });
+    BlockCopy {
+        devid,
+        physical,
+        data,
+    }
+}
+
+/// length bytes from logical on, e.g. a data extent, which may cross
+/// stripe and chunk boundaries and be in any profile. Each piece is read
+/// from the first of its copies which can be, and a RAID5/6 data strip
+/// which can't is rebuilt from parity. Checksums are left to the caller.
+pub fn read_logical(fs: &FsInfo, logical: u64, length: u64) -> Result<Vec<u8>> {
+    let end = logical
+        .checked_add(length)
+        .ok_or_else(|| anyhow!("{logical}+{length} overflows"))?;
+    let mut data = Vec::with_capacity(length as usize);
+    let mut pos = logical;
+    while pos < end {
+        let piece = logical_piece(fs, pos, end - pos)
+            .with_context(|| format!("reading {length} bytes at logical {logical}"))?;
+        pos += piece.len() as u64;
+        data.extend_from_slice(&piece);
+    }
+    Ok(data)
+}
+
+/// the bytes from logical on, up to length of them, as far as the end of
+/// its strip, raid stripe extent or chunk
+fn logical_piece(fs: &FsInfo, logical: u64, length: u64) -> Result<Vec<u8>> {
+    let found = find_chunk(fs, logical)
+        .ok_or_else(|| anyhow!("logical {logical} is not within any chunk"))?;
+    let ChunkInfo(key, chunk, _) = &found;
+    let start = key.offset;
+    let chunk_type = chunk.r#type;
+    let stripe_len = chunk.stripe_len.max(1);
+    let length = length.min(start + chunk.length - logical);
+    if chunk_type & RAID56_PROFILES != 0 {
+        let stripe = full_stripe(fs, logical)?;
+        let (index, strip_start) = stripe
+            .strips
+            .iter()
+            .enumerate()
+            .find_map(|(index, strip)| match strip.role {
+                StripRole::Data(at) if (at..at + stripe_len).contains(&logical) => {
+                    Some((index, at))
+                }
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("logical {logical} is in no data strip"))?;
+        let within = (logical - strip_start) as usize;
+        let length = length.min(strip_start + stripe_len - logical) as usize;
+        return Ok(match &stripe.strips[index].data {
+            Some(data) => data[within..within + length].to_vec(),
+            None => regenerate_strip(&stripe, index)?[within..within + length].to_vec(),
+        });
+    }
+    let copies = if uses_raid_stripe_tree(fs, chunk_type) {
+        let extent = stripe_extent(fs, logical)?
+            .ok_or_else(|| anyhow!("logical {logical} is in no raid stripe extent"))?;
+        block_copies(
+            fs,
+            logical,
+            length.min(extent.logical + extent.length - logical),
+        )?
+    } else if chunk_type & STRIPED_PROFILES != 0 {
+        let (locations, length) = strip_copies(&found, logical, length);
+        locations
+            .into_iter()
+            .map(|(devid, physical)| read_copy(fs, logical, length, devid, physical))
+            .collect()
+    } else {
+        block_copies(fs, logical, length)?
+    };
+    let devids: Vec<String> = copies.iter().map(|copy| copy.devid.to_string()).collect();
+    copies
+        .into_iter()
+        .find_map(|copy| copy.data)
+        .ok_or_else(|| {
+            anyhow!(
+                "no copy of logical {logical} can be read (devids {})",
+                devids.join(", ")
+            )
+        })
+}
+
 //TODO: could make this into an iterator then use it in the above however
 // the iterator would be a little complex so... maybe later.
+/// the files holding each copy of the block at virt_offset and the offset
+/// within each file, which includes the device's offset in its file. Data
+/// the raid stripe tree maps is looked up there.
 pub fn virtual_offset_to_physical(
     fs: &FsInfo,
     virt_offset: u64,
@@ -153,79 +635,27 @@ pub fn virtual_offset_to_physical(
     let block_offset = virt_offset % node_length;
     let block_start = virt_offset - block_offset;
 
-    let mut results: Vec<(u64, &Path)> = Vec::new();
+    let ChunkInfo(key, chunk, stripes) = find_chunk(fs, block_start).ok_or_else(|| {
+        anyhow!("virt address {virt_offset} not found among available chunks/devices")
+    })?;
+    let start = key.offset;
 
-    for chunk in &fs.bootstrap_chunks {
-        let start = chunk.0.offset;
-        let length = chunk.1.length;
-        if block_start >= start && block_start < start + length {
-            for stripe in &chunk.2 {
-                let devid = stripe.devid;
-                if let Some(dev) = fs.devid_map.get(&devid) {
-                    let dev_offset = block_start - start + stripe.offset + block_offset;
-                    results.push((dev_offset, dev.path.as_path()));
-                }
-            }
-            if !results.is_empty() {
-                return Ok(results);
-            } else {
-                return Err(anyhow!("no device containing stripe copy is present"));
+    let mut results: Vec<(u64, &Path)> = Vec::new();
+    if uses_raid_stripe_tree(fs, chunk.r#type) {
+        let extent = stripe_extent(fs, virt_offset)?
+            .ok_or_else(|| anyhow!("virt address {virt_offset} is in no raid stripe extent"))?;
+        for (devid, physical) in extent.strides {
+            if let Some(dev) = fs.devid_map.get(&devid) {
+                let dev_offset = physical + (virt_offset - extent.logical);
+                results.push((dev.offset + dev_offset, dev.path.as_path()));
             }
         }
-    }
-
-    /* obtain leaf node structure + data slice */
-    for leaf_item in fs.search_node(
-        fs.master_sb.chunk_root,
-        &NodeSearchOption {
-            min_key: btrfs_disk_key {
-                objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
-                item_type: BtrfsItemType::CHUNK_ITEM,
-                offset: block_start,
-            },
-            max_key: btrfs_disk_key {
-                objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
-                item_type: BtrfsItemType::CHUNK_ITEM,
-                offset: block_start,
-            },
-            min_match: std::cmp::Ordering::Equal,
-            max_match: std::cmp::Ordering::Equal,
-        },
-    ) {
-        let size = leaf_item.0.size;
-        let chunk =
-            unsafe { &*std::mem::transmute::<*const u8, *const btrfs_chunk>(leaf_item.1.as_ptr()) };
-        let length = chunk.length;
-        let owner = chunk.owner;
-        let num_stripes = chunk.num_stripes;
-        let start = leaf_item.0.key.offset;
-        debug!(
-            "Found leaf chunk item: key: {:?} length: {}, owner: {}, num_stripes {}",
-            leaf_item.0.key, length, owner, num_stripes
-        );
-        assert_eq!(
-            size as usize,
-            std::mem::size_of::<btrfs_chunk>()
-                + chunk.num_stripes as usize * std::mem::size_of::<btrfs_stripe>()
-        );
-        for stripe in ChunkStripeIter::new(
-            unsafe {
-                std::slice::from_raw_parts::<'_, u8>(
-                    leaf_item.1.as_ptr().add(std::mem::size_of::<btrfs_chunk>()),
-                    size as usize,
-                )
-            },
-            num_stripes.into(),
-        ) {
+    } else {
+        for stripe in &stripes {
             let devid = stripe.devid;
-            let offset = stripe.offset;
-
-            debug!(
-                "stripe devid {devid} offset {offset}, virt_offset {virt_offset}, start {start}"
-            );
             if let Some(dev) = fs.devid_map.get(&devid) {
                 let dev_offset = block_start - start + stripe.offset + block_offset;
-                results.push((dev_offset, dev.path.as_path()));
+                results.push((dev.offset + dev_offset, dev.path.as_path()));
             }
         }
     }
@@ -233,8 +663,131 @@ pub fn virtual_offset_to_physical(
     if !results.is_empty() {
         Ok(results)
     } else {
-        Err(anyhow!(
-            "virt address {virt_offset} not found among available chunks/devices"
-        ))
+        Err(anyhow!("no device containing stripe copy is present"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_cache::BlockCache;
+    use crate::btrfs_node::{build_leaf, seal_tree_block};
+    use crate::tree::tests::{key, test_fs, NODESIZE};
+
+    #[test]
+    fn logical_reads() {
+        let blocks: Vec<Vec<u8>> = (0..3).map(|n| vec![n as u8; NODESIZE]).collect();
+        let mut fs = test_fs("logical_reads", &blocks);
+        // a RAID0 chunk at 0 whose strips are blocks 2 and 0, then a SINGLE
+        // chunk of block 1
+        let ChunkInfo(key, chunk, stripes) = &fs.bootstrap_chunks[0];
+        let (mut key, mut chunk, mut stripe) = (*key, *chunk, stripes[0]);
+        chunk.length = 2 * NODESIZE as u64;
+        chunk.stripe_len = NODESIZE as u64;
+        chunk.r#type = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID0;
+        chunk.num_stripes = 2;
+        let mut second = stripe;
+        stripe.offset = 2 * NODESIZE as u64;
+        let raid0 = ChunkInfo(key, chunk, vec![stripe, second]);
+        key.offset = 2 * NODESIZE as u64;
+        chunk.length = NODESIZE as u64;
+        chunk.r#type = BTRFS_BLOCK_GROUP_DATA;
+        chunk.num_stripes = 1;
+        second.offset = NODESIZE as u64;
+        fs.bootstrap_chunks = vec![raid0, ChunkInfo(key, chunk, vec![second])];
+
+        let data = read_logical(&fs, 2048, 2 * NODESIZE as u64).unwrap();
+        let expected = [vec![2; 2048], vec![0; NODESIZE], vec![1; 2048]].concat();
+        assert_eq!(data, expected);
+        assert!(read_logical(&fs, 0, 0).unwrap().is_empty());
+        assert!(read_logical(&fs, 2048, 3 * NODESIZE as u64).is_err());
+        fs.quarantine.add_physical(1, 0..1);
+        assert!(read_logical(&fs, 2048, 4096).is_err());
+    }
+
+    #[test]
+    fn mirror_failover() {
+        let header: btrfs_header = unsafe { std::mem::zeroed() };
+        let mut good = build_leaf(&header, &[(key(10), [1u8; 8])], NODESIZE).unwrap();
+        seal_tree_block(&mut good, BtrfsCsumType::CRC32);
+        let mut bad = good.clone();
+        bad[200] ^= 1;
+        // a DUP chunk of the one block, whose first copy is bad
+        let mut fs = test_fs("mirror_failover", &[good.clone(), bad]);
+        let ChunkInfo(_, chunk, stripes) = &mut fs.bootstrap_chunks[0];
+        chunk.length = NODESIZE as u64;
+        chunk.num_stripes = 2;
+        let mut first = stripes[0];
+        first.offset = NODESIZE as u64;
+        stripes.insert(0, first);
+
+        fs.block_cache = BlockCache::new(NODESIZE, NODESIZE);
+
+        assert_ne!(&load_virt_block_unverified(&fs, 0).unwrap()[..], &good[..]);
+        assert_eq!(&load_virt_block(&fs, 0).unwrap()[..], &good[..]);
+        assert_eq!(fs.block_cache.verified_copy(0), Some(1));
+        assert_eq!(&load_virt_block(&fs, 0).unwrap()[..], &good[..]);
+
+        fs.block_cache.remove(0);
+        fs.bootstrap_chunks[0].2.truncate(1);
+        assert!(load_virt_block(&fs, 0).is_err());
+        fs.csum_policy = CsumPolicy::WarnOnly;
+        assert!(load_virt_block(&fs, 0).is_ok());
+    }
+
+    #[test]
+    fn raid10_failover() {
+        let mut header: btrfs_header = unsafe { std::mem::zeroed() };
+        let mut leaf = |bytenr: u64, objectid: u64| {
+            header.bytenr = bytenr;
+            let mut block = build_leaf(&header, &[(key(objectid), [1u8; 8])], NODESIZE).unwrap();
+            seal_tree_block(&mut block, BtrfsCsumType::CRC32);
+            block
+        };
+        let (first, second) = (leaf(0, 10), leaf(NODESIZE as u64, 20));
+        let mut bad = first.clone();
+        bad[200] ^= 1;
+        // a RAID10 chunk of two strips, each on a pair of stripes: the first
+        // strip's first copy is bad, and the second strip is on the stripes
+        // at blocks 2 and 3
+        let mut fs = test_fs(
+            "raid10_failover",
+            &[bad, first.clone(), second.clone(), second.clone()],
+        );
+        let ChunkInfo(_, chunk, stripes) = &mut fs.bootstrap_chunks[0];
+        chunk.length = 2 * NODESIZE as u64;
+        chunk.stripe_len = NODESIZE as u64;
+        chunk.r#type = BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_RAID10;
+        chunk.num_stripes = 4;
+        chunk.sub_stripes = 2;
+        let stripe = stripes[0];
+        *stripes = (0..4)
+            .map(|n| btrfs_stripe {
+                offset: n * NODESIZE as u64,
+                ..stripe
+            })
+            .collect();
+
+        assert_eq!(&load_virt_block(&fs, 0).unwrap()[..], &first[..]);
+        assert_eq!(
+            &load_virt_block(&fs, NODESIZE as u64).unwrap()[..],
+            &second[..]
+        );
+        let copies = tree_block_copies(&fs, NODESIZE as u64).unwrap();
+        let physical: Vec<u64> = copies.iter().map(|copy| copy.physical).collect();
+        assert_eq!(physical, [2 * NODESIZE as u64, 3 * NODESIZE as u64]);
+
+        // RAID0: the second strip has one copy, and no other to fail over to
+        let ChunkInfo(_, chunk, stripes) = &mut fs.bootstrap_chunks[0];
+        chunk.r#type = BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_RAID0;
+        chunk.num_stripes = 2;
+        stripes.swap(1, 2);
+        stripes.truncate(2);
+        assert!(load_virt_block(&fs, 0).is_err());
+        fs.csum_policy = CsumPolicy::Skip;
+        assert_eq!(
+            &load_virt_block(&fs, NODESIZE as u64).unwrap()[..],
+            &second[..]
+        );
     }
 }
@@ -0,0 +1,302 @@
+//! Extent backreferences, i.e. what is using a given extent.
+//!
+//! Each EXTENT_ITEM (or skinny METADATA_ITEM) in the extent tree carries
+//! inline refs after the item, and any refs which did not fit are stored as
+//! separate keyed items with the same objectid.
+
+use crate::address::*;
+use crate::block_cache::load_verified_block;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::dump::fmt_treeid;
+use crate::items::item_as;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use crc::{Crc, CRC_32_ISCSI};
+
+const TREE_BLOCK_REF: u8 = BtrfsItemType::TREE_BLOCK_REF as u8;
+const SHARED_BLOCK_REF: u8 = BtrfsItemType::SHARED_BLOCK_REF as u8;
+const EXTENT_DATA_REF: u8 = BtrfsItemType::EXTENT_DATA_REF as u8;
+const SHARED_DATA_REF: u8 = BtrfsItemType::SHARED_DATA_REF as u8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum ExtentRef {
+    /// tree block owned by root
+    TreeBlock { root: u64 },
+    /// tree block referenced by the (shared) node at parent
+    SharedBlock { parent: u64 },
+    /// data referenced by inode objectid of root, at file offset offset
+    Data {
+        root: u64,
+        objectid: u64,
+        offset: u64,
+        count: u32,
+    },
+    /// data referenced by the (shared) leaf at parent
+    SharedData { parent: u64, count: u32 },
+}
+
+impl ExtentRef {
+    pub fn describe(&self) -> String {
+        match *self {
+            ExtentRef::TreeBlock { root } => {
+                format!("tree block backref root {}", fmt_treeid(root))
+            }
+            ExtentRef::SharedBlock { parent } => format!("shared block backref parent {parent}"),
+            ExtentRef::Data {
+                root,
+                objectid,
+                offset,
+                count,
+            } => format!(
+                "extent data backref root {} objectid {objectid} offset {offset} count {count}",
+                fmt_treeid(root)
+            ),
+            ExtentRef::SharedData { parent, count } => {
+                format!("shared data backref parent {parent} count {count}")
+            }
+        }
+    }
+}
+
+/// parse the inline refs packed after an extent item (and its tree block info).
+/// Returns the refs decoded, plus a description of anything left over that
+/// could not be decoded.
+pub fn parse_inline_refs(data: &[u8]) -> (Vec<ExtentRef>, Option<String>) {
+    let mut refs = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let ref_type = rest[0];
+        let (r, len) = match ref_type {
+            TREE_BLOCK_REF | SHARED_BLOCK_REF if rest.len() >= 9 => {
+                let offset = u64::from_le_bytes(rest[1..9].try_into().unwrap());
+                if ref_type == TREE_BLOCK_REF {
+                    (ExtentRef::TreeBlock { root: offset }, 9)
+                } else {
+                    (ExtentRef::SharedBlock { parent: offset }, 9)
+                }
+            }
+            EXTENT_DATA_REF if rest.len() > std::mem::size_of::<btrfs_extent_data_ref>() => {
+                let dref = item_as::<btrfs_extent_data_ref>(&rest[1..]).unwrap();
+                (
+                    ExtentRef::Data {
+                        root: dref.root,
+                        objectid: dref.objectid,
+                        offset: dref.offset,
+                        count: dref.count,
+                    },
+                    1 + std::mem::size_of::<btrfs_extent_data_ref>(),
+                )
+            }
+            SHARED_DATA_REF if rest.len() >= 13 => {
+                let parent = u64::from_le_bytes(rest[1..9].try_into().unwrap());
+                let count = u32::from_le_bytes(rest[9..13].try_into().unwrap());
+                (ExtentRef::SharedData { parent, count }, 13)
+            }
+            _ => {
+                return (
+                    refs,
+                    Some(format!(
+                        "unrecognised inline ref type 0x{ref_type:x} with {} bytes remaining",
+                        rest.len()
+                    )),
+                );
+            }
+        };
+        refs.push(r);
+        rest = &rest[len..];
+    }
+    (refs, None)
+}
+
+/// the key offset of a keyed EXTENT_DATA_REF, as the kernel's
+/// hash_extent_data_ref: crc32c of the root in the high half (shifted by 31,
+/// not 32) xored with crc32c of the inode and offset
+pub fn hash_extent_data_ref(root: u64, objectid: u64, offset: u64) -> u64 {
+    const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+    // the kernel seeds with ~0 and doesn't invert the result
+    let high = !CASTAGNOLI.checksum(&root.to_le_bytes());
+    let mut digest = CASTAGNOLI.digest();
+    digest.update(&objectid.to_le_bytes());
+    digest.update(&offset.to_le_bytes());
+    let low = !digest.finalize();
+    ((high as u64) << 31) ^ low as u64
+}
+
+/// a backref stored as its own item in the extent tree
+pub fn keyed_ref(key: &btrfs_disk_key, data: &[u8]) -> Option<ExtentRef> {
+    match key.item_type {
+        BtrfsItemType::TREE_BLOCK_REF => Some(ExtentRef::TreeBlock { root: key.offset }),
+        BtrfsItemType::SHARED_BLOCK_REF => Some(ExtentRef::SharedBlock { parent: key.offset }),
+        BtrfsItemType::EXTENT_DATA_REF => {
+            let dref = item_as::<btrfs_extent_data_ref>(data)?;
+            Some(ExtentRef::Data {
+                root: dref.root,
+                objectid: dref.objectid,
+                offset: dref.offset,
+                count: dref.count,
+            })
+        }
+        BtrfsItemType::SHARED_DATA_REF => {
+            let count = u32::from_le_bytes(data.get(0..4)?.try_into().unwrap());
+            Some(ExtentRef::SharedData {
+                parent: key.offset,
+                count,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// an allocated extent and everything referring to it
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Extent {
+    pub key: btrfs_disk_key,
+    pub start: u64,
+    pub length: u64,
+    pub refs_count: u64,
+    pub generation: u64,
+    pub flags: u64,
+    pub refs: Vec<ExtentRef>,
+    /// inline ref data that could not be decoded
+    pub problem: Option<String>,
+}
+
+impl Extent {
+    pub fn is_tree_block(&self) -> bool {
+        self.flags & BTRFS_EXTENT_FLAG_TREE_BLOCK != 0
+    }
+}
+
+pub(crate) fn extent_from_item(fs: &FsInfo, key: &btrfs_disk_key, data: &[u8]) -> Option<Extent> {
+    let ei = item_as::<btrfs_extent_item>(data)?;
+    let flags = ei.flags;
+    let length = if key.item_type == BtrfsItemType::METADATA_ITEM {
+        fs.master_sb.nodesize as u64
+    } else {
+        key.offset
+    };
+    let mut rest = &data[std::mem::size_of::<btrfs_extent_item>()..];
+    if key.item_type == BtrfsItemType::EXTENT_ITEM && flags & BTRFS_EXTENT_FLAG_TREE_BLOCK != 0 {
+        rest = rest.get(std::mem::size_of::<btrfs_tree_block_info>()..)?;
+    }
+    let (refs, problem) = parse_inline_refs(rest);
+    Some(Extent {
+        key: *key,
+        start: key.objectid,
+        length,
+        refs_count: ei.refs,
+        generation: ei.generation,
+        flags,
+        refs,
+        problem,
+    })
+}
+
+/// the extent tree entry covering a logical address, if the address is allocated
+pub fn find_extent(fs: &FsInfo, logical: u64) -> Result<Option<Extent>> {
+    let extent_root = global_root_for(fs, BTRFS_EXTENT_TREE_OBJECTID, logical)
+        .ok_or_else(|| anyhow!("extent tree not found"))?;
+    // extents never cross chunks, so it is enough to search from the chunk start
+    let chunk_start = find_chunk(fs, logical).map(|c| c.0.offset).unwrap_or(0);
+    let search = NodeSearchOption::between(
+        btrfs_disk_key {
+            objectid: chunk_start,
+            item_type: BtrfsItemType::MIN,
+            offset: 0,
+        },
+        btrfs_disk_key {
+            objectid: logical,
+            item_type: BtrfsItemType::MAX,
+            offset: u64::MAX,
+        },
+    );
+    let mut found: Option<Extent> = None;
+    for (item, data, _block_offset, _slot) in BtrfsTreeIter::new(fs, extent_root, search) {
+        let key = item.key;
+        match key.item_type {
+            BtrfsItemType::EXTENT_ITEM | BtrfsItemType::METADATA_ITEM => {
+                found = extent_from_item(fs, &key, &data);
+            }
+            _ => {
+                if let Some(extent) = found.as_mut() {
+                    if extent.start == key.objectid {
+                        if let Some(r) = keyed_ref(&key, &data) {
+                            extent.refs.push(r);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(found.filter(|e| logical >= e.start && logical < e.start + e.length))
+}
+
+/// a file whose data covers a logical address
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DataOwner {
+    pub root: u64,
+    pub inode: u64,
+    pub file_offset: u64,
+}
+
+/// files referencing the byte at logical within a data extent. Shared refs
+/// are resolved by scanning the EXTENT_DATA items of the parent leaf.
+pub fn data_owners(fs: &FsInfo, extent: &Extent, logical: u64) -> Vec<DataOwner> {
+    let within = logical - extent.start;
+    let mut owners = Vec::new();
+    for r in &extent.refs {
+        match *r {
+            ExtentRef::Data {
+                root,
+                objectid,
+                offset,
+                ..
+            } => owners.push(DataOwner {
+                root,
+                inode: objectid,
+                file_offset: offset + within,
+            }),
+            ExtentRef::SharedData { parent, .. } => {
+                // many refs can share a parent leaf, which is read once
+                let Result::Ok(block) = load_verified_block(fs, parent) else {
+                    continue;
+                };
+                let block = &block[..];
+                let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+                let root = header.owner;
+                for entry in node_entries(block) {
+                    let NodeEntry::Item(item, Some(data)) = entry else {
+                        continue;
+                    };
+                    if item.key.item_type != BtrfsItemType::EXTENT_DATA {
+                        continue;
+                    }
+                    let Some(fe) = item_as::<btrfs_file_extent_item>(data) else {
+                        continue;
+                    };
+                    let disk_bytenr = fe.disk_bytenr;
+                    let extent_offset = fe.offset;
+                    let num_bytes = fe.num_bytes;
+                    if fe.r#type == BTRFS_FILE_EXTENT_INLINE
+                        || disk_bytenr != extent.start
+                        || within < extent_offset
+                        || within >= extent_offset + num_bytes
+                    {
+                        continue;
+                    }
+                    owners.push(DataOwner {
+                        root,
+                        inode: item.key.objectid,
+                        file_offset: item.key.offset + within - extent_offset,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    owners
+}
@@ -0,0 +1,196 @@
+//! Tree blocks which have been read and verified, kept so that walks which
+//! come back to the same nodes (backref walks, lookups through several
+//! trees, the root tree on every tree_root_offset) don't read and check them
+//! again. Every block load_virt_block returns comes through here, with the
+//! copy it was read from, and the least recently used block is dropped once
+//! the cache is full. Blocks are kept in the buffers they were read into,
+//! so the size is worth bounding where memory is short, or setting to 0 for
+//! no cache at all.
+
+use crate::address::tree_block_copies;
+use crate::btrfs::*;
+use crate::scrub::check_tree_block;
+
+use anyhow::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_BLOCK_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+static BLOCK_CACHE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_BLOCK_CACHE_SIZE);
+
+/// give the filesystems opened from now on a block cache of size bytes
+pub fn set_block_cache_size(size: usize) {
+    BLOCK_CACHE_SIZE.store(size, Ordering::Relaxed);
+}
+
+pub fn block_cache_size() -> usize {
+    BLOCK_CACHE_SIZE.load(Ordering::Relaxed)
+}
+
+/// a block in the cache
+#[derive(Clone)]
+pub struct CachedBlock {
+    pub block: Arc<[u8]>,
+    /// the copy it was read from, by the stripe of its chunk
+    pub copy: usize,
+    /// it passed check_tree_block, not only its checksum
+    pub checked: bool,
+}
+
+#[derive(Default)]
+struct Lru {
+    clock: u64,
+    /// logical address to (last use, block)
+    blocks: HashMap<u64, (u64, CachedBlock)>,
+    /// last use to logical address, oldest first
+    uses: BTreeMap<u64, u64>,
+}
+
+pub struct BlockCache {
+    /// in blocks
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+impl BlockCache {
+    /// a cache of up to size bytes of blocks of nodesize bytes
+    pub fn new(size: usize, nodesize: usize) -> BlockCache {
+        BlockCache {
+            capacity: size / nodesize.max(1),
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    pub fn get(&self, logical: u64) -> Option<CachedBlock> {
+        let mut lru = self.lru.lock().unwrap();
+        lru.clock += 1;
+        let clock = lru.clock;
+        let (last_used, cached) = lru.blocks.get_mut(&logical)?;
+        let previous = std::mem::replace(last_used, clock);
+        let cached = cached.clone();
+        lru.uses.remove(&previous);
+        lru.uses.insert(clock, logical);
+        Some(cached)
+    }
+
+    pub fn insert(&self, logical: u64, cached: CachedBlock) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.clock += 1;
+        let clock = lru.clock;
+        if let Some((previous, _)) = lru.blocks.insert(logical, (clock, cached)) {
+            lru.uses.remove(&previous);
+        }
+        lru.uses.insert(clock, logical);
+        while lru.blocks.len() > self.capacity {
+            let (_, oldest) = lru.uses.pop_first().unwrap();
+            lru.blocks.remove(&oldest);
+        }
+    }
+
+    /// forget the block at logical, once it has been rewritten
+    pub fn remove(&self, logical: u64) {
+        let mut lru = self.lru.lock().unwrap();
+        if let Some((last_used, _)) = lru.blocks.remove(&logical) {
+            lru.uses.remove(&last_used);
+        }
+    }
+
+    /// which copy of the block at logical the cached block was read from,
+    /// by the stripe of its chunk
+    pub fn verified_copy(&self, logical: u64) -> Option<usize> {
+        let lru = self.lru.lock().unwrap();
+        lru.blocks.get(&logical).map(|(_, cached)| cached.copy)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// the first copy of the tree block at logical which passes check_tree_block,
+/// from the block cache if it has been checked before
+pub fn load_verified_block(fs: &FsInfo, logical: u64) -> Result<Arc<[u8]>> {
+    if let Some(mut cached) = fs.block_cache.get(logical) {
+        if cached.checked {
+            return Ok(cached.block);
+        }
+        if check_tree_block(fs, &cached.block, logical, None).is_empty() {
+            cached.checked = true;
+            fs.block_cache.insert(logical, cached.clone());
+            return Ok(cached.block);
+        }
+    }
+    let mut failures = Vec::new();
+    for (copy, found) in tree_block_copies(fs, logical)?.iter().enumerate() {
+        let data = match found.read(fs) {
+            None => {
+                failures.push(format!("devid {} is missing", found.devid));
+                continue;
+            }
+            Some(Err(e)) => {
+                failures.push(format!("devid {}: {e:#}", found.devid));
+                continue;
+            }
+            Some(Result::Ok(data)) => data,
+        };
+        let problems = check_tree_block(fs, &data, logical, None);
+        if problems.is_empty() {
+            let block: Arc<[u8]> = Arc::from(data);
+            let cached = CachedBlock {
+                block: Arc::clone(&block),
+                copy,
+                checked: true,
+            };
+            fs.block_cache.insert(logical, cached);
+            return Ok(block);
+        }
+        failures.push(format!(
+            "devid {} physical {}: {}",
+            found.devid,
+            found.physical,
+            problems.join(", ")
+        ));
+    }
+    bail!(
+        "no good copy of tree block {logical}: {}",
+        failures.join("; ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used() {
+        let cache = BlockCache::new(2 * 4096, 4096);
+        let block = |n: u8| CachedBlock {
+            block: Arc::from(vec![n; 4096]),
+            copy: 0,
+            checked: false,
+        };
+        cache.insert(1, block(1));
+        cache.insert(2, block(2));
+        assert!(cache.get(1).is_some());
+        cache.insert(3, block(3));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.get(1).unwrap().block[0], 1);
+        cache.remove(1);
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(3).is_some());
+
+        let disabled = BlockCache::new(0, 4096);
+        disabled.insert(1, block(1));
+        assert!(disabled.is_empty());
+    }
+}
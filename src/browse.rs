@@ -0,0 +1,318 @@
+//! Interactive terminal browser for the metadata trees.
+//!
+//! Starts with a list of trees, and lets the user drill down from a tree
+//! root through the internal nodes to the leaves. Nodes are read straight
+//! from the block with bounds checks rather than through the tree
+//! iterators, so that corrupt nodes can still be looked at.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::dump::*;
+use crate::items::*;
+use crate::structures::*;
+
+use anyhow::*;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::sync::Arc;
+
+struct NodeView {
+    bytenr: u64,
+    block: Arc<[u8]>,
+    state: ListState,
+}
+
+impl NodeView {
+    fn load(fs: &FsInfo, bytenr: u64) -> Result<NodeView> {
+        let block = load_virt_block_unverified(fs, bytenr)?;
+        let mut state = ListState::default();
+        if !node_entries(&block).is_empty() {
+            state.select(Some(0));
+        }
+        Ok(NodeView {
+            bytenr,
+            block,
+            state,
+        })
+    }
+
+    fn entries(&self) -> Vec<NodeEntry<'_>> {
+        node_entries(&self.block)
+    }
+
+    fn header(&self) -> &btrfs_header {
+        unsafe { &*(self.block.as_ptr() as *const btrfs_header) }
+    }
+
+    fn title(&self, fs: &FsInfo) -> String {
+        let header = self.header();
+        let level = header.level;
+        let owner = header.owner;
+        let generation = header.generation;
+        let nritems = header.nritems;
+        let bytenr = header.bytenr;
+        let csum_ok =
+            header.csum == csum_data(&self.block[BTRFS_CSUM_SIZE..], fs.master_sb.csum_type);
+        let mut title = format!(
+            " {} level {level} owner {} gen {generation} nritems {nritems} csum {} ",
+            self.bytenr,
+            fmt_treeid(owner),
+            if csum_ok { "ok" } else { "BAD" }
+        );
+        if bytenr != self.bytenr {
+            title.push_str(&format!("(header bytenr {bytenr}!) "));
+        }
+        if nritems as usize != self.entries().len() {
+            title.push_str("(nritems overflows block!) ");
+        }
+        title
+    }
+
+    fn selected(&self) -> Option<NodeEntry<'_>> {
+        self.entries().into_iter().nth(self.state.selected()?)
+    }
+}
+
+struct Browser<'a> {
+    fs: &'a FsInfo,
+    trees: Vec<(String, u64)>,
+    tree_state: ListState,
+    stack: Vec<NodeView>,
+    whole_block: bool,
+    hex_scroll: u16,
+    status: String,
+}
+
+fn move_selection(state: &mut ListState, len: usize, delta: isize) {
+    if len == 0 {
+        return;
+    }
+    let cur = state.selected().unwrap_or(0) as isize;
+    let next = (cur + delta).clamp(0, len as isize - 1);
+    state.select(Some(next as usize));
+}
+
+impl<'a> Browser<'a> {
+    fn new(fs: &'a FsInfo) -> Browser<'a> {
+        let mut tree_state = ListState::default();
+        tree_state.select(Some(0));
+        Browser {
+            fs,
+            trees: list_trees(fs),
+            tree_state,
+            stack: Vec::new(),
+            whole_block: false,
+            hex_scroll: 0,
+            status: String::new(),
+        }
+    }
+
+    fn descend(&mut self) {
+        let target = match self.stack.last() {
+            None => self
+                .tree_state
+                .selected()
+                .and_then(|i| self.trees.get(i))
+                .map(|t| t.1),
+            Some(node) => match node.selected() {
+                Some(NodeEntry::Ptr(ptr)) => Some(ptr.blockptr),
+                _ => None,
+            },
+        };
+        let Some(bytenr) = target else {
+            return;
+        };
+        match NodeView::load(self.fs, bytenr) {
+            Result::Ok(node) => {
+                self.stack.push(node);
+                self.hex_scroll = 0;
+                self.status.clear();
+            }
+            Err(e) => self.status = format!("cannot load block {bytenr}: {e}"),
+        }
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        match self.stack.last_mut() {
+            None => move_selection(&mut self.tree_state, self.trees.len(), delta),
+            Some(node) => {
+                let len = node.entries().len();
+                move_selection(&mut node.state, len, delta)
+            }
+        }
+        self.hex_scroll = 0;
+    }
+
+    fn details(&self) -> (Vec<String>, Vec<String>) {
+        let Some(node) = self.stack.last() else {
+            return (
+                vec![String::from("select a tree and press enter")],
+                Vec::new(),
+            );
+        };
+        if self.whole_block {
+            return (
+                vec![format!("whole block {}", node.bytenr)],
+                hexdump_lines(&node.block, 0),
+            );
+        }
+        let header_size = std::mem::size_of::<btrfs_header>();
+        match node.selected() {
+            None => (vec![String::from("empty node")], Vec::new()),
+            Some(NodeEntry::Ptr(ptr)) => {
+                let key = ptr.key;
+                let blockptr = ptr.blockptr;
+                let generation = ptr.generation;
+                let slot = node.state.selected().unwrap_or(0);
+                let start = header_size + slot * std::mem::size_of::<btrfs_key_ptr>();
+                let end = start + std::mem::size_of::<btrfs_key_ptr>();
+                (
+                    vec![
+                        format!("key {key:?}"),
+                        format!("blockptr {blockptr} generation {generation}"),
+                    ],
+                    hexdump_lines(&node.block[start..end], start as u64),
+                )
+            }
+            Some(NodeEntry::Item(item, data)) => {
+                let key = item.key;
+                let offset = item.offset;
+                let size = item.size;
+                let mut lines = vec![
+                    format!("key {key:?}"),
+                    format!("data offset {offset} size {size}"),
+                ];
+                match data {
+                    Some(data) => {
+                        lines.extend(describe_item(&key, data));
+                        let start = header_size as u64 + offset as u64;
+                        (lines, hexdump_lines(data, start))
+                    }
+                    None => {
+                        lines.push(String::from("item data lies outside the block"));
+                        (lines, Vec::new())
+                    }
+                }
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(45), Constraint::Percentage(55)])
+                .areas(main);
+        let [decoded, hex] =
+            Layout::vertical([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(right);
+
+        let highlight = Style::default().add_modifier(Modifier::REVERSED);
+        let (details, hexdump) = self.details();
+        match self.stack.last_mut() {
+            None => {
+                let items: Vec<ListItem> = self
+                    .trees
+                    .iter()
+                    .map(|(name, bytenr)| ListItem::new(format!("{name} @ {bytenr}")))
+                    .collect();
+                let list = List::new(items)
+                    .block(Block::default().borders(Borders::ALL).title(" trees "))
+                    .highlight_style(highlight);
+                frame.render_stateful_widget(list, left, &mut self.tree_state);
+            }
+            Some(node) => {
+                let title = node.title(self.fs);
+                let items: Vec<ListItem> = node
+                    .entries()
+                    .iter()
+                    .enumerate()
+                    .map(|(slot, entry)| match entry {
+                        NodeEntry::Ptr(ptr) => {
+                            let key = ptr.key;
+                            let blockptr = ptr.blockptr;
+                            ListItem::new(format!("{slot:>3} {key:?} -> {blockptr}"))
+                        }
+                        NodeEntry::Item(item, data) => {
+                            let key = item.key;
+                            let size = item.size;
+                            let flag = if data.is_none() { " !" } else { "" };
+                            ListItem::new(format!("{slot:>3} {key:?} [{size}]{flag}"))
+                        }
+                    })
+                    .collect();
+                let list = List::new(items)
+                    .block(Block::default().borders(Borders::ALL).title(title))
+                    .highlight_style(highlight);
+                frame.render_stateful_widget(list, left, &mut node.state);
+            }
+        }
+
+        frame.render_widget(
+            Paragraph::new(details.join("\n"))
+                .block(Block::default().borders(Borders::ALL).title(" decoded ")),
+            decoded,
+        );
+        frame.render_widget(
+            Paragraph::new(hexdump.join("\n"))
+                .scroll((self.hex_scroll, 0))
+                .block(Block::default().borders(Borders::ALL).title(" hex ")),
+            hex,
+        );
+        let help = "enter: open  backspace: up  x: whole block  [/]: scroll hex  q: quit";
+        let status_line = if self.status.is_empty() {
+            String::from(help)
+        } else {
+            format!("{}  ({help})", self.status)
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Esc | KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => {
+                    if self.stack.pop().is_none() {
+                        return Ok(());
+                    }
+                    self.hex_scroll = 0;
+                    self.status.clear();
+                }
+                KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => self.descend(),
+                KeyCode::Up | KeyCode::Char('k') => self.move_by(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.move_by(1),
+                KeyCode::PageUp => self.move_by(-20),
+                KeyCode::PageDown => self.move_by(20),
+                KeyCode::Home => self.move_by(isize::MIN / 2),
+                KeyCode::End => self.move_by(isize::MAX / 2),
+                KeyCode::Char('x') => {
+                    self.whole_block = !self.whole_block;
+                    self.hex_scroll = 0;
+                }
+                KeyCode::Char('[') => self.hex_scroll = self.hex_scroll.saturating_sub(8),
+                KeyCode::Char(']') => self.hex_scroll = self.hex_scroll.saturating_add(8),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// run the interactive browser until the user quits
+pub fn browse(fs: &FsInfo) -> Result<()> {
+    let mut browser = Browser::new(fs);
+    let mut terminal = ratatui::init();
+    let result = browser.run(&mut terminal);
+    ratatui::restore();
+    result
+}
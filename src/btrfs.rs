@@ -1,52 +1,54 @@
+//! btrfs-kit is a library that provides tools to help with recovery of
+//! corrupted btrfs filesystems.
+//!
+//! btrfsprogs does quite a lot of work when opening a btrfs filesystem.
+//! It uses libblkid to scan devices and identify those that are part of
+//! the same filesystem then performs a lot of checks on the validity of
+//! the superblock.
+//!
+//! This programme does little of this, requiring the user to provide a list
+//! of devices (or, with load_fs_scan, one of them and a directory to find
+//! the others in), and relies on the superblock already being known to be
+//! valid.
+//!
+//! btrfs_new_fs_info
+//! btrfs_scan_fs_devices
+//! btrfs_open_devices
+//! btrfs_read_dev_super
+//! sbread
+//! btrfs_check_super
+
+use crate::address::all_chunks;
+use crate::block_cache::{block_cache_size, BlockCache};
+use crate::cancel::CancellationToken;
 use crate::dump::fmt_treeid;
+use crate::edit::super_mirrors;
+use crate::flags::{fmt_block_group_type, unsupported_features};
+use crate::items::item_as;
 use crate::mapped_file::MappedFile;
+use crate::parse::parse_device;
+use crate::partition::*;
+use crate::quarantine::Quarantine;
+use crate::space::stripe_length;
 use crate::structures::*;
 use crate::tree::*;
+use crate::zoned::{device_zone_size, zoned_super_copies};
 use anyhow::*;
 use crc::{Crc, CRC_32_ISCSI};
 use log::*;
 use more_asserts::*;
-use std::collections::HashMap;
-use std::io::Read;
-use std::path::PathBuf;
-use std::rc::Rc;
-
-/// btrfs-kit is a library that provides tools to help with recovery of
-/// corrupted btrfs filesystems.
-///
-/// btrfsprogs does quite a lot of work when opening a btrfs filesystem.
-/// It uses libblkid to scan devices and identify those that are part of
-/// the same filesystem then performs a lot of checks on the validity of
-/// the superblock.
-///
-/// This programme does none of this, requiring the user to provide a list
-/// of devices, and relies on the superblock already being known to be
-/// valid.
-///
-/// btrfs_new_fs_info
-/// btrfs_scan_fs_devices
-/// btrfs_open_devices
-/// btrfs_read_dev_super
-/// sbread
-/// btrfs_check_super
-
-fn load_sb_at(mf: &MappedFile, offset: usize) -> Result<btrfs_super_block> {
-    let sb = mf.at::<btrfs_super_block>(offset);
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+pub(crate) fn load_sb_at(mf: &MappedFile, offset: usize) -> Result<btrfs_super_block> {
+    let sb = mf.at::<btrfs_super_block>(offset)?;
 
     if sb.magic != BTRFS_MAGIC {
         return Err(anyhow!("invalid magic in block"));
     }
-    unsafe {
-        let ptr: *const btrfs_super_block = sb;
-        let ptr_u8 = ptr as *const u8;
-        let slice = std::slice::from_raw_parts(
-            ptr_u8.add(BTRFS_CSUM_SIZE),
-            BTRFS_SUPER_INFO_SIZE - BTRFS_CSUM_SIZE,
-        );
-        if csum_data(slice, sb.csum_type) != sb.csum {
-            return Err(anyhow!("invalid checksum in superblock"));
-        }
-    }
+    let sb = &verify_sb_csum(mf, &mf.slice(offset, BTRFS_SUPER_INFO_SIZE)?)?;
 
     if sb.total_bytes == 0 {
         return Err(anyhow!("zero length filesystem"));
@@ -71,31 +73,138 @@ fn load_sb_at(mf: &MappedFile, offset: usize) -> Result<btrfs_super_block> {
     Ok(*sb)
 }
 
+const CSUM_TYPE_OFFSET: usize = std::mem::offset_of!(btrfs_super_block, csum_type);
+
+/// the superblock in bytes, once its checksum is verified. A damaged
+/// csum_type would fail the check however sound the rest is, so each
+/// checksum type csum_data implements is tried in its place against the
+/// superblock's checksum, which covers csum_type too. If csum_type isn't
+/// even a known type and none matches, the superblock is damaged elsewhere
+/// too, and the type the chunk root's checksum matches is taken so that
+/// the trees can still be read, with a warning.
+fn verify_sb_csum(mf: &MappedFile, bytes: &[u8]) -> Result<btrfs_super_block> {
+    let mut bytes = bytes.to_vec();
+    let raw = u16::from_le_bytes([bytes[CSUM_TYPE_OFFSET], bytes[CSUM_TYPE_OFFSET + 1]]);
+    let stated = csum_type_from_raw(raw);
+    let with_csum_type = |bytes: &mut [u8], csum_type: BtrfsCsumType| {
+        bytes[CSUM_TYPE_OFFSET..CSUM_TYPE_OFFSET + 2]
+            .copy_from_slice(&(csum_type as u16).to_le_bytes());
+        // csum_type is valid now, and the struct is packed
+        unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const btrfs_super_block) }
+    };
+    let candidates = CSUM_TYPES.into_iter().filter(|&t| csum_implemented(t));
+    for csum_type in stated.into_iter().chain(candidates.clone()) {
+        if !csum_implemented(csum_type) {
+            continue;
+        }
+        let sb = with_csum_type(&mut bytes, csum_type);
+        if csum_data(&bytes[BTRFS_CSUM_SIZE..], csum_type) != sb.csum {
+            continue;
+        }
+        if Some(csum_type) != stated {
+            warn!(
+                "superblock csum_type {raw} is damaged, but its checksum is {csum_type:?}, so taking it as {csum_type:?}"
+            );
+        }
+        return Ok(sb);
+    }
+    match stated {
+        Some(csum_type) if !csum_implemented(csum_type) => {
+            bail!("{csum_type:?} checksums are not implemented")
+        }
+        Some(_) => bail!("invalid checksum in superblock"),
+        None => {}
+    }
+    for csum_type in candidates {
+        let sb = with_csum_type(&mut bytes, csum_type);
+        if chunk_root_csum_matches(mf, &sb) {
+            warn!(
+                "superblock csum_type {raw} is unknown and its checksum matches no type, but the chunk root's is {csum_type:?}: taking the superblock as {csum_type:?}, though it may be damaged elsewhere"
+            );
+            return Ok(sb);
+        }
+    }
+    bail!("invalid checksum in superblock, whose csum_type {raw} is unknown")
+}
+
+/// whether the chunk root, if it lies on this device as the superblock's
+/// system chunks map it, has a good checksum of the superblock's type
+fn chunk_root_csum_matches(mf: &MappedFile, sb: &btrfs_super_block) -> bool {
+    let (logical, devid, nodesize) = (sb.chunk_root, sb.dev_item.devid, sb.nodesize as usize);
+    let array_size = sb.sys_chunk_array_size as usize;
+    if array_size == 0 || array_size > BTRFS_SYSTEM_CHUNK_ARRAY_SIZE || nodesize < BTRFS_CSUM_SIZE {
+        return false;
+    }
+    for ChunkInfo(key, chunk, stripes) in SysChunkIter::new(sb) {
+        let start = key.offset;
+        if logical < start || logical - start >= chunk.length {
+            continue;
+        }
+        for stripe in stripes.iter().filter(|stripe| stripe.devid == devid) {
+            let Some(physical) = stripe.offset.checked_add(logical - start) else {
+                continue;
+            };
+            let Result::Ok(block) = mf.checked_read(physical as usize, nodesize) else {
+                continue;
+            };
+            let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+            if header.bytenr == logical
+                && header.csum == csum_data(&block[BTRFS_CSUM_SIZE..], sb.csum_type)
+            {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// every superblock copy which fits on the device with its offset, each
+/// checked as load_sb_at does, and for a bytenr which is its offset
+pub fn super_copies(mf: &MappedFile) -> Vec<(u64, Result<btrfs_super_block>)> {
+    if let Some(zone_size) = device_zone_size(mf) {
+        return zoned_super_copies(mf, zone_size);
+    }
+    super_mirrors(mf.len() as u64)
+        .into_iter()
+        .map(|physical| {
+            debug!("reading superblock at {physical}");
+            let sb = load_sb_at(mf, physical as usize).and_then(|sb| {
+                let bytenr = sb.bytenr;
+                ensure!(bytenr == physical, "bytenr {bytenr} is not its offset");
+                Ok(sb)
+            });
+            (physical, sb)
+        })
+        .collect()
+}
+
 /* read all superblocks in mapped file, then choose the one with the highest generation (as only one is updated at a time on ssds) */
-fn load_sb(mf: &MappedFile) -> Result<btrfs_super_block> {
+pub(crate) fn load_sb(mf: &MappedFile) -> Result<btrfs_super_block> {
     assert_ge!(mf.len(), BTRFS_SUPER_INFO_OFFSET + BTRFS_SUPER_INFO_SIZE);
-    let mut master_sb = load_sb_at(mf, BTRFS_SUPER_INFO_OFFSET)?;
-
-    for mirror in 1..BTRFS_SUPER_MIRROR_MAX {
-        let next_sb_offset = 0x4000 << (BTRFS_SUPER_MIRROR_SHIFT * mirror);
-        debug!("reading superblock at {next_sb_offset}");
-        if mf.len() >= next_sb_offset + BTRFS_SUPER_INFO_SIZE {
-            let sb = load_sb_at(mf, next_sb_offset);
-            match sb {
-                Result::Err(e) => println!("superblock #{} invalid: {}", mirror + 1, e),
-                Result::Ok(s) => {
-                    if s.generation > master_sb.generation {
-                        let sg = s.generation;
-                        let msg = master_sb.generation;
-                        debug!("sb #{} had higher generation {} vs {}", mirror + 1, sg, msg);
-                        master_sb = s;
-                    }
-                }
+    let mut copies = super_copies(mf);
+    let newest = copies
+        .iter()
+        .filter_map(|(_, sb)| sb.as_ref().ok())
+        .map(|sb| sb.generation)
+        .max();
+    let Some(newest) = newest else {
+        return Err(copies.swap_remove(0).1.unwrap_err());
+    };
+    let mut master_sb = None;
+    for (mirror, (physical, sb)) in copies.into_iter().enumerate() {
+        match sb {
+            Err(e) => warn!("superblock #{} at {physical} invalid: {e}", mirror + 1),
+            Result::Ok(sb) if sb.generation < newest => warn!(
+                "superblock #{} at {physical} is stale, at generation {} of {newest}",
+                mirror + 1,
+                { sb.generation }
+            ),
+            Result::Ok(sb) => {
+                master_sb.get_or_insert(sb);
             }
         }
     }
-
-    Ok(master_sb)
+    Ok(master_sb.unwrap())
 }
 
 pub struct SysChunkIter<'a> {
@@ -104,7 +213,7 @@ pub struct SysChunkIter<'a> {
 }
 
 impl SysChunkIter<'_> {
-    pub fn new(sb: &btrfs_super_block) -> SysChunkIter {
+    pub fn new(sb: &btrfs_super_block) -> SysChunkIter<'_> {
         SysChunkIter {
             cursor: std::io::Cursor::<&[u8]>::new(&sb.sys_chunk_array),
             size: sb.sys_chunk_array_size as u64,
@@ -154,6 +263,32 @@ pub fn csum_data(buf: &[u8], csum_type: BtrfsCsumType) -> BtrfsCsum {
     }
 }
 
+pub const CSUM_TYPES: [BtrfsCsumType; 4] = [
+    BtrfsCsumType::CRC32,
+    BtrfsCsumType::XXHASH,
+    BtrfsCsumType::SHA256,
+    BtrfsCsumType::BLAKE2,
+];
+
+/// the checksum type csum_type is on disk, None if it's none of them
+pub fn csum_type_from_raw(csum_type: u16) -> Option<BtrfsCsumType> {
+    CSUM_TYPES.into_iter().find(|&t| t as u16 == csum_type)
+}
+
+/// whether csum_data can compute checksums of a type
+pub fn csum_implemented(csum_type: BtrfsCsumType) -> bool {
+    csum_type == BtrfsCsumType::CRC32
+}
+
+/// number of bytes of a BtrfsCsum used by a checksum type
+pub fn csum_size(csum_type: BtrfsCsumType) -> usize {
+    match csum_type {
+        BtrfsCsumType::CRC32 => 4,
+        BtrfsCsumType::XXHASH => 8,
+        BtrfsCsumType::SHA256 | BtrfsCsumType::BLAKE2 => 32,
+    }
+}
+
 fn csum_data_crc32(buf: &[u8]) -> [u8; BTRFS_CSUM_SIZE] {
     const CASTAGNOLI: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
     let mut ret = [0_u8; BTRFS_CSUM_SIZE];
@@ -164,78 +299,978 @@ fn csum_data_crc32(buf: &[u8]) -> [u8; BTRFS_CSUM_SIZE] {
 
 pub struct DeviceInfo {
     pub path: PathBuf,
+    /// where the filesystem starts within the file, e.g. the start of its
+    /// partition in a disk image. Physical offsets count from here.
+    pub offset: u64,
     pub file: MappedFile,
     pub devid: LE64,
     pub dev_uuid: BtrfsUuid,
+    /// the device belongs to a seed the filesystem was sprouted from, so
+    /// it is only ever read
+    pub seed: bool,
+    /// the superblock was made up from the geometry the user gave (see
+    /// geometry.rs), so nothing may be written
+    pub sb_guessed: bool,
+    /// how many generations the device's own superblock is behind the
+    /// filesystem's, which is 0 unless it missed commits
+    pub generations_behind: u64,
+    /// the size of its zones if it is zoned, when its superblocks are logs
+    /// in zone pairs and nothing is written in place (see zoned.rs)
+    pub zone_size: Option<u64>,
+}
+
+impl DeviceInfo {
+    /// length bytes at physical, an error rather than SIGBUS if they can't
+    /// be read
+    pub fn read_at(&self, physical: u64, length: usize) -> Result<Vec<u8>> {
+        self.file
+            .checked_read(physical as usize, length)
+            .with_context(|| format!("devid {} ({})", self.devid, self.path.display()))
+    }
+
+    /// a seed's devices are shared by every filesystem sprouted from it, and
+    /// are never written, nor are devices whose superblock was made up
+    pub fn ensure_writable(&self) -> Result<()> {
+        ensure!(
+            !self.seed,
+            "devid {} ({}) belongs to a seed, which is read-only",
+            self.devid,
+            self.path.display()
+        );
+        ensure!(
+            !self.sb_guessed,
+            "devid {} ({}) was loaded from a made up superblock, and is only read",
+            self.devid,
+            self.path.display()
+        );
+        ensure!(
+            self.zone_size.is_none(),
+            "devid {} ({}) is zoned, and its zones are only written sequentially, so nothing is rewritten in place",
+            self.devid,
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    /// write data at physical and sync it. Every write to the devices goes
+    /// through here: not to seeds and the like (see ensure_writable), nor
+    /// when the trees were read at an older generation, nor once fs has
+    /// been cancelled before its first write.
+    pub fn write_at(&self, fs: &FsInfo, physical: u64, data: &[u8]) -> Result<()> {
+        self.ensure_writable()?;
+        if let Some(generation) = fs.pinned_generation {
+            bail!("the trees were read at generation {generation}, so nothing is written");
+        }
+        ensure!(
+            physical.saturating_add(data.len() as u64) <= self.file.len() as u64,
+            "{} bytes at {physical} run past the end of devid {} ({})",
+            data.len(),
+            self.devid,
+            self.path.display()
+        );
+        fs.cancel.begin_writes()?;
+        let path = &self.path;
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("cannot open {} for writing", path.display()))?;
+        file.seek(SeekFrom::Start(self.offset + physical))?;
+        file.write_all(data)?;
+        file.sync_data()?;
+        Ok(())
+    }
 }
 
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ChunkInfo(pub btrfs_disk_key, pub btrfs_chunk, pub Vec<btrfs_stripe>);
 
 /// processed info about the filesystem
 pub struct FsInfo {
     pub fsid: BtrfsFsid,
-    pub devid_map: HashMap<LE64, Rc<DeviceInfo>>,
-    pub devuuid_map: HashMap<BtrfsUuid, Rc<DeviceInfo>>,
+    pub devid_map: HashMap<LE64, Arc<DeviceInfo>>,
+    pub devuuid_map: HashMap<BtrfsUuid, Arc<DeviceInfo>>,
     pub master_sb: btrfs_super_block,
     pub bootstrap_chunks: Vec<ChunkInfo>,
+    /// chunks found in the chunk tree so far, keyed by logical start
+    pub chunk_cache: RwLock<BTreeMap<u64, ChunkInfo>>,
+    /// the metadata fsids of the seeds whose devices were given, which
+    /// the tree blocks in their chunks carry
+    pub seed_fsids: Vec<BtrfsFsid>,
+    /// the chunks came from a chunk map file (see chunk_map.rs) and are all
+    /// in the chunk cache, so the chunk tree is never searched
+    pub chunk_map: bool,
+    /// stops tree walks and scans early when cancelled
+    pub cancel: CancellationToken,
+    /// the generation of the older root tree the trees are read from (see
+    /// pin_root), when they aren't the superblock's; nothing is written then
+    pub pinned_generation: Option<u64>,
+    /// the root blocks tree_root_offset has found by tree id, None for the
+    /// trees without a ROOT_ITEM; cleared when the root tree is written
+    pub root_cache: RwLock<HashMap<u64, Option<u64>>>,
+    /// tree blocks read by load_virt_block and load_verified_block
+    pub block_cache: BlockCache,
+    /// what load_virt_block does about tree blocks with bad checksums
+    pub csum_policy: CsumPolicy,
+    /// ranges known to be unreadable, which reads go around
+    pub quarantine: Quarantine,
+}
+
+/// what load_virt_block does about a tree block whose checksum doesn't match
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CsumPolicy {
+    /// fail to read it
+    #[default]
+    Strict,
+    /// log a warning and read it all the same, e.g. to walk trees whose
+    /// checksums are known to be bad after a memory fault
+    WarnOnly,
+    /// read it without checking
+    Skip,
+}
+
+impl std::str::FromStr for CsumPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(CsumPolicy::Strict),
+            "warn" => Ok(CsumPolicy::WarnOnly),
+            "skip" => Ok(CsumPolicy::Skip),
+            _ => bail!("unknown checksum policy {s:?}, expected strict, warn or skip"),
+        }
+    }
+}
+
+/// 0 Strict, 1 WarnOnly, 2 Skip
+static CSUM_POLICY: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(0);
+
+/// give the filesystems opened from now on this checksum policy, which
+/// also covers the reads made while opening them
+pub fn set_default_csum_policy(policy: CsumPolicy) {
+    CSUM_POLICY.store(policy as u8, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn default_csum_policy() -> CsumPolicy {
+    match CSUM_POLICY.load(std::sync::atomic::Ordering::Relaxed) {
+        1 => CsumPolicy::WarnOnly,
+        2 => CsumPolicy::Skip,
+        _ => CsumPolicy::Strict,
+    }
+}
+
+/// the feature flags of a filesystem; see flags.rs for their names
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Features {
+    pub compat: u64,
+    pub compat_ro: u64,
+    pub incompat: u64,
+}
+
+impl Features {
+    pub fn has_compat_ro(&self, flag: u64) -> bool {
+        self.compat_ro & flag != 0
+    }
+
+    pub fn has_incompat(&self, flag: u64) -> bool {
+        self.incompat & flag != 0
+    }
 }
 
 impl FsInfo {
-    pub fn search_node(&self, tree_root: LE64, options: &NodeSearchOption) -> BtrfsTreeIter {
+    /// the fsid stamped in tree block headers, which differs from the fsid
+    /// if the fsid was changed with the METADATA_UUID feature
+    pub fn metadata_fsid(&self) -> BtrfsFsid {
+        sb_metadata_fsid(&self.master_sb)
+    }
+
+    /// whether a tree block header's fsid is that of the filesystem or of
+    /// one of its seeds
+    pub fn is_tree_block_fsid(&self, fsid: &BtrfsFsid) -> bool {
+        *fsid == self.metadata_fsid() || self.seed_fsids.contains(fsid)
+    }
+
+    /// the devids of the filesystem's own devices, i.e. not those of seeds,
+    /// in order
+    pub fn own_devids(&self) -> Vec<u64> {
+        let mut devids: Vec<u64> = self
+            .devid_map
+            .values()
+            .filter(|dev| !dev.seed)
+            .map(|dev| dev.devid)
+            .collect();
+        devids.sort();
+        devids
+    }
+
+    /// the label, up to its first nul
+    pub fn label(&self) -> String {
+        let label = &self.master_sb.label;
+        let len = label.iter().position(|&c| c == 0).unwrap_or(label.len());
+        String::from_utf8_lossy(&label[..len]).into_owned()
+    }
+
+    /// the generation the trees are read from: the superblock's, or that of
+    /// the pinned root tree
+    pub fn generation(&self) -> u64 {
+        self.master_sb.generation
+    }
+
+    /// the superblock's feature flags
+    pub fn features(&self) -> Features {
+        let sb = &self.master_sb;
+        Features {
+            compat: sb.compat_flags,
+            compat_ro: sb.compat_ro_flags,
+            incompat: sb.incompat_flags,
+        }
+    }
+
+    /// the size of the filesystem, all its devices together
+    pub fn total_bytes(&self) -> u64 {
+        self.master_sb.total_bytes
+    }
+
+    /// the bytes allocated to extents, as the superblock counts them
+    pub fn bytes_used(&self) -> u64 {
+        self.master_sb.bytes_used
+    }
+
+    /// the checksum algorithm of the tree blocks and data
+    pub fn csum_type(&self) -> BtrfsCsumType {
+        self.master_sb.csum_type
+    }
+
+    /// the devices given, seeds included, in devid order
+    pub fn devices(&self) -> Vec<&DeviceInfo> {
+        let mut devices: Vec<&DeviceInfo> = self.devid_map.values().map(|dev| &**dev).collect();
+        devices.sort_by_key(|dev| dev.devid);
+        devices
+    }
+
+    /// the devids with a dev item in the chunk tree (or with a chunk map,
+    /// with a stripe in a chunk) which weren't given, in order
+    pub fn missing_devices(&self) -> Vec<u64> {
+        let mut devids: Vec<u64> = if self.chunk_map {
+            let chunks = self.chunk_cache.read().unwrap();
+            chunks
+                .values()
+                .flat_map(|ChunkInfo(_, _, stripes)| stripes.iter().map(|stripe| stripe.devid))
+                .collect()
+        } else {
+            let search = key_range(
+                Some(BTRFS_DEV_ITEMS_OBJECTID),
+                Some(BtrfsItemType::DEV_ITEM),
+                None,
+            );
+            search_range(self, self.master_sb.chunk_root, search)
+                .map(|(item, ..)| item.key.offset)
+                .collect()
+        };
+        devids.sort();
+        devids.dedup();
+        devids.retain(|devid| !self.devid_map.contains_key(devid));
+        devids
+    }
+
+    /// read the trees from the root tree with its root block at root, e.g.
+    /// an older one kept in a backup root or listed by `orphans`, rather
+    /// than the superblock's. Blocks outlive their generation until their
+    /// space is reused, so the trees of a recent generation can often still
+    /// be read whole. The log is dropped, as it belongs to the superblock's
+    /// generation, and nothing may be written afterwards.
+    pub fn pin_root(&mut self, root: u64) -> Result<()> {
+        let block = crate::address::load_virt_block_unverified(self, root)?;
+        let problems = crate::scrub::check_tree_block(self, &block, root, None);
+        ensure!(
+            problems.is_empty(),
+            "root tree block {root}: {}",
+            problems.join(", ")
+        );
+        let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+        let (owner, level, generation) = (header.owner, header.level, header.generation);
+        ensure!(
+            owner == BTRFS_ROOT_TREE_OBJECTID,
+            "block {root} belongs to {}, not the root tree",
+            fmt_treeid(owner)
+        );
+        let sb = &mut self.master_sb;
+        ensure!(
+            generation <= sb.generation,
+            "block {root} is of generation {generation}, newer than the superblock's {}",
+            { sb.generation }
+        );
+        sb.root = root;
+        sb.root_level = level;
+        sb.generation = generation;
+        sb.log_root = 0;
+        sb.log_root_level = 0;
+        self.pinned_generation = Some(generation);
+        self.invalidate_roots();
+        Ok(())
+    }
+
+    /// pin_root at the root tree of one of the superblock's backup roots
+    pub fn pin_backup_root(&mut self, backup: usize) -> Result<()> {
+        let roots = self.master_sb.super_roots;
+        let root = roots.get(backup).ok_or_else(|| {
+            anyhow!(
+                "backup root {backup} doesn't exist, there are {}",
+                roots.len()
+            )
+        })?;
+        ensure!(root.tree_root != 0, "backup root {backup} is unused");
+        self.pin_root(root.tree_root)
+    }
+
+    /// forget the root blocks cached by tree_root_offset, once the root tree
+    /// has been rewritten or another one pinned
+    pub fn invalidate_roots(&self) {
+        self.root_cache.write().unwrap().clear();
+    }
+
+    /// every ROOT_ITEM in the root tree, as (tree id, root item), in key
+    /// order: the trees of the filesystem, subvolumes and snapshots, and
+    /// relocation trees and global roots, which have an item apiece under
+    /// the same tree id
+    pub fn roots(&self) -> impl Iterator<Item = (u64, btrfs_root_item)> + '_ {
+        root_items_in(self, self.master_sb.root)
+    }
+
+    pub fn search_node(&self, tree_root: LE64, options: &NodeSearchOption) -> BtrfsTreeIter<'_> {
         BtrfsTreeIter::new(self, tree_root, *options)
     }
 }
 
-pub fn load_fs(paths: &Vec<PathBuf>) -> Result<FsInfo> {
-    let mut fsid = None;
-    let mut devid_map = HashMap::<LE64, Rc<DeviceInfo>>::new();
-    let mut devuuid_map = HashMap::<BtrfsUuid, Rc<DeviceInfo>>::new();
-    let mut master_sb: Option<btrfs_super_block> = None;
-    let mut initial_chunks = Vec::new();
-    for path in paths {
-        println!("checking {}", path.display());
-        let mf = MappedFile::open(path)?;
-        let sb = load_sb(&mf)?;
+/// the fsid stamped in the tree blocks of the filesystem a superblock
+/// belongs to, which is also the fsid of its dev items
+fn sb_metadata_fsid(sb: &btrfs_super_block) -> BtrfsFsid {
+    let incompat = sb.incompat_flags;
+    if incompat & BTRFS_FEATURE_INCOMPAT_METADATA_UUID != 0 {
+        sb.metadata_uuid
+    } else {
+        sb.fsid
+    }
+}
 
-        match fsid {
-            None => fsid = Some(sb.fsid),
-            Some(f) => assert_eq!(sb.fsid, f),
-        };
-        assert_eq!(sb.dev_item.fsid, fsid.unwrap());
-        if let Some(prev_sb) = master_sb {
-            let prev_num_devices = prev_sb.num_devices;
-            let num_devices = sb.num_devices;
-            assert_eq!(prev_num_devices, num_devices);
+/// open a device argument, a path or path@offset. A filesystem at the start
+/// of a partition is only mapped to the partition's end, so that no
+/// superblock mirror is taken from the partition after it.
+pub(crate) fn open_device(arg: &Path) -> Result<(PathBuf, u64, MappedFile)> {
+    let (path, offset) = parse_device(arg)?;
+    let length = match offset {
+        0 => None,
+        _ => read_partitions(&path)?
+            .into_iter()
+            .find(|partition| partition.start == offset)
+            .map(|partition| partition.length),
+    };
+    let mf = MappedFile::open_range(&path, offset, length)
+        .with_context(|| format!("cannot open {}", arg.display()))?;
+    Ok((path, offset, mf))
+}
+
+/// the superblock of a device, or if a whole disk image was given, an error
+/// naming its btrfs partitions as they can be given instead
+fn load_device_sb(
+    arg: &Path,
+    path: &Path,
+    offset: u64,
+    mf: &MappedFile,
+) -> Result<btrfs_super_block> {
+    let error = match load_sb(mf) {
+        Result::Ok(sb) => return Ok(sb),
+        Err(e) => e.context(format!("{} holds no btrfs filesystem", arg.display())),
+    };
+    if offset != 0 {
+        return Err(error);
+    }
+    let candidates: Vec<String> = btrfs_partitions(path)
+        .unwrap_or_default()
+        .iter()
+        .map(|(partition, sb)| {
+            format!(
+                "{}@{} (partition {}, fsid {}, devid {})",
+                path.display(),
+                partition.start,
+                partition.number,
+                sb.fsid,
+                { sb.dev_item.devid }
+            )
+        })
+        .collect();
+    if candidates.is_empty() {
+        return Err(error);
+    }
+    Err(error.context(format!(
+        "{} has btrfs partitions, give them instead: {}",
+        arg.display(),
+        candidates.join(", ")
+    )))
+}
+
+/// warn about a member device whose superblock isn't the newest one's, and
+/// return how many generations it is behind. A device behind missed commits,
+/// e.g. it dropped out of the array, and its copies of all written since are
+/// stale. If the newest superblock's backup roots have another root at its
+/// generation, or it is as new with other roots, the devices were written
+/// separately (split brain, e.g. each mounted degraded on its own).
+fn compare_generations(
+    path: &Path,
+    dev_sb: &btrfs_super_block,
+    newest_path: &Path,
+    sb: &btrfs_super_block,
+) -> u64 {
+    let (generation, newest) = (dev_sb.generation, sb.generation);
+    let (root, chunk_root) = (dev_sb.root, dev_sb.chunk_root);
+    let (path, newest_path) = (path.display(), newest_path.display());
+    if generation == newest {
+        if root != sb.root || chunk_root != sb.chunk_root {
+            warn!(
+                "{path} and {newest_path} are both at generation {generation}, but with other roots: \
+                 the devices were written separately (split brain), and which holds the filesystem \
+                 wanted can't be told. {newest_path} is used; don't repair from {path}"
+            );
         }
+        return 0;
+    }
+    let backup = sb
+        .super_roots
+        .iter()
+        .find(|backup| backup.tree_root_gen == generation);
+    if backup.is_some_and(|backup| backup.tree_root != root || backup.chunk_root != chunk_root) {
+        warn!(
+            "{path} at generation {generation} has other roots than {newest_path} had then: the \
+             devices were written separately (split brain, e.g. each mounted degraded alone). \
+             Treat {newest_path} at generation {newest} as authoritative, and don't repair from {path}"
+        );
+    } else {
+        warn!(
+            "{path} is {} generations behind: at {generation}, where {newest_path} is at {newest}. \
+             It missed those commits (e.g. it dropped out of the array), so its copies of what was \
+             written since are stale. Treat {newest_path} as authoritative, and don't repair from {path}",
+            newest - generation
+        );
+    }
+    newest - generation
+}
 
-        let di = Rc::new(DeviceInfo {
-            path: path.clone(),
-            file: mf,
-            devid: sb.dev_item.devid,
-            dev_uuid: sb.dev_item.uuid,
+/// a device's superblock, as MixedFilesystems lists it
+#[derive(Clone, Debug)]
+pub struct DeviceFsid {
+    pub path: PathBuf,
+    pub fsid: BtrfsFsid,
+    pub label: String,
+    pub generation: u64,
+    pub seeding: bool,
+}
+
+/// the error load_fs returns for devices of more than one filesystem (not
+/// counting seeds). The filesystem is taken to be the one of most of the
+/// devices, or of the newest if as many belong to each.
+#[derive(Debug)]
+pub struct MixedFilesystems {
+    pub devices: Vec<DeviceFsid>,
+    pub fsid: BtrfsFsid,
+}
+
+impl MixedFilesystems {
+    fn new<'a>(devices: impl Iterator<Item = (&'a Path, &'a btrfs_super_block)>) -> Self {
+        let devices: Vec<DeviceFsid> = devices
+            .map(|(path, sb)| DeviceFsid {
+                path: path.to_path_buf(),
+                fsid: sb.fsid,
+                label: sb.label_str(),
+                generation: sb.generation,
+                seeding: sb.flags & BTRFS_SUPER_FLAG_SEEDING != 0,
+            })
+            .collect();
+        let fsid = devices
+            .iter()
+            .filter(|dev| !dev.seeding)
+            .max_by_key(|dev| {
+                let members = devices.iter().filter(|d| d.fsid == dev.fsid).count();
+                (members, dev.generation)
+            })
+            .unwrap()
+            .fsid;
+        MixedFilesystems { devices, fsid }
+    }
+
+    /// the devices of other filesystems than fsid which aren't seeds
+    pub fn strays(&self) -> impl Iterator<Item = &DeviceFsid> {
+        self.devices
+            .iter()
+            .filter(|dev| dev.fsid != self.fsid && !dev.seeding)
+    }
+}
+
+impl std::fmt::Display for MixedFilesystems {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut fsids: Vec<BtrfsFsid> = self.devices.iter().map(|dev| dev.fsid).collect();
+        fsids.sort();
+        fsids.dedup();
+        writeln!(
+            f,
+            "the devices belong to {} different filesystems:",
+            fsids.len()
+        )?;
+        for dev in &self.devices {
+            writeln!(
+                f,
+                "    {}: fsid {} label {:?} generation {}{}{}",
+                dev.path.display(),
+                dev.fsid,
+                dev.label,
+                dev.generation,
+                if dev.seeding { " (seed)" } else { "" },
+                if dev.fsid != self.fsid && !dev.seeding {
+                    " <- does not belong"
+                } else {
+                    ""
+                }
+            )?;
+        }
+        let strays: Vec<String> = self
+            .strays()
+            .map(|dev| dev.path.display().to_string())
+            .collect();
+        write!(
+            f,
+            "{} {} not part of filesystem {}",
+            strays.join(", "),
+            if strays.len() == 1 { "is" } else { "are" },
+            self.fsid
+        )
+    }
+}
+
+impl std::error::Error for MixedFilesystems {}
+
+/// load the filesystem on the devices at paths. Normally they all share one
+/// fsid, but a filesystem sprouted from a seed is made of its own devices
+/// and those of the seed (and of the seed's seeds), which keep their fsid
+/// and have the SEEDING flag set. The filesystem is then the one whose
+/// devices aren't seeding, or if all are, the newest; the seed devices are
+/// only read, for the chunks on them.
+pub fn load_fs(paths: &[PathBuf]) -> Result<FsInfo> {
+    load_fs_mapped(paths, &[])
+}
+
+/// like load_fs, but each (devid, path) of devid_paths is that devid
+/// whatever its dev item says, e.g. for a clone whose dev item is wrong, or
+/// to choose between two clones of one device. It takes the place of a path
+/// given for the same devid, and the path needn't be in paths too. The
+/// device's dev uuid is then the one its devid's dev item records.
+pub fn load_fs_mapped(paths: &[PathBuf], devid_paths: &[(u64, PathBuf)]) -> Result<FsInfo> {
+    let real = |p: &PathBuf| std::fs::canonicalize(p).unwrap_or_else(|_| p.clone());
+    let mapped: Vec<PathBuf> = devid_paths.iter().map(|(_, p)| real(p)).collect();
+    let args = paths
+        .iter()
+        .filter(|p| !mapped.contains(&real(p)))
+        .map(|p| (None, p))
+        .chain(devid_paths.iter().map(|(devid, p)| (Some(*devid), p)));
+    let mut devices = Vec::new();
+    for (devid, arg) in args {
+        eprintln!("checking {}", arg.display());
+        let (path, offset, mf) = open_device(arg)?;
+        let sb = load_device_sb(arg, &path, offset, &mf)?;
+        // the dev item carries the metadata's fsid, which differs under the
+        // METADATA_UUID feature
+        let dev_item_fsid = sb.dev_item.fsid;
+        ensure!(
+            dev_item_fsid == sb_metadata_fsid(&sb),
+            "{}: dev item fsid {dev_item_fsid} is not the filesystem's",
+            arg.display()
+        );
+        devices.push(((path, offset, devid), mf, sb));
+    }
+    ensure!(!devices.is_empty(), "no devices given");
+    // a device mapped to a devid displaces any other device with that devid
+    for (devid, path) in devid_paths {
+        devices.retain(|((other, _, mapped), _, sb)| {
+            let displaced = mapped.is_none() && { sb.dev_item.devid } == *devid;
+            if displaced {
+                warn!(
+                    "using {} as devid {devid}, not {}",
+                    path.display(),
+                    other.display()
+                );
+            }
+            !displaced
         });
-        devid_map.insert(di.devid, Rc::clone(&di));
-        devuuid_map.insert(di.dev_uuid, Rc::clone(&di));
-        master_sb = Some(sb);
-        if initial_chunks.is_empty() {
-            for ci in SysChunkIter::new(&sb) {
-                initial_chunks.push(ci);
+    }
+
+    let seeding = |sb: &btrfs_super_block| sb.flags & BTRFS_SUPER_FLAG_SEEDING != 0;
+    let mut sprouts = Vec::new();
+    for (_, _, sb) in devices.iter().filter(|(_, _, sb)| !seeding(sb)) {
+        if !sprouts.contains(&sb.fsid) {
+            sprouts.push(sb.fsid);
+        }
+    }
+    let fsid = match sprouts.len() {
+        0 => {
+            devices
+                .iter()
+                .max_by_key(|(_, _, sb)| sb.generation)
+                .unwrap()
+                .2
+                .fsid
+        }
+        1 => sprouts[0],
+        _ => {
+            let devices = devices
+                .iter()
+                .map(|((path, ..), _, sb)| (path.as_path(), sb));
+            return Err(MixedFilesystems::new(devices).into());
+        }
+    };
+    // every commit writes the superblocks of all devices, so the newest is
+    // the filesystem's and one behind it missed commits
+    let ((newest_path, newest_offset, _), _, sb) = devices
+        .iter()
+        .filter(|(_, _, sb)| sb.fsid == fsid)
+        .reduce(|newest, dev| {
+            if dev.2.generation > newest.2.generation {
+                dev
+            } else {
+                newest
+            }
+        })
+        .unwrap();
+    let (newest, sb) = ((newest_path.clone(), *newest_offset), *sb);
+
+    let mut devid_map = HashMap::<LE64, Arc<DeviceInfo>>::new();
+    let mut devuuid_map = HashMap::<BtrfsUuid, Arc<DeviceInfo>>::new();
+    let mut seed_fsids = Vec::new();
+    let mut remapped = Vec::new();
+    let mut dev_sizes = Vec::new();
+    for ((path, offset, mapped), mf, dev_sb) in devices {
+        let seed = dev_sb.fsid != fsid;
+        let mut generations_behind = 0;
+        if seed {
+            ensure!(
+                seeding(&dev_sb),
+                "{} belongs to filesystem {}, which is not a seed",
+                path.display(),
+                dev_sb.fsid
+            );
+            let seed_fsid = sb_metadata_fsid(&dev_sb);
+            if !seed_fsids.contains(&seed_fsid) {
+                seed_fsids.push(seed_fsid);
             }
+        } else if (&path, offset) != (&newest.0, newest.1) {
+            generations_behind = compare_generations(&path, &dev_sb, &newest.0, &sb);
+            let (num_devices, dev_num_devices) = (sb.num_devices, dev_sb.num_devices);
+            ensure!(
+                num_devices == dev_num_devices || generations_behind > 0,
+                "{} counts {dev_num_devices} devices, another {num_devices}",
+                path.display()
+            );
         }
+        let zone_size = device_zone_size(&mf);
+        let di = Arc::new(DeviceInfo {
+            path,
+            offset,
+            file: mf,
+            devid: mapped.unwrap_or(dev_sb.dev_item.devid),
+            dev_uuid: dev_sb.dev_item.uuid,
+            seed,
+            sb_guessed: false,
+            generations_behind,
+            zone_size,
+        });
+        if mapped.is_some_and(|devid| devid != dev_sb.dev_item.devid) {
+            remapped.push(di.devid);
+        }
+        dev_sizes.push((di.devid, dev_sb.dev_item.total_bytes));
+        ensure!(
+            devid_map.insert(di.devid, Arc::clone(&di)).is_none(),
+            "devid {} is given twice",
+            di.devid
+        );
+        devuuid_map.insert(di.dev_uuid, Arc::clone(&di));
+    }
+    let initial_chunks = SysChunkIter::new(&sb).collect();
+    let csum_type = sb.csum_type as u16;
+    ensure!(
+        csum_type == BtrfsCsumType::CRC32 as u16,
+        "checksum type {csum_type} is not supported, only crc32c is implemented"
+    );
+    for problem in unsupported_features(&sb) {
+        warn!("{problem}");
     }
-    assert!(master_sb.is_some());
-    let sb = master_sb.unwrap();
 
-    Ok(FsInfo {
-        fsid: fsid.unwrap(),
+    let mut fs = FsInfo {
+        fsid,
         devid_map,
         devuuid_map,
         master_sb: sb,
         bootstrap_chunks: initial_chunks,
-    })
+        chunk_cache: RwLock::new(BTreeMap::new()),
+        seed_fsids,
+        chunk_map: false,
+        cancel: CancellationToken::new(),
+        pinned_generation: None,
+        root_cache: RwLock::new(HashMap::new()),
+        block_cache: BlockCache::new(block_cache_size(), sb.nodesize as usize),
+        csum_policy: default_csum_policy(),
+        quarantine: Quarantine::default(),
+    };
+    if !remapped.is_empty() {
+        set_mapped_dev_uuids(&mut fs, &remapped);
+    }
+    check_device_sizes(&mut fs, &dev_sizes);
+    Ok(fs)
+}
+
+/// warn about devices shorter than their dev items say, as partial images
+/// often are, and about the chunk stripes (which the dev extents mirror)
+/// running past the end of their device. What lies past the end is
+/// quarantined, so that reads there fail as lost rather than beyond the
+/// mapping. The chunk tree is only read for a device found short, as the
+/// stripes in the superblock's system chunks are all that's checked
+/// otherwise.
+fn check_device_sizes(fs: &mut FsInfo, dev_sizes: &[(u64, u64)]) {
+    let mut short = false;
+    for &(devid, total_bytes) in dev_sizes {
+        let dev = &fs.devid_map[&devid];
+        let len = dev.file.len() as u64;
+        if total_bytes > len {
+            warn!(
+                "{} looks truncated: devid {devid} is {total_bytes} bytes by its dev item, but only {len} are there",
+                dev.path.display()
+            );
+            fs.quarantine.add_physical(devid, len..total_bytes);
+            short = true;
+        }
+    }
+    let mut chunks = fs.bootstrap_chunks.clone();
+    if short {
+        let system: Vec<u64> = chunks.iter().map(|chunk| chunk.0.offset).collect();
+        chunks.extend(
+            all_chunks(fs)
+                .into_iter()
+                .filter(|chunk| !system.contains(&{ chunk.0.offset })),
+        );
+    }
+    let mut past_end = BTreeMap::<u64, (usize, u64)>::new();
+    for ChunkInfo(key, chunk, stripes) in &chunks {
+        let length = stripe_length(chunk);
+        for stripe in stripes {
+            let (devid, offset) = (stripe.devid, stripe.offset);
+            let Some(dev) = fs.devid_map.get(&devid) else {
+                continue;
+            };
+            let len = dev.file.len() as u64;
+            let end = offset.saturating_add(length);
+            if end <= len {
+                continue;
+            }
+            debug!(
+                "chunk {} ({}): stripe on devid {devid} at {offset}+{length} runs past the end ({len} bytes)",
+                { key.offset },
+                fmt_block_group_type(chunk.r#type)
+            );
+            fs.quarantine.add_physical(devid, offset.max(len)..end);
+            let (count, _) = past_end.entry(devid).or_insert((0, key.offset));
+            *count += 1;
+        }
+    }
+    for (devid, (count, first)) in past_end {
+        warn!(
+            "{count} chunk stripes on devid {devid} run past the end of {} (the first of chunk {first}), which can't be read",
+            fs.devid_map[&devid].path.display()
+        );
+    }
 }
 
+/// give the devices mapped to another devid than their dev item's the dev
+/// uuid of their devid's dev item, as the chunk stripes name them by it
+fn set_mapped_dev_uuids(fs: &mut FsInfo, devids: &[u64]) {
+    let search = key_range(
+        Some(BTRFS_DEV_ITEMS_OBJECTID),
+        Some(BtrfsItemType::DEV_ITEM),
+        None,
+    );
+    let mut uuids = HashMap::new();
+    for (_, data, ..) in search_range(fs, fs.master_sb.chunk_root, search) {
+        if let Some(dev) = item_as::<btrfs_dev_item>(&data) {
+            uuids.insert(dev.devid, dev.uuid);
+        }
+    }
+    fs.devuuid_map.clear();
+    for devid in devids {
+        let Some(&uuid) = uuids.get(devid) else {
+            warn!("the chunk tree has no dev item for devid {devid}");
+            continue;
+        };
+        let dev = fs.devid_map.remove(devid).unwrap();
+        let mut dev = Arc::into_inner(dev).unwrap();
+        dev.dev_uuid = uuid;
+        fs.devid_map.insert(*devid, Arc::new(dev));
+    }
+    for dev in fs.devid_map.values() {
+        fs.devuuid_map.insert(dev.dev_uuid, Arc::clone(dev));
+    }
+}
+
+#[cfg(unix)]
+fn is_block_device(md: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    md.file_type().is_block_device()
+}
+
+/// elsewhere only image files are scanned
+#[cfg(not(unix))]
+fn is_block_device(_md: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// the block devices and image files directly in dir with a valid primary
+/// superblock which is wanted. Symlinks are skipped, as e.g. /dev/mapper and
+/// /dev/disk only link to devices directly in /dev.
+pub fn scan_devices(
+    dir: &Path,
+    wanted: impl Fn(&btrfs_super_block) -> bool,
+) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("cannot scan {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let Result::Ok(md) = std::fs::symlink_metadata(&path) else {
+            continue;
+        };
+        if !md.is_file() && !is_block_device(&md) {
+            continue;
+        }
+        // devices which can't be opened, e.g. without permission, or are too
+        // small to hold a superblock can't be members
+        let mf = match MappedFile::open(&path) {
+            Result::Ok(mf) => mf,
+            Err(e) => {
+                debug!("skipping {}: {e}", path.display());
+                continue;
+            }
+        };
+        if mf.len() < BTRFS_SUPER_INFO_OFFSET + BTRFS_SUPER_INFO_SIZE {
+            continue;
+        }
+        if let Result::Ok(sb) = load_sb_at(&mf, BTRFS_SUPER_INFO_OFFSET) {
+            if wanted(&sb) {
+                found.push(path);
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// add the devices scan_devices finds in dir to paths, leaving out those
+/// already there under another name. Returns whether any were added.
+fn add_scanned(
+    paths: &mut Vec<PathBuf>,
+    dir: &Path,
+    wanted: impl Fn(&btrfs_super_block) -> bool,
+) -> Result<bool> {
+    let known: Vec<PathBuf> = paths
+        .iter()
+        .map(|p| std::fs::canonicalize(p).unwrap_or_else(|_| p.clone()))
+        .collect();
+    let before = paths.len();
+    for path in scan_devices(dir, wanted)? {
+        let real = std::fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if !known.contains(&real) {
+            paths.push(path);
+        }
+    }
+    Ok(paths.len() > before)
+}
+
+/// like load_fs, but adding the other members of the filesystem the paths
+/// belong to from the devices in dir. For a sprout the devices of its seeds
+/// are found too: the chunks it still has on a seed name the seed's devices
+/// by dev uuid, and once those are read its dev items give the seed's fsid.
+pub fn load_fs_scan(
+    paths: &[PathBuf],
+    dir: &Path,
+    devid_paths: &[(u64, PathBuf)],
+) -> Result<FsInfo> {
+    let mut fsids = Vec::new();
+    for arg in paths {
+        let (path, _, mf) = open_device(arg)?;
+        ensure!(
+            mf.len() >= BTRFS_SUPER_INFO_OFFSET + BTRFS_SUPER_INFO_SIZE,
+            "{} is too small to hold a superblock",
+            path.display()
+        );
+        let sb = load_sb_at(&mf, BTRFS_SUPER_INFO_OFFSET)
+            .with_context(|| format!("{} has no valid superblock", path.display()))?;
+        fsids.push(sb.fsid);
+    }
+    let mut all = paths.to_vec();
+    add_scanned(&mut all, dir, |sb| fsids.contains(&sb.fsid))?;
+
+    loop {
+        let fs = load_fs_mapped(&all, devid_paths)?;
+        let mut uuids = Vec::new();
+        for ChunkInfo(_, _, stripes) in &fs.bootstrap_chunks {
+            for stripe in stripes {
+                if !fs.devuuid_map.contains_key(&stripe.dev_uuid) {
+                    uuids.push(stripe.dev_uuid);
+                }
+            }
+        }
+        let mut seeds = Vec::new();
+        let search = key_range(
+            Some(BTRFS_DEV_ITEMS_OBJECTID),
+            Some(BtrfsItemType::DEV_ITEM),
+            None,
+        );
+        for (_, data, ..) in search_range(&fs, fs.master_sb.chunk_root, search) {
+            let Some(dev) = item_as::<btrfs_dev_item>(&data) else {
+                continue;
+            };
+            let devid = dev.devid;
+            if !fs.devid_map.contains_key(&devid) && !fs.is_tree_block_fsid(&{ dev.fsid }) {
+                seeds.push(dev.fsid);
+                uuids.push(dev.uuid);
+            }
+        }
+        let seed = |sb: &btrfs_super_block| {
+            sb.flags & BTRFS_SUPER_FLAG_SEEDING != 0
+                && (uuids.contains(&{ sb.dev_item.uuid }) || seeds.contains(&sb_metadata_fsid(sb)))
+        };
+        if uuids.is_empty() || !add_scanned(&mut all, dir, seed)? {
+            return Ok(fs);
+        }
+    }
+}
+
+/// the root tree, chunk tree and log tree are found via the superblock,
+/// other trees via their ROOT_ITEM in the root tree. Of a tree extent tree
+/// v2 splits into global roots, this is global root 0; see global_root_for.
 pub fn tree_root_offset(fs: &FsInfo, tree_id: u64) -> Option<u64> {
+    let root = fs.master_sb.root;
+    match tree_id {
+        BTRFS_ROOT_TREE_OBJECTID => return Some(root),
+        BTRFS_CHUNK_TREE_OBJECTID => return Some(fs.master_sb.chunk_root),
+        BTRFS_TREE_LOG_OBJECTID if fs.master_sb.log_root != 0 => {
+            return Some(fs.master_sb.log_root)
+        }
+        _ => {}
+    }
+    if let Some(&cached) = fs.root_cache.read().unwrap().get(&tree_id) {
+        return cached;
+    }
+    let found = find_root_item(fs, tree_id);
+    fs.root_cache.write().unwrap().insert(tree_id, found);
+    found
+}
+
+/// the root block in the first ROOT_ITEM of tree_id in the root tree
+fn find_root_item(fs: &FsInfo, tree_id: u64) -> Option<u64> {
     let root = fs.master_sb.root;
     let search = NodeSearchOption {
         min_key: btrfs_disk_key {
@@ -252,9 +1287,7 @@ pub fn tree_root_offset(fs: &FsInfo, tree_id: u64) -> Option<u64> {
         max_match: std::cmp::Ordering::Greater,
     };
 
-    if let Some((leaf, data, _block_offset, _leaf_pos)) =
-        BtrfsTreeIter::new(fs, root, search).next()
-    {
+    if let Some((leaf, data, _block_offset, _leaf_pos)) = search_range(fs, root, search).next() {
         let btrfs_disk_key {
             objectid,
             item_type,
@@ -266,7 +1299,7 @@ pub fn tree_root_offset(fs: &FsInfo, tree_id: u64) -> Option<u64> {
         assert_eq!(size as usize, std::mem::size_of::<btrfs_root_item>());
         let root_item = unsafe { &*((data.as_ptr()) as *const btrfs_root_item) };
         let tree_root = root_item.bytenr;
-        println!(
+        debug!(
             "leaf {} {item_type:?} {offset} data size {} tree root {tree_root}",
             fmt_treeid(objectid),
             size
@@ -276,6 +1309,186 @@ pub fn tree_root_offset(fs: &FsInfo, tree_id: u64) -> Option<u64> {
     None
 }
 
+/// the trees which extent tree v2 splits into global roots, each with
+/// ROOT_ITEMs keyed (tree, ROOT_ITEM, global root id), and every block group
+/// having its items in the global roots of its own id
+pub const GLOBAL_TREES: [u64; 3] = [
+    BTRFS_EXTENT_TREE_OBJECTID,
+    BTRFS_CSUM_TREE_OBJECTID,
+    BTRFS_FREE_SPACE_TREE_OBJECTID,
+];
+
+pub fn extent_tree_v2(fs: &FsInfo) -> bool {
+    fs.master_sb.incompat_flags & BTRFS_FEATURE_INCOMPAT_EXTENT_TREE_V2 != 0
+}
+
+/// the root of one global root of a global tree
+pub fn global_root_offset(fs: &FsInfo, tree_id: u64, global_id: u64) -> Option<u64> {
+    let search = key_range(
+        Some(tree_id),
+        Some(BtrfsItemType::ROOT_ITEM),
+        Some(global_id),
+    );
+    let (item, data, ..) = search_range(fs, fs.master_sb.root, search).next()?;
+    let key = item.key;
+    if (key.objectid, key.item_type, key.offset) != (tree_id, BtrfsItemType::ROOT_ITEM, global_id) {
+        return None;
+    }
+    item_as::<btrfs_root_item>(&data).map(|root_item| root_item.bytenr)
+}
+
+/// every root of a tree as (global root id, root): without extent tree v2
+/// its one root as id 0, with it those of the superblock's nr_global_roots
+/// found in the root tree
+pub fn global_roots(fs: &FsInfo, tree_id: u64) -> Vec<(u64, u64)> {
+    if !extent_tree_v2(fs) || !GLOBAL_TREES.contains(&tree_id) {
+        return tree_root_offset(fs, tree_id)
+            .map(|root| (0, root))
+            .into_iter()
+            .collect();
+    }
+    let nr_global_roots = fs.master_sb.nr_global_roots.max(1);
+    (0..nr_global_roots)
+        .filter_map(|id| {
+            let root = global_root_offset(fs, tree_id, id);
+            if root.is_none() {
+                warn!("global root {id} of {} not found", fmt_treeid(tree_id));
+            }
+            root.map(|root| (id, root))
+        })
+        .collect()
+}
+
+/// the global root id of the block group containing logical, kept in its
+/// block group item where chunk_objectid was, or 0 without extent tree v2
+pub fn global_root_id(fs: &FsInfo, logical: u64) -> u64 {
+    if !extent_tree_v2(fs) {
+        return 0;
+    }
+    let Some(root) = tree_root_offset(fs, BTRFS_BLOCK_GROUP_TREE_OBJECTID) else {
+        return 0;
+    };
+    let key = btrfs_disk_key {
+        objectid: logical,
+        item_type: BtrfsItemType::BLOCK_GROUP_ITEM,
+        offset: u64::MAX,
+    };
+    // the item at or left of the key is the block group which could hold
+    // logical
+    let Some((item, data, ..)) = BtrfsTreeIter::new(fs, root, SearchKey::floor(key)).next() else {
+        return 0;
+    };
+    let (start, length) = (item.key.objectid, item.key.offset);
+    if item.key.item_type != BtrfsItemType::BLOCK_GROUP_ITEM
+        || logical < start
+        || logical >= start + length
+    {
+        return 0;
+    }
+    item_as::<btrfs_block_group_item>(&data).map_or(0, |bg| bg.chunk_objectid)
+}
+
+/// the root of the global tree holding the items about logical; without
+/// extent tree v2 simply the tree's root
+pub fn global_root_for(fs: &FsInfo, tree_id: u64, logical: u64) -> Option<u64> {
+    if !extent_tree_v2(fs) || !GLOBAL_TREES.contains(&tree_id) {
+        return tree_root_offset(fs, tree_id);
+    }
+    global_root_offset(fs, tree_id, global_root_id(fs, logical))
+}
+
+/// every tree reachable from the superblock and the root tree
+pub fn list_trees(fs: &FsInfo) -> Vec<(String, u64)> {
+    let sb = &fs.master_sb;
+    let mut trees = vec![
+        (String::from("ROOT_TREE"), sb.root),
+        (String::from("CHUNK_TREE"), sb.chunk_root),
+    ];
+    if sb.log_root != 0 {
+        trees.push((String::from("TREE_LOG"), sb.log_root));
+    }
+    let search = NodeSearchOption {
+        min_key: btrfs_disk_key {
+            objectid: 0,
+            item_type: BtrfsItemType::MIN,
+            offset: 0,
+        },
+        max_key: btrfs_disk_key {
+            objectid: u64::MAX,
+            item_type: BtrfsItemType::MAX,
+            offset: u64::MAX,
+        },
+        min_match: std::cmp::Ordering::Less,
+        max_match: std::cmp::Ordering::Greater,
+    };
+    for (item, data, _block_offset, _slot) in BtrfsTreeIter::new(fs, sb.root, search) {
+        if item.key.item_type != BtrfsItemType::ROOT_ITEM {
+            continue;
+        }
+        if let Some(root_item) = item_as::<btrfs_root_item>(&data) {
+            let objectid = item.key.objectid;
+            let offset = item.key.offset;
+            let name = if offset == 0 {
+                fmt_treeid(objectid)
+            } else {
+                format!("{} ({offset})", fmt_treeid(objectid))
+            };
+            trees.push((name, root_item.bytenr));
+        }
+    }
+    trees
+}
+
+/// every tree reachable from the superblock and the root tree, as (tree id,
+/// root bytenr, root level)
+pub fn tree_roots(fs: &FsInfo) -> Vec<(u64, u64, u8)> {
+    let sb = &fs.master_sb;
+    let mut roots = vec![
+        (
+            BTRFS_CHUNK_TREE_OBJECTID,
+            sb.chunk_root,
+            sb.chunk_root_level,
+        ),
+        (BTRFS_ROOT_TREE_OBJECTID, sb.root, sb.root_level),
+    ];
+    if sb.log_root != 0 {
+        roots.push((BTRFS_TREE_LOG_OBJECTID, sb.log_root, sb.log_root_level));
+    }
+    roots.extend(root_items(fs, sb.root));
+    roots
+}
+
+/// the trees with a ROOT_ITEM in the root tree whose root block is at
+/// root_tree, e.g. that of a backup root, as (tree id, root bytenr, root
+/// level)
+pub fn root_items(fs: &FsInfo, root_tree: u64) -> Vec<(u64, u64, u8)> {
+    root_items_in(fs, root_tree)
+        .map(|(objectid, root_item)| (objectid, root_item.bytenr, root_item.level))
+        .collect()
+}
+
+/// the ROOT_ITEMs of the root tree whose root block is at root_tree, as
+/// (tree id, root item). Those too short to be root items are skipped.
+fn root_items_in(fs: &FsInfo, root_tree: u64) -> impl Iterator<Item = (u64, btrfs_root_item)> + '_ {
+    let search = key_range(None, Some(BtrfsItemType::ROOT_ITEM), None);
+    search_range(fs, root_tree, search)
+        .filter(|(item, ..)| item.key.item_type == BtrfsItemType::ROOT_ITEM)
+        .filter_map(|(item, data, ..)| {
+            item_as::<btrfs_root_item>(&data).map(|root_item| (item.key.objectid, *root_item))
+        })
+}
+
+/// FS_TREE and every subvolume/snapshot tree, as (tree id, root bytenr)
+pub fn fs_trees(fs: &FsInfo) -> Vec<(u64, u64)> {
+    fs.roots()
+        .filter(|&(objectid, _)| {
+            objectid == BTRFS_FS_TREE_OBJECTID
+                || (BTRFS_FIRST_FREE_OBJECTID..=BTRFS_LAST_FREE_OBJECTID).contains(&objectid)
+        })
+        .map(|(objectid, root_item)| (objectid, root_item.bytenr))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -289,4 +1502,112 @@ mod tests {
         println!("{result:x?}");
         assert_eq!(expected, result[0..4]);
     }
+
+    #[test]
+    fn mixed_filesystems() {
+        let mut sbs: [btrfs_super_block; 3] = unsafe { std::mem::zeroed() };
+        sbs[0].fsid = BtrfsUuid([1; 16]);
+        sbs[1].fsid = BtrfsUuid([2; 16]);
+        sbs[1].generation = 100;
+        sbs[2].fsid = BtrfsUuid([1; 16]);
+        let paths = [Path::new("a"), Path::new("b"), Path::new("c")];
+        let mixed = MixedFilesystems::new(paths.into_iter().zip(&sbs));
+        assert_eq!(mixed.fsid, BtrfsUuid([1; 16]));
+        let strays: Vec<&Path> = mixed.strays().map(|dev| dev.path.as_path()).collect();
+        assert_eq!(strays, [Path::new("b")]);
+        assert!(mixed
+            .to_string()
+            .ends_with("b is not part of filesystem 01010101-0101-0101-0101-010101010101"));
+    }
+
+    #[test]
+    fn device_io() {
+        let mut fs = crate::tree::tests::test_fs("device_io", &[vec![5; 8192]]);
+        let dev = Arc::clone(&fs.devid_map[&1]);
+        assert_eq!(dev.read_at(4096, 16).unwrap(), [5; 16]);
+        assert!(dev.read_at(8192 - 8, 16).is_err());
+        assert!(dev.write_at(&fs, 8192 - 8, &[0; 16]).is_err());
+        fs.pinned_generation = Some(1);
+        assert!(dev.write_at(&fs, 0, &[0; 16]).is_err());
+        fs.pinned_generation = None;
+        fs.cancel.cancel();
+        let error = dev.write_at(&fs, 0, &[0; 16]).unwrap_err();
+        assert!(error.to_string().contains("nothing was written"), "{error}");
+    }
+
+    #[test]
+    fn truncated_device() {
+        let mut fs = crate::tree::tests::test_fs("truncated_device", &[vec![0; 8192]]);
+        fs.bootstrap_chunks[0].1.length = 16384;
+        check_device_sizes(&mut fs, &[(1, 12288)]);
+        assert_eq!(fs.quarantine.physical(1, 8192, 4096), Some(8192..16384));
+        assert_eq!(fs.quarantine.physical(1, 0, 8192), None);
+        let error = crate::address::load_virt_block_unverified(&fs, 12288).unwrap_err();
+        assert!(error.to_string().contains("lost"), "{error}");
+    }
+
+    #[test]
+    fn damaged_csum_type() {
+        use crate::btrfs_node::{as_bytes, build_leaf, seal_tree_block};
+
+        const CHUNK_ROOT: usize = 2 * BTRFS_SUPER_INFO_OFFSET;
+        let mut sb: btrfs_super_block = unsafe { std::mem::zeroed() };
+        sb.magic = BTRFS_MAGIC;
+        sb.bytenr = BTRFS_SUPER_INFO_OFFSET as u64;
+        sb.total_bytes = 1024 * 1024;
+        sb.num_devices = 1;
+        sb.sectorsize = 4096;
+        sb.nodesize = 4096;
+        sb.stripesize = 4096;
+        sb.chunk_root = CHUNK_ROOT as u64;
+        sb.dev_item.devid = 1;
+        sb.csum_type = BtrfsCsumType::CRC32;
+        let mut chunk: btrfs_chunk = unsafe { std::mem::zeroed() };
+        chunk.length = 1024 * 1024;
+        chunk.num_stripes = 1;
+        let key = btrfs_disk_key {
+            objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+            item_type: BtrfsItemType::CHUNK_ITEM,
+            offset: 0,
+        };
+        let stripe = btrfs_stripe {
+            devid: 1,
+            offset: 0,
+            dev_uuid: BtrfsUuid::default(),
+        };
+        let array = [as_bytes(&key), as_bytes(&chunk), as_bytes(&stripe)].concat();
+        sb.sys_chunk_array[..array.len()].copy_from_slice(&array);
+        sb.sys_chunk_array_size = array.len() as u32;
+        let mut header: btrfs_header = unsafe { std::mem::zeroed() };
+        header.bytenr = CHUNK_ROOT as u64;
+        let mut chunk_root = build_leaf::<&[u8]>(&header, &[], 4096).unwrap();
+        seal_tree_block(&mut chunk_root, BtrfsCsumType::CRC32);
+
+        let mut sb_bytes = as_bytes(&sb).to_vec();
+        let csum = csum_data(&sb_bytes[BTRFS_CSUM_SIZE..], BtrfsCsumType::CRC32);
+        sb_bytes[..BTRFS_CSUM_SIZE].copy_from_slice(&csum);
+        let path = std::env::temp_dir().join(format!("damaged_csum_type.{}", std::process::id()));
+        let load = |sb_bytes: &[u8], chunk_root: &[u8]| {
+            let mut image = vec![0u8; CHUNK_ROOT];
+            image[BTRFS_SUPER_INFO_OFFSET..][..BTRFS_SUPER_INFO_SIZE].copy_from_slice(sb_bytes);
+            image.extend_from_slice(chunk_root);
+            std::fs::write(&path, &image).unwrap();
+            let mf = MappedFile::open(&path).unwrap();
+            load_sb_at(&mf, BTRFS_SUPER_INFO_OFFSET).map(|sb| sb.csum_type)
+        };
+        assert_eq!(load(&sb_bytes, &chunk_root).unwrap(), BtrfsCsumType::CRC32);
+        // damaged csum_type, with the rest sound
+        sb_bytes[CSUM_TYPE_OFFSET] = 0x40;
+        assert_eq!(load(&sb_bytes, &chunk_root).unwrap(), BtrfsCsumType::CRC32);
+        // damaged elsewhere too, when only the chunk root says
+        sb_bytes[CSUM_TYPE_OFFSET + 1] = 0x12;
+        sb_bytes[BTRFS_SUPER_INFO_SIZE - 1] ^= 1;
+        assert_eq!(load(&sb_bytes, &chunk_root).unwrap(), BtrfsCsumType::CRC32);
+        chunk_root[100] ^= 1;
+        assert!(load(&sb_bytes, &chunk_root).is_err());
+        // a known csum_type whose checksum is wrong is an error as before
+        sb_bytes[CSUM_TYPE_OFFSET..][..2].copy_from_slice(&[0, 0]);
+        assert!(load(&sb_bytes, &chunk_root).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
 }
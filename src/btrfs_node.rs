@@ -1,24 +1,63 @@
 use crate::address::*;
 use crate::btrfs::*;
 use crate::structures::*;
+use crate::tree::cmp_key;
 
-pub struct BtrfsLeafNodeIter<'a> {
-    block: &'a [u8],
+use anyhow::{anyhow, ensure, Context};
+use log::warn;
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+/// the data of a leaf's item, holding on to the block it lies in so that it
+/// outlives the block's time in the block cache
+#[derive(Clone)]
+pub struct ItemData {
+    block: Arc<[u8]>,
+    range: Range<usize>,
+}
+
+impl Deref for ItemData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.block[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for ItemData {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for ItemData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[derive(Clone)]
+pub struct BtrfsLeafNodeIter {
+    block: Arc<[u8]>,
     cur_item: u32,
     pub block_offset: u64,
+    /// corrupt items stepped past
+    skipped: u32,
 }
 
 /// iterator through btrfs nodes
-/// accepts a slice to a block, then returns an Iterator object
+/// accepts a block, then returns an Iterator object
 /// with methods to return a reference to the block header,
 /// and iterate through the key pointers/items, or perform
 /// binary search to locate a key pointer/item matching a spec
-
-pub fn block_as_leaf_node(block: &[u8], block_offset: u64) -> BtrfsLeafNodeIter {
+pub fn block_as_leaf_node(block: Arc<[u8]>, block_offset: u64) -> BtrfsLeafNodeIter {
     BtrfsLeafNodeIter {
         block,
         cur_item: 0,
         block_offset,
+        skipped: 0,
     }
 }
 
@@ -30,70 +69,136 @@ pub fn btrfs_leaf_node(fs: &FsInfo, block_offset: u64) -> anyhow::Result<BtrfsLe
         block,
         cur_item: 0,
         block_offset,
+        skipped: 0,
     })
 }
 
-impl<'a> BtrfsLeafNodeIter<'a> {
+impl BtrfsLeafNodeIter {
     pub fn header(&self) -> &btrfs_header {
         unsafe { &*(self.block.as_ptr() as *const btrfs_header) }
     }
 
+    /// the item next returns, past any corrupt ones, or None at the end of
+    /// the leaf
     pub fn peek(&self) -> Option<<Self as Iterator>::Item> {
-        if self.cur_item >= self.header().nritems {
+        self.clone().skip_corrupt(false)
+    }
+
+    /// how many corrupt items next has stepped past, each logged as a
+    /// warning; see try_peek
+    pub fn skipped(&self) -> u32 {
+        self.skipped
+    }
+
+    /// step past corrupt items to the next good one, or the end of the
+    /// leaf, and return it
+    fn skip_corrupt(&mut self, warn: bool) -> Option<<Self as Iterator>::Item> {
+        let header_size = std::mem::size_of::<btrfs_header>();
+        let item_size = std::mem::size_of::<btrfs_item>();
+        loop {
+            let e = match self.try_peek()? {
+                Result::Ok(item) => return Some(item),
+                Err(e) => e,
+            };
+            // once one item header lies beyond the end of the block, so
+            // do all those after it
+            let within = self.block.len().saturating_sub(header_size) / item_size;
+            let step = if (self.cur_item as usize) < within {
+                1
+            } else {
+                self.header().nritems - self.cur_item
+            };
+            if warn {
+                warn!("leaf {}: {e:#}, skipping {step} item(s)", self.block_offset);
+            }
+            self.cur_item += step;
+            self.skipped += step;
+        }
+    }
+
+    /// the next item, or an error if its header or data doesn't lie within
+    /// the leaf, the data after the item headers, as on-disc offsets and
+    /// sizes can't be trusted
+    pub fn try_peek(&self) -> Option<anyhow::Result<<Self as Iterator>::Item>> {
+        let nritems = self.header().nritems;
+        if self.cur_item >= nritems {
             return None;
         }
+        let slot = self.cur_item as usize;
+        let offset = std::mem::size_of::<btrfs_header>() + slot * std::mem::size_of::<btrfs_item>();
+        if offset + std::mem::size_of::<btrfs_item>() > self.block.len() {
+            return Some(Err(anyhow!(
+                "item {slot} of {nritems} lies beyond the end of the block"
+            )));
+        }
+        let item = unsafe { *(self.block.as_ptr().add(offset) as *const btrfs_item) };
+        Some(
+            item_data_range(&self.block, nritems as usize, &item)
+                .map(|range| {
+                    let data = ItemData {
+                        block: Arc::clone(&self.block),
+                        range,
+                    };
+                    (item, data, self.block_offset, self.cur_item)
+                })
+                .with_context(|| format!("item {slot}")),
+        )
+    }
 
-        let offset = std::mem::size_of::<btrfs_header>()
-            + self.cur_item as usize * std::mem::size_of::<btrfs_item>();
-        let item = unsafe { &*((self.block.as_ptr() as usize + offset) as *const btrfs_item) };
-        let data_offset = std::mem::size_of::<btrfs_header>() + item.offset as usize;
-        Some((
-            item,
-            &self.block[data_offset..(data_offset + item.size as usize)],
-            self.block_offset,
-            self.cur_item,
-        ))
+    /// the slot of the item next returns
+    pub fn position(&self) -> u32 {
+        self.cur_item
     }
 
-    //TODO: pub fn search(&self, btrfs_search_options)
+    pub fn set_position(&mut self, slot: u32) {
+        self.cur_item = slot;
+    }
+
+    /// position the iterator at the first item whose key is not less than
+    /// key, or past the last, by binary search as the items are in key order
+    pub fn seek(&mut self, key: &btrfs_disk_key) {
+        let header_size = std::mem::size_of::<btrfs_header>();
+        let item_size = std::mem::size_of::<btrfs_item>();
+        let nritems = self.header().nritems as usize;
+        let nritems = nritems.min(self.block.len().saturating_sub(header_size) / item_size);
+        self.cur_item = seek_slot(nritems, key, |slot| {
+            let offset = header_size + slot * item_size;
+            unsafe { &*(self.block.as_ptr().add(offset) as *const btrfs_item) }.key
+        });
+    }
 }
 
-impl<'a> Iterator for BtrfsLeafNodeIter<'a> {
-    type Item = (&'a btrfs_item, &'a [u8], u64, u32);
+impl Iterator for BtrfsLeafNodeIter {
+    type Item = (btrfs_item, ItemData, u64, u32);
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.peek() {
-            None => None,
-            Some(s) => {
-                self.cur_item += 1;
-                Some(s)
-            }
-        }
+        let item = self.skip_corrupt(true)?;
+        self.cur_item += 1;
+        Some(item)
     }
 }
 //////////////////////////////////////////////////////////////////////
-pub struct BtrfsInternalNodeIter<'a> {
-    block: &'a [u8],
+#[derive(Clone)]
+pub struct BtrfsInternalNodeIter {
+    block: Arc<[u8]>,
     cur_item: u32,
     pub block_offset: u64,
 }
 
-impl<'a> BtrfsInternalNodeIter<'a> {
+impl BtrfsInternalNodeIter {
     /// reinterpret this internal node as a leaf node
     /// any iteration progress is reset.
-    pub fn as_leaf_node(&self) -> BtrfsLeafNodeIter<'a> {
-        let leaf = block_as_leaf_node(self.block, self.block_offset);
-        leaf
+    pub fn as_leaf_node(&self) -> BtrfsLeafNodeIter {
+        block_as_leaf_node(Arc::clone(&self.block), self.block_offset)
     }
 }
 
 /// iterator through btrfs nodes
-/// accepts a slice to a block, then returns an Iterator object
+/// accepts a block, then returns an Iterator object
 /// with methods to return a reference to the block header,
 /// and iterate through the key pointers/items, or perform
 /// binary search to locate a key pointer/item matching a spec
-
-pub fn block_as_internal_node(block: &[u8], block_offset: u64) -> BtrfsInternalNodeIter {
+pub fn block_as_internal_node(block: Arc<[u8]>, block_offset: u64) -> BtrfsInternalNodeIter {
     BtrfsInternalNodeIter {
         block,
         cur_item: 0,
@@ -113,7 +218,7 @@ pub fn btrfs_internal_node(
     })
 }
 
-impl<'a> BtrfsInternalNodeIter<'a> {
+impl BtrfsInternalNodeIter {
     pub fn header(&self) -> &btrfs_header {
         unsafe { &*(self.block.as_ptr() as *const btrfs_header) }
     }
@@ -125,15 +230,60 @@ impl<'a> BtrfsInternalNodeIter<'a> {
 
         let offset = std::mem::size_of::<btrfs_header>()
             + self.cur_item as usize * std::mem::size_of::<btrfs_key_ptr>();
-        let item = unsafe { &*((self.block.as_ptr() as usize + offset) as *const btrfs_key_ptr) };
+        if offset + std::mem::size_of::<btrfs_key_ptr>() > self.block.len() {
+            warn!(
+                "node {}: key pointer {} lies beyond the end of the block",
+                self.block_offset, self.cur_item
+            );
+            return None;
+        }
+        let item = unsafe { *(self.block.as_ptr().add(offset) as *const btrfs_key_ptr) };
         Some(item)
     }
 
-    //TODO: pub fn search(&self, btrfs_search_options)
+    /// the slot of the key pointer next returns
+    pub fn position(&self) -> u32 {
+        self.cur_item
+    }
+
+    pub fn set_position(&mut self, slot: u32) {
+        self.cur_item = slot;
+    }
+
+    /// position the iterator at the first key pointer whose key is not less
+    /// than key, or past the last, by binary search
+    pub fn seek(&mut self, key: &btrfs_disk_key) {
+        let header_size = std::mem::size_of::<btrfs_header>();
+        let ptr_size = std::mem::size_of::<btrfs_key_ptr>();
+        let nritems = self.header().nritems as usize;
+        let nritems = nritems.min(self.block.len().saturating_sub(header_size) / ptr_size);
+        self.cur_item = seek_slot(nritems, key, |slot| {
+            let offset = header_size + slot * ptr_size;
+            unsafe { &*(self.block.as_ptr().add(offset) as *const btrfs_key_ptr) }.key
+        });
+    }
 }
 
-impl<'a> Iterator for BtrfsInternalNodeIter<'a> {
-    type Item = &'a btrfs_key_ptr;
+/// the first of nritems slots in key order whose key is not less than key
+pub(crate) fn seek_slot(
+    nritems: usize,
+    key: &btrfs_disk_key,
+    key_at: impl Fn(usize) -> btrfs_disk_key,
+) -> u32 {
+    let (mut low, mut high) = (0, nritems);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if cmp_key(&key_at(mid), key) == Ordering::Less {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low as u32
+}
+
+impl Iterator for BtrfsInternalNodeIter {
+    type Item = btrfs_key_ptr;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.peek() {
@@ -145,3 +295,288 @@ impl<'a> Iterator for BtrfsInternalNodeIter<'a> {
         }
     }
 }
+//////////////////////////////////////////////////////////////////////
+/// one slot of a node, as returned by node_entries
+pub enum NodeEntry<'a> {
+    Ptr(&'a btrfs_key_ptr),
+    /// item header and its data, if the data lies within the block
+    Item(&'a btrfs_item, Option<&'a [u8]>),
+}
+
+/// where an item's data lies in a leaf of nritems items: after the item
+/// headers (the kernel fills the rest of the leaf from its end backwards),
+/// and within the block
+pub fn item_data_range(
+    block: &[u8],
+    nritems: usize,
+    item: &btrfs_item,
+) -> anyhow::Result<Range<usize>> {
+    let header_size = std::mem::size_of::<btrfs_header>();
+    let (offset, size) = (item.offset as usize, item.size as usize);
+    let start = header_size + offset;
+    let end = start + size;
+    let headers_end = header_size + nritems * std::mem::size_of::<btrfs_item>();
+    ensure!(
+        end <= block.len(),
+        "data at {offset}+{size} runs past the end of the block"
+    );
+    ensure!(
+        start >= headers_end || size == 0,
+        "data at {offset}+{size} overlaps the item headers"
+    );
+    Ok(start..end)
+}
+
+/// the key pointers or items of a block, read with bounds checks so that
+/// corrupt nodes can still be inspected. An nritems value that overflows the
+/// block is truncated, so the result can be shorter than header.nritems.
+/// Item data is checked as by item_data_range, and left out if it runs past
+/// the block or overlaps the item headers.
+pub fn node_entries(block: &[u8]) -> Vec<NodeEntry<'_>> {
+    let header_size = std::mem::size_of::<btrfs_header>();
+    if block.len() < header_size {
+        return Vec::new();
+    }
+    let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+    let mut entries = Vec::new();
+    if header.level == 0 {
+        let max_items = (block.len() - header_size) / std::mem::size_of::<btrfs_item>();
+        for i in 0..(header.nritems as usize).min(max_items) {
+            let offset = header_size + i * std::mem::size_of::<btrfs_item>();
+            let item = unsafe { &*(block.as_ptr().add(offset) as *const btrfs_item) };
+            let data = item_data_range(block, header.nritems as usize, item)
+                .ok()
+                .map(|range| &block[range]);
+            entries.push(NodeEntry::Item(item, data));
+        }
+    } else {
+        let max_ptrs = (block.len() - header_size) / std::mem::size_of::<btrfs_key_ptr>();
+        for i in 0..(header.nritems as usize).min(max_ptrs) {
+            let offset = header_size + i * std::mem::size_of::<btrfs_key_ptr>();
+            let ptr = unsafe { &*(block.as_ptr().add(offset) as *const btrfs_key_ptr) };
+            entries.push(NodeEntry::Ptr(ptr));
+        }
+    }
+    entries
+}
+
+/// the keys of a leaf's items, without their data being looked at, read
+/// with the bounds checks of node_entries. Empty if the block isn't a leaf.
+pub fn leaf_keys(block: &[u8]) -> impl Iterator<Item = btrfs_disk_key> + '_ {
+    let header_size = std::mem::size_of::<btrfs_header>();
+    let item_size = std::mem::size_of::<btrfs_item>();
+    let nritems = if block.len() < header_size {
+        0
+    } else {
+        let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+        match header.level {
+            0 => (header.nritems as usize).min((block.len() - header_size) / item_size),
+            _ => 0,
+        }
+    };
+    (0..nritems).map(move |i| {
+        let offset = header_size + i * item_size;
+        unsafe { &*(block.as_ptr().add(offset) as *const btrfs_item) }.key
+    })
+}
+
+/// a leaf holding items in key order, packed as the kernel packs them: the
+/// item headers after the block header, and their data from the end of the
+/// block backwards. The header is copied with nritems and level set, and the
+/// csum left for seal_tree_block. None if the items don't fit.
+pub fn build_leaf<T: AsRef<[u8]>>(
+    header: &btrfs_header,
+    items: &[(btrfs_disk_key, T)],
+    nodesize: usize,
+) -> Option<Vec<u8>> {
+    let header_size = std::mem::size_of::<btrfs_header>();
+    let item_size = std::mem::size_of::<btrfs_item>();
+    let used: usize = items
+        .iter()
+        .map(|(_, data)| item_size + data.as_ref().len())
+        .sum();
+    if header_size + used > nodesize {
+        return None;
+    }
+    let mut block = vec![0_u8; nodesize];
+    let mut header = *header;
+    header.nritems = items.len() as u32;
+    header.level = 0;
+    block[..header_size].copy_from_slice(as_bytes(&header));
+    let mut data_end = nodesize - header_size;
+    for (i, (key, data)) in items.iter().enumerate() {
+        let data = data.as_ref();
+        data_end -= data.len();
+        let item = btrfs_item {
+            key: *key,
+            offset: data_end as u32,
+            size: data.len() as u32,
+        };
+        let start = header_size + i * item_size;
+        block[start..start + item_size].copy_from_slice(as_bytes(&item));
+        block[header_size + data_end..header_size + data_end + data.len()].copy_from_slice(data);
+    }
+    Some(block)
+}
+
+/// a node holding key pointers, with the header copied and nritems set. The
+/// level is left as the header gives it. None if the pointers don't fit.
+pub fn build_node(
+    header: &btrfs_header,
+    ptrs: &[btrfs_key_ptr],
+    nodesize: usize,
+) -> Option<Vec<u8>> {
+    let header_size = std::mem::size_of::<btrfs_header>();
+    let ptr_size = std::mem::size_of::<btrfs_key_ptr>();
+    if header_size + std::mem::size_of_val(ptrs) > nodesize {
+        return None;
+    }
+    let mut block = vec![0_u8; nodesize];
+    let mut header = *header;
+    header.nritems = ptrs.len() as u32;
+    block[..header_size].copy_from_slice(as_bytes(&header));
+    for (i, ptr) in ptrs.iter().enumerate() {
+        let start = header_size + i * ptr_size;
+        block[start..start + ptr_size].copy_from_slice(as_bytes(ptr));
+    }
+    Some(block)
+}
+
+/// set the csum of a tree block to match its contents
+pub fn seal_tree_block(block: &mut [u8], csum_type: BtrfsCsumType) {
+    let csum = csum_data(&block[BTRFS_CSUM_SIZE..], csum_type);
+    block[..BTRFS_CSUM_SIZE].copy_from_slice(&csum);
+}
+
+/// the bytes of an on-disc structure
+pub(crate) fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaf_round_trip() {
+        let header: btrfs_header = unsafe { std::mem::zeroed() };
+        let key = |offset| btrfs_disk_key {
+            objectid: 256,
+            item_type: BtrfsItemType::EXTENT_DATA,
+            offset,
+        };
+        let items = [(key(0), vec![1_u8; 53]), (key(4096), vec![2_u8; 21])];
+        let block = build_leaf(&header, &items, 4096).unwrap();
+        let entries = node_entries(&block);
+        assert_eq!(entries.len(), 2);
+        for (entry, (key, data)) in entries.iter().zip(&items) {
+            let NodeEntry::Item(item, Some(item_data)) = entry else {
+                panic!("item data outside the leaf");
+            };
+            let (got, expected) = (item.key.offset, key.offset);
+            assert_eq!(got, expected);
+            assert_eq!(item_data, data);
+        }
+        let NodeEntry::Item(last, _) = entries[1] else {
+            unreachable!()
+        };
+        let last_offset = last.offset;
+        assert_eq!(
+            last_offset as usize,
+            4096 - std::mem::size_of::<btrfs_header>() - 53 - 21
+        );
+        assert!(build_leaf(&header, &[(key(0), vec![0_u8; 4000])], 4096).is_none());
+    }
+
+    #[test]
+    fn node_seek() {
+        let header: btrfs_header = unsafe { std::mem::zeroed() };
+        let key = |objectid| btrfs_disk_key {
+            objectid,
+            item_type: BtrfsItemType::INODE_ITEM,
+            offset: 0,
+        };
+        let items: Vec<_> = (1..=9).map(|i| (key(i * 10), [i as u8])).collect();
+        let block = build_leaf(&header, &items, 4096).unwrap();
+        let mut leaf = block_as_leaf_node(Arc::from(&block[..]), 0);
+        for (sought, slot) in [(0, 0), (10, 0), (11, 1), (50, 4), (90, 8), (91, 9)] {
+            leaf.seek(&key(sought));
+            assert_eq!(leaf.position(), slot, "seeking {sought}");
+        }
+        leaf.seek(&key(35));
+        assert_eq!(*leaf.next().unwrap().1, [4]);
+
+        let mut header = header;
+        header.level = 1;
+        let ptrs: Vec<_> = (1..=3)
+            .map(|i| btrfs_key_ptr {
+                key: key(i * 100),
+                blockptr: i * 4096,
+                generation: 0,
+            })
+            .collect();
+        let block = build_node(&header, &ptrs, 4096).unwrap();
+        let mut node = block_as_internal_node(Arc::from(&block[..]), 0);
+        node.seek(&key(200));
+        assert_eq!({ node.next().unwrap().blockptr }, 2 * 4096);
+        node.seek(&key(301));
+        assert!(node.next().is_none());
+    }
+
+    #[test]
+    fn corrupt_items() {
+        let header: btrfs_header = unsafe { std::mem::zeroed() };
+        let key = |objectid| btrfs_disk_key {
+            objectid,
+            item_type: BtrfsItemType::INODE_ITEM,
+            offset: 0,
+        };
+        let items = [
+            (key(1), [1_u8; 16]),
+            (key(2), [2_u8; 16]),
+            (key(3), [3_u8; 16]),
+        ];
+        let header_size = std::mem::size_of::<btrfs_header>();
+        let item_size = std::mem::size_of::<btrfs_item>();
+        let set_item = |block: &mut Vec<u8>, slot: usize, offset: u32, size: u32| {
+            let at = header_size + slot * item_size;
+            let item = unsafe { &mut *(block[at..].as_mut_ptr() as *mut btrfs_item) };
+            item.offset = offset;
+            item.size = size;
+        };
+
+        let mut block = build_leaf(&header, &items, 4096).unwrap();
+        set_item(&mut block, 1, 4090, 16);
+        let mut leaf = block_as_leaf_node(Arc::from(&block[..]), 0);
+        assert!(leaf.next().is_some());
+        assert!(leaf.try_peek().unwrap().is_err());
+        assert_eq!(*leaf.peek().unwrap().1, [3; 16]);
+        // the items after the corrupt one are still returned
+        let (item, data, _, slot) = leaf.next().unwrap();
+        assert_eq!(({ item.key.objectid }, slot), (3, 2));
+        assert_eq!(*data, [3; 16]);
+        assert!(leaf.next().is_none());
+        assert_eq!(leaf.skipped(), 1);
+        assert!(matches!(node_entries(&block)[1], NodeEntry::Item(_, None)));
+
+        let mut block = build_leaf(&header, &items, 4096).unwrap();
+        set_item(&mut block, 0, 0, 16);
+        let leaf = block_as_leaf_node(Arc::from(&block[..]), 0);
+        assert!(leaf.try_peek().unwrap().is_err());
+        assert!(matches!(node_entries(&block)[0], NodeEntry::Item(_, None)));
+        assert!(matches!(
+            node_entries(&block)[1],
+            NodeEntry::Item(_, Some(_))
+        ));
+
+        let mut block = build_leaf(&header, &items, 4096).unwrap();
+        unsafe { &mut *(block.as_mut_ptr() as *mut btrfs_header) }.nritems = 1000;
+        let mut leaf = block_as_leaf_node(Arc::from(&block[..]), 0);
+        leaf.set_position(999);
+        assert!(leaf.try_peek().unwrap().is_err());
+        // the headers beyond the end of the block are skipped together
+        leaf.set_position(0);
+        let returned = leaf.by_ref().count() as u32;
+        assert_eq!(returned + leaf.skipped(), 1000);
+    }
+}
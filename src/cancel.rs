@@ -0,0 +1,108 @@
+//! Cooperative cancellation of long scans. Tree walks and device scans stop
+//! early once the filesystem's token is cancelled, so that what was found so
+//! far can still be reported. Writing is different: a command is only
+//! stopped before its first write, and once it has written anything it
+//! finishes, as stopping part way could leave the metadata inconsistent.
+
+use anyhow::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+#[derive(Default)]
+struct State {
+    cancelled: AtomicBool,
+    writing: AtomicBool,
+}
+
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<State>);
+
+static SIGINT_TOKEN: OnceLock<CancellationToken> = OnceLock::new();
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    let token = SIGINT_TOKEN.get();
+    if let Some(token) = token {
+        token.cancel();
+    }
+    let message: &[u8] = if token.is_some_and(|token| token.0.writing.load(Ordering::SeqCst)) {
+        b"\ninterrupted: finishing the writes under way, interrupt again to kill\n"
+    } else {
+        b"\ninterrupted: stopping, interrupt again to kill\n"
+    };
+    // only async-signal-safe calls: a second SIGINT kills as usual
+    unsafe {
+        libc::write(
+            2,
+            message.as_ptr() as *const libc::c_void,
+            message.len() as _,
+        );
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// the process wide token which SIGINT cancels once cancel_on_sigint
+    /// has been called
+    pub fn sigint() -> CancellationToken {
+        SIGINT_TOKEN.get_or_init(CancellationToken::new).clone()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// whether a scan should stop. Once writing has begun this stays false,
+    /// as the scans a command does while writing must be complete.
+    pub fn is_cancelled(&self) -> bool {
+        self.was_cancelled() && !self.0.writing.load(Ordering::SeqCst)
+    }
+
+    /// whether cancel was called, even if writing went on
+    pub fn was_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// called before each write: an error if cancelled before the first,
+    /// as what led up to it may have been cut short
+    pub fn begin_writes(&self) -> Result<()> {
+        if self.0.writing.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        ensure!(!self.was_cancelled(), "interrupted, nothing was written");
+        self.0.writing.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// have SIGINT cancel CancellationToken::sigint() instead of killing the
+/// process; a second SIGINT kills it
+pub fn cancel_on_sigint() {
+    CancellationToken::sigint();
+    let handler = on_sigint as extern "C" fn(libc::c_int);
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_finish() {
+        let token = CancellationToken::new();
+        assert!(!token.is_cancelled());
+        token.begin_writes().unwrap();
+        token.cancel();
+        assert!(!token.is_cancelled());
+        assert!(token.was_cancelled());
+        token.begin_writes().unwrap();
+
+        let token = CancellationToken::new();
+        token.clone().cancel();
+        assert!(token.is_cancelled());
+        assert!(token.begin_writes().is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! A C ABI over the core of the library, built with the capi feature, for C
+//! recovery tools and scripting environments: open a filesystem, iterate the
+//! items of a tree, translate logical addresses and read tree blocks. The
+//! declarations are in include/btrfs_kit.h.
+//!
+//! Functions returning int return 0 (or a count) on success and -1 on
+//! error, and those returning a pointer NULL; btrfs_kit_last_error then
+//! describes the error. Panics are caught and reported the same way.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+
+/// an open filesystem, opaque to C
+pub struct BtrfsKitFs(FsInfo);
+
+/// a key; item_type is the on-disc item type byte
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BtrfsKitKey {
+    pub objectid: u64,
+    pub item_type: u8,
+    pub offset: u64,
+}
+
+/// one copy of a logical address on a device
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct BtrfsKitCopy {
+    pub devid: u64,
+    pub physical: u64,
+}
+
+/// called with each item, and the context given; a nonzero return stops the
+/// iteration and is returned from btrfs_kit_iterate_tree
+pub type BtrfsKitItemFn =
+    extern "C" fn(ctx: *mut c_void, key: *const BtrfsKitKey, data: *const u8, len: u32) -> c_int;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// run f, turning an error or panic into failed after recording its message
+fn guard<T>(failed: T, f: impl FnOnce() -> Result<T>) -> T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        std::result::Result::Ok(Result::Ok(value)) => value,
+        std::result::Result::Ok(Err(e)) => {
+            set_last_error(format!("{e:#}"));
+            failed
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<String>()
+                .map(String::as_str)
+                .or_else(|| panic.downcast_ref::<&str>().copied())
+                .unwrap_or("panic");
+            set_last_error(message.to_string());
+            failed
+        }
+    }
+}
+
+unsafe fn fs_ref<'a>(fs: *const BtrfsKitFs) -> Result<&'a FsInfo> {
+    ensure!(!fs.is_null(), "fs is NULL");
+    Ok(&(*fs).0)
+}
+
+fn item_type(value: u8) -> Result<BtrfsItemType> {
+    BtrfsItemType::ALL
+        .iter()
+        .find(|t| **t as u8 == value)
+        .copied()
+        .ok_or_else(|| anyhow!("unknown item type {value}"))
+}
+
+/// the message of the last error on this thread, valid until the next call
+/// which fails
+#[no_mangle]
+pub extern "C" fn btrfs_kit_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// open the filesystem on npaths devices or images, as load_fs does
+///
+/// # Safety
+/// paths must point to npaths NUL terminated strings
+#[no_mangle]
+pub unsafe extern "C" fn btrfs_kit_open(
+    paths: *const *const c_char,
+    npaths: usize,
+) -> *mut BtrfsKitFs {
+    guard(std::ptr::null_mut(), || {
+        ensure!(!paths.is_null() && npaths > 0, "no devices given");
+        let paths = std::slice::from_raw_parts(paths, npaths)
+            .iter()
+            .map(|path| {
+                ensure!(!path.is_null(), "a path is NULL");
+                Ok(PathBuf::from(CStr::from_ptr(*path).to_str()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let fs = load_fs(&paths)?;
+        Ok(Box::into_raw(Box::new(BtrfsKitFs(fs))))
+    })
+}
+
+/// close a filesystem btrfs_kit_open returned; NULL is ignored
+///
+/// # Safety
+/// fs must come from btrfs_kit_open and not be used again
+#[no_mangle]
+pub unsafe extern "C" fn btrfs_kit_close(fs: *mut BtrfsKitFs) {
+    if !fs.is_null() {
+        drop(Box::from_raw(fs));
+    }
+}
+
+/// the size of a tree block, 0 if fs is NULL
+///
+/// # Safety
+/// fs must be NULL or come from btrfs_kit_open
+#[no_mangle]
+pub unsafe extern "C" fn btrfs_kit_nodesize(fs: *const BtrfsKitFs) -> u32 {
+    guard(0, || Ok(fs_ref(fs)?.master_sb.nodesize))
+}
+
+/// the logical address of the root block of a tree
+///
+/// # Safety
+/// fs must come from btrfs_kit_open, and root point to a u64
+#[no_mangle]
+pub unsafe extern "C" fn btrfs_kit_tree_root(
+    fs: *const BtrfsKitFs,
+    tree_id: u64,
+    root: *mut u64,
+) -> c_int {
+    guard(-1, || {
+        let fs = fs_ref(fs)?;
+        ensure!(!root.is_null(), "root is NULL");
+        *root = tree_root_offset(fs, tree_id).ok_or_else(|| anyhow!("tree {tree_id} not found"))?;
+        Ok(0)
+    })
+}
+
+/// call callback with each item of a tree whose key lies between min and
+/// max inclusive, in key order. The key and data are only valid during the
+/// call.
+///
+/// # Safety
+/// fs must come from btrfs_kit_open, and min and max point to keys
+#[no_mangle]
+pub unsafe extern "C" fn btrfs_kit_iterate_tree(
+    fs: *const BtrfsKitFs,
+    tree_id: u64,
+    min: *const BtrfsKitKey,
+    max: *const BtrfsKitKey,
+    callback: BtrfsKitItemFn,
+    ctx: *mut c_void,
+) -> c_int {
+    guard(-1, || {
+        let fs = fs_ref(fs)?;
+        ensure!(!min.is_null() && !max.is_null(), "min or max is NULL");
+        let (min, max) = (*min, *max);
+        let root =
+            tree_root_offset(fs, tree_id).ok_or_else(|| anyhow!("tree {tree_id} not found"))?;
+        let search = NodeSearchOption::between(
+            btrfs_disk_key {
+                objectid: min.objectid,
+                item_type: item_type(min.item_type)?,
+                offset: min.offset,
+            },
+            btrfs_disk_key {
+                objectid: max.objectid,
+                item_type: item_type(max.item_type)?,
+                offset: max.offset,
+            },
+        );
+        for (item, data, _block_offset, _slot) in search_range(fs, root, search) {
+            let key = BtrfsKitKey {
+                objectid: item.key.objectid,
+                item_type: item.key.item_type as u8,
+                offset: item.key.offset,
+            };
+            let stop = callback(ctx, &key, data.as_ptr(), data.len() as u32);
+            if stop != 0 {
+                return Ok(stop);
+            }
+        }
+        Ok(0)
+    })
+}
+
+/// the devid and physical offset of each copy of a logical address, of
+/// which the first ncopies are stored in copies. Returns the number of
+/// copies, which may be more than ncopies. Striped chunks aren't supported.
+///
+/// # Safety
+/// fs must come from btrfs_kit_open, and copies point to ncopies entries
+#[no_mangle]
+pub unsafe extern "C" fn btrfs_kit_logical_to_physical(
+    fs: *const BtrfsKitFs,
+    logical: u64,
+    copies: *mut BtrfsKitCopy,
+    ncopies: usize,
+) -> c_int {
+    guard(-1, || {
+        let fs = fs_ref(fs)?;
+        ensure!(!copies.is_null() || ncopies == 0, "copies is NULL");
+        let found = block_copies(fs, logical, 1)?;
+        for (i, copy) in found.iter().take(ncopies).enumerate() {
+            *copies.add(i) = BtrfsKitCopy {
+                devid: copy.devid,
+                physical: copy.physical,
+            };
+        }
+        Ok(found.len() as c_int)
+    })
+}
+
+/// copy the tree block at logical, nodesize bytes from the first device
+/// holding a copy, into buf. The block isn't verified.
+///
+/// # Safety
+/// fs must come from btrfs_kit_open, and buf point to len bytes
+#[no_mangle]
+pub unsafe extern "C" fn btrfs_kit_read_block(
+    fs: *const BtrfsKitFs,
+    logical: u64,
+    buf: *mut u8,
+    len: usize,
+) -> c_int {
+    guard(-1, || {
+        let fs = fs_ref(fs)?;
+        let nodesize = fs.master_sb.nodesize as usize;
+        ensure!(!buf.is_null(), "buf is NULL");
+        ensure!(
+            len >= nodesize,
+            "buf holds {len} bytes, a block is {nodesize}"
+        );
+        ensure!(
+            logical.is_multiple_of(nodesize as u64),
+            "{logical} is not a multiple of the node size"
+        );
+        let block = load_virt_block_unverified(fs, logical)?;
+        std::ptr::copy_nonoverlapping(block.as_ptr(), buf, nodesize);
+        Ok(0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn count(ctx: *mut c_void, _: *const BtrfsKitKey, _: *const u8, _: u32) -> c_int {
+        unsafe { *(ctx as *mut usize) += 1 };
+        0
+    }
+
+    #[test]
+    fn errors() {
+        let path = CString::new("/nonexistent").unwrap();
+        let fs = unsafe { btrfs_kit_open(&path.as_ptr(), 1) };
+        assert!(fs.is_null());
+        let error = unsafe { CStr::from_ptr(btrfs_kit_last_error()) };
+        assert!(!error.to_bytes().is_empty());
+
+        let key = BtrfsKitKey {
+            objectid: 0,
+            item_type: 0,
+            offset: 0,
+        };
+        let mut items = 0_usize;
+        let ret = unsafe {
+            btrfs_kit_iterate_tree(
+                fs,
+                5,
+                &key,
+                &key,
+                count,
+                &mut items as *mut usize as *mut c_void,
+            )
+        };
+        assert_eq!(ret, -1);
+        assert_eq!(items, 0);
+        assert!(item_type(0xff).is_ok() && item_type(0x02).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+//! Recovery of file data from the data chunks alone, for when the trees
+//! which said what belonged to which file are gone.
+//!
+//! Runs of sectors matching the checksums which survive, in the csum tree or
+//! in csum tree leaves no current root reaches, are written out one file per
+//! run, cut wherever the extent tree (if it can be read) has an extent start.
+//! Sectors beginning with a known file signature can be carved as well, up
+//! to a size limit. Data is written as it is stored, so compressed extents
+//! come out compressed.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::census::find_orphans;
+use crate::flags::fmt_block_group_type;
+use crate::structures::*;
+use crate::tree::*;
+use crate::undelete::{allocated_data_extents, leaf_csums};
+
+use anyhow::*;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// the start of files of common types, by file name extension
+const SIGNATURES: &[(&str, &[u8])] = &[
+    ("jpg", b"\xff\xd8\xff"),
+    ("png", b"\x89PNG\r\n\x1a\n"),
+    ("gif", b"GIF8"),
+    ("pdf", b"%PDF-"),
+    ("zip", b"PK\x03\x04"),
+    ("gz", b"\x1f\x8b\x08"),
+    ("xz", b"\xfd7zXZ\0"),
+    ("zst", b"\x28\xb5\x2f\xfd"),
+    ("sqlite", b"SQLite format 3\0"),
+    ("elf", b"\x7fELF"),
+];
+
+/// the extension of the file type whose signature data starts with
+pub fn signature(data: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|(_, magic)| data.starts_with(magic))
+        .map(|(extension, _)| *extension)
+}
+
+pub struct CarveOptions {
+    /// where the carved files and their index are written
+    pub output_dir: PathBuf,
+    /// skip the data extents the extent tree records, to carve only what was
+    /// deleted
+    pub free_only: bool,
+    /// also carve from sectors starting with a known file signature
+    pub signatures: bool,
+    /// the most carved from a signature
+    pub max_size: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CarveMethod {
+    /// every sector matched its checksum
+    Checksums,
+    /// started with the signature of this file type
+    Signature(&'static str),
+}
+
+pub struct CarvedFile {
+    pub path: PathBuf,
+    pub logical: u64,
+    pub length: u64,
+    /// the copy the data was read from
+    pub devid: u64,
+    pub physical: u64,
+    pub method: CarveMethod,
+}
+
+#[derive(Default)]
+pub struct CarveScan {
+    pub files: Vec<CarvedFile>,
+    /// sectors with a checksum, and those which matched it
+    pub sectors_with_csums: u64,
+    pub sectors_verified: u64,
+    pub errors: Vec<String>,
+}
+
+/// the first copy of a sector which can be read: (devid, physical, data)
+fn read_sector(fs: &FsInfo, logical: u64) -> Option<(u64, u64, Vec<u8>)> {
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    block_copies(fs, logical, sectorsize)
+        .ok()?
+        .into_iter()
+        .find_map(|copy| Some((copy.devid, copy.physical, copy.data?)))
+}
+
+/// the checksum of each sector which has one, from the csum tree or, for
+/// sectors it doesn't cover, the newest unreachable csum tree leaf
+fn surviving_csums(fs: &FsInfo, scan: &mut CarveScan) -> BTreeMap<u64, Vec<u8>> {
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let csum_size = csum_size(fs.master_sb.csum_type);
+    let mut csums = BTreeMap::new();
+    let csum_roots = global_roots(fs, BTRFS_CSUM_TREE_OBJECTID);
+    if csum_roots.is_empty() {
+        scan.errors.push(String::from("csum tree not found"));
+    }
+    for (_, csum_root) in csum_roots {
+        let search = key_range(
+            Some(BTRFS_EXTENT_CSUM_OBJECTID),
+            Some(BtrfsItemType::EXTENT_CSUM),
+            None,
+        );
+        for (item, data, _block_offset, _slot) in search_range(fs, csum_root, search) {
+            let start = item.key.offset;
+            for (i, csum) in data.chunks_exact(csum_size).enumerate() {
+                csums.insert(start + i as u64 * sectorsize, csum.to_vec());
+            }
+        }
+    }
+    let orphans = find_orphans(fs);
+    scan.errors.extend(orphans.errors);
+    let mut leaves: Vec<_> = orphans
+        .orphans
+        .iter()
+        .filter(|o| o.owner == BTRFS_CSUM_TREE_OBJECTID && o.level == 0)
+        .collect();
+    leaves.sort_by_key(|o| std::cmp::Reverse(o.generation));
+    for leaf in leaves {
+        let Result::Ok(block) = load_virt_block(fs, leaf.logical) else {
+            continue;
+        };
+        for (logical, csum) in leaf_csums(fs, &block) {
+            csums.entry(logical).or_insert_with(|| csum.to_vec());
+        }
+    }
+    csums
+}
+
+/// writes the carved files, numbering them in turn
+struct Output<'a> {
+    dir: &'a Path,
+    index: std::fs::File,
+    scan: CarveScan,
+}
+
+impl Output<'_> {
+    /// write the sectors from logical to end, as read_sector finds them
+    fn write(&mut self, fs: &FsInfo, logical: u64, end: u64, method: CarveMethod) -> Result<()> {
+        let sectorsize = fs.master_sb.sectorsize as u64;
+        let number = self.scan.files.len() + 1;
+        let extension = match method {
+            CarveMethod::Checksums => "bin",
+            CarveMethod::Signature(extension) => extension,
+        };
+        let path = self.dir.join(format!("carved-{number:06}.{extension}"));
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("cannot create {}", path.display()))?;
+        let mut file = std::io::BufWriter::new(file);
+        let (mut devid, mut physical) = (0, 0);
+        for sector in (logical..end).step_by(sectorsize as usize) {
+            match read_sector(fs, sector) {
+                Some((d, p, data)) => {
+                    if sector == logical {
+                        (devid, physical) = (d, p);
+                    }
+                    file.write_all(&data)?;
+                }
+                None => file.write_all(&vec![0; sectorsize as usize])?,
+            }
+        }
+        file.flush()?;
+        let method_name = match method {
+            CarveMethod::Checksums => String::from("checksums"),
+            CarveMethod::Signature(extension) => format!("signature {extension}"),
+        };
+        writeln!(
+            self.index,
+            "{},{logical},{},{devid},{physical},{method_name}",
+            path.file_name().unwrap().to_string_lossy(),
+            end - logical
+        )?;
+        self.scan.files.push(CarvedFile {
+            path,
+            logical,
+            length: end - logical,
+            devid,
+            physical,
+            method,
+        });
+        Ok(())
+    }
+}
+
+/// scan the data chunks and write out the runs of sectors which match their
+/// checksums and, with options.signatures, what follows known file
+/// signatures, each as a numbered file in options.output_dir. An index.csv
+/// there gives the logical address, length and first copy of each. Striped
+/// chunks are skipped.
+pub fn carve(fs: &FsInfo, options: &CarveOptions) -> Result<CarveScan> {
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let csum_type = fs.master_sb.csum_type;
+    let dir = options.output_dir.as_path();
+    std::fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    let index_path = dir.join("index.csv");
+    let mut index = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&index_path)
+        .with_context(|| format!("cannot create {}", index_path.display()))?;
+    writeln!(index, "file,logical,length,devid,physical,method")?;
+    let mut out = Output {
+        dir,
+        index,
+        scan: CarveScan::default(),
+    };
+    let csums = surviving_csums(fs, &mut out.scan);
+    let allocated = match allocated_data_extents(fs) {
+        Result::Ok(allocated) => allocated,
+        Err(e) if !options.free_only => {
+            out.scan
+                .errors
+                .push(format!("{e}, so runs aren't cut at extents"));
+            Vec::new()
+        }
+        Err(e) => return Err(e.context("free space can't be told without the extent tree")),
+    };
+    // the allocated extent holding logical, if any
+    let extent_at = |logical: u64| {
+        let i = allocated.partition_point(|(_, end)| *end <= logical);
+        allocated
+            .get(i)
+            .filter(|(start, _)| *start <= logical)
+            .copied()
+    };
+    let skipped = |logical: u64| options.free_only && extent_at(logical).is_some();
+    let verifies = |logical: u64| {
+        csums.get(&logical).is_some_and(|expected| {
+            read_sector(fs, logical).is_some_and(|(_, _, data)| {
+                csum_data(&data, csum_type)[..expected.len()] == expected[..]
+            })
+        })
+    };
+
+    for ChunkInfo(key, chunk, _stripes) in all_chunks(fs) {
+        let chunk_type = chunk.r#type;
+        if chunk_type & BTRFS_BLOCK_GROUP_DATA == 0 {
+            continue;
+        }
+        let (start, end) = (key.offset, key.offset + chunk.length);
+        if chunk_type & STRIPED_PROFILES != 0 {
+            out.scan.errors.push(format!(
+                "chunk {start} ({}) is striped, which is not supported",
+                fmt_block_group_type(chunk_type)
+            ));
+            continue;
+        }
+        let mut logical = start;
+        while logical < end {
+            ensure!(!fs.cancel.is_cancelled(), "interrupted");
+            if !options.signatures {
+                // only sectors with checksums can start a file
+                match csums.range(logical..end).next() {
+                    Some((&next, _)) => logical = next,
+                    None => break,
+                }
+            }
+            if let Some((_, extent_end)) = extent_at(logical).filter(|_| options.free_only) {
+                logical = extent_end;
+                continue;
+            }
+            if csums.contains_key(&logical) {
+                out.scan.sectors_with_csums += 1;
+                if verifies(logical) {
+                    out.scan.sectors_verified += 1;
+                    let run = logical;
+                    logical += sectorsize;
+                    while logical < end
+                        && allocated
+                            .binary_search_by_key(&logical, |(s, _)| *s)
+                            .is_err()
+                        && !skipped(logical)
+                        && csums.contains_key(&logical)
+                        && verifies(logical)
+                    {
+                        out.scan.sectors_with_csums += 1;
+                        out.scan.sectors_verified += 1;
+                        logical += sectorsize;
+                    }
+                    out.write(fs, run, logical, CarveMethod::Checksums)?;
+                    continue;
+                }
+            }
+            let found = options
+                .signatures
+                .then(|| read_sector(fs, logical).and_then(|(_, _, data)| signature(&data)))
+                .flatten();
+            let Some(extension) = found else {
+                logical += sectorsize;
+                continue;
+            };
+            // up to the next file, as far as it can be told
+            let file = logical;
+            let limit = end.min(file + options.max_size.max(sectorsize));
+            logical += sectorsize;
+            while logical < limit
+                && !skipped(logical)
+                && !verifies(logical)
+                && read_sector(fs, logical).is_none_or(|(_, _, data)| signature(&data).is_none())
+            {
+                logical += sectorsize;
+            }
+            out.write(fs, file, logical, CarveMethod::Signature(extension))?;
+        }
+    }
+    Ok(out.scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures() {
+        assert_eq!(signature(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("png"));
+        assert_eq!(signature(b"SQLite format 3\0\x10\0"), Some("sqlite"));
+        assert_eq!(signature(b"PK\x05\x06"), None);
+        assert_eq!(signature(b""), None);
+    }
+}
@@ -0,0 +1,499 @@
+//! Census of metadata blocks: which tree and level each tree block belongs
+//! to, found both by walking every tree and from the extent tree. After
+//! damage the two disagree, and the blocks the extent tree records but no
+//! walk reached are the holes in each tree.
+//!
+//! Also here is the scan for orphans: blocks in metadata chunks which verify
+//! but can't be reached from any current root. Most are older generations
+//! of blocks since rewritten, which may help rebuild a damaged tree.
+
+use crate::address::*;
+use crate::backref::*;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::items::item_as;
+use crate::mapped_file::Advice;
+use crate::parallel::*;
+use crate::scrub::check_tree_block;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::{BTreeMap, HashSet};
+
+/// everything known about one tree block
+#[derive(Default)]
+pub struct CensusBlock {
+    /// tree whose walk first reached the block
+    pub reached_from: Option<u64>,
+    /// owner, level and generation from the header, if the block was reached
+    /// and verified
+    pub header: Option<(u64, u8, u64)>,
+    /// the block's EXTENT_ITEM or METADATA_ITEM, with its keyed refs
+    pub extent: Option<Extent>,
+    /// level recorded in the extent tree
+    pub extent_level: Option<u8>,
+}
+
+impl CensusBlock {
+    pub fn level(&self) -> Option<u8> {
+        self.header.map(|(_, level, _)| level).or(self.extent_level)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct LevelCensus {
+    /// blocks reached by walking the trees
+    pub reached: u64,
+    /// blocks the extent tree records
+    pub allocated: u64,
+}
+
+#[derive(Default)]
+pub struct TreeCensus {
+    pub levels: BTreeMap<u8, LevelCensus>,
+    /// (bytenr, level) of the blocks the extent tree records for the tree
+    /// which no walk reached
+    pub holes: Vec<(u64, Option<u8>)>,
+}
+
+#[derive(Default)]
+pub struct Census {
+    pub blocks: BTreeMap<u64, CensusBlock>,
+    /// keyed by owning tree; 0 collects blocks whose owner can't be found
+    pub trees: BTreeMap<u64, TreeCensus>,
+    /// (bytenr, tree walked, reason) of blocks which couldn't be read or
+    /// didn't verify. Nothing below them was reached.
+    pub damaged: Vec<(u64, u64, String)>,
+    /// (bytenr, reason) of reached blocks whose extent item is missing or
+    /// disagrees with the header
+    pub mismatches: Vec<(u64, String)>,
+}
+
+/// walk every tree, visiting each block once
+fn walk(fs: &FsInfo, census: &mut Census) {
+    let mut seen = HashSet::new();
+    for (tree, root, level) in tree_roots(fs) {
+        let mut stack = vec![(root, Some(level))];
+        while let Some((logical, expected_level)) = stack.pop() {
+            if fs.cancel.is_cancelled() {
+                return;
+            }
+            if !seen.insert(logical) {
+                // a pointer at the wrong level may lead back up the tree
+                let level = census
+                    .blocks
+                    .get(&logical)
+                    .and_then(|b| b.header)
+                    .map(|h| h.1);
+                if let (Some(level), Some(expected)) = (level, expected_level) {
+                    if level != expected {
+                        census.damaged.push((
+                            logical,
+                            tree,
+                            format!("level {level}, also pointed to as level {expected}"),
+                        ));
+                    }
+                }
+                continue;
+            }
+            let block = match load_virt_block_unverified(fs, logical) {
+                Result::Ok(block) => block,
+                Err(e) => {
+                    census.damaged.push((logical, tree, e.to_string()));
+                    continue;
+                }
+            };
+            let entry = census.blocks.entry(logical).or_default();
+            entry.reached_from = Some(tree);
+            let problems = check_tree_block(fs, &block, logical, expected_level);
+            if !problems.is_empty() {
+                census.damaged.push((logical, tree, problems.join(", ")));
+                continue;
+            }
+            let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+            let level = header.level;
+            entry.header = Some((header.owner, level, header.generation));
+            for node_entry in node_entries(&block).into_iter().rev() {
+                if let NodeEntry::Ptr(ptr) = node_entry {
+                    let child = ptr.blockptr;
+                    stack.push((child, level.checked_sub(1)));
+                }
+            }
+        }
+    }
+}
+
+/// what walk_parallel found reading one block
+enum Visited {
+    Unreadable(String),
+    Damaged(String),
+    Verified((u64, u8, u64)),
+}
+
+/// walk with the blocks of each level of a tree read and checked on jobs
+/// threads
+fn walk_parallel(fs: &FsInfo, jobs: usize, census: &mut Census) -> Result<()> {
+    let roots: Vec<_> = tree_roots(fs)
+        .into_iter()
+        .map(|(tree, root, level)| (tree, root, Some(level)))
+        .collect();
+    let mut revisits = Vec::new();
+    walk_levels(
+        fs,
+        jobs,
+        &roots,
+        |_, logical, expected_level| {
+            let block = match load_virt_block_unverified(fs, logical) {
+                Result::Ok(block) => block,
+                Err(e) => return (Visited::Unreadable(e.to_string()), Vec::new()),
+            };
+            let problems = check_tree_block(fs, &block, logical, expected_level);
+            if !problems.is_empty() {
+                return (Visited::Damaged(problems.join(", ")), Vec::new());
+            }
+            let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+            (
+                Visited::Verified((header.owner, header.level, header.generation)),
+                child_pointers(&block),
+            )
+        },
+        |&tree, logical, visited| match visited {
+            Visited::Unreadable(e) => census.damaged.push((logical, tree, e)),
+            Visited::Damaged(problems) => {
+                census.blocks.entry(logical).or_default().reached_from = Some(tree);
+                census.damaged.push((logical, tree, problems));
+            }
+            Visited::Verified(header) => {
+                let entry = census.blocks.entry(logical).or_default();
+                entry.reached_from = Some(tree);
+                entry.header = Some(header);
+            }
+        },
+        |&tree, logical, expected_level| revisits.push((logical, tree, expected_level)),
+    )?;
+    // a pointer at the wrong level may lead back up the tree. The block may
+    // have been visited in the same level as the pointer, so these are only
+    // checked once every header is known.
+    for (logical, tree, expected_level) in revisits {
+        let level = census
+            .blocks
+            .get(&logical)
+            .and_then(|b| b.header)
+            .map(|h| h.1);
+        if let (Some(level), Some(expected)) = (level, expected_level) {
+            if level != expected {
+                census.damaged.push((
+                    logical,
+                    tree,
+                    format!("level {level}, also pointed to as level {expected}"),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// record every tree block the extent tree holds
+fn read_extent_tree(fs: &FsInfo, census: &mut Census) -> Result<()> {
+    let extent_roots = global_roots(fs, BTRFS_EXTENT_TREE_OBJECTID);
+    ensure!(!extent_roots.is_empty(), "extent tree not found");
+    let search = key_range(None, None, None);
+    let mut last = None;
+    let items = extent_roots
+        .into_iter()
+        .flat_map(|(_, extent_root)| search_range(fs, extent_root, search));
+    for (item, data, _block_offset, _slot) in items {
+        let key = item.key;
+        match key.item_type {
+            BtrfsItemType::EXTENT_ITEM | BtrfsItemType::METADATA_ITEM => {
+                last = None;
+                let Some(extent) = extent_from_item(fs, &key, &data) else {
+                    continue;
+                };
+                if !extent.is_tree_block() {
+                    continue;
+                }
+                let level = if key.item_type == BtrfsItemType::METADATA_ITEM {
+                    Some(key.offset as u8)
+                } else {
+                    data.get(std::mem::size_of::<btrfs_extent_item>()..)
+                        .and_then(item_as::<btrfs_tree_block_info>)
+                        .map(|info| info.level)
+                };
+                let entry = census.blocks.entry(key.objectid).or_default();
+                entry.extent = Some(extent);
+                entry.extent_level = level;
+                last = Some(key.objectid);
+            }
+            _ => {
+                let Some(r) = keyed_ref(&key, &data) else {
+                    continue;
+                };
+                let objectid = key.objectid;
+                if last == Some(objectid) {
+                    if let Some(extent) = census
+                        .blocks
+                        .get_mut(&objectid)
+                        .and_then(|b| b.extent.as_mut())
+                    {
+                        extent.refs.push(r);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the trees owning a block: the roots of its tree block refs, otherwise the
+/// header's owner, otherwise the owners of the parents of its shared refs
+fn owners(census: &Census, bytenr: u64, depth: u8) -> Vec<u64> {
+    let Some(block) = census.blocks.get(&bytenr) else {
+        return Vec::new();
+    };
+    let refs = block.extent.as_ref().map(|e| &e.refs[..]).unwrap_or(&[]);
+    let roots: Vec<u64> = refs
+        .iter()
+        .filter_map(|r| match *r {
+            ExtentRef::TreeBlock { root } => Some(root),
+            _ => None,
+        })
+        .collect();
+    if !roots.is_empty() {
+        return roots;
+    }
+    if let Some((owner, _, _)) = block.header {
+        return vec![owner];
+    }
+    if depth >= BTRFS_MAX_LEVEL {
+        return Vec::new();
+    }
+    let mut owners_found = Vec::new();
+    for r in refs {
+        if let ExtentRef::SharedBlock { parent } = *r {
+            for owner in owners(census, parent, depth + 1) {
+                if !owners_found.contains(&owner) {
+                    owners_found.push(owner);
+                }
+            }
+        }
+    }
+    owners_found
+}
+
+/// walk every tree and read the extent tree, then cross-check the two
+pub fn metadata_census(fs: &FsInfo) -> Result<Census> {
+    metadata_census_parallel(fs, 1)
+}
+
+/// metadata_census with the walk reading and checking the blocks of each
+/// level of a tree on jobs threads (0 for one per CPU). Only with jobs 1 is
+/// the walk sequential.
+pub fn metadata_census_parallel(fs: &FsInfo, jobs: usize) -> Result<Census> {
+    let mut census = Census::default();
+    if jobs == 1 {
+        walk(fs, &mut census);
+    } else {
+        walk_parallel(fs, jobs, &mut census)?;
+    }
+    read_extent_tree(fs, &mut census)?;
+
+    let mut trees = BTreeMap::<u64, TreeCensus>::new();
+    let mut mismatches = Vec::new();
+    for (&bytenr, block) in &census.blocks {
+        if let Some((owner, level, _)) = block.header {
+            match (&block.extent, block.extent_level) {
+                // log tree blocks are never in the extent tree
+                (None, _) if block.reached_from != Some(BTRFS_TREE_LOG_OBJECTID) => {
+                    mismatches.push((bytenr, String::from("not in the extent tree")));
+                }
+                (Some(extent), extent_level) => {
+                    if let Some(extent_level) = extent_level.filter(|&l| l != level) {
+                        mismatches.push((
+                            bytenr,
+                            format!("level {level}, the extent tree records {extent_level}"),
+                        ));
+                    }
+                    let roots: Vec<u64> = extent
+                        .refs
+                        .iter()
+                        .filter_map(|r| match *r {
+                            ExtentRef::TreeBlock { root } => Some(root),
+                            _ => None,
+                        })
+                        .collect();
+                    if !roots.is_empty() && !roots.contains(&owner) {
+                        mismatches.push((
+                            bytenr,
+                            format!("owner {owner} has no tree block ref, the refs are {roots:?}"),
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+        if block.header.is_none() && block.extent.is_none() {
+            continue;
+        }
+        let level = block.level().unwrap_or(0);
+        let mut block_owners = owners(&census, bytenr, 0);
+        if block_owners.is_empty() {
+            block_owners.push(0);
+        }
+        for owner in block_owners {
+            let tree = trees.entry(owner).or_default();
+            let counts = tree.levels.entry(level).or_default();
+            if block.header.is_some() {
+                counts.reached += 1;
+            }
+            if block.extent.is_some() {
+                counts.allocated += 1;
+                if block.header.is_none() {
+                    tree.holes.push((bytenr, block.level()));
+                }
+            }
+        }
+    }
+    census.trees = trees;
+    census.mismatches = mismatches;
+    Ok(census)
+}
+
+/// a tree block which verifies but isn't reachable from any current root
+pub struct OrphanBlock {
+    pub logical: u64,
+    /// the first copy of the block found to verify
+    pub devid: u64,
+    pub physical: u64,
+    pub owner: u64,
+    pub level: u8,
+    pub generation: u64,
+    /// None if the block is empty
+    pub first_key: Option<btrfs_disk_key>,
+}
+
+#[derive(Default)]
+pub struct OrphanScan {
+    /// node sized slots of metadata and system chunks read
+    pub scanned: u64,
+    pub orphans: Vec<OrphanBlock>,
+    /// chunks which couldn't be scanned, e.g. for having a striped profile
+    pub errors: Vec<String>,
+}
+
+/// read every node sized slot of every copy of every metadata and system
+/// chunk, listing the blocks that verify as they would if still in use
+/// (checksum, fsid, bytenr, level and nritems) but that no walk reaches
+pub fn find_orphans(fs: &FsInfo) -> OrphanScan {
+    let mut census = Census::default();
+    walk(fs, &mut census);
+    let nodesize = fs.master_sb.nodesize as u64;
+    let mut scan = OrphanScan::default();
+    for ChunkInfo(key, chunk, _stripes) in all_chunks(fs) {
+        let chunk_type = chunk.r#type;
+        if chunk_type & (BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_SYSTEM) == 0 {
+            continue;
+        }
+        let start = key.offset;
+        let end = start + chunk.length;
+        advise_logical(fs, start, chunk.length, Advice::Sequential, false);
+        for logical in (start.next_multiple_of(nodesize)..end).step_by(nodesize as usize) {
+            if logical + nodesize > end || fs.cancel.is_cancelled() {
+                break;
+            }
+            let copies = match block_copies(fs, logical, nodesize) {
+                Result::Ok(copies) => copies,
+                Err(e) => {
+                    scan.errors.push(e.to_string());
+                    break;
+                }
+            };
+            scan.scanned += 1;
+            if census
+                .blocks
+                .get(&logical)
+                .is_some_and(|b| b.reached_from.is_some())
+            {
+                continue;
+            }
+            let Some((copy, block)) = copies.iter().find_map(|c| {
+                c.data
+                    .as_deref()
+                    .filter(|block| check_tree_block(fs, block, logical, None).is_empty())
+                    .map(|block| (c, block))
+            }) else {
+                continue;
+            };
+            let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+            let first_key = node_entries(block).first().map(|entry| match entry {
+                NodeEntry::Ptr(ptr) => ptr.key,
+                NodeEntry::Item(item, _) => item.key,
+            });
+            scan.orphans.push(OrphanBlock {
+                logical,
+                devid: copy.devid,
+                physical: copy.physical,
+                owner: header.owner,
+                level: header.level,
+                generation: header.generation,
+                first_key,
+            });
+        }
+    }
+    scan
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree_block(refs: Vec<ExtentRef>) -> CensusBlock {
+        CensusBlock {
+            extent: Some(Extent {
+                key: btrfs_disk_key {
+                    objectid: 0,
+                    item_type: BtrfsItemType::METADATA_ITEM,
+                    offset: 0,
+                },
+                start: 0,
+                length: 4096,
+                refs_count: refs.len() as u64,
+                generation: 1,
+                flags: BTRFS_EXTENT_FLAG_TREE_BLOCK,
+                refs,
+                problem: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn owner_resolution() {
+        let mut census = Census::default();
+        census.blocks.insert(
+            4096,
+            tree_block(vec![
+                ExtentRef::TreeBlock { root: 5 },
+                ExtentRef::TreeBlock { root: 256 },
+            ]),
+        );
+        census.blocks.insert(
+            8192,
+            tree_block(vec![ExtentRef::SharedBlock { parent: 4096 }]),
+        );
+        census.blocks.insert(
+            12288,
+            tree_block(vec![ExtentRef::SharedBlock { parent: 8192 }]),
+        );
+        assert_eq!(owners(&census, 12288, 0), vec![5, 256]);
+        census.blocks.get_mut(&8192).unwrap().header = Some((257, 1, 10));
+        assert_eq!(owners(&census, 12288, 0), vec![257]);
+        // a parent that isn't a known tree block
+        census.blocks.insert(
+            16384,
+            tree_block(vec![ExtentRef::SharedBlock { parent: 65536 }]),
+        );
+        assert!(owners(&census, 16384, 0).is_empty());
+    }
+}
@@ -0,0 +1,834 @@
+//! Offline tree checker: the structural rules the kernel's tree-checker
+//! applies to every tree block it reads, applied to every block of every
+//! tree. Nothing is asserted; every violation found is collected into one
+//! report.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::items::item_as;
+use crate::scrub::check_tree_block;
+use crate::space::stripe_length;
+use crate::structures::*;
+use crate::tree::*;
+use crate::zoned::check_zoned;
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+
+const BTRFS_NAME_LEN: usize = 255;
+const BTRFS_DEV_ITEMS_OBJECTID: u64 = 1;
+/// ROOT_ITEMs written by old kernels end before generation_v2
+const BTRFS_LEGACY_ROOT_ITEM_SIZE: usize = std::mem::offset_of!(btrfs_root_item, generation_v2);
+
+/// one rule violation
+pub struct CheckProblem {
+    pub tree: u64,
+    pub block: u64,
+    /// the item or key pointer the problem is with, if not the whole block
+    pub slot: Option<usize>,
+    pub message: String,
+}
+
+#[derive(Default)]
+pub struct CheckReport {
+    pub trees: u64,
+    pub blocks: u64,
+    pub items: u64,
+    pub problems: Vec<CheckProblem>,
+}
+
+/// what a block's parent pointer (or the root item) says about it
+#[derive(Clone, Copy, Default)]
+pub struct Expected {
+    pub level: Option<u8>,
+    /// the parent's key for the block, which must be its first key
+    pub first_key: Option<btrfs_disk_key>,
+    /// the parent's next key; every key of the block must be below it
+    pub end_key: Option<btrfs_disk_key>,
+    /// the generation recorded in the parent's key pointer
+    pub generation: Option<u64>,
+}
+
+fn is_fs_tree(tree: u64) -> bool {
+    tree == BTRFS_FS_TREE_OBJECTID
+        || tree == BTRFS_TREE_RELOC_OBJECTID
+        || tree == BTRFS_DATA_RELOC_TREE_OBJECTID
+        || (BTRFS_FIRST_FREE_OBJECTID..=BTRFS_LAST_FREE_OBJECTID).contains(&tree)
+}
+
+/// problems with the data of one item, judging by its key
+pub fn check_item(
+    key: &btrfs_disk_key,
+    data: &[u8],
+    sectorsize: u64,
+    csum_size: usize,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let size = data.len();
+    let objectid = key.objectid;
+    let offset = key.offset;
+    let expect_size = |what: &str, ok: bool, expected: String| {
+        (!ok).then(|| format!("{what} is {size} bytes, expected {expected}"))
+    };
+    match key.item_type {
+        BtrfsItemType::INODE_ITEM => {
+            let expected = std::mem::size_of::<btrfs_inode_item>();
+            problems.extend(expect_size(
+                "inode item",
+                size == expected,
+                expected.to_string(),
+            ));
+            if offset != 0 {
+                problems.push(format!("inode item key offset is {offset}, expected 0"));
+            }
+        }
+        BtrfsItemType::ROOT_ITEM => {
+            let expected = std::mem::size_of::<btrfs_root_item>();
+            problems.extend(expect_size(
+                "root item",
+                size == expected || size == BTRFS_LEGACY_ROOT_ITEM_SIZE,
+                format!("{expected} or {BTRFS_LEGACY_ROOT_ITEM_SIZE}"),
+            ));
+            if objectid == 0 {
+                problems.push(String::from("root item for tree 0"));
+            }
+        }
+        BtrfsItemType::DIR_ITEM | BtrfsItemType::DIR_INDEX | BtrfsItemType::XATTR_ITEM => {
+            let header_size = std::mem::size_of::<btrfs_dir_item>();
+            let mut rest = data;
+            let mut entries = 0;
+            while !rest.is_empty() {
+                let Some(di) = item_as::<btrfs_dir_item>(rest) else {
+                    problems.push(format!(
+                        "{} trailing bytes are too short for a dir item",
+                        rest.len()
+                    ));
+                    break;
+                };
+                let name_len = di.name_len as usize;
+                let data_len = di.data_len as usize;
+                entries += 1;
+                if name_len > BTRFS_NAME_LEN {
+                    problems.push(format!("dir item name is {name_len} bytes long"));
+                }
+                if data_len != 0 && key.item_type != BtrfsItemType::XATTR_ITEM {
+                    problems.push(format!("dir item has {data_len} bytes of data"));
+                }
+                let entry_len = header_size + name_len + data_len;
+                if entry_len > rest.len() {
+                    problems.push(format!(
+                        "dir item of {entry_len} bytes runs past the end of the item"
+                    ));
+                    break;
+                }
+                rest = &rest[entry_len..];
+            }
+            if key.item_type == BtrfsItemType::DIR_INDEX && entries > 1 {
+                problems.push(format!("dir index holds {entries} entries"));
+            }
+        }
+        BtrfsItemType::INODE_REF => {
+            check_names::<btrfs_inode_ref>(data, |r| r.name_len, &mut problems)
+        }
+        BtrfsItemType::INODE_EXTREF => {
+            check_names::<btrfs_inode_extref>(data, |r| r.name_len, &mut problems)
+        }
+        BtrfsItemType::ROOT_REF | BtrfsItemType::ROOT_BACKREF => {
+            match item_as::<btrfs_root_ref>(data) {
+                Some(r) => {
+                    let expected = std::mem::size_of::<btrfs_root_ref>() + r.name_len as usize;
+                    problems.extend(expect_size(
+                        "root ref",
+                        size == expected,
+                        expected.to_string(),
+                    ));
+                }
+                None => problems.push(format!("root ref is only {size} bytes")),
+            }
+        }
+        BtrfsItemType::EXTENT_DATA => {
+            if !offset.is_multiple_of(sectorsize) {
+                problems.push(format!(
+                    "file extent offset {offset} is not aligned to the sector size"
+                ));
+            }
+            if size < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+                problems.push(format!("file extent item is only {size} bytes"));
+                return problems;
+            }
+            let fe = unsafe { &*(data.as_ptr() as *const btrfs_file_extent_item) };
+            match fe.r#type {
+                BTRFS_FILE_EXTENT_INLINE => {
+                    let ram_bytes = fe.ram_bytes;
+                    let inline = size - BTRFS_FILE_EXTENT_INLINE_DATA_START;
+                    if fe.compression == BTRFS_COMPRESS_NONE && inline as u64 != ram_bytes {
+                        problems.push(format!(
+                            "uncompressed inline extent holds {inline} bytes, ram_bytes is {ram_bytes}"
+                        ));
+                    }
+                }
+                BTRFS_FILE_EXTENT_REG | BTRFS_FILE_EXTENT_PREALLOC => {
+                    let expected = std::mem::size_of::<btrfs_file_extent_item>();
+                    problems.extend(expect_size(
+                        "file extent item",
+                        size == expected,
+                        expected.to_string(),
+                    ));
+                    if let Some(fe) = item_as::<btrfs_file_extent_item>(data) {
+                        for (field, value) in [
+                            ("disk_bytenr", fe.disk_bytenr),
+                            ("disk_num_bytes", fe.disk_num_bytes),
+                            ("offset", fe.offset),
+                            ("num_bytes", fe.num_bytes),
+                        ] {
+                            if !value.is_multiple_of(sectorsize) {
+                                problems.push(format!(
+                                    "file extent {field} {value} is not aligned to the sector size"
+                                ));
+                            }
+                        }
+                    }
+                }
+                t => problems.push(format!("unknown file extent type {t}")),
+            }
+        }
+        BtrfsItemType::EXTENT_CSUM => {
+            if objectid != BTRFS_EXTENT_CSUM_OBJECTID {
+                problems.push(format!("csum item objectid is {objectid}"));
+            }
+            if !offset.is_multiple_of(sectorsize) {
+                problems.push(format!(
+                    "csum item offset {offset} is not aligned to the sector size"
+                ));
+            }
+            problems.extend(expect_size(
+                "csum item",
+                size.is_multiple_of(csum_size),
+                format!("a multiple of {csum_size}"),
+            ));
+        }
+        BtrfsItemType::CHUNK_ITEM => match item_as::<btrfs_chunk>(data) {
+            Some(chunk) => {
+                let num_stripes = chunk.num_stripes as usize;
+                let expected = std::mem::size_of::<btrfs_chunk>()
+                    + num_stripes * std::mem::size_of::<btrfs_stripe>();
+                problems.extend(expect_size(
+                    "chunk item",
+                    size == expected,
+                    expected.to_string(),
+                ));
+                if num_stripes == 0 {
+                    problems.push(String::from("chunk has no stripes"));
+                }
+                let length = chunk.length;
+                if length == 0 || !length.is_multiple_of(sectorsize) {
+                    problems.push(format!(
+                        "chunk length {length} is not a multiple of the sector size"
+                    ));
+                }
+            }
+            None => problems.push(format!("chunk item is only {size} bytes")),
+        },
+        BtrfsItemType::DEV_ITEM => {
+            let expected = std::mem::size_of::<btrfs_dev_item>();
+            problems.extend(expect_size(
+                "dev item",
+                size == expected,
+                expected.to_string(),
+            ));
+            if objectid != BTRFS_DEV_ITEMS_OBJECTID {
+                problems.push(format!("dev item objectid is {objectid}"));
+            }
+            if let Some(dev) = item_as::<btrfs_dev_item>(data) {
+                let devid = dev.devid;
+                if devid != offset {
+                    problems.push(format!(
+                        "dev item for devid {devid} has key offset {offset}"
+                    ));
+                }
+            }
+        }
+        BtrfsItemType::DEV_EXTENT => {
+            let expected = std::mem::size_of::<btrfs_dev_extent>();
+            problems.extend(expect_size(
+                "dev extent",
+                size == expected,
+                expected.to_string(),
+            ));
+        }
+        BtrfsItemType::BLOCK_GROUP_ITEM => {
+            let expected = std::mem::size_of::<btrfs_block_group_item>();
+            problems.extend(expect_size(
+                "block group item",
+                size == expected,
+                expected.to_string(),
+            ));
+            if offset == 0 {
+                problems.push(String::from("block group has length 0"));
+            }
+            if let Some(bg) = item_as::<btrfs_block_group_item>(data) {
+                let used = bg.used;
+                if used > offset {
+                    problems.push(format!("block group uses {used} of {offset} bytes"));
+                }
+            }
+        }
+        BtrfsItemType::EXTENT_ITEM | BtrfsItemType::METADATA_ITEM => {
+            let expected = std::mem::size_of::<btrfs_extent_item>();
+            problems.extend(expect_size(
+                "extent item",
+                size >= expected,
+                format!("at least {expected}"),
+            ));
+            if !objectid.is_multiple_of(sectorsize) {
+                problems.push(format!(
+                    "extent {objectid} is not aligned to the sector size"
+                ));
+            }
+            if key.item_type == BtrfsItemType::EXTENT_ITEM && offset == 0 {
+                problems.push(String::from("extent has length 0"));
+            }
+            if key.item_type == BtrfsItemType::METADATA_ITEM && offset >= 8 {
+                problems.push(format!("metadata item level is {offset}"));
+            }
+        }
+        _ => {}
+    }
+    problems
+}
+
+/// INODE_REF style items: a sequence of T, each followed by a name
+fn check_names<T>(data: &[u8], name_len: impl Fn(&T) -> u16, problems: &mut Vec<String>) {
+    let mut rest = data;
+    while !rest.is_empty() {
+        let Some(entry) = item_as::<T>(rest) else {
+            problems.push(format!(
+                "{} trailing bytes are too short for a ref",
+                rest.len()
+            ));
+            return;
+        };
+        let entry_len = std::mem::size_of::<T>() + name_len(entry) as usize;
+        if entry_len > rest.len() {
+            problems.push(format!(
+                "ref of {entry_len} bytes runs past the end of the item"
+            ));
+            return;
+        }
+        rest = &rest[entry_len..];
+    }
+}
+
+/// the first and last keys of a block, to set against its parent's keys
+fn check_key_bounds(
+    first: Option<btrfs_disk_key>,
+    last: Option<btrfs_disk_key>,
+    expected: &Expected,
+    problems: &mut Vec<(Option<usize>, String)>,
+) {
+    if let (Some(first), Some(parent)) = (first, expected.first_key) {
+        if cmp_key(&first, &parent) != Ordering::Equal {
+            problems.push((
+                Some(0),
+                format!("first key {first:?} doesn't match the parent's key {parent:?}"),
+            ));
+        }
+    }
+    if let (Some(last), Some(end)) = (last, expected.end_key) {
+        if cmp_key(&last, &end) != Ordering::Less {
+            problems.push((
+                None,
+                format!("last key {last:?} is not below the parent's next key {end:?}"),
+            ));
+        }
+    }
+}
+
+/// key order and item layout of a leaf: the item headers grow from the
+/// front of the block, their data from the back, and neither may overlap
+pub fn check_leaf_layout(block: &[u8]) -> Vec<(Option<usize>, String)> {
+    let mut problems = Vec::new();
+    let header_size = std::mem::size_of::<btrfs_header>();
+    let item_size = std::mem::size_of::<btrfs_item>();
+    let data_size = block.len() - header_size;
+    let mut previous: Option<btrfs_disk_key> = None;
+    let mut data_end = data_size;
+    for (slot, entry) in node_entries(block).into_iter().enumerate() {
+        let NodeEntry::Item(item, _data) = entry else {
+            continue;
+        };
+        let key = item.key;
+        let offset = item.offset as usize;
+        let size = item.size as usize;
+        if let Some(prev) = previous {
+            if cmp_key(&key, &prev) != Ordering::Greater {
+                problems.push((
+                    Some(slot),
+                    format!("key {key:?} is not after the previous key {prev:?}"),
+                ));
+            }
+        }
+        previous = Some(key);
+        if offset + size > data_size {
+            problems.push((
+                Some(slot),
+                format!("item data {offset}+{size} runs past the end of the leaf"),
+            ));
+        } else if offset + size != data_end {
+            problems.push((
+                Some(slot),
+                format!(
+                    "item data {offset}+{size} should end at {data_end}, where the previous item's starts"
+                ),
+            ));
+        }
+        if (slot + 1) * item_size > offset {
+            problems.push((
+                Some(slot),
+                format!("item data at {offset} overlaps the item headers"),
+            ));
+        }
+        data_end = offset;
+    }
+    problems
+}
+
+/// every problem with one tree block, and the blocks it points to with what
+/// is expected of them
+fn check_block(
+    fs: &FsInfo,
+    tree: u64,
+    logical: u64,
+    expected: &Expected,
+    is_root: bool,
+    report: &mut CheckReport,
+) -> Vec<(u64, Expected)> {
+    let mut problems: Vec<(Option<usize>, String)> = Vec::new();
+    let mut children = Vec::new();
+    let block = match load_virt_block_unverified(fs, logical) {
+        Result::Ok(block) => block,
+        Err(e) => {
+            report.problems.push(CheckProblem {
+                tree,
+                block: logical,
+                slot: None,
+                message: e.to_string(),
+            });
+            return children;
+        }
+    };
+    report.blocks += 1;
+    for message in check_tree_block(fs, &block, logical, expected.level) {
+        problems.push((None, message));
+    }
+    let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+    let owner = header.owner;
+    let generation = header.generation;
+    let nritems = header.nritems;
+    let sb_generation = fs.master_sb.generation;
+    if owner == 0 {
+        problems.push((None, String::from("owner is 0")));
+    } else if !is_fs_tree(tree) && owner != tree {
+        problems.push((None, format!("owner is {owner}, expected {tree}")));
+    }
+    // the log tree is written in the transaction after the superblock's
+    let max_generation = sb_generation + u64::from(tree == BTRFS_TREE_LOG_OBJECTID);
+    if let Some(expected_generation) = expected.generation {
+        if generation != expected_generation {
+            problems.push((
+                None,
+                format!(
+                    "generation {generation}, its parent pointer expects {expected_generation}"
+                ),
+            ));
+        }
+    }
+    if generation > max_generation {
+        problems.push((
+            None,
+            format!("generation {generation} is newer than the superblock's {sb_generation}"),
+        ));
+    }
+
+    let entries = node_entries(&block);
+    if header.level == 0 {
+        if nritems == 0 && !is_root {
+            problems.push((None, String::from("leaf is empty and not a tree root")));
+        }
+        problems.extend(check_leaf_layout(&block));
+        let sectorsize = fs.master_sb.sectorsize as u64;
+        let csum_size = csum_size(fs.master_sb.csum_type);
+        let mut keys = Vec::new();
+        for (slot, entry) in entries.iter().enumerate() {
+            let NodeEntry::Item(item, data) = entry else {
+                continue;
+            };
+            report.items += 1;
+            let key = item.key;
+            keys.push(key);
+            if let Some(data) = data {
+                for message in check_item(&key, data, sectorsize, csum_size) {
+                    problems.push((Some(slot), message));
+                }
+            }
+        }
+        check_key_bounds(
+            keys.first().copied(),
+            keys.last().copied(),
+            expected,
+            &mut problems,
+        );
+    } else {
+        if nritems == 0 {
+            problems.push((None, String::from("node has no key pointers")));
+        }
+        let ptrs: Vec<&btrfs_key_ptr> = entries
+            .iter()
+            .filter_map(|e| match e {
+                NodeEntry::Ptr(ptr) => Some(*ptr),
+                NodeEntry::Item(..) => None,
+            })
+            .collect();
+        let nodesize = fs.master_sb.nodesize as u64;
+        for (slot, ptr) in ptrs.iter().enumerate() {
+            let key = ptr.key;
+            let blockptr = ptr.blockptr;
+            let ptr_generation = ptr.generation;
+            if slot > 0 {
+                let prev = ptrs[slot - 1].key;
+                if cmp_key(&key, &prev) != Ordering::Greater {
+                    problems.push((
+                        Some(slot),
+                        format!("key {key:?} is not after the previous key {prev:?}"),
+                    ));
+                }
+            }
+            if blockptr == 0 || !blockptr.is_multiple_of(nodesize) {
+                problems.push((
+                    Some(slot),
+                    format!("block pointer {blockptr} is not aligned to the node size"),
+                ));
+                continue;
+            }
+            if ptr_generation == 0 || ptr_generation > generation {
+                problems.push((
+                    Some(slot),
+                    format!(
+                        "pointer generation {ptr_generation} is outside 1..={generation}, the node's generation"
+                    ),
+                ));
+            }
+            children.push((
+                blockptr,
+                Expected {
+                    level: header.level.checked_sub(1),
+                    first_key: Some(key),
+                    end_key: ptrs.get(slot + 1).map(|next| next.key).or(expected.end_key),
+                    generation: Some(ptr_generation),
+                },
+            ));
+        }
+        check_key_bounds(
+            ptrs.first().map(|p| p.key),
+            ptrs.last().map(|p| p.key),
+            expected,
+            &mut problems,
+        );
+    }
+    report
+        .problems
+        .extend(problems.into_iter().map(|(slot, message)| CheckProblem {
+            tree,
+            block: logical,
+            slot,
+            message,
+        }));
+    children
+}
+
+/// check every block of the tree rooted at root. seen holds the blocks
+/// already checked, e.g. through a snapshot sharing them, which are skipped.
+/// A block reached twice within the tree is reported and not followed
+/// again, as the pointer may lead round in a cycle.
+pub fn check_tree(
+    fs: &FsInfo,
+    tree: u64,
+    root: u64,
+    root_level: Option<u8>,
+    seen: &mut HashSet<u64>,
+    report: &mut CheckReport,
+) {
+    report.trees += 1;
+    let mut stack = vec![(
+        root,
+        Expected {
+            level: root_level,
+            ..Default::default()
+        },
+    )];
+    let mut in_tree = HashSet::new();
+    while let Some((logical, expected)) = stack.pop() {
+        if fs.cancel.is_cancelled() {
+            return;
+        }
+        if !in_tree.insert(logical) {
+            report.problems.push(CheckProblem {
+                tree,
+                block: logical,
+                slot: None,
+                message: String::from("block is referenced more than once in the tree"),
+            });
+            continue;
+        }
+        if !seen.insert(logical) {
+            continue;
+        }
+        let children = check_block(fs, tree, logical, &expected, logical == root, report);
+        stack.extend(children.into_iter().rev());
+    }
+}
+
+/// check every tree reachable from the superblock and the root tree
+/// a DEV_EXTENT item, with where it was found
+struct DevExtent {
+    block: u64,
+    slot: usize,
+    length: u64,
+    chunk_tree: u64,
+    chunk_objectid: u64,
+    chunk_offset: u64,
+    /// whether a chunk stripe claims it
+    claimed: bool,
+}
+
+/// that every stripe of every chunk has a DEV_EXTENT at its devid and
+/// offset, of its length and pointing back at its chunk, and that every
+/// DEV_EXTENT is a stripe of some chunk and overlaps no other on its device
+pub fn check_dev_extents(fs: &FsInfo) -> Vec<CheckProblem> {
+    let mut problems = Vec::new();
+    let Some(dev_root) = tree_root_offset(fs, BTRFS_DEV_TREE_OBJECTID) else {
+        return problems;
+    };
+    let mut extents = BTreeMap::<(u64, u64), DevExtent>::new();
+    let search = key_range(None, Some(BtrfsItemType::DEV_EXTENT), None);
+    for (item, data, block, slot) in search_range(fs, dev_root, search) {
+        if item.key.item_type != BtrfsItemType::DEV_EXTENT {
+            continue;
+        }
+        let Some(de) = item_as::<btrfs_dev_extent>(&data) else {
+            continue;
+        };
+        extents.insert(
+            (item.key.objectid, item.key.offset),
+            DevExtent {
+                block,
+                slot: slot as usize,
+                length: de.length,
+                chunk_tree: de.chunk_tree,
+                chunk_objectid: de.chunk_objectid,
+                chunk_offset: de.chunk_offset,
+                claimed: false,
+            },
+        );
+    }
+
+    let dev_problem =
+        |(devid, physical): (u64, u64), extent: &DevExtent, message: String| CheckProblem {
+            tree: BTRFS_DEV_TREE_OBJECTID,
+            block: extent.block,
+            slot: Some(extent.slot),
+            message: format!("dev extent devid {devid} physical {physical}: {message}"),
+        };
+    let search = key_range(
+        Some(BTRFS_FIRST_CHUNK_TREE_OBJECTID),
+        Some(BtrfsItemType::CHUNK_ITEM),
+        None,
+    );
+    for (item, data, block, slot) in search_range(fs, fs.master_sb.chunk_root, search) {
+        let logical = item.key.offset;
+        let Some(chunk) = item_as::<btrfs_chunk>(&data) else {
+            continue;
+        };
+        let length = stripe_length(chunk);
+        let stripes = data[std::mem::size_of::<btrfs_chunk>()..]
+            .chunks_exact(std::mem::size_of::<btrfs_stripe>())
+            .take(chunk.num_stripes as usize)
+            .filter_map(item_as::<btrfs_stripe>);
+        for stripe in stripes {
+            let at = (stripe.devid, stripe.offset);
+            let Some(extent) = extents.get_mut(&at) else {
+                problems.push(CheckProblem {
+                    tree: BTRFS_CHUNK_TREE_OBJECTID,
+                    block,
+                    slot: Some(slot as usize),
+                    message: format!(
+                        "chunk {logical}: stripe on devid {} at {} has no dev extent",
+                        at.0, at.1
+                    ),
+                });
+                continue;
+            };
+            if extent.claimed {
+                problems.push(dev_problem(
+                    at,
+                    extent,
+                    format!("is claimed again by a stripe of chunk {logical}"),
+                ));
+                continue;
+            }
+            extent.claimed = true;
+            if extent.chunk_offset != logical {
+                problems.push(dev_problem(
+                    at,
+                    extent,
+                    format!(
+                        "points at chunk {}, but is a stripe of chunk {logical}",
+                        extent.chunk_offset
+                    ),
+                ));
+            }
+            if extent.length != length {
+                problems.push(dev_problem(
+                    at,
+                    extent,
+                    format!(
+                        "is {} bytes, the stripes of chunk {logical} are {length}",
+                        extent.length
+                    ),
+                ));
+            }
+            if extent.chunk_tree != BTRFS_CHUNK_TREE_OBJECTID
+                || extent.chunk_objectid != BTRFS_FIRST_CHUNK_TREE_OBJECTID
+            {
+                problems.push(dev_problem(
+                    at,
+                    extent,
+                    format!(
+                        "chunk_tree {} chunk_objectid {}, expected {BTRFS_CHUNK_TREE_OBJECTID} {BTRFS_FIRST_CHUNK_TREE_OBJECTID}",
+                        extent.chunk_tree, extent.chunk_objectid
+                    ),
+                ));
+            }
+        }
+    }
+
+    let mut previous: Option<((u64, u64), u64)> = None;
+    for (&at, extent) in &extents {
+        if !extent.claimed {
+            problems.push(dev_problem(
+                at,
+                extent,
+                format!(
+                    "is a stripe of no chunk (it points at chunk {})",
+                    extent.chunk_offset
+                ),
+            ));
+        }
+        if let Some(((devid, start), end)) = previous {
+            if devid == at.0 && at.1 < end {
+                problems.push(dev_problem(
+                    at,
+                    extent,
+                    format!("overlaps the dev extent at {start}, which ends at {end}"),
+                ));
+            }
+        }
+        let end = at.1 + extent.length;
+        if previous.is_none_or(|((devid, _), previous_end)| devid != at.0 || end > previous_end) {
+            previous = Some((at, end));
+        }
+    }
+    problems
+}
+
+pub fn check_fs(fs: &FsInfo) -> CheckReport {
+    let mut report = CheckReport::default();
+    let mut seen = HashSet::new();
+    for (tree, root, level) in tree_roots(fs) {
+        check_tree(fs, tree, root, Some(level), &mut seen, &mut report);
+    }
+    report.problems.extend(check_dev_extents(fs));
+    if fs.master_sb.incompat_flags & BTRFS_FEATURE_INCOMPAT_ZONED != 0 {
+        report.problems.extend(check_zoned(fs));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(objectid: u64, item_type: BtrfsItemType, offset: u64) -> btrfs_disk_key {
+        btrfs_disk_key {
+            objectid,
+            item_type,
+            offset,
+        }
+    }
+
+    /// a 4KiB leaf holding items with the given keys, data offsets and sizes
+    fn leaf(items: &[(btrfs_disk_key, u32, u32)]) -> Vec<u8> {
+        let header_size = std::mem::size_of::<btrfs_header>();
+        let mut block = vec![0_u8; 4096];
+        block[header_size - 5..header_size - 1]
+            .copy_from_slice(&(items.len() as u32).to_le_bytes());
+        for (i, (key, offset, size)) in items.iter().enumerate() {
+            let item = btrfs_item {
+                key: *key,
+                offset: *offset,
+                size: *size,
+            };
+            let bytes = unsafe {
+                std::slice::from_raw_parts(
+                    &item as *const btrfs_item as *const u8,
+                    std::mem::size_of::<btrfs_item>(),
+                )
+            };
+            let start = header_size + i * bytes.len();
+            block[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+        block
+    }
+
+    #[test]
+    fn leaf_layout() {
+        let data_size = 4096 - std::mem::size_of::<btrfs_header>() as u32;
+        let a = key(256, BtrfsItemType::INODE_ITEM, 0);
+        let b = key(256, BtrfsItemType::INODE_REF, 256);
+        let good = leaf(&[(a, data_size - 160, 160), (b, data_size - 172, 12)]);
+        assert!(check_leaf_layout(&good).is_empty());
+
+        let unsorted = leaf(&[(b, data_size - 12, 12), (a, data_size - 172, 160)]);
+        let problems = check_leaf_layout(&unsorted);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, Some(1));
+
+        let gap = leaf(&[(a, data_size - 160, 160), (b, data_size - 200, 12)]);
+        assert_eq!(check_leaf_layout(&gap).len(), 1);
+        let overflow = leaf(&[(a, data_size - 100, 160)]);
+        assert_eq!(check_leaf_layout(&overflow).len(), 1);
+        let overlap = leaf(&[(a, 0, data_size)]);
+        assert_eq!(check_leaf_layout(&overlap).len(), 1);
+    }
+
+    #[test]
+    fn item_sizes() {
+        let inode = key(256, BtrfsItemType::INODE_ITEM, 0);
+        assert!(check_item(&inode, &[0; 160], 4096, 4).is_empty());
+        assert_eq!(check_item(&inode, &[0; 100], 4096, 4).len(), 1);
+        let csum = key(BTRFS_EXTENT_CSUM_OBJECTID, BtrfsItemType::EXTENT_CSUM, 4096);
+        assert!(check_item(&csum, &[0; 8], 4096, 4).is_empty());
+        assert_eq!(check_item(&csum, &[0; 6], 4096, 4).len(), 1);
+        let root = key(5, BtrfsItemType::ROOT_ITEM, 0);
+        assert!(check_item(&root, &[0; BTRFS_LEGACY_ROOT_ITEM_SIZE], 4096, 4).is_empty());
+        assert_eq!(check_item(&root, &[0; 300], 4096, 4).len(), 1);
+        // a dir item whose name runs past the end of the item
+        let dir = key(256, BtrfsItemType::DIR_ITEM, 1234);
+        let mut entry = vec![0_u8; 30 + 3];
+        entry[27..29].copy_from_slice(&5_u16.to_le_bytes());
+        assert_eq!(check_item(&dir, &entry, 4096, 4).len(), 1);
+        entry[27..29].copy_from_slice(&3_u16.to_le_bytes());
+        assert!(check_item(&dir, &entry, 4096, 4).is_empty());
+    }
+}
@@ -0,0 +1,268 @@
+//! The logical to physical chunk map as a file, for when the chunk tree
+//! can't be read. `chunk-map` writes the map of a filesystem whose chunk
+//! tree is intact (or one pieced together by hand, e.g. from the dev extents
+//! and what is found on the devices), and given back with `--chunk-map` it
+//! replaces the chunk tree in every lookup.
+//!
+//! The file is TOML, a `[[chunk]]` table per chunk followed by a
+//! `[[chunk.stripe]]` table per stripe:
+//!
+//! ```toml
+//! [[chunk]]
+//! logical = 22020096
+//! length = 8388608
+//! type = "METADATA|DUP"
+//!
+//! [[chunk.stripe]]
+//! devid = 1
+//! offset = 30408704
+//! ```
+//!
+//! Only this much of TOML is read: `name = value` lines under the table
+//! headers, and # comments.
+
+use crate::btrfs::*;
+use crate::flags::*;
+use crate::parse::parse_u64;
+use crate::structures::*;
+
+use anyhow::*;
+use log::*;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// the chunks in the file format described above
+pub fn format_chunk_map(chunks: &[ChunkInfo]) -> String {
+    let mut text = String::new();
+    for ChunkInfo(key, chunk, stripes) in chunks {
+        text.push_str(&format!(
+            "[[chunk]]\nlogical = {}\nlength = {}\ntype = \"{}\"\nstripe_len = {}\n",
+            { key.offset },
+            { chunk.length },
+            fmt_block_group_type(chunk.r#type),
+            { chunk.stripe_len }
+        ));
+        if chunk.sub_stripes > 1 {
+            text.push_str(&format!("sub_stripes = {}\n", { chunk.sub_stripes }));
+        }
+        for stripe in stripes {
+            text.push_str(&format!(
+                "\n[[chunk.stripe]]\ndevid = {}\noffset = {}\ndev_uuid = \"{}\"\n",
+                { stripe.devid },
+                { stripe.offset },
+                stripe.dev_uuid
+            ));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// chunk or block group type flags from e.g. `DATA|RAID1`, as
+/// fmt_block_group_type writes them, or a number
+fn parse_chunk_type(value: &str) -> Result<u64> {
+    if let Result::Ok(flags) = parse_u64(value) {
+        return Ok(flags);
+    }
+    let mut flags = 0;
+    for name in value.split('|') {
+        let name = name.trim();
+        if name.eq_ignore_ascii_case("SINGLE") {
+            continue;
+        }
+        let (bit, _) = BLOCK_GROUP_TYPES
+            .iter()
+            .chain(BLOCK_GROUP_PROFILES)
+            .find(|(_, known)| known.eq_ignore_ascii_case(name))
+            .ok_or_else(|| anyhow!("unknown chunk type {name:?}"))?;
+        flags |= bit;
+    }
+    Ok(flags)
+}
+
+/// read the file format described above. stripe_len defaults to 64KiB,
+/// and a stripe's dev_uuid, when left out, is taken from the device later
+/// (see use_chunk_map). Chunks may be given in any order, but not overlap.
+pub fn parse_chunk_map(text: &str) -> Result<Vec<ChunkInfo>> {
+    enum Table {
+        None,
+        Chunk,
+        Stripe,
+    }
+    let mut table = Table::None;
+    let mut chunks: Vec<ChunkInfo> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line {
+            "[[chunk]]" => {
+                let key = btrfs_disk_key {
+                    objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+                    item_type: BtrfsItemType::CHUNK_ITEM,
+                    offset: u64::MAX,
+                };
+                let chunk = btrfs_chunk {
+                    length: 0,
+                    owner: BTRFS_EXTENT_TREE_OBJECTID,
+                    stripe_len: BTRFS_STRIPE_LEN,
+                    r#type: 0,
+                    io_align: BTRFS_STRIPE_LEN as u32,
+                    io_width: BTRFS_STRIPE_LEN as u32,
+                    sector_size: 0,
+                    num_stripes: 0,
+                    sub_stripes: 1,
+                };
+                chunks.push(ChunkInfo(key, chunk, Vec::new()));
+                table = Table::Chunk;
+                continue;
+            }
+            "[[chunk.stripe]]" => {
+                let ChunkInfo(_, chunk, stripes) = chunks
+                    .last_mut()
+                    .ok_or_else(|| anyhow!("line {number}: a stripe before any chunk"))?;
+                stripes.push(btrfs_stripe {
+                    devid: 0,
+                    offset: u64::MAX,
+                    dev_uuid: BtrfsUuid::default(),
+                });
+                chunk.num_stripes += 1;
+                table = Table::Stripe;
+                continue;
+            }
+            _ => (),
+        }
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {number}: expected name = value, not {line:?}"))?;
+        let (name, value) = (name.trim(), value.trim().trim_matches('"'));
+        let context = || format!("line {number}: {name}");
+        let Some(ChunkInfo(key, chunk, stripes)) = chunks.last_mut() else {
+            bail!("line {number}: {name} outside of a [[chunk]]");
+        };
+        match (&table, name) {
+            (Table::Chunk, "logical") => key.offset = parse_u64(value).with_context(context)?,
+            (Table::Chunk, "length") => chunk.length = parse_u64(value).with_context(context)?,
+            (Table::Chunk, "type") => {
+                chunk.r#type = parse_chunk_type(value).with_context(context)?
+            }
+            (Table::Chunk, "stripe_len") => {
+                chunk.stripe_len = parse_u64(value).with_context(context)?;
+                chunk.io_align = u32::try_from(chunk.stripe_len).with_context(context)?;
+                chunk.io_width = chunk.io_align;
+            }
+            (Table::Chunk, "sub_stripes") => {
+                chunk.sub_stripes = u16::try_from(parse_u64(value)?).with_context(context)?
+            }
+            (Table::Stripe, "devid") => {
+                stripes.last_mut().unwrap().devid = parse_u64(value).with_context(context)?
+            }
+            (Table::Stripe, "offset") => {
+                stripes.last_mut().unwrap().offset = parse_u64(value).with_context(context)?
+            }
+            (Table::Stripe, "dev_uuid") => {
+                stripes.last_mut().unwrap().dev_uuid = value.parse().with_context(context)?
+            }
+            _ => bail!("line {number}: unknown field {name}"),
+        }
+    }
+
+    let mut by_start = BTreeMap::new();
+    for ChunkInfo(key, chunk, stripes) in chunks {
+        let logical = key.offset;
+        ensure!(logical != u64::MAX, "a chunk has no logical address");
+        ensure!(chunk.length > 0, "chunk {logical} has no length");
+        ensure!(
+            chunk.r#type
+                & (BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_SYSTEM)
+                != 0,
+            "chunk {logical} has no DATA, METADATA or SYSTEM type"
+        );
+        ensure!(!stripes.is_empty(), "chunk {logical} has no stripes");
+        for stripe in &stripes {
+            ensure!(
+                stripe.devid != 0 && stripe.offset != u64::MAX,
+                "a stripe of chunk {logical} needs a devid and an offset"
+            );
+        }
+        ensure!(
+            by_start
+                .insert(logical, ChunkInfo(key, chunk, stripes))
+                .is_none(),
+            "chunk {logical} is given twice"
+        );
+    }
+    let mut end = 0;
+    for (&start, ChunkInfo(_, chunk, _)) in &by_start {
+        ensure!(start >= end, "chunk {start} overlaps the chunk before it");
+        end = start + chunk.length;
+    }
+    Ok(by_start.into_values().collect())
+}
+
+/// make the chunks of a map the only ones fs knows: they replace those of
+/// the superblock's system chunk array, and the chunk tree isn't searched
+/// any more. Stripes without a dev_uuid take that of their device.
+pub fn use_chunk_map(fs: &mut FsInfo, mut chunks: Vec<ChunkInfo>) {
+    for ChunkInfo(key, chunk, stripes) in &mut chunks {
+        chunk.sector_size = fs.master_sb.sectorsize;
+        for stripe in stripes {
+            if !stripe.dev_uuid.is_nil() {
+                continue;
+            }
+            match fs.devid_map.get(&{ stripe.devid }) {
+                Some(dev) => stripe.dev_uuid = dev.dev_uuid,
+                None => warn!(
+                    "chunk {} has a stripe on devid {}, which wasn't given",
+                    { key.offset },
+                    { stripe.devid }
+                ),
+            }
+        }
+    }
+    fs.bootstrap_chunks = chunks
+        .iter()
+        .filter(|chunk| chunk.1.r#type & BTRFS_BLOCK_GROUP_SYSTEM != 0)
+        .cloned()
+        .collect();
+    fs.chunk_cache = RwLock::new(
+        chunks
+            .into_iter()
+            .map(|chunk| (chunk.0.offset, chunk))
+            .collect(),
+    );
+    fs.chunk_map = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_map_round_trip() {
+        let text = "# by hand\n[[chunk]]\nlogical = 0x1500000\nlength = 8388608\ntype = \"METADATA|DUP\"\n\n[[chunk.stripe]]\ndevid = 1\noffset = 30408704\n\n[[chunk.stripe]]\ndevid = 1\noffset = 38797312\n\n[[chunk]]\nlogical = 1048576\nlength = 4194304\ntype = \"SYSTEM|SINGLE\"\n[[chunk.stripe]]\ndevid = 1\noffset = 1048576\n";
+        let chunks = parse_chunk_map(text).unwrap();
+        assert_eq!(chunks.len(), 2);
+        let ChunkInfo(key, chunk, stripes) = &chunks[1];
+        assert_eq!({ key.offset }, 22020096);
+        assert_eq!(
+            { chunk.r#type },
+            BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP
+        );
+        assert_eq!({ chunk.num_stripes }, 2);
+        assert_eq!({ stripes[1].offset }, 38797312);
+        assert_eq!({ chunks[0].1.r#type }, BTRFS_BLOCK_GROUP_SYSTEM);
+
+        let again = parse_chunk_map(&format_chunk_map(&chunks)).unwrap();
+        assert_eq!(format_chunk_map(&again), format_chunk_map(&chunks));
+
+        let overlapping = "[[chunk]]\nlogical = 0\nlength = 8192\ntype = \"DATA\"\n[[chunk.stripe]]\ndevid = 1\noffset = 0\n[[chunk]]\nlogical = 4096\nlength = 4096\ntype = \"DATA\"\n[[chunk.stripe]]\ndevid = 1\noffset = 8192\n";
+        assert!(parse_chunk_map(overlapping).is_err());
+        assert!(
+            parse_chunk_map("[[chunk]]\nlogical = 0\nlength = 4096\ntype = \"DATA\"\n").is_err()
+        );
+        assert!(parse_chunk_map("logical = 0").is_err());
+    }
+}
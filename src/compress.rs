@@ -0,0 +1,138 @@
+//! Decompression of file data as btrfs compresses it: zlib and zstd extents
+//! are each a single stream, lzo ones a length prefixed series of segments,
+//! each a sector or less once decompressed. Inline data is compressed the
+//! same way.
+
+use crate::structures::*;
+
+use anyhow::*;
+use std::io::Read;
+
+/// the bytes in an lzo segment header, and in the header giving the length
+/// of the whole
+const LZO_LEN: usize = 4;
+
+fn read_le32(data: &[u8], at: usize) -> Option<usize> {
+    let bytes = data.get(at..at + LZO_LEN)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+fn decompress_lzo(data: &[u8], ram_bytes: usize, sectorsize: usize) -> Result<Vec<u8>> {
+    let total = read_le32(data, 0).ok_or_else(|| anyhow!("lzo: no header"))?;
+    ensure!(
+        total <= data.len(),
+        "lzo: {total} bytes compressed, but the extent holds {}",
+        data.len()
+    );
+    let mut out = Vec::with_capacity(ram_bytes);
+    let mut at = LZO_LEN;
+    while at < total && out.len() < ram_bytes {
+        // a segment header never straddles a sector; the rest is padding
+        if sectorsize - at % sectorsize < LZO_LEN {
+            at = at.next_multiple_of(sectorsize);
+            continue;
+        }
+        let len =
+            read_le32(data, at).ok_or_else(|| anyhow!("lzo: segment header at {at} cut off"))?;
+        at += LZO_LEN;
+        let segment = data
+            .get(at..at + len)
+            .ok_or_else(|| anyhow!("lzo: segment at {at} of {len} bytes runs past the end"))?;
+        let decompressed = lzokay_native::decompress_all(segment, None)
+            .map_err(|e| anyhow!("lzo: segment at {at}: {e}"))?;
+        out.extend_from_slice(&decompressed);
+        at += len;
+    }
+    Ok(out)
+}
+
+/// the data of an extent or inline item of the compression given, of
+/// ram_bytes once decompressed. A compressed extent never holds more than
+/// BTRFS_MAX_UNCOMPRESSED, so a larger ram_bytes is taken as corrupt rather
+/// than allocated.
+pub fn decompress(
+    compression: u8,
+    data: &[u8],
+    ram_bytes: u64,
+    sectorsize: u64,
+) -> Result<Vec<u8>> {
+    ensure!(
+        compression == BTRFS_COMPRESS_NONE || ram_bytes <= BTRFS_MAX_UNCOMPRESSED,
+        "ram_bytes {ram_bytes} is more than a compressed extent holds ({BTRFS_MAX_UNCOMPRESSED})"
+    );
+    let ram_bytes = ram_bytes as usize;
+    let mut out = match compression {
+        BTRFS_COMPRESS_NONE => data.to_vec(),
+        BTRFS_COMPRESS_ZLIB => {
+            miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(data, ram_bytes)
+                .map_err(|e| anyhow!("zlib: {e}"))?
+        }
+        BTRFS_COMPRESS_LZO => decompress_lzo(data, ram_bytes, sectorsize as usize)?,
+        BTRFS_COMPRESS_ZSTD => {
+            let decoder =
+                ruzstd::decoding::StreamingDecoder::new(data).map_err(|e| anyhow!("zstd: {e}"))?;
+            let mut out = Vec::with_capacity(ram_bytes);
+            decoder
+                .take(ram_bytes as u64)
+                .read_to_end(&mut out)
+                .map_err(|e| anyhow!("zstd: {e}"))?;
+            out
+        }
+        _ => bail!("unknown compression type {compression}"),
+    };
+    out.truncate(ram_bytes);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(len: usize) -> Vec<u8> {
+        (0..len).map(|i| b"btrfs extent "[i % 13]).collect()
+    }
+
+    #[test]
+    fn zlib_and_zstd() {
+        let data = text(10000);
+        let zlib = miniz_oxide::deflate::compress_to_vec_zlib(&data, 6);
+        assert_eq!(
+            decompress(BTRFS_COMPRESS_ZLIB, &zlib, 10000, 4096).unwrap(),
+            data
+        );
+        let zstd = ruzstd::encoding::compress_to_vec(
+            &data[..],
+            ruzstd::encoding::CompressionLevel::Fastest,
+        );
+        assert_eq!(
+            decompress(BTRFS_COMPRESS_ZSTD, &zstd, 10000, 4096).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn lzo_segments() {
+        let data = text(600);
+        let segments: Vec<_> = data
+            .chunks(200)
+            .map(|chunk| lzokay_native::compress(chunk).unwrap())
+            .collect();
+        // a sector ending two bytes after the first segment, so the next
+        // segment header starts in the sector after
+        let sectorsize = 2 * LZO_LEN + segments[0].len() + 2;
+        let mut framed = vec![0; LZO_LEN];
+        for segment in &segments {
+            if sectorsize - framed.len() % sectorsize < LZO_LEN {
+                framed.resize(framed.len().next_multiple_of(sectorsize), 0);
+            }
+            framed.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+            framed.extend_from_slice(segment);
+        }
+        assert_eq!(read_le32(&framed, sectorsize), Some(segments[1].len()));
+        let total = framed.len() as u32;
+        framed[..LZO_LEN].copy_from_slice(&total.to_le_bytes());
+        let out = decompress(BTRFS_COMPRESS_LZO, &framed, 600, sectorsize as u64).unwrap();
+        assert_eq!(out, data);
+        assert!(decompress(BTRFS_COMPRESS_LZO, &framed, u64::MAX, 4096).is_err());
+    }
+}
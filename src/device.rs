@@ -0,0 +1,674 @@
+//! Offline changes to the set of devices making up the filesystem, made by
+//! rewriting the chunk tree, dev tree, block group items and superblocks to
+//! match.
+//!
+//! The trees are edited in place with edit::edit_items, and every block
+//! and superblock overwritten is saved to the backup directory first.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::edit::*;
+use crate::flags::fmt_block_group_type;
+use crate::items::{dev_stats_values, item_as};
+use crate::repair::*;
+use crate::space::PROFILE_MASK;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+
+/// a chunk which had stripes on a removed device, and the profile its
+/// remaining stripes give it
+pub struct DegradedChunk {
+    pub logical: u64,
+    pub old_type: u64,
+    pub new_type: u64,
+}
+
+/// what remove_missing_device changed (or in a dry run, would change)
+pub struct DeviceRemoval {
+    pub chunks: Vec<DegradedChunk>,
+    pub dev_extents: usize,
+    /// tree blocks rewritten
+    pub tree_blocks: usize,
+    /// tree blocks left empty, which were dropped from their trees and the
+    /// extent tree
+    pub freed_blocks: usize,
+    /// every tree block copy written
+    pub copies: Vec<CopyRepair>,
+    pub supers: Vec<SuperCopy>,
+}
+
+/// the profile of a mirrored chunk left with copies stripes
+fn mirrored_profile(copies: usize) -> Option<u64> {
+    match copies {
+        1 => Some(0),
+        2 => Some(BTRFS_BLOCK_GROUP_RAID1),
+        3 => Some(BTRFS_BLOCK_GROUP_RAID1C3),
+        _ => None,
+    }
+}
+
+/// the sys_chunk_array of sb, with the chunks in changed replaced by their
+/// new item data
+fn rewrite_sys_chunk_array(
+    sb: &mut btrfs_super_block,
+    changed: &HashMap<u64, Vec<u8>>,
+) -> Result<()> {
+    let mut array = Vec::new();
+    for ChunkInfo(key, chunk, stripes) in SysChunkIter::new(sb) {
+        array.extend_from_slice(as_bytes(&key));
+        let logical = key.offset;
+        match changed.get(&logical) {
+            Some(data) => array.extend_from_slice(data),
+            None => {
+                array.extend_from_slice(as_bytes(&chunk));
+                for stripe in &stripes {
+                    array.extend_from_slice(as_bytes(stripe));
+                }
+            }
+        }
+    }
+    ensure!(
+        array.len() <= BTRFS_SYSTEM_CHUNK_ARRAY_SIZE,
+        "the system chunk array would need {} bytes",
+        array.len()
+    );
+    sb.sys_chunk_array = [0; BTRFS_SYSTEM_CHUNK_ARRAY_SIZE];
+    sb.sys_chunk_array[..array.len()].copy_from_slice(&array);
+    sb.sys_chunk_array_size = array.len() as u32;
+    Ok(())
+}
+
+/// record a lost device as removed, so the filesystem can be mounted
+/// without it, as `btrfs device remove missing` would once mounted degraded
+/// but without moving any data. Its dev item and dev extents are dropped,
+/// the superblocks count one device less, and each chunk with a stripe on
+/// it keeps its other stripes as a mirrored profile with fewer copies
+/// (RAID1 becomes SINGLE, RAID1C3 RAID1, RAID1C4 RAID1C3), with its block
+/// group item to match. A chunk which can't do without the device, such as
+/// a SINGLE, DUP, RAID0 or parity one on it, is refused, and every other
+/// device must be present.
+pub fn remove_missing_device(
+    fs: &FsInfo,
+    devid: u64,
+    options: &RepairOptions,
+) -> Result<DeviceRemoval> {
+    let sb = &fs.master_sb;
+    ensure!(
+        !fs.devid_map.contains_key(&devid),
+        "device {devid} was given; only a device which is lost can be removed"
+    );
+    ensure!(
+        sb.log_root == 0,
+        "the log tree must be replayed or zeroed before a device is removed"
+    );
+    ensure!(
+        !extent_tree_v2(fs),
+        "removing a device from a filesystem with extent tree v2's global roots is not supported"
+    );
+    let nodesize = sb.nodesize as u64;
+
+    let mut chunk_changes: Vec<ItemChange> = Vec::new();
+    let mut lost_bytes = None;
+    let dev_items = key_range(
+        Some(BTRFS_DEV_ITEMS_OBJECTID),
+        Some(BtrfsItemType::DEV_ITEM),
+        None,
+    );
+    for (item, data, _block_offset, _slot) in search_range(fs, sb.chunk_root, dev_items) {
+        let Some(dev_item) = item_as::<btrfs_dev_item>(&data) else {
+            let offset = item.key.offset;
+            bail!("dev item {offset} is damaged");
+        };
+        let id = dev_item.devid;
+        if id == devid {
+            lost_bytes = Some(dev_item.total_bytes);
+            chunk_changes.push((item.key, None));
+        } else {
+            ensure!(
+                fs.devid_map.contains_key(&id),
+                "device {id} is missing too; every other device must be given"
+            );
+        }
+    }
+    let lost_bytes = lost_bytes.ok_or_else(|| anyhow!("there is no device {devid}"))?;
+
+    let mut removal = DeviceRemoval {
+        chunks: Vec::new(),
+        dev_extents: 0,
+        tree_blocks: 0,
+        freed_blocks: 0,
+        copies: Vec::new(),
+        supers: Vec::new(),
+    };
+    // the new item data of every chunk changed, by logical start
+    let mut new_chunks = HashMap::new();
+    for ChunkInfo(key, chunk, stripes) in all_chunks(fs) {
+        let kept: Vec<btrfs_stripe> = stripes
+            .iter()
+            .filter(|stripe| stripe.devid != devid)
+            .copied()
+            .collect();
+        if kept.len() == stripes.len() {
+            continue;
+        }
+        let logical = key.offset;
+        let old_type = chunk.r#type;
+        let mirrored =
+            BTRFS_BLOCK_GROUP_RAID1 | BTRFS_BLOCK_GROUP_RAID1C3 | BTRFS_BLOCK_GROUP_RAID1C4;
+        let new_profile = if old_type & mirrored != 0 {
+            mirrored_profile(kept.len())
+        } else {
+            None
+        };
+        let Some(new_profile) = new_profile else {
+            bail!(
+                "chunk {logical} ({}) has {} of its {} stripes on device {devid}, and can't do without them",
+                fmt_block_group_type(old_type),
+                stripes.len() - kept.len(),
+                stripes.len()
+            );
+        };
+        let mut chunk = chunk;
+        chunk.r#type = old_type & !PROFILE_MASK | new_profile;
+        chunk.num_stripes = kept.len() as u16;
+        let mut data = as_bytes(&chunk).to_vec();
+        for stripe in &kept {
+            data.extend_from_slice(as_bytes(stripe));
+        }
+        chunk_changes.push((key, Some(data.clone())));
+        new_chunks.insert(logical, data);
+        removal.chunks.push(DegradedChunk {
+            logical,
+            old_type,
+            new_type: chunk.r#type,
+        });
+    }
+
+    let dev_root = tree_root_offset(fs, BTRFS_DEV_TREE_OBJECTID)
+        .ok_or_else(|| anyhow!("dev tree not found"))?;
+    let mut dev_changes: Vec<ItemChange> = Vec::new();
+    let dev_extents = key_range(Some(devid), Some(BtrfsItemType::DEV_EXTENT), None);
+    for (item, ..) in search_range(fs, dev_root, dev_extents) {
+        dev_changes.push((item.key, None));
+    }
+    removal.dev_extents = dev_changes.len();
+    let dev_stats = key_range(
+        Some(BTRFS_DEV_STATS_OBJECTID),
+        Some(BtrfsItemType::PERSISTENT_ITEM),
+        Some(devid),
+    );
+    if let Some((item, ..)) = search_range(fs, dev_root, dev_stats).next() {
+        dev_changes.push((item.key, None));
+    }
+
+    let chunk_edit = edit_items(fs, BTRFS_CHUNK_TREE_OBJECTID, &chunk_changes)?;
+    let dev_edit = edit_items(fs, BTRFS_DEV_TREE_OBJECTID, &dev_changes)?;
+
+    // the block group items of the degraded chunks get their new type, and
+    // those holding freed blocks less used
+    let compat_ro = sb.compat_ro_flags;
+    let group_tree = if compat_ro & BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE != 0 {
+        BTRFS_BLOCK_GROUP_TREE_OBJECTID
+    } else {
+        BTRFS_EXTENT_TREE_OBJECTID
+    };
+    let group_root =
+        tree_root_offset(fs, group_tree).ok_or_else(|| anyhow!("block group tree not found"))?;
+    let find_group = |logical: u64| -> Result<(btrfs_disk_key, btrfs_block_group_item)> {
+        let search = key_range(Some(logical), Some(BtrfsItemType::BLOCK_GROUP_ITEM), None);
+        search_range(fs, group_root, search)
+            .find_map(|(item, data, ..)| {
+                Some((item.key, *item_as::<btrfs_block_group_item>(&data)?))
+            })
+            .ok_or_else(|| anyhow!("no block group item for chunk {logical}"))
+    };
+    let mut groups = BTreeMap::new();
+    for chunk in &removal.chunks {
+        let (key, mut group) = find_group(chunk.logical)?;
+        group.flags = chunk.new_type;
+        groups.insert(chunk.logical, (key, group));
+    }
+    let extent_root = tree_root_offset(fs, BTRFS_EXTENT_TREE_OBJECTID)
+        .ok_or_else(|| anyhow!("extent tree not found"))?;
+    let mut extent_changes: Vec<ItemChange> = Vec::new();
+    let freed: Vec<u64> = chunk_edit
+        .freed
+        .iter()
+        .chain(&dev_edit.freed)
+        .map(|&(logical, _level)| logical)
+        .collect();
+    for &logical in &freed {
+        for (item, ..) in search_range(fs, extent_root, key_range(Some(logical), None, None)) {
+            if matches!(
+                item.key.item_type,
+                BtrfsItemType::EXTENT_ITEM
+                    | BtrfsItemType::METADATA_ITEM
+                    | BtrfsItemType::TREE_BLOCK_REF
+                    | BtrfsItemType::SHARED_BLOCK_REF
+            ) {
+                extent_changes.push((item.key, None));
+            }
+        }
+        let chunk = find_chunk(fs, logical)
+            .ok_or_else(|| anyhow!("freed block {logical} is not within any chunk"))?;
+        let start = chunk.0.offset;
+        let (_, group) = match groups.entry(start) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(find_group(start)?),
+        };
+        group.used = group.used.saturating_sub(nodesize);
+    }
+    let group_changes: Vec<ItemChange> = groups
+        .values()
+        .map(|(key, group)| (*key, Some(as_bytes(group).to_vec())))
+        .collect();
+    let mut edits = vec![dev_edit];
+    if group_tree == BTRFS_EXTENT_TREE_OBJECTID {
+        extent_changes.extend(group_changes);
+    } else {
+        edits.push(edit_items(fs, group_tree, &group_changes)?);
+    }
+    if !extent_changes.is_empty() {
+        let extent_edit = edit_items(fs, BTRFS_EXTENT_TREE_OBJECTID, &extent_changes)?;
+        ensure!(
+            extent_edit.freed.is_empty(),
+            "dropping the extent items of the freed blocks would empty an extent tree leaf"
+        );
+        edits.push(extent_edit);
+    }
+    // the chunk tree and superblocks, which say which devices there are,
+    // are written last
+    edits.push(chunk_edit);
+    removal.freed_blocks = freed.len();
+    removal.tree_blocks = edits.iter().map(|edit| edit.blocks.len()).sum();
+
+    let mut supers = Vec::new();
+    for devid in fs.own_devids() {
+        let mut sb = load_sb(&fs.devid_map[&devid].file)?;
+        sb.num_devices -= 1;
+        sb.total_bytes = sb.total_bytes.saturating_sub(lost_bytes);
+        sb.bytes_used = sb.bytes_used.saturating_sub(freed.len() as u64 * nodesize);
+        // the backup roots are restored with their device counts
+        for backup in &mut sb.super_roots {
+            backup.num_devices = backup.num_devices.saturating_sub(1);
+            backup.total_bytes = backup.total_bytes.saturating_sub(lost_bytes);
+        }
+        rewrite_sys_chunk_array(&mut sb, &new_chunks)?;
+        supers.push(sb);
+    }
+
+    let mut transaction = RepairTransaction::new(fs);
+    for edit in edits {
+        for (logical, block) in edit.blocks {
+            transaction.stage_block(logical, block);
+        }
+    }
+    for sb in supers {
+        transaction.stage_super(sb);
+    }
+    let written = transaction.commit(options)?;
+    removal.copies = written.copies;
+    removal.supers = written.supers;
+    Ok(removal)
+}
+
+/// the error counters the kernel keeps for each device, by devid. Devices
+/// which never had an error may have no item.
+pub fn device_stats(fs: &FsInfo) -> Result<BTreeMap<u64, [u64; BTRFS_DEV_STAT_VALUES_MAX]>> {
+    let dev_root = tree_root_offset(fs, BTRFS_DEV_TREE_OBJECTID)
+        .ok_or_else(|| anyhow!("dev tree not found"))?;
+    let search = key_range(
+        Some(BTRFS_DEV_STATS_OBJECTID),
+        Some(BtrfsItemType::PERSISTENT_ITEM),
+        None,
+    );
+    Ok(search_range(fs, dev_root, search)
+        .map(|(item, data, ..)| (item.key.offset, dev_stats_values(&data)))
+        .collect())
+}
+
+/// a device replace recorded in the dev tree, with what the devices given
+/// say about it
+pub struct DevReplaceStatus {
+    pub item: btrfs_dev_replace_item,
+    /// the size of the source device, from its dev item
+    pub src_total_bytes: Option<u64>,
+    pub src_present: bool,
+    /// whether the target, which has devid 0 until the replace is
+    /// finished, is among the devices given
+    pub target_present: bool,
+}
+
+impl DevReplaceStatus {
+    /// whether the replace was still running (or suspended), so that the
+    /// target only holds a copy of the source below the cursor
+    pub fn in_progress(&self) -> bool {
+        matches!(
+            self.item.replace_state,
+            BTRFS_DEV_REPLACE_ITEM_STATE_STARTED | BTRFS_DEV_REPLACE_ITEM_STATE_SUSPENDED
+        )
+    }
+}
+
+/// the DEV_REPLACE item of the dev tree, if a replace was ever started
+pub fn dev_replace_status(fs: &FsInfo) -> Result<Option<DevReplaceStatus>> {
+    let dev_root = tree_root_offset(fs, BTRFS_DEV_TREE_OBJECTID)
+        .ok_or_else(|| anyhow!("dev tree not found"))?;
+    let search = key_range(
+        Some(BTRFS_DEV_REPLACE_OBJECTID),
+        Some(BtrfsItemType::DEV_REPLACE),
+        Some(0),
+    );
+    let Some((_, data, ..)) = search_range(fs, dev_root, search).next() else {
+        return Ok(None);
+    };
+    let item = *item_as::<btrfs_dev_replace_item>(&data).ok_or_else(|| {
+        anyhow!(
+            "the dev replace item is only {} bytes, {} are needed",
+            data.len(),
+            std::mem::size_of::<btrfs_dev_replace_item>()
+        )
+    })?;
+    let src_devid = item.src_devid;
+    let dev_items = key_range(
+        Some(BTRFS_DEV_ITEMS_OBJECTID),
+        Some(BtrfsItemType::DEV_ITEM),
+        Some(src_devid),
+    );
+    let src_total_bytes = search_range(fs, fs.master_sb.chunk_root, dev_items)
+        .find_map(|(_, data, ..)| item_as::<btrfs_dev_item>(&data).map(|dev| dev.total_bytes));
+    Ok(Some(DevReplaceStatus {
+        item,
+        src_total_bytes,
+        src_present: fs.devid_map.contains_key(&src_devid),
+        target_present: fs.devid_map.contains_key(&BTRFS_DEV_REPLACE_DEVID),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrub::check_tree_block;
+    use crate::tree::tests::{test_fs, NODESIZE};
+
+    const IMAGE: usize = 2 * BTRFS_SUPER_INFO_OFFSET;
+    /// the system chunk, RAID1 over devids 1 and 2 at physical 0
+    const SYSTEM_LENGTH: u64 = BTRFS_SUPER_INFO_OFFSET as u64;
+    const DATA: u64 = 1024 * 1024;
+    const DATA_PHYSICAL: u64 = 3 * BTRFS_SUPER_INFO_OFFSET as u64 / 2;
+    const DATA_LENGTH: u64 = 8 * NODESIZE as u64;
+    const CHUNK_ROOT: u64 = NODESIZE as u64;
+    const EXTENT_ROOT: u64 = 2 * NODESIZE as u64;
+    const DEV_ROOT: u64 = 3 * NODESIZE as u64;
+
+    fn disk_key(objectid: u64, item_type: BtrfsItemType, offset: u64) -> btrfs_disk_key {
+        btrfs_disk_key {
+            objectid,
+            item_type,
+            offset,
+        }
+    }
+
+    fn chunk_item(length: u64, r#type: u64, stripes: &[(u64, u64)]) -> (btrfs_chunk, Vec<u8>) {
+        let mut chunk: btrfs_chunk = unsafe { std::mem::zeroed() };
+        chunk.length = length;
+        chunk.r#type = r#type;
+        chunk.num_stripes = stripes.len() as u16;
+        let mut data = as_bytes(&chunk).to_vec();
+        for &(devid, offset) in stripes {
+            let stripe = btrfs_stripe {
+                devid,
+                offset,
+                dev_uuid: Default::default(),
+            };
+            data.extend_from_slice(as_bytes(&stripe));
+        }
+        (chunk, data)
+    }
+
+    fn leaf(logical: u64, items: &[(btrfs_disk_key, Vec<u8>)]) -> Vec<u8> {
+        let mut header: btrfs_header = unsafe { std::mem::zeroed() };
+        header.bytenr = logical;
+        let mut leaf = build_leaf(&header, items, NODESIZE).unwrap();
+        seal_tree_block(&mut leaf, BtrfsCsumType::CRC32);
+        leaf
+    }
+
+    /// devid 1 of a filesystem whose devices are dev_items, with its trees
+    /// in a RAID1 system chunk over devids 1 and 2, and a data chunk of
+    /// data_type over data_devids. Only devid 1 is given.
+    fn removal_fs(name: &str, dev_items: &[u64], data_type: u64, data_devids: &[u64]) -> FsInfo {
+        let system = BTRFS_BLOCK_GROUP_SYSTEM | BTRFS_BLOCK_GROUP_RAID1;
+        let (system_chunk, system_data) = chunk_item(SYSTEM_LENGTH, system, &[(1, 0), (2, 0)]);
+        let data_stripes: Vec<(u64, u64)> = data_devids
+            .iter()
+            .map(|&devid| (devid, DATA_PHYSICAL))
+            .collect();
+        let (_, data_data) = chunk_item(DATA_LENGTH, data_type, &data_stripes);
+
+        let root_item = |bytenr| {
+            let mut root: btrfs_root_item = unsafe { std::mem::zeroed() };
+            root.bytenr = bytenr;
+            as_bytes(&root).to_vec()
+        };
+        let root_tree = leaf(
+            0,
+            &[
+                (
+                    disk_key(BTRFS_EXTENT_TREE_OBJECTID, BtrfsItemType::ROOT_ITEM, 0),
+                    root_item(EXTENT_ROOT),
+                ),
+                (
+                    disk_key(BTRFS_DEV_TREE_OBJECTID, BtrfsItemType::ROOT_ITEM, 0),
+                    root_item(DEV_ROOT),
+                ),
+            ],
+        );
+        let mut chunk_items: Vec<_> = dev_items
+            .iter()
+            .map(|&devid| {
+                let mut dev_item: btrfs_dev_item = unsafe { std::mem::zeroed() };
+                dev_item.devid = devid;
+                dev_item.total_bytes = IMAGE as u64;
+                let key = disk_key(BTRFS_DEV_ITEMS_OBJECTID, BtrfsItemType::DEV_ITEM, devid);
+                (key, as_bytes(&dev_item).to_vec())
+            })
+            .collect();
+        let chunk_key = |logical| {
+            disk_key(
+                BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+                BtrfsItemType::CHUNK_ITEM,
+                logical,
+            )
+        };
+        chunk_items.push((chunk_key(0), system_data.clone()));
+        chunk_items.push((chunk_key(DATA), data_data));
+        let chunk_tree = leaf(CHUNK_ROOT, &chunk_items);
+        let group = |flags| {
+            let group = btrfs_block_group_item {
+                used: 0,
+                chunk_objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+                flags,
+            };
+            as_bytes(&group).to_vec()
+        };
+        let extent_tree = leaf(
+            EXTENT_ROOT,
+            &[
+                (
+                    disk_key(0, BtrfsItemType::BLOCK_GROUP_ITEM, SYSTEM_LENGTH),
+                    group(system),
+                ),
+                (
+                    disk_key(DATA, BtrfsItemType::BLOCK_GROUP_ITEM, DATA_LENGTH),
+                    group(data_type),
+                ),
+            ],
+        );
+        let dev_extent = |logical, length| {
+            let mut extent: btrfs_dev_extent = unsafe { std::mem::zeroed() };
+            extent.chunk_offset = logical;
+            extent.length = length;
+            as_bytes(&extent).to_vec()
+        };
+        let mut dev_tree_items = vec![(
+            disk_key(BTRFS_DEV_STATS_OBJECTID, BtrfsItemType::PERSISTENT_ITEM, 2),
+            vec![0; 8 * BTRFS_DEV_STAT_VALUES_MAX],
+        )];
+        for &devid in dev_items {
+            let extent = |physical| disk_key(devid, BtrfsItemType::DEV_EXTENT, physical);
+            if devid <= 2 {
+                dev_tree_items.push((extent(0), dev_extent(0, SYSTEM_LENGTH)));
+            }
+            if data_devids.contains(&devid) {
+                dev_tree_items.push((extent(DATA_PHYSICAL), dev_extent(DATA, DATA_LENGTH)));
+            }
+        }
+        let dev_tree = leaf(DEV_ROOT, &dev_tree_items);
+
+        let mut sb: btrfs_super_block = unsafe { std::mem::zeroed() };
+        sb.magic = BTRFS_MAGIC;
+        sb.generation = 1;
+        sb.root = 0;
+        sb.chunk_root = CHUNK_ROOT;
+        sb.total_bytes = dev_items.len() as u64 * IMAGE as u64;
+        sb.num_devices = dev_items.len() as u64;
+        sb.sectorsize = NODESIZE as u32;
+        sb.nodesize = NODESIZE as u32;
+        sb.stripesize = NODESIZE as u32;
+        sb.csum_type = BtrfsCsumType::CRC32;
+        sb.dev_item.devid = 1;
+        let key = chunk_key(0);
+        let array = [as_bytes(&key), &system_data].concat();
+        sb.sys_chunk_array[..array.len()].copy_from_slice(&array);
+        sb.sys_chunk_array_size = array.len() as u32;
+
+        let mut image = [root_tree, chunk_tree, extent_tree, dev_tree].concat();
+        image.resize(IMAGE, 0);
+        image[BTRFS_SUPER_INFO_OFFSET..][..BTRFS_SUPER_INFO_SIZE]
+            .copy_from_slice(&sealed_super(&sb, BTRFS_SUPER_INFO_OFFSET as u64));
+        let mut fs = test_fs(name, &[image.clone()]);
+        // the fixture's file is gone once mapped, so writes go to a copy
+        std::fs::write(&fs.devid_map[&1].path, &image).unwrap();
+        fs.master_sb = sb;
+        let stripes = SysChunkIter::new(&sb).next().unwrap().2;
+        fs.bootstrap_chunks = vec![ChunkInfo(key, system_chunk, stripes)];
+        fs
+    }
+
+    fn keys(block: &[u8]) -> Vec<(u64, BtrfsItemType, u64)> {
+        node_entries(block)
+            .iter()
+            .map(|entry| match entry {
+                NodeEntry::Item(item, _) => {
+                    (item.key.objectid, item.key.item_type, item.key.offset)
+                }
+                NodeEntry::Ptr(ptr) => (ptr.key.objectid, ptr.key.item_type, ptr.key.offset),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn remove_mirrored_device() {
+        let raid1 = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID1;
+        let fs = removal_fs("remove_mirrored_device", &[1, 2], raid1, &[1, 2]);
+        let path = fs.devid_map[&1].path.clone();
+        let image = std::fs::read(&path).unwrap();
+        let backup_dir = path.with_extension("backups");
+        std::fs::create_dir(&backup_dir).unwrap();
+        let mut options = RepairOptions {
+            dry_run: true,
+            backup_dir: backup_dir.clone(),
+        };
+
+        let removal = remove_missing_device(&fs, 2, &options).unwrap();
+        let types: Vec<_> = removal
+            .chunks
+            .iter()
+            .map(|chunk| (chunk.logical, chunk.new_type))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                (0, BTRFS_BLOCK_GROUP_SYSTEM),
+                (DATA, BTRFS_BLOCK_GROUP_DATA)
+            ]
+        );
+        assert_eq!(removal.dev_extents, 2);
+        assert_eq!(removal.freed_blocks, 0);
+        // the chunk, dev and extent tree leaves, on devid 1 alone
+        assert_eq!(removal.tree_blocks, 3);
+        assert_eq!(removal.copies.len(), 3);
+        assert_eq!(removal.supers.len(), 1);
+        assert_eq!(std::fs::read(&path).unwrap(), image);
+        assert_eq!(std::fs::read_dir(&backup_dir).unwrap().count(), 0);
+
+        options.dry_run = false;
+        remove_missing_device(&fs, 2, &options).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        let block = |logical: u64| &written[logical as usize..][..NODESIZE];
+        let chunk_keys = keys(block(CHUNK_ROOT));
+        assert_eq!(chunk_keys.len(), 3);
+        assert_eq!(
+            chunk_keys[0],
+            (BTRFS_DEV_ITEMS_OBJECTID, BtrfsItemType::DEV_ITEM, 1)
+        );
+        let dev_keys = keys(block(DEV_ROOT));
+        assert_eq!(
+            dev_keys,
+            [
+                (1, BtrfsItemType::DEV_EXTENT, 0),
+                (1, BtrfsItemType::DEV_EXTENT, DATA_PHYSICAL)
+            ]
+        );
+        for logical in [CHUNK_ROOT, EXTENT_ROOT, DEV_ROOT] {
+            assert!(check_tree_block(&fs, block(logical), logical, None).is_empty());
+        }
+        let sb = unsafe {
+            std::ptr::read_unaligned(
+                written[BTRFS_SUPER_INFO_OFFSET..].as_ptr() as *const btrfs_super_block
+            )
+        };
+        let (num_devices, total_bytes) = (sb.num_devices, sb.total_bytes);
+        assert_eq!((num_devices, total_bytes), (1, IMAGE as u64));
+        let ChunkInfo(_, chunk, stripes) = SysChunkIter::new(&sb).next().unwrap();
+        let chunk_type = chunk.r#type;
+        assert_eq!(chunk_type, BTRFS_BLOCK_GROUP_SYSTEM);
+        assert_eq!(stripes.len(), 1);
+        assert_eq!({ stripes[0].devid }, 1);
+        std::fs::remove_dir_all(&backup_dir).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn remove_refused() {
+        let options = RepairOptions {
+            dry_run: true,
+            backup_dir: std::env::temp_dir(),
+        };
+        let error = |fs: &FsInfo, devid| {
+            let Err(error) = remove_missing_device(fs, devid, &options) else {
+                panic!("device {devid} was removed");
+            };
+            std::fs::remove_file(&fs.devid_map[&1].path).unwrap();
+            error.to_string()
+        };
+
+        let raid1 = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID1;
+        let fs = removal_fs("remove_given", &[1, 2], raid1, &[1, 2]);
+        assert!(error(&fs, 1).contains("only a device which is lost"));
+        let fs = removal_fs("remove_unknown", &[1], raid1, &[1]);
+        assert!(error(&fs, 3).contains("there is no device 3"));
+        let fs = removal_fs("remove_two_missing", &[1, 2, 3], raid1, &[1, 2]);
+        assert!(error(&fs, 2).contains("device 3 is missing too"));
+        let fs = removal_fs("remove_single", &[1, 2], BTRFS_BLOCK_GROUP_DATA, &[2]);
+        let message = error(&fs, 2);
+        assert!(message.contains("can't do without them"), "{message}");
+        assert!(message.starts_with(&format!("chunk {DATA}")), "{message}");
+    }
+}
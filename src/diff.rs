@@ -0,0 +1,147 @@
+//! Comparison of the trees of two filesystems item by item, e.g. a copy
+//! taken before a repair and the repaired filesystem.
+
+use crate::btrfs::*;
+use crate::btrfs_node::ItemData;
+use crate::structures::*;
+use crate::tree::*;
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Deref;
+
+/// an item which differs: old is None for an added item, and new for a
+/// removed one
+#[derive(Clone, Debug)]
+pub struct ItemDiff<D = ItemData> {
+    pub key: btrfs_disk_key,
+    pub old: Option<D>,
+    pub new: Option<D>,
+}
+
+/// merge two sequences of items in key order, yielding those only in one of
+/// them and those whose data differs
+pub fn diff_items<D: Deref<Target = [u8]>>(
+    old: impl Iterator<Item = (btrfs_disk_key, D)>,
+    new: impl Iterator<Item = (btrfs_disk_key, D)>,
+) -> impl Iterator<Item = ItemDiff<D>> {
+    let (mut old, mut new) = (old.peekable(), new.peekable());
+    std::iter::from_fn(move || loop {
+        let order = match (old.peek(), new.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((old, _)), Some((new, _))) => cmp_key(old, new),
+        };
+        let (key, old, new) = match order {
+            Ordering::Less => {
+                let (key, old) = old.next()?;
+                (key, Some(old), None)
+            }
+            Ordering::Greater => {
+                let (key, new) = new.next()?;
+                (key, None, Some(new))
+            }
+            Ordering::Equal => {
+                let (key, old) = old.next()?;
+                let (_, new) = new.next()?;
+                if *old == *new {
+                    continue;
+                }
+                (key, Some(old), Some(new))
+            }
+        };
+        return Some(ItemDiff { key, old, new });
+    })
+}
+
+/// the items of the tree with its root block at root, none without a root
+fn tree_items(
+    fs: &FsInfo,
+    root: Option<u64>,
+) -> impl Iterator<Item = (btrfs_disk_key, ItemData)> + '_ {
+    root.into_iter().flat_map(move |root| {
+        search_range(fs, root, key_range(None, None, None))
+            .map(|(item, data, _, _)| (item.key, data))
+    })
+}
+
+/// the root block of each tree of a filesystem by tree id
+pub fn tree_root_map(fs: &FsInfo) -> BTreeMap<u64, u64> {
+    let mut roots = BTreeMap::new();
+    for (tree, bytenr, _level) in tree_roots(fs) {
+        roots.entry(tree).or_insert(bytenr);
+    }
+    roots
+}
+
+/// the root block of each tree as a backup root in the superblock recorded
+/// them, by tree id. The log is never part of a backup root.
+pub fn backup_root_map(fs: &FsInfo, backup: &btrfs_root_backup) -> BTreeMap<u64, u64> {
+    let mut roots = BTreeMap::from([
+        (BTRFS_CHUNK_TREE_OBJECTID, backup.chunk_root),
+        (BTRFS_ROOT_TREE_OBJECTID, backup.tree_root),
+    ]);
+    for (tree, bytenr, _level) in root_items(fs, backup.tree_root) {
+        roots.entry(tree).or_insert(bytenr);
+    }
+    roots
+}
+
+/// the differences between a tree in old_fs and new_fs, given the root
+/// block of the tree in each (None where the tree doesn't exist). old_fs and
+/// new_fs may be the same filesystem, and then a tree with the same root
+/// block in both is skipped; blocks of different filesystems are always
+/// compared, as a repair may have rewritten blocks in place.
+pub fn diff_tree<'a>(
+    old_fs: &'a FsInfo,
+    old_root: Option<u64>,
+    new_fs: &'a FsInfo,
+    new_root: Option<u64>,
+) -> impl Iterator<Item = ItemDiff> + 'a {
+    let unchanged = std::ptr::eq(old_fs, new_fs) && old_root == new_root;
+    let (old_root, new_root) = if unchanged {
+        (None, None)
+    } else {
+        (old_root, new_root)
+    };
+    diff_items(tree_items(old_fs, old_root), tree_items(new_fs, new_root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(objectid: u64, item_type: BtrfsItemType) -> btrfs_disk_key {
+        btrfs_disk_key {
+            objectid,
+            item_type,
+            offset: 0,
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let old = [
+            (key(256, BtrfsItemType::INODE_ITEM), &b"a"[..]),
+            (key(256, BtrfsItemType::INODE_REF), &b"b"[..]),
+            (key(257, BtrfsItemType::INODE_ITEM), &b"c"[..]),
+        ];
+        let new = [
+            (key(256, BtrfsItemType::INODE_ITEM), &b"a"[..]),
+            (key(256, BtrfsItemType::INODE_REF), &b"x"[..]),
+            (key(258, BtrfsItemType::INODE_ITEM), &b"d"[..]),
+        ];
+        let changes: Vec<_> = diff_items(old.into_iter(), new.into_iter())
+            .map(|c| ({ c.key.objectid }, c.old, c.new))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (256, Some(&b"b"[..]), Some(&b"x"[..])),
+                (257, Some(&b"c"[..]), None),
+                (258, None, Some(&b"d"[..])),
+            ]
+        );
+    }
+}
@@ -1,30 +1,237 @@
 use crate::address::*;
 use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::cancel::CancellationToken;
+use crate::carve::*;
+use crate::census::*;
+use crate::check::*;
+use crate::chunk_map::*;
+use crate::device::*;
+use crate::diff::*;
+use crate::edit::*;
+use crate::export::*;
+use crate::flags::*;
+use crate::free_space::*;
+use crate::heatmap::*;
+use crate::inode::*;
+use crate::items::*;
+use crate::mapped_file::MappedFile;
+use crate::partition::*;
+use crate::qgroup::*;
+use crate::raid56::*;
+use crate::raid_stripe::*;
+use crate::rebuild::*;
+use crate::repair::*;
+use crate::restore::*;
+use crate::scrub::*;
+use crate::space::*;
+use crate::stats::*;
 use crate::structures::*;
 use crate::tree::*;
+use crate::tree_log::*;
+use crate::undelete::*;
+use crate::units::fmt_size;
+use crate::verity::verity_descriptor;
 
 use anyhow::*;
 use more_asserts::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-fn uuid_str(uuid: &BtrfsUuid) -> String {
-    std::format!(
-        "{}-{}-{}-{}-{}",
-        hex::encode(&uuid[0..4]),
-        hex::encode(&uuid[4..6]),
-        hex::encode(&uuid[6..8]),
-        hex::encode(&uuid[8..10]),
-        hex::encode(&uuid[10..])
-    )
+/// classic 16 bytes per line hexdump. base is added to the printed offsets
+pub fn hexdump_lines(data: &[u8], base: u64) -> Vec<String> {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, line)| {
+            let hex: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+            let ascii: String = line
+                .iter()
+                .map(|b| {
+                    if b.is_ascii_graphic() || *b == b' ' {
+                        *b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!(
+                "{:08x}  {:<47}  {ascii}",
+                base + (i * 16) as u64,
+                hex.join(" ")
+            )
+        })
+        .collect()
 }
 
+/// every field of the superblock, raw values alongside decoded ones
 pub fn dump_sb(sb: &btrfs_super_block) {
+    let sb_bytes = unsafe {
+        std::slice::from_raw_parts(
+            sb as *const btrfs_super_block as *const u8,
+            std::mem::size_of::<btrfs_super_block>(),
+        )
+    };
+    let csum_ok = sb.csum == csum_data(&sb_bytes[BTRFS_CSUM_SIZE..], sb.csum_type);
+    let bytenr = sb.bytenr;
+    let flags = sb.flags;
+    let magic = sb.magic;
+    let generation = sb.generation;
+    let root = sb.root;
+    let chunk_root = sb.chunk_root;
+    let log_root = sb.log_root;
+    let total_bytes = sb.total_bytes;
+    let bytes_used = sb.bytes_used;
+    let root_dir_object_id = sb.root_dir_object_id;
+    let num_devices = sb.num_devices;
     let sectorsize = sb.sectorsize;
     let nodesize = sb.nodesize;
+    let leafsize = sb.__unused_leafsize;
     let stripesize = sb.stripesize;
+    let sys_chunk_array_size = sb.sys_chunk_array_size;
+    let chunk_root_generation = sb.chunk_root_generation;
+    let compat_flags = sb.compat_flags;
+    let compat_ro_flags = sb.compat_ro_flags;
+    let incompat_flags = sb.incompat_flags;
+    let csum_type = sb.csum_type;
+    let cache_generation = sb.cache_generation;
+    let uuid_tree_generation = sb.uuid_tree_generation;
+    let nr_global_roots = sb.nr_global_roots;
+    let label_len = sb
+        .label
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(BTRFS_LABEL_SIZE);
 
-    println!("sector size: {sectorsize}");
-    println!("node size: {nodesize}");
-    println!("stripe size: {stripesize}");
+    println!(
+        "csum: {} ({})",
+        hex::encode(&sb.csum[..csum_size(sb.csum_type)]),
+        if csum_ok { "ok" } else { "BAD" }
+    );
+    println!("bytenr: {bytenr}");
+    println!("flags: {flags:#x} ({})", fmt_flags(flags, SUPER_FLAGS));
+    println!(
+        "magic: {magic:#x} ({})",
+        if magic == BTRFS_MAGIC { "ok" } else { "BAD" }
+    );
+    println!("fsid: {}", sb.fsid);
+    println!(
+        "metadata_uuid: {}{}",
+        sb.metadata_uuid,
+        if sb.metadata_uuid == sb.fsid {
+            " (same as fsid)"
+        } else if sb.metadata_uuid.is_nil() {
+            " (unset)"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "label: {:?}",
+        String::from_utf8_lossy(&sb.label[..label_len])
+    );
+    println!("generation: {generation}");
+    println!("root: {root} level {}", sb.root_level);
+    println!(
+        "chunk root: {chunk_root} level {} generation {chunk_root_generation}",
+        sb.chunk_root_level
+    );
+    println!("log root: {log_root} level {}", sb.log_root_level);
+    println!("total bytes: {}", fmt_size(total_bytes));
+    println!("bytes used: {}", fmt_size(bytes_used));
+    println!("root dir objectid: {root_dir_object_id}");
+    println!("num devices: {num_devices}");
+    println!("sector size: {}", fmt_size(sectorsize as u64));
+    println!("node size: {}", fmt_size(nodesize as u64));
+    println!("leaf size (unused): {}", fmt_size(leafsize as u64));
+    println!("stripe size: {}", fmt_size(stripesize as u64));
+    println!(
+        "sys chunk array size: {}",
+        fmt_size(sys_chunk_array_size as u64)
+    );
+    println!("compat flags: {compat_flags:#x}");
+    println!(
+        "compat_ro flags: {compat_ro_flags:#x} ({})",
+        fmt_flags(compat_ro_flags, COMPAT_RO_FEATURES)
+    );
+    println!(
+        "incompat flags: {incompat_flags:#x} ({})",
+        fmt_flags(incompat_flags, INCOMPAT_FEATURES)
+    );
+    println!("csum type: {} ({csum_type:?})", csum_type as u16);
+    println!("cache generation: {cache_generation}");
+    println!("uuid tree generation: {uuid_tree_generation}");
+    println!("nr global roots: {nr_global_roots}");
+    println!("dev item:");
+    for line in describe_dev_item(&sb.dev_item) {
+        println!("    {line}");
+    }
+    println!("sys chunk array:");
+    for ChunkInfo(key, chunk, stripes) in SysChunkIter::new(sb) {
+        let offset = key.offset;
+        let length = chunk.length;
+        let chunk_type = chunk.r#type;
+        println!(
+            "    chunk {offset} length {} type {}",
+            fmt_size(length),
+            fmt_block_group_type(chunk_type)
+        );
+        for stripe in stripes {
+            let devid = stripe.devid;
+            let stripe_offset = stripe.offset;
+            println!(
+                "        devid {devid} offset {stripe_offset} dev_uuid {}",
+                stripe.dev_uuid
+            );
+        }
+    }
+    for (i, backup) in sb.super_roots.iter().enumerate() {
+        let tree_root = backup.tree_root;
+        let tree_root_gen = backup.tree_root_gen;
+        let chunk_root = backup.chunk_root;
+        let chunk_root_gen = backup.chunk_root_gen;
+        let extent_root = backup.extent_root;
+        let extent_root_gen = backup.extent_root_gen;
+        let fs_root = backup.fs_root;
+        let fs_root_gen = backup.fs_root_gen;
+        let dev_root = backup.dev_root;
+        let dev_root_gen = backup.dev_root_gen;
+        let csum_root = backup.csum_root;
+        let csum_root_gen = backup.csum_root_gen;
+        let total_bytes = backup.total_bytes;
+        let bytes_used = backup.bytes_used;
+        let num_devices = backup.num_devices;
+        println!("backup root {i}:");
+        println!(
+            "    tree root {tree_root} gen {tree_root_gen} level {}",
+            backup.tree_root_level
+        );
+        println!(
+            "    chunk root {chunk_root} gen {chunk_root_gen} level {}",
+            backup.chunk_root_level
+        );
+        println!(
+            "    extent root {extent_root} gen {extent_root_gen} level {}",
+            backup.extent_root_level
+        );
+        println!(
+            "    fs root {fs_root} gen {fs_root_gen} level {}",
+            backup.fs_root_level
+        );
+        println!(
+            "    dev root {dev_root} gen {dev_root_gen} level {}",
+            backup.dev_root_level
+        );
+        println!(
+            "    csum root {csum_root} gen {csum_root_gen} level {}",
+            backup.csum_root_level
+        );
+        println!(
+            "    total bytes {} bytes used {} num devices {num_devices}",
+            fmt_size(total_bytes),
+            fmt_size(bytes_used)
+        );
+    }
 }
 
 /// sys_chunk_array has members with inconsistent lengths. Each member is comprised of a btrfs_disk_key, a btrfs_chunk (which contains one btrfs_stripe) then btrfs_chunk.num_stripes -1 additional btrfs_stripes.
@@ -45,7 +252,11 @@ pub fn dump_chunks(sb: &btrfs_super_block) {
         assert_eq!(offset, chunk_root);
         //disk key offset is the virtual location
         //stripe devid/offset is the physical location
-        println!("chunk: objectid {objectid} offset {offset} length {length} owner {owner} num_stripes: {num_stripes} substripes: {num_substripes}");
+        println!(
+            "chunk: objectid {objectid} offset {offset} length {} owner {owner} type {} num_stripes: {num_stripes} substripes: {num_substripes}",
+            fmt_size(length),
+            fmt_block_group_type(chunk.r#type)
+        );
         for stripe in stripes {
             dump_stripe(&stripe);
         }
@@ -57,9 +268,7 @@ fn dump_stripe(stripe: &btrfs_stripe) {
     let offset = stripe.offset;
     println!(
         "devid: {}, offset: {}, dev_uuid: {}",
-        devid,
-        offset,
-        uuid_str(&stripe.dev_uuid)
+        devid, offset, stripe.dev_uuid
     );
 }
 
@@ -71,11 +280,7 @@ pub fn dump_node_header(node_header: &btrfs_header) {
 
     println!(
         "node header: owner {}, uuid {}, generation: {}, nritems: {}, level: {}",
-        owner,
-        uuid_str(&node_header.chunk_tree_uuid),
-        gen,
-        nri,
-        level
+        owner, node_header.chunk_tree_uuid, gen, nri, level
     );
 }
 
@@ -92,6 +297,7 @@ pub fn fmt_treeid(treeid: u64) -> String {
         BTRFS_UUID_TREE_OBJECTID => String::from("UUID_TREE"),
         BTRFS_FREE_SPACE_TREE_OBJECTID => String::from("FREE_SPACE_TREE"),
         BTRFS_BLOCK_GROUP_TREE_OBJECTID => String::from("BLOCK_GROUP_TREE"),
+        BTRFS_RAID_STRIPE_TREE_OBJECTID => String::from("RAID_STRIPE_TREE"),
         BTRFS_DEV_STATS_OBJECTID => String::from("DEV_STATS"),
         BTRFS_BALANCE_OBJECTID => String::from("BALANCE"),
         BTRFS_ORPHAN_OBJECTID => String::from("ORPHAN"),
@@ -110,12 +316,8 @@ pub fn fmt_treeid(treeid: u64) -> String {
 
 pub fn dump_tree(fs: &FsInfo, root: LE64) -> Result<()> {
     let node_header = load_virt::<btrfs_header>(fs, root)?;
-    assert_eq!(node_header.fsid, fs.fsid);
-    let bytenr = node_header.bytenr;
-    assert_eq!(bytenr, root);
-    let node = &load_virt_block(fs, root)?[BTRFS_CSUM_SIZE..];
-    assert_eq!(node_header.csum, csum_data(node, fs.master_sb.csum_type));
-    dump_node_header(node_header);
+    check_header(fs, &node_header, root)?;
+    dump_node_header(&node_header);
     //TODO: dump nodes
     let search = NodeSearchOption {
         min_key: btrfs_disk_key {
@@ -148,157 +350,2133 @@ pub fn dump_tree(fs: &FsInfo, root: LE64) -> Result<()> {
     Ok(())
 }
 
-pub fn dump_root_tree(fs: &FsInfo) -> Result<()> {
-    let root = fs.master_sb.root;
-    let node_header = load_virt::<btrfs_header>(fs, root)?;
-    assert_eq!(node_header.fsid, fs.fsid);
-    let bytenr = node_header.bytenr;
-    assert_eq!(bytenr, root);
-    let node = &load_virt_block(fs, root)?[BTRFS_CSUM_SIZE..];
-    assert_eq!(node_header.csum, csum_data(node, fs.master_sb.csum_type));
-    dump_node_header(node_header);
-    //TODO: dump nodes
-    let search = NodeSearchOption {
-        min_key: btrfs_disk_key {
-            objectid: 0,
-            item_type: BtrfsItemType::MIN,
-            offset: 0,
-        },
-        max_key: btrfs_disk_key {
-            objectid: u64::MAX,
-            item_type: BtrfsItemType::MAX,
-            offset: u64::MAX,
+/// load_virt_block, but returning an error rather than panicking when the
+/// address is not the start of a node
+fn load_node_block(fs: &FsInfo, bytenr: u64) -> Result<Arc<[u8]>> {
+    let nodesize = fs.master_sb.nodesize as u64;
+    ensure!(
+        bytenr.is_multiple_of(nodesize),
+        "{bytenr} is not aligned to the node size {nodesize}"
+    );
+    load_virt_block_unverified(fs, bytenr)
+}
+
+/// print a single metadata block: its header, checksum status and the
+/// key pointers or decoded items it contains
+pub fn dump_block(fs: &FsInfo, bytenr: u64) -> Result<()> {
+    let block = load_node_block(fs, bytenr)?;
+    let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+    dump_node_header(header);
+    let header_bytenr = header.bytenr;
+    let csum_ok = header.csum == csum_data(&block[BTRFS_CSUM_SIZE..], fs.master_sb.csum_type);
+    println!(
+        "bytenr {header_bytenr}{} csum {}",
+        if header_bytenr != bytenr {
+            " (mismatch!)"
+        } else {
+            ""
         },
-        min_match: std::cmp::Ordering::Less,
-        max_match: std::cmp::Ordering::Greater,
+        if csum_ok { "ok" } else { "BAD" }
+    );
+    let entries = node_entries(&block);
+    let nritems = header.nritems;
+    if entries.len() != nritems as usize {
+        println!(
+            "nritems {nritems} overflows block, showing {}",
+            entries.len()
+        );
+    }
+    for (slot, entry) in entries.iter().enumerate() {
+        match entry {
+            NodeEntry::Ptr(ptr) => {
+                let key = ptr.key;
+                let blockptr = ptr.blockptr;
+                let generation = ptr.generation;
+                println!("ptr #{slot} {key:?} blockptr {blockptr} generation {generation}");
+            }
+            NodeEntry::Item(item, data) => {
+                let key = item.key;
+                let size = item.size;
+                println!("item #{slot} {key:?} size {size}");
+                match data {
+                    Some(data) => {
+                        for line in describe_item(&key, data) {
+                            println!("    {line}");
+                        }
+                    }
+                    None => println!("    item data lies outside the block"),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// a labelled byte range within a block, used by annotate_block
+struct Region {
+    start: usize,
+    end: usize,
+    label: String,
+}
+
+/// hexdump of a metadata block with structure boundaries marked: each header
+/// field, each item header or key pointer, and each item's data. Item data
+/// lying outside the block, overlapping regions and nritems overflowing the
+/// block are flagged, and bytes not referenced by anything are shown as unused
+/// (collapsed when they are all zero).
+pub fn annotate_block(block: &[u8], bytenr: u64, csum_type: BtrfsCsumType) -> Vec<String> {
+    let header_size = std::mem::size_of::<btrfs_header>();
+    if block.len() < header_size {
+        return vec![format!("block is only {} bytes", block.len())];
+    }
+    let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+    let csum_ok = header.csum == csum_data(&block[BTRFS_CSUM_SIZE..], csum_type);
+    let header_bytenr = header.bytenr;
+    let flags = header.flags;
+    let generation = header.generation;
+    let owner = header.owner;
+    let nritems = header.nritems;
+    let level = header.level;
+
+    let field = |start: usize, len: usize, label: String| Region {
+        start,
+        end: start + len,
+        label: format!("header {label}"),
     };
-    for (leaf, data, _block_offset, leaf_pos) in BtrfsTreeIter::new(fs, root, search) {
-        let btrfs_disk_key {
-            objectid,
-            item_type,
-            offset,
-        } = leaf.key;
-        let size = leaf.size;
+    let mut regions = vec![
+        field(
+            std::mem::offset_of!(btrfs_header, csum),
+            BTRFS_CSUM_SIZE,
+            format!("csum ({})", if csum_ok { "ok" } else { "BAD" }),
+        ),
+        field(
+            std::mem::offset_of!(btrfs_header, fsid),
+            std::mem::size_of::<BtrfsFsid>(),
+            format!("fsid {}", header.fsid),
+        ),
+        field(
+            std::mem::offset_of!(btrfs_header, bytenr),
+            8,
+            format!(
+                "bytenr {header_bytenr}{}",
+                if header_bytenr != bytenr {
+                    " (mismatch!)"
+                } else {
+                    ""
+                }
+            ),
+        ),
+        field(
+            std::mem::offset_of!(btrfs_header, flags),
+            8,
+            format!("flags {flags:#x}"),
+        ),
+        field(
+            std::mem::offset_of!(btrfs_header, chunk_tree_uuid),
+            std::mem::size_of::<BtrfsUuid>(),
+            format!("chunk_tree_uuid {}", header.chunk_tree_uuid),
+        ),
+        field(
+            std::mem::offset_of!(btrfs_header, generation),
+            8,
+            format!("generation {generation}"),
+        ),
+        field(
+            std::mem::offset_of!(btrfs_header, owner),
+            8,
+            format!("owner {}", fmt_treeid(owner)),
+        ),
+        field(
+            std::mem::offset_of!(btrfs_header, nritems),
+            4,
+            format!("nritems {nritems}"),
+        ),
+        field(
+            std::mem::offset_of!(btrfs_header, level),
+            1,
+            format!("level {level}"),
+        ),
+    ];
 
-        match item_type {
-            BtrfsItemType::ROOT_ITEM => {
-                assert_eq!(size as usize, std::mem::size_of::<btrfs_root_item>());
-                let root_item = unsafe { &*((data.as_ptr()) as *const btrfs_root_item) };
-                let tree_root = root_item.bytenr;
-                println!(
-                    "leaf #{leaf_pos} {} {item_type:?} {offset} data size {} tree root {tree_root}",
-                    fmt_treeid(objectid),
-                    size
-                );
+    let mut notes = Vec::new();
+    let entries = node_entries(block);
+    if entries.len() != nritems as usize {
+        notes.push(format!(
+            "nritems {nritems} overflows the block, only {} fit",
+            entries.len()
+        ));
+    }
+    for (slot, entry) in entries.iter().enumerate() {
+        match entry {
+            NodeEntry::Ptr(ptr) => {
+                let key = ptr.key;
+                let blockptr = ptr.blockptr;
+                let start = header_size + slot * std::mem::size_of::<btrfs_key_ptr>();
+                regions.push(Region {
+                    start,
+                    end: start + std::mem::size_of::<btrfs_key_ptr>(),
+                    label: format!("ptr {slot}: {key:?} -> {blockptr}"),
+                });
             }
-            BtrfsItemType::ROOT_REF => {
-                assert_ge!(size as usize, std::mem::size_of::<btrfs_root_ref>());
+            NodeEntry::Item(item, data) => {
+                let key = item.key;
+                let offset = item.offset;
+                let size = item.size;
+                let start = header_size + slot * std::mem::size_of::<btrfs_item>();
+                let data_start = header_size + offset as usize;
+                let outside = if data.is_none() {
+                    " (data outside block!)"
+                } else {
+                    ""
+                };
+                regions.push(Region {
+                    start,
+                    end: start + std::mem::size_of::<btrfs_item>(),
+                    label: format!("item {slot}: {key:?} data {data_start}+{size}{outside}"),
+                });
+                if data.is_some() && size > 0 {
+                    regions.push(Region {
+                        start: data_start,
+                        end: data_start + size as usize,
+                        label: format!("item {slot} data: {:?}", key.item_type),
+                    });
+                } else if data.is_none() {
+                    notes.push(format!(
+                        "item {slot} data {data_start}+{size} lies outside the {} byte block",
+                        block.len()
+                    ));
+                }
+            }
+        }
+    }
+    regions.sort_by_key(|r| (r.start, r.end));
 
-                let root_ref = unsafe { &*((data.as_ptr()) as *const btrfs_root_ref) };
-                let name_len = root_ref.name_len;
-                let dirid = root_ref.dirid;
-                assert_eq!(
-                    size as usize,
-                    name_len as usize + std::mem::size_of::<btrfs_root_ref>()
-                );
-                println!(
-                    "root ref {} {item_type:?} {offset} dirid {dirid} name: {}",
-                    fmt_treeid(objectid),
-                    std::str::from_utf8(&data[std::mem::size_of::<btrfs_root_ref>()..])?
-                );
+    let mut lines = notes;
+    let mut cursor = 0;
+    let show = |lines: &mut Vec<String>, start: usize, end: usize, label: &str| {
+        let data = &block[start..end];
+        if label == "unused" && data.iter().all(|b| *b == 0) {
+            lines.push(format!(
+                "-- {start:#06x}..{end:#06x} unused ({} zero bytes)",
+                end - start
+            ));
+            return;
+        }
+        lines.push(format!("-- {start:#06x}..{end:#06x} {label}"));
+        lines.extend(hexdump_lines(data, start as u64));
+    };
+    for region in &regions {
+        if region.start > cursor {
+            show(&mut lines, cursor, region.start, "unused");
+        }
+        if region.start < cursor {
+            show(
+                &mut lines,
+                region.start,
+                region.end,
+                &format!("{} (overlaps previous region!)", region.label),
+            );
+        } else {
+            show(&mut lines, region.start, region.end, &region.label);
+        }
+        cursor = cursor.max(region.end);
+    }
+    if cursor < block.len() {
+        show(&mut lines, cursor, block.len(), "unused");
+    }
+    lines
+}
+
+/// print the annotated hexdump of the metadata block at bytenr
+pub fn dump_block_annotated(fs: &FsInfo, bytenr: u64) -> Result<()> {
+    let block = load_node_block(fs, bytenr)?;
+    for line in annotate_block(&block, bytenr, fs.master_sb.csum_type) {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// print (subvolume, inode, parent dir, full path) for every name matching pattern
+pub fn dump_find_name(fs: &FsInfo, pattern: &str) {
+    for m in find_names(fs, pattern) {
+        let name = String::from_utf8_lossy(&m.name);
+        let dir = tree_root_offset(fs, m.subvol)
+            .and_then(|root| inode_paths(fs, root, m.parent).into_iter().next())
+            .unwrap_or_else(|| String::from("?"));
+        println!(
+            "subvol {} inode {} parent {} path {}{dir}/{name}",
+            fmt_treeid(m.subvol),
+            m.inode,
+            m.parent,
+            subvol_path(fs, m.subvol)
+        );
+    }
+}
+
+/// print every item belonging to an inode (INODE_ITEM, refs, xattrs and
+/// EXTENT_DATA), its paths, and a summary of how much of the file's data is
+/// reachable
+pub fn dump_inode(fs: &FsInfo, subvol: u64, inode: u64) -> Result<()> {
+    let root = tree_root_offset(fs, subvol)
+        .ok_or_else(|| anyhow!("subvolume {} not found", fmt_treeid(subvol)))?;
+    for path in inode_paths(fs, root, inode) {
+        let path = if path.is_empty() { "/" } else { &path };
+        println!("path {}{path}", subvol_path(fs, subvol));
+    }
+
+    let mut size = None;
+    let mut readable = 0;
+    let mut inline = 0;
+    let mut sparse = 0;
+    let mut unreachable = 0;
+    let mut file_pos = 0;
+    let mut flags = 0;
+    let mut merkle_bytes = 0;
+    // extents may run past i_size (the last block is rounded up), only count what's in the file
+    let within_size = |size: Option<u64>, start: u64, len: u64| {
+        let end = (start + len).min(size.unwrap_or(u64::MAX));
+        end.saturating_sub(start)
+    };
+    for (item, data, block_offset, slot) in
+        search_range(fs, root, key_range(Some(inode), None, None))
+    {
+        let key = item.key;
+        let item_size = item.size;
+        println!("{block_offset}#{slot} {key:?} size {item_size}");
+        for line in describe_item(&key, &data) {
+            println!("    {line}");
+        }
+        match key.item_type {
+            BtrfsItemType::INODE_ITEM => {
+                let inode_item = item_as::<btrfs_inode_item>(&data);
+                size = inode_item.map(|i| i.size);
+                flags = inode_item.map_or(0, |i| i.flags);
+            }
+            BtrfsItemType::VERITY_MERKLE_ITEM => merkle_bytes += data.len() as u64,
+            BtrfsItemType::EXTENT_DATA => {
+                if data.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+                    continue;
+                }
+                let fe = unsafe { &*(data.as_ptr() as *const btrfs_file_extent_item) };
+                sparse += key.offset.saturating_sub(file_pos);
+                if fe.r#type == BTRFS_FILE_EXTENT_INLINE {
+                    let ram_bytes = fe.ram_bytes;
+                    inline += within_size(size, key.offset, ram_bytes);
+                    file_pos = key.offset + ram_bytes;
+                    continue;
+                }
+                let Some(fe) = item_as::<btrfs_file_extent_item>(&data) else {
+                    continue;
+                };
+                let disk_bytenr = fe.disk_bytenr;
+                let num_bytes = fe.num_bytes;
+                let len = within_size(size, key.offset, num_bytes);
+                if disk_bytenr == 0 || fe.r#type == BTRFS_FILE_EXTENT_PREALLOC {
+                    sparse += len;
+                } else if virtual_offset_to_physical(fs, disk_bytenr).is_ok() {
+                    readable += len;
+                } else {
+                    println!("    extent at {disk_bytenr} is not on any present device");
+                    unreachable += len;
+                }
+                file_pos = key.offset + num_bytes;
             }
             _ => {}
         }
     }
+
+    match size {
+        None => println!("no INODE_ITEM found for inode {inode}"),
+        Some(size) => {
+            sparse += size.saturating_sub(file_pos);
+            println!(
+                "size {}: {} in readable extents, {} inline, {} in holes or prealloc, {} unreachable",
+                fmt_size(size),
+                fmt_size(readable),
+                fmt_size(inline),
+                fmt_size(sparse),
+                fmt_size(unreachable)
+            );
+        }
+    }
+    match verity_descriptor(fs, root, inode) {
+        Result::Ok(Some(desc)) => {
+            println!("fs-verity {desc}, merkle tree {}", fmt_size(merkle_bytes))
+        }
+        Result::Ok(None) if flags & BTRFS_INODE_RO_VERITY != 0 => {
+            println!("VERITY flag set, but no verity descriptor found")
+        }
+        Result::Ok(None) => {}
+        Err(e) => println!("{e}"),
+    }
     Ok(())
 }
 
-pub fn dump_fs(fs: &FsInfo) -> Result<()> {
-    let sb = fs.master_sb;
-    dump_sb(&sb);
-
-    //dump_chunks(&sb);
+/// print the items of a tree with keys from min_key to max_key inclusive
+pub fn dump_tree_range(
+    fs: &FsInfo,
+    tree: u64,
+    min_key: btrfs_disk_key,
+    max_key: btrfs_disk_key,
+) -> Result<()> {
+    let root =
+        tree_root_offset(fs, tree).ok_or_else(|| anyhow!("tree {} not found", fmt_treeid(tree)))?;
+    ensure!(
+        cmp_key(&min_key, &max_key) != std::cmp::Ordering::Greater,
+        "min key {min_key:?} is greater than max key {max_key:?}"
+    );
+    dump_tree_items(fs, root, NodeSearchOption::between(min_key, max_key));
+    Ok(())
+}
 
-    for (devid, di) in fs.devid_map.iter() {
-        println!("devid {} is {}", devid, di.path.display());
+/// print an added, removed or changed item with its data decoded; for a
+/// changed item only the lines of the description which differ
+fn dump_item_diff(change: &ItemDiff) {
+    let key = change.key;
+    match (change.old.as_deref(), change.new.as_deref()) {
+        (Some(old), Some(new)) => {
+            println!("    ~ {key:?} size {} -> {}", old.len(), new.len());
+            let (old_lines, new_lines) = (describe_item(&key, old), describe_item(&key, new));
+            if old_lines == new_lines {
+                let first = old.iter().zip(new).position(|(o, n)| o != n);
+                let first = first.unwrap_or(old.len().min(new.len()));
+                println!("        data differs from byte {first}");
+                return;
+            }
+            for line in old_lines.iter().filter(|line| !new_lines.contains(line)) {
+                println!("        - {line}");
+            }
+            for line in new_lines.iter().filter(|line| !old_lines.contains(line)) {
+                println!("        + {line}");
+            }
+        }
+        (old, new) => {
+            let (sign, data) = match (old, new) {
+                (Some(old), _) => ('-', old),
+                (_, new) => ('+', new.unwrap_or_default()),
+            };
+            println!("    {sign} {key:?} size {}", data.len());
+            for line in describe_item(&key, data) {
+                println!("        {line}");
+            }
+        }
     }
-    let num_devices = sb.num_devices;
-    println!("{}/{} devices present", fs.devid_map.len(), num_devices);
+}
 
-    // There are two things we need to be able to do with these trees,
-    // iterate through an entire tree (perhaps until a condition is met),
-    // and identify a specific key (or part of a key) in a tree.
-    let ct_header = load_virt::<btrfs_header>(fs, sb.chunk_root)?;
-    assert_eq!(ct_header.fsid, fs.fsid);
-    let bn = ct_header.bytenr;
-    let cr = fs.master_sb.chunk_root;
-    assert_eq!(bn, cr);
-    //TODO: bother checking csum?
-    let cto = ct_header.owner;
-    //let ct_gen = ct_header.generation;
-    let ct_nri = ct_header.nritems;
-    //let ct_level = ct_header.level;
-    assert_eq!(cto, BTRFS_CHUNK_TREE_OBJECTID);
-    dump_node_header(ct_header);
+/// list the items added, removed and changed in each tree from before to
+/// fs, or only in the trees given
+pub fn dump_diff(before: &FsInfo, fs: &FsInfo, trees: Option<&[u64]>) -> Result<()> {
+    dump_tree_diffs(
+        before,
+        &tree_root_map(before),
+        fs,
+        &tree_root_map(fs),
+        trees,
+    )
+}
 
-    // for levels != 0 we have internal nodes
-    // https://btrfs.wiki.kernel.org/index.php/On-disk_Format#Internal_Node
+/// list the items added, removed and changed in each tree since backup root
+/// `backup` of the superblock, i.e. what rolling back to it would lose, or
+/// only in the trees given
+pub fn dump_diff_backup(fs: &FsInfo, backup: usize, trees: Option<&[u64]>) -> Result<()> {
+    let sb = &fs.master_sb;
+    let roots = sb.super_roots.get(backup).ok_or_else(|| {
+        anyhow!(
+            "backup root {backup} doesn't exist, there are {}",
+            sb.super_roots.len()
+        )
+    })?;
+    let (tree_root, generation) = (roots.tree_root, roots.tree_root_gen);
+    ensure!(tree_root != 0, "backup root {backup} is unused");
+    ensure!(
+        generation <= sb.generation,
+        "backup root {backup} is at generation {generation}, newer than the superblock at {}",
+        { sb.generation }
+    );
+    println!(
+        "since backup root {backup} at generation {generation}, {} generations ago:",
+        sb.generation - generation
+    );
+    dump_tree_diffs(
+        fs,
+        &backup_root_map(fs, roots),
+        fs,
+        &tree_root_map(fs),
+        trees,
+    )
+}
 
-    //the first level of the tree looks like this. After the header there is  random DEV_ITEM
-    //then a number of chunk_items. not clear what offset refers to.
-    //chunk tree header: uuid ab00c287-f8de-4fe1-b463-61cfc5c6814c, generation: 4756888, nritems: 76, level: 1
-    //object id: 1, node_type: DEV_ITEM, offset: 7, blockptr: 22093116751872, generation: 4756888
-    //object id: 256, node_type: CHUNK_ITEM, offset: 21264188047360, blockptr: 22093116882944, generation: 3409876
-    //...
-    let key_ptr_start: u64 = sb.chunk_root + std::mem::size_of::<btrfs_header>() as u64;
-    for i in 0..ct_nri {
-        let int_node = load_virt::<btrfs_key_ptr>(
-            fs,
-            key_ptr_start + i as u64 * std::mem::size_of::<btrfs_key_ptr>() as u64,
-        )?;
-        let oid = int_node.key.objectid;
-        let node_type = int_node.key.item_type;
-        let offset = int_node.key.offset;
-        let blockptr = int_node.blockptr;
-        let generation = int_node.generation;
-        println!(
-            "object id: {}, node_type: {:?}, offset: {}, blockptr: {}, generation: {}",
-            oid, node_type, offset, blockptr, generation
+fn dump_tree_diffs(
+    before: &FsInfo,
+    old_roots: &BTreeMap<u64, u64>,
+    fs: &FsInfo,
+    new_roots: &BTreeMap<u64, u64>,
+    trees: Option<&[u64]>,
+) -> Result<()> {
+    let trees: Vec<u64> = match trees {
+        Some(trees) => trees.to_vec(),
+        None => {
+            let mut trees: Vec<u64> = old_roots.keys().chain(new_roots.keys()).copied().collect();
+            trees.sort();
+            trees.dedup();
+            trees
+        }
+    };
+    let (mut added, mut removed, mut changed, mut changed_trees) = (0, 0, 0, 0);
+    for tree in trees {
+        let (old, new) = (old_roots.get(&tree).copied(), new_roots.get(&tree).copied());
+        ensure!(
+            old.is_some() || new.is_some(),
+            "tree {} not found",
+            fmt_treeid(tree)
         );
+        let mut counts = [0; 3];
+        for change in diff_tree(before, old, fs, new) {
+            if fs.cancel.is_cancelled() || before.cancel.is_cancelled() {
+                break;
+            }
+            if counts == [0; 3] {
+                println!(
+                    "{}:{}",
+                    fmt_treeid(tree),
+                    match (old, new) {
+                        (None, _) => " added",
+                        (_, None) => " removed",
+                        _ => "",
+                    }
+                );
+            }
+            counts[match (&change.old, &change.new) {
+                (None, _) => 0,
+                (_, None) => 1,
+                _ => 2,
+            }] += 1;
+            dump_item_diff(&change);
+        }
+        if counts != [0; 3] {
+            println!(
+                "    {} added, {} removed, {} changed",
+                counts[0], counts[1], counts[2]
+            );
+            changed_trees += 1;
+        }
+        added += counts[0];
+        removed += counts[1];
+        changed += counts[2];
     }
+    println!(
+        "diff: {added} added, {removed} removed, {changed} changed items in {changed_trees} trees"
+    );
+    Ok(())
+}
 
-    //let's look at one chunk item.
-    let block_ptr = load_virt::<btrfs_key_ptr>(
+/// write the items of trees between two keys to a CSV or Parquet file
+pub fn dump_export(
+    fs: &FsInfo,
+    trees: &[u64],
+    min_key: btrfs_disk_key,
+    max_key: btrfs_disk_key,
+    format: ExportFormat,
+    output: &Path,
+) -> Result<()> {
+    ensure!(
+        cmp_key(&min_key, &max_key) != std::cmp::Ordering::Greater,
+        "min key {min_key:?} is greater than max key {max_key:?}"
+    );
+    let count = export_items(
         fs,
-        key_ptr_start + std::mem::size_of::<btrfs_key_ptr>() as u64,
-    )?
-    .blockptr;
-    let node = load_virt::<btrfs_header>(fs, block_ptr)?;
-    dump_node_header(node);
-    let node_items_start = block_ptr + std::mem::size_of::<btrfs_header>() as u64;
-    for i in 0..node.nritems {
-        let leaf_node = load_virt::<btrfs_item>(
-            fs,
-            node_items_start + i as u64 * std::mem::size_of::<btrfs_item>() as u64,
-        )?;
-        let oid = leaf_node.key.objectid;
-        let node_type = leaf_node.key.item_type;
-        let offset = leaf_node.key.offset;
-        let int_offset = leaf_node.offset;
-        let size = leaf_node.size;
+        trees,
+        NodeSearchOption::between(min_key, max_key),
+        format,
+        output,
+    )?;
+    println!("wrote {count} items to {}", output.display());
+    Ok(())
+}
 
+/// print the shape, leaf fill and item histogram of a tree
+pub fn dump_tree_stats(fs: &FsInfo, tree: u64) -> Result<()> {
+    let root =
+        tree_root_offset(fs, tree).ok_or_else(|| anyhow!("tree {} not found", fmt_treeid(tree)))?;
+    let stats = tree_stats(fs, root);
+    println!("{} root {root}", fmt_treeid(tree));
+    println!("nodes: {}", stats.nodes());
+    for (level, count) in stats.nodes_per_level.iter().rev() {
+        println!("    level {level}: {count}");
+    }
+    if stats.leaf_bytes_available > 0 {
         println!(
-            "object id: {}, node_type: {:?}, offset: {}, itemoffset: {}, size: {}",
-            oid, node_type, offset, int_offset, size
+            "leaf fill: {} of {} ({:.1}%)",
+            fmt_size(stats.leaf_bytes_used),
+            fmt_size(stats.leaf_bytes_available),
+            stats.leaf_bytes_used as f64 * 100.0 / stats.leaf_bytes_available as f64
         );
     }
+    if let Some((min, max)) = stats.generations {
+        println!("generations: {min}..{max}");
+    }
+    println!(
+        "items: {} with {} of data",
+        stats.items(),
+        fmt_size(stats.item_data_bytes)
+    );
+    for (item_type, count) in &stats.item_types {
+        println!("    {item_type:?}: {count}");
+    }
+    if let Some((key, size, leaf)) = stats.largest_item {
+        println!("largest item: {key:?} size {size} in leaf {leaf}");
+    }
+    for problem in &stats.problems {
+        println!("problem: {problem}");
+    }
+    Ok(())
+}
 
-    println!("root tree");
-    dump_root_tree(fs)?;
-
-    let extent_tree_root = tree_root_offset(fs, BTRFS_EXTENT_TREE_OBJECTID).unwrap();
-    println!("root of extent tree: {}", extent_tree_root);
-    dump_tree(fs, extent_tree_root)?;
+/// space allocated and used per block group type and profile, compared
+/// with the totals in the superblock
+pub fn dump_df(fs: &FsInfo) -> Result<()> {
+    let usage = space_usage(fs)?;
+    println!(
+        "{:<20} {:>6} {:>14} {:>14} {:>14}",
+        "type", "groups", "allocated", "used", "on devices"
+    );
+    let mut total = ProfileUsage {
+        raw: Some(0),
+        ..Default::default()
+    };
+    for (flags, u) in &usage {
+        println!(
+            "{:<20} {:>6} {:>14} {:>14} {:>14}",
+            fmt_block_group_type(*flags),
+            u.block_groups,
+            fmt_size(u.allocated),
+            fmt_size(u.used),
+            u.raw.map(fmt_size).unwrap_or_else(|| String::from("?"))
+        );
+        total.block_groups += u.block_groups;
+        total.allocated += u.allocated;
+        total.used += u.used;
+        total.raw = total.raw.zip(u.raw).map(|(a, b)| a + b);
+    }
+    println!(
+        "{:<20} {:>6} {:>14} {:>14} {:>14}",
+        "total",
+        total.block_groups,
+        fmt_size(total.allocated),
+        fmt_size(total.used),
+        total.raw.map(fmt_size).unwrap_or_else(|| String::from("?"))
+    );
+
+    let bytes_used = fs.master_sb.bytes_used;
+    let total_bytes = fs.master_sb.total_bytes;
+    println!(
+        "superblock: bytes used {} of {} ({} unallocated)",
+        fmt_size(bytes_used),
+        fmt_size(total_bytes),
+        fmt_size(total_bytes.saturating_sub(total.raw.unwrap_or(0)))
+    );
+    if bytes_used != total.used {
+        println!(
+            "warning: superblock bytes used differs from the block groups by {}",
+            fmt_size(bytes_used.abs_diff(total.used))
+        );
+    }
+    Ok(())
+}
+
+/// data referenced per subvolume, or with subvolumes false just the totals
+pub fn dump_du(fs: &FsInfo, subvolumes: bool) -> Result<()> {
+    let usage = data_usage(fs)?;
+    if subvolumes {
+        println!(
+            "{:>8} {:>14} {:>14} {:>14} {:>10}  path",
+            "subvol", "referenced", "exclusive", "shared", "inline"
+        );
+        for u in &usage.subvols {
+            let path = subvol_path(fs, u.subvol);
+            println!(
+                "{:>8} {:>14} {:>14} {:>14} {:>10}  {}",
+                u.subvol,
+                fmt_size(u.referenced),
+                fmt_size(u.exclusive),
+                fmt_size(u.referenced - u.exclusive),
+                fmt_size(u.inline),
+                if path.is_empty() { "/" } else { &path }
+            );
+        }
+    }
+    println!("data referenced: {}", fmt_size(usage.referenced));
+    println!(
+        "data extents referenced by no subvolume: {}",
+        fmt_size(usage.unreferenced)
+    );
+    for subvol in &usage.incomplete {
+        println!(
+            "warning: subvolume {} could not be read completely, its usage is too low",
+            fmt_treeid(*subvol)
+        );
+    }
+    Ok(())
+}
+
+/// recount the qgroups and report those whose QGROUP_INFO item has drifted
+/// from what the trees hold
+pub fn dump_qgroup_check(fs: &FsInfo) -> Result<()> {
+    let check = check_qgroups(fs)?;
+    if let Some(status) = &check.status {
+        let flags = status.flags;
+        let generation = status.generation;
+        println!(
+            "quota status: flags {} generation {generation}",
+            fmt_flags(flags, QGROUP_STATUS_FLAGS)
+        );
+        if flags & BTRFS_QGROUP_STATUS_FLAG_RESCAN != 0 {
+            println!(
+                "a rescan is in progress, at objectid {}: the recorded counts are partial",
+                { status.rescan }
+            );
+        }
+        if flags & BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT != 0 {
+            println!("the kernel has marked the counts inconsistent: a rescan is needed");
+        }
+    }
+    println!(
+        "{:>10} {:>14} {:>14} {:>14} {:>14}",
+        "qgroup", "rfer recorded", "rfer found", "excl recorded", "excl found"
+    );
+    let mut drifted = 0;
+    for qgroup in &check.qgroups {
+        let (rfer, excl) = qgroup.found;
+        let (rfer_recorded, excl_recorded) = match qgroup.recorded {
+            Some((rfer, excl)) => (fmt_size(rfer), fmt_size(excl)),
+            None => (String::from("-"), String::from("-")),
+        };
+        let state = match qgroup.recorded {
+            None => "no info item",
+            Some(_) if qgroup.drifted() => "drifted",
+            Some(_) => "ok",
+        };
+        drifted += usize::from(qgroup.drifted());
+        println!(
+            "{:>10} {:>14} {:>14} {:>14} {:>14}  {state}",
+            fmt_qgroupid(qgroup.qgroupid),
+            rfer_recorded,
+            fmt_size(rfer),
+            excl_recorded,
+            fmt_size(excl)
+        );
+    }
+    for problem in &check.problems {
+        println!("problem: {problem}");
+    }
+    for subvol in &check.incomplete {
+        println!(
+            "warning: subvolume {} could not be read completely, its counts are too low",
+            fmt_treeid(*subvol)
+        );
+    }
+    println!(
+        "{} qgroups, {drifted} not matching the trees",
+        check.qgroups.len()
+    );
+    Ok(())
+}
+
+/// compare the free space tree or v1 cache with the free space the extent
+/// tree leaves, per block group, and with fix_plan say what would make them
+/// agree
+pub fn dump_free_space_check(fs: &FsInfo, fix_plan: bool) -> Result<()> {
+    let check = check_free_space(fs)?;
+    match check.source {
+        FreeSpaceSource::Tree { .. } => println!("free space recorded in the free space tree"),
+        FreeSpaceSource::Cache => println!("free space recorded in v1 cache files"),
+    }
+    let mut mismatched = 0;
+    for group in &check.groups {
+        let recorded = match group.recorded {
+            Some(recorded) => fmt_size(recorded),
+            None => String::from("-"),
+        };
+        let state = if !group.trusted() {
+            "stale"
+        } else if group.consistent() {
+            "ok"
+        } else {
+            mismatched += 1;
+            "mismatch"
+        };
+        println!(
+            "block group {}..{}: free {} recorded {recorded}  {state}",
+            group.start,
+            group.start + group.length,
+            fmt_size(group.expected)
+        );
+        for reason in &group.stale {
+            println!("    not trusted: {reason}");
+        }
+        for problem in &group.problems {
+            println!("    problem: {problem}");
+        }
+        if group.recorded.is_none() {
+            continue;
+        }
+        for (start, end) in &group.allocated {
+            println!(
+                "    {start}..{end} ({}) recorded free, but allocated",
+                fmt_size(end - start)
+            );
+        }
+        for (start, end) in &group.unrecorded {
+            println!(
+                "    {start}..{end} ({}) free, but not recorded",
+                fmt_size(end - start)
+            );
+        }
+    }
+    for problem in &check.problems {
+        println!("problem: {problem}");
+    }
+    println!(
+        "{} block groups, {mismatched} trusted and not matching the extent tree",
+        check.groups.len()
+    );
+    if check.source == (FreeSpaceSource::Tree { valid: false }) {
+        println!("FREE_SPACE_TREE_VALID is clear: the kernel rebuilds the free space tree at the next read-write mount");
+    }
+    if fix_plan {
+        let options = RepairOptions {
+            dry_run: true,
+            backup_dir: PathBuf::new(),
+        };
+        match plan_free_space_fix(fs, &check)? {
+            Some(FreeSpaceFix::ClearTreeValid(change)) => {
+                println!("fix plan: clear FREE_SPACE_TREE_VALID with features --clear free-space-tree-valid, so that the kernel rebuilds the free space tree from the extent tree at the next read-write mount");
+                print_super_copies(&change.supers, &options);
+            }
+            Some(FreeSpaceFix::InvalidateCaches(copies)) => {
+                println!("fix plan: set cache_generation to {} with super edit, so that the kernel discards every v1 cache and writes them afresh", u64::MAX);
+                print_super_copies(&copies, &options);
+            }
+            None => println!("fix plan: nothing to do"),
+        }
+    }
+    Ok(())
+}
+
+/// check every copy of every tree block and list the damaged ones, on jobs
+/// threads
+pub fn dump_scrub_metadata(fs: &FsInfo, jobs: usize) -> Result<()> {
+    let scrub = scrub_metadata_parallel(fs, jobs)?;
+    for block in &scrub.damaged {
+        println!("block {} ({}):", block.logical, block.tree);
+        for copy in &block.copies {
+            println!(
+                "    devid {} physical {}: {}",
+                copy.devid,
+                copy.physical,
+                if copy.problems.is_empty() {
+                    String::from("ok")
+                } else {
+                    copy.problems.join(", ")
+                }
+            );
+        }
+        if !block.recoverable {
+            println!("    no good copy, blocks below it were not checked");
+        }
+    }
+    println!(
+        "metadata scrub: {} tree blocks, {} copies, {} damaged",
+        scrub.blocks,
+        scrub.copies,
+        scrub.damaged.len()
+    );
+    Ok(())
+}
+
+/// list the tree blocks whose mirrored copies differ, and how
+pub fn dump_mirror_divergence(fs: &FsInfo) {
+    let divergent = mirror_divergence(fs);
+    for d in &divergent {
+        println!("block {} ({}):", d.logical, d.tree);
+        for (i, copy) in d.copies.iter().enumerate() {
+            let difference = match (copy.first_difference, copy.last_difference) {
+                (Some(first), Some(last)) => format!(
+                    "{} bytes differ in {} runs between {first:#x} and {last:#x}",
+                    copy.differing_bytes, copy.differing_runs
+                ),
+                _ if i == 0 => String::from("reference copy"),
+                _ => String::from("same as the reference copy"),
+            };
+            println!(
+                "    devid {} physical {}: csum {} generation {}, {difference}",
+                copy.devid,
+                copy.physical,
+                if copy.csum_ok { "ok" } else { "BAD" },
+                copy.generation
+            );
+        }
+    }
+    println!("{} tree blocks with divergent copies", divergent.len());
+}
+
+/// repair the copies of one block, or with logical None everything a scrub
+/// finds, and print what was (or would be) overwritten
+pub fn dump_repair_copies(
+    fs: &FsInfo,
+    logical: Option<u64>,
+    options: &RepairOptions,
+) -> Result<()> {
+    let RepairReport { repairs, failures } = match logical {
+        Some(logical) => RepairReport {
+            repairs: repair_copies(fs, logical, options)?,
+            ..Default::default()
+        },
+        None => repair_from_scrub(fs, options)?,
+    };
+    for r in &repairs {
+        println!(
+            "{} {}..{} on devid {} physical {}{}",
+            if options.dry_run {
+                "would overwrite"
+            } else {
+                "overwrote"
+            },
+            r.logical,
+            r.logical + r.length,
+            r.devid,
+            r.physical,
+            match &r.backup {
+                Some(path) => format!(", old copy saved to {}", path.display()),
+                None => String::new(),
+            }
+        );
+    }
+    for (logical, reason) in &failures {
+        println!("cannot repair {logical}: {reason}");
+    }
+    println!(
+        "{} copies repaired, {} blocks not repairable",
+        repairs.len(),
+        failures.len()
+    );
+    Ok(())
+}
+
+/// check every copy of the data with checksums, optionally only that of some
+/// subvolumes, and list the sectors that don't match. With jobs other than 1
+/// the data is read on readers threads and checked on jobs threads.
+pub fn dump_scrub_data(
+    fs: &FsInfo,
+    subvols: Option<&[u64]>,
+    jobs: usize,
+    readers: usize,
+) -> Result<()> {
+    let scrub = scrub_data_parallel(fs, subvols, jobs, readers)?;
+    for bad in &scrub.bad {
+        println!(
+            "bad data {}..{} on devid {} physical {} ({})",
+            bad.logical,
+            bad.logical + bad.length,
+            bad.devid,
+            bad.physical,
+            match bad.extent {
+                Some(start) => format!("extent {start}"),
+                None => String::from("not allocated in the extent tree"),
+            }
+        );
+    }
+    for error in &scrub.errors {
+        println!("not checked: {error}");
+    }
+    println!(
+        "data scrub: {} sectors, {} copies, {} bad runs, {} not checked",
+        scrub.sectors,
+        scrub.copies,
+        scrub.bad.len(),
+        scrub.errors.len()
+    );
+    Ok(())
+}
+
+/// run the tree checker over every tree and list every problem found
+pub fn dump_check(fs: &FsInfo) {
+    let report = check_fs(fs);
+    for problem in &report.problems {
+        println!(
+            "{} block {}{}: {}",
+            fmt_treeid(problem.tree),
+            problem.block,
+            match problem.slot {
+                Some(slot) => format!(" slot {slot}"),
+                None => String::new(),
+            },
+            problem.message
+        );
+    }
+    println!(
+        "check: {} trees, {} blocks, {} items, {} problems",
+        report.trees,
+        report.blocks,
+        report.items,
+        report.problems.len()
+    );
+}
+
+/// print, per tree, how many of the blocks the extent tree records could be
+/// reached, and which could not. The trees are walked on jobs threads.
+pub fn dump_census(fs: &FsInfo, jobs: usize) -> Result<()> {
+    let census = metadata_census_parallel(fs, jobs)?;
+    for (&tree, tree_census) in &census.trees {
+        let (reached, allocated) = tree_census
+            .levels
+            .values()
+            .fold((0, 0), |(r, a), l| (r + l.reached, a + l.allocated));
+        println!(
+            "{}: {reached} blocks reached, {allocated} allocated",
+            if tree == 0 {
+                String::from("owner unknown")
+            } else {
+                fmt_treeid(tree)
+            }
+        );
+        for (level, counts) in tree_census.levels.iter().rev() {
+            println!(
+                "    level {level}: {} reached, {} allocated",
+                counts.reached, counts.allocated
+            );
+        }
+        for (bytenr, level) in &tree_census.holes {
+            match level {
+                Some(level) => println!("    hole: block {bytenr} level {level}"),
+                None => println!("    hole: block {bytenr}"),
+            }
+        }
+    }
+    for (bytenr, tree, reason) in &census.damaged {
+        println!("damaged: block {bytenr} in {}: {reason}", fmt_treeid(*tree));
+    }
+    for (bytenr, reason) in &census.mismatches {
+        println!("extent tree mismatch: block {bytenr}: {reason}");
+    }
+    let holes: usize = census.trees.values().map(|t| t.holes.len()).sum();
+    println!(
+        "census: {} blocks, {} damaged, {holes} holes, {} extent tree mismatches",
+        census.blocks.len(),
+        census.damaged.len(),
+        census.mismatches.len()
+    );
+    Ok(())
+}
+
+/// list the tree blocks in metadata chunks which verify but are no longer
+/// reachable
+pub fn dump_orphans(fs: &FsInfo) {
+    let scan = find_orphans(fs);
+    let mut per_owner = BTreeMap::<u64, u64>::new();
+    for orphan in &scan.orphans {
+        *per_owner.entry(orphan.owner).or_default() += 1;
+        println!(
+            "block {} owner {} level {} generation {} on devid {} at {}{}",
+            orphan.logical,
+            fmt_treeid(orphan.owner),
+            orphan.level,
+            orphan.generation,
+            orphan.devid,
+            orphan.physical,
+            match orphan.first_key {
+                Some(key) => format!(" first key {key:?}"),
+                None => String::from(" empty"),
+            }
+        );
+    }
+    for error in &scan.errors {
+        println!("not scanned: {error}");
+    }
+    for (owner, count) in &per_owner {
+        println!("{}: {count} orphans", fmt_treeid(*owner));
+    }
+    println!(
+        "orphans: {} of {} blocks scanned",
+        scan.orphans.len(),
+        scan.scanned
+    );
+}
+
+/// list the files found to have been deleted recently, most recoverable
+/// first, with the state of each of their extents
+pub fn dump_undelete(fs: &FsInfo) -> Result<()> {
+    let scan = find_deleted(fs)?;
+    for file in &scan.files {
+        println!(
+            "{} inode {}: {:.0}% recoverable, {}{}{}",
+            fmt_treeid(file.tree),
+            file.inode,
+            file.confidence * 100.0,
+            match file.source {
+                DeletedSource::OrphanItem => String::from("orphan item"),
+                DeletedSource::OldLeaves(generation) =>
+                    format!("old leaves of generation {generation}"),
+            },
+            match file.size {
+                Some(size) => format!(", size {}", fmt_size(size)),
+                None => String::from(", no inode item"),
+            },
+            match file.transid {
+                Some(transid) => format!(", last changed in generation {transid}"),
+                None => String::new(),
+            }
+        );
+        for path in &file.paths {
+            println!("  {path}");
+        }
+        for extent in &file.extents {
+            match extent.state {
+                ExtentState::Inline => {
+                    println!(
+                        "  offset {}: {} inline",
+                        extent.file_offset,
+                        fmt_size(extent.num_bytes)
+                    )
+                }
+                state => println!(
+                    "  offset {}: {} in extent {} length {}: {state:?}",
+                    extent.file_offset,
+                    fmt_size(extent.num_bytes),
+                    extent.disk_bytenr,
+                    extent.disk_num_bytes
+                ),
+            }
+        }
+    }
+    for error in &scan.errors {
+        println!("not scanned: {error}");
+    }
+    println!(
+        "undelete: {} deleted files, from {} orphan items and {} old fs tree leaves",
+        scan.files.len(),
+        scan.orphan_items,
+        scan.old_leaves
+    );
+    Ok(())
+}
+
+/// carve files out of the data chunks and list them
+pub fn dump_carve(fs: &FsInfo, options: &CarveOptions) -> Result<()> {
+    let scan = carve(fs, options)?;
+    for file in &scan.files {
+        println!(
+            "{}: {} at {} (devid {} at {}), {}",
+            file.path.display(),
+            fmt_size(file.length),
+            file.logical,
+            file.devid,
+            file.physical,
+            match file.method {
+                CarveMethod::Checksums => String::from("checksums match"),
+                CarveMethod::Signature(extension) => format!("{extension} signature"),
+            }
+        );
+    }
+    for error in &scan.errors {
+        println!("not scanned: {error}");
+    }
+    println!(
+        "carve: {} files, {} of {} sectors with checksums matched",
+        scan.files.len(),
+        scan.sectors_verified,
+        scan.sectors_with_csums
+    );
+    Ok(())
+}
+
+/// restore files from a subvolume, then list those damaged with the ranges
+/// lost and what was skipped
+pub fn dump_restore(fs: &FsInfo, subvol: u64, path: &str, options: &RestoreOptions) -> Result<()> {
+    let restore = restore(fs, subvol, path, options)?;
+    for file in &restore.damaged {
+        let lost: Vec<_> = file
+            .lost
+            .iter()
+            .map(|(start, end)| format!("{start}-{end}"))
+            .collect();
+        println!(
+            "{}: lost {} ({})",
+            file.path.display(),
+            lost.join(", "),
+            file.reasons.join("; ")
+        );
+    }
+    for skipped in &restore.skipped {
+        println!("skipped {skipped}");
+    }
+    for (path, why) in &restore.verity_stripped {
+        println!("{}: restored without fs-verity ({why})", path.display());
+    }
+    println!(
+        "restore: {} files ({}), {} directories, {} symlinks; {} damaged, {} skipped",
+        restore.files,
+        fmt_size(restore.bytes),
+        restore.directories,
+        restore.symlinks,
+        restore.damaged.len(),
+        restore.skipped.len()
+    );
+    Ok(())
+}
+
+/// write a file's contents to stdout, and to stderr the ranges lost. A
+/// reader which stops early, as head does, isn't an error.
+pub fn dump_cat(fs: &FsInfo, subvol: u64, path: &str, salvage: bool) -> Result<()> {
+    let out = std::io::BufWriter::new(std::io::stdout().lock());
+    let damage = match cat(fs, subvol, path, out, salvage) {
+        Result::Ok(damage) => damage,
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe) =>
+        {
+            return Ok(())
+        }
+        Err(e) => return Err(e),
+    };
+    for (start, end) in &damage.lost {
+        eprintln!("{path}: lost {start}-{end}");
+    }
+    for reason in &damage.reasons {
+        eprintln!("{path}: {reason}");
+    }
+    Ok(())
+}
+
+/// verify the parity of every used RAID5/6 full stripe and list the
+/// inconsistent ones
+pub fn dump_scrub_parity(fs: &FsInfo) -> Result<()> {
+    let scrub = scrub_parity(fs)?;
+    for check in &scrub.inconsistent {
+        println!(
+            "full stripe {}: P differs in {} bytes{}, {}",
+            check.logical,
+            check.p_mismatch,
+            match check.q_mismatch {
+                Some(q) => format!(", Q in {q} bytes"),
+                None => String::new(),
+            },
+            match check.suspect {
+                Some((role, devid)) => format!("{role} on devid {devid} is probably wrong"),
+                None => String::from("the wrong strip can't be told"),
+            }
+        );
+    }
+    for error in &scrub.errors {
+        println!("not checked: {error}");
+    }
+    println!(
+        "parity scrub: {} full stripes, {} inconsistent, {} not checked",
+        scrub.full_stripes,
+        scrub.inconsistent.len(),
+        scrub.errors.len()
+    );
+    Ok(())
+}
+
+/// regenerate the damaged csum tree leaves covering data between start and
+/// end, and print what was (or would be) overwritten
+pub fn dump_rebuild_csums(
+    fs: &FsInfo,
+    start: u64,
+    end: u64,
+    options: &RepairOptions,
+) -> Result<()> {
+    let rebuild = rebuild_csums(fs, start, end, options)?;
+    for leaf in &rebuild.leaves {
+        println!(
+            "csum leaf {} for data {}..{}: {} items, {} sectors",
+            leaf.leaf,
+            leaf.start,
+            if leaf.end == u64::MAX {
+                String::from("max")
+            } else {
+                leaf.end.to_string()
+            },
+            leaf.items,
+            leaf.sectors
+        );
+        for r in &leaf.copies {
+            println!(
+                "    {} devid {} physical {}{}",
+                if options.dry_run {
+                    "would overwrite"
+                } else {
+                    "overwrote"
+                },
+                r.devid,
+                r.physical,
+                match &r.backup {
+                    Some(path) => format!(", old copy saved to {}", path.display()),
+                    None => String::new(),
+                }
+            );
+        }
+    }
+    for (logical, reason) in &rebuild.failures {
+        println!("cannot rebuild {logical}: {reason}");
+    }
+    println!(
+        "{} csum leaves rebuilt, {} not rebuildable",
+        rebuild.leaves.len(),
+        rebuild.failures.len()
+    );
+    Ok(())
+}
+
+fn print_item_edit(edit: &ItemEdit, done: &str, would: &str, options: &RepairOptions) {
+    println!(
+        "{} item {} of leaf {}",
+        if options.dry_run { would } else { done },
+        edit.slot,
+        edit.leaf
+    );
+    for node in &edit.fixed_parents {
+        println!("    key pointer updated in node {node}");
+    }
+    for r in &edit.copies {
+        println!(
+            "    {} {} devid {} physical {}{}",
+            if options.dry_run {
+                "would write"
+            } else {
+                "wrote"
+            },
+            r.logical,
+            r.devid,
+            r.physical,
+            match &r.backup {
+                Some(path) => format!(", old contents saved to {}", path.display()),
+                None => String::new(),
+            }
+        );
+    }
+}
+
+/// delete an item from a tree, and print what was (or would be) written
+pub fn dump_delete_item(
+    fs: &FsInfo,
+    tree: u64,
+    key: &btrfs_disk_key,
+    options: &RepairOptions,
+) -> Result<()> {
+    let edit = delete_item(fs, tree, key, options)?;
+    print_item_edit(&edit, "deleted", "would delete", options);
+    Ok(())
+}
+
+/// add an item to a tree, or with replace rewrite an existing item's data,
+/// and print what was (or would be) written
+pub fn dump_insert_item(
+    fs: &FsInfo,
+    tree: u64,
+    key: &btrfs_disk_key,
+    data: &[u8],
+    replace: bool,
+    options: &RepairOptions,
+) -> Result<()> {
+    if replace {
+        let edit = replace_item_data(fs, tree, key, data, options)?;
+        print_item_edit(&edit, "replaced", "would replace", options);
+    } else {
+        let edit = insert_item(fs, tree, key, data, options)?;
+        print_item_edit(&edit, "inserted", "would insert", options);
+    }
+    Ok(())
+}
+
+/// edit the superblock fields on every device, and print the copies which
+/// were (or would be) written
+pub fn dump_edit_super(
+    fs: &FsInfo,
+    edits: &[(String, String)],
+    options: &RepairOptions,
+) -> Result<()> {
+    print_super_copies(&edit_super(fs, edits, options)?, options);
+    Ok(())
+}
+
+/// every superblock copy of every device, and how it compares with the
+/// newest on its device; with resync, the copies which differ are rewritten
+pub fn dump_super_mirrors(fs: &FsInfo, resync: Option<&RepairOptions>) -> Result<()> {
+    let mirrors = check_super_mirrors(fs);
+    let mut bad = 0;
+    for mirror in &mirrors {
+        let generation = match mirror.generation {
+            Some(generation) => format!("generation {generation}"),
+            None => String::from("-"),
+        };
+        let state = match &mirror.state {
+            MirrorState::Newest => String::from("ok"),
+            MirrorState::Stale(newest) => format!("STALE, the newest copy is at {newest}"),
+            MirrorState::Differs => String::from("DIFFERS from the newest copy"),
+            MirrorState::Invalid(e) => format!("INVALID: {e}"),
+        };
+        if !matches!(mirror.state, MirrorState::Newest) {
+            bad += 1;
+        }
+        println!(
+            "devid {} superblock at {}: {generation}, {state}",
+            mirror.devid, mirror.physical
+        );
+    }
+    println!(
+        "{} copies, {bad} stale, differing or invalid",
+        mirrors.len()
+    );
+    if let Some(options) = resync {
+        print_super_copies(&resync_super_mirrors(fs, options)?, options);
+    }
+    Ok(())
+}
+
+fn print_super_copies(copies: &[SuperCopy], options: &RepairOptions) {
+    for copy in copies {
+        println!(
+            "{} superblock devid {} physical {}{}",
+            if options.dry_run {
+                "would write"
+            } else {
+                "wrote"
+            },
+            copy.devid,
+            copy.physical,
+            match &copy.backup {
+                Some(path) => format!(", old copy saved to {}", path.display()),
+                None => String::new(),
+            }
+        );
+    }
+}
+
+/// print the feature flags, after setting and clearing any named
+pub fn dump_features(
+    fs: &FsInfo,
+    set: &[String],
+    clear: &[String],
+    options: &RepairOptions,
+) -> Result<()> {
+    let change = change_features(fs, set, clear, options)?;
+    print_super_copies(&change.supers, options);
+    let compat = fs.master_sb.compat_flags;
+    println!("compat flags: {compat:#x}");
+    for (name, names, (old, new)) in [
+        ("compat_ro", COMPAT_RO_FEATURES, change.compat_ro),
+        ("incompat", INCOMPAT_FEATURES, change.incompat),
+    ] {
+        if old == new {
+            println!("{name} flags: {new:#x} ({})", fmt_flags(new, names));
+        } else {
+            println!(
+                "{name} flags: {old:#x} ({}) -> {new:#x} ({})",
+                fmt_flags(old, names),
+                fmt_flags(new, names)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// change the fsid, and print what was (or would be) written
+pub fn dump_change_fsid(
+    fs: &FsInfo,
+    new_fsid: BtrfsFsid,
+    metadata_uuid: bool,
+    options: &RepairOptions,
+) -> Result<()> {
+    let change = change_fsid(fs, new_fsid, metadata_uuid, options)?;
+    let verb = if options.dry_run {
+        "would write"
+    } else {
+        "wrote"
+    };
+    print_super_copies(&change.supers, options);
+    println!(
+        "{verb} {} copies of {} tree blocks, with {} dev items",
+        change.copies.len(),
+        change.tree_blocks,
+        change.dev_items
+    );
+    println!("new fsid {new_fsid}");
+    Ok(())
+}
+
+/// record a lost device as removed, and print what was (or would be)
+/// rewritten
+pub fn dump_remove_device(fs: &FsInfo, devid: u64, options: &RepairOptions) -> Result<()> {
+    let removal = remove_missing_device(fs, devid, options)?;
+    for chunk in &removal.chunks {
+        println!(
+            "chunk {} {} -> {}",
+            chunk.logical,
+            fmt_block_group_type(chunk.old_type),
+            fmt_block_group_type(chunk.new_type)
+        );
+    }
+    println!(
+        "dev item and {} dev extents of device {devid} dropped, {} tree blocks rewritten and {} left empty freed",
+        removal.dev_extents, removal.tree_blocks, removal.freed_blocks
+    );
+    for r in &removal.copies {
+        println!(
+            "    {} {} devid {} physical {}{}",
+            if options.dry_run {
+                "would write"
+            } else {
+                "wrote"
+            },
+            r.logical,
+            r.devid,
+            r.physical,
+            match &r.backup {
+                Some(path) => format!(", old contents saved to {}", path.display()),
+                None => String::new(),
+            }
+        );
+    }
+    print_super_copies(&removal.supers, options);
+    if !removal.chunks.is_empty() {
+        println!("once a device is added, a balance converting the chunks back restores their redundancy");
+    }
+    Ok(())
+}
+
+/// report the device replace recorded in the dev tree, and what an
+/// unfinished one means for the devices given
+pub fn dump_dev_replace(fs: &FsInfo) -> Result<()> {
+    let Some(status) = dev_replace_status(fs)? else {
+        println!("no device replace recorded");
+        return Ok(());
+    };
+    let item = &status.item;
+    let src_devid = item.src_devid;
+    let cursor_left = item.cursor_left;
+    println!(
+        "source devid {src_devid} ({}), target devid {BTRFS_DEV_REPLACE_DEVID} ({})",
+        if status.src_present {
+            "given"
+        } else {
+            "not given"
+        },
+        if status.target_present {
+            "given"
+        } else {
+            "not given"
+        }
+    );
+    for line in describe_dev_replace(item) {
+        println!("    {line}");
+    }
+    match status.src_total_bytes {
+        Some(total) if total > 0 => println!(
+            "copied {} of {} ({:.1}%)",
+            fmt_size(cursor_left),
+            fmt_size(total),
+            cursor_left.min(total) as f64 * 100.0 / total as f64
+        ),
+        _ => println!("copied {} of the source", fmt_size(cursor_left)),
+    }
+    if status.in_progress() {
+        println!(
+            "the replace is unfinished: the chunks still name devid {src_devid}, and the target only holds copies of its dev extents below physical {cursor_left}"
+        );
+        println!("the kernel resumes it at the next read-write mount, unless it is cancelled");
+        if !status.src_present {
+            println!("the source device is not given, so what lies above the cursor can't be read from it");
+        }
+    }
+    Ok(())
+}
+
+/// report an unfinished balance and the relocation trees it left behind
+pub fn dump_balance(fs: &FsInfo) -> Result<()> {
+    let status = balance_status(fs)?;
+    match &status.balance {
+        Some(balance) => {
+            println!("balance in progress");
+            for line in describe_balance(balance) {
+                println!("    {line}");
+            }
+            println!("the kernel resumes it at the next read-write mount, unless mounted with skip_balance");
+        }
+        None => println!("no balance in progress"),
+    }
+    let mut interrupted = false;
+    for tree in &status.reloc_trees {
+        let name = match tree.objectid {
+            BTRFS_TREE_RELOC_OBJECTID => {
+                interrupted = true;
+                format!("TREE_RELOC for subvolume {}", tree.offset)
+            }
+            _ => {
+                interrupted |= tree.file_extents > 0;
+                fmt_treeid(tree.objectid)
+            }
+        };
+        println!(
+            "{name}: root {} level {} generation {}, {} items, {} file extents",
+            tree.bytenr, tree.level, tree.generation, tree.items, tree.file_extents
+        );
+    }
+    if interrupted {
+        println!("a relocation was interrupted: the kernel finishes or drops it when the filesystem is next mounted read-write");
+    }
+    Ok(())
+}
+
+/// report what replaying the log tree would change, and what would make it
+/// fail, to decide between mounting and zeroing the log
+pub fn dump_log_replay(fs: &FsInfo) -> Result<()> {
+    let replay = simulate_replay(fs)?;
+    let Some(log_root) = replay.log_root else {
+        println!("no log tree: nothing to replay");
+        return Ok(());
+    };
+    println!(
+        "log root tree at {log_root}, {} blocks, logs of {} subvolumes",
+        replay.blocks,
+        replay.subvols.len()
+    );
+    for log in &replay.subvols {
+        println!(
+            "{}: log tree at {}, {} blocks",
+            fmt_treeid(log.subvol),
+            log.log_root,
+            log.blocks
+        );
+        for logged in &log.inodes {
+            let what = match (&logged.before, &logged.after) {
+                (None, Some(_)) => String::from("created"),
+                (Some(before), Some(after)) => {
+                    let mut changes = Vec::new();
+                    let (size_before, size_after) = (before.size, after.size);
+                    if size_before != size_after {
+                        changes.push(format!(
+                            "size {} -> {}",
+                            fmt_size(size_before),
+                            fmt_size(size_after)
+                        ));
+                    }
+                    let (mode_before, mode_after) = (before.mode, after.mode);
+                    if mode_before != mode_after {
+                        changes.push(format!("mode {mode_before:o} -> {mode_after:o}"));
+                    }
+                    let (nlink_before, nlink_after) = (before.nlink, after.nlink);
+                    if nlink_before != nlink_after {
+                        changes.push(format!("nlink {nlink_before} -> {nlink_after}"));
+                    }
+                    match changes.is_empty() {
+                        true => String::from("inode item updated"),
+                        false => format!("updated, {}", changes.join(", ")),
+                    }
+                }
+                (_, None) => String::from("inode item not logged"),
+            };
+            println!(
+                "  inode {}{}: {what}",
+                logged.inode,
+                match logged.paths.first() {
+                    Some(path) if path.is_empty() => String::from(" /"),
+                    Some(path) => format!(" {path}"),
+                    None => String::new(),
+                }
+            );
+            for (change, parent, name) in &logged.names {
+                println!(
+                    "    name {:?} in directory {parent} {}",
+                    String::from_utf8_lossy(name),
+                    if *change == Change::Added {
+                        "added"
+                    } else {
+                        "removed"
+                    }
+                );
+            }
+            for (change, index, name, location) in &logged.entries {
+                let target = match location.item_type {
+                    BtrfsItemType::ROOT_ITEM => format!("subvolume {}", { location.objectid }),
+                    _ => format!("inode {}", { location.objectid }),
+                };
+                println!(
+                    "    entry {index} {:?} -> {target} {}",
+                    String::from_utf8_lossy(name),
+                    if *change == Change::Added {
+                        "added"
+                    } else {
+                        "removed"
+                    }
+                );
+            }
+            for (start, end) in &logged.ranges {
+                println!("    file range {start}..{end} replaced");
+            }
+        }
+        for extent in &log.extents {
+            println!(
+                "  extent {} length {}: {}",
+                extent.logical,
+                extent.length,
+                if extent.in_extent_tree {
+                    "in the extent tree, a reference is added"
+                } else {
+                    "not in the extent tree, allocated by replay"
+                }
+            );
+        }
+        if log.csum_bytes > 0 {
+            println!("  checksums of {} of data", fmt_size(log.csum_bytes));
+        }
+    }
+    let allocated: u64 = replay
+        .subvols
+        .iter()
+        .flat_map(|log| &log.extents)
+        .filter(|extent| !extent.in_extent_tree)
+        .map(|extent| extent.length)
+        .sum();
+    println!(
+        "replay pins {} log tree blocks ({}) and allocates {} of data extents",
+        replay.pinned_blocks(),
+        fmt_size(replay.pinned_blocks() * fs.master_sb.nodesize as u64),
+        fmt_size(allocated)
+    );
+    for problem in &replay.problems {
+        println!("problem: {problem}");
+    }
+    if replay.problems.is_empty() {
+        println!("the log looks consistent: mounting replays the changes above, zeroing the log (btrfs rescue zero-log) loses them");
+    } else {
+        println!("replay may fail or do damage: zeroing the log (btrfs rescue zero-log) instead loses the changes above, fsynced since the last commit");
+    }
+    Ok(())
+}
+
+/// the chunk tree's root node and the first block below it
+fn dump_chunk_tree_top(fs: &FsInfo) -> Result<()> {
+    let sb = &fs.master_sb;
+    let ct_header = load_virt::<btrfs_header>(fs, sb.chunk_root)?;
+    check_header(fs, &ct_header, sb.chunk_root)?;
+    //TODO: bother checking csum?
+    let cto = ct_header.owner;
+    //let ct_gen = ct_header.generation;
+    let ct_nri = ct_header.nritems;
+    //let ct_level = ct_header.level;
+    assert_eq!(cto, BTRFS_CHUNK_TREE_OBJECTID);
+    dump_node_header(&ct_header);
+
+    // for levels != 0 we have internal nodes
+    // https://btrfs.wiki.kernel.org/index.php/On-disk_Format#Internal_Node
+
+    //the first level of the tree looks like this. After the header there is  random DEV_ITEM
+    //then a number of chunk_items. not clear what offset refers to.
+    //chunk tree header: uuid ab00c287-f8de-4fe1-b463-61cfc5c6814c, generation: 4756888, nritems: 76, level: 1
+    //object id: 1, node_type: DEV_ITEM, offset: 7, blockptr: 22093116751872, generation: 4756888
+    //object id: 256, node_type: CHUNK_ITEM, offset: 21264188047360, blockptr: 22093116882944, generation: 3409876
+    //...
+    let key_ptr_start: u64 = sb.chunk_root + std::mem::size_of::<btrfs_header>() as u64;
+    for i in 0..ct_nri {
+        let int_node = load_virt::<btrfs_key_ptr>(
+            fs,
+            key_ptr_start + i as u64 * std::mem::size_of::<btrfs_key_ptr>() as u64,
+        )?;
+        let oid = int_node.key.objectid;
+        let node_type = int_node.key.item_type;
+        let offset = int_node.key.offset;
+        let blockptr = int_node.blockptr;
+        let generation = int_node.generation;
+        println!(
+            "object id: {}, node_type: {:?}, offset: {}, blockptr: {}, generation: {}",
+            oid, node_type, offset, blockptr, generation
+        );
+    }
+
+    //let's look at one chunk item.
+    let block_ptr = load_virt::<btrfs_key_ptr>(
+        fs,
+        key_ptr_start + std::mem::size_of::<btrfs_key_ptr>() as u64,
+    )?
+    .blockptr;
+    let node = load_virt::<btrfs_header>(fs, block_ptr)?;
+    dump_node_header(&node);
+    let node_items_start = block_ptr + std::mem::size_of::<btrfs_header>() as u64;
+    for i in 0..node.nritems {
+        let leaf_node = load_virt::<btrfs_item>(
+            fs,
+            node_items_start + i as u64 * std::mem::size_of::<btrfs_item>() as u64,
+        )?;
+        let oid = leaf_node.key.objectid;
+        let node_type = leaf_node.key.item_type;
+        let offset = leaf_node.key.offset;
+        let int_offset = leaf_node.offset;
+        let size = leaf_node.size;
+
+        println!(
+            "object id: {}, node_type: {:?}, offset: {}, itemoffset: {}, size: {}",
+            oid, node_type, offset, int_offset, size
+        );
+    }
+    Ok(())
+}
+
+/// every chunk's mapping in the file format `--chunk-map` reads, printed or
+/// written to a file
+pub fn dump_chunk_map(fs: &FsInfo, output: Option<&Path>) -> Result<()> {
+    let chunks = all_chunks(fs);
+    let text = format!("# chunk map of {}\n{}", fs.fsid, format_chunk_map(&chunks));
+    match output {
+        Some(path) => {
+            std::fs::write(path, text)
+                .with_context(|| format!("cannot write {}", path.display()))?;
+            println!("wrote {} chunks to {}", chunks.len(), path.display());
+        }
+        None => print!("{text}"),
+    }
+    Ok(())
+}
+
+/// render where the chunks of each device are and how used they are, to
+/// output or stdout
+pub fn dump_heatmap(
+    fs: &FsInfo,
+    format: HeatmapFormat,
+    width: usize,
+    output: Option<&Path>,
+) -> Result<()> {
+    let layouts = device_layouts(fs);
+    let text = format_heatmap(&layouts, format, width);
+    match output {
+        Some(path) => {
+            std::fs::write(path, text)
+                .with_context(|| format!("cannot write {}", path.display()))?;
+            println!(
+                "wrote the layout of {} devices to {}",
+                layouts.len(),
+                path.display()
+            );
+        }
+        None => print!("{text}"),
+    }
+    Ok(())
+}
+
+/// list every stripe extent of the raid stripe tree with its strides, and
+/// what is wrong with it
+pub fn dump_raid_stripe_tree(fs: &FsInfo) -> Result<()> {
+    let extents = stripe_extents(fs)?;
+    let mut bad = 0;
+    for extent in &extents {
+        let strides: Vec<String> = extent
+            .strides
+            .iter()
+            .map(|(devid, physical)| format!("devid {devid} physical {physical}"))
+            .collect();
+        println!(
+            "logical {} length {}: {}",
+            extent.logical,
+            fmt_size(extent.length),
+            strides.join(", ")
+        );
+        let problems = check_stripe_extent(fs, extent);
+        if !problems.is_empty() {
+            bad += 1;
+        }
+        for problem in problems {
+            println!("    {problem}");
+        }
+    }
+    println!("{} stripe extents, {bad} with problems", extents.len());
+    Ok(())
+}
+
+/// list the partitions of disk images, and the device argument for each
+/// holding a btrfs filesystem
+pub fn dump_partitions(paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        let partitions = read_partitions(path)?;
+        if partitions.is_empty() {
+            println!("{}: no partition table", path.display());
+            continue;
+        }
+        println!("{}:", path.display());
+        let btrfs = btrfs_partitions(path)?;
+        for partition in &partitions {
+            println!(
+                "    partition {} start {} length {} {}",
+                partition.number,
+                partition.start,
+                fmt_size(partition.length),
+                partition.description
+            );
+            if let Some((_, sb)) = btrfs.iter().find(|(p, _)| p == partition) {
+                println!(
+                    "        btrfs fsid {} label {:?} devid {}: {}@{}",
+                    sb.fsid,
+                    sb.label_str(),
+                    { sb.dev_item.devid },
+                    path.display(),
+                    partition.start
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// list the superblocks found anywhere in files, and where the devices they
+/// belong to start
+pub fn dump_superblock_scan(
+    paths: &[PathBuf],
+    align: u64,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    ensure!(align > 0, "the alignment must be at least 1 byte");
+    for path in paths {
+        let mf = MappedFile::open(path)?;
+        let hits = scan_superblocks(&mf, align, cancel)?;
+        println!("{}: {} superblocks", path.display(), hits.len());
+        for hit in &hits {
+            let sb = &hit.sb;
+            let start = match hit.device_start() {
+                Some(start) => format!(", device at {start}: {}@{start}", path.display()),
+                None => String::new(),
+            };
+            println!(
+                "    offset {} generation {} fsid {} devid {} bytenr {}{}{start}",
+                hit.offset,
+                { sb.generation },
+                sb.fsid,
+                { sb.dev_item.devid },
+                { sb.bytenr },
+                if hit.verified {
+                    String::new()
+                } else {
+                    format!(", {:?} checksum not verified", { sb.csum_type })
+                }
+            );
+        }
+    }
+    Ok(())
+}
+
+/// write a new extent tree, and print what was (or would be) written
+pub fn dump_rebuild_extent_tree(fs: &FsInfo, options: &RepairOptions) -> Result<()> {
+    let rebuild = rebuild_extent_tree(fs, options)?;
+    println!(
+        "{} tree blocks, {} data extents and {} block groups in use: {} items",
+        rebuild.tree_blocks, rebuild.data_extents, rebuild.block_groups, rebuild.items
+    );
+    println!(
+        "new extent tree of {} blocks, root {} level {}",
+        rebuild.new_blocks, rebuild.root, rebuild.level
+    );
+    for r in &rebuild.copies {
+        println!(
+            "    {} {} devid {} physical {}{}",
+            if options.dry_run {
+                "would write"
+            } else {
+                "wrote"
+            },
+            r.logical,
+            r.devid,
+            r.physical,
+            match &r.backup {
+                Some(path) => format!(", old contents saved to {}", path.display()),
+                None => String::new(),
+            }
+        );
+    }
+    if !options.dry_run {
+        println!(
+            "the free space cache doesn't know these blocks are used: clear it before mounting read-write, with `btrfs check --clear-space-cache v1` (or v2 for the free space tree) or `mount -o clear_cache`"
+        );
+    }
+    Ok(())
+}
+
+/// regenerate the strip on devid of the full stripe containing logical, and
+/// print what was (or would be) overwritten
+pub fn dump_rebuild_strip(
+    fs: &FsInfo,
+    logical: u64,
+    devid: u64,
+    options: &RepairOptions,
+) -> Result<()> {
+    match rebuild_strip(fs, logical, devid, options)? {
+        None => println!("the strip on devid {devid} is already consistent, nothing written"),
+        Some((role, r)) => println!(
+            "{} {role}{} on devid {} physical {}, {} bytes{}",
+            if options.dry_run {
+                "would overwrite"
+            } else {
+                "overwrote"
+            },
+            match role {
+                StripRole::Data(_) => String::new(),
+                StripRole::P | StripRole::Q => format!(" of full stripe {}", r.logical),
+            },
+            r.devid,
+            r.physical,
+            fmt_size(r.length),
+            match &r.backup {
+                Some(path) => format!(", old strip saved to {}", path.display()),
+                None => String::new(),
+            }
+        ),
+    }
+    Ok(())
+}
+
+/// print every item in a tree with its decoded contents
+pub fn dump_tree_items(fs: &FsInfo, root: LE64, search: NodeSearchOption) {
+    for (item, data, block_offset, slot) in search_range(fs, root, search) {
+        let key = item.key;
+        let size = item.size;
+        println!("{block_offset}#{slot} {key:?} size {size}");
+        for line in describe_item(&key, &data) {
+            println!("    {line}");
+        }
+    }
+}
+
+pub fn dump_root_tree(fs: &FsInfo) -> Result<()> {
+    let root = fs.master_sb.root;
+    let node_header = load_virt::<btrfs_header>(fs, root)?;
+    check_header(fs, &node_header, root)?;
+    dump_node_header(&node_header);
+    //TODO: dump nodes
+    let search = NodeSearchOption {
+        min_key: btrfs_disk_key {
+            objectid: 0,
+            item_type: BtrfsItemType::MIN,
+            offset: 0,
+        },
+        max_key: btrfs_disk_key {
+            objectid: u64::MAX,
+            item_type: BtrfsItemType::MAX,
+            offset: u64::MAX,
+        },
+        min_match: std::cmp::Ordering::Less,
+        max_match: std::cmp::Ordering::Greater,
+    };
+    for (leaf, data, _block_offset, leaf_pos) in BtrfsTreeIter::new(fs, root, search) {
+        let btrfs_disk_key {
+            objectid,
+            item_type,
+            offset,
+        } = leaf.key;
+        let size = leaf.size;
+
+        match item_type {
+            BtrfsItemType::ROOT_ITEM => {
+                assert_eq!(size as usize, std::mem::size_of::<btrfs_root_item>());
+                let root_item = unsafe { &*((data.as_ptr()) as *const btrfs_root_item) };
+                let tree_root = root_item.bytenr;
+                println!(
+                    "leaf #{leaf_pos} {} {item_type:?} {offset} data size {} tree root {tree_root}",
+                    fmt_treeid(objectid),
+                    size
+                );
+            }
+            BtrfsItemType::ROOT_REF => {
+                assert_ge!(size as usize, std::mem::size_of::<btrfs_root_ref>());
+
+                let root_ref = unsafe { &*((data.as_ptr()) as *const btrfs_root_ref) };
+                let name_len = root_ref.name_len;
+                let dirid = root_ref.dirid;
+                assert_eq!(
+                    size as usize,
+                    name_len as usize + std::mem::size_of::<btrfs_root_ref>()
+                );
+                println!(
+                    "root ref {} {item_type:?} {offset} dirid {dirid} name: {}",
+                    fmt_treeid(objectid),
+                    std::str::from_utf8(&data[std::mem::size_of::<btrfs_root_ref>()..])?
+                );
+            }
+            BtrfsItemType::TEMPORARY_ITEM | BtrfsItemType::PERSISTENT_ITEM => {
+                println!(
+                    "leaf #{leaf_pos} {} {item_type:?} {offset} data size {size}",
+                    fmt_treeid(objectid)
+                );
+                for line in describe_item(&leaf.key, &data) {
+                    println!("    {line}");
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub fn dump_fs(fs: &FsInfo) -> Result<()> {
+    let sb = fs.master_sb;
+    dump_sb(&sb);
+
+    //dump_chunks(&sb);
+
+    let stats = device_stats(fs)?;
+    let fmt_errors = |devid: &u64| match stats.get(devid) {
+        Some(values) if values.iter().any(|&v| v > 0) => {
+            format!(", ERRORS {}", fmt_dev_stats(values))
+        }
+        Some(_) => String::from(", no errors"),
+        None => String::new(),
+    };
+    for (devid, di) in fs.devid_map.iter() {
+        let seed = if di.seed { " (seed)" } else { "" };
+        let stale = match di.generations_behind {
+            0 => String::new(),
+            behind => format!(", STALE: {behind} generations behind"),
+        };
+        let offset = match di.offset {
+            0 => String::new(),
+            offset => format!("@{offset}"),
+        };
+        let zoned = match di.zone_size {
+            Some(zone_size) => format!(", zoned in {} zones", fmt_size(zone_size)),
+            None => String::new(),
+        };
+        println!(
+            "devid {} is {}{offset}{seed}{zoned}{stale}{}",
+            devid,
+            di.path.display(),
+            fmt_errors(devid)
+        );
+    }
+    for devid in stats.keys().filter(|d| !fs.devid_map.contains_key(d)) {
+        println!("devid {devid} not given{}", fmt_errors(devid));
+    }
+    let num_devices = sb.num_devices;
+    println!("{}/{} devices present", fs.devid_map.len(), num_devices);
+
+    // There are two things we need to be able to do with these trees,
+    // iterate through an entire tree (perhaps until a condition is met),
+    // and identify a specific key (or part of a key) in a tree.
+    if fs.chunk_map {
+        println!("chunk tree not read, the chunks come from the chunk map");
+    } else {
+        dump_chunk_tree_top(fs)?;
+    }
+
+    println!("root tree");
+    dump_root_tree(fs)?;
+
+    let extent_roots = global_roots(fs, BTRFS_EXTENT_TREE_OBJECTID);
+    ensure!(!extent_roots.is_empty(), "extent tree not found");
+    for (global_id, extent_tree_root) in extent_roots {
+        if extent_tree_v2(fs) {
+            println!("root of extent tree global root {global_id}: {extent_tree_root}");
+        } else {
+            println!("root of extent tree: {}", extent_tree_root);
+        }
+        dump_tree(fs, extent_tree_root)?;
+    }
 
     //TODO: do we need log tree?
     //TODO: build root tree
@@ -0,0 +1,1073 @@
+//! Item level edits of tree leaves in place, for repairs which come down to
+//! dropping, adding or rewriting a single item, and edits of superblock
+//! fields.
+//!
+//! Leaves are edited as the kernel would, without COW: the block keeps its
+//! bytenr and generation, so its parents' pointers stay valid, and every
+//! copy is written through a repair::RepairTransaction with a backup first,
+//! along with any parents whose key pointer changes. Superblock edits are
+//! applied to each device's own superblock and written to every mirror,
+//! again as a transaction.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::dump::fmt_treeid;
+use crate::parse::parse_u64;
+use crate::repair::*;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::cmp::Ordering;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+fn tree_root(fs: &FsInfo, tree: u64) -> Result<u64> {
+    tree_root_offset(fs, tree).ok_or_else(|| anyhow!("tree {} not found", fmt_treeid(tree)))
+}
+
+/// the path to the item with exactly key in tree
+fn find_item(fs: &FsInfo, tree: u64, key: &btrfs_disk_key) -> Result<TreePath> {
+    let path = search_path(fs, tree_root(fs, tree)?, key)?;
+    let (objectid, item_type, offset) = (key.objectid, key.item_type, key.offset);
+    ensure!(
+        path.found,
+        "no item {objectid} {item_type:?} {offset} in {}",
+        fmt_treeid(tree)
+    );
+    Ok(path)
+}
+
+/// the items of a leaf, and the start of its data area
+fn leaf_items(leaf: &[u8]) -> (Vec<btrfs_item>, usize) {
+    let items: Vec<btrfs_item> = node_entries(leaf)
+        .iter()
+        .filter_map(|entry| match entry {
+            NodeEntry::Item(item, _) => Some(**item),
+            NodeEntry::Ptr(_) => None,
+        })
+        .collect();
+    let data_size = leaf.len() - std::mem::size_of::<btrfs_header>();
+    // where the last item's data starts
+    let data_end = items
+        .iter()
+        .map(|i| i.offset as usize)
+        .min()
+        .unwrap_or(data_size);
+    (items, data_end)
+}
+
+/// bytes free between a leaf's item array and its data
+fn leaf_free_space(leaf: &[u8]) -> usize {
+    let (items, data_end) = leaf_items(leaf);
+    data_end.saturating_sub(items.len() * std::mem::size_of::<btrfs_item>())
+}
+
+/// add an item at slot, as the kernel's setup_items_for_insert does: the
+/// data of the items from slot on moves down to make room below the data
+/// of the item before it, and the item array shifts up. The caller checks
+/// there is space.
+fn insert_slot(leaf: &mut [u8], slot: usize, key: &btrfs_disk_key, data: &[u8]) {
+    let header_size = std::mem::size_of::<btrfs_header>();
+    let item_size = std::mem::size_of::<btrfs_item>();
+    let (items, data_end) = leaf_items(leaf);
+    let nritems = items.len();
+    // the data goes where the previous item's data starts
+    let old_data = match slot {
+        0 => leaf.len() - header_size,
+        _ => items[slot - 1].offset as usize,
+    };
+    let len = data.len();
+    let start = header_size + data_end;
+    leaf.copy_within(start..header_size + old_data, start - len);
+    leaf[header_size + old_data - len..header_size + old_data].copy_from_slice(data);
+    let first = header_size + slot * item_size;
+    let last = header_size + nritems * item_size;
+    leaf.copy_within(first..last, first + item_size);
+    for i in slot + 1..=nritems {
+        let at = header_size + i * item_size;
+        let item = unsafe { &mut *(leaf[at..].as_mut_ptr() as *mut btrfs_item) };
+        if (item.offset as usize) < old_data {
+            item.offset -= len as u32;
+        }
+    }
+    let item = btrfs_item {
+        key: *key,
+        offset: (old_data - len) as u32,
+        size: len as u32,
+    };
+    leaf[first..first + item_size].copy_from_slice(as_bytes(&item));
+    let header = unsafe { &mut *(leaf.as_mut_ptr() as *mut btrfs_header) };
+    header.nritems = (nritems + 1) as u32;
+}
+
+/// remove slot from a leaf, as the kernel's btrfs_del_items does: the data
+/// of the items after it moves up to close the gap, their offsets are
+/// adjusted and the item array shifts down over it
+fn remove_slot(leaf: &mut [u8], slot: usize) {
+    let header_size = std::mem::size_of::<btrfs_header>();
+    let item_size = std::mem::size_of::<btrfs_item>();
+    let (items, data_end) = leaf_items(leaf);
+    let nritems = items.len();
+
+    let deleted = items[slot];
+    let (offset, size) = (deleted.offset as usize, deleted.size as usize);
+    let data_size = leaf.len() - header_size;
+    // data running past the end of the block, as a corrupt item's can, is
+    // left where it is
+    if offset + size <= data_size {
+        let start = header_size + data_end;
+        leaf.copy_within(start..header_size + offset, start + size);
+        leaf[start..start + size].fill(0);
+        for (i, item) in items.iter().enumerate() {
+            if i != slot && (item.offset as usize) < offset {
+                let mut moved = *item;
+                moved.offset += size as u32;
+                let at = header_size + i * item_size;
+                leaf[at..at + item_size].copy_from_slice(as_bytes(&moved));
+            }
+        }
+    }
+    let first = header_size + slot * item_size;
+    let last = header_size + nritems * item_size;
+    leaf.copy_within(first + item_size..last, first);
+    leaf[last - item_size..last].fill(0);
+    let header = unsafe { &mut *(leaf.as_mut_ptr() as *mut btrfs_header) };
+    header.nritems = (nritems - 1) as u32;
+}
+
+/// an item deleted, inserted or rewritten (or in a dry run, which would be)
+pub struct ItemEdit {
+    pub leaf: u64,
+    pub slot: usize,
+    /// nodes whose key pointer was changed as the leaf's first key changed
+    pub fixed_parents: Vec<u64>,
+    /// every copy written, the leaf's first
+    pub copies: Vec<CopyRepair>,
+}
+
+/// write an edited leaf, and the nodes above it whose key pointer no
+/// longer matches its first key, as one transaction
+fn write_leaf(
+    fs: &FsInfo,
+    path: &TreePath,
+    leaf: Vec<u8>,
+    options: &RepairOptions,
+) -> Result<ItemEdit> {
+    let first_key = match node_entries(&leaf).first() {
+        Some(NodeEntry::Item(item, _)) => Some(item.key),
+        _ => None,
+    };
+    let mut transaction = RepairTransaction::new(fs);
+    transaction.stage_block(path.leaf, leaf);
+    let mut fixed_parents = Vec::new();
+    let header_size = std::mem::size_of::<btrfs_header>();
+    if let Some(first_key) = first_key {
+        for &PathNode {
+            bytenr: node, slot, ..
+        } in path.nodes.iter().rev()
+        {
+            let mut block = load_virt_block(fs, node)?.to_vec();
+            let at = header_size + slot * std::mem::size_of::<btrfs_key_ptr>();
+            let ptr = unsafe { &mut *(block[at..].as_mut_ptr() as *mut btrfs_key_ptr) };
+            if cmp_key(&ptr.key, &first_key) == Ordering::Equal {
+                break;
+            }
+            ptr.key = first_key;
+            transaction.stage_block(node, block);
+            fixed_parents.push(node);
+            if slot != 0 {
+                break;
+            }
+        }
+    }
+    Ok(ItemEdit {
+        leaf: path.leaf,
+        slot: path.slot,
+        fixed_parents,
+        copies: transaction.commit(options)?.copies,
+    })
+}
+
+/// remove the item with key from tree, writing every copy of its leaf. If it
+/// was the leaf's first item, the key pointers above are updated to the new
+/// first key. A leaf is never left empty, as the kernel would remove it from
+/// its parent.
+pub fn delete_item(
+    fs: &FsInfo,
+    tree: u64,
+    key: &btrfs_disk_key,
+    options: &RepairOptions,
+) -> Result<ItemEdit> {
+    let path = find_item(fs, tree, key)?;
+    let mut leaf = load_virt_block(fs, path.leaf)?.to_vec();
+    ensure!(
+        node_entries(&leaf).len() > 1 || path.nodes.is_empty(),
+        "deleting the only item of leaf {} would leave it empty",
+        path.leaf
+    );
+    remove_slot(&mut leaf, path.slot);
+    write_leaf(fs, &path, leaf, options)
+}
+
+/// add an item with key and data to tree, in the leaf where it sorts. The
+/// leaf must have room for it, as leaves are never split.
+pub fn insert_item(
+    fs: &FsInfo,
+    tree: u64,
+    key: &btrfs_disk_key,
+    data: &[u8],
+    options: &RepairOptions,
+) -> Result<ItemEdit> {
+    let path = search_path(fs, tree_root(fs, tree)?, key)?;
+    let (objectid, item_type, offset) = (key.objectid, key.item_type, key.offset);
+    ensure!(
+        !path.found,
+        "{} already has an item {objectid} {item_type:?} {offset}",
+        fmt_treeid(tree)
+    );
+    let mut leaf = load_virt_block(fs, path.leaf)?.to_vec();
+    let needed = std::mem::size_of::<btrfs_item>() + data.len();
+    let free = leaf_free_space(&leaf);
+    ensure!(
+        needed <= free,
+        "leaf {} has {free} bytes free, {needed} are needed",
+        path.leaf
+    );
+    insert_slot(&mut leaf, path.slot, key, data);
+    write_leaf(fs, &path, leaf, options)
+}
+
+/// replace the data of the item with key in tree, growing or shrinking it
+/// in place. The leaf must have room if it grows.
+pub fn replace_item_data(
+    fs: &FsInfo,
+    tree: u64,
+    key: &btrfs_disk_key,
+    data: &[u8],
+    options: &RepairOptions,
+) -> Result<ItemEdit> {
+    let path = find_item(fs, tree, key)?;
+    let mut leaf = load_virt_block(fs, path.leaf)?.to_vec();
+    let (items, _) = leaf_items(&leaf);
+    let free = leaf_free_space(&leaf) + items[path.slot].size as usize;
+    ensure!(
+        data.len() <= free,
+        "leaf {} has room for {free} bytes of item data, {} are needed",
+        path.leaf,
+        data.len()
+    );
+    remove_slot(&mut leaf, path.slot);
+    insert_slot(&mut leaf, path.slot, key, data);
+    write_leaf(fs, &path, leaf, options)
+}
+
+/// a change to an existing item in a batch given to edit_items: its data
+/// is replaced, or with None it is deleted
+pub(crate) type ItemChange = (btrfs_disk_key, Option<Vec<u8>>);
+
+/// the blocks of a tree rewritten by edit_items, sealed but not yet written
+pub(crate) struct TreeEdit {
+    pub blocks: Vec<(u64, Vec<u8>)>,
+    /// blocks left empty and dropped from their parents, with their level
+    pub freed: Vec<(u64, u8)>,
+}
+
+/// a block edit_items changed, on the level it is working up through
+struct ChangedBlock {
+    logical: u64,
+    /// the nodes above it, as in TreePath
+    nodes: Vec<PathNode>,
+    first_key: Option<btrfs_disk_key>,
+}
+
+fn first_key(block: &[u8]) -> Option<btrfs_disk_key> {
+    match node_entries(block).first()? {
+        NodeEntry::Item(item, _) => Some(item.key),
+        NodeEntry::Ptr(ptr) => Some(ptr.key),
+    }
+}
+
+/// remove the key pointer at slot from a node, shifting the later ones down
+fn remove_ptr(node: &mut [u8], slot: usize) {
+    let header_size = std::mem::size_of::<btrfs_header>();
+    let ptr_size = std::mem::size_of::<btrfs_key_ptr>();
+    let header = unsafe { &mut *(node.as_mut_ptr() as *mut btrfs_header) };
+    let nritems = header.nritems as usize;
+    header.nritems = (nritems - 1) as u32;
+    let first = header_size + slot * ptr_size;
+    let last = header_size + nritems * ptr_size;
+    node.copy_within(first + ptr_size..last, first);
+    node[last - ptr_size..last].fill(0);
+}
+
+/// apply a batch of changes to existing items of tree in memory, so that
+/// each block is rewritten once however many of its items change. A leaf
+/// left empty is dropped from its parent, as the kernel does, and so is a
+/// node left without pointers, but the tree itself can't be emptied.
+pub(crate) fn edit_items(fs: &FsInfo, tree: u64, changes: &[ItemChange]) -> Result<TreeEdit> {
+    let mut paths = Vec::new();
+    for (key, data) in changes {
+        paths.push((find_item(fs, tree, key)?, key, data.as_deref()));
+    }
+    // the last slot of a leaf is changed first, so that the slots found for
+    // the others stay right
+    paths.sort_by_key(|(path, ..)| (path.leaf, std::cmp::Reverse(path.slot)));
+    let mut blocks: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+    let mut changed = Vec::new();
+    let mut last = None;
+    for (path, key, data) in &paths {
+        let (objectid, item_type, offset) = (key.objectid, key.item_type, key.offset);
+        ensure!(
+            last != Some((path.leaf, path.slot)),
+            "item {objectid} {item_type:?} {offset} is changed twice"
+        );
+        last = Some((path.leaf, path.slot));
+        let leaf = match blocks.entry(path.leaf) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let block = load_virt_block(fs, path.leaf)?;
+                changed.push(ChangedBlock {
+                    logical: path.leaf,
+                    nodes: path.nodes.clone(),
+                    first_key: first_key(&block),
+                });
+                entry.insert(block.to_vec())
+            }
+        };
+        remove_slot(leaf, path.slot);
+        if let Some(data) = data {
+            let free = leaf_free_space(leaf);
+            ensure!(
+                std::mem::size_of::<btrfs_item>() + data.len() <= free,
+                "leaf {} has no room for {} bytes of data for item {objectid} {item_type:?} {offset}",
+                path.leaf,
+                data.len()
+            );
+            insert_slot(leaf, path.slot, key, data);
+        }
+    }
+
+    // work up the tree a level at a time, dropping the pointers to emptied
+    // blocks and updating those whose block's first key changed
+    let header_size = std::mem::size_of::<btrfs_header>();
+    let ptr_size = std::mem::size_of::<btrfs_key_ptr>();
+    let mut freed = Vec::new();
+    let mut level = 0;
+    while !changed.is_empty() {
+        let mut parents = BTreeMap::new();
+        let mut dropped: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        for block in changed {
+            let new_first = first_key(&blocks[&block.logical]);
+            let Some((
+                &PathNode {
+                    bytenr: parent,
+                    slot,
+                    ..
+                },
+                above,
+            )) = block.nodes.split_last()
+            else {
+                ensure!(
+                    new_first.is_some() || level == 0,
+                    "the changes would leave {} empty",
+                    fmt_treeid(tree)
+                );
+                continue;
+            };
+            if let Entry::Vacant(entry) = blocks.entry(parent) {
+                let node = load_virt_block(fs, parent)?;
+                parents.insert(
+                    parent,
+                    ChangedBlock {
+                        logical: parent,
+                        nodes: above.to_vec(),
+                        first_key: first_key(&node),
+                    },
+                );
+                entry.insert(node.to_vec());
+            }
+            match new_first {
+                None => {
+                    blocks.remove(&block.logical);
+                    freed.push((block.logical, level));
+                    dropped.entry(parent).or_default().push(slot);
+                }
+                Some(key)
+                    if block
+                        .first_key
+                        .is_some_and(|old| cmp_key(&old, &key) == Ordering::Equal) => {}
+                Some(key) => {
+                    let node = blocks.get_mut(&parent).unwrap();
+                    let at = header_size + slot * ptr_size;
+                    let ptr = unsafe { &mut *(node[at..].as_mut_ptr() as *mut btrfs_key_ptr) };
+                    ptr.key = key;
+                }
+            }
+        }
+        for (parent, mut slots) in dropped {
+            slots.sort_by(|a, b| b.cmp(a));
+            let node = blocks.get_mut(&parent).unwrap();
+            for slot in slots {
+                remove_ptr(node, slot);
+            }
+        }
+        changed = parents.into_values().collect();
+        level += 1;
+    }
+
+    let csum_type = fs.master_sb.csum_type;
+    let blocks = blocks
+        .into_iter()
+        .map(|(logical, mut block)| {
+            seal_tree_block(&mut block, csum_type);
+            (logical, block)
+        })
+        .collect();
+    Ok(TreeEdit { blocks, freed })
+}
+
+/// set a superblock field from its value as text, as printed by `super`.
+/// Fields which can't be changed without rewriting the metadata to match,
+/// such as the fsid, sizes and checksum type, are refused.
+pub fn set_super_field(sb: &mut btrfs_super_block, field: &str, value: &str) -> Result<()> {
+    let level = || -> Result<u8> {
+        let level = parse_u64(value)?;
+        ensure!(
+            level < BTRFS_MAX_LEVEL as u64,
+            "level {level} is not below {BTRFS_MAX_LEVEL}"
+        );
+        Ok(level as u8)
+    };
+    match field {
+        "label" => {
+            ensure!(
+                value.len() < BTRFS_LABEL_SIZE,
+                "the label must be under {BTRFS_LABEL_SIZE} bytes"
+            );
+            sb.label = [0; BTRFS_LABEL_SIZE];
+            sb.label[..value.len()].copy_from_slice(value.as_bytes());
+        }
+        "metadata_uuid" => sb.metadata_uuid = value.parse()?,
+        "root_level" => sb.root_level = level()?,
+        "chunk_root_level" => sb.chunk_root_level = level()?,
+        "log_root_level" => sb.log_root_level = level()?,
+        "flags" => sb.flags = parse_u64(value)?,
+        "generation" => sb.generation = parse_u64(value)?,
+        "root" => sb.root = parse_u64(value)?,
+        "chunk_root" => sb.chunk_root = parse_u64(value)?,
+        "log_root" => sb.log_root = parse_u64(value)?,
+        "total_bytes" => sb.total_bytes = parse_u64(value)?,
+        "bytes_used" => sb.bytes_used = parse_u64(value)?,
+        "root_dir_object_id" => sb.root_dir_object_id = parse_u64(value)?,
+        "num_devices" => sb.num_devices = parse_u64(value)?,
+        "chunk_root_generation" => sb.chunk_root_generation = parse_u64(value)?,
+        "compat_flags" => sb.compat_flags = parse_u64(value)?,
+        "compat_ro_flags" => sb.compat_ro_flags = parse_u64(value)?,
+        "incompat_flags" => sb.incompat_flags = parse_u64(value)?,
+        "cache_generation" => sb.cache_generation = parse_u64(value)?,
+        "uuid_tree_generation" => sb.uuid_tree_generation = parse_u64(value)?,
+        "nr_global_roots" => sb.nr_global_roots = parse_u64(value)?,
+        _ => bail!("superblock field {field:?} can't be edited"),
+    }
+    Ok(())
+}
+
+/// the offsets of the superblock copies which fit on a device of len bytes
+pub fn super_mirrors(len: u64) -> Vec<u64> {
+    (0..BTRFS_SUPER_MIRROR_MAX)
+        .map(|mirror| match mirror {
+            0 => BTRFS_SUPER_INFO_OFFSET as u64,
+            _ => 0x4000_u64 << (BTRFS_SUPER_MIRROR_SHIFT * mirror),
+        })
+        .filter(|&offset| offset + BTRFS_SUPER_INFO_SIZE as u64 <= len)
+        .collect()
+}
+
+/// sb as the copy at physical, with its bytenr and a fresh checksum
+pub(crate) fn sealed_super(sb: &btrfs_super_block, physical: u64) -> Vec<u8> {
+    let mut copy = *sb;
+    copy.bytenr = physical;
+    let mut block = as_bytes(&copy).to_vec();
+    let csum = csum_data(&block[BTRFS_CSUM_SIZE..], copy.csum_type);
+    block[..BTRFS_CSUM_SIZE].copy_from_slice(&csum);
+    block
+}
+
+/// how a superblock copy compares with the newest valid copy on its device
+pub enum MirrorState {
+    Newest,
+    /// valid, but at an older generation
+    Stale(u64),
+    /// at the newest generation, but with other contents
+    Differs,
+    Invalid(String),
+}
+
+/// one superblock copy of a device
+pub struct SuperMirror {
+    pub devid: u64,
+    pub physical: u64,
+    pub generation: Option<u64>,
+    pub state: MirrorState,
+}
+
+/// every superblock copy of every device compared with the newest valid
+/// copy on the same device, in devid order
+pub fn check_super_mirrors(fs: &FsInfo) -> Vec<SuperMirror> {
+    let mut devids: Vec<u64> = fs.devid_map.keys().copied().collect();
+    devids.sort();
+    let mut mirrors = Vec::new();
+    for devid in devids {
+        let copies = super_copies(&fs.devid_map[&devid].file);
+        let newest = copies
+            .iter()
+            .filter_map(|(_, sb)| sb.as_ref().ok())
+            .reduce(|newest, sb| {
+                if sb.generation > newest.generation {
+                    sb
+                } else {
+                    newest
+                }
+            })
+            .copied();
+        for (physical, sb) in copies {
+            let (generation, state) = match sb {
+                Err(e) => (None, MirrorState::Invalid(e.to_string())),
+                Result::Ok(sb) => {
+                    // a valid copy means there is a newest
+                    let newest = newest.as_ref().unwrap();
+                    let state = if sb.generation < newest.generation {
+                        MirrorState::Stale(newest.generation)
+                    } else if sealed_super(&sb, 0) != sealed_super(newest, 0) {
+                        MirrorState::Differs
+                    } else {
+                        MirrorState::Newest
+                    };
+                    (Some(sb.generation), state)
+                }
+            };
+            mirrors.push(SuperMirror {
+                devid,
+                physical,
+                generation,
+                state,
+            });
+        }
+    }
+    mirrors
+}
+
+/// write the newest valid superblock copy of each of the filesystem's own
+/// devices over its copies which are stale, differ or are invalid, saving
+/// them first
+pub fn resync_super_mirrors(fs: &FsInfo, options: &RepairOptions) -> Result<Vec<SuperCopy>> {
+    let mirrors = check_super_mirrors(fs);
+    let mut copies = Vec::new();
+    for devid in fs.own_devids() {
+        let dev = &fs.devid_map[&devid];
+        let sb = load_sb(&dev.file)?;
+        for mirror in &mirrors {
+            if mirror.devid != devid || matches!(mirror.state, MirrorState::Newest) {
+                continue;
+            }
+            let mut copy = SuperCopy {
+                devid,
+                physical: mirror.physical,
+                backup: None,
+            };
+            if !options.dry_run {
+                let old = dev.read_at(mirror.physical, BTRFS_SUPER_INFO_SIZE)?;
+                copy.backup = Some(save_backup(
+                    &options.backup_dir,
+                    devid,
+                    mirror.physical,
+                    &old,
+                )?);
+                dev.write_at(fs, mirror.physical, &sealed_super(&sb, mirror.physical))?;
+            }
+            copies.push(copy);
+        }
+    }
+    Ok(copies)
+}
+
+/// a superblock copy written (or in a dry run, which would be)
+pub struct SuperCopy {
+    pub devid: u64,
+    pub physical: u64,
+    pub backup: Option<PathBuf>,
+}
+
+/// write each of supers to every mirror on the device with its devid, as
+/// one transaction
+fn write_supers(
+    fs: &FsInfo,
+    supers: Vec<btrfs_super_block>,
+    options: &RepairOptions,
+) -> Result<Vec<SuperCopy>> {
+    let mut transaction = RepairTransaction::new(fs);
+    for sb in supers {
+        transaction.stage_super(sb);
+    }
+    Ok(transaction.commit(options)?.supers)
+}
+
+/// apply field edits to the superblock of every device, and write each to
+/// all its mirrors. Each device's own latest copy is edited, so that its dev
+/// item is kept.
+pub fn edit_super(
+    fs: &FsInfo,
+    edits: &[(String, String)],
+    options: &RepairOptions,
+) -> Result<Vec<SuperCopy>> {
+    // every edit is checked on every device before anything is written
+    let mut supers = Vec::new();
+    for devid in fs.own_devids() {
+        let mut sb = load_sb(&fs.devid_map[&devid].file)?;
+        for (field, value) in edits {
+            set_super_field(&mut sb, field, value)?;
+        }
+        supers.push(sb);
+    }
+    write_supers(fs, supers, options)
+}
+
+/// what change_fsid rewrote (or in a dry run, would rewrite)
+pub struct FsidChange {
+    pub tree_blocks: usize,
+    pub dev_items: usize,
+    /// every tree block copy written
+    pub copies: Vec<CopyRepair>,
+    /// every superblock copy written, those marking the change as under way
+    /// and then the final ones
+    pub supers: Vec<SuperCopy>,
+}
+
+/// change the filesystem's fsid, as `btrfstune -u` (or with metadata_uuid,
+/// `btrfstune -m`) does. Without metadata_uuid the fsid in every tree block
+/// header and dev item is rewritten, between superblocks flagged
+/// CHANGING_FSID so that an interrupted change isn't mounted. With it only
+/// the superblocks change, keeping the old fsid for the metadata under the
+/// METADATA_UUID feature; changing back to the metadata's fsid drops the
+/// feature again.
+pub fn change_fsid(
+    fs: &FsInfo,
+    new_fsid: BtrfsFsid,
+    metadata_uuid: bool,
+    options: &RepairOptions,
+) -> Result<FsidChange> {
+    let sb = &fs.master_sb;
+    let incompat = sb.incompat_flags;
+    let has_metadata_uuid = incompat & BTRFS_FEATURE_INCOMPAT_METADATA_UUID != 0;
+    let mut change = FsidChange {
+        tree_blocks: 0,
+        dev_items: 0,
+        copies: Vec::new(),
+        supers: Vec::new(),
+    };
+    let mut supers = Vec::new();
+    for devid in fs.own_devids() {
+        supers.push(load_sb(&fs.devid_map[&devid].file)?);
+    }
+
+    if metadata_uuid {
+        let metadata_fsid = fs.metadata_fsid();
+        for sb in &mut supers {
+            sb.fsid = new_fsid;
+            if new_fsid == metadata_fsid {
+                sb.incompat_flags &= !BTRFS_FEATURE_INCOMPAT_METADATA_UUID;
+                sb.metadata_uuid = BtrfsUuid([0; BTRFS_UUID_SIZE]);
+            } else {
+                sb.incompat_flags |= BTRFS_FEATURE_INCOMPAT_METADATA_UUID;
+                sb.metadata_uuid = metadata_fsid;
+            }
+        }
+        change.supers = write_supers(fs, supers, options)?;
+        return Ok(change);
+    }
+
+    ensure!(
+        !has_metadata_uuid,
+        "the metadata fsid differs from the fsid; change it back with --metadata-uuid first"
+    );
+    ensure!(
+        sb.log_root == 0,
+        "the log tree must be replayed or zeroed before the fsid is changed"
+    );
+    ensure!(
+        fs.seed_fsids.is_empty(),
+        "the blocks on the seed devices can't be rewritten, as seeds are read-only"
+    );
+    // every block is rewritten in memory, and must verify, before anything
+    // is written
+    let mut transaction = RepairTransaction::new(fs);
+    transaction.set_fsid(new_fsid);
+    let mut seen = std::collections::HashSet::new();
+    for (tree, root, level) in tree_roots(fs) {
+        let mut stack = vec![(root, level)];
+        while let Some((logical, level)) = stack.pop() {
+            if !seen.insert(logical) {
+                continue;
+            }
+            let block = load_virt_block(fs, logical)?;
+            let problems = crate::scrub::check_tree_block(fs, &block, logical, Some(level));
+            ensure!(
+                problems.is_empty(),
+                "block {logical} of {}: {}; every tree block must verify before the fsid is changed",
+                fmt_treeid(tree),
+                problems.join(", ")
+            );
+            let mut block = block.to_vec();
+            let header = unsafe { &mut *(block.as_mut_ptr() as *mut btrfs_header) };
+            header.fsid = new_fsid;
+            if level == 0 && tree == BTRFS_CHUNK_TREE_OBJECTID {
+                // the data of items never moves, so its offsets can be taken
+                // from the item headers
+                let items = leaf_items(&block).0;
+                for item in items {
+                    if item.key.item_type != BtrfsItemType::DEV_ITEM {
+                        continue;
+                    }
+                    let start = std::mem::size_of::<btrfs_header>() + item.offset as usize;
+                    let size = item.size as usize;
+                    ensure!(
+                        size >= std::mem::size_of::<btrfs_dev_item>(),
+                        "dev item in block {logical} is only {size} bytes"
+                    );
+                    let dev_item =
+                        unsafe { &mut *(block[start..].as_mut_ptr() as *mut btrfs_dev_item) };
+                    dev_item.fsid = new_fsid;
+                    change.dev_items += 1;
+                }
+            }
+            for entry in node_entries(&block) {
+                if let NodeEntry::Ptr(ptr) = entry {
+                    stack.push((ptr.blockptr, level - 1));
+                }
+            }
+            transaction.stage_block(logical, block);
+            change.tree_blocks += 1;
+        }
+    }
+
+    // the superblocks are marked before any block is written, and only
+    // take the new fsid once they all are
+    for mut sb in supers {
+        let mut marked = sb;
+        marked.flags |= BTRFS_SUPER_FLAG_CHANGING_FSID;
+        transaction.stage_leading_super(marked);
+        sb.fsid = new_fsid;
+        sb.dev_item.fsid = new_fsid;
+        transaction.stage_super(sb);
+    }
+    let written = transaction.commit(options)?;
+    change.copies = written.copies;
+    change.supers = written.supers;
+    Ok(change)
+}
+
+/// what the feature bits must agree with, as found in the metadata
+#[derive(Clone, Copy, Default)]
+struct FeatureFacts {
+    free_space_tree: bool,
+    block_group_tree: bool,
+    /// whether the extent tree holds BLOCK_GROUP_ITEMs
+    extent_tree_block_groups: bool,
+    /// the chunk type bits of every chunk
+    chunk_types: u64,
+}
+
+fn feature_facts(fs: &FsInfo) -> FeatureFacts {
+    let extent_tree_block_groups = tree_root_offset(fs, BTRFS_EXTENT_TREE_OBJECTID)
+        .map(|root| {
+            let search = key_range(None, Some(BtrfsItemType::BLOCK_GROUP_ITEM), None);
+            search_range(fs, root, search)
+                .any(|(item, ..)| item.key.item_type == BtrfsItemType::BLOCK_GROUP_ITEM)
+        })
+        .unwrap_or(false);
+    FeatureFacts {
+        free_space_tree: tree_root_offset(fs, BTRFS_FREE_SPACE_TREE_OBJECTID).is_some(),
+        block_group_tree: tree_root_offset(fs, BTRFS_BLOCK_GROUP_TREE_OBJECTID).is_some(),
+        extent_tree_block_groups,
+        chunk_types: all_chunks(fs).iter().fold(0, |all, c| all | c.1.r#type),
+    }
+}
+
+/// a compat_ro or incompat feature by name, as `super` prints it, ignoring
+/// case and accepting - for _. Returns whether it is compat_ro, and its bit.
+pub fn parse_feature(name: &str) -> Result<(bool, u64)> {
+    let name = name.to_ascii_uppercase().replace('-', "_");
+    let find = |names: &[(u64, &str)]| names.iter().find(|(_, n)| *n == name).map(|f| f.0);
+    if let Some(bit) = find(crate::flags::COMPAT_RO_FEATURES) {
+        return Ok((true, bit));
+    }
+    if let Some(bit) = find(crate::flags::INCOMPAT_FEATURES) {
+        return Ok((false, bit));
+    }
+    bail!("unknown feature {name}")
+}
+
+/// incompat features the kernel sets on a mounted filesystem whenever it
+/// first writes something needing them. Nothing already on disk changes
+/// meaning when they are set, but clearing them could hide what was written.
+const INCOMPAT_SAFE_SET: u64 = BTRFS_FEATURE_INCOMPAT_MIXED_BACKREF
+    | BTRFS_FEATURE_INCOMPAT_COMPRESS_LZO
+    | BTRFS_FEATURE_INCOMPAT_COMPRESS_ZSTD
+    | BTRFS_FEATURE_INCOMPAT_BIG_METADATA
+    | BTRFS_FEATURE_INCOMPAT_EXTENDED_IREF
+    | BTRFS_FEATURE_INCOMPAT_SKINNY_METADATA
+    | BTRFS_FEATURE_INCOMPAT_NO_HOLES;
+
+/// refuse a feature change the metadata doesn't agree with, or which leaves
+/// a combination the kernel won't mount that wasn't there before
+fn check_features(
+    facts: &FeatureFacts,
+    (old_compat_ro, old_incompat): (u64, u64),
+    (compat_ro, incompat): (u64, u64),
+) -> Result<()> {
+    use crate::flags::{fmt_flags, COMPAT_RO_FEATURES, INCOMPAT_FEATURES};
+    let changed_ro = old_compat_ro ^ compat_ro;
+    for &(bit, name) in COMPAT_RO_FEATURES {
+        if changed_ro & bit == 0 {
+            continue;
+        }
+        let set = compat_ro & bit != 0;
+        match bit {
+            BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE => ensure!(
+                set == facts.free_space_tree,
+                if set {
+                    "there is no free space tree; mount with space_cache=v2 to create one"
+                } else {
+                    "the free space tree would be left behind, stale; remove it with btrfs check --clear-space-cache v2"
+                }
+            ),
+            BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID => {}
+            BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE => ensure!(
+                set == (facts.block_group_tree && !facts.extent_tree_block_groups),
+                if set {
+                    "the block group items aren't all in a block group tree"
+                } else {
+                    "the block group items aren't in the extent tree"
+                }
+            ),
+            _ => ensure!(set, "{name} can't be cleared, something may depend on it"),
+        }
+    }
+    let changed = old_incompat ^ incompat;
+    for &(bit, name) in INCOMPAT_FEATURES {
+        if changed & bit == 0 {
+            continue;
+        }
+        let set = incompat & bit != 0;
+        let raid = match bit {
+            BTRFS_FEATURE_INCOMPAT_RAID56 => BTRFS_BLOCK_GROUP_RAID5 | BTRFS_BLOCK_GROUP_RAID6,
+            BTRFS_FEATURE_INCOMPAT_RAID1C34 => {
+                BTRFS_BLOCK_GROUP_RAID1C3 | BTRFS_BLOCK_GROUP_RAID1C4
+            }
+            _ => 0,
+        };
+        if raid != 0 {
+            ensure!(
+                set == (facts.chunk_types & raid != 0),
+                "{name} must be set exactly when there are {} chunks",
+                fmt_flags(raid, crate::flags::BLOCK_GROUP_PROFILES)
+            );
+        } else if bit == BTRFS_FEATURE_INCOMPAT_METADATA_UUID {
+            bail!("{name} is changed with set-fsid --metadata-uuid");
+        } else if bit & INCOMPAT_SAFE_SET == 0 {
+            bail!("{name} changes the on-disk format and can't be toggled");
+        } else {
+            ensure!(
+                set,
+                "{name} can't be cleared, items written under it may exist"
+            );
+        }
+    }
+    // the kernel's checks in btrfs_validate_super
+    let problems = |compat_ro: u64, incompat: u64| {
+        let mut problems = Vec::new();
+        if compat_ro & BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID != 0
+            && compat_ro & BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE == 0
+        {
+            problems.push("FREE_SPACE_TREE_VALID needs FREE_SPACE_TREE");
+        }
+        if compat_ro & BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE != 0
+            && (compat_ro & BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE == 0
+                || incompat & BTRFS_FEATURE_INCOMPAT_NO_HOLES == 0)
+        {
+            problems.push("BLOCK_GROUP_TREE needs FREE_SPACE_TREE and NO_HOLES");
+        }
+        problems
+    };
+    let before = problems(old_compat_ro, old_incompat);
+    for problem in problems(compat_ro, incompat) {
+        ensure!(before.contains(&problem), "{problem}");
+    }
+    Ok(())
+}
+
+/// the feature flags before and after change_features, and the superblock
+/// copies written (or in a dry run, which would be)
+pub struct FeatureChange {
+    pub compat_ro: (u64, u64),
+    pub incompat: (u64, u64),
+    pub supers: Vec<SuperCopy>,
+}
+
+/// set and clear compat_ro and incompat features, named as parse_feature
+/// takes them, in every device's superblock. Only changes the metadata
+/// agrees with are made: e.g. FREE_SPACE_TREE_VALID can be cleared to have
+/// the kernel rebuild the free space tree at the next mount, and
+/// BLOCK_GROUP_TREE set once the block group items have been moved to their
+/// own tree. Nothing is written if nothing changes.
+pub fn change_features(
+    fs: &FsInfo,
+    set: &[String],
+    clear: &[String],
+    options: &RepairOptions,
+) -> Result<FeatureChange> {
+    let (mut set_ro, mut set_incompat, mut clear_ro, mut clear_incompat) = (0, 0, 0, 0);
+    for name in set {
+        match parse_feature(name)? {
+            (true, bit) => set_ro |= bit,
+            (false, bit) => set_incompat |= bit,
+        }
+    }
+    for name in clear {
+        match parse_feature(name)? {
+            (true, bit) => clear_ro |= bit,
+            (false, bit) => clear_incompat |= bit,
+        }
+    }
+    ensure!(
+        set_ro & clear_ro == 0 && set_incompat & clear_incompat == 0,
+        "a feature can't be both set and cleared"
+    );
+    let old = (fs.master_sb.compat_ro_flags, fs.master_sb.incompat_flags);
+    let new = (
+        (old.0 | set_ro) & !clear_ro,
+        (old.1 | set_incompat) & !clear_incompat,
+    );
+    let mut change = FeatureChange {
+        compat_ro: (old.0, new.0),
+        incompat: (old.1, new.1),
+        supers: Vec::new(),
+    };
+    if new == old {
+        return Ok(change);
+    }
+    check_features(&feature_facts(fs), old, new)?;
+    let mut supers = Vec::new();
+    for devid in fs.own_devids() {
+        let mut sb = load_sb(&fs.devid_map[&devid].file)?;
+        sb.compat_ro_flags = (sb.compat_ro_flags | set_ro) & !clear_ro;
+        sb.incompat_flags = (sb.incompat_flags | set_incompat) & !clear_incompat;
+        supers.push(sb);
+    }
+    change.supers = write_supers(fs, supers, options)?;
+    Ok(change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(objectid: u64) -> btrfs_disk_key {
+        btrfs_disk_key {
+            objectid,
+            item_type: BtrfsItemType::INODE_REF,
+            offset: 0,
+        }
+    }
+
+    #[test]
+    fn remove_slot_closes_gap() {
+        let header: btrfs_header = unsafe { std::mem::zeroed() };
+        let items = [
+            (key(1), vec![1_u8; 10]),
+            (key(2), vec![2_u8; 20]),
+            (key(3), vec![3_u8; 30]),
+        ];
+        let mut leaf = build_leaf(&header, &items, 4096).unwrap();
+        remove_slot(&mut leaf, 1);
+        let expected = build_leaf(&header, &[items[0].clone(), items[2].clone()], 4096).unwrap();
+        assert_eq!(leaf, expected);
+    }
+
+    #[test]
+    fn insert_slot_matches_packed_leaf() {
+        let header: btrfs_header = unsafe { std::mem::zeroed() };
+        let items = [
+            (key(1), vec![1_u8; 10]),
+            (key(2), vec![2_u8; 20]),
+            (key(3), vec![3_u8; 30]),
+        ];
+        for slot in 0..items.len() {
+            let mut rest = items.to_vec();
+            let (k, data) = rest.remove(slot);
+            let mut leaf = build_leaf(&header, &rest, 4096).unwrap();
+            insert_slot(&mut leaf, slot, &k, &data);
+            assert_eq!(leaf, build_leaf(&header, &items, 4096).unwrap());
+        }
+    }
+
+    #[test]
+    fn super_field_edits() {
+        let mut sb: btrfs_super_block = unsafe { std::mem::zeroed() };
+        set_super_field(&mut sb, "log_root", "0x1000").unwrap();
+        set_super_field(&mut sb, "label", "recovered").unwrap();
+        let log_root = sb.log_root;
+        assert_eq!(log_root, 0x1000);
+        assert_eq!(&sb.label[..10], b"recovered\0");
+        assert!(set_super_field(&mut sb, "root_level", "8").is_err());
+        assert!(set_super_field(&mut sb, "fsid", "0").is_err());
+    }
+
+    #[test]
+    fn remove_ptr_shifts_later_pointers() {
+        let mut header: btrfs_header = unsafe { std::mem::zeroed() };
+        header.level = 1;
+        let ptrs: Vec<btrfs_key_ptr> = (1..=3)
+            .map(|i| btrfs_key_ptr {
+                key: key(i),
+                blockptr: i * 4096,
+                generation: 7,
+            })
+            .collect();
+        let mut node = build_node(&header, &ptrs, 4096).unwrap();
+        remove_ptr(&mut node, 0);
+        let expected = build_node(&header, &ptrs[1..], 4096).unwrap();
+        assert_eq!(node, expected);
+    }
+
+    #[test]
+    fn feature_changes_checked() {
+        const FST: u64 = BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE;
+        const VALID: u64 = BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID;
+        const BGT: u64 = BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE;
+        const NO_HOLES: u64 = BTRFS_FEATURE_INCOMPAT_NO_HOLES;
+        let facts = FeatureFacts {
+            free_space_tree: true,
+            block_group_tree: true,
+            ..Default::default()
+        };
+        assert_eq!(parse_feature("block-group-tree").unwrap(), (true, BGT));
+        // invalidating the free space tree is always allowed
+        assert!(check_features(&facts, (FST | VALID, 0), (FST, 0)).is_ok());
+        // the free space tree exists, so the bit must stay
+        assert!(check_features(&facts, (FST, 0), (0, 0)).is_err());
+        assert!(check_features(
+            &facts,
+            (FST | VALID, NO_HOLES),
+            (FST | VALID | BGT, NO_HOLES)
+        )
+        .is_ok());
+        assert!(check_features(&facts, (FST | VALID, 0), (FST | VALID | BGT, 0)).is_err());
+        assert!(check_features(&facts, (FST, 0), (FST, NO_HOLES)).is_ok());
+        assert!(check_features(&facts, (FST, NO_HOLES), (FST, 0)).is_err());
+        assert!(check_features(&facts, (FST, 0), (FST, BTRFS_FEATURE_INCOMPAT_RAID56)).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+//! Export of the items of trees as tables with one row per item, for
+//! analysis with other tools: CSV, or Parquet when built with the parquet
+//! feature.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::dump::fmt_treeid;
+use crate::items::{describe_item, item_as};
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            _ => bail!("unknown export format {s:?}, expected csv or parquet"),
+        }
+    }
+}
+
+/// the columns of an export, in order
+pub const COLUMNS: &[&str] = &[
+    "tree",
+    "leaf",
+    "slot",
+    "leaf_generation",
+    "objectid",
+    "item_type",
+    "offset",
+    "size",
+    "generation",
+    "bytenr",
+    "length",
+    "description",
+];
+
+/// an item, with the fields of its data worth analysing decoded
+#[derive(Clone, Debug)]
+pub struct ItemRow {
+    pub tree: u64,
+    /// the logical address of the leaf holding the item
+    pub leaf: u64,
+    pub slot: u32,
+    pub leaf_generation: u64,
+    pub key: btrfs_disk_key,
+    pub size: u32,
+    /// the generation recorded in inode, root, file extent and extent items
+    pub generation: Option<u64>,
+    /// the address and length of the extent, chunk or block group the item
+    /// describes; the address is physical for dev extents and logical
+    /// otherwise
+    pub bytenr: Option<u64>,
+    pub length: Option<u64>,
+    /// the item as dump-tree describes it, lines joined by "; "
+    pub description: String,
+}
+
+/// (generation, bytenr, length) of an item, where it has them
+fn decode(
+    fs: &FsInfo,
+    key: &btrfs_disk_key,
+    data: &[u8],
+) -> (Option<u64>, Option<u64>, Option<u64>) {
+    match key.item_type {
+        BtrfsItemType::INODE_ITEM => (
+            item_as::<btrfs_inode_item>(data).map(|i| i.generation),
+            None,
+            None,
+        ),
+        BtrfsItemType::ROOT_ITEM => match item_as::<btrfs_root_item>(data) {
+            Some(root) => (Some(root.generation), Some(root.bytenr), None),
+            None => (None, None, None),
+        },
+        BtrfsItemType::EXTENT_DATA => {
+            if data.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+                return (None, None, None);
+            }
+            let fe = unsafe { &*(data.as_ptr() as *const btrfs_file_extent_item) };
+            let generation = Some(fe.generation);
+            if fe.r#type == BTRFS_FILE_EXTENT_INLINE
+                || data.len() < std::mem::size_of::<btrfs_file_extent_item>()
+                || fe.disk_bytenr == 0
+            {
+                return (generation, None, None);
+            }
+            (generation, Some(fe.disk_bytenr), Some(fe.disk_num_bytes))
+        }
+        BtrfsItemType::EXTENT_ITEM => (
+            item_as::<btrfs_extent_item>(data).map(|e| e.generation),
+            Some(key.objectid),
+            Some(key.offset),
+        ),
+        BtrfsItemType::METADATA_ITEM => (
+            item_as::<btrfs_extent_item>(data).map(|e| e.generation),
+            Some(key.objectid),
+            Some(fs.master_sb.nodesize as u64),
+        ),
+        BtrfsItemType::CHUNK_ITEM => (
+            None,
+            Some(key.offset),
+            item_as::<btrfs_chunk>(data).map(|c| c.length),
+        ),
+        BtrfsItemType::BLOCK_GROUP_ITEM => (None, Some(key.objectid), Some(key.offset)),
+        BtrfsItemType::DEV_EXTENT => (
+            None,
+            Some(key.offset),
+            item_as::<btrfs_dev_extent>(data).map(|d| d.length),
+        ),
+        _ => (None, None, None),
+    }
+}
+
+/// the rows of the items of a tree which the search matches
+pub fn item_rows<'a>(
+    fs: &'a FsInfo,
+    tree: u64,
+    search: NodeSearchOption,
+) -> Result<impl Iterator<Item = ItemRow> + 'a> {
+    let root =
+        tree_root_offset(fs, tree).ok_or_else(|| anyhow!("tree {} not found", fmt_treeid(tree)))?;
+    let mut leaf_generation = (u64::MAX, 0);
+    Ok(
+        search_range(fs, root, search).map(move |(item, data, block_offset, slot)| {
+            if leaf_generation.0 != block_offset {
+                let generation = load_virt::<btrfs_header>(fs, block_offset)
+                    .map(|header| header.generation)
+                    .unwrap_or(0);
+                leaf_generation = (block_offset, generation);
+            }
+            let key = item.key;
+            let (generation, bytenr, length) = decode(fs, &key, &data);
+            ItemRow {
+                tree,
+                leaf: block_offset,
+                slot,
+                leaf_generation: leaf_generation.1,
+                key,
+                size: item.size,
+                generation,
+                bytenr,
+                length,
+                description: describe_item(&key, &data).join("; "),
+            }
+        }),
+    )
+}
+
+/// a CSV field, quoted if it needs to be
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn fmt_optional(value: Option<u64>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// write rows as CSV with a header line, leaving missing values empty
+pub fn write_csv(out: &mut impl Write, rows: impl Iterator<Item = ItemRow>) -> Result<usize> {
+    writeln!(out, "{}", COLUMNS.join(","))?;
+    let mut count = 0;
+    for row in rows {
+        let key = row.key;
+        writeln!(
+            out,
+            "{},{},{},{},{},{:?},{},{},{},{},{},{}",
+            row.tree,
+            row.leaf,
+            row.slot,
+            row.leaf_generation,
+            { key.objectid },
+            { key.item_type },
+            { key.offset },
+            row.size,
+            fmt_optional(row.generation),
+            fmt_optional(row.bytenr),
+            fmt_optional(row.length),
+            csv_field(&row.description),
+        )?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(feature = "parquet")]
+const PARQUET_SCHEMA: &str = "message items {
+    required int64 tree (integer(64, false));
+    required int64 leaf (integer(64, false));
+    required int32 slot (integer(32, false));
+    required int64 leaf_generation (integer(64, false));
+    required int64 objectid (integer(64, false));
+    required binary item_type (string);
+    required int64 offset (integer(64, false));
+    required int32 size (integer(32, false));
+    optional int64 generation (integer(64, false));
+    optional int64 bytenr (integer(64, false));
+    optional int64 length (integer(64, false));
+    required binary description (string);
+}";
+
+/// rows of a Parquet row group
+#[cfg(feature = "parquet")]
+const PARQUET_ROW_GROUP: usize = 64 * 1024;
+
+/// write rows to a Parquet file, in row groups of PARQUET_ROW_GROUP rows.
+/// u64 values are stored as the bits of int64 columns annotated as unsigned.
+#[cfg(feature = "parquet")]
+pub fn write_parquet(out: impl Write + Send, rows: impl Iterator<Item = ItemRow>) -> Result<usize> {
+    use parquet::data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type};
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use std::sync::Arc;
+
+    let schema = Arc::new(parquet::schema::parser::parse_message_type(PARQUET_SCHEMA)?);
+    let mut writer =
+        SerializedFileWriter::new(out, schema, Arc::new(WriterProperties::builder().build()))?;
+    let mut rows = rows.peekable();
+    let mut count = 0;
+    while rows.peek().is_some() {
+        let group: Vec<ItemRow> = rows.by_ref().take(PARQUET_ROW_GROUP).collect();
+        count += group.len();
+        let mut row_group = writer.next_row_group()?;
+        let mut column = 0;
+        while let Some(mut column_writer) = row_group.next_column()? {
+            let int64 = |get: fn(&ItemRow) -> u64| -> Vec<i64> {
+                group.iter().map(|row| get(row) as i64).collect()
+            };
+            let optional = |get: fn(&ItemRow) -> Option<u64>| -> (Vec<i64>, Vec<i16>) {
+                let values = group.iter().filter_map(get).map(|v| v as i64).collect();
+                let levels = group.iter().map(|row| get(row).is_some() as i16).collect();
+                (values, levels)
+            };
+            let strings = |get: fn(&ItemRow) -> String| -> Vec<ByteArray> {
+                group
+                    .iter()
+                    .map(|row| ByteArray::from(get(row).into_bytes()))
+                    .collect()
+            };
+            match COLUMNS[column] {
+                "tree" => column_writer.typed::<Int64Type>().write_batch(
+                    &int64(|r| r.tree),
+                    None,
+                    None,
+                )?,
+                "leaf" => column_writer.typed::<Int64Type>().write_batch(
+                    &int64(|r| r.leaf),
+                    None,
+                    None,
+                )?,
+                "slot" => {
+                    let slots: Vec<i32> = group.iter().map(|r| r.slot as i32).collect();
+                    column_writer
+                        .typed::<Int32Type>()
+                        .write_batch(&slots, None, None)?
+                }
+                "leaf_generation" => column_writer.typed::<Int64Type>().write_batch(
+                    &int64(|r| r.leaf_generation),
+                    None,
+                    None,
+                )?,
+                "objectid" => column_writer.typed::<Int64Type>().write_batch(
+                    &int64(|r| r.key.objectid),
+                    None,
+                    None,
+                )?,
+                "item_type" => column_writer.typed::<ByteArrayType>().write_batch(
+                    &strings(|r| format!("{:?}", { r.key.item_type })),
+                    None,
+                    None,
+                )?,
+                "offset" => column_writer.typed::<Int64Type>().write_batch(
+                    &int64(|r| r.key.offset),
+                    None,
+                    None,
+                )?,
+                "size" => {
+                    let sizes: Vec<i32> = group.iter().map(|r| r.size as i32).collect();
+                    column_writer
+                        .typed::<Int32Type>()
+                        .write_batch(&sizes, None, None)?
+                }
+                "generation" | "bytenr" | "length" => {
+                    let (values, levels) = optional(match COLUMNS[column] {
+                        "generation" => |r| r.generation,
+                        "bytenr" => |r| r.bytenr,
+                        _ => |r| r.length,
+                    });
+                    column_writer
+                        .typed::<Int64Type>()
+                        .write_batch(&values, Some(&levels), None)?
+                }
+                _ => column_writer.typed::<ByteArrayType>().write_batch(
+                    &strings(|r| r.description.clone()),
+                    None,
+                    None,
+                )?,
+            };
+            column_writer.close()?;
+            column += 1;
+        }
+        row_group.close()?;
+    }
+    writer.close()?;
+    Ok(count)
+}
+
+/// write the items of trees which the search matches to path, returning
+/// how many there were
+pub fn export_items(
+    fs: &FsInfo,
+    trees: &[u64],
+    search: NodeSearchOption,
+    format: ExportFormat,
+    path: &Path,
+) -> Result<usize> {
+    let mut rows = Vec::new();
+    for tree in trees {
+        rows.push(item_rows(fs, *tree, search)?);
+    }
+    let rows = rows.into_iter().flatten();
+    let file =
+        std::fs::File::create(path).with_context(|| format!("cannot create {}", path.display()))?;
+    match format {
+        ExportFormat::Csv => {
+            let mut out = std::io::BufWriter::new(file);
+            let count = write_csv(&mut out, rows)?;
+            out.flush()?;
+            Ok(count)
+        }
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => write_parquet(file, rows),
+        #[cfg(not(feature = "parquet"))]
+        ExportFormat::Parquet => bail!("built without the parquet feature"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_quoting() {
+        assert_eq!(csv_field("refs 1 generation 7"), "refs 1 generation 7");
+        assert_eq!(csv_field("name \"a,b\""), "\"name \"\"a,b\"\"\"");
+        assert_eq!(
+            "Parquet".parse::<ExportFormat>().unwrap(),
+            ExportFormat::Parquet
+        );
+        assert!("json".parse::<ExportFormat>().is_err());
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_round_trip() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let row = |slot, generation| ItemRow {
+            tree: 5,
+            leaf: 30408704,
+            slot,
+            leaf_generation: 9,
+            key: btrfs_disk_key {
+                objectid: 256,
+                item_type: BtrfsItemType::INODE_ITEM,
+                offset: 0,
+            },
+            size: 160,
+            generation,
+            bytenr: None,
+            length: None,
+            description: String::from("generation 9"),
+        };
+        let path = std::env::temp_dir().join(format!("export.{}.parquet", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let rows = [row(0, Some(u64::MAX)), row(1, None)];
+        assert_eq!(write_parquet(file, rows.into_iter()).unwrap(), 2);
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        let rows: Vec<String> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().to_string())
+            .collect();
+        assert!(rows[0].contains("item_type: \"INODE_ITEM\""), "{}", rows[0]);
+        assert!(
+            rows[0].contains(&format!("generation: {}", u64::MAX)),
+            "{}",
+            rows[0]
+        );
+        assert!(rows[1].contains("generation: null"), "{}", rows[1]);
+    }
+}
@@ -0,0 +1,201 @@
+//! Names for the bit flags found in the superblock and elsewhere, and
+//! checks for filesystem features this crate can't (fully) parse.
+
+use crate::structures::*;
+
+/// names of the bits set in flags, as NAME|NAME, with any unnamed bits in hex
+pub fn fmt_flags(flags: u64, names: &[(u64, &str)]) -> String {
+    let mut parts: Vec<String> = names
+        .iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| String::from(*name))
+        .collect();
+    let unknown = names.iter().fold(flags, |rest, (bit, _)| rest & !bit);
+    if unknown != 0 {
+        parts.push(format!("{unknown:#x}"));
+    }
+    if parts.is_empty() {
+        String::from("none")
+    } else {
+        parts.join("|")
+    }
+}
+
+pub const SUPER_FLAGS: &[(u64, &str)] = &[
+    (BTRFS_HEADER_FLAG_WRITTEN, "WRITTEN"),
+    (BTRFS_HEADER_FLAG_RELOC, "RELOC"),
+    (BTRFS_SUPER_FLAG_ERROR, "ERROR"),
+    (BTRFS_SUPER_FLAG_SEEDING, "SEEDING"),
+    (BTRFS_SUPER_FLAG_METADUMP, "METADUMP"),
+    (BTRFS_SUPER_FLAG_METADUMP_V2, "METADUMP_V2"),
+    (BTRFS_SUPER_FLAG_CHANGING_FSID, "CHANGING_FSID"),
+    (BTRFS_SUPER_FLAG_CHANGING_FSID_V2, "CHANGING_FSID_V2"),
+    (BTRFS_SUPER_FLAG_CHANGING_BG_TREE, "CHANGING_BG_TREE"),
+    (BTRFS_SUPER_FLAG_CHANGING_DATA_CSUM, "CHANGING_DATA_CSUM"),
+    (BTRFS_SUPER_FLAG_CHANGING_META_CSUM, "CHANGING_META_CSUM"),
+];
+
+pub const COMPAT_RO_FEATURES: &[(u64, &str)] = &[
+    (BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE, "FREE_SPACE_TREE"),
+    (
+        BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID,
+        "FREE_SPACE_TREE_VALID",
+    ),
+    (BTRFS_FEATURE_COMPAT_RO_VERITY, "VERITY"),
+    (BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE, "BLOCK_GROUP_TREE"),
+];
+
+pub const INCOMPAT_FEATURES: &[(u64, &str)] = &[
+    (BTRFS_FEATURE_INCOMPAT_MIXED_BACKREF, "MIXED_BACKREF"),
+    (BTRFS_FEATURE_INCOMPAT_DEFAULT_SUBVOL, "DEFAULT_SUBVOL"),
+    (BTRFS_FEATURE_INCOMPAT_MIXED_GROUPS, "MIXED_GROUPS"),
+    (BTRFS_FEATURE_INCOMPAT_COMPRESS_LZO, "COMPRESS_LZO"),
+    (BTRFS_FEATURE_INCOMPAT_COMPRESS_ZSTD, "COMPRESS_ZSTD"),
+    (BTRFS_FEATURE_INCOMPAT_BIG_METADATA, "BIG_METADATA"),
+    (BTRFS_FEATURE_INCOMPAT_EXTENDED_IREF, "EXTENDED_IREF"),
+    (BTRFS_FEATURE_INCOMPAT_RAID56, "RAID56"),
+    (BTRFS_FEATURE_INCOMPAT_SKINNY_METADATA, "SKINNY_METADATA"),
+    (BTRFS_FEATURE_INCOMPAT_NO_HOLES, "NO_HOLES"),
+    (BTRFS_FEATURE_INCOMPAT_METADATA_UUID, "METADATA_UUID"),
+    (BTRFS_FEATURE_INCOMPAT_RAID1C34, "RAID1C34"),
+    (BTRFS_FEATURE_INCOMPAT_ZONED, "ZONED"),
+    (BTRFS_FEATURE_INCOMPAT_EXTENT_TREE_V2, "EXTENT_TREE_V2"),
+    (BTRFS_FEATURE_INCOMPAT_RAID_STRIPE_TREE, "RAID_STRIPE_TREE"),
+    (BTRFS_FEATURE_INCOMPAT_SIMPLE_QUOTA, "SIMPLE_QUOTA"),
+];
+
+pub const BLOCK_GROUP_TYPES: &[(u64, &str)] = &[
+    (BTRFS_BLOCK_GROUP_DATA, "DATA"),
+    (BTRFS_BLOCK_GROUP_SYSTEM, "SYSTEM"),
+    (BTRFS_BLOCK_GROUP_METADATA, "METADATA"),
+];
+
+pub const BLOCK_GROUP_PROFILES: &[(u64, &str)] = &[
+    (BTRFS_BLOCK_GROUP_RAID0, "RAID0"),
+    (BTRFS_BLOCK_GROUP_RAID1, "RAID1"),
+    (BTRFS_BLOCK_GROUP_DUP, "DUP"),
+    (BTRFS_BLOCK_GROUP_RAID10, "RAID10"),
+    (BTRFS_BLOCK_GROUP_RAID5, "RAID5"),
+    (BTRFS_BLOCK_GROUP_RAID6, "RAID6"),
+    (BTRFS_BLOCK_GROUP_RAID1C3, "RAID1C3"),
+    (BTRFS_BLOCK_GROUP_RAID1C4, "RAID1C4"),
+];
+
+pub const BALANCE_FLAGS: &[(u64, &str)] = &[
+    (BTRFS_BALANCE_DATA, "DATA"),
+    (BTRFS_BALANCE_SYSTEM, "SYSTEM"),
+    (BTRFS_BALANCE_METADATA, "METADATA"),
+    (BTRFS_BALANCE_FORCE, "FORCE"),
+    (BTRFS_BALANCE_RESUME, "RESUME"),
+];
+
+pub const BALANCE_ARGS_FLAGS: &[(u64, &str)] = &[
+    (BTRFS_BALANCE_ARGS_PROFILES, "PROFILES"),
+    (BTRFS_BALANCE_ARGS_USAGE, "USAGE"),
+    (BTRFS_BALANCE_ARGS_DEVID, "DEVID"),
+    (BTRFS_BALANCE_ARGS_DRANGE, "DRANGE"),
+    (BTRFS_BALANCE_ARGS_VRANGE, "VRANGE"),
+    (BTRFS_BALANCE_ARGS_LIMIT, "LIMIT"),
+    (BTRFS_BALANCE_ARGS_LIMIT_RANGE, "LIMIT_RANGE"),
+    (BTRFS_BALANCE_ARGS_STRIPES_RANGE, "STRIPES_RANGE"),
+    (BTRFS_BALANCE_ARGS_CONVERT, "CONVERT"),
+    (BTRFS_BALANCE_ARGS_SOFT, "SOFT"),
+    (BTRFS_BALANCE_ARGS_USAGE_RANGE, "USAGE_RANGE"),
+];
+
+pub const QGROUP_STATUS_FLAGS: &[(u64, &str)] = &[
+    (BTRFS_QGROUP_STATUS_FLAG_ON, "ON"),
+    (BTRFS_QGROUP_STATUS_FLAG_RESCAN, "RESCAN"),
+    (BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT, "INCONSISTENT"),
+    (BTRFS_QGROUP_STATUS_FLAG_SIMPLE_MODE, "SIMPLE_MODE"),
+];
+
+pub const FREE_SPACE_INFO_FLAGS: &[(u64, &str)] =
+    &[(BTRFS_FREE_SPACE_USING_BITMAPS as u64, "USING_BITMAPS")];
+
+/// chunk or block group type flags as e.g. DATA|RAID1 or METADATA|SINGLE
+pub fn fmt_block_group_type(flags: u64) -> String {
+    let types: Vec<(u64, &str)> = BLOCK_GROUP_TYPES
+        .iter()
+        .chain(BLOCK_GROUP_PROFILES)
+        .copied()
+        .collect();
+    let profile_bits = BLOCK_GROUP_PROFILES
+        .iter()
+        .fold(0, |all, (bit, _)| all | bit);
+    let names = fmt_flags(flags, &types);
+    if flags & profile_bits == 0 {
+        format!("{names}|SINGLE")
+    } else {
+        names
+    }
+}
+
+/// incompat features which are known, but which change the on-disk format
+/// in ways this crate doesn't understand yet, with what will go wrong
+const UNSUPPORTED_INCOMPAT: &[(u64, &str)] = &[
+    (
+        BTRFS_FEATURE_INCOMPAT_RAID56,
+        "RAID5/6 chunks cannot be mapped to devices",
+    ),
+    (
+        BTRFS_FEATURE_INCOMPAT_METADATA_UUID,
+        "tree block fsids are the metadata_uuid, not the fsid",
+    ),
+    (
+        BTRFS_FEATURE_INCOMPAT_EXTENT_TREE_V2,
+        "global roots are read, but the extent, csum and free space trees are never rewritten",
+    ),
+];
+
+/// one message per feature of the filesystem this crate can't fully parse.
+/// Unknown incompat bits are reported too, as they may change anything.
+pub fn unsupported_features(sb: &btrfs_super_block) -> Vec<String> {
+    let incompat = sb.incompat_flags;
+    let mut problems: Vec<String> = UNSUPPORTED_INCOMPAT
+        .iter()
+        .filter(|(bit, _)| incompat & bit != 0)
+        .map(|(bit, what)| {
+            format!(
+                "{} feature is not supported: {what}",
+                fmt_flags(*bit, INCOMPAT_FEATURES)
+            )
+        })
+        .collect();
+    let unknown = INCOMPAT_FEATURES
+        .iter()
+        .fold(incompat, |rest, (bit, _)| rest & !bit);
+    if unknown != 0 {
+        problems.push(format!("unknown incompat feature bits {unknown:#x}"));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_names() {
+        assert_eq!(fmt_flags(0, INCOMPAT_FEATURES), "none");
+        assert_eq!(
+            fmt_flags(0x341, INCOMPAT_FEATURES),
+            "MIXED_BACKREF|EXTENDED_IREF|SKINNY_METADATA|NO_HOLES"
+        );
+        assert_eq!(
+            fmt_flags(BTRFS_FEATURE_COMPAT_RO_VERITY | 1 << 40, COMPAT_RO_FEATURES),
+            "VERITY|0x10000000000"
+        );
+        assert_eq!(fmt_block_group_type(BTRFS_BLOCK_GROUP_DATA), "DATA|SINGLE");
+        assert_eq!(
+            fmt_block_group_type(BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP),
+            "METADATA|DUP"
+        );
+        assert_eq!(
+            fmt_block_group_type(
+                BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_RAID1C3
+            ),
+            "DATA|METADATA|RAID1C3"
+        );
+    }
+}
@@ -0,0 +1,476 @@
+//! Cross-check of the recorded free space against the extent tree: the free
+//! space of each block group is what no EXTENT_ITEM or METADATA_ITEM covers,
+//! less the superblock copies in it, and should be exactly what its
+//! FREE_SPACE_EXTENT or FREE_SPACE_BITMAP items in the free space tree
+//! (space_cache=v2) record, or on older filesystems its v1 cache file.
+//!
+//! Space recorded free which the extent tree has allocated is the dangerous
+//! case: the kernel would allocate it again, over live data or metadata.
+//! Free space missing from the record is only lost until it is rebuilt.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::edit::{change_features, edit_super, super_mirrors, FeatureChange, SuperCopy};
+use crate::repair::RepairOptions;
+use crate::space::{block_groups, stripe_length};
+use crate::space_cache::space_caches;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+pub struct BlockGroupFreeSpace {
+    pub start: u64,
+    pub length: u64,
+    /// bytes free by the extent tree
+    pub expected: u64,
+    /// bytes recorded free, None if nothing could be read for the block
+    /// group
+    pub recorded: Option<u64>,
+    /// (start, end) ranges free by the extent tree but not recorded free
+    pub unrecorded: Vec<(u64, u64)>,
+    /// (start, end) ranges recorded free which are allocated or excluded
+    pub allocated: Vec<(u64, u64)>,
+    /// a wrong extent count or USING_BITMAPS flag, or a malformed item
+    pub problems: Vec<String>,
+    /// why the kernel would discard a v1 cache rather than trust it
+    pub stale: Vec<String>,
+}
+
+impl BlockGroupFreeSpace {
+    pub fn consistent(&self) -> bool {
+        self.recorded.is_some()
+            && self.unrecorded.is_empty()
+            && self.allocated.is_empty()
+            && self.problems.is_empty()
+    }
+
+    /// whether the kernel would use the recorded free space, so that it
+    /// matters whether it is consistent
+    pub fn trusted(&self) -> bool {
+        self.stale.is_empty()
+    }
+}
+
+/// where the free space is recorded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreeSpaceSource {
+    /// the free space tree, and whether FREE_SPACE_TREE_VALID is set, i.e.
+    /// the kernel trusts it rather than rebuilding it at the next mount
+    Tree { valid: bool },
+    /// v1 cache files
+    Cache,
+}
+
+pub struct FreeSpaceCheck {
+    pub source: FreeSpaceSource,
+    pub groups: Vec<BlockGroupFreeSpace>,
+    /// items outside every block group
+    pub problems: Vec<String>,
+}
+
+impl FreeSpaceCheck {
+    pub fn consistent(&self) -> bool {
+        self.problems.is_empty()
+            && self
+                .groups
+                .iter()
+                .all(|group| group.consistent() || !group.trusted())
+    }
+}
+
+/// merge sorted (start, end) ranges which overlap or touch
+pub(crate) fn merge_ranges(ranges: impl IntoIterator<Item = (u64, u64)>) -> Vec<(u64, u64)> {
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// the parts of merged ranges a which no merged range of b covers
+fn subtract_ranges(a: &[(u64, u64)], b: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut rest = Vec::new();
+    let mut b = b.iter().peekable();
+    for &(mut start, end) in a {
+        while let Some(&&(b_start, b_end)) = b.peek() {
+            if b_end <= start {
+                b.next();
+                continue;
+            }
+            if b_start >= end {
+                break;
+            }
+            if b_start > start {
+                rest.push((start, b_start));
+            }
+            start = start.max(b_end);
+            if b_end > end {
+                break;
+            }
+            b.next();
+        }
+        if start < end {
+            rest.push((start, end));
+        }
+    }
+    rest
+}
+
+/// the runs of set bits in the bitmap of a FREE_SPACE_BITMAP at start, one
+/// bit per sector, as (start, end) ranges
+pub(crate) fn bitmap_ranges(start: u64, sectorsize: u64, bitmap: &[u8]) -> Vec<(u64, u64)> {
+    let bits = (0..bitmap.len() * 8).filter(|bit| bitmap[bit / 8] & (1 << (bit % 8)) != 0);
+    merge_ranges(bits.map(|bit| {
+        let at = start + bit as u64 * sectorsize;
+        (at, at + sectorsize)
+    }))
+}
+
+/// the ranges of a block group the kernel excludes from its free space
+/// because superblock copies are stored there, as btrfs_rmap_block maps
+/// them: the whole 64KiB stripe (or full RAID5/6 stripe) holding a copy
+fn super_stripes(chunk: &ChunkInfo) -> Vec<(u64, u64)> {
+    let ChunkInfo(key, chunk, stripes) = chunk;
+    let (start, length) = (key.offset, chunk.length);
+    let chunk_type = chunk.r#type;
+    let num_stripes = (chunk.num_stripes as u64).max(1);
+    let sub_stripes = (chunk.sub_stripes as u64).max(1);
+    let dev_length = stripe_length(chunk);
+    let mut ranges = Vec::new();
+    if start < BTRFS_SUPER_INFO_OFFSET as u64 {
+        ranges.push((start, BTRFS_SUPER_INFO_OFFSET as u64));
+    }
+    for (index, stripe) in stripes.iter().enumerate() {
+        let index = index as u64;
+        let stripe_offset = stripe.offset;
+        for physical in super_mirrors(u64::MAX) {
+            if physical < stripe_offset || physical >= stripe_offset + dev_length {
+                continue;
+            }
+            let mut stripe_nr = (physical - stripe_offset) / BTRFS_STRIPE_LEN;
+            let mut io_length = BTRFS_STRIPE_LEN;
+            if chunk_type & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+                stripe_nr = stripe_nr * (num_stripes / sub_stripes) + index / sub_stripes;
+            } else if chunk_type & BTRFS_BLOCK_GROUP_RAID0 != 0 {
+                stripe_nr = stripe_nr * num_stripes + index;
+            } else if chunk_type & BTRFS_BLOCK_GROUP_RAID5 != 0 {
+                io_length *= num_stripes.saturating_sub(1).max(1);
+            } else if chunk_type & BTRFS_BLOCK_GROUP_RAID6 != 0 {
+                io_length *= num_stripes.saturating_sub(2).max(1);
+            }
+            let logical = start + stripe_nr * io_length;
+            ranges.push((logical, (logical + io_length).min(start + length)));
+        }
+    }
+    ranges.sort();
+    merge_ranges(ranges)
+}
+
+/// what is recorded free for one block group
+#[derive(Default)]
+struct Recorded {
+    found: bool,
+    ranges: Vec<(u64, u64)>,
+    problems: Vec<String>,
+    stale: Vec<String>,
+}
+
+/// the free space tree's record of each block group, by start
+fn tree_recorded(
+    fs: &FsInfo,
+    roots: &[(u64, u64)],
+    groups: &[crate::space::BlockGroup],
+    problems: &mut Vec<String>,
+) -> BTreeMap<u64, Recorded> {
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let mut recorded: BTreeMap<u64, Recorded> = groups
+        .iter()
+        .map(|group| (group.start, Recorded::default()))
+        .collect();
+    // the info item of each block group, and its extent and bitmap items
+    let mut infos = BTreeMap::<u64, (btrfs_free_space_info, usize, usize)>::new();
+    let items = roots
+        .iter()
+        .flat_map(|&(_, root)| search_range(fs, root, key_range(None, None, None)));
+    for (item, data, _block_offset, _slot) in items {
+        let key = item.key;
+        let (start, length) = (key.objectid, key.offset);
+        let group = groups
+            .iter()
+            .find(|group| start >= group.start && start + length <= group.start + group.length);
+        let Some(group) = group else {
+            problems.push(format!(
+                "{:?} {start}..{} is in no block group",
+                key.item_type,
+                start + length
+            ));
+            continue;
+        };
+        let entry = recorded.entry(group.start).or_default();
+        match key.item_type {
+            BtrfsItemType::FREE_SPACE_INFO => {
+                if start != group.start || length != group.length {
+                    entry.problems.push(format!(
+                        "info item for {start}..{}, not the whole block group",
+                        start + length
+                    ));
+                }
+                match crate::items::item_as::<btrfs_free_space_info>(&data) {
+                    Some(info) => {
+                        entry.found = true;
+                        infos.entry(group.start).or_insert((*info, 0, 0)).0 = *info;
+                    }
+                    None => entry.problems.push(String::from("info item too short")),
+                }
+            }
+            BtrfsItemType::FREE_SPACE_EXTENT => {
+                if let Some(info) = infos.get_mut(&group.start) {
+                    info.1 += 1;
+                }
+                entry.ranges.push((start, start + length));
+            }
+            BtrfsItemType::FREE_SPACE_BITMAP => {
+                if let Some(info) = infos.get_mut(&group.start) {
+                    info.2 += 1;
+                }
+                let need = (length / sectorsize).div_ceil(8) as usize;
+                if data.len() != need {
+                    entry.problems.push(format!(
+                        "bitmap at {start} of {} bytes, {need} needed for its {} sectors",
+                        data.len(),
+                        length / sectorsize
+                    ));
+                }
+                let end = start + length;
+                entry.ranges.extend(
+                    bitmap_ranges(start, sectorsize, &data)
+                        .into_iter()
+                        .filter(|range| range.0 < end)
+                        .map(|(from, to)| (from, to.min(end))),
+                );
+            }
+            _ => problems.push(format!(
+                "unexpected item {:?} at {start} in the free space tree",
+                key.item_type
+            )),
+        }
+    }
+    for (start, entry) in &mut recorded {
+        let Some(&(info, extents, bitmaps)) = infos.get(start) else {
+            entry.problems.push(String::from("no info item"));
+            continue;
+        };
+        entry.ranges.sort();
+        let runs = merge_ranges(entry.ranges.iter().copied()).len();
+        let extent_count = info.extent_count as usize;
+        if extent_count != runs {
+            entry.problems.push(format!(
+                "info item counts {extent_count} free extents, {runs} recorded"
+            ));
+        }
+        let using_bitmaps = info.flags & BTRFS_FREE_SPACE_USING_BITMAPS != 0;
+        if using_bitmaps && extents > 0 {
+            entry
+                .problems
+                .push(format!("USING_BITMAPS, but {extents} extent items"));
+        }
+        if !using_bitmaps && bitmaps > 0 {
+            entry
+                .problems
+                .push(format!("{bitmaps} bitmap items without USING_BITMAPS"));
+        }
+    }
+    recorded
+}
+
+/// the v1 caches' record of each block group, by start
+fn cache_recorded(fs: &FsInfo, problems: &mut Vec<String>) -> BTreeMap<u64, Recorded> {
+    let mut recorded = BTreeMap::new();
+    for cache in space_caches(fs) {
+        let entry = Recorded {
+            found: cache.free.is_some(),
+            ranges: cache.free.unwrap_or_default(),
+            problems: Vec::new(),
+            stale: cache.stale,
+        };
+        if recorded.insert(cache.block_group, entry).is_some() {
+            problems.push(format!(
+                "more than one cache for block group {}",
+                cache.block_group
+            ));
+        }
+    }
+    recorded
+}
+
+/// derive the free space of every block group from the extent tree and
+/// compare it with the free space tree, or without one the v1 caches
+pub fn check_free_space(fs: &FsInfo) -> Result<FreeSpaceCheck> {
+    let extent_roots = global_roots(fs, BTRFS_EXTENT_TREE_OBJECTID);
+    ensure!(!extent_roots.is_empty(), "extent tree not found");
+    let nodesize = fs.master_sb.nodesize as u64;
+    let groups = block_groups(fs)?;
+    let mut problems = Vec::new();
+    let free_space_roots = global_roots(fs, BTRFS_FREE_SPACE_TREE_OBJECTID);
+    let (source, mut recorded) = if !free_space_roots.is_empty() {
+        let compat_ro = fs.master_sb.compat_ro_flags;
+        let valid = compat_ro & BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID != 0;
+        (
+            FreeSpaceSource::Tree { valid },
+            tree_recorded(fs, &free_space_roots, &groups, &mut problems),
+        )
+    } else {
+        let recorded = cache_recorded(fs, &mut problems);
+        ensure!(
+            !recorded.is_empty(),
+            "there is neither a free space tree nor a v1 free space cache"
+        );
+        (FreeSpaceSource::Cache, recorded)
+    };
+    if source == FreeSpaceSource::Cache {
+        for start in recorded.keys() {
+            if !groups.iter().any(|group| group.start == *start) {
+                problems.push(format!("cache for {start}, which is no block group"));
+            }
+        }
+    }
+
+    // every allocated extent, in order
+    let mut allocated = Vec::new();
+    let search = key_range(None, None, None);
+    let items = extent_roots
+        .iter()
+        .flat_map(|&(_, extent_root)| search_range(fs, extent_root, search));
+    for (item, _data, _block_offset, _slot) in items {
+        let key = item.key;
+        match key.item_type {
+            BtrfsItemType::EXTENT_ITEM => allocated.push((key.objectid, key.objectid + key.offset)),
+            BtrfsItemType::METADATA_ITEM => allocated.push((key.objectid, key.objectid + nodesize)),
+            _ => {}
+        }
+    }
+    // the global roots of extent tree v2 each hold their own block groups
+    allocated.sort();
+    let allocated = merge_ranges(allocated);
+
+    let total = |ranges: &[(u64, u64)]| -> u64 { ranges.iter().map(|(from, to)| to - from).sum() };
+    let mut checked = Vec::new();
+    for group in &groups {
+        ensure!(!fs.cancel.is_cancelled(), "interrupted");
+        let end = group.start + group.length;
+        let mut used: Vec<(u64, u64)> = allocated
+            .iter()
+            .filter(|range| range.1 > group.start && range.0 < end)
+            .map(|&(from, to)| (from.max(group.start), to.min(end)))
+            .collect();
+        let supers = match find_chunk(fs, group.start) {
+            Some(chunk) => super_stripes(&chunk),
+            None => Vec::new(),
+        };
+        used.extend(supers.iter().copied());
+        used.sort();
+        let used = merge_ranges(used);
+        let expected = subtract_ranges(&[(group.start, end)], &used);
+
+        let mut entry = recorded.remove(&group.start).unwrap_or_default();
+        entry.ranges.sort();
+        let ranges = merge_ranges(entry.ranges.iter().copied());
+        if source == FreeSpaceSource::Cache {
+            // the kernel's own check on loading a cache
+            let should = group.length - group.used - total(&supers);
+            if !entry.found && entry.stale.is_empty() {
+                entry.stale.push(String::from(
+                    "no cache: the kernel finds the free space from the extent tree",
+                ));
+            } else if entry.found && total(&ranges) != should {
+                entry.stale.push(format!(
+                    "the cache holds {} free, the block group item leaves {should}",
+                    total(&ranges)
+                ));
+            }
+        }
+        checked.push(BlockGroupFreeSpace {
+            start: group.start,
+            length: group.length,
+            expected: total(&expected),
+            recorded: entry.found.then(|| total(&ranges)),
+            unrecorded: subtract_ranges(&expected, &ranges),
+            allocated: subtract_ranges(&ranges, &expected),
+            problems: entry.problems,
+            stale: entry.stale,
+        });
+    }
+    Ok(FreeSpaceCheck {
+        source,
+        groups: checked,
+        problems,
+    })
+}
+
+/// what would make the kernel rebuild the recorded free space, and the
+/// superblock copies it would rewrite
+pub enum FreeSpaceFix {
+    /// clear FREE_SPACE_TREE_VALID, so that the kernel rebuilds the free
+    /// space tree from the extent tree at the next read-write mount
+    ClearTreeValid(FeatureChange),
+    /// set the superblock's cache_generation to -1, as btrfs check
+    /// --clear-space-cache v1 leaves it, so that the kernel discards every
+    /// cache and writes them afresh
+    InvalidateCaches(Vec<SuperCopy>),
+}
+
+/// the fix for free space the kernel would trust which doesn't match the
+/// extent tree, if there is any. Nothing is written.
+pub fn plan_free_space_fix(fs: &FsInfo, check: &FreeSpaceCheck) -> Result<Option<FreeSpaceFix>> {
+    if check.consistent() {
+        return Ok(None);
+    }
+    let options = RepairOptions {
+        dry_run: true,
+        backup_dir: PathBuf::new(),
+    };
+    match check.source {
+        FreeSpaceSource::Tree { valid: false } => Ok(None),
+        FreeSpaceSource::Tree { valid: true } => {
+            let clear = [String::from("free-space-tree-valid")];
+            let change = change_features(fs, &[], &clear, &options)?;
+            Ok(Some(FreeSpaceFix::ClearTreeValid(change)))
+        }
+        FreeSpaceSource::Cache => {
+            let edit = (String::from("cache_generation"), u64::MAX.to_string());
+            let copies = edit_super(fs, &[edit], &options)?;
+            Ok(Some(FreeSpaceFix::InvalidateCaches(copies)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(
+            merge_ranges([(0, 4), (4, 8), (6, 7), (10, 12)]),
+            vec![(0, 8), (10, 12)]
+        );
+        let a = [(0, 100), (200, 300)];
+        let b = [(10, 20), (90, 210), (250, 260)];
+        assert_eq!(
+            subtract_ranges(&a, &b),
+            vec![(0, 10), (20, 90), (210, 250), (260, 300)]
+        );
+        assert_eq!(subtract_ranges(&b, &a), vec![(100, 200)]);
+        // sectors 0, 1, 3 and 8 free
+        assert_eq!(
+            bitmap_ranges(4096, 512, &[0b1011, 0b1]),
+            vec![(4096, 5120), (5632, 6144), (8192, 8704)]
+        );
+    }
+}
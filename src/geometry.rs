@@ -0,0 +1,471 @@
+//! Loading a filesystem none of whose superblocks is left, from what the
+//! user knows of it: the node and sector size, the checksum type and the
+//! logical address of the chunk tree's root (e.g. from an old `btrfs
+//! inspect-internal dump-super`, or an `orphans` listing of a similar
+//! filesystem).
+//!
+//! The chunk root is looked for on each device to learn where its system
+//! chunk lies; the chunk tree then maps everything else and its stripes tell
+//! which device is which. The root tree's root, unless given, is the newest
+//! root tree block in the metadata chunks. The superblock so made up is
+//! never written: devices loaded this way are read-only.
+
+use crate::address::*;
+use crate::block_cache::{block_cache_size, BlockCache};
+use crate::btrfs::*;
+use crate::btrfs_node::as_bytes;
+use crate::cancel::CancellationToken;
+use crate::items::item_as;
+use crate::mapped_file::MappedFile;
+use crate::parse::parse_u64;
+use crate::quarantine::Quarantine;
+use crate::scrub::check_tree_block;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use log::*;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// what the superblock would have said
+#[derive(Clone, Debug)]
+pub struct Geometry {
+    pub nodesize: u32,
+    pub sectorsize: u32,
+    pub csum_type: BtrfsCsumType,
+    /// logical address of the chunk tree's root
+    pub chunk_root: u64,
+    /// logical address of the root tree's root, looked for if None
+    pub root: Option<u64>,
+}
+
+impl Geometry {
+    /// read `name = value` lines, names being nodesize, sectorsize,
+    /// csum_type, chunk_root and root; a later line for a name overrides an
+    /// earlier one, and # starts a comment. chunk_root is required, the node
+    /// and sector size default to mkfs's 16KiB and 4KiB.
+    pub fn parse(text: &str) -> Result<Geometry> {
+        let mut geometry = Geometry {
+            nodesize: 16384,
+            sectorsize: 4096,
+            csum_type: BtrfsCsumType::CRC32,
+            chunk_root: 0,
+            root: None,
+        };
+        let mut chunk_root = None;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected name = value, not {line:?}"))?;
+            let (name, value) = (name.trim(), value.trim());
+            let size = |value: &str| -> Result<u32> {
+                let size = u32::try_from(parse_u64(value)?)?;
+                ensure!(
+                    size.is_power_of_two() && size >= 4096,
+                    "{name} {size} is not a power of two of at least 4096"
+                );
+                Ok(size)
+            };
+            match name {
+                "nodesize" => geometry.nodesize = size(value)?,
+                "sectorsize" => geometry.sectorsize = size(value)?,
+                "csum_type" => {
+                    geometry.csum_type = match value.to_ascii_lowercase().as_str() {
+                        "crc32c" | "crc32" | "0" => BtrfsCsumType::CRC32,
+                        "xxhash" | "xxhash64" | "1" => BtrfsCsumType::XXHASH,
+                        "sha256" | "2" => BtrfsCsumType::SHA256,
+                        "blake2" | "blake2b" | "3" => BtrfsCsumType::BLAKE2,
+                        _ => bail!("unknown checksum type {value:?}"),
+                    }
+                }
+                "chunk_root" => chunk_root = Some(parse_u64(value)?),
+                "root" => geometry.root = Some(parse_u64(value)?),
+                _ => bail!("unknown geometry {name:?}"),
+            }
+        }
+        geometry.chunk_root = chunk_root.ok_or_else(|| anyhow!("chunk_root must be given"))?;
+        ensure!(
+            geometry.nodesize >= geometry.sectorsize,
+            "the nodesize can't be smaller than the sectorsize"
+        );
+        ensure!(
+            geometry.chunk_root.is_multiple_of(geometry.nodesize as u64),
+            "chunk_root {} is not a multiple of the nodesize",
+            geometry.chunk_root
+        );
+        Ok(geometry)
+    }
+}
+
+/// the physical offset of the first copy of the tree block at logical on
+/// the device, found by reading every sector sized slot
+fn find_block(
+    mf: &MappedFile,
+    geometry: &Geometry,
+    logical: u64,
+    owner: u64,
+) -> Result<Option<u64>> {
+    let nodesize = geometry.nodesize as usize;
+    let step = geometry.sectorsize as usize;
+    let header_size = std::mem::size_of::<btrfs_header>();
+    let mut physical = 0;
+    while physical + nodesize <= mf.len() {
+        let header = mf.slice(physical, header_size)?;
+        let header = unsafe { &*(header.as_ptr() as *const btrfs_header) };
+        let candidate = header.bytenr == logical && header.owner == owner;
+        if candidate {
+            let block = mf.slice(physical, nodesize)?;
+            if header.csum == csum_data(&block[BTRFS_CSUM_SIZE..], geometry.csum_type) {
+                return Ok(Some(physical as u64));
+            }
+        }
+        physical += step;
+    }
+    Ok(None)
+}
+
+fn fs_info(sb: btrfs_super_block, devices: Vec<DeviceInfo>, chunks: Vec<ChunkInfo>) -> FsInfo {
+    let mut devid_map = HashMap::new();
+    let mut devuuid_map = HashMap::new();
+    for device in devices {
+        let device = Arc::new(device);
+        devid_map.insert(device.devid, Arc::clone(&device));
+        devuuid_map.insert(device.dev_uuid, device);
+    }
+    FsInfo {
+        fsid: sb.fsid,
+        devid_map,
+        devuuid_map,
+        master_sb: sb,
+        bootstrap_chunks: chunks,
+        chunk_cache: RwLock::new(BTreeMap::new()),
+        seed_fsids: Vec::new(),
+        chunk_map: false,
+        cancel: CancellationToken::new(),
+        pinned_generation: None,
+        root_cache: RwLock::new(HashMap::new()),
+        block_cache: BlockCache::new(block_cache_size(), sb.nodesize as usize),
+        csum_policy: default_csum_policy(),
+        quarantine: Quarantine::default(),
+    }
+}
+
+fn device(path: PathBuf, offset: u64, file: MappedFile, devid: u64, uuid: BtrfsUuid) -> DeviceInfo {
+    DeviceInfo {
+        path,
+        offset,
+        file,
+        devid,
+        dev_uuid: uuid,
+        seed: false,
+        sb_guessed: true,
+        generations_behind: 0,
+        zone_size: None,
+    }
+}
+
+/// load the filesystem on the devices at paths as load_fs would, but from
+/// the geometry given instead of a superblock
+pub fn load_fs_geometry(paths: &[PathBuf], geometry: &Geometry) -> Result<FsInfo> {
+    ensure!(
+        geometry.csum_type == BtrfsCsumType::CRC32,
+        "checksum type {:?} is not supported, only crc32c is implemented",
+        geometry.csum_type
+    );
+    let chunk_root = geometry.chunk_root;
+    let mut opened = Vec::new();
+    for arg in paths {
+        info!("checking {} for chunk root {chunk_root}", arg.display());
+        let (path, offset, mf) = open_device(arg)?;
+        let found = find_block(&mf, geometry, chunk_root, BTRFS_CHUNK_TREE_OBJECTID)?;
+        opened.push((path, offset, mf, found));
+    }
+    let Some(first) = opened.iter().position(|(.., found)| found.is_some()) else {
+        bail!("no device holds a tree block of the chunk tree at {chunk_root}");
+    };
+
+    let mut sb: btrfs_super_block = unsafe { std::mem::zeroed() };
+    sb.magic = BTRFS_MAGIC;
+    sb.nodesize = geometry.nodesize;
+    sb.sectorsize = geometry.sectorsize;
+    sb.stripesize = geometry.sectorsize;
+    sb.csum_type = geometry.csum_type;
+    sb.chunk_root = chunk_root;
+    {
+        let (_, _, mf, found) = &opened[first];
+        let header = mf.at::<btrfs_header>(found.unwrap() as usize)?;
+        sb.fsid = header.fsid;
+        sb.metadata_uuid = header.fsid;
+        sb.chunk_root_level = header.level;
+        sb.chunk_root_generation = header.generation;
+    }
+
+    // until the chunk tree is read, map its root's device from the start as
+    // if it were all one system chunk, which holds for the chunk it is in
+    let (path, offset, mf, found) = opened.remove(first);
+    let physical = found.unwrap();
+    let start = chunk_root.saturating_sub(physical);
+    let stripe_offset = physical - (chunk_root - start);
+    let provisional = ChunkInfo(
+        btrfs_disk_key {
+            objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+            item_type: BtrfsItemType::CHUNK_ITEM,
+            offset: start,
+        },
+        btrfs_chunk {
+            length: mf.len() as u64 - stripe_offset,
+            owner: BTRFS_EXTENT_TREE_OBJECTID,
+            stripe_len: 65536,
+            r#type: BTRFS_BLOCK_GROUP_SYSTEM,
+            io_align: geometry.sectorsize,
+            io_width: geometry.sectorsize,
+            sector_size: geometry.sectorsize,
+            num_stripes: 1,
+            sub_stripes: 1,
+        },
+        vec![btrfs_stripe {
+            devid: 1,
+            offset: stripe_offset,
+            dev_uuid: BtrfsUuid::default(),
+        }],
+    );
+    let fs = fs_info(
+        sb,
+        vec![device(path, offset, mf, 1, BtrfsUuid::default())],
+        vec![provisional],
+    );
+    let search = key_range(
+        Some(BTRFS_FIRST_CHUNK_TREE_OBJECTID),
+        Some(BtrfsItemType::CHUNK_ITEM),
+        None,
+    );
+    let system: Vec<ChunkInfo> = search_range(&fs, chunk_root, search)
+        .map(|(item, data, ..)| chunk_item_info(&item, &data))
+        .filter(|ChunkInfo(_, chunk, _)| chunk.r#type & BTRFS_BLOCK_GROUP_SYSTEM != 0)
+        .collect();
+    let search = key_range(
+        Some(BTRFS_DEV_ITEMS_OBJECTID),
+        Some(BtrfsItemType::DEV_ITEM),
+        None,
+    );
+    let dev_items: Vec<btrfs_dev_item> = search_range(&fs, chunk_root, search)
+        .filter_map(|(_, data, ..)| item_as::<btrfs_dev_item>(&data).copied())
+        .collect();
+    let Some(ChunkInfo(key, _, stripes)) = system
+        .iter()
+        .find(|ChunkInfo(key, chunk, _)| {
+            (key.offset..key.offset + chunk.length).contains(&chunk_root)
+        })
+        .cloned()
+    else {
+        bail!("the chunk tree has no system chunk containing its root {chunk_root}");
+    };
+    ensure!(!dev_items.is_empty(), "the chunk tree has no dev items");
+
+    // a device holding a copy of the chunk root is one whose stripe of the
+    // system chunk puts it there. Mirrored stripes at the same offset can
+    // only be told apart by the device sizes the dev items record.
+    let FsInfo {
+        devid_map,
+        devuuid_map,
+        ..
+    } = fs;
+    drop(devuuid_map);
+    let provisional = Arc::into_inner(devid_map.into_values().next().unwrap()).unwrap();
+    opened.insert(
+        first,
+        (
+            provisional.path,
+            provisional.offset,
+            provisional.file,
+            Some(physical),
+        ),
+    );
+    let within = chunk_root - key.offset;
+    let mut devices: Vec<DeviceInfo> = Vec::new();
+    for (path, offset, mf, found) in opened {
+        let len = mf.len() as u64;
+        let size_matches = |devid: u64| {
+            dev_items
+                .iter()
+                .any(|dev| dev.devid == devid && dev.total_bytes == len)
+        };
+        let mut candidates: Vec<&btrfs_stripe> = stripes
+            .iter()
+            .filter(|stripe| Some(stripe.offset + within) == found)
+            .filter(|stripe| !devices.iter().any(|d| d.devid == stripe.devid))
+            .collect();
+        if candidates.iter().any(|stripe| size_matches(stripe.devid)) {
+            candidates.retain(|stripe| size_matches(stripe.devid));
+        }
+        let Some(stripe) = candidates.first() else {
+            warn!(
+                "{} holds no copy of the chunk root where a stripe puts one, so which device it is isn't known; leaving it out",
+                path.display()
+            );
+            continue;
+        };
+        if candidates.len() > 1 {
+            let devids: Vec<String> = candidates.iter().map(|s| { s.devid }.to_string()).collect();
+            warn!(
+                "{} could be devid {}; taking devid {} in the order the devices were given",
+                path.display(),
+                devids.join(" or "),
+                { stripe.devid }
+            );
+        }
+        let (devid, uuid) = (stripe.devid, stripe.dev_uuid);
+        devices.push(device(path, offset, mf, devid, uuid));
+    }
+    ensure!(
+        !devices.is_empty(),
+        "no stripe of the system chunk at {} puts the chunk root where it was found",
+        { key.offset }
+    );
+
+    let mut array = Vec::new();
+    for ChunkInfo(key, chunk, stripes) in &system {
+        let size = std::mem::size_of_val(key)
+            + std::mem::size_of_val(chunk)
+            + stripes.len() * std::mem::size_of::<btrfs_stripe>();
+        ensure!(
+            array.len() + size <= BTRFS_SYSTEM_CHUNK_ARRAY_SIZE,
+            "the system chunks don't fit in a superblock's sys_chunk_array ({BTRFS_SYSTEM_CHUNK_ARRAY_SIZE} bytes)"
+        );
+        array.extend_from_slice(as_bytes(key));
+        array.extend_from_slice(as_bytes(chunk));
+        for stripe in stripes {
+            array.extend_from_slice(as_bytes(stripe));
+        }
+    }
+    sb.sys_chunk_array[..array.len()].copy_from_slice(&array);
+    sb.sys_chunk_array_size = array.len() as u32;
+    sb.num_devices = dev_items.len() as u64;
+    sb.total_bytes = dev_items.iter().map(|dev| dev.total_bytes).sum();
+    sb.bytes_used = dev_items.iter().map(|dev| dev.bytes_used).sum();
+    let own_devid = devices[0].devid;
+    sb.dev_item = *dev_items
+        .iter()
+        .find(|dev| dev.devid == own_devid)
+        .ok_or_else(|| anyhow!("devid {own_devid} has no dev item"))?;
+    for dev in &dev_items {
+        let devid = dev.devid;
+        if !devices.iter().any(|d| d.devid == devid) {
+            warn!("devid {devid} not found among the devices given");
+        }
+    }
+    let mut fs = fs_info(sb, devices, system);
+
+    let (root, level, generation) = match geometry.root {
+        Some(root) => {
+            let nodesize = geometry.nodesize as u64;
+            ensure!(
+                root.is_multiple_of(nodesize),
+                "root {root} is not aligned to the node size {nodesize}"
+            );
+            let header = load_virt::<btrfs_header>(&fs, root)?;
+            (root, header.level, header.generation)
+        }
+        None => newest_root_tree(&fs)?,
+    };
+    info!("root tree at {root}, level {level}, generation {generation}");
+    fs.master_sb.root = root;
+    fs.master_sb.root_level = level;
+    fs.master_sb.generation = generation;
+    fs.invalidate_roots();
+    // the trees present tell the features which decide where the block
+    // group items are and whether the free space tree is used
+    for (tree, flag) in [
+        (
+            BTRFS_FREE_SPACE_TREE_OBJECTID,
+            BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE,
+        ),
+        (
+            BTRFS_BLOCK_GROUP_TREE_OBJECTID,
+            BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE,
+        ),
+    ] {
+        if tree_root_offset(&fs, tree).is_some() {
+            fs.master_sb.compat_ro_flags |= flag;
+        }
+    }
+    Ok(fs)
+}
+
+/// the root tree block with the highest generation (and the highest level
+/// within it) in the metadata chunks. Blocks of striped chunks are read
+/// with read_logical; those which can't be read at all are counted and
+/// passed over.
+fn newest_root_tree(fs: &FsInfo) -> Result<(u64, u8, u64)> {
+    let nodesize = fs.master_sb.nodesize as u64;
+    let mut newest: Option<(u64, u8, u64)> = None;
+    let mut unreadable = 0;
+    for ChunkInfo(key, chunk, _) in all_chunks(fs) {
+        let chunk_type = chunk.r#type;
+        if chunk_type & BTRFS_BLOCK_GROUP_METADATA == 0 {
+            continue;
+        }
+        let start = key.offset;
+        let end = start + chunk.length;
+        for logical in (start.next_multiple_of(nodesize)..end).step_by(nodesize as usize) {
+            if logical + nodesize > end {
+                break;
+            }
+            let copies: Vec<Vec<u8>> = match block_copies(fs, logical, nodesize) {
+                Result::Ok(copies) => copies.into_iter().filter_map(|c| c.data).collect(),
+                Err(_) => match read_logical(fs, logical, nodesize) {
+                    Result::Ok(block) => vec![block],
+                    Err(e) => {
+                        debug!("{logical}: {e:#}");
+                        unreadable += 1;
+                        continue;
+                    }
+                },
+            };
+            let Some(block) = copies
+                .iter()
+                .find(|block| check_tree_block(fs, block, logical, None).is_empty())
+            else {
+                continue;
+            };
+            let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+            if header.owner != BTRFS_ROOT_TREE_OBJECTID {
+                continue;
+            }
+            let (level, generation) = (header.level, header.generation);
+            if newest.is_none_or(|(_, l, g)| (generation, level) > (g, l)) {
+                newest = Some((logical, level, generation));
+            }
+        }
+    }
+    if unreadable > 0 {
+        warn!("{unreadable} blocks of the metadata chunks couldn't be read to look for root tree blocks");
+    }
+    newest.ok_or_else(|| anyhow!("no root tree block found in the metadata chunks"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometry_lines() {
+        let geometry = Geometry::parse(
+            "# from an old dump-super\nnodesize = 16384\nchunk_root = 0x1500000\n\nchunk_root = 22036480 # again\nroot=30408704",
+        )
+        .unwrap();
+        assert_eq!(geometry.nodesize, 16384);
+        assert_eq!(geometry.sectorsize, 4096);
+        assert_eq!(geometry.chunk_root, 22036480);
+        assert_eq!(geometry.root, Some(30408704));
+        assert!(Geometry::parse("nodesize = 16384").is_err());
+        assert!(Geometry::parse("chunk_root = 16384\nnodesize = 12288").is_err());
+        assert!(Geometry::parse("chunk_root = 16384\nlabel = x").is_err());
+    }
+}
@@ -0,0 +1,317 @@
+//! Where each device's space goes: the stripes of every chunk laid out along
+//! the device they are on, with the type of their block group and how much
+//! of it is used, rendered as a text strip, CSV or SVG. Seeing where the
+//! metadata is tells which ranges to image first from a failing disk.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::flags::fmt_block_group_type;
+use crate::items::item_as;
+use crate::space::{block_groups, stripe_length};
+use crate::structures::*;
+use crate::tree::*;
+use crate::units::fmt_size;
+
+use anyhow::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeatmapFormat {
+    Text,
+    Csv,
+    Svg,
+}
+
+impl FromStr for HeatmapFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(HeatmapFormat::Text),
+            "csv" => Ok(HeatmapFormat::Csv),
+            "svg" => Ok(HeatmapFormat::Svg),
+            _ => bail!("unknown heatmap format {s:?}, expected text, csv or svg"),
+        }
+    }
+}
+
+/// one stripe of a chunk, i.e. a dev extent
+pub struct DeviceStripe {
+    pub physical: u64,
+    pub length: u64,
+    /// the logical start of its chunk
+    pub logical: u64,
+    pub flags: u64,
+    /// bytes used of the block group, and its length, if its item was found
+    pub used: Option<(u64, u64)>,
+}
+
+impl DeviceStripe {
+    /// the fraction of its block group in use
+    pub fn utilization(&self) -> Option<f64> {
+        self.used
+            .map(|(used, length)| used as f64 / length.max(1) as f64)
+    }
+}
+
+pub struct DeviceLayout {
+    pub devid: u64,
+    /// total_bytes of its dev item, or failing that the end of its last
+    /// stripe
+    pub size: u64,
+    /// in physical order
+    pub stripes: Vec<DeviceStripe>,
+}
+
+/// the stripes of every chunk, by device, from the chunk tree and the block
+/// group items. Without the block group items' tree, the use of every
+/// stripe is unknown.
+pub fn device_layouts(fs: &FsInfo) -> Vec<DeviceLayout> {
+    let used: BTreeMap<u64, (u64, u64)> = block_groups(fs)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|group| (group.start, (group.used, group.length)))
+        .collect();
+    let mut layouts = BTreeMap::<u64, DeviceLayout>::new();
+    let search = key_range(
+        Some(BTRFS_DEV_ITEMS_OBJECTID),
+        Some(BtrfsItemType::DEV_ITEM),
+        None,
+    );
+    for (_, data, ..) in search_range(fs, fs.master_sb.chunk_root, search) {
+        if let Some(dev) = item_as::<btrfs_dev_item>(&data) {
+            let devid = dev.devid;
+            layouts.insert(
+                devid,
+                DeviceLayout {
+                    devid,
+                    size: dev.total_bytes,
+                    stripes: Vec::new(),
+                },
+            );
+        }
+    }
+    for ChunkInfo(key, chunk, stripes) in all_chunks(fs) {
+        let length = stripe_length(&chunk);
+        let logical = key.offset;
+        for stripe in &stripes {
+            let devid = stripe.devid;
+            let layout = layouts.entry(devid).or_insert(DeviceLayout {
+                devid,
+                size: 0,
+                stripes: Vec::new(),
+            });
+            layout.stripes.push(DeviceStripe {
+                physical: stripe.offset,
+                length,
+                logical,
+                flags: chunk.r#type,
+                used: used.get(&logical).copied(),
+            });
+        }
+    }
+    let mut layouts: Vec<DeviceLayout> = layouts.into_values().collect();
+    for layout in &mut layouts {
+        layout.stripes.sort_by_key(|stripe| stripe.physical);
+        let end = layout
+            .stripes
+            .last()
+            .map_or(0, |stripe| stripe.physical + stripe.length);
+        layout.size = layout.size.max(end);
+    }
+    layouts
+}
+
+/// the letter standing for a block group type in the text strip
+fn type_letter(flags: u64) -> char {
+    let data = flags & BTRFS_BLOCK_GROUP_DATA != 0;
+    let metadata = flags & BTRFS_BLOCK_GROUP_METADATA != 0;
+    match (data, metadata) {
+        (true, true) => 'X',
+        (true, false) => 'D',
+        (false, true) => 'M',
+        _ if flags & BTRFS_BLOCK_GROUP_SYSTEM != 0 => 'S',
+        _ => '?',
+    }
+}
+
+fn type_colour(flags: u64) -> &'static str {
+    match type_letter(flags) {
+        'X' => "#b07aa1",
+        'D' => "#4e79a7",
+        'M' => "#f28e2b",
+        'S' => "#e15759",
+        _ => "#000000",
+    }
+}
+
+/// two lines of width cells per device: the type of the stripe covering
+/// most of each cell, and beneath it the use of its block group in tenths
+fn format_text(layouts: &[DeviceLayout], width: usize) -> String {
+    let mut out = String::new();
+    for layout in layouts {
+        let cell = layout.size.div_ceil(width as u64).max(1);
+        let mut types = String::new();
+        let mut usage = String::new();
+        for n in 0..width as u64 {
+            let (from, to) = (n * cell, ((n + 1) * cell).min(layout.size));
+            if from >= to {
+                break;
+            }
+            let overlap = |stripe: &&DeviceStripe| {
+                (stripe.physical + stripe.length)
+                    .min(to)
+                    .saturating_sub(stripe.physical.max(from))
+            };
+            let most = layout
+                .stripes
+                .iter()
+                .filter(|stripe| overlap(stripe) > 0)
+                .max_by_key(overlap);
+            match most {
+                Some(stripe) => {
+                    types.push(type_letter(stripe.flags));
+                    usage.push(match stripe.utilization() {
+                        Some(fraction) => {
+                            char::from_digit(((fraction * 10.0) as u32).min(9), 10).unwrap()
+                        }
+                        None => '?',
+                    });
+                }
+                None => {
+                    types.push('.');
+                    usage.push(' ');
+                }
+            }
+        }
+        let _ = writeln!(
+            out,
+            "devid {} {} ({} per cell)",
+            layout.devid,
+            fmt_size(layout.size),
+            fmt_size(cell)
+        );
+        let _ = writeln!(out, "  type |{types}|");
+        let _ = writeln!(out, "  use  |{usage}|");
+    }
+    out.push_str("D data, M metadata, S system, X mixed, . unallocated; use in tenths of the block group, 9 for 90% or more\n");
+    out
+}
+
+fn format_csv(layouts: &[DeviceLayout]) -> String {
+    let mut out = String::from("devid,physical,length,logical,type,used,block_group_length\n");
+    for layout in layouts {
+        for stripe in &layout.stripes {
+            let (used, length) = match stripe.used {
+                Some((used, length)) => (used.to_string(), length.to_string()),
+                None => (String::new(), String::new()),
+            };
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{},{used},{length}",
+                layout.devid,
+                stripe.physical,
+                stripe.length,
+                stripe.logical,
+                fmt_block_group_type(stripe.flags)
+            );
+        }
+    }
+    out
+}
+
+/// a band per device, width pixels wide, with a rectangle per stripe in the
+/// colour of its type, the more opaque the more of its block group is used
+fn format_svg(layouts: &[DeviceLayout], width: usize) -> String {
+    const BAND: usize = 40;
+    const LABEL: usize = 20;
+    let row = BAND + LABEL;
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{}" font-family="sans-serif" font-size="12">"#,
+        row * layouts.len()
+    );
+    for (n, layout) in layouts.iter().enumerate() {
+        let y = n * row;
+        let scale = width as f64 / layout.size.max(1) as f64;
+        let _ = writeln!(
+            out,
+            r#"<text x="0" y="{}">devid {} ({})</text>"#,
+            y + LABEL - 6,
+            layout.devid,
+            fmt_size(layout.size)
+        );
+        let _ = writeln!(
+            out,
+            r##"<rect x="0" y="{}" width="{width}" height="{BAND}" fill="#e0e0e0"/>"##,
+            y + LABEL
+        );
+        for stripe in &layout.stripes {
+            let opacity = 0.25 + 0.75 * stripe.utilization().unwrap_or(1.0);
+            let used = match stripe.utilization() {
+                Some(fraction) => format!("{:.0}% used", fraction * 100.0),
+                None => String::from("use unknown"),
+            };
+            let _ = writeln!(
+                out,
+                r#"<rect x="{:.2}" y="{}" width="{:.2}" height="{BAND}" fill="{}" fill-opacity="{opacity:.2}"><title>physical {} length {} logical {} {} {used}</title></rect>"#,
+                stripe.physical as f64 * scale,
+                y + LABEL,
+                (stripe.length as f64 * scale).max(1.0),
+                type_colour(stripe.flags),
+                stripe.physical,
+                stripe.length,
+                stripe.logical,
+                fmt_block_group_type(stripe.flags)
+            );
+        }
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+/// render the device layouts; width is in cells for text and in pixels for
+/// SVG, and unused for CSV
+pub fn format_heatmap(layouts: &[DeviceLayout], format: HeatmapFormat, width: usize) -> String {
+    match format {
+        HeatmapFormat::Text => format_text(layouts, width.max(1)),
+        HeatmapFormat::Csv => format_csv(layouts),
+        HeatmapFormat::Svg => format_svg(layouts, width.max(1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_strip() {
+        let layouts = [DeviceLayout {
+            devid: 1,
+            size: 8 << 20,
+            stripes: vec![
+                DeviceStripe {
+                    physical: 1 << 20,
+                    length: 1 << 20,
+                    logical: 1 << 20,
+                    flags: BTRFS_BLOCK_GROUP_SYSTEM,
+                    used: Some((16384, 1 << 20)),
+                },
+                DeviceStripe {
+                    physical: 4 << 20,
+                    length: 2 << 20,
+                    logical: 8 << 20,
+                    flags: BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP,
+                    used: Some((3 << 20, 4 << 20)),
+                },
+            ],
+        }];
+        let text = format_heatmap(&layouts, HeatmapFormat::Text, 8);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], "  type |.S..MM..|");
+        assert_eq!(lines[2], "  use  | 0  77  |");
+    }
+}
@@ -0,0 +1,242 @@
+//! Inodes and names within fs trees: parsing of inode refs and resolving
+//! inode numbers and subvolumes back to paths.
+
+use crate::btrfs::*;
+use crate::items::item_as;
+use crate::parse::name_matches;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+
+/// how far up the directory tree to follow refs before assuming a loop
+const MAX_PATH_DEPTH: usize = 256;
+
+/// the file type bits of an inode's mode, as Linux has them whatever
+/// platform this runs on (libc's are u16 on macOS, and missing on Windows)
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+/// (index, name) pairs from an INODE_REF item, whose key offset is the parent
+pub fn parse_inode_refs(data: &[u8]) -> Vec<(u64, &[u8])> {
+    let mut refs = Vec::new();
+    let mut rest = data;
+    while let Some(iref) = item_as::<btrfs_inode_ref>(rest) {
+        let start = std::mem::size_of::<btrfs_inode_ref>();
+        let end = (start + iref.name_len as usize).min(rest.len());
+        refs.push((iref.index, &rest[start..end]));
+        rest = &rest[end..];
+    }
+    refs
+}
+
+/// (parent, index, name) triples from an INODE_EXTREF item
+pub fn parse_inode_extrefs(data: &[u8]) -> Vec<(u64, u64, &[u8])> {
+    let mut refs = Vec::new();
+    let mut rest = data;
+    while let Some(eref) = item_as::<btrfs_inode_extref>(rest) {
+        let start = std::mem::size_of::<btrfs_inode_extref>();
+        let end = (start + eref.name_len as usize).min(rest.len());
+        refs.push((eref.parent_objectid, eref.index, &rest[start..end]));
+        rest = &rest[end..];
+    }
+    refs
+}
+
+/// the inode item of an inode, if it can be found
+pub fn inode_item(fs: &FsInfo, tree_root: u64, inode: u64) -> Option<btrfs_inode_item> {
+    let search = key_range(Some(inode), Some(BtrfsItemType::INODE_ITEM), Some(0));
+    let (_, data, _, _) = search_range(fs, tree_root, search).next()?;
+    item_as::<btrfs_inode_item>(&data).copied()
+}
+
+/// every (parent directory, name) an inode is linked from
+pub fn inode_parents(fs: &FsInfo, tree_root: u64, inode: u64) -> Vec<(u64, Vec<u8>)> {
+    let mut parents = Vec::new();
+    let search = key_range(Some(inode), None, None);
+    for (item, data, _block_offset, _slot) in search_range(fs, tree_root, search) {
+        match item.key.item_type {
+            BtrfsItemType::INODE_REF => {
+                let parent = item.key.offset;
+                for (_index, name) in parse_inode_refs(&data) {
+                    parents.push((parent, name.to_vec()));
+                }
+            }
+            BtrfsItemType::INODE_EXTREF => {
+                for (parent, _index, name) in parse_inode_extrefs(&data) {
+                    parents.push((parent, name.to_vec()));
+                }
+            }
+            _ => {}
+        }
+    }
+    parents
+}
+
+/// every path to an inode from the root of its subvolume. An inode with hard
+/// links has several; a disconnected inode (a ref missing somewhere up the
+/// chain) is shown relative to "?".
+pub fn inode_paths(fs: &FsInfo, tree_root: u64, inode: u64) -> Vec<String> {
+    paths_at_depth(fs, tree_root, inode, 0)
+}
+
+fn paths_at_depth(fs: &FsInfo, tree_root: u64, inode: u64, depth: usize) -> Vec<String> {
+    if inode == BTRFS_FIRST_FREE_OBJECTID {
+        return vec![String::new()];
+    }
+    if depth >= MAX_PATH_DEPTH {
+        return vec![String::from("?(loop)")];
+    }
+    let parents = inode_parents(fs, tree_root, inode);
+    if parents.is_empty() {
+        return vec![format!("?/<inode {inode}>")];
+    }
+    let mut paths = Vec::new();
+    for (parent, name) in parents {
+        let name = String::from_utf8_lossy(&name);
+        for parent_path in paths_at_depth(fs, tree_root, parent, depth + 1) {
+            paths.push(format!("{parent_path}/{name}"));
+        }
+    }
+    paths
+}
+
+/// the (name, inode, type) of each entry of a directory. Entries for
+/// subvolumes refer to a ROOT_ITEM, and have its id as the inode.
+pub fn dir_entries(fs: &FsInfo, tree_root: u64, dir: u64) -> Vec<(Vec<u8>, btrfs_disk_key, u8)> {
+    let mut entries = Vec::new();
+    let search = key_range(Some(dir), Some(BtrfsItemType::DIR_INDEX), None);
+    for (item, data, _block_offset, _slot) in search_range(fs, tree_root, search) {
+        if item.key.item_type != BtrfsItemType::DIR_INDEX {
+            continue;
+        }
+        let Some(di) = item_as::<btrfs_dir_item>(&data) else {
+            continue;
+        };
+        let start = std::mem::size_of::<btrfs_dir_item>();
+        let end = (start + di.name_len as usize).min(data.len());
+        entries.push((data[start..end].to_vec(), di.location, di.r#type));
+    }
+    entries
+}
+
+/// the inode at a /-separated path from the top of a subvolume
+pub fn lookup_path(fs: &FsInfo, tree_root: u64, path: &str) -> Result<u64> {
+    let mut inode = BTRFS_FIRST_FREE_OBJECTID;
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        let (_, location, _) = dir_entries(fs, tree_root, inode)
+            .into_iter()
+            .find(|(name, _, _)| name == component.as_bytes())
+            .ok_or_else(|| anyhow!("{component} not found in {path}"))?;
+        ensure!(
+            location.item_type == BtrfsItemType::INODE_ITEM,
+            "{component} in {path} is a subvolume, to be restored by its id"
+        );
+        inode = location.objectid;
+    }
+    Ok(inode)
+}
+
+/// path of a subvolume from the top level FS_TREE, following ROOT_BACKREFs.
+/// FS_TREE itself is the empty path.
+pub fn subvol_path(fs: &FsInfo, subvol: u64) -> String {
+    let mut components = Vec::new();
+    let mut current = subvol;
+    for _ in 0..MAX_PATH_DEPTH {
+        if current == BTRFS_FS_TREE_OBJECTID {
+            break;
+        }
+        let search = key_range(Some(current), Some(BtrfsItemType::ROOT_BACKREF), None);
+        let Some((item, data, _, _)) = search_range(fs, fs.master_sb.root, search).next() else {
+            components.push(format!("?<subvol {current}>"));
+            break;
+        };
+        let parent_root = item.key.offset;
+        let Some(root_ref) = item_as::<btrfs_root_ref>(&data) else {
+            components.push(format!("?<subvol {current}>"));
+            break;
+        };
+        let dirid = root_ref.dirid;
+        let start = std::mem::size_of::<btrfs_root_ref>();
+        let end = (start + root_ref.name_len as usize).min(data.len());
+        let name = String::from_utf8_lossy(&data[start..end]).into_owned();
+        let dir = tree_root_offset(fs, parent_root)
+            .and_then(|root| inode_paths(fs, root, dirid).into_iter().next())
+            .unwrap_or_else(|| String::from("?"));
+        components.push(format!("{dir}/{name}"));
+        current = parent_root;
+    }
+    components.reverse();
+    components.concat()
+}
+
+/// a directory entry or inode ref whose name matched in find_names
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NameMatch {
+    pub subvol: u64,
+    pub inode: u64,
+    pub parent: u64,
+    pub name: Vec<u8>,
+}
+
+/// scan DIR_ITEM, DIR_INDEX, INODE_REF and INODE_EXTREF items of every fs
+/// tree for names matching pattern (see name_matches). The same link found
+/// through several item types is only reported once.
+pub fn find_names(fs: &FsInfo, pattern: &str) -> Vec<NameMatch> {
+    let pattern = pattern.as_bytes();
+    let mut seen = std::collections::HashSet::new();
+    let mut matches = Vec::new();
+    for (subvol, root) in fs_trees(fs) {
+        let search = key_range(None, None, None);
+        for (item, data, _block_offset, _slot) in search_range(fs, root, search) {
+            let key = item.key;
+            let mut found = Vec::new();
+            match key.item_type {
+                BtrfsItemType::DIR_ITEM | BtrfsItemType::DIR_INDEX => {
+                    let mut rest = &data[..];
+                    while let Some(di) = item_as::<btrfs_dir_item>(rest) {
+                        let start = std::mem::size_of::<btrfs_dir_item>();
+                        let name_end = (start + di.name_len as usize).min(rest.len());
+                        let data_end = (name_end + di.data_len as usize).min(rest.len());
+                        let location = di.location;
+                        // subvolume entries point at a ROOT_ITEM rather than an inode
+                        let inode = if location.item_type == BtrfsItemType::ROOT_ITEM {
+                            BTRFS_FIRST_FREE_OBJECTID
+                        } else {
+                            location.objectid
+                        };
+                        found.push((inode, key.objectid, &rest[start..name_end]));
+                        rest = &rest[data_end..];
+                    }
+                }
+                BtrfsItemType::INODE_REF => {
+                    for (_index, name) in parse_inode_refs(&data) {
+                        found.push((key.objectid, key.offset, name));
+                    }
+                }
+                BtrfsItemType::INODE_EXTREF => {
+                    for (parent, _index, name) in parse_inode_extrefs(&data) {
+                        found.push((key.objectid, parent, name));
+                    }
+                }
+                _ => continue,
+            }
+            for (inode, parent, name) in found {
+                if !name_matches(pattern, name) {
+                    continue;
+                }
+                if seen.insert((subvol, inode, parent, name.to_vec())) {
+                    matches.push(NameMatch {
+                        subvol,
+                        inode,
+                        parent,
+                        name: name.to_vec(),
+                    });
+                }
+            }
+        }
+    }
+    matches
+}
@@ -0,0 +1,231 @@
+//! "What is at this offset": triage of a logical or physical address.
+//!
+//! Combines address translation, the extent tree and backrefs to report the
+//! chunk an address falls in, the tree block or data extent covering it, the
+//! tree owning it and, for data, the files using it. The other way about,
+//! the extent map of a file gives where each of its extents is.
+
+use crate::address::*;
+use crate::backref::*;
+use crate::btrfs::*;
+use crate::dump::fmt_treeid;
+use crate::flags::fmt_block_group_type;
+use crate::inode::*;
+use crate::items::{fmt_compression, item_as};
+use crate::structures::*;
+use crate::tree::*;
+use crate::units::fmt_size;
+
+use anyhow::*;
+
+/// print what is stored at a physical offset on a device
+pub fn inspect_physical(fs: &FsInfo, devid: u64, physical: u64) -> Result<()> {
+    let (_chunk, logical) = physical_to_logical(fs, devid, physical)?;
+    println!("devid {devid} physical {physical} is logical {logical}");
+    inspect_logical(fs, logical)
+}
+
+/// print what is stored at a logical address
+pub fn inspect_logical(fs: &FsInfo, logical: u64) -> Result<()> {
+    let ChunkInfo(key, chunk, stripes) = find_chunk(fs, logical)
+        .ok_or_else(|| anyhow!("logical {logical} is not within any chunk"))?;
+    let start = key.offset;
+    let length = chunk.length;
+    let chunk_type = chunk.r#type;
+    println!(
+        "chunk {start}..{} type {}, {} bytes in",
+        start + length,
+        fmt_block_group_type(chunk_type),
+        logical - start
+    );
+    for stripe in &stripes {
+        let devid = stripe.devid;
+        let offset = stripe.offset;
+        let present = if fs.devid_map.contains_key(&devid) {
+            ""
+        } else {
+            " (device missing)"
+        };
+        println!(
+            "  stripe devid {devid} physical {}{present}",
+            offset + (logical - start)
+        );
+    }
+
+    let Some(extent) = find_extent(fs, logical)? else {
+        println!("not allocated in the extent tree");
+        return Ok(());
+    };
+    let ext_start = extent.start;
+    let ext_key = extent.key;
+    println!(
+        "{} extent {ext_start}..{} ({ext_key:?}) refs {} generation {}",
+        if extent.is_tree_block() {
+            "tree block"
+        } else {
+            "data"
+        },
+        ext_start + extent.length,
+        extent.refs_count,
+        extent.generation
+    );
+    for r in &extent.refs {
+        println!("  {}", r.describe());
+    }
+    if let Some(problem) = &extent.problem {
+        println!("  {problem}");
+    }
+
+    if extent.is_tree_block() {
+        describe_tree_block(fs, &extent);
+    } else {
+        for owner in data_owners(fs, &extent, logical) {
+            let subvol = subvol_path(fs, owner.root);
+            let paths = match tree_root_offset(fs, owner.root) {
+                Some(root) => inode_paths(fs, root, owner.inode),
+                None => vec![String::from("?(subvolume not found)")],
+            };
+            for path in paths {
+                println!(
+                    "file {subvol}{path} (root {} inode {}) at file offset {}",
+                    fmt_treeid(owner.root),
+                    owner.inode,
+                    owner.file_offset
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn describe_tree_block(fs: &FsInfo, extent: &Extent) {
+    let header = match load_virt::<btrfs_header>(fs, extent.start) {
+        Result::Ok(header) => header,
+        Err(e) => {
+            println!("cannot read tree block: {e}");
+            return;
+        }
+    };
+    let owner = header.owner;
+    let level = header.level;
+    let generation = header.generation;
+    let nritems = header.nritems;
+    println!(
+        "tree block header: owner {} level {level} generation {generation} nritems {nritems}",
+        fmt_treeid(owner)
+    );
+    for r in &extent.refs {
+        if let ExtentRef::SharedBlock { parent } = r {
+            if let Result::Ok(parent_header) = load_virt::<btrfs_header>(fs, *parent) {
+                let parent_owner = parent_header.owner;
+                println!(
+                    "  shared via parent {parent} owned by {}",
+                    fmt_treeid(parent_owner)
+                );
+            }
+        }
+    }
+}
+
+/// print every file extent item of the file at path in a subvolume, as
+/// `filefrag -v` would: where its data is logically and on each device
+/// holding a copy, its compression, and whether other files or snapshots
+/// share the extent
+pub fn inspect_extent_map(fs: &FsInfo, subvol: u64, path: &str) -> Result<()> {
+    let tree_root =
+        tree_root_offset(fs, subvol).ok_or_else(|| anyhow!("subvolume {subvol} not found"))?;
+    let inode = lookup_path(fs, tree_root, path)?;
+    let search = key_range(Some(inode), Some(BtrfsItemType::INODE_ITEM), Some(0));
+    let size = search_range(fs, tree_root, search)
+        .next()
+        .and_then(|(_, data, _, _)| item_as::<btrfs_inode_item>(&data).map(|i| i.size));
+    println!(
+        "{path}: inode {inode} in {}, size {}",
+        fmt_treeid(subvol),
+        match size {
+            Some(size) => fmt_size(size),
+            None => String::from("unknown (no inode item)"),
+        }
+    );
+    let search = key_range(Some(inode), Some(BtrfsItemType::EXTENT_DATA), None);
+    let mut count = 0;
+    for (item, data, _block_offset, _slot) in search_range(fs, tree_root, search) {
+        if item.key.item_type != BtrfsItemType::EXTENT_DATA {
+            continue;
+        }
+        let file_offset = item.key.offset;
+        if data.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+            println!("ext {count}: file offset {file_offset}, item too short");
+            count += 1;
+            continue;
+        }
+        let fe = unsafe { &*(data.as_ptr() as *const btrfs_file_extent_item) };
+        let compression = fmt_compression(fe.compression);
+        if fe.r#type == BTRFS_FILE_EXTENT_INLINE {
+            println!(
+                "ext {count}: file offset {file_offset}..{} inline, {} in the leaf, compression {compression}",
+                file_offset + fe.ram_bytes,
+                fmt_size((data.len() - BTRFS_FILE_EXTENT_INLINE_DATA_START) as u64)
+            );
+            count += 1;
+            continue;
+        }
+        let Some(fe) = item_as::<btrfs_file_extent_item>(&data) else {
+            println!("ext {count}: file offset {file_offset}, item too short");
+            count += 1;
+            continue;
+        };
+        let (disk_bytenr, disk_num_bytes) = (fe.disk_bytenr, fe.disk_num_bytes);
+        let (offset, num_bytes) = (fe.offset, fe.num_bytes);
+        let file_range = format!("{file_offset}..{}", file_offset + num_bytes);
+        if disk_bytenr == 0 {
+            println!("ext {count}: file offset {file_range} hole");
+            count += 1;
+            continue;
+        }
+        let shared = match find_extent(fs, disk_bytenr)? {
+            Some(extent) if extent.start == disk_bytenr && extent.refs_count > 1 => {
+                format!("shared ({} refs)", extent.refs_count)
+            }
+            Some(extent) if extent.start == disk_bytenr => String::from("not shared"),
+            _ => String::from("not in the extent tree"),
+        };
+        // compressed, the file offset is into the data once decompressed,
+        // so the whole extent is where the data is
+        let (logical, length) = if fe.compression == BTRFS_COMPRESS_NONE {
+            (disk_bytenr + offset, num_bytes)
+        } else {
+            (disk_bytenr, disk_num_bytes)
+        };
+        println!(
+            "ext {count}: file offset {file_range} {}, logical {logical}..{} of extent {disk_bytenr} length {}, compression {compression}, {shared}",
+            if fe.r#type == BTRFS_FILE_EXTENT_PREALLOC {
+                "prealloc"
+            } else {
+                "regular"
+            },
+            logical + length,
+            fmt_size(disk_num_bytes)
+        );
+        match block_copies(fs, logical, length) {
+            Result::Ok(copies) => {
+                for copy in copies {
+                    println!(
+                        "  devid {} physical {}{}",
+                        copy.devid,
+                        copy.physical,
+                        if copy.data.is_some() {
+                            ""
+                        } else {
+                            " (device missing)"
+                        }
+                    );
+                }
+            }
+            Err(e) => println!("  {e}"),
+        }
+        count += 1;
+    }
+    println!("{path}: {count} extents");
+    Ok(())
+}
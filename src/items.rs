@@ -0,0 +1,906 @@
+//! Decoding of leaf item payloads into human readable lines.
+//!
+//! Decoding never panics on malformed data: payloads that are too short
+//! for their type are reported as such so that corrupt leaves can still
+//! be inspected.
+
+use crate::backref::parse_inline_refs;
+use crate::dump::fmt_treeid;
+use crate::flags::{
+    fmt_block_group_type, fmt_flags, BALANCE_FLAGS, BLOCK_GROUP_PROFILES, FREE_SPACE_INFO_FLAGS,
+    QGROUP_STATUS_FLAGS,
+};
+use crate::qgroup::fmt_qgroupid;
+use crate::raid_stripe::parse_strides;
+use crate::structures::*;
+use crate::units::{fmt_size, fmt_time};
+use crate::verity::parse_descriptor;
+
+/// reinterpret the start of an item's data as T, if it is long enough
+pub fn item_as<T>(data: &[u8]) -> Option<&T> {
+    if data.len() < std::mem::size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { &*(data.as_ptr() as *const T) })
+}
+
+fn fmt_name(name: &[u8]) -> String {
+    String::from_utf8_lossy(name).into_owned()
+}
+
+pub fn fmt_file_type(t: u8) -> &'static str {
+    match t {
+        BTRFS_FT_UNKNOWN => "UNKNOWN",
+        BTRFS_FT_REG_FILE => "FILE",
+        BTRFS_FT_DIR => "DIR",
+        BTRFS_FT_CHRDEV => "CHRDEV",
+        BTRFS_FT_BLKDEV => "BLKDEV",
+        BTRFS_FT_FIFO => "FIFO",
+        BTRFS_FT_SOCK => "SOCK",
+        BTRFS_FT_SYMLINK => "SYMLINK",
+        BTRFS_FT_XATTR => "XATTR",
+        _ => "INVALID",
+    }
+}
+
+pub fn fmt_compression(c: u8) -> String {
+    match c {
+        BTRFS_COMPRESS_NONE => String::from("none"),
+        BTRFS_COMPRESS_ZLIB => String::from("zlib"),
+        BTRFS_COMPRESS_LZO => String::from("lzo"),
+        BTRFS_COMPRESS_ZSTD => String::from("zstd"),
+        _ => format!("unknown({c})"),
+    }
+}
+
+fn too_short(what: &str, need: usize, data: &[u8]) -> Vec<String> {
+    vec![format!(
+        "{what}: item data too short ({} bytes, need {need})",
+        data.len()
+    )]
+}
+
+pub fn describe_inode_item(inode: &btrfs_inode_item) -> Vec<String> {
+    let generation = inode.generation;
+    let transid = inode.transid;
+    let size = inode.size;
+    let nbytes = inode.nbytes;
+    let nlink = inode.nlink;
+    let uid = inode.uid;
+    let gid = inode.gid;
+    let mode = inode.mode;
+    let rdev = inode.rdev;
+    let flags = inode.flags;
+    let sequence = inode.sequence;
+    let atime = inode.atime;
+    let ctime = inode.ctime;
+    let mtime = inode.mtime;
+    let otime = inode.otime;
+    vec![
+        format!(
+            "generation {generation} transid {transid} size {} nbytes {}",
+            fmt_size(size),
+            fmt_size(nbytes)
+        ),
+        format!("nlink {nlink} uid {uid} gid {gid} mode {mode:o} rdev {rdev}"),
+        format!("flags 0x{flags:x} sequence {sequence}"),
+        format!("atime {atime} ctime {ctime} mtime {mtime} otime {otime}"),
+    ]
+}
+
+fn describe_root_item(data: &[u8]) -> Vec<String> {
+    let Some(root) = item_as::<btrfs_root_item>(data) else {
+        return too_short("root item", std::mem::size_of::<btrfs_root_item>(), data);
+    };
+    let generation = root.generation;
+    let root_dirid = root.root_dirid;
+    let bytenr = root.bytenr;
+    let level = root.level;
+    let bytes_used = root.bytes_used;
+    let last_snapshot = root.last_snapshot;
+    let flags = root.flags;
+    let refs = root.refs;
+    let drop_progress = root.drop_progress;
+    let drop_level = root.drop_level;
+    let ctransid = root.ctransid;
+    let otransid = root.otransid;
+    let ctime = root.ctime;
+    let otime = root.otime;
+    let stime = root.stime;
+    let rtime = root.rtime;
+    vec![
+        format!("bytenr {bytenr} level {level} generation {generation} root_dirid {root_dirid}"),
+        format!(
+            "bytes_used {} last_snapshot {last_snapshot} flags 0x{flags:x} refs {refs}",
+            fmt_size(bytes_used)
+        ),
+        format!("drop_progress {drop_progress:?} drop_level {drop_level}"),
+        format!("ctransid {ctransid} otransid {otransid}"),
+        format!("ctime {ctime} otime {otime} stime {stime} rtime {rtime}"),
+        format!("uuid {}", root.uuid),
+        format!("parent_uuid {}", root.parent_uuid),
+        format!("received_uuid {}", root.received_uuid),
+    ]
+}
+
+fn describe_root_ref(data: &[u8]) -> Vec<String> {
+    let Some(root_ref) = item_as::<btrfs_root_ref>(data) else {
+        return too_short("root ref", std::mem::size_of::<btrfs_root_ref>(), data);
+    };
+    let dirid = root_ref.dirid;
+    let sequence = root_ref.sequence;
+    let name_len = root_ref.name_len as usize;
+    let name = &data[std::mem::size_of::<btrfs_root_ref>()..];
+    let mut lines = vec![format!(
+        "dirid {dirid} sequence {sequence} name {}",
+        fmt_name(&name[..name_len.min(name.len())])
+    )];
+    if name_len != name.len() {
+        lines.push(format!(
+            "name_len {name_len} does not match remaining item data {}",
+            name.len()
+        ));
+    }
+    lines
+}
+
+fn describe_inode_refs(data: &[u8]) -> Vec<String> {
+    let mut lines = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let Some(iref) = item_as::<btrfs_inode_ref>(rest) else {
+            lines.extend(too_short(
+                "inode ref",
+                std::mem::size_of::<btrfs_inode_ref>(),
+                rest,
+            ));
+            break;
+        };
+        let index = iref.index;
+        let name_len = iref.name_len as usize;
+        let start = std::mem::size_of::<btrfs_inode_ref>();
+        let end = (start + name_len).min(rest.len());
+        lines.push(format!(
+            "index {index} name {}",
+            fmt_name(&rest[start..end])
+        ));
+        rest = &rest[end..];
+    }
+    lines
+}
+
+fn describe_inode_extrefs(data: &[u8]) -> Vec<String> {
+    let mut lines = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let Some(eref) = item_as::<btrfs_inode_extref>(rest) else {
+            lines.extend(too_short(
+                "inode extref",
+                std::mem::size_of::<btrfs_inode_extref>(),
+                rest,
+            ));
+            break;
+        };
+        let parent = eref.parent_objectid;
+        let index = eref.index;
+        let name_len = eref.name_len as usize;
+        let start = std::mem::size_of::<btrfs_inode_extref>();
+        let end = (start + name_len).min(rest.len());
+        lines.push(format!(
+            "parent {parent} index {index} name {}",
+            fmt_name(&rest[start..end])
+        ));
+        rest = &rest[end..];
+    }
+    lines
+}
+
+/// (name, value) of each entry of an XATTR_ITEM, which holds several when
+/// their names hash alike
+pub fn parse_xattrs(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut xattrs = Vec::new();
+    let mut rest = data;
+    while let Some(di) = item_as::<btrfs_dir_item>(rest) {
+        let start = std::mem::size_of::<btrfs_dir_item>();
+        let name_end = (start + di.name_len as usize).min(rest.len());
+        let data_end = (name_end + di.data_len as usize).min(rest.len());
+        xattrs.push((&rest[start..name_end], &rest[name_end..data_end]));
+        rest = &rest[data_end..];
+    }
+    xattrs
+}
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const POSIX_ACL_XATTR_VERSION: u32 = 2;
+
+/// a POSIX ACL xattr value (version, then a tag, permissions and id per
+/// entry) in the short text form of getfacl -c, e.g.
+/// user::rw-,user:1000:r--,group::r--,mask::r--,other::---
+pub fn fmt_posix_acl(value: &[u8]) -> Option<String> {
+    let version = u32::from_le_bytes(value.get(..4)?.try_into().unwrap());
+    if version != POSIX_ACL_XATTR_VERSION || !(value.len() - 4).is_multiple_of(8) {
+        return None;
+    }
+    let mut entries = Vec::new();
+    for entry in value[4..].chunks_exact(8) {
+        let tag = u16::from_le_bytes([entry[0], entry[1]]);
+        let perm = u16::from_le_bytes([entry[2], entry[3]]);
+        let id = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+        let (kind, qualifier) = match tag {
+            ACL_USER_OBJ => ("user", String::new()),
+            ACL_USER => ("user", id.to_string()),
+            ACL_GROUP_OBJ => ("group", String::new()),
+            ACL_GROUP => ("group", id.to_string()),
+            ACL_MASK => ("mask", String::new()),
+            ACL_OTHER => ("other", String::new()),
+            _ => return None,
+        };
+        let perms: String = [(4, 'r'), (2, 'w'), (1, 'x')]
+            .iter()
+            .map(|&(bit, c)| if perm & bit != 0 { c } else { '-' })
+            .collect();
+        entries.push(format!("{kind}:{qualifier}:{perms}"));
+    }
+    Some(entries.join(","))
+}
+
+/// an xattr value as text: ACLs decoded, strings (such as SELinux labels)
+/// as they are, anything else in hex
+pub fn fmt_xattr_value(name: &[u8], value: &[u8]) -> String {
+    if name == b"system.posix_acl_access" || name == b"system.posix_acl_default" {
+        if let Some(acl) = fmt_posix_acl(value) {
+            return acl;
+        }
+    }
+    let text = value.strip_suffix(b"\0").unwrap_or(value);
+    match std::str::from_utf8(text) {
+        std::result::Result::Ok(text) if !text.chars().any(|c| c.is_control()) => {
+            format!("{:?}", text)
+        }
+        _ => format!("0x{}", hex::encode(value)),
+    }
+}
+
+fn describe_xattrs(data: &[u8]) -> Vec<String> {
+    let xattrs = parse_xattrs(data);
+    if xattrs.is_empty() {
+        return too_short("xattr", std::mem::size_of::<btrfs_dir_item>(), data);
+    }
+    xattrs
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "xattr {} = {}",
+                fmt_name(name),
+                fmt_xattr_value(name, value)
+            )
+        })
+        .collect()
+}
+
+/// the VERITY_DESC_ITEM at offset 0 gives the size of the descriptor, which
+/// is held by those from offset 1 on
+fn describe_verity_desc(key: &btrfs_disk_key, data: &[u8]) -> Vec<String> {
+    if key.offset == 0 {
+        let Some(desc_item) = item_as::<btrfs_verity_descriptor_item>(data) else {
+            return too_short(
+                "verity descriptor item",
+                std::mem::size_of::<btrfs_verity_descriptor_item>(),
+                data,
+            );
+        };
+        let size = desc_item.size;
+        let encryption = desc_item.encryption;
+        return vec![format!("descriptor size {size} encryption {encryption}")];
+    }
+    let start = key.offset - 1;
+    let mut lines = vec![format!(
+        "descriptor bytes {start}..{}",
+        start + data.len() as u64
+    )];
+    // normally the whole descriptor fits in the first item
+    if let Some(desc) = parse_descriptor(data).filter(|_| start == 0) {
+        lines.push(desc.to_string());
+    }
+    lines
+}
+
+/// DIR_ITEM and DIR_INDEX may hold several entries back to back
+fn describe_dir_items(data: &[u8]) -> Vec<String> {
+    let mut lines = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let Some(di) = item_as::<btrfs_dir_item>(rest) else {
+            lines.extend(too_short(
+                "dir item",
+                std::mem::size_of::<btrfs_dir_item>(),
+                rest,
+            ));
+            break;
+        };
+        let location = di.location;
+        let transid = di.transid;
+        let name_len = di.name_len as usize;
+        let data_len = di.data_len as usize;
+        let start = std::mem::size_of::<btrfs_dir_item>();
+        let name_end = (start + name_len).min(rest.len());
+        let data_end = (name_end + data_len).min(rest.len());
+        lines.push(format!(
+            "location {location:?} type {} transid {transid} name {}",
+            fmt_file_type(di.r#type),
+            fmt_name(&rest[start..name_end])
+        ));
+        if data_len > 0 {
+            lines.push(format!(
+                "data {}",
+                String::from_utf8_lossy(&rest[name_end..data_end]).escape_debug()
+            ));
+        }
+        rest = &rest[data_end..];
+    }
+    lines
+}
+
+fn describe_file_extent(data: &[u8]) -> Vec<String> {
+    if data.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+        return too_short("file extent", BTRFS_FILE_EXTENT_INLINE_DATA_START, data);
+    }
+    let fe = unsafe { &*(data.as_ptr() as *const btrfs_file_extent_item) };
+    let generation = fe.generation;
+    let ram_bytes = fe.ram_bytes;
+    let extent_type = fe.r#type;
+    let mut lines = vec![format!(
+        "generation {generation} ram_bytes {} compression {} type {}",
+        fmt_size(ram_bytes),
+        fmt_compression(fe.compression),
+        match extent_type {
+            BTRFS_FILE_EXTENT_INLINE => "inline",
+            BTRFS_FILE_EXTENT_REG => "regular",
+            BTRFS_FILE_EXTENT_PREALLOC => "prealloc",
+            _ => "invalid",
+        }
+    )];
+    if extent_type == BTRFS_FILE_EXTENT_INLINE {
+        lines.push(format!(
+            "inline data size {}",
+            fmt_size((data.len() - BTRFS_FILE_EXTENT_INLINE_DATA_START) as u64)
+        ));
+    } else if data.len() < std::mem::size_of::<btrfs_file_extent_item>() {
+        lines.extend(too_short(
+            "file extent",
+            std::mem::size_of::<btrfs_file_extent_item>(),
+            data,
+        ));
+    } else {
+        let disk_bytenr = fe.disk_bytenr;
+        let disk_num_bytes = fe.disk_num_bytes;
+        let offset = fe.offset;
+        let num_bytes = fe.num_bytes;
+        lines.push(format!(
+            "disk_bytenr {disk_bytenr} disk_num_bytes {} offset {offset} num_bytes {}",
+            fmt_size(disk_num_bytes),
+            fmt_size(num_bytes)
+        ));
+    }
+    lines
+}
+
+fn describe_chunk(data: &[u8]) -> Vec<String> {
+    let Some(chunk) = item_as::<btrfs_chunk>(data) else {
+        return too_short("chunk", std::mem::size_of::<btrfs_chunk>(), data);
+    };
+    let length = chunk.length;
+    let owner = chunk.owner;
+    let stripe_len = chunk.stripe_len;
+    let chunk_type = chunk.r#type;
+    let num_stripes = chunk.num_stripes;
+    let sub_stripes = chunk.sub_stripes;
+    let mut lines = vec![format!(
+        "length {} owner {owner} stripe_len {} type {} num_stripes {num_stripes} sub_stripes {sub_stripes}",
+        fmt_size(length),
+        fmt_size(stripe_len),
+        fmt_block_group_type(chunk_type)
+    )];
+    let mut rest = &data[std::mem::size_of::<btrfs_chunk>()..];
+    for i in 0..num_stripes {
+        let Some(stripe) = item_as::<btrfs_stripe>(rest) else {
+            lines.push(format!("stripe {i}: truncated"));
+            break;
+        };
+        let devid = stripe.devid;
+        let offset = stripe.offset;
+        lines.push(format!(
+            "stripe {i}: devid {devid} offset {offset} dev_uuid {}",
+            stripe.dev_uuid
+        ));
+        rest = &rest[std::mem::size_of::<btrfs_stripe>()..];
+    }
+    lines
+}
+
+pub fn describe_dev_item(dev: &btrfs_dev_item) -> Vec<String> {
+    let devid = dev.devid;
+    let total_bytes = dev.total_bytes;
+    let bytes_used = dev.bytes_used;
+    let generation = dev.generation;
+    let io_align = dev.io_align;
+    let io_width = dev.io_width;
+    let sector_size = dev.sector_size;
+    let dev_type = dev.r#type;
+    let start_offset = dev.start_offset;
+    let dev_group = dev.dev_group;
+    vec![
+        format!(
+            "devid {devid} total_bytes {} bytes_used {} generation {generation}",
+            fmt_size(total_bytes),
+            fmt_size(bytes_used)
+        ),
+        format!(
+            "io_align {io_align} io_width {io_width} sector_size {sector_size} type {dev_type}"
+        ),
+        format!(
+            "start_offset {start_offset} dev_group {dev_group} seek_speed {} bandwidth {}",
+            dev.seek_speed, dev.bandwidth
+        ),
+        format!("uuid {} fsid {}", dev.uuid, dev.fsid),
+    ]
+}
+
+fn describe_dev_extent(data: &[u8]) -> Vec<String> {
+    let Some(de) = item_as::<btrfs_dev_extent>(data) else {
+        return too_short("dev extent", std::mem::size_of::<btrfs_dev_extent>(), data);
+    };
+    let chunk_tree = de.chunk_tree;
+    let chunk_objectid = de.chunk_objectid;
+    let chunk_offset = de.chunk_offset;
+    let length = de.length;
+    vec![format!(
+        "chunk_tree {chunk_tree} chunk_objectid {chunk_objectid} chunk_offset {chunk_offset} length {}",
+        fmt_size(length)
+    )]
+}
+
+/// the name of a dev replace item's state
+pub fn dev_replace_state_name(state: u64) -> String {
+    match state {
+        BTRFS_DEV_REPLACE_ITEM_STATE_NEVER_STARTED => String::from("NEVER_STARTED"),
+        BTRFS_DEV_REPLACE_ITEM_STATE_STARTED => String::from("STARTED"),
+        BTRFS_DEV_REPLACE_ITEM_STATE_FINISHED => String::from("FINISHED"),
+        BTRFS_DEV_REPLACE_ITEM_STATE_CANCELED => String::from("CANCELED"),
+        BTRFS_DEV_REPLACE_ITEM_STATE_SUSPENDED => String::from("SUSPENDED"),
+        _ => format!("unknown state {state}"),
+    }
+}
+
+pub fn describe_dev_replace(replace: &btrfs_dev_replace_item) -> Vec<String> {
+    let src_devid = replace.src_devid;
+    let cursor_left = replace.cursor_left;
+    let cursor_right = replace.cursor_right;
+    let mode = replace.cont_reading_from_srcdev_mode;
+    let time_started = replace.time_started;
+    let time_stopped = replace.time_stopped;
+    let num_write_errors = replace.num_write_errors;
+    let num_uncorrectable_read_errors = replace.num_uncorrectable_read_errors;
+    let mode = match mode {
+        BTRFS_DEV_REPLACE_ITEM_CONT_READING_FROM_SRCDEV_MODE_ALWAYS => String::from("ALWAYS"),
+        BTRFS_DEV_REPLACE_ITEM_CONT_READING_FROM_SRCDEV_MODE_AVOID => String::from("AVOID"),
+        _ => format!("{mode}"),
+    };
+    vec![
+        format!(
+            "state {} src_devid {src_devid} cursor_left {cursor_left} cursor_right {cursor_right} read_from_srcdev {mode}",
+            dev_replace_state_name(replace.replace_state)
+        ),
+        format!(
+            "started {} stopped {}",
+            fmt_time(time_started, 0),
+            fmt_time(time_stopped, 0)
+        ),
+        format!(
+            "write_errors {num_write_errors} uncorrectable_read_errors {num_uncorrectable_read_errors}"
+        ),
+    ]
+}
+
+pub const DEV_STAT_NAMES: [&str; BTRFS_DEV_STAT_VALUES_MAX] =
+    ["write", "read", "flush", "corruption", "generation"];
+
+/// the counters of a dev stats item, those missing from a short item being 0
+/// as the kernel takes them
+pub fn dev_stats_values(data: &[u8]) -> [u64; BTRFS_DEV_STAT_VALUES_MAX] {
+    let mut values = [0; BTRFS_DEV_STAT_VALUES_MAX];
+    for (value, bytes) in values.iter_mut().zip(data.chunks_exact(8)) {
+        *value = u64::from_le_bytes(bytes.try_into().unwrap());
+    }
+    values
+}
+
+/// dev stats counters as e.g. "write_errs 0 read_errs 2 ..."
+pub fn fmt_dev_stats(values: &[u64; BTRFS_DEV_STAT_VALUES_MAX]) -> String {
+    DEV_STAT_NAMES
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{name}_errs {value}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// balance profile filters as e.g. raid1|single
+fn fmt_balance_profiles(profiles: u64) -> String {
+    let names: Vec<(u64, &str)> = BLOCK_GROUP_PROFILES
+        .iter()
+        .copied()
+        .chain([(BTRFS_AVAIL_ALLOC_BIT_SINGLE, "SINGLE")])
+        .collect();
+    fmt_flags(profiles, &names).to_lowercase()
+}
+
+/// the filters of a balance for one block group type, in the syntax of
+/// `btrfs balance start -d<filters>`
+pub fn fmt_balance_args(args: &btrfs_balance_args) -> String {
+    let flags = args.flags;
+    let (usage, devid, limit) = (args.usage, args.devid, args.limit);
+    let (pstart, pend, vstart, vend) = (args.pstart, args.pend, args.vstart, args.vend);
+    let (stripes_min, stripes_max) = (args.stripes_min, args.stripes_max);
+    let low = |v: u64| v & 0xffff_ffff;
+    let high = |v: u64| v >> 32;
+    let mut filters = Vec::new();
+    if flags & BTRFS_BALANCE_ARGS_PROFILES != 0 {
+        filters.push(format!("profiles={}", fmt_balance_profiles(args.profiles)));
+    }
+    if flags & BTRFS_BALANCE_ARGS_USAGE_RANGE != 0 {
+        filters.push(format!("usage={}..{}", low(usage), high(usage)));
+    } else if flags & BTRFS_BALANCE_ARGS_USAGE != 0 {
+        filters.push(format!("usage={usage}"));
+    }
+    if flags & BTRFS_BALANCE_ARGS_DEVID != 0 {
+        filters.push(format!("devid={devid}"));
+    }
+    if flags & BTRFS_BALANCE_ARGS_DRANGE != 0 {
+        filters.push(format!("drange={pstart}..{pend}"));
+    }
+    if flags & BTRFS_BALANCE_ARGS_VRANGE != 0 {
+        filters.push(format!("vrange={vstart}..{vend}"));
+    }
+    if flags & BTRFS_BALANCE_ARGS_LIMIT_RANGE != 0 {
+        filters.push(format!("limit={}..{}", low(limit), high(limit)));
+    } else if flags & BTRFS_BALANCE_ARGS_LIMIT != 0 {
+        filters.push(format!("limit={limit}"));
+    }
+    if flags & BTRFS_BALANCE_ARGS_STRIPES_RANGE != 0 {
+        filters.push(format!("stripes={stripes_min}..{stripes_max}"));
+    }
+    if flags & BTRFS_BALANCE_ARGS_CONVERT != 0 {
+        filters.push(format!("convert={}", fmt_balance_profiles(args.target)));
+    }
+    if flags & BTRFS_BALANCE_ARGS_SOFT != 0 {
+        filters.push(String::from("soft"));
+    }
+    let known = (BTRFS_BALANCE_ARGS_USAGE_RANGE << 1) - 1;
+    if flags & !known != 0 {
+        filters.push(format!("unknown flags {:#x}", flags & !known));
+    }
+    if filters.is_empty() {
+        String::from("all")
+    } else {
+        filters.join(",")
+    }
+}
+
+fn describe_free_space_item(key: &btrfs_disk_key, data: &[u8]) -> Vec<String> {
+    let (start, length) = (key.objectid, key.offset);
+    match key.item_type {
+        BtrfsItemType::FREE_SPACE_INFO => {
+            let Some(info) = item_as::<btrfs_free_space_info>(data) else {
+                return too_short(
+                    "free space info",
+                    std::mem::size_of::<btrfs_free_space_info>(),
+                    data,
+                );
+            };
+            let extent_count = info.extent_count;
+            vec![format!(
+                "block group {start}..{} extent count {extent_count} flags {}",
+                start + length,
+                fmt_flags(info.flags as u64, FREE_SPACE_INFO_FLAGS)
+            )]
+        }
+        BtrfsItemType::FREE_SPACE_EXTENT => vec![format!("free {start}..{}", start + length)],
+        _ => {
+            let set: u32 = data.iter().map(|byte| byte.count_ones()).sum();
+            vec![format!(
+                "bitmap of {start}..{}: {set} of {} bits set (free)",
+                start + length,
+                data.len() * 8
+            )]
+        }
+    }
+}
+
+fn describe_qgroup_item(key: &btrfs_disk_key, data: &[u8]) -> Vec<String> {
+    match key.item_type {
+        BtrfsItemType::QGROUP_STATUS => {
+            let Some(status) = item_as::<btrfs_qgroup_status_item>(data) else {
+                return too_short(
+                    "qgroup status",
+                    std::mem::size_of::<btrfs_qgroup_status_item>(),
+                    data,
+                );
+            };
+            let version = status.version;
+            let generation = status.generation;
+            let rescan = status.rescan;
+            vec![format!(
+                "version {version} generation {generation} flags {} rescan {rescan}",
+                fmt_flags(status.flags, QGROUP_STATUS_FLAGS)
+            )]
+        }
+        BtrfsItemType::QGROUP_INFO => {
+            let Some(info) = item_as::<btrfs_qgroup_info_item>(data) else {
+                return too_short(
+                    "qgroup info",
+                    std::mem::size_of::<btrfs_qgroup_info_item>(),
+                    data,
+                );
+            };
+            let generation = info.generation;
+            vec![format!(
+                "qgroup {} generation {generation} referenced {} (compressed {}) exclusive {} (compressed {})",
+                fmt_qgroupid(key.offset),
+                fmt_size(info.rfer),
+                fmt_size(info.rfer_cmpr),
+                fmt_size(info.excl),
+                fmt_size(info.excl_cmpr)
+            )]
+        }
+        BtrfsItemType::QGROUP_LIMIT => {
+            let Some(limit) = item_as::<btrfs_qgroup_limit_item>(data) else {
+                return too_short(
+                    "qgroup limit",
+                    std::mem::size_of::<btrfs_qgroup_limit_item>(),
+                    data,
+                );
+            };
+            let flags = limit.flags;
+            vec![format!(
+                "qgroup {} flags 0x{flags:x} max referenced {} max exclusive {} reserved referenced {} reserved exclusive {}",
+                fmt_qgroupid(key.offset),
+                fmt_size(limit.max_rfer),
+                fmt_size(limit.max_excl),
+                fmt_size(limit.rsv_rfer),
+                fmt_size(limit.rsv_excl)
+            )]
+        }
+        _ => {
+            // stored both ways; the member is the one of the lower level
+            let (a, b) = (key.objectid, key.offset);
+            let (member, parent) = if a >> BTRFS_QGROUP_LEVEL_SHIFT < b >> BTRFS_QGROUP_LEVEL_SHIFT
+            {
+                (a, b)
+            } else {
+                (b, a)
+            };
+            vec![format!(
+                "qgroup {} is a member of {}",
+                fmt_qgroupid(member),
+                fmt_qgroupid(parent)
+            )]
+        }
+    }
+}
+
+pub fn describe_balance(balance: &btrfs_balance_item) -> Vec<String> {
+    let flags = balance.flags;
+    let mut lines = vec![format!("flags {}", fmt_flags(flags, BALANCE_FLAGS))];
+    for (bit, name, args) in [
+        (BTRFS_BALANCE_DATA, "data", &balance.data),
+        (BTRFS_BALANCE_METADATA, "metadata", &balance.meta),
+        (BTRFS_BALANCE_SYSTEM, "system", &balance.sys),
+    ] {
+        if flags & bit != 0 {
+            lines.push(format!("{name} {}", fmt_balance_args(args)));
+        }
+    }
+    lines
+}
+
+fn describe_block_group(data: &[u8]) -> Vec<String> {
+    let Some(bg) = item_as::<btrfs_block_group_item>(data) else {
+        return too_short(
+            "block group",
+            std::mem::size_of::<btrfs_block_group_item>(),
+            data,
+        );
+    };
+    let used = bg.used;
+    let chunk_objectid = bg.chunk_objectid;
+    let flags = bg.flags;
+    vec![format!(
+        "used {} chunk_objectid {chunk_objectid} flags {}",
+        fmt_size(used),
+        fmt_block_group_type(flags)
+    )]
+}
+
+fn describe_extent_item(key: &btrfs_disk_key, data: &[u8]) -> Vec<String> {
+    let Some(ei) = item_as::<btrfs_extent_item>(data) else {
+        return too_short(
+            "extent item",
+            std::mem::size_of::<btrfs_extent_item>(),
+            data,
+        );
+    };
+    let refs = ei.refs;
+    let generation = ei.generation;
+    let flags = ei.flags;
+    let mut lines = vec![format!(
+        "refs {refs} generation {generation} flags 0x{flags:x}"
+    )];
+    let mut rest = &data[std::mem::size_of::<btrfs_extent_item>()..];
+    if key.item_type == BtrfsItemType::EXTENT_ITEM && flags & BTRFS_EXTENT_FLAG_TREE_BLOCK != 0 {
+        let Some(info) = item_as::<btrfs_tree_block_info>(rest) else {
+            lines.push(String::from("tree block info truncated"));
+            return lines;
+        };
+        let info_key = info.key;
+        let level = info.level;
+        lines.push(format!("tree block key {info_key:?} level {level}"));
+        rest = &rest[std::mem::size_of::<btrfs_tree_block_info>()..];
+    }
+    let (refs, problem) = parse_inline_refs(rest);
+    lines.extend(refs.iter().map(|r| r.describe()));
+    lines.extend(problem);
+    lines
+}
+
+fn describe_extent_data_ref(data: &[u8]) -> Vec<String> {
+    let Some(dref) = item_as::<btrfs_extent_data_ref>(data) else {
+        return too_short(
+            "extent data ref",
+            std::mem::size_of::<btrfs_extent_data_ref>(),
+            data,
+        );
+    };
+    let root = dref.root;
+    let objectid = dref.objectid;
+    let offset = dref.offset;
+    let count = dref.count;
+    vec![format!(
+        "root {} objectid {objectid} offset {offset} count {count}",
+        fmt_treeid(root)
+    )]
+}
+
+/// returns a description of the item data, one entry per line
+pub fn describe_item(key: &btrfs_disk_key, data: &[u8]) -> Vec<String> {
+    match key.item_type {
+        BtrfsItemType::INODE_ITEM => match item_as::<btrfs_inode_item>(data) {
+            Some(inode) => describe_inode_item(inode),
+            None => too_short("inode item", std::mem::size_of::<btrfs_inode_item>(), data),
+        },
+        BtrfsItemType::INODE_REF => describe_inode_refs(data),
+        BtrfsItemType::INODE_EXTREF => describe_inode_extrefs(data),
+        BtrfsItemType::DIR_ITEM | BtrfsItemType::DIR_INDEX => describe_dir_items(data),
+        BtrfsItemType::XATTR_ITEM => describe_xattrs(data),
+        BtrfsItemType::EXTENT_DATA => describe_file_extent(data),
+        BtrfsItemType::DIR_LOG_ITEM | BtrfsItemType::DIR_LOG_INDEX => {
+            match item_as::<btrfs_dir_log_item>(data) {
+                Some(dir_log) => {
+                    let end = dir_log.end;
+                    vec![format!("logged from {} to {end}", { key.offset })]
+                }
+                None => too_short(
+                    "dir log item",
+                    std::mem::size_of::<btrfs_dir_log_item>(),
+                    data,
+                ),
+            }
+        }
+        BtrfsItemType::VERITY_DESC_ITEM => describe_verity_desc(key, data),
+        BtrfsItemType::VERITY_MERKLE_ITEM => {
+            let offset = key.offset;
+            vec![format!(
+                "merkle tree bytes {offset}..{}",
+                offset + data.len() as u64
+            )]
+        }
+        BtrfsItemType::ROOT_ITEM => describe_root_item(data),
+        BtrfsItemType::ROOT_REF | BtrfsItemType::ROOT_BACKREF => describe_root_ref(data),
+        BtrfsItemType::CHUNK_ITEM => describe_chunk(data),
+        BtrfsItemType::DEV_ITEM => match item_as::<btrfs_dev_item>(data) {
+            Some(dev) => describe_dev_item(dev),
+            None => too_short("dev item", std::mem::size_of::<btrfs_dev_item>(), data),
+        },
+        BtrfsItemType::DEV_EXTENT => describe_dev_extent(data),
+        BtrfsItemType::RAID_STRIPE => parse_strides(data)
+            .into_iter()
+            .map(|(devid, physical)| format!("stride devid {devid} physical {physical}"))
+            .collect(),
+        BtrfsItemType::DEV_REPLACE => match item_as::<btrfs_dev_replace_item>(data) {
+            Some(replace) => describe_dev_replace(replace),
+            None => too_short(
+                "dev replace",
+                std::mem::size_of::<btrfs_dev_replace_item>(),
+                data,
+            ),
+        },
+        BtrfsItemType::PERSISTENT_ITEM if key.objectid == BTRFS_DEV_STATS_OBJECTID => {
+            vec![fmt_dev_stats(&dev_stats_values(data))]
+        }
+        BtrfsItemType::TEMPORARY_ITEM if key.objectid == BTRFS_BALANCE_OBJECTID => {
+            match item_as::<btrfs_balance_item>(data) {
+                Some(balance) => describe_balance(balance),
+                None => too_short("balance", std::mem::size_of::<btrfs_balance_item>(), data),
+            }
+        }
+        BtrfsItemType::QGROUP_STATUS
+        | BtrfsItemType::QGROUP_INFO
+        | BtrfsItemType::QGROUP_LIMIT
+        | BtrfsItemType::QGROUP_RELATION => describe_qgroup_item(key, data),
+        BtrfsItemType::FREE_SPACE_INFO
+        | BtrfsItemType::FREE_SPACE_EXTENT
+        | BtrfsItemType::FREE_SPACE_BITMAP => describe_free_space_item(key, data),
+        BtrfsItemType::MIN if key.objectid == BTRFS_FREE_SPACE_OBJECTID => {
+            match item_as::<btrfs_free_space_header>(data) {
+                Some(header) => {
+                    let inode = header.location.objectid;
+                    let generation = header.generation;
+                    let num_entries = header.num_entries;
+                    let num_bitmaps = header.num_bitmaps;
+                    vec![format!(
+                        "v1 cache of block group {} in inode {inode} generation {generation}, {num_entries} entries of which {num_bitmaps} bitmaps",
+                        { key.offset }
+                    )]
+                }
+                None => too_short(
+                    "free space header",
+                    std::mem::size_of::<btrfs_free_space_header>(),
+                    data,
+                ),
+            }
+        }
+        BtrfsItemType::BLOCK_GROUP_ITEM => describe_block_group(data),
+        BtrfsItemType::EXTENT_ITEM | BtrfsItemType::METADATA_ITEM => {
+            describe_extent_item(key, data)
+        }
+        BtrfsItemType::EXTENT_DATA_REF => describe_extent_data_ref(data),
+        BtrfsItemType::EXTENT_CSUM => vec![format!("{} of checksums", fmt_size(data.len() as u64))],
+        _ => vec![format!("{} of undecoded data", fmt_size(data.len() as u64))],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posix_acl() {
+        let mut value = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [
+            (1u16, 6u16, u32::MAX),
+            (2, 4, 1000),
+            (4, 4, u32::MAX),
+            (0x10, 5, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ] {
+            value.extend_from_slice(&tag.to_le_bytes());
+            value.extend_from_slice(&perm.to_le_bytes());
+            value.extend_from_slice(&id.to_le_bytes());
+        }
+        assert_eq!(
+            fmt_posix_acl(&value).unwrap(),
+            "user::rw-,user:1000:r--,group::r--,mask::r-x,other::---"
+        );
+        assert_eq!(fmt_posix_acl(&value[..7]), None);
+        assert_eq!(
+            fmt_xattr_value(b"security.selinux", b"system_u:object_r:etc_t:s0\0"),
+            "\"system_u:object_r:etc_t:s0\""
+        );
+        assert_eq!(fmt_xattr_value(b"security.capability", b"\x01\0"), "0x0100");
+    }
+}
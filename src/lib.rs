@@ -1,7 +1,51 @@
 pub mod address;
+pub mod backref;
+pub mod block_cache;
+pub mod browse;
 pub mod btrfs;
 pub mod btrfs_node;
+pub mod cancel;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod carve;
+pub mod census;
+pub mod check;
+pub mod chunk_map;
+pub mod compress;
+pub mod device;
+pub mod diff;
 pub mod dump;
+pub mod edit;
+pub mod export;
+pub mod flags;
+pub mod free_space;
+pub mod geometry;
+pub mod heatmap;
+pub mod inode;
+pub mod inspect;
+pub mod items;
 pub mod mapped_file;
+pub mod parallel;
+pub mod parse;
+pub mod partition;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod qgroup;
+pub mod quarantine;
+pub mod raid56;
+pub mod raid_stripe;
+pub mod rebuild;
+pub mod repair;
+pub mod restore;
+pub mod scrub;
+pub mod shell;
+pub mod space;
+pub mod space_cache;
+pub mod stats;
 pub mod structures;
 pub mod tree;
+pub mod tree_log;
+pub mod undelete;
+pub mod units;
+pub mod verity;
+pub mod zoned;
@@ -1,21 +1,1171 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
 /// access internal structures in an unmounted btrfs filesystem
 ///
 /// Each available block device in the filesystem should be specified on the command line.
+/// Without a subcommand the filesystem overview is dumped.
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about)]
+#[command(author, version, about, long_about, subcommand_negates_reqs = true)]
 struct Params {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// print sizes as KiB/MiB/GiB/... and timestamps as RFC3339 dates
+    #[clap(long, global = true)]
+    human: bool,
+
+    /// map devices and images in windows of this many bytes as they are read,
+    /// rather than whole (the default on 32 bit hosts, with 64MiB windows)
+    #[clap(long, value_name = "BYTES", global = true)]
+    map_window: Option<String>,
+
+    /// take image files to be zoned devices with zones of this many bytes,
+    /// e.g. images of zoned disks (block devices report their own zones)
+    #[clap(long, value_name = "BYTES", global = true)]
+    zone_size: Option<String>,
+
+    /// keep up to this many bytes of tree blocks once read and verified,
+    /// 64MiB by default; 0 for none where memory is short
+    #[clap(long, value_name = "BYTES", global = true)]
+    block_cache: Option<String>,
+
+    /// what to do about tree blocks with bad checksums: strict to fail to
+    /// read them, warn to read them with a warning, skip not to check
+    #[clap(long, value_name = "POLICY", default_value = "strict", global = true)]
+    csum_policy: btrfs_kit::btrfs::CsumPolicy,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct Devices {
+    /// block devices or image files making up the filesystem
+    #[clap(required = true)]
+    paths: Vec<std::path::PathBuf>,
+
+    /// add the other devices of the filesystem, found among the block
+    /// devices and files in a directory (/dev by default)
+    #[clap(long, value_name = "DIR", num_args = 0..=1, require_equals = true, default_missing_value = "/dev")]
+    scan: Option<std::path::PathBuf>,
+
+    /// take the device at PATH to be devid DEVID, whatever its dev item
+    /// says, instead of any other device with that devid
+    #[clap(long, value_name = "DEVID=PATH")]
+    map_devid: Vec<String>,
+
+    /// map logical addresses with the chunks in this file (as `chunk-map`
+    /// writes them) instead of the chunk tree
+    #[clap(long, value_name = "FILE")]
+    chunk_map: Option<std::path::PathBuf>,
+
+    /// don't read the ranges in this file (logical START LENGTH or
+    /// physical DEVID START LENGTH lines), trying other copies instead
+    #[clap(long, value_name = "FILE")]
+    quarantine: Vec<std::path::PathBuf>,
+
+    /// don't read the areas of a device which a ddrescue mapfile of its
+    /// file has as bad or not yet read
+    #[clap(long, value_name = "DEVID=MAPFILE")]
+    ddrescue_map: Vec<String>,
+
+    /// read the trees from an older root tree, with its root block at this
+    /// logical address (e.g. one `orphans` lists as owned by ROOT_TREE),
+    /// rather than the superblock's; nothing is written then
+    #[clap(long, value_name = "BYTENR", help_heading = "Older generations")]
+    at_root: Option<String>,
+
+    /// as --at-root, with the root tree of a backup root (0 to 3)
+    #[clap(
+        long,
+        value_name = "N",
+        conflicts_with = "at_root",
+        help_heading = "Older generations"
+    )]
+    at_backup_root: Option<usize>,
+
+    /// load without a superblock, from a file of name = value lines giving
+    /// the geometry below
+    #[clap(long, value_name = "FILE", help_heading = "Without a superblock")]
+    geometry: Option<std::path::PathBuf>,
+
+    /// logical address of the chunk tree's root
+    #[clap(long, help_heading = "Without a superblock")]
+    chunk_root: Option<String>,
+
+    /// logical address of the root tree's root, by default the newest found
+    #[clap(long, help_heading = "Without a superblock")]
+    root_tree: Option<String>,
+
+    /// [default: 16384]
+    #[clap(long, help_heading = "Without a superblock")]
+    nodesize: Option<String>,
+
+    /// [default: 4096]
+    #[clap(long, help_heading = "Without a superblock")]
+    sectorsize: Option<String>,
+
+    /// crc32c, xxhash, sha256 or blake2 [default: crc32c]
+    #[clap(long, help_heading = "Without a superblock")]
+    csum_type: Option<String>,
+}
+
+impl Devices {
+    fn load(&self) -> anyhow::Result<btrfs_kit::btrfs::FsInfo> {
+        let devid_paths = self
+            .map_devid
+            .iter()
+            .map(|s| btrfs_kit::parse::parse_devid_path(s))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut fs = if let Some(geometry) = self.geometry()? {
+            anyhow::ensure!(
+                self.scan.is_none() && devid_paths.is_empty(),
+                "--scan and --map-devid need superblocks to find the devices by"
+            );
+            btrfs_kit::geometry::load_fs_geometry(&self.paths, &geometry)?
+        } else {
+            match &self.scan {
+                Some(dir) => btrfs_kit::btrfs::load_fs_scan(&self.paths, dir, &devid_paths)?,
+                None => btrfs_kit::btrfs::load_fs_mapped(&self.paths, &devid_paths)?,
+            }
+        };
+        if let Some(path) = &self.chunk_map {
+            let text = std::fs::read_to_string(path)?;
+            let chunks = btrfs_kit::chunk_map::parse_chunk_map(&text)
+                .map_err(|e| e.context(format!("in {}", path.display())))?;
+            btrfs_kit::chunk_map::use_chunk_map(&mut fs, chunks);
+        }
+        for path in &self.quarantine {
+            let text = std::fs::read_to_string(path)?;
+            fs.quarantine
+                .parse(&text)
+                .map_err(|e| e.context(format!("in {}", path.display())))?;
+        }
+        for devid_path in &self.ddrescue_map {
+            let (devid, path) = btrfs_kit::parse::parse_devid_path(devid_path)?;
+            let dev = fs
+                .devid_map
+                .get(&devid)
+                .ok_or_else(|| anyhow::anyhow!("--ddrescue-map: devid {devid} is not present"))?;
+            let text = std::fs::read_to_string(&path)?;
+            let ranges = btrfs_kit::quarantine::parse_ddrescue_map(&text)
+                .map_err(|e| e.context(format!("in {}", path.display())))?;
+            let offset = dev.offset;
+            for range in ranges {
+                let (start, end) = (range.start.max(offset), range.end);
+                if start < end {
+                    fs.quarantine
+                        .add_physical(devid, start - offset..end - offset);
+                }
+            }
+        }
+        if let Some(backup) = self.at_backup_root {
+            fs.pin_backup_root(backup)?;
+        }
+        if let Some(root) = &self.at_root {
+            fs.pin_root(btrfs_kit::parse::parse_u64(root)?)?;
+        }
+        if let Some(generation) = fs.pinned_generation {
+            eprintln!(
+                "reading the trees of generation {generation} from the root tree at {}",
+                { fs.master_sb.root }
+            );
+        }
+        fs.cancel = btrfs_kit::cancel::CancellationToken::sigint();
+        Ok(fs)
+    }
+
+    /// the geometry file with the options given on the command line after
+    /// it, if either was given
+    fn geometry(&self) -> anyhow::Result<Option<btrfs_kit::geometry::Geometry>> {
+        let mut text = match &self.geometry {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => String::new(),
+        };
+        let options = [
+            ("chunk_root", &self.chunk_root),
+            ("root", &self.root_tree),
+            ("nodesize", &self.nodesize),
+            ("sectorsize", &self.sectorsize),
+            ("csum_type", &self.csum_type),
+        ];
+        for (name, value) in options {
+            if let Some(value) = value {
+                text.push_str(&format!("\n{name} = {value}"));
+            }
+        }
+        if self.geometry.is_none() && options.iter().all(|(_, value)| value.is_none()) {
+            return Ok(None);
+        }
+        btrfs_kit::geometry::Geometry::parse(&text).map(Some)
+    }
+}
+
+#[derive(Args, Debug)]
+struct BlockArgs {
+    /// logical address of the block
+    bytenr: String,
+
+    /// hexdump the block with header fields, items and item data marked
+    #[clap(long)]
+    annotate: bool,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct InspectArgs {
+    #[command(subcommand)]
+    command: Option<InspectCommand>,
+
+    /// logical address, or physical offset when --devid is given
+    #[clap(required = true)]
+    offset: Option<String>,
+
+    /// treat the offset as a physical offset on this device
+    #[clap(long)]
+    devid: Option<u64>,
+
+    /// as --devid, with the device given by its uuid
+    #[clap(long, conflicts_with = "devid")]
+    device_uuid: Option<btrfs_kit::structures::BtrfsUuid>,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Subcommand, Debug)]
+enum InspectCommand {
+    /// list where each extent of a file is, logically and on each device, with its compression and sharing
+    ExtentMap {
+        /// path of the file from the top of the subvolume
+        path: String,
+        /// subvolume (tree) id holding the file
+        #[clap(long, default_value = "5")]
+        subvol: String,
+        #[clap(flatten)]
+        devices: Devices,
+    },
+}
+
+#[derive(Args, Debug)]
+struct ChunkMapArgs {
+    /// write the map to a file instead of printing it
+    #[clap(long, short)]
+    output: Option<std::path::PathBuf>,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct HeatmapArgs {
+    /// text, csv or svg
+    #[clap(long, default_value = "text")]
+    format: btrfs_kit::heatmap::HeatmapFormat,
+
+    /// cells per device for text, pixels for svg
+    #[clap(long, default_value_t = 64)]
+    width: usize,
+
+    /// write to a file instead of printing
+    #[clap(long, short)]
+    output: Option<std::path::PathBuf>,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct PartitionsArgs {
+    /// whole disk images or block devices
+    #[clap(required = true)]
+    paths: Vec<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct SbScanArgs {
+    /// look for a superblock at every multiple of this many bytes
+    #[clap(long, default_value_t = 512)]
+    align: u64,
+
+    /// images or block devices to scan
     #[clap(required = true)]
     paths: Vec<std::path::PathBuf>,
 }
 
+#[derive(Args, Debug)]
+struct FindNameArgs {
+    /// name to look for; * and ? are wildcards, otherwise any name containing it matches
+    pattern: String,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct DumpTreeArgs {
+    /// tree to dump, by id or name (e.g. 7 or CSUM_TREE)
+    #[clap(long)]
+    tree: String,
+
+    /// first key to dump, as objectid,type,offset (e.g. 1234,EXTENT_CSUM,0)
+    #[clap(long, default_value = "0,MIN,0")]
+    min_key: String,
+
+    /// last key to dump, as objectid,type,offset; "max" may be used for a field
+    #[clap(long, default_value = "max,MAX,max")]
+    max_key: String,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct ExportArgs {
+    /// tree to export, by id or name; may be given more than once
+    #[clap(long, required = true)]
+    tree: Vec<String>,
+
+    /// first key to export, as objectid,type,offset
+    #[clap(long, default_value = "0,MIN,0")]
+    min_key: String,
+
+    /// last key to export, as objectid,type,offset; "max" may be used for a field
+    #[clap(long, default_value = "max,MAX,max")]
+    max_key: String,
+
+    /// csv, or parquet if built with the parquet feature
+    #[clap(long, default_value = "csv")]
+    format: btrfs_kit::export::ExportFormat,
+
+    /// file to write
+    #[clap(long, short)]
+    output: std::path::PathBuf,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct DiffArgs {
+    /// the filesystem to compare with, e.g. a copy taken before a repair;
+    /// give once per device
+    #[clap(long, required_unless_present = "backup_root")]
+    before: Vec<std::path::PathBuf>,
+
+    /// compare with the trees of this backup root (0 to 3) of the
+    /// superblock instead, so listing what rolling back to it would lose
+    #[clap(long, conflicts_with = "before")]
+    backup_root: Option<usize>,
+
+    /// only compare these trees, by id or name
+    #[clap(long)]
+    tree: Vec<String>,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct CarveArgs {
+    /// directory to write the carved files and their index.csv to
+    #[clap(long, short)]
+    output: std::path::PathBuf,
+
+    /// skip the data extents the extent tree records, to recover only deleted data
+    #[clap(long)]
+    free_only: bool,
+
+    /// also carve from sectors starting with a known file signature (JPEG, PNG, PDF, ZIP, ...)
+    #[clap(long)]
+    signatures: bool,
+
+    /// the most bytes to carve from a signature
+    #[clap(long, default_value = "16777216")]
+    max_size: String,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct RestoreArgs {
+    /// subvolume (tree) id, e.g. 5 for the top level
+    subvol: String,
+
+    /// file or directory to restore, relative to the top of the subvolume; all of it by default
+    #[clap(long, default_value = "")]
+    path: String,
+
+    /// directory to restore into; nothing in it is overwritten
+    #[clap(long, short)]
+    output: std::path::PathBuf,
+
+    /// write zeros for data which can't be read and go on, listing the ranges lost per file
+    #[clap(long)]
+    salvage: bool,
+
+    /// don't set the xattrs (and ACLs) of what is restored
+    #[clap(long)]
+    no_xattrs: bool,
+
+    /// enable fs-verity again on the files which had it, with the same parameters and signature
+    #[clap(long)]
+    verity: bool,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct CatArgs {
+    /// path of the file from the top of the subvolume
+    path: String,
+
+    /// subvolume (tree) id holding the file
+    #[clap(long, default_value = "5")]
+    subvol: String,
+
+    /// write zeros for data which can't be read and go on, listing the ranges lost
+    #[clap(long)]
+    salvage: bool,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct StatsArgs {
+    /// tree to examine, by id or name
+    #[clap(long)]
+    tree: String,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct DuArgs {
+    /// break the totals down by subvolume, as referenced, exclusive and shared bytes
+    #[clap(long)]
+    subvolumes: bool,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct FreeSpaceCheckArgs {
+    /// say what would bring the free space tree back in line with the extent tree
+    #[clap(long)]
+    fix_plan: bool,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct ScrubArgs {
+    /// verify the checksum, bytenr, fsid and level of every copy of every tree block
+    #[clap(long)]
+    metadata: bool,
+
+    /// verify every copy of file data against the checksums in the csum tree
+    #[clap(long)]
+    data: bool,
+
+    /// with --data, only check data referenced by this subvolume (may be repeated)
+    #[clap(long, requires = "data")]
+    subvol: Vec<String>,
+
+    /// verify the P and Q parity of every used full stripe of RAID5/6 chunks
+    #[clap(long)]
+    parity: bool,
+
+    /// check the tree blocks and data on this many threads, 0 for one per CPU
+    #[clap(short, long, default_value_t = 1)]
+    jobs: usize,
+
+    /// with --jobs, read the data on this many threads while the others check it
+    #[clap(long, default_value_t = 2)]
+    readers: usize,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct CensusArgs {
+    /// walk the trees on this many threads, 0 for one per CPU
+    #[clap(short, long, default_value_t = 1)]
+    jobs: usize,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+/// what every command which writes to the devices takes
+#[derive(Args, Debug)]
+struct RepairFlags {
+    /// only report what would be written
+    #[clap(long)]
+    dry_run: bool,
+
+    /// directory where everything overwritten is saved first
+    #[clap(long, default_value = ".")]
+    backup_dir: std::path::PathBuf,
+}
+
+impl RepairFlags {
+    fn options(self) -> btrfs_kit::repair::RepairOptions {
+        btrfs_kit::repair::RepairOptions {
+            dry_run: self.dry_run,
+            backup_dir: self.backup_dir,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct RepairCopiesArgs {
+    /// logical address of the tree block or data sector to repair
+    #[clap(long, required_unless_present = "from_scrub")]
+    logical: Option<String>,
+
+    /// scrub the filesystem and repair every damaged block which has a good copy
+    #[clap(long, conflicts_with = "logical")]
+    from_scrub: bool,
+
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct RebuildStripArgs {
+    /// logical address within the full stripe
+    #[clap(long)]
+    logical: String,
+
+    /// device holding the strip to regenerate, whether data, P or Q
+    #[clap(long)]
+    devid: u64,
+
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct RebuildCsumsArgs {
+    /// logical address where the data range starts
+    #[clap(long)]
+    start: String,
+
+    /// logical address where the data range ends
+    #[clap(long)]
+    end: String,
+
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct RebuildExtentTreeArgs {
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct DeleteItemArgs {
+    /// tree holding the item, by id or name (e.g. 5 or FS_TREE)
+    #[clap(long)]
+    tree: String,
+
+    /// key of the item, as objectid,type,offset (e.g. 256,INODE_ITEM,0)
+    #[clap(long)]
+    key: String,
+
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct InsertItemArgs {
+    /// tree to hold the item, by id or name (e.g. 5 or FS_TREE)
+    #[clap(long)]
+    tree: String,
+
+    /// key of the item, as objectid,type,offset (e.g. 256,INODE_ITEM,0)
+    #[clap(long)]
+    key: String,
+
+    /// the item's data, in hex
+    #[clap(long, required_unless_present = "data_file")]
+    data: Option<String>,
+
+    /// file holding the item's data
+    #[clap(long, conflicts_with = "data")]
+    data_file: Option<std::path::PathBuf>,
+
+    /// replace the data of the existing item with the key
+    #[clap(long)]
+    replace: bool,
+
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct SuperArgs {
+    #[command(subcommand)]
+    command: Option<SuperCommand>,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Subcommand, Debug)]
+enum SuperCommand {
+    /// set superblock fields and write every copy on every device
+    Edit(SuperEditArgs),
+    /// compare each device's superblock copies, optionally rewriting those
+    /// which aren't the newest
+    Mirrors(SuperMirrorsArgs),
+}
+
+#[derive(Args, Debug)]
+#[command(group(clap::ArgGroup::new("only_report").arg("dry_run").requires("resync")))]
+struct SuperMirrorsArgs {
+    /// write each device's newest copy over its stale, differing and
+    /// invalid ones
+    #[clap(long)]
+    resync: bool,
+
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct SuperEditArgs {
+    /// field=value, as the field is named by `super`; may be repeated
+    #[clap(long, required = true)]
+    set: Vec<String>,
+
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct SetFsidArgs {
+    /// the new fsid, e.g. as printed by uuidgen
+    fsid: String,
+
+    /// only change the superblocks, keeping the old fsid for the metadata as btrfstune -m does
+    #[clap(long)]
+    metadata_uuid: bool,
+
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct RemoveDeviceArgs {
+    /// devid of the lost device, which is not among the devices given
+    #[clap(long)]
+    devid: u64,
+
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Args, Debug)]
+struct FeaturesArgs {
+    /// a compat_ro or incompat feature to set, e.g. block-group-tree; may be repeated
+    #[clap(long)]
+    set: Vec<String>,
+
+    /// a feature to clear, e.g. free-space-tree-valid; may be repeated
+    #[clap(long)]
+    clear: Vec<String>,
+
+    #[clap(flatten)]
+    repair: RepairFlags,
+
+    #[clap(flatten)]
+    devices: Devices,
+}
+
+#[derive(Subcommand, Debug)]
+enum InodeCommand {
+    /// show every item belonging to an inode, decoded, and how much of its data is reachable
+    Show {
+        /// subvolume (tree) id, e.g. 5 for the top level
+        subvol: String,
+        /// inode number
+        inode: String,
+        #[clap(flatten)]
+        devices: Devices,
+    },
+}
+
+// parsed once, so the variants needn't be boxed to the same size
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// dump the superblock, chunk tree, root tree and extent tree
+    Dump(Devices),
+    /// dump every field of the superblock, or edit them
+    Super(SuperArgs),
+    /// interactively browse the metadata trees
+    Browse(Devices),
+    /// dump a single metadata block
+    Block(BlockArgs),
+    /// report what is stored at a logical or physical offset, or where the extents of a file are
+    Inspect(InspectArgs),
+    /// dump the items of a tree between two keys
+    DumpTree(DumpTreeArgs),
+    /// list the items added, removed or changed in each tree since another copy of the filesystem or a backup root
+    Diff(DiffArgs),
+    /// write the items of trees to a CSV or Parquet file, one row per item
+    Export(ExportArgs),
+    /// report space allocated and used per block group type and profile
+    Df(Devices),
+    /// report the file data referenced by the filesystem or by each subvolume
+    Du(DuArgs),
+    /// recount the referenced and exclusive bytes of every qgroup and compare them with the quota tree
+    QgroupCheck(Devices),
+    /// compare the free space tree with the space the extent tree leaves free
+    FreeSpaceCheck(FreeSpaceCheckArgs),
+    /// verify the filesystem offline; without options everything is checked
+    Scrub(ScrubArgs),
+    /// check every tree block against the kernel's tree-checker rules
+    Check(Devices),
+    /// count the blocks of each tree reached by walking it against those the extent tree records
+    Census(CensusArgs),
+    /// list tree blocks in metadata chunks which verify but no current root reaches
+    Orphans(Devices),
+    /// list recently deleted files and how much of each is likely to be recovered
+    Undelete(Devices),
+    /// write out the data in data chunks which matches surviving checksums or file signatures
+    Carve(CarveArgs),
+    /// copy files out of a subvolume into a directory
+    Restore(RestoreArgs),
+    /// write the contents of a file to stdout
+    Cat(CatArgs),
+    /// compare the copies of every tree block on DUP and RAID1 chunks
+    Mirrors(Devices),
+    /// overwrite damaged copies of a mirrored block with a copy that verifies
+    RepairCopies(RepairCopiesArgs),
+    /// regenerate one strip of a RAID5/6 full stripe from the others
+    RebuildStrip(RebuildStripArgs),
+    /// regenerate damaged csum tree leaves from the data they cover
+    RebuildCsums(RebuildCsumsArgs),
+    /// write a new extent tree describing every block and extent the other trees use
+    RebuildExtentTree(RebuildExtentTreeArgs),
+    /// remove one item from a leaf in place
+    DeleteItem(DeleteItemArgs),
+    /// add one item to a leaf with room for it, or replace an item's data
+    InsertItem(InsertItemArgs),
+    /// change the fsid, rewriting every tree block header and dev item
+    SetFsid(SetFsidArgs),
+    /// show the feature flags, or set and clear compat_ro and incompat features
+    Features(FeaturesArgs),
+    /// record a lost device as removed, so the filesystem mounts without it
+    RemoveDevice(RemoveDeviceArgs),
+    /// report the state of a device replace recorded in the dev tree
+    DevReplace(Devices),
+    /// report an unfinished balance and any relocation trees
+    Balance(Devices),
+    /// work out what replaying the log tree at mount would change, and whether it would succeed
+    LogReplay(Devices),
+    /// count a tree's nodes per level and its items per type, and measure leaf fill
+    Stats(StatsArgs),
+    /// inspect individual inodes
+    #[command(subcommand)]
+    Inode(InodeCommand),
+    /// search every subvolume for file names matching a pattern
+    FindName(FindNameArgs),
+    /// query the filesystem interactively, keeping it loaded between commands
+    Shell(Devices),
+    /// print the chunk map in the file format --chunk-map reads
+    ChunkMap(ChunkMapArgs),
+    /// show where on each device the chunks are, of which type and how used
+    Heatmap(HeatmapArgs),
+    /// list where the raid stripe tree puts each copy of each data extent
+    RaidStripeTree(Devices),
+    /// list the partitions of disk images and those holding a btrfs filesystem
+    Partitions(PartitionsArgs),
+    /// scan whole files for superblocks, wherever they are
+    SbScan(SbScanArgs),
+}
+
 fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    // warnings about unsupported features should be seen without RUST_LOG set
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     let args = Params::parse();
+    btrfs_kit::units::set_human(args.human);
+    if let Some(size) = &args.map_window {
+        let size = btrfs_kit::parse::parse_u64(size)?;
+        btrfs_kit::mapped_file::set_window_size(Some(size as usize));
+    }
+    if let Some(size) = &args.zone_size {
+        let size = btrfs_kit::parse::parse_u64(size)?;
+        anyhow::ensure!(
+            size.is_power_of_two() && size >= 4096,
+            "the zone size must be a power of two of at least 4KiB"
+        );
+        btrfs_kit::mapped_file::set_zone_size(Some(size));
+    }
+    if let Some(size) = &args.block_cache {
+        let size = btrfs_kit::parse::parse_u64(size)?;
+        btrfs_kit::block_cache::set_block_cache_size(size as usize);
+    }
+    btrfs_kit::btrfs::set_default_csum_policy(args.csum_policy);
+    // the interactive commands keep the default, as a query cut short
+    // would leave the filesystem unusable for the next
+    if !matches!(args.command, Some(Command::Shell(_) | Command::Browse(_))) {
+        btrfs_kit::cancel::cancel_on_sigint();
+    }
 
-    let fs = btrfs_kit::btrfs::load_fs(&args.paths)?;
-    btrfs_kit::dump::dump_fs(&fs)?;
+    match args.command {
+        None => btrfs_kit::dump::dump_fs(&args.devices.load()?)?,
+        Some(Command::Dump(devices)) => btrfs_kit::dump::dump_fs(&devices.load()?)?,
+        Some(Command::Super(SuperArgs {
+            command: Some(SuperCommand::Edit(args)),
+            ..
+        })) => {
+            let fs = args.devices.load()?;
+            let edits = args
+                .set
+                .iter()
+                .map(|edit| {
+                    edit.split_once('=')
+                        .map(|(field, value)| (field.to_string(), value.to_string()))
+                        .ok_or_else(|| anyhow::anyhow!("{edit:?} is not field=value"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let options = args.repair.options();
+            btrfs_kit::dump::dump_edit_super(&fs, &edits, &options)?
+        }
+        Some(Command::Super(SuperArgs {
+            command: Some(SuperCommand::Mirrors(args)),
+            ..
+        })) => {
+            let options = args.repair.options();
+            let fs = args.devices.load()?;
+            btrfs_kit::dump::dump_super_mirrors(&fs, args.resync.then_some(&options))?
+        }
+        Some(Command::Super(args)) => btrfs_kit::dump::dump_sb(&args.devices.load()?.master_sb),
+        Some(Command::Browse(devices)) => btrfs_kit::browse::browse(&devices.load()?)?,
+        Some(Command::Block(args)) => {
+            let fs = args.devices.load()?;
+            let bytenr = btrfs_kit::parse::parse_u64(&args.bytenr)?;
+            if args.annotate {
+                btrfs_kit::dump::dump_block_annotated(&fs, bytenr)?
+            } else {
+                btrfs_kit::dump::dump_block(&fs, bytenr)?
+            }
+        }
+        Some(Command::Inspect(InspectArgs {
+            command:
+                Some(InspectCommand::ExtentMap {
+                    path,
+                    subvol,
+                    devices,
+                }),
+            ..
+        })) => btrfs_kit::inspect::inspect_extent_map(
+            &devices.load()?,
+            btrfs_kit::parse::parse_treeid(&subvol)?,
+            &path,
+        )?,
+        Some(Command::Inspect(args)) => {
+            let fs = args.devices.load()?;
+            let offset = btrfs_kit::parse::parse_u64(args.offset.as_deref().unwrap_or_default())?;
+            let devid = match args.device_uuid {
+                Some(uuid) => Some(
+                    fs.devuuid_map
+                        .get(&uuid)
+                        .ok_or_else(|| anyhow::anyhow!("no device with uuid {uuid}"))?
+                        .devid,
+                ),
+                None => args.devid,
+            };
+            match devid {
+                Some(devid) => btrfs_kit::inspect::inspect_physical(&fs, devid, offset)?,
+                None => btrfs_kit::inspect::inspect_logical(&fs, offset)?,
+            }
+        }
+        Some(Command::DumpTree(args)) => {
+            let fs = args.devices.load()?;
+            btrfs_kit::dump::dump_tree_range(
+                &fs,
+                btrfs_kit::parse::parse_treeid(&args.tree)?,
+                btrfs_kit::parse::parse_key_str(&args.min_key)?,
+                btrfs_kit::parse::parse_key_str(&args.max_key)?,
+            )?
+        }
+        Some(Command::Diff(args)) => {
+            let fs = args.devices.load()?;
+            let trees = args
+                .tree
+                .iter()
+                .map(|s| btrfs_kit::parse::parse_treeid(s))
+                .collect::<anyhow::Result<Vec<u64>>>()?;
+            let trees = if trees.is_empty() {
+                None
+            } else {
+                Some(&trees[..])
+            };
+            match args.backup_root {
+                Some(backup) => btrfs_kit::dump::dump_diff_backup(&fs, backup, trees)?,
+                None => {
+                    let mut before = btrfs_kit::btrfs::load_fs(&args.before)?;
+                    before.cancel = btrfs_kit::cancel::CancellationToken::sigint();
+                    btrfs_kit::dump::dump_diff(&before, &fs, trees)?
+                }
+            }
+        }
+        Some(Command::Export(args)) => {
+            let fs = args.devices.load()?;
+            let trees = args
+                .tree
+                .iter()
+                .map(|s| btrfs_kit::parse::parse_treeid(s))
+                .collect::<anyhow::Result<Vec<u64>>>()?;
+            btrfs_kit::dump::dump_export(
+                &fs,
+                &trees,
+                btrfs_kit::parse::parse_key_str(&args.min_key)?,
+                btrfs_kit::parse::parse_key_str(&args.max_key)?,
+                args.format,
+                &args.output,
+            )?
+        }
+        Some(Command::Df(devices)) => btrfs_kit::dump::dump_df(&devices.load()?)?,
+        Some(Command::Du(args)) => {
+            btrfs_kit::dump::dump_du(&args.devices.load()?, args.subvolumes)?
+        }
+        Some(Command::QgroupCheck(devices)) => {
+            btrfs_kit::dump::dump_qgroup_check(&devices.load()?)?
+        }
+        Some(Command::FreeSpaceCheck(args)) => {
+            btrfs_kit::dump::dump_free_space_check(&args.devices.load()?, args.fix_plan)?
+        }
+        Some(Command::Scrub(args)) => {
+            let fs = args.devices.load()?;
+            let everything = !args.metadata && !args.data && !args.parity;
+            if args.metadata || everything {
+                btrfs_kit::dump::dump_scrub_metadata(&fs, args.jobs)?;
+            }
+            if args.data || everything {
+                let subvols = args
+                    .subvol
+                    .iter()
+                    .map(|s| btrfs_kit::parse::parse_treeid(s))
+                    .collect::<anyhow::Result<Vec<u64>>>()?;
+                let subvols = if subvols.is_empty() {
+                    None
+                } else {
+                    Some(&subvols[..])
+                };
+                btrfs_kit::dump::dump_scrub_data(&fs, subvols, args.jobs, args.readers)?;
+            }
+            if args.parity || everything {
+                btrfs_kit::dump::dump_scrub_parity(&fs)?;
+            }
+        }
+        Some(Command::Check(devices)) => btrfs_kit::dump::dump_check(&devices.load()?),
+        Some(Command::Census(args)) => {
+            btrfs_kit::dump::dump_census(&args.devices.load()?, args.jobs)?
+        }
+        Some(Command::Orphans(devices)) => btrfs_kit::dump::dump_orphans(&devices.load()?),
+        Some(Command::Undelete(devices)) => btrfs_kit::dump::dump_undelete(&devices.load()?)?,
+        Some(Command::Carve(args)) => {
+            let fs = args.devices.load()?;
+            let options = btrfs_kit::carve::CarveOptions {
+                output_dir: args.output,
+                free_only: args.free_only,
+                signatures: args.signatures,
+                max_size: btrfs_kit::parse::parse_u64(&args.max_size)?,
+            };
+            btrfs_kit::dump::dump_carve(&fs, &options)?
+        }
+        Some(Command::Restore(args)) => {
+            let fs = args.devices.load()?;
+            let options = btrfs_kit::restore::RestoreOptions {
+                output_dir: args.output,
+                salvage: args.salvage,
+                xattrs: !args.no_xattrs,
+                verity: args.verity,
+            };
+            let subvol = btrfs_kit::parse::parse_treeid(&args.subvol)?;
+            btrfs_kit::dump::dump_restore(&fs, subvol, &args.path, &options)?
+        }
+        Some(Command::Cat(args)) => btrfs_kit::dump::dump_cat(
+            &args.devices.load()?,
+            btrfs_kit::parse::parse_treeid(&args.subvol)?,
+            &args.path,
+            args.salvage,
+        )?,
+        Some(Command::Mirrors(devices)) => {
+            btrfs_kit::dump::dump_mirror_divergence(&devices.load()?)
+        }
+        Some(Command::RepairCopies(args)) => {
+            let fs = args.devices.load()?;
+            let logical = match &args.logical {
+                Some(logical) => Some(btrfs_kit::parse::parse_u64(logical)?),
+                None => None,
+            };
+            let options = args.repair.options();
+            btrfs_kit::dump::dump_repair_copies(&fs, logical, &options)?
+        }
+        Some(Command::RebuildStrip(args)) => {
+            let fs = args.devices.load()?;
+            let options = args.repair.options();
+            btrfs_kit::dump::dump_rebuild_strip(
+                &fs,
+                btrfs_kit::parse::parse_u64(&args.logical)?,
+                args.devid,
+                &options,
+            )?
+        }
+        Some(Command::RebuildCsums(args)) => {
+            let fs = args.devices.load()?;
+            let options = args.repair.options();
+            btrfs_kit::dump::dump_rebuild_csums(
+                &fs,
+                btrfs_kit::parse::parse_u64(&args.start)?,
+                btrfs_kit::parse::parse_u64(&args.end)?,
+                &options,
+            )?
+        }
+        Some(Command::RebuildExtentTree(args)) => {
+            let fs = args.devices.load()?;
+            let options = args.repair.options();
+            btrfs_kit::dump::dump_rebuild_extent_tree(&fs, &options)?
+        }
+        Some(Command::DeleteItem(args)) => {
+            let fs = args.devices.load()?;
+            let options = args.repair.options();
+            btrfs_kit::dump::dump_delete_item(
+                &fs,
+                btrfs_kit::parse::parse_treeid(&args.tree)?,
+                &btrfs_kit::parse::parse_key_str(&args.key)?,
+                &options,
+            )?
+        }
+        Some(Command::InsertItem(args)) => {
+            let fs = args.devices.load()?;
+            let data = match (&args.data, &args.data_file) {
+                (Some(data), _) => hex::decode(data.trim_start_matches("0x"))?,
+                (None, Some(path)) => std::fs::read(path)?,
+                (None, None) => unreachable!(),
+            };
+            let options = args.repair.options();
+            btrfs_kit::dump::dump_insert_item(
+                &fs,
+                btrfs_kit::parse::parse_treeid(&args.tree)?,
+                &btrfs_kit::parse::parse_key_str(&args.key)?,
+                &data,
+                args.replace,
+                &options,
+            )?
+        }
+        Some(Command::SetFsid(args)) => {
+            let fs = args.devices.load()?;
+            let options = args.repair.options();
+            btrfs_kit::dump::dump_change_fsid(
+                &fs,
+                args.fsid.parse()?,
+                args.metadata_uuid,
+                &options,
+            )?
+        }
+        Some(Command::Features(args)) => {
+            let fs = args.devices.load()?;
+            let options = args.repair.options();
+            btrfs_kit::dump::dump_features(&fs, &args.set, &args.clear, &options)?
+        }
+        Some(Command::RemoveDevice(args)) => {
+            let fs = args.devices.load()?;
+            let options = args.repair.options();
+            btrfs_kit::dump::dump_remove_device(&fs, args.devid, &options)?
+        }
+        Some(Command::DevReplace(devices)) => btrfs_kit::dump::dump_dev_replace(&devices.load()?)?,
+        Some(Command::Balance(devices)) => btrfs_kit::dump::dump_balance(&devices.load()?)?,
+        Some(Command::LogReplay(devices)) => btrfs_kit::dump::dump_log_replay(&devices.load()?)?,
+        Some(Command::Stats(args)) => btrfs_kit::dump::dump_tree_stats(
+            &args.devices.load()?,
+            btrfs_kit::parse::parse_treeid(&args.tree)?,
+        )?,
+        Some(Command::Inode(InodeCommand::Show {
+            subvol,
+            inode,
+            devices,
+        })) => btrfs_kit::dump::dump_inode(
+            &devices.load()?,
+            btrfs_kit::parse::parse_treeid(&subvol)?,
+            btrfs_kit::parse::parse_u64(&inode)?,
+        )?,
+        Some(Command::FindName(args)) => {
+            btrfs_kit::dump::dump_find_name(&args.devices.load()?, &args.pattern)
+        }
+        Some(Command::Shell(devices)) => btrfs_kit::shell::shell(&devices.load()?)?,
+        Some(Command::ChunkMap(args)) => {
+            btrfs_kit::dump::dump_chunk_map(&args.devices.load()?, args.output.as_deref())?
+        }
+        Some(Command::Heatmap(args)) => btrfs_kit::dump::dump_heatmap(
+            &args.devices.load()?,
+            args.format,
+            args.width,
+            args.output.as_deref(),
+        )?,
+        Some(Command::RaidStripeTree(devices)) => {
+            btrfs_kit::dump::dump_raid_stripe_tree(&devices.load()?)?
+        }
+        Some(Command::Partitions(args)) => btrfs_kit::dump::dump_partitions(&args.paths)?,
+        Some(Command::SbScan(args)) => btrfs_kit::dump::dump_superblock_scan(
+            &args.paths,
+            args.align,
+            &btrfs_kit::cancel::CancellationToken::sigint(),
+        )?,
+    }
 
+    if btrfs_kit::cancel::CancellationToken::sigint().was_cancelled() {
+        eprintln!("interrupted: the report above is partial");
+    }
     Ok(())
 }
@@ -1,56 +1,301 @@
+//! Memory mapped devices and images, through memmap2 so that images can be
+//! read on any platform memmap2 supports.
+
 use anyhow::*;
-use libc::c_void;
+use memmap2::{Mmap, MmapOptions};
 use more_asserts::*;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::ops::Index;
-use std::os::fd::AsRawFd;
+use std::io::{Seek, SeekFrom};
+use std::ops::{Deref, Index, Range};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 /// Interpret offsets of a memory mapped file as
 /// references to arbitrary types.
-
 pub struct MappedFile {
-    pointer: *mut c_void,
     len: usize,
-    mapping_size: usize,
+    mappings: Mappings,
+    /// the size of its zones, if it's a zoned device
+    zone_size: Option<u64>,
+}
+
+enum Mappings {
+    /// with the file and where offset 0 lies in it, for checked reads
+    Whole(Arc<Mapping>, File, u64),
+    Windowed(Windows),
+}
+
+/// one mmap of part of a file
+struct Mapping(Mmap);
+
+/// a file mapped a window at a time as it is read. Window n maps from n
+/// times the window size on, and overlaps the next by WINDOW_OVERLAP so that
+/// a block starting in a window lies wholly within it. Longer slices which
+/// cross a window boundary are mapped on their own, for as long as they are
+/// held. Only the live most recently used windows are kept; slices hold on
+/// to their window, so one dropped from here is unmapped with its last slice.
+struct Windows {
+    file: File,
+    /// where offset 0 lies in the file
+    offset: u64,
+    size: usize,
+    live: usize,
+    lru: Mutex<WindowLru>,
+}
+
+#[derive(Default)]
+struct WindowLru {
+    clock: u64,
+    /// window index to (last use, window)
+    windows: HashMap<usize, (u64, Arc<Mapping>)>,
+    /// last use to window index, oldest first
+    uses: BTreeMap<u64, usize>,
+}
+
+/// bytes of a MappedFile, holding on to the mapping they lie in so that it
+/// isn't unmapped while they are in use
+pub struct MappedSlice {
+    mapping: Arc<Mapping>,
+    /// within the mapping
+    range: Range<usize>,
+}
+
+impl Deref for MappedSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.mapping.0[self.range.clone()]
+    }
+}
+
+/// how much windows overlap, and so the longest slice sure to fit in one
+const WINDOW_OVERLAP: usize = 1024 * 1024;
+
+/// how many windows of a file are kept mapped, which with the default 64MiB
+/// windows of 32 bit hosts is about half a GiB of address space
+const LIVE_WINDOWS: usize = 8;
+
+/// 0 to map files whole
+static WINDOW_SIZE: AtomicUsize = AtomicUsize::new(if cfg!(target_pointer_width = "32") {
+    64 * 1024 * 1024
+} else {
+    0
+});
+
+/// 0 for image files not to be taken as zoned
+static ZONE_SIZE: AtomicU64 = AtomicU64::new(0);
+
+/// map the files opened from now on in windows of size bytes (rounded up to
+/// pages) as they're read, or with None (or 0) whole. Windows are the
+/// default on 32 bit hosts, which haven't the address space to map a large
+/// device whole.
+pub fn set_window_size(size: Option<usize>) {
+    let ps = sysconf::page::pagesize();
+    let size = size.map_or(0, |size| size.div_ceil(ps).saturating_mul(ps));
+    WINDOW_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// take the image files opened from now on to be zoned devices, with zones
+/// of size bytes, or with None not. Block devices report their own zones.
+pub fn set_zone_size(size: Option<u64>) {
+    ZONE_SIZE.store(size.unwrap_or(0), Ordering::Relaxed);
+}
+
+/// the zone size a zoned block device reports, which BLKGETZONESZ gives in
+/// 512 byte sectors, 0 if it isn't zoned
+#[cfg(target_os = "linux")]
+fn block_zone_size(f: &File) -> Option<u64> {
+    use std::os::fd::AsRawFd;
+    const BLKGETZONESZ: u64 = 0x8004_1284;
+    let mut sectors: u32 = 0;
+    let ret = unsafe { libc::ioctl(f.as_raw_fd(), BLKGETZONESZ as _, &mut sectors) };
+    (ret == 0 && sectors != 0).then_some(sectors as u64 * 512)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn block_zone_size(_f: &File) -> Option<u64> {
+    None
+}
+
+/// how a range of a MappedFile is about to be read, passed on to madvise
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Advice {
+    Normal,
+    Sequential,
+    Random,
+    WillNeed,
+}
+
+impl Mapping {
+    fn new(f: &File, offset: u64, len: usize) -> Result<Mapping> {
+        // memmap2 aligns the offset as the platform needs
+        let mmap = unsafe { MmapOptions::new().offset(offset).len(len).map(f) }
+            .map_err(|e| anyhow!("Failed to map file: {e}"))?;
+        Ok(Mapping(mmap))
+    }
+
+    #[cfg(unix)]
+    fn advise(&self, offset: usize, length: usize, advice: Advice) -> Result<()> {
+        let advice = match advice {
+            Advice::Normal => memmap2::Advice::Normal,
+            Advice::Sequential => memmap2::Advice::Sequential,
+            Advice::Random => memmap2::Advice::Random,
+            Advice::WillNeed => memmap2::Advice::WillNeed,
+        };
+        self.0
+            .advise_range(advice, offset, length)
+            .map_err(|e| anyhow!("madvise failed: {e}"))
+    }
+
+    #[cfg(not(unix))]
+    fn advise(&self, _offset: usize, _length: usize, _advice: Advice) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Windows {
+    /// length bytes from offset, mapping the window they lie in if it isn't
+    /// already, which may drop the least recently used
+    fn slice(&self, len: usize, offset: usize, length: usize) -> Result<MappedSlice> {
+        let index = offset / self.size;
+        let within = offset % self.size;
+        if within + length > self.size + WINDOW_OVERLAP {
+            let mapping = Mapping::new(&self.file, self.offset + offset as u64, length)
+                .with_context(|| format!("mapping {length} bytes at {offset}"))?;
+            return Ok(MappedSlice {
+                mapping: Arc::new(mapping),
+                range: 0..length,
+            });
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.clock += 1;
+        let clock = lru.clock;
+        let mapping = match lru.windows.get_mut(&index) {
+            Some((last_used, mapping)) => {
+                let previous = std::mem::replace(last_used, clock);
+                let mapping = Arc::clone(mapping);
+                lru.uses.remove(&previous);
+                mapping
+            }
+            None => {
+                let start = index * self.size;
+                let window_len = (self.size + WINDOW_OVERLAP).min(len - start);
+                let mapping = Mapping::new(&self.file, self.offset + start as u64, window_len)
+                    .with_context(|| format!("mapping the window at {start}"))?;
+                let mapping = Arc::new(mapping);
+                lru.windows.insert(index, (clock, Arc::clone(&mapping)));
+                mapping
+            }
+        };
+        lru.uses.insert(clock, index);
+        while lru.windows.len() > self.live {
+            let (_, oldest) = lru.uses.pop_first().unwrap();
+            lru.windows.remove(&oldest);
+        }
+        Ok(MappedSlice {
+            mapping,
+            range: within..within + length,
+        })
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    fn advise(&self, offset: usize, length: usize, advice: Advice) -> Result<()> {
+        use std::os::fd::AsRawFd;
+        let advice = match advice {
+            Advice::Normal => libc::POSIX_FADV_NORMAL,
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::Random => libc::POSIX_FADV_RANDOM,
+            Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        };
+        let ret = unsafe {
+            libc::posix_fadvise(
+                self.file.as_raw_fd(),
+                (self.offset + offset as u64) as libc::off_t,
+                length as libc::off_t,
+                advice,
+            )
+        };
+        ensure!(
+            ret == 0,
+            "posix_fadvise failed: {}",
+            std::io::Error::from_raw_os_error(ret)
+        );
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    fn advise(&self, _offset: usize, _length: usize, _advice: Advice) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl MappedFile {
     pub fn open(file: &Path) -> Result<MappedFile> {
-        let f = File::open(file)?;
+        MappedFile::open_range(file, 0, None)
+    }
+
+    /// map length bytes of the file (or the rest of it) from offset on, so
+    /// that offsets count from there, e.g. for a partition of a whole disk
+    /// image. The file is mapped whole, or in windows after set_window_size.
+    pub fn open_range(file: &Path, offset: u64, length: Option<u64>) -> Result<MappedFile> {
+        let window_size = WINDOW_SIZE.load(Ordering::Relaxed);
+        MappedFile::open_windowed(file, offset, length, window_size)
+    }
+
+    /// open_range with windows of window_size bytes, a multiple of the page
+    /// size, or 0 to map whole
+    fn open_windowed(
+        file: &Path,
+        offset: u64,
+        length: Option<u64>,
+        window_size: usize,
+    ) -> Result<MappedFile> {
+        let mut f = File::open(file)?;
         let md = f.metadata()?;
-        let len = if md.is_file() {
-            md.len() as usize
+        let zone_size = if md.is_file() {
+            Some(ZONE_SIZE.load(Ordering::Relaxed)).filter(|&size| size != 0)
         } else {
-            //assume block device
-            let mut len64 = 0_u64;
-            let len_ref = &mut len64 as *mut u64;
-            let ret = unsafe { ioctls::blkgetsize64(f.as_raw_fd(), len_ref) };
-            assert_eq!(0, ret);
-            len64 as usize
+            block_zone_size(&f)
         };
-        let ps = sysconf::page::pagesize();
-        let mapping_size = ((len + ps - 1) / ps) * ps;
-        let p = unsafe {
-            libc::mmap(
-                std::ptr::null_mut::<c_void>(),
-                len,
-                libc::PROT_READ,
-                libc::MAP_PRIVATE,
-                f.as_raw_fd(),
-                0,
+        let file_len = if md.is_file() {
+            md.len()
+        } else {
+            // a block device, whose size seeking to the end finds
+            f.seek(SeekFrom::End(0))
+                .with_context(|| format!("cannot find the size of {}", file.display()))?
+        };
+        ensure!(
+            offset < file_len,
+            "offset {offset} is beyond the end of {} ({file_len} bytes)",
+            file.display()
+        );
+        let len = match length {
+            Some(length) => length.min(file_len - offset),
+            None => file_len - offset,
+        };
+        let len = usize::try_from(len).map_err(|_| {
+            anyhow!(
+                "{} is too large to address on this host ({len} bytes)",
+                file.display()
             )
+        })?;
+        let mappings = if window_size == 0 || len <= window_size {
+            Mappings::Whole(Arc::new(Mapping::new(&f, offset, len)?), f, offset)
+        } else {
+            Mappings::Windowed(Windows {
+                file: f,
+                offset,
+                size: window_size,
+                live: LIVE_WINDOWS,
+                lru: Mutex::new(WindowLru::default()),
+            })
         };
-        if libc::MAP_FAILED == p {
-            return Err(anyhow!(
-                "Failed to map file: {}",
-                std::io::Error::last_os_error()
-            ));
-        }
         Ok(MappedFile {
-            pointer: p,
             len,
-            mapping_size,
+            mappings,
+            zone_size,
         })
     }
 
@@ -58,42 +303,124 @@ impl MappedFile {
         self.len
     }
 
+    /// the size of the zones of a zoned device, as the block device reports
+    /// it or set_zone_size gave it for an image
+    pub fn zone_size(&self) -> Option<u64> {
+        self.zone_size
+    }
+
     /* this function exists to make clippy happy */
     pub fn is_empty(&self) -> bool {
         self.len == 0
     }
 
-    /// Returns a reference to T. T should be a primitive type or
-    /// (probably) #[repr(C)]
-    /// panics if the index is out of bounds.
-    pub fn at<T>(&self, offset: usize) -> &T {
-        if self.len - std::mem::size_of::<T>() <= offset {
-            panic!("access beyond end of file");
+    /// Returns a copy of the T at offset. T should be a primitive type or
+    /// (probably) #[repr(C)]; offset needn't be aligned for it. An error if
+    /// it runs past the end of the file or the window holding it can't be
+    /// mapped.
+    pub fn at<T: Copy>(&self, offset: usize) -> Result<T> {
+        let size = std::mem::size_of::<T>();
+        ensure!(
+            offset.checked_add(size).is_some_and(|end| end <= self.len),
+            "{size} bytes at {offset} run past the end of the file ({} bytes)",
+            self.len
+        );
+        let bytes = self.slice(offset, std::mem::size_of::<T>())?;
+        Ok(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+    }
+
+    /// tell the kernel how the bytes in range will be read, e.g. WillNeed to
+    /// read them ahead. The range is widened to whole pages and clipped to
+    /// the end of the file. A file mapped in windows is advised through its
+    /// descriptor, as the range may not be mapped yet. Only a hint, which is
+    /// dropped where the platform has no equivalent.
+    pub fn advise(&self, range: Range<usize>, advice: Advice) -> Result<()> {
+        let end = range.end.min(self.len);
+        if range.start >= end {
+            return Ok(());
+        }
+        match &self.mappings {
+            Mappings::Whole(mapping, ..) => mapping.advise(range.start, end - range.start, advice),
+            Mappings::Windowed(windows) => windows.advise(range.start, end - range.start, advice),
         }
-        unsafe { &*((self.pointer as usize + offset) as *mut c_void as *const T) }
     }
 
-    /// Returns a slice of u8s representing part of the mapped file
-    pub fn slice(&self, offset: usize, length: usize) -> &[u8] {
+    /// Returns a slice of u8s representing part of the mapped file, or an
+    /// error if the window holding it can't be mapped
+    pub fn slice(&self, offset: usize, length: usize) -> Result<MappedSlice> {
         assert_le!(offset + length, self.len);
-        unsafe {
-            std::slice::from_raw_parts(
-                &*((self.pointer as usize + offset) as *mut c_void as *const u8),
-                length,
-            )
+        match &self.mappings {
+            Mappings::Whole(mapping, ..) => Ok(MappedSlice {
+                mapping: Arc::clone(mapping),
+                range: offset..offset + length,
+            }),
+            Mappings::Windowed(windows) => windows.slice(self.len, offset, length),
         }
     }
+
+    /// length bytes at offset, read with pread rather than through the
+    /// mapping. Touching a page of the mapping which can't be read (a bad
+    /// sector, or past the end of a file which has shrunk) raises SIGBUS,
+    /// which kills the process, where pread returns an error. (mincore can't
+    /// tell beforehand, as it reports pages past the end of a shrunk file as
+    /// resident.) The bytes read are returned rather than the mapping's, so
+    /// they are only read once and can't be reclaimed from under the caller.
+    pub fn checked_read(&self, offset: usize, length: usize) -> Result<Vec<u8>> {
+        ensure!(
+            offset
+                .checked_add(length)
+                .is_some_and(|end| end <= self.len),
+            "{length} bytes at {offset} run past the end of the file ({} bytes)",
+            self.len
+        );
+        let (file, start) = match &self.mappings {
+            Mappings::Whole(_, file, start) => (file, *start),
+            Mappings::Windowed(windows) => (&windows.file, windows.offset),
+        };
+        read_at(file, start + offset as u64, length)
+            .with_context(|| format!("reading {length} bytes at {offset}"))
+    }
 }
 
-impl Drop for MappedFile {
-    fn drop(&mut self) {
-        unsafe {
-            let ret = libc::munmap(self.pointer, self.mapping_size);
-            assert_eq!(ret, 0);
+/// length bytes at offset in the file
+#[cfg(unix)]
+fn read_at(file: &File, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+    use std::os::unix::fs::FileExt;
+    let mut buffer = vec![0u8; length];
+    file.read_exact_at(&mut buffer, offset)?;
+    std::io::Result::Ok(buffer)
+}
+
+/// length bytes at offset in the file. seek_read moves the file's cursor,
+/// but nothing else here uses it.
+#[cfg(windows)]
+fn read_at(file: &File, offset: u64, length: usize) -> std::io::Result<Vec<u8>> {
+    use std::os::windows::fs::FileExt;
+    let mut buffer = vec![0u8; length];
+    let mut done = 0;
+    while done < length {
+        match file.seek_read(&mut buffer[done..], offset + done as u64)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            read => done += read,
         }
     }
+    std::io::Result::Ok(buffer)
 }
 
+/// every byte value, for Index to hand out a reference to, as one into a
+/// window could outlive the window's mapping
+static BYTE_VALUES: [u8; 256] = {
+    let mut values = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        values[i] = i as u8;
+        i += 1;
+    }
+    values
+};
+
+/// panics if the index is out of bounds or the window holding it can't be
+/// mapped
 impl Index<usize> for MappedFile {
     type Output = u8;
 
@@ -101,7 +428,10 @@ impl Index<usize> for MappedFile {
         if self.len - std::mem::size_of::<usize>() <= idx {
             panic!("access beyond end of file");
         }
-        unsafe { &*((self.pointer as usize + idx) as *mut c_void as *const u8) }
+        let byte = self
+            .slice(idx, 1)
+            .unwrap_or_else(|e| panic!("reading byte {idx}: {e:#}"))[0];
+        &BYTE_VALUES[byte as usize]
     }
 }
 
@@ -122,31 +452,118 @@ mod tests {
     }
 
     #[test]
+    #[allow(unnecessary_transmutes)]
     fn file_at() -> Result<()> {
         let mf = MappedFile::open(Path::new("Cargo.toml"))?;
-        assert_eq!(*mf.at::<u8>(0), b'[');
-        assert_eq!(*mf.at::<u8>(1), b'p');
+        assert_eq!(mf.at::<u8>(0)?, b'[');
+        assert_eq!(mf.at::<u8>(1)?, b'p');
 
-        assert_eq!(*mf.at::<u16>(0), unsafe {
+        assert_eq!(mf.at::<u16>(0)?, unsafe {
             std::mem::transmute::<[u8; 2], u16>([b'[', b'p'])
         });
-        assert_eq!(*mf.at::<u16>(1), unsafe {
+        assert_eq!(mf.at::<u16>(1)?, unsafe {
             std::mem::transmute::<[u8; 2], u16>([b'p', b'a'])
         });
+        assert_eq!(mf.at::<u16>(2)?, unsafe {
+            std::mem::transmute::<[u8; 2], u16>([b'a', b'c'])
+        });
 
         Ok(())
     }
+    #[test]
+    fn file_open_range() -> Result<()> {
+        let whole = MappedFile::open(Path::new("Cargo.toml"))?;
+        let part = MappedFile::open_range(Path::new("Cargo.toml"), 3, None)?;
+        assert_eq!(part.len(), whole.len() - 3);
+        assert_eq!(*part.slice(0, 4)?, *whole.slice(3, 4)?);
+        let part = MappedFile::open_range(Path::new("Cargo.toml"), 3, Some(5))?;
+        assert_eq!(part.len(), 5);
+        let end = whole.len() as u64;
+        assert!(MappedFile::open_range(Path::new("Cargo.toml"), end, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn file_advise() -> Result<()> {
+        let part = MappedFile::open_range(Path::new("Cargo.toml"), 3, None)?;
+        part.advise(0..part.len(), Advice::Sequential)?;
+        part.advise(1..part.len() + 4096, Advice::WillNeed)?;
+        part.advise(part.len()..part.len() + 1, Advice::Random)?;
+        Ok(())
+    }
+
+    #[test]
+    fn file_windows() -> Result<()> {
+        let ps = sysconf::page::pagesize();
+        let path = std::env::temp_dir().join(format!("mapped_file_windows.{}", std::process::id()));
+        let data: Vec<u8> = (0..ps * 5 + 100).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data)?;
+        let mut mf = MappedFile::open_windowed(&path, 7, None, ps)?;
+        let data = &data[7..];
+        assert_eq!(mf.len(), data.len());
+        assert_eq!(*mf.slice(ps - 10, 20)?, data[ps - 10..ps + 10]);
+        assert_eq!(*mf.slice(10, ps * 3)?, data[10..ps * 3 + 10]);
+        assert_eq!(mf[ps * 4 + 3], data[ps * 4 + 3]);
+        assert_eq!(mf.at::<u8>(ps * 2)?, data[ps * 2]);
+        mf.advise(0..mf.len(), Advice::WillNeed)?;
+        let Mappings::Windowed(windows) = &mut mf.mappings else {
+            panic!("not windowed")
+        };
+        windows.live = 1;
+        let held = mf.slice(ps + 10, 20)?;
+        assert_eq!(mf.at::<u8>(ps * 2 + 1)?, data[ps * 2 + 1]);
+        let Mappings::Windowed(windows) = &mf.mappings else {
+            panic!("not windowed")
+        };
+        let lru = windows.lru.lock().unwrap();
+        assert_eq!(lru.windows.len(), 1);
+        assert!(lru.windows.contains_key(&2));
+        drop(lru);
+        // window 1 is gone from the LRU but still mapped for the slice
+        assert_eq!(*held, data[ps + 10..ps + 30]);
+        assert_eq!(*mf.slice(ps - 10, 20)?, data[ps - 10..ps + 10]);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn file_checked_read() -> Result<()> {
+        let ps = sysconf::page::pagesize();
+        let path = std::env::temp_dir().join(format!("mapped_file_checked.{}", std::process::id()));
+        let data: Vec<u8> = (0..ps * 4).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data)?;
+        let mf = MappedFile::open(&path)?;
+        assert_eq!(mf.checked_read(ps - 10, 20)?, &data[ps - 10..ps + 10]);
+        assert!(mf.checked_read(ps * 4 - 10, 20).is_err());
+        // the last pages are past the end of the file now, and touching
+        // them through the mapping would raise SIGBUS
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_len(ps as u64 * 2)?;
+        assert!(mf.checked_read(ps * 3, 16).is_err());
+        assert_eq!(mf.checked_read(16, 16)?, &data[16..32]);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
     #[test]
     #[should_panic(expected = "access beyond end of file")]
+    #[allow(clippy::unnecessary_operation)]
     fn file_index_panic() {
         let mf = MappedFile::open(Path::new("Cargo.toml")).unwrap();
         mf[mf.len];
     }
 
     #[test]
-    #[should_panic]
-    fn file_at_panic() {
-        let mf = MappedFile::open(Path::new("Cargo.toml")).unwrap();
-        mf.at::<u8>(mf.len);
+    fn file_at_bounds() -> Result<()> {
+        let mf = MappedFile::open(Path::new("Cargo.toml"))?;
+        assert!(mf.at::<u8>(mf.len).is_err());
+        assert!(mf.at::<u16>(mf.len - 1).is_err());
+        assert!(mf.at::<u8>(usize::MAX).is_err());
+        assert_eq!(mf.at::<u8>(mf.len - 1)?, b'\n');
+        // a T larger than the whole file
+        assert!(mf.at::<[u8; 8192]>(0).is_err());
+        Ok(())
     }
 }
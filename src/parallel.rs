@@ -0,0 +1,76 @@
+//! Parallel tree walks. Nothing is written to the filesystem while it is
+//! scanned, so the blocks of each level of a tree can be read and checked on
+//! a pool of threads, with only the bookkeeping (which blocks were seen, and
+//! collecting the results) left to the calling thread.
+
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::structures::*;
+
+use anyhow::*;
+use rayon::prelude::*;
+use std::collections::HashSet;
+
+/// (logical, level the parent implies) of the blocks a node points to, in
+/// key order
+pub fn child_pointers(block: &[u8]) -> Vec<(u64, Option<u8>)> {
+    let level = unsafe { &*(block.as_ptr() as *const btrfs_header) }.level;
+    node_entries(block)
+        .into_iter()
+        .filter_map(|entry| match entry {
+            NodeEntry::Ptr(ptr) => Some((ptr.blockptr, level.checked_sub(1))),
+            _ => None,
+        })
+        .collect()
+}
+
+/// walk from each root in turn, a level at a time, visiting every block once
+/// from the first root to reach it (as the sequential walks do, though
+/// breadth first within a tree). visit runs on a pool of jobs threads (0 for
+/// one per CPU) and returns its result for the block and the pointers to
+/// follow from it. merge then gets the results of the level in order, and
+/// revisit the pointers to blocks already visited.
+pub fn walk_levels<T: Sync, R: Send>(
+    fs: &FsInfo,
+    jobs: usize,
+    roots: &[(T, u64, Option<u8>)],
+    visit: impl Fn(&T, u64, Option<u8>) -> (R, Vec<(u64, Option<u8>)>) + Sync,
+    mut merge: impl FnMut(&T, u64, R),
+    mut revisit: impl FnMut(&T, u64, Option<u8>),
+) -> Result<()> {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    let mut seen = HashSet::new();
+    for (tree, root, root_level) in roots {
+        let mut pending = vec![(*root, *root_level)];
+        while !pending.is_empty() {
+            let mut level = Vec::new();
+            for (logical, expected_level) in pending {
+                if seen.insert(logical) {
+                    level.push((logical, expected_level));
+                } else {
+                    revisit(tree, logical, expected_level);
+                }
+            }
+            let visited: Vec<_> = pool.install(|| {
+                level
+                    .par_iter()
+                    .map(|&(logical, expected_level)| {
+                        if fs.cancel.is_cancelled() {
+                            return None;
+                        }
+                        Some((logical, visit(tree, logical, expected_level)))
+                    })
+                    .collect()
+            });
+            pending = Vec::new();
+            for (logical, (result, children)) in visited.into_iter().flatten() {
+                merge(tree, logical, result);
+                pending.extend(children);
+            }
+            if fs.cancel.is_cancelled() {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,258 @@
+//! Parsing of user supplied numbers, item types and tree ids, as used on
+//! the command line and in the shell.
+
+use crate::dump::fmt_treeid;
+use crate::structures::*;
+
+use anyhow::*;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// decimal or 0x-prefixed hexadecimal, or "max" for u64::MAX
+pub fn parse_u64(s: &str) -> Result<u64> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("max") {
+        return Ok(u64::MAX);
+    }
+    let parsed = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16)
+    } else {
+        s.parse::<u64>()
+    };
+    parsed.map_err(|e| anyhow!("invalid number {s:?}: {e}"))
+}
+
+/// accepts a numeric tree id, a name as printed by fmt_treeid
+/// (e.g. EXTENT_TREE), or a negative objectid such as -10
+pub fn parse_treeid(s: &str) -> Result<u64> {
+    let s = s.trim();
+    if let Some(neg) = s.strip_prefix('-') {
+        return Ok((parse_u64(neg)? as i64).wrapping_neg() as u64);
+    }
+    if let Result::Ok(id) = parse_u64(s) {
+        return Ok(id);
+    }
+    let upper = s.to_ascii_uppercase();
+    for candidate in [upper.clone(), format!("{upper}_TREE")] {
+        for id in (0..=BTRFS_RAID_STRIPE_TREE_OBJECTID).chain([
+            BTRFS_BALANCE_OBJECTID,
+            BTRFS_ORPHAN_OBJECTID,
+            BTRFS_TREE_LOG_OBJECTID,
+            BTRFS_TREE_LOG_FIXUP_OBJECTID,
+            BTRFS_TREE_RELOC_OBJECTID,
+            BTRFS_DATA_RELOC_TREE_OBJECTID,
+            BTRFS_EXTENT_CSUM_OBJECTID,
+            BTRFS_FREE_SPACE_OBJECTID,
+            BTRFS_FREE_INO_OBJECTID,
+        ]) {
+            if fmt_treeid(id) == candidate {
+                return Ok(id);
+            }
+        }
+    }
+    Err(anyhow!("unknown tree {s:?}"))
+}
+
+impl FromStr for BtrfsItemType {
+    type Err = anyhow::Error;
+
+    /// accepts the item type name (case insensitive) or its numeric value
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(t) = BtrfsItemType::ALL
+            .iter()
+            .find(|t| format!("{t:?}").eq_ignore_ascii_case(s))
+        {
+            return Ok(*t);
+        }
+        let value = parse_u64(s).map_err(|_| anyhow!("unknown item type {s:?}"))?;
+        BtrfsItemType::ALL
+            .iter()
+            .find(|t| **t as u64 == value)
+            .copied()
+            .ok_or_else(|| anyhow!("unknown item type value {value}"))
+    }
+}
+
+/// 32 hex digits, optionally split by dashes as printed
+/// (e.g. 4e7f5a6c-1b2d-4c3e-8f90-a1b2c3d4e5f6)
+impl FromStr for BtrfsUuid {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let digits: String = s.trim().chars().filter(|c| *c != '-').collect();
+        let mut uuid = [0; BTRFS_UUID_SIZE];
+        hex::decode_to_slice(&digits, &mut uuid).map_err(|e| anyhow!("invalid uuid {s:?}: {e}"))?;
+        Ok(BtrfsUuid(uuid))
+    }
+}
+
+/// objectid, item type and offset, each parsed as above
+pub fn parse_key(objectid: &str, item_type: &str, offset: &str) -> Result<btrfs_disk_key> {
+    Ok(btrfs_disk_key {
+        objectid: parse_treeid(objectid)?,
+        item_type: item_type.parse()?,
+        offset: parse_u64(offset)?,
+    })
+}
+
+/// shell style match of a name against a pattern with * and ? wildcards.
+/// A pattern without wildcards matches any name containing it.
+pub fn name_matches(pattern: &[u8], name: &[u8]) -> bool {
+    if !pattern.contains(&b'*') && !pattern.contains(&b'?') {
+        return pattern.is_empty() || name.windows(pattern.len()).any(|w| w == pattern);
+    }
+    // iterative wildcard match, backtracking to the most recent *
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// a key written as objectid,type,offset, e.g. 1234,EXTENT_CSUM,0 or 256,1,max
+pub fn parse_key_str(s: &str) -> Result<btrfs_disk_key> {
+    let fields: Vec<&str> = s.split(',').collect();
+    let [objectid, item_type, offset] = fields[..] else {
+        bail!("key {s:?} should be objectid,type,offset");
+    };
+    parse_key(objectid, item_type, offset)
+}
+
+/// a device argument: a path, or path@offset for a filesystem starting at a
+/// byte offset within the file, e.g. a partition of a whole disk image. The
+/// offset is in bytes, or in 512 byte sectors with an "s" suffix as fdisk
+/// and parted print them. A path which exists as given is never split.
+pub fn parse_device(arg: &Path) -> Result<(PathBuf, u64)> {
+    if arg.exists() {
+        return Ok((arg.to_path_buf(), 0));
+    }
+    let Some((path, offset)) = arg.to_str().and_then(|s| s.rsplit_once('@')) else {
+        return Ok((arg.to_path_buf(), 0));
+    };
+    let offset = match offset.strip_suffix('s') {
+        Some(sectors) => parse_u64(sectors)?
+            .checked_mul(512)
+            .ok_or_else(|| anyhow!("offset {offset} is too large"))?,
+        None => parse_u64(offset)?,
+    };
+    Ok((PathBuf::from(path), offset))
+}
+
+/// a devid=path mapping, the path being a device argument as parse_device
+/// takes it
+pub fn parse_devid_path(s: &str) -> Result<(u64, PathBuf)> {
+    let (devid, path) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("{s:?} should be devid=path"))?;
+    let devid = parse_u64(devid)?;
+    ensure!(devid != 0, "devid 0 is only a replace target");
+    Ok((devid, PathBuf::from(path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_u64("1234").unwrap(), 1234);
+        assert_eq!(parse_u64("0x10").unwrap(), 16);
+        assert_eq!(parse_u64("max").unwrap(), u64::MAX);
+        assert!(parse_u64("12a").is_err());
+    }
+
+    #[test]
+    fn devices() {
+        let parse = |s: &str| parse_device(Path::new(s)).unwrap();
+        assert_eq!(parse("Cargo.toml"), (PathBuf::from("Cargo.toml"), 0));
+        assert_eq!(
+            parse("disk.img@1048576"),
+            (PathBuf::from("disk.img"), 1048576)
+        );
+        assert_eq!(
+            parse("a@b/disk.img@0x100000"),
+            (PathBuf::from("a@b/disk.img"), 1 << 20)
+        );
+        assert_eq!(
+            parse("disk.img@2048s"),
+            (PathBuf::from("disk.img"), 1 << 20)
+        );
+        assert!(parse_device(Path::new("disk.img@1M")).is_err());
+        assert_eq!(
+            parse_devid_path("3=/dev/sdc1").unwrap(),
+            (3, PathBuf::from("/dev/sdc1"))
+        );
+        assert!(parse_devid_path("/dev/sdc1").is_err());
+    }
+
+    #[test]
+    fn tree_ids() {
+        assert_eq!(parse_treeid("2").unwrap(), BTRFS_EXTENT_TREE_OBJECTID);
+        assert_eq!(parse_treeid("extent").unwrap(), BTRFS_EXTENT_TREE_OBJECTID);
+        assert_eq!(parse_treeid("CSUM_TREE").unwrap(), BTRFS_CSUM_TREE_OBJECTID);
+        assert_eq!(parse_treeid("-10").unwrap(), BTRFS_EXTENT_CSUM_OBJECTID);
+        assert_eq!(
+            parse_treeid("EXTENT_CSUM").unwrap(),
+            BTRFS_EXTENT_CSUM_OBJECTID
+        );
+        assert!(parse_treeid("bogus").is_err());
+    }
+
+    #[test]
+    fn uuids() {
+        let s = "4e7f5a6c-1b2d-4c3e-8f90-a1b2c3d4e5f6";
+        let uuid: BtrfsUuid = s.parse().unwrap();
+        assert_eq!(uuid.0[0], 0x4e);
+        assert_eq!(uuid.0[15], 0xf6);
+        assert_eq!(uuid.to_string(), s);
+        assert_eq!(s.replace('-', "").parse::<BtrfsUuid>().unwrap(), uuid);
+        assert!("4e7f5a6c".parse::<BtrfsUuid>().is_err());
+        assert!(BtrfsUuid::default().is_nil());
+    }
+
+    #[test]
+    fn item_types() {
+        let t: BtrfsItemType = "extent_data".parse().unwrap();
+        assert_eq!(t, BtrfsItemType::EXTENT_DATA);
+        let t: BtrfsItemType = "0x84".parse().unwrap();
+        assert_eq!(t, BtrfsItemType::ROOT_ITEM);
+        assert!("0x02".parse::<BtrfsItemType>().is_err());
+        let t: BtrfsItemType = "max".parse().unwrap();
+        assert_eq!(t, BtrfsItemType::MAX);
+    }
+
+    #[test]
+    fn keys() {
+        let key = parse_key_str("1234,EXTENT_CSUM,max").unwrap();
+        let (objectid, offset) = (key.objectid, key.offset);
+        assert_eq!(objectid, 1234);
+        assert_eq!(key.item_type, BtrfsItemType::EXTENT_CSUM);
+        assert_eq!(offset, u64::MAX);
+        assert!(parse_key_str("1234,EXTENT_CSUM").is_err());
+    }
+
+    #[test]
+    fn names() {
+        assert!(name_matches(b"ell", b"hello.txt"));
+        assert!(!name_matches(b"elk", b"hello.txt"));
+        assert!(name_matches(b"*.txt", b"hello.txt"));
+        assert!(!name_matches(b"*.txt", b"hello.txt.bak"));
+        assert!(name_matches(b"h?llo*", b"hello.txt"));
+        assert!(name_matches(b"*l*l*", b"hello"));
+        assert!(!name_matches(b"h*z", b"hello"));
+    }
+}
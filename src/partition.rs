@@ -0,0 +1,387 @@
+//! Partition tables of whole disk images: the MBR (with logical partitions
+//! in an extended partition) and GPT, so that the btrfs partitions of an
+//! image can be found and opened as path@offset without carving them out.
+//!
+//! Sectors are taken to be 512 bytes, except that a GPT is also looked for
+//! at 4096 bytes for disks with 4K logical sectors.
+//!
+//! Also here is the scan for superblocks anywhere in an image, for when the
+//! partition table is gone or the filesystem's start is otherwise unknown.
+
+use crate::btrfs::*;
+use crate::cancel::CancellationToken;
+use crate::mapped_file::{Advice, MappedFile};
+use crate::structures::*;
+
+use anyhow::*;
+use crc::{Crc, CRC_32_ISO_HDLC};
+use log::*;
+use std::path::Path;
+
+const MBR_SECTOR: u64 = 512;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// EBRs followed before giving up on a chain which loops
+const MAX_LOGICAL_PARTITIONS: u32 = 128;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Partition {
+    /// as in /dev/sda1: primary MBR partitions are 1 to 4 and logical ones
+    /// count from 5, GPT partitions count entries from 1
+    pub number: u32,
+    /// offset of the partition in bytes
+    pub start: u64,
+    pub length: u64,
+    /// the MBR type byte, or the GPT type and partition name
+    pub description: String,
+}
+
+fn le32(disk: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(disk[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(disk: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(disk[offset..offset + 8].try_into().unwrap())
+}
+
+/// the (type, start sector, sectors) of the 4 entries of the MBR or EBR in
+/// the sector at offset, or None without the 0x55AA signature
+fn mbr_entries(disk: &[u8], offset: u64) -> Option<[(u8, u64, u64); 4]> {
+    let offset = usize::try_from(offset).ok()?;
+    let sector = disk.get(offset..offset + MBR_SECTOR as usize)?;
+    if sector[510..512] != [0x55, 0xaa] {
+        return None;
+    }
+    Some(std::array::from_fn(|i| {
+        let entry = 446 + 16 * i;
+        (
+            sector[entry + 4],
+            le32(sector, entry + 8) as u64,
+            le32(sector, entry + 12) as u64,
+        )
+    }))
+}
+
+fn mbr_partitions(disk: &[u8], entries: &[(u8, u64, u64); 4]) -> Vec<Partition> {
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (i, &(kind, start, sectors)) in entries.iter().enumerate() {
+        if kind == 0 || sectors == 0 {
+            continue;
+        }
+        if MBR_TYPES_EXTENDED.contains(&kind) {
+            extended = Some(start);
+            continue;
+        }
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            start: start * MBR_SECTOR,
+            length: sectors * MBR_SECTOR,
+            description: format!("MBR type {kind:#04x}"),
+        });
+    }
+    // logical partitions: each EBR describes one, relative to the EBR, and
+    // links to the next relative to the extended partition
+    let Some(extended) = extended else {
+        return partitions;
+    };
+    let mut ebr = extended;
+    for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+        let Some(entries) = mbr_entries(disk, ebr * MBR_SECTOR) else {
+            warn!("no EBR at sector {ebr}");
+            break;
+        };
+        let (kind, start, sectors) = entries[0];
+        if kind != 0 && sectors != 0 {
+            partitions.push(Partition {
+                number,
+                start: (ebr + start) * MBR_SECTOR,
+                length: sectors * MBR_SECTOR,
+                description: format!("MBR type {kind:#04x}"),
+            });
+        }
+        let (_, next, next_sectors) = entries[1];
+        if next == 0 || next_sectors == 0 {
+            break;
+        }
+        ebr = extended + next;
+    }
+    partitions
+}
+
+/// a GUID as printed, the first three fields being little endian
+fn fmt_guid(guid: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{}-{}",
+        le32(guid, 0),
+        u16::from_le_bytes([guid[4], guid[5]]),
+        u16::from_le_bytes([guid[6], guid[7]]),
+        hex::encode(&guid[8..10]),
+        hex::encode(&guid[10..16])
+    )
+}
+
+const GPT_TYPES: &[(&str, &str)] = &[
+    ("0fc63daf-8483-4772-8e79-3d69d8477de4", "Linux filesystem"),
+    ("c12a7328-f81f-11d2-ba4b-00a0c93ec93b", "EFI system"),
+    ("0657fd6d-a4ab-43c4-84e5-0933c84b4f4f", "Linux swap"),
+    ("e6d6d379-f507-44c2-a23c-238f2a3df928", "Linux LVM"),
+    ("a19d880f-05fc-4d3b-a006-743f0f84911e", "Linux RAID"),
+    (
+        "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7",
+        "Microsoft basic data",
+    ),
+];
+
+fn gpt_partitions(disk: &[u8], sector: u64) -> Option<Vec<Partition>> {
+    let header_start = usize::try_from(sector).ok()?;
+    let header = disk.get(header_start..header_start + 92)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return None;
+    }
+    let header_size = (le32(header, 12) as usize).clamp(92, sector as usize);
+    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+    let mut copy = disk.get(header_start..header_start + header_size)?.to_vec();
+    copy[16..20].fill(0);
+    if crc.checksum(&copy) != le32(header, 16) {
+        warn!("GPT header at {header_start} has a bad checksum, using it anyway");
+    }
+    let entries_start = le64(header, 72).checked_mul(sector)?;
+    let num_entries = le32(header, 80) as usize;
+    let entry_size = le32(header, 84) as usize;
+    if entry_size < 128 {
+        warn!("GPT entries of {entry_size} bytes are too small");
+        return None;
+    }
+    let entries_start = usize::try_from(entries_start).ok()?;
+    let entries = disk.get(entries_start..entries_start + num_entries * entry_size)?;
+    if crc.checksum(entries) != le32(header, 88) {
+        warn!("GPT partition entries have a bad checksum, using them anyway");
+    }
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks_exact(entry_size).enumerate() {
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let (first, last) = (le64(entry, 32), le64(entry, 40));
+        if last < first {
+            continue;
+        }
+        let guid = fmt_guid(&entry[..16]);
+        let kind = GPT_TYPES
+            .iter()
+            .find(|(g, _)| *g == guid)
+            .map_or(guid.as_str(), |(_, name)| name);
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+        let name = String::from_utf16_lossy(&name);
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            start: first * sector,
+            length: (last - first + 1) * sector,
+            description: if name.is_empty() {
+                format!("GPT {kind}")
+            } else {
+                format!("GPT {kind} {name:?}")
+            },
+        });
+    }
+    Some(partitions)
+}
+
+/// the partitions of a disk image: the GPT if the MBR is a protective one,
+/// otherwise the MBR's. Empty if there is no partition table.
+pub fn parse_partitions(disk: &[u8]) -> Vec<Partition> {
+    let Some(entries) = mbr_entries(disk, 0) else {
+        return Vec::new();
+    };
+    if entries
+        .iter()
+        .any(|(kind, ..)| *kind == MBR_TYPE_GPT_PROTECTIVE)
+    {
+        if let Some(partitions) = [512, 4096]
+            .into_iter()
+            .find_map(|sector| gpt_partitions(disk, sector))
+        {
+            return partitions;
+        }
+        warn!("protective MBR without a readable GPT");
+    }
+    mbr_partitions(disk, &entries)
+}
+
+/// the partitions of the file at path
+pub fn read_partitions(path: &Path) -> Result<Vec<Partition>> {
+    let mf = MappedFile::open(path)?;
+    Ok(parse_partitions(&mf.slice(0, mf.len())?))
+}
+
+/// the partitions of the file at path whose primary superblock is valid
+pub fn btrfs_partitions(path: &Path) -> Result<Vec<(Partition, btrfs_super_block)>> {
+    let mf = MappedFile::open(path)?;
+    let mut found = Vec::new();
+    for partition in parse_partitions(&mf.slice(0, mf.len())?) {
+        let sb_offset = partition.start + BTRFS_SUPER_INFO_OFFSET as u64;
+        if partition.length < (BTRFS_SUPER_INFO_OFFSET + BTRFS_SUPER_INFO_SIZE) as u64
+            || sb_offset + BTRFS_SUPER_INFO_SIZE as u64 > mf.len() as u64
+        {
+            continue;
+        }
+        if let Result::Ok(sb) = load_sb_at(&mf, sb_offset as usize) {
+            found.push((partition, sb));
+        }
+    }
+    Ok(found)
+}
+
+/// a superblock found by scan_superblocks
+pub struct SuperblockHit {
+    /// where it is in the file
+    pub offset: u64,
+    pub sb: btrfs_super_block,
+    /// false if its checksum type can't be verified, only crc32c being
+    /// implemented
+    pub verified: bool,
+}
+
+impl SuperblockHit {
+    /// where the device the superblock belongs to starts in the file, from
+    /// the offset the superblock records for itself
+    pub fn device_start(&self) -> Option<u64> {
+        let bytenr = self.sb.bytenr;
+        let mut mirrors = (0..BTRFS_SUPER_MIRROR_MAX).map(|mirror| match mirror {
+            0 => BTRFS_SUPER_INFO_OFFSET as u64,
+            _ => 0x4000 << (BTRFS_SUPER_MIRROR_SHIFT * mirror),
+        });
+        if mirrors.any(|m| m == bytenr) {
+            self.offset.checked_sub(bytenr)
+        } else {
+            None
+        }
+    }
+}
+
+/// every superblock in the file whose magic is at a multiple of align and
+/// whose checksum verifies; those with a checksum type which can't be
+/// verified are included, unverified. The scan stops early if cancelled. An
+/// error if the file can't be mapped as it goes.
+pub fn scan_superblocks(
+    mf: &MappedFile,
+    align: u64,
+    cancel: &CancellationToken,
+) -> Result<Vec<SuperblockHit>> {
+    let magic_at = std::mem::offset_of!(btrfs_super_block, magic);
+    let csum_type_at = std::mem::offset_of!(btrfs_super_block, csum_type);
+    let magic = BTRFS_MAGIC.to_le_bytes();
+    if let Err(e) = mf.advise(0..mf.len(), Advice::Sequential) {
+        debug!("{e}");
+    }
+    let mut hits = Vec::new();
+    let mut offset = 0;
+    while offset as usize + BTRFS_SUPER_INFO_SIZE <= mf.len() && !cancel.is_cancelled() {
+        let at = offset as usize;
+        if *mf.slice(at + magic_at, 8)? == magic {
+            let csum_type = mf.slice(at + csum_type_at, 2)?;
+            let csum_type = u16::from_le_bytes([csum_type[0], csum_type[1]]);
+            if csum_type == BtrfsCsumType::CRC32 as u16 {
+                if let Result::Ok(sb) = load_sb_at(mf, at) {
+                    hits.push(SuperblockHit {
+                        offset,
+                        sb,
+                        verified: true,
+                    });
+                }
+            } else if csum_type <= BtrfsCsumType::BLAKE2 as u16 {
+                hits.push(SuperblockHit {
+                    offset,
+                    sb: mf.at::<btrfs_super_block>(at)?,
+                    verified: false,
+                });
+            }
+        }
+        offset += align;
+    }
+    Ok(hits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mbr_entry(sector: &mut [u8], i: usize, kind: u8, start: u32, sectors: u32) {
+        let entry = 446 + 16 * i;
+        sector[entry + 4] = kind;
+        sector[entry + 8..entry + 12].copy_from_slice(&start.to_le_bytes());
+        sector[entry + 12..entry + 16].copy_from_slice(&sectors.to_le_bytes());
+        sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+    }
+
+    #[test]
+    fn mbr_with_logical_partitions() {
+        let mut disk = vec![0_u8; 64 * 512];
+        mbr_entry(&mut disk, 0, 0x83, 2, 8);
+        mbr_entry(&mut disk, 1, 0x05, 16, 40);
+        // EBRs at sectors 16 and 32, each describing a partition after it
+        mbr_entry(&mut disk[16 * 512..], 0, 0x83, 1, 4);
+        mbr_entry(&mut disk[16 * 512..], 1, 0x05, 16, 20);
+        mbr_entry(&mut disk[32 * 512..], 0, 0x82, 2, 6);
+        let starts: Vec<(u32, u64, u64)> = parse_partitions(&disk)
+            .iter()
+            .map(|p| (p.number, p.start / 512, p.length / 512))
+            .collect();
+        assert_eq!(starts, vec![(1, 2, 8), (5, 17, 4), (6, 34, 6)]);
+    }
+
+    #[test]
+    fn gpt_behind_protective_mbr() {
+        let mut disk = vec![0_u8; 64 * 512];
+        mbr_entry(&mut disk, 0, MBR_TYPE_GPT_PROTECTIVE, 1, 63);
+        let header = &mut disk[512..1024];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&92_u32.to_le_bytes());
+        header[72..80].copy_from_slice(&2_u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4_u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128_u32.to_le_bytes());
+        let entry = &mut disk[1024 + 128..1024 + 256];
+        entry[..16].copy_from_slice(&[
+            0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47,
+            0x7d, 0xe4,
+        ]);
+        entry[32..40].copy_from_slice(&34_u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&63_u64.to_le_bytes());
+        entry[56..60].copy_from_slice(&[b'f', 0, b's', 0]);
+        let partitions = parse_partitions(&disk);
+        assert_eq!(
+            partitions,
+            vec![Partition {
+                number: 2,
+                start: 34 * 512,
+                length: 30 * 512,
+                description: String::from("GPT Linux filesystem \"fs\""),
+            }]
+        );
+    }
+
+    #[test]
+    fn device_start_from_mirror_bytenr() {
+        let mut sb: btrfs_super_block = unsafe { std::mem::zeroed() };
+        let mut hit = |offset: u64, bytenr: u64| {
+            sb.bytenr = bytenr;
+            SuperblockHit {
+                offset,
+                sb,
+                verified: true,
+            }
+            .device_start()
+        };
+        assert_eq!(hit(1 << 20 | 65536, 65536), Some(1 << 20));
+        assert_eq!(hit(1 << 30, 64 << 20), Some((1 << 30) - (64 << 20)));
+        assert_eq!(hit(65536, 64 << 20), None);
+        assert_eq!(hit(1 << 20, 12345), None);
+    }
+}
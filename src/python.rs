@@ -0,0 +1,155 @@
+//! A Python module over the core of the library, built with the python
+//! feature, for ad hoc recovery scripts: open a filesystem, search its
+//! trees, decode items and translate logical addresses.
+//!
+//! Keys are (objectid, item type, offset) tuples. Trees and item types may
+//! be given by number or by name, as on the command line; the item types of
+//! the keys returned are numbers.
+
+use crate::address::*;
+use crate::btrfs::{tree_root_offset, FsInfo};
+use crate::items::describe_item;
+use crate::parse::parse_treeid;
+use crate::structures::*;
+use crate::tree::*;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::path::PathBuf;
+
+/// a tree id, or a name such as fs or csum
+#[derive(FromPyObject)]
+enum TreeArg {
+    Id(u64),
+    Name(String),
+}
+
+impl TreeArg {
+    fn id(&self) -> PyResult<u64> {
+        match self {
+            TreeArg::Id(id) => Ok(*id),
+            TreeArg::Name(name) => Ok(parse_treeid(name)?),
+        }
+    }
+}
+
+/// an item type value, or its name such as INODE_ITEM
+#[derive(FromPyObject)]
+enum ItemTypeArg {
+    Value(u64),
+    Name(String),
+}
+
+type KeyArg = (u64, ItemTypeArg, u64);
+type Key = (u64, u8, u64);
+
+fn disk_key((objectid, item_type, offset): &KeyArg) -> PyResult<btrfs_disk_key> {
+    let item_type = match item_type {
+        ItemTypeArg::Value(value) => BtrfsItemType::ALL
+            .iter()
+            .find(|t| **t as u64 == *value)
+            .copied()
+            .ok_or_else(|| PyValueError::new_err(format!("unknown item type value {value}")))?,
+        ItemTypeArg::Name(name) => name.parse()?,
+    };
+    Ok(btrfs_disk_key {
+        objectid: *objectid,
+        item_type,
+        offset: *offset,
+    })
+}
+
+fn key_tuple(key: &btrfs_disk_key) -> Key {
+    (key.objectid, key.item_type as u8, key.offset)
+}
+
+/// an open filesystem
+#[pyclass(frozen, module = "btrfs_kit")]
+pub struct Fs(FsInfo);
+
+#[pymethods]
+impl Fs {
+    /// the size of a tree block
+    #[getter]
+    fn nodesize(&self) -> u32 {
+        self.0.master_sb.nodesize
+    }
+
+    /// the filesystem uuid
+    #[getter]
+    fn fsid(&self) -> String {
+        self.0.fsid.to_string()
+    }
+
+    /// the logical address of the root block of a tree
+    fn tree_root(&self, tree: TreeArg) -> PyResult<u64> {
+        let tree_id = tree.id()?;
+        tree_root_offset(&self.0, tree_id)
+            .ok_or_else(|| PyValueError::new_err(format!("tree {tree_id} not found")))
+    }
+
+    /// the (key, data) of each item of a tree whose key lies between min and
+    /// max inclusive, in key order
+    #[pyo3(signature = (tree, min = None, max = None))]
+    fn search<'py>(
+        &self,
+        py: Python<'py>,
+        tree: TreeArg,
+        min: Option<KeyArg>,
+        max: Option<KeyArg>,
+    ) -> PyResult<Vec<(Key, Bound<'py, PyBytes>)>> {
+        let root = self.tree_root(tree)?;
+        let mut search = key_range(None, None, None);
+        if let Some(min) = min {
+            search.min_key = disk_key(&min)?;
+        }
+        if let Some(max) = max {
+            search.max_key = disk_key(&max)?;
+        }
+        Ok(search_range(&self.0, root, search)
+            .map(|(item, data, _block_offset, _slot)| {
+                (key_tuple(&item.key), PyBytes::new(py, &data))
+            })
+            .collect())
+    }
+
+    /// the (devid, physical offset) of each copy of a logical address.
+    /// Striped chunks aren't supported.
+    fn logical_to_physical(&self, logical: u64) -> PyResult<Vec<(u64, u64)>> {
+        Ok(block_copies(&self.0, logical, 1)?
+            .iter()
+            .map(|copy| (copy.devid, copy.physical))
+            .collect())
+    }
+
+    /// the tree block at logical, from the first device holding a copy. The
+    /// block isn't verified.
+    fn read_block<'py>(&self, py: Python<'py>, logical: u64) -> PyResult<Bound<'py, PyBytes>> {
+        Ok(PyBytes::new(
+            py,
+            &load_virt_block_unverified(&self.0, logical)?,
+        ))
+    }
+}
+
+/// open the filesystem on the devices or images given
+#[pyfunction]
+fn load_fs(paths: Vec<PathBuf>) -> PyResult<Fs> {
+    Ok(Fs(crate::btrfs::load_fs(&paths)?))
+}
+
+/// a description of an item's data as printed by dump-tree, one entry per
+/// line
+#[pyfunction]
+fn describe(key: KeyArg, data: &[u8]) -> PyResult<Vec<String>> {
+    Ok(describe_item(&disk_key(&key)?, data))
+}
+
+#[pymodule]
+fn btrfs_kit(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Fs>()?;
+    module.add_function(wrap_pyfunction!(load_fs, module)?)?;
+    module.add_function(wrap_pyfunction!(describe, module)?)?;
+    Ok(())
+}
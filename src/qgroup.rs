@@ -0,0 +1,264 @@
+//! Offline verification of quota groups: the referenced and exclusive bytes
+//! of every qgroup counted afresh from the fs trees, as a quota rescan
+//! would, and compared with its QGROUP_INFO item.
+//!
+//! A level 0 qgroup counts the tree blocks and data extents its subvolume
+//! reaches, including those it shares with snapshots; those no other
+//! subvolume reaches are exclusive to it. A higher level qgroup counts what
+//! any subvolume among its members (through QGROUP_RELATION items, at any
+//! depth) reaches, and as exclusive what only its members reach. Data
+//! extents count whole, however little of them files still use.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::dump::fmt_treeid;
+use crate::items::item_as;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// a qgroupid as level/id, e.g. 0/256
+pub fn fmt_qgroupid(qgroupid: u64) -> String {
+    format!(
+        "{}/{}",
+        qgroupid >> BTRFS_QGROUP_LEVEL_SHIFT,
+        qgroupid & ((1 << BTRFS_QGROUP_LEVEL_SHIFT) - 1)
+    )
+}
+
+pub struct QgroupCount {
+    pub qgroupid: u64,
+    /// (referenced, exclusive) from its QGROUP_INFO item, if it has one
+    pub recorded: Option<(u64, u64)>,
+    /// (referenced, exclusive) as counted from the trees
+    pub found: (u64, u64),
+}
+
+impl QgroupCount {
+    pub fn drifted(&self) -> bool {
+        self.recorded != Some(self.found)
+    }
+}
+
+pub struct QgroupCheck {
+    pub status: Option<btrfs_qgroup_status_item>,
+    pub qgroups: Vec<QgroupCount>,
+    /// subvolumes whose trees couldn't be read completely, so that what
+    /// they reach is undercounted
+    pub incomplete: Vec<u64>,
+    pub problems: Vec<String>,
+}
+
+/// the tree blocks of the tree at root, and the data extents its file
+/// extent items point at as (disk_bytenr, disk_num_bytes)
+fn reached_extents(
+    fs: &FsInfo,
+    root: u64,
+    problems: &mut Vec<String>,
+) -> (HashSet<u64>, BTreeMap<u64, u64>) {
+    let mut blocks = HashSet::new();
+    let mut data = BTreeMap::new();
+    let mut stack = vec![root];
+    while let Some(bytenr) = stack.pop() {
+        if !blocks.insert(bytenr) {
+            continue;
+        }
+        let block = match load_virt_block(fs, bytenr) {
+            Result::Ok(block) => block,
+            Err(e) => {
+                problems.push(format!("block {bytenr}: {e}"));
+                continue;
+            }
+        };
+        for entry in node_entries(&block) {
+            match entry {
+                NodeEntry::Ptr(ptr) => stack.push(ptr.blockptr),
+                NodeEntry::Item(item, Some(item_data))
+                    if item.key.item_type == BtrfsItemType::EXTENT_DATA =>
+                {
+                    let Some(fe) = item_as::<btrfs_file_extent_item>(item_data) else {
+                        continue; // inline, or too short
+                    };
+                    let disk_bytenr = fe.disk_bytenr;
+                    if fe.r#type != BTRFS_FILE_EXTENT_INLINE && disk_bytenr != 0 {
+                        data.insert(disk_bytenr, fe.disk_num_bytes);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    (blocks, data)
+}
+
+/// the qgroup of a subvolume and every qgroup it is a member of, directly
+/// or through others
+fn qgroups_of(subvol: u64, parents: &BTreeMap<u64, Vec<u64>>) -> BTreeSet<u64> {
+    let mut qgroups = BTreeSet::new();
+    let mut stack = vec![subvol];
+    while let Some(qgroup) = stack.pop() {
+        if qgroups.insert(qgroup) {
+            stack.extend(parents.get(&qgroup).into_iter().flatten());
+        }
+    }
+    qgroups
+}
+
+/// (referenced, exclusive) bytes per qgroup, from the length of each extent
+/// with the subvolumes reaching it, and the qgroups of each subvolume
+fn count_qgroups<'a>(
+    extents: impl Iterator<Item = (u64, &'a [u64])>,
+    qgroups: &HashMap<u64, BTreeSet<u64>>,
+) -> BTreeMap<u64, (u64, u64)> {
+    let mut counts = BTreeMap::<u64, (u64, u64)>::new();
+    for (length, subvols) in extents {
+        let mut sets = subvols.iter().filter_map(|subvol| qgroups.get(subvol));
+        let Some(first) = sets.next() else {
+            continue;
+        };
+        let (mut any, mut all) = (first.clone(), first.clone());
+        for set in sets {
+            any.extend(set);
+            all.retain(|qgroup| set.contains(qgroup));
+        }
+        for qgroup in any {
+            let count = counts.entry(qgroup).or_default();
+            count.0 += length;
+            if all.contains(&qgroup) {
+                count.1 += length;
+            }
+        }
+    }
+    counts
+}
+
+/// recount every qgroup from the fs trees and pair the counts with those
+/// recorded in the quota tree
+pub fn check_qgroups(fs: &FsInfo) -> Result<QgroupCheck> {
+    let quota_root = tree_root_offset(fs, BTRFS_QUOTA_TREE_OBJECTID)
+        .ok_or_else(|| anyhow!("quotas are not enabled: there is no quota tree"))?;
+    let mut status = None;
+    let mut recorded = BTreeMap::new();
+    let mut parents = BTreeMap::<u64, Vec<u64>>::new();
+    let mut problems = Vec::new();
+    for (item, data, _block_offset, _slot) in
+        search_range(fs, quota_root, key_range(None, None, None))
+    {
+        let key = item.key;
+        match key.item_type {
+            BtrfsItemType::QGROUP_STATUS => {
+                status = item_as::<btrfs_qgroup_status_item>(&data).copied();
+            }
+            BtrfsItemType::QGROUP_INFO => match item_as::<btrfs_qgroup_info_item>(&data) {
+                Some(info) => {
+                    recorded.insert(key.offset, (info.rfer, info.excl));
+                }
+                None => problems.push(format!(
+                    "qgroup {}: info item too short",
+                    fmt_qgroupid(key.offset)
+                )),
+            },
+            BtrfsItemType::QGROUP_RELATION => {
+                let (member, parent) = (key.objectid, key.offset);
+                if member >> BTRFS_QGROUP_LEVEL_SHIFT < parent >> BTRFS_QGROUP_LEVEL_SHIFT {
+                    parents.entry(member).or_default().push(parent);
+                }
+            }
+            _ => {}
+        }
+    }
+    match status {
+        Some(status) => ensure!(
+            status.flags & BTRFS_QGROUP_STATUS_FLAG_SIMPLE_MODE == 0,
+            "simple quotas count each extent towards the subvolume which wrote it, since quotas were enabled; only full qgroups can be recounted"
+        ),
+        None => problems.push(String::from("the quota tree has no status item")),
+    }
+
+    let nodesize = fs.master_sb.nodesize as u64;
+    // extent start -> (length, subvolumes reaching it)
+    let mut extents = HashMap::<u64, (u64, Vec<u64>)>::new();
+    let mut incomplete = Vec::new();
+    let mut subvol_qgroups = HashMap::new();
+    for (subvol, root) in fs_trees(fs) {
+        ensure!(!fs.cancel.is_cancelled(), "interrupted");
+        let mut unread = Vec::new();
+        let (blocks, data) = reached_extents(fs, root, &mut unread);
+        if !unread.is_empty() {
+            incomplete.push(subvol);
+            problems.extend(
+                unread
+                    .iter()
+                    .map(|p| format!("{}: {p}", fmt_treeid(subvol))),
+            );
+        }
+        let reached = blocks
+            .into_iter()
+            .map(|bytenr| (bytenr, nodesize))
+            .chain(data);
+        for (start, length) in reached {
+            let extent = extents.entry(start).or_insert((length, Vec::new()));
+            extent.1.push(subvol);
+        }
+        subvol_qgroups.insert(subvol, qgroups_of(subvol, &parents));
+    }
+    let found = count_qgroups(
+        extents
+            .values()
+            .map(|(length, subvols)| (*length, subvols.as_slice())),
+        &subvol_qgroups,
+    );
+
+    // every qgroup recorded, and the level 0 qgroup of every subvolume
+    let ids: BTreeSet<u64> = recorded
+        .keys()
+        .chain(found.keys())
+        .chain(subvol_qgroups.keys())
+        .copied()
+        .collect();
+    let qgroups = ids
+        .into_iter()
+        .map(|qgroupid| QgroupCount {
+            qgroupid,
+            recorded: recorded.get(&qgroupid).copied(),
+            found: found.get(&qgroupid).copied().unwrap_or_default(),
+        })
+        .collect();
+    Ok(QgroupCheck {
+        status,
+        qgroups,
+        incomplete,
+        problems,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        // 256 and its snapshot 257 in 1/1, 258 alone
+        let level1 = 1 << BTRFS_QGROUP_LEVEL_SHIFT | 1;
+        let parents = BTreeMap::from([(256, vec![level1]), (257, vec![level1])]);
+        let qgroups: HashMap<_, _> = [256, 257, 258]
+            .into_iter()
+            .map(|subvol| (subvol, qgroups_of(subvol, &parents)))
+            .collect();
+        let extents: [(u64, &[u64]); 4] = [
+            (4096, &[256, 257]),
+            (8192, &[256]),
+            (16384, &[257, 258]),
+            (32768, &[258]),
+        ];
+        let counts = count_qgroups(extents.into_iter(), &qgroups);
+        assert_eq!(counts[&256], (4096 + 8192, 8192));
+        assert_eq!(counts[&257], (4096 + 16384, 0));
+        assert_eq!(counts[&258], (16384 + 32768, 32768));
+        assert_eq!(counts[&level1], (4096 + 8192 + 16384, 4096 + 8192));
+        assert_eq!(fmt_qgroupid(level1), "1/1");
+    }
+}
@@ -0,0 +1,191 @@
+//! Ranges known to be bad, e.g. the areas ddrescue couldn't read from a
+//! failing disk, which reads are kept away from. A copy of a tree block in
+//! a bad range is passed over for another mirror, and a block with no other
+//! copy is reported lost, rather than reading (and having the disk retry)
+//! sectors already known to be gone.
+//!
+//! `--quarantine <file>` takes ranges a line apiece, with # comments:
+//!
+//! ```text
+//! logical 30408704 16384
+//! physical 2 0x1d00000 65536
+//! ```
+//!
+//! giving the start and length of a range of logical addresses, or of
+//! physical offsets on a devid. `--ddrescue-map <devid>=<mapfile>` takes
+//! the bad areas of a ddrescue mapfile of that device's file.
+
+use crate::parse::parse_u64;
+
+use anyhow::*;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+/// disjoint ranges, start to end, with those touching merged
+#[derive(Clone, Debug, Default)]
+struct RangeSet(BTreeMap<u64, u64>);
+
+impl RangeSet {
+    fn insert(&mut self, range: Range<u64>) {
+        let (mut start, mut end) = (range.start, range.end);
+        if start >= end {
+            return;
+        }
+        let overlapping: Vec<(u64, u64)> = self
+            .0
+            .range(..=end)
+            .rev()
+            .take_while(|(_, &e)| e >= start)
+            .map(|(&s, &e)| (s, e))
+            .collect();
+        for (s, e) in overlapping {
+            self.0.remove(&s);
+            start = start.min(s);
+            end = end.max(e);
+        }
+        self.0.insert(start, end);
+    }
+
+    /// the first range overlapping start..start + length
+    fn overlap(&self, start: u64, length: u64) -> Option<Range<u64>> {
+        let end = start.saturating_add(length);
+        let (&s, &e) = self.0.range(..end).next_back()?;
+        (e > start).then_some(s..e)
+    }
+}
+
+/// the ranges reads are kept away from, as FsInfo::quarantine holds them
+#[derive(Clone, Debug, Default)]
+pub struct Quarantine {
+    logical: RangeSet,
+    /// by devid
+    physical: HashMap<u64, RangeSet>,
+}
+
+impl Quarantine {
+    pub fn is_empty(&self) -> bool {
+        self.logical.0.is_empty() && self.physical.values().all(|ranges| ranges.0.is_empty())
+    }
+
+    pub fn add_logical(&mut self, range: Range<u64>) {
+        self.logical.insert(range);
+    }
+
+    pub fn add_physical(&mut self, devid: u64, range: Range<u64>) {
+        self.physical.entry(devid).or_default().insert(range);
+    }
+
+    /// the bad range overlapping length bytes at logical, if there is one
+    pub fn logical(&self, logical: u64, length: u64) -> Option<Range<u64>> {
+        self.logical.overlap(logical, length)
+    }
+
+    /// the bad range overlapping length bytes at physical on devid, if
+    /// there is one
+    pub fn physical(&self, devid: u64, physical: u64, length: u64) -> Option<Range<u64>> {
+        self.physical.get(&devid)?.overlap(physical, length)
+    }
+
+    /// add the ranges of a --quarantine file, in the format described above
+    pub fn parse(&mut self, text: &str) -> Result<()> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields[..] {
+                ["logical", start, length] => {
+                    let (start, length) = (parse_u64(start)?, parse_u64(length)?);
+                    self.add_logical(start..start.saturating_add(length));
+                    Ok(())
+                }
+                ["physical", devid, start, length] => {
+                    let (start, length) = (parse_u64(start)?, parse_u64(length)?);
+                    self.add_physical(parse_u64(devid)?, start..start.saturating_add(length));
+                    Ok(())
+                }
+                _ => Err(anyhow!(
+                    "expected \"logical <start> <length>\" or \"physical <devid> <start> <length>\""
+                )),
+            };
+            parsed.with_context(|| format!("line {}: {line:?}", number + 1))?;
+        }
+        Ok(())
+    }
+}
+
+/// the areas of a ddrescue mapfile which weren't read successfully: bad
+/// sectors (-), and those not yet trimmed (*), scraped (/) or tried (?), as
+/// positions in the rescued file. The first line which isn't a comment is
+/// ddrescue's current position and status, and is skipped.
+pub fn parse_ddrescue_map(text: &str) -> Result<Vec<Range<u64>>> {
+    let mut ranges = Vec::new();
+    let lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'));
+    for (number, line) in lines.skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [pos, size, status, ..] = fields[..] else {
+            bail!("line {}: {line:?} should be pos size status", number + 1);
+        };
+        let (pos, size) = (parse_u64(pos)?, parse_u64(size)?);
+        match status {
+            "+" => {}
+            "-" | "*" | "/" | "?" => ranges.push(pos..pos.saturating_add(size)),
+            _ => bail!("line {}: unknown status {status:?}", number + 1),
+        }
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        let mut quarantine = Quarantine::default();
+        assert!(quarantine.is_empty());
+        quarantine
+            .parse("# from the last scrub\nlogical 8192 4096\nphysical 2 0x10000 512 # bad\n")
+            .unwrap();
+        quarantine.add_logical(12288..16384);
+        assert_eq!(quarantine.logical(0, 16384), Some(8192..16384));
+        assert_eq!(quarantine.logical(16384, 4096), None);
+        assert_eq!(quarantine.logical(0, 8192), None);
+        assert_eq!(quarantine.physical(2, 0x10100, 1), Some(0x10000..0x10200));
+        assert_eq!(quarantine.physical(1, 0x10100, 1), None);
+        assert!(quarantine.parse("logical 1").is_err());
+    }
+
+    #[test]
+    fn ddrescue_map() {
+        let map = "# Mapfile. Created by GNU ddrescue version 1.27\n\
+                   # current_pos  current_status  current_pass\n\
+                   0x00120000     +               1\n\
+                   #      pos        size  status\n\
+                   0x00000000  0x00100000  +\n\
+                   0x00100000  0x00000200  -\n\
+                   0x00100200  0x0000FE00  *\n\
+                   0x00110000  0x00010000  +\n";
+        let ranges = parse_ddrescue_map(map).unwrap();
+        assert_eq!(ranges, [0x100000..0x100200, 0x100200..0x110000]);
+    }
+
+    #[test]
+    fn lost_blocks() {
+        use crate::address::{block_copies, load_virt_block_unverified};
+
+        let mut fs = crate::tree::tests::two_level_tree("quarantine");
+        fs.quarantine.add_physical(1, 4096 + 512..4096 + 1024);
+        fs.quarantine.add_logical(8192..8192 + 512);
+        let error = load_virt_block_unverified(&fs, 4096).unwrap_err();
+        assert!(error.to_string().contains("lost"), "{error}");
+        assert!(load_virt_block_unverified(&fs, 8192).is_err());
+        assert!(load_virt_block_unverified(&fs, 0).is_ok());
+        assert!(block_copies(&fs, 4096, 4096).unwrap()[0].data.is_none());
+        assert!(block_copies(&fs, 0, 4096).unwrap()[0].data.is_some());
+    }
+}
@@ -0,0 +1,516 @@
+//! RAID5/6 full stripes: where their strips are on the devices, and
+//! verification of the P and Q parity against the data strips.
+//!
+//! A full stripe is one stripe_len strip on every device of the chunk. Its
+//! data strips hold consecutive logical ranges; which device holds which
+//! strip, and which hold P and Q, rotates by one device per full stripe.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::flags::fmt_block_group_type;
+use crate::scrub::data_csum;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::BTreeSet;
+use std::fmt;
+
+pub const RAID56_PROFILES: u64 = BTRFS_BLOCK_GROUP_RAID5 | BTRFS_BLOCK_GROUP_RAID6;
+
+/// what a strip of a full stripe holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StripRole {
+    /// data, starting at this logical address
+    Data(u64),
+    P,
+    Q,
+}
+
+impl fmt::Display for StripRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StripRole::Data(logical) => write!(f, "data strip {logical}"),
+            StripRole::P => write!(f, "P strip"),
+            StripRole::Q => write!(f, "Q strip"),
+        }
+    }
+}
+
+pub struct Strip {
+    pub role: StripRole,
+    pub devid: u64,
+    pub physical: u64,
+    /// None if the device is missing or too short, or the strip is
+    /// quarantined or can't be read
+    pub data: Option<Vec<u8>>,
+}
+
+pub struct FullStripe {
+    /// logical address of the first data strip
+    pub logical: u64,
+    pub stripe_len: u64,
+    pub chunk_type: u64,
+    /// the data strips in logical order, then P, then (for RAID6) Q
+    pub strips: Vec<Strip>,
+}
+
+impl FullStripe {
+    pub fn data_strips(&self) -> usize {
+        self.strips
+            .iter()
+            .filter(|s| matches!(s.role, StripRole::Data(_)))
+            .count()
+    }
+
+    /// logical bytes covered by the data strips
+    pub fn length(&self) -> u64 {
+        self.data_strips() as u64 * self.stripe_len
+    }
+}
+
+pub(crate) fn parity_strips(chunk_type: u64) -> u64 {
+    if chunk_type & BTRFS_BLOCK_GROUP_RAID6 != 0 {
+        2
+    } else {
+        1
+    }
+}
+
+/// full stripe number nr of a RAID5/6 chunk
+fn chunk_full_stripe(fs: &FsInfo, chunk: &ChunkInfo, nr: u64) -> FullStripe {
+    let ChunkInfo(key, chunk, stripes) = chunk;
+    let stripe_len = chunk.stripe_len;
+    let chunk_type = chunk.r#type;
+    let num_stripes = stripes.len() as u64;
+    let data_strips = num_stripes - parity_strips(chunk_type);
+    let logical = key.offset + nr * data_strips * stripe_len;
+    let strips = (0..num_stripes)
+        .map(|i| {
+            let stripe = &stripes[((i + nr) % num_stripes) as usize];
+            let devid = stripe.devid;
+            let physical = stripe.offset + nr * stripe_len;
+            let data = fs
+                .devid_map
+                .get(&devid)
+                .filter(|_| {
+                    fs.quarantine
+                        .physical(devid, physical, stripe_len)
+                        .is_none()
+                })
+                .and_then(|dev| {
+                    dev.file
+                        .checked_read(physical as usize, stripe_len as usize)
+                        .ok()
+                });
+            let role = match i.checked_sub(data_strips) {
+                None => StripRole::Data(logical + i * stripe_len),
+                Some(0) => StripRole::P,
+                Some(_) => StripRole::Q,
+            };
+            Strip {
+                role,
+                devid,
+                physical,
+                data,
+            }
+        })
+        .collect();
+    FullStripe {
+        logical,
+        stripe_len,
+        chunk_type,
+        strips,
+    }
+}
+
+/// the full stripe of a RAID5/6 chunk containing logical
+pub fn full_stripe(fs: &FsInfo, logical: u64) -> Result<FullStripe> {
+    let chunk = find_chunk(fs, logical)
+        .ok_or_else(|| anyhow!("logical {logical} is not within any chunk"))?;
+    let start = chunk.0.offset;
+    let chunk_type = chunk.1.r#type;
+    ensure!(
+        chunk_type & RAID56_PROFILES != 0,
+        "logical {logical} is in chunk {start} ({}), which is not RAID5/6",
+        fmt_block_group_type(chunk_type)
+    );
+    let data_strips = chunk.2.len() as u64 - parity_strips(chunk_type);
+    let full_stripe_len = data_strips * chunk.1.stripe_len;
+    Ok(chunk_full_stripe(
+        fs,
+        &chunk,
+        (logical - start) / full_stripe_len,
+    ))
+}
+
+/// exponents and logarithms of the generator 2 of GF(2^8) with the
+/// polynomial 0x11d, as used by the RAID6 Q syndrome
+const fn gf_tables() -> ([u8; 256], [u8; 256]) {
+    let mut exp = [0_u8; 256];
+    let mut log = [0_u8; 256];
+    let mut x: u8 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x;
+        log[x as usize] = i as u8;
+        x = gf_mul2(x);
+        i += 1;
+    }
+    (exp, log)
+}
+
+const fn gf_mul2(x: u8) -> u8 {
+    (x << 1) ^ if x & 0x80 != 0 { 0x1d } else { 0 }
+}
+
+static GF: ([u8; 256], [u8; 256]) = gf_tables();
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let (exp, log) = &GF;
+    exp[(log[a as usize] as usize + log[b as usize] as usize) % 255]
+}
+
+/// 2^-n in GF(2^8)
+fn gf_inv_pow2(n: usize) -> u8 {
+    GF.0[(255 - n % 255) % 255]
+}
+
+/// P (the xor of the data strips) and Q (the sum of 2^i times data strip i)
+pub fn compute_parity(data: &[&[u8]]) -> (Vec<u8>, Vec<u8>) {
+    let len = data.first().map_or(0, |d| d.len());
+    let mut p = vec![0_u8; len];
+    let mut q = vec![0_u8; len];
+    for strip in data.iter().rev() {
+        for ((p, q), d) in p.iter_mut().zip(q.iter_mut()).zip(strip.iter()) {
+            *p ^= d;
+            *q = gf_mul2(*q) ^ d;
+        }
+    }
+    (p, q)
+}
+
+/// the contents of strip target of the full stripe, computed from the other
+/// strips, which are assumed to be good. A data strip is rebuilt from P when
+/// it is present and from Q otherwise.
+pub fn regenerate_strip(stripe: &FullStripe, target: usize) -> Result<Vec<u8>> {
+    let data_strips = stripe.data_strips();
+    let present = |i: usize| -> Result<&[u8]> {
+        let strip = &stripe.strips[i];
+        strip.data.as_deref().ok_or_else(|| {
+            anyhow!(
+                "{} of full stripe {} on devid {} is missing",
+                strip.role,
+                stripe.logical,
+                strip.devid
+            )
+        })
+    };
+    let data = |skip: Option<usize>| -> Result<Vec<&[u8]>> {
+        (0..data_strips)
+            .map(|i| {
+                if Some(i) == skip {
+                    Ok(&[][..])
+                } else {
+                    present(i)
+                }
+            })
+            .collect()
+    };
+    ensure!(target < stripe.strips.len(), "no strip {target}");
+    if target >= data_strips {
+        let (p, q) = compute_parity(&data(None)?);
+        return Ok(if target == data_strips { p } else { q });
+    }
+
+    // the parity of the other data strips, with the target strip taken as zero
+    let zeros = vec![0_u8; stripe.stripe_len as usize];
+    let mut others = data(Some(target))?;
+    others[target] = &zeros;
+    let (p, q) = compute_parity(&others);
+    if let Some(stored_p) = &stripe.strips[data_strips].data {
+        return Ok(p.iter().zip(stored_p).map(|(a, b)| a ^ b).collect());
+    }
+    ensure!(
+        stripe.strips.len() > data_strips + 1,
+        "P strip of full stripe {} on devid {} is missing, and there is no Q to rebuild from",
+        stripe.logical,
+        stripe.strips[data_strips].devid
+    );
+    let stored_q = present(data_strips + 1)?;
+    let factor = gf_inv_pow2(target);
+    Ok(q.iter()
+        .zip(stored_q)
+        .map(|(a, b)| gf_mul(a ^ b, factor))
+        .collect())
+}
+
+/// a full stripe whose parity doesn't match its data
+pub struct StripeCheck {
+    pub logical: u64,
+    /// bytes of P differing from the xor of the data
+    pub p_mismatch: usize,
+    /// bytes of Q differing from the Q computed from the data; None for RAID5
+    pub q_mismatch: Option<usize>,
+    /// role and devid of the strip most likely to be wrong, found from the
+    /// data checksums or, for RAID6, the P and Q syndromes
+    pub suspect: Option<(StripRole, u64)>,
+}
+
+/// which data strip, if any, holds sectors failing the csum tree. None if
+/// none or several do.
+fn csum_suspect(fs: &FsInfo, stripe: &FullStripe) -> Option<usize> {
+    if stripe.chunk_type & BTRFS_BLOCK_GROUP_DATA == 0 {
+        return None;
+    }
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let csum_type = fs.master_sb.csum_type;
+    let mut bad = stripe.strips.iter().enumerate().filter(|(_, strip)| {
+        let (StripRole::Data(logical), Some(data)) = (strip.role, &strip.data) else {
+            return false;
+        };
+        data.chunks_exact(sectorsize as usize)
+            .enumerate()
+            .any(|(i, sector)| {
+                matches!(data_csum(fs, logical + i as u64 * sectorsize),
+                    Result::Ok(Some(csum)) if csum_data(sector, csum_type)[..csum.len()] != csum[..])
+            })
+    });
+    match (bad.next(), bad.next()) {
+        (Some((i, _)), None) => Some(i),
+        _ => None,
+    }
+}
+
+/// the data strip a RAID6 full stripe's P and Q syndromes point to: when
+/// only data strip z is wrong, by e, P is off by e and Q by 2^z e
+fn syndrome_suspect(p_syndrome: &[u8], q_syndrome: &[u8], data_strips: usize) -> Option<usize> {
+    let log = &GF.1;
+    let mut suspect = None;
+    for (&sp, &sq) in p_syndrome.iter().zip(q_syndrome) {
+        if sp == 0 && sq == 0 {
+            continue;
+        }
+        if sp == 0 || sq == 0 {
+            return None;
+        }
+        let z = (log[sq as usize] as usize + 255 - log[sp as usize] as usize) % 255;
+        if *suspect.get_or_insert(z) != z {
+            return None;
+        }
+    }
+    suspect.filter(|z| *z < data_strips)
+}
+
+/// compare a full stripe's parity with its data. Returns None if the stripe
+/// is consistent.
+pub fn check_full_stripe(fs: &FsInfo, stripe: &FullStripe) -> Result<Option<StripeCheck>> {
+    let data_strips = stripe.data_strips();
+    let mut strips = Vec::new();
+    for strip in &stripe.strips {
+        strips.push(strip.data.as_deref().ok_or_else(|| {
+            anyhow!(
+                "full stripe {}: {} on devid {} is missing",
+                stripe.logical,
+                strip.role,
+                strip.devid
+            )
+        })?);
+    }
+    let (p, q) = compute_parity(&strips[..data_strips]);
+    let p_syndrome: Vec<u8> = p
+        .iter()
+        .zip(strips[data_strips])
+        .map(|(a, b)| a ^ b)
+        .collect();
+    let q_syndrome: Option<Vec<u8>> = strips
+        .get(data_strips + 1)
+        .map(|stored| q.iter().zip(*stored).map(|(a, b)| a ^ b).collect());
+    let count = |syndrome: &[u8]| syndrome.iter().filter(|b| **b != 0).count();
+    let p_mismatch = count(&p_syndrome);
+    let q_mismatch = q_syndrome.as_deref().map(count);
+    if p_mismatch == 0 && q_mismatch.unwrap_or(0) == 0 {
+        return Ok(None);
+    }
+    let suspect = csum_suspect(fs, stripe).or_else(|| match (&q_syndrome, q_mismatch) {
+        // only one of the parities disagrees, so it is the one that's wrong
+        (Some(_), Some(0)) => Some(data_strips),
+        (Some(_), Some(_)) if p_mismatch == 0 => Some(data_strips + 1),
+        (Some(q_syndrome), _) => syndrome_suspect(&p_syndrome, q_syndrome, data_strips),
+        (None, _) => None,
+    });
+    Ok(Some(StripeCheck {
+        logical: stripe.logical,
+        p_mismatch,
+        q_mismatch,
+        suspect: suspect.map(|i| (stripe.strips[i].role, stripe.strips[i].devid)),
+    }))
+}
+
+#[derive(Default)]
+pub struct ParityScrub {
+    /// full stripes holding allocated extents that were checked
+    pub full_stripes: u64,
+    pub inconsistent: Vec<StripeCheck>,
+    /// full stripes that couldn't be checked, e.g. for a missing device
+    pub errors: Vec<String>,
+}
+
+/// start and end of the extents allocated between start and end, which lie
+/// within one chunk
+fn allocated_ranges(fs: &FsInfo, start: u64, end: u64) -> Result<Vec<(u64, u64)>> {
+    let extent_root = global_root_for(fs, BTRFS_EXTENT_TREE_OBJECTID, start)
+        .ok_or_else(|| anyhow!("extent tree not found"))?;
+    let search = NodeSearchOption::between(
+        btrfs_disk_key {
+            objectid: start,
+            item_type: BtrfsItemType::MIN,
+            offset: 0,
+        },
+        btrfs_disk_key {
+            objectid: end - 1,
+            item_type: BtrfsItemType::MAX,
+            offset: u64::MAX,
+        },
+    );
+    let nodesize = fs.master_sb.nodesize as u64;
+    let mut ranges = Vec::new();
+    for (item, _data, _block_offset, _slot) in search_range(fs, extent_root, search) {
+        let key = item.key;
+        let length = match key.item_type {
+            BtrfsItemType::EXTENT_ITEM => key.offset,
+            BtrfsItemType::METADATA_ITEM => nodesize,
+            _ => continue,
+        };
+        ranges.push((key.objectid, key.objectid + length));
+    }
+    Ok(ranges)
+}
+
+/// verify the parity of every full stripe of every RAID5/6 chunk that holds
+/// an allocated extent. Parity of never written full stripes is
+/// meaningless, so they are skipped.
+pub fn scrub_parity(fs: &FsInfo) -> Result<ParityScrub> {
+    let mut scrub = ParityScrub::default();
+    for chunk in all_chunks(fs) {
+        let chunk_type = chunk.1.r#type;
+        if chunk_type & RAID56_PROFILES == 0 {
+            continue;
+        }
+        let start = chunk.0.offset;
+        let length = chunk.1.length;
+        let data_strips = chunk.2.len() as u64 - parity_strips(chunk_type);
+        let full_stripe_len = data_strips * chunk.1.stripe_len;
+        let mut used = BTreeSet::new();
+        for (extent_start, extent_end) in allocated_ranges(fs, start, start + length)? {
+            let first = (extent_start - start) / full_stripe_len;
+            let last = (extent_end - 1 - start) / full_stripe_len;
+            used.extend(first..=last);
+        }
+        for nr in used {
+            if fs.cancel.is_cancelled() {
+                return Ok(scrub);
+            }
+            let stripe = chunk_full_stripe(fs, &chunk, nr);
+            scrub.full_stripes += 1;
+            match check_full_stripe(fs, &stripe) {
+                Result::Ok(Some(check)) => scrub.inconsistent.push(check),
+                Result::Ok(None) => (),
+                Err(e) => scrub.errors.push(e.to_string()),
+            }
+        }
+    }
+    Ok(scrub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stripe(strips: &[&[u8]], raid6: bool) -> FullStripe {
+        let data_strips = strips.len() - if raid6 { 2 } else { 1 };
+        FullStripe {
+            logical: 0,
+            stripe_len: strips[0].len() as u64,
+            chunk_type: BTRFS_BLOCK_GROUP_DATA
+                | if raid6 {
+                    BTRFS_BLOCK_GROUP_RAID6
+                } else {
+                    BTRFS_BLOCK_GROUP_RAID5
+                },
+            strips: strips
+                .iter()
+                .enumerate()
+                .map(|(i, data)| Strip {
+                    role: match i.checked_sub(data_strips) {
+                        None => StripRole::Data(i as u64),
+                        Some(0) => StripRole::P,
+                        Some(_) => StripRole::Q,
+                    },
+                    devid: i as u64 + 1,
+                    physical: 0,
+                    data: Some(data.to_vec()),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn gf_arithmetic() {
+        assert_eq!(gf_mul(2, 0x80), 0x1d);
+        assert_eq!(gf_mul(3, 7), 9);
+        for n in 0..300 {
+            let mut x = 1;
+            for _ in 0..n % 255 {
+                x = gf_mul2(x);
+            }
+            assert_eq!(gf_mul(x, gf_inv_pow2(n)), 1);
+        }
+    }
+
+    #[test]
+    fn parity() {
+        let (p, q) = compute_parity(&[&[1, 0x80], &[2, 0], &[4, 1]]);
+        assert_eq!(p, [7, 0x81]);
+        // 1 + 2*2 + 4*4, and 0x80 + 4*1
+        assert_eq!(q, [1 ^ 4 ^ 16, 0x80 ^ 4]);
+    }
+
+    #[test]
+    fn regenerate() {
+        let d: [&[u8]; 3] = [&[1, 2, 3, 4], &[9, 8, 7, 6], &[0xff, 0, 0x80, 0x55]];
+        let (p, q) = compute_parity(&d);
+        let all = [d[0], d[1], d[2], &p[..], &q[..]];
+        for target in 0..all.len() {
+            assert_eq!(
+                regenerate_strip(&stripe(&all, true), target).unwrap(),
+                all[target]
+            );
+        }
+        // without P a data strip comes from Q
+        let mut no_p = stripe(&all, true);
+        no_p.strips[3].data = None;
+        assert_eq!(regenerate_strip(&no_p, 1).unwrap(), d[1]);
+        let raid5 = [d[0], d[1], d[2], &p[..]];
+        assert_eq!(regenerate_strip(&stripe(&raid5, false), 2).unwrap(), d[2]);
+        let mut missing = stripe(&raid5, false);
+        missing.strips[3].data = None;
+        assert!(regenerate_strip(&missing, 0).is_err());
+    }
+
+    #[test]
+    fn syndromes() {
+        let d: [&[u8]; 3] = [&[1, 2, 3, 4], &[9, 8, 7, 6], &[0xff, 0, 0x80, 0x55]];
+        let (p, q) = compute_parity(&d);
+        let corrupt = [9, 0x18, 7, 0];
+        let (p2, q2) = compute_parity(&[d[0], &corrupt, d[2]]);
+        let sp: Vec<u8> = p.iter().zip(&p2).map(|(a, b)| a ^ b).collect();
+        let sq: Vec<u8> = q.iter().zip(&q2).map(|(a, b)| a ^ b).collect();
+        assert_eq!(syndrome_suspect(&sp, &sq, 3), Some(1));
+        assert_eq!(syndrome_suspect(&sp, &sp, 3), Some(0));
+        assert_eq!(syndrome_suspect(&sp, &[0; 4], 3), None);
+    }
+}
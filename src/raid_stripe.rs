@@ -0,0 +1,148 @@
+//! The raid stripe tree (RAID_STRIPE_TREE incompat feature), which zoned
+//! filesystems use to map their mirrored and striped data: a zoned device
+//! decides where an append lands, so a copy's physical offset can't be
+//! worked out from its chunk. Each data extent written gets a RAID_STRIPE
+//! item keyed (logical, RAID_STRIPE, length) listing a stride, a (devid,
+//! physical) pair, for every copy or strip of it.
+//!
+//! Metadata, and data in SINGLE chunks, are still mapped by their chunk.
+
+use crate::address::find_chunk;
+use crate::btrfs::*;
+use crate::flags::fmt_block_group_type;
+use crate::items::item_as;
+use crate::space::stripe_length;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+
+/// the profiles whose data is mapped through the raid stripe tree
+const RAID_STRIPE_PROFILES: u64 = BTRFS_BLOCK_GROUP_DUP
+    | BTRFS_BLOCK_GROUP_RAID0
+    | BTRFS_BLOCK_GROUP_RAID1
+    | BTRFS_BLOCK_GROUP_RAID1C3
+    | BTRFS_BLOCK_GROUP_RAID1C4
+    | BTRFS_BLOCK_GROUP_RAID10;
+
+/// one RAID_STRIPE item
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StripeExtent {
+    pub logical: u64,
+    pub length: u64,
+    /// (devid, physical) of each copy or strip
+    pub strides: Vec<(u64, u64)>,
+}
+
+impl StripeExtent {
+    fn new(key: &btrfs_disk_key, data: &[u8]) -> StripeExtent {
+        StripeExtent {
+            logical: key.objectid,
+            length: key.offset,
+            strides: parse_strides(data),
+        }
+    }
+}
+
+/// the strides of a RAID_STRIPE item, ignoring a partial one at the end
+pub fn parse_strides(data: &[u8]) -> Vec<(u64, u64)> {
+    data.chunks_exact(std::mem::size_of::<btrfs_raid_stride>())
+        .filter_map(item_as::<btrfs_raid_stride>)
+        .map(|stride| (stride.devid, stride.physical))
+        .collect()
+}
+
+/// whether data in a chunk of chunk_type is mapped by the raid stripe tree
+/// rather than by its chunk, as the kernel decides it
+pub fn uses_raid_stripe_tree(fs: &FsInfo, chunk_type: u64) -> bool {
+    fs.master_sb.incompat_flags & BTRFS_FEATURE_INCOMPAT_RAID_STRIPE_TREE != 0
+        && chunk_type & BTRFS_BLOCK_GROUP_DATA != 0
+        && chunk_type & RAID_STRIPE_PROFILES != 0
+}
+
+fn raid_stripe_root(fs: &FsInfo) -> Result<u64> {
+    tree_root_offset(fs, BTRFS_RAID_STRIPE_TREE_OBJECTID)
+        .ok_or_else(|| anyhow!("raid stripe tree not found"))
+}
+
+/// the stripe extent covering logical, if it has one
+pub fn stripe_extent(fs: &FsInfo, logical: u64) -> Result<Option<StripeExtent>> {
+    let root = raid_stripe_root(fs)?;
+    let key = btrfs_disk_key {
+        objectid: logical,
+        item_type: BtrfsItemType::RAID_STRIPE,
+        offset: u64::MAX,
+    };
+    // as in data_csum, the item at or left of the key is the one extent
+    // which could cover logical
+    let Some((item, data, _, _)) = BtrfsTreeIter::new(fs, root, SearchKey::floor(key)).next()
+    else {
+        return Ok(None);
+    };
+    let (start, length) = (item.key.objectid, item.key.offset);
+    if item.key.item_type != BtrfsItemType::RAID_STRIPE
+        || logical < start
+        || logical >= start + length
+    {
+        return Ok(None);
+    }
+    Ok(Some(StripeExtent::new(&item.key, &data)))
+}
+
+/// every stripe extent, in logical order
+pub fn stripe_extents(fs: &FsInfo) -> Result<Vec<StripeExtent>> {
+    let root = raid_stripe_root(fs)?;
+    let search = key_range(None, Some(BtrfsItemType::RAID_STRIPE), None);
+    Ok(search_range(fs, root, search)
+        .filter(|(item, ..)| item.key.item_type == BtrfsItemType::RAID_STRIPE)
+        .map(|(item, data, ..)| StripeExtent::new(&item.key, &data))
+        .collect())
+}
+
+/// what is wrong with a stripe extent: no chunk of a profile the tree maps
+/// holding it, or strides outside the stripes of its chunk
+pub fn check_stripe_extent(fs: &FsInfo, extent: &StripeExtent) -> Vec<String> {
+    let Some(ChunkInfo(key, chunk, stripes)) = find_chunk(fs, extent.logical) else {
+        return vec![String::from("in no chunk")];
+    };
+    let (start, chunk_type) = (key.offset, chunk.r#type);
+    let mut problems = Vec::new();
+    if extent.logical + extent.length > start + chunk.length {
+        problems.push(format!("runs past the end of chunk {start}"));
+    }
+    if !uses_raid_stripe_tree(fs, chunk_type) {
+        problems.push(format!(
+            "chunk {start} is {}, which isn't mapped through the raid stripe tree",
+            fmt_block_group_type(chunk_type)
+        ));
+    }
+    let length = stripe_length(&chunk);
+    for &(devid, physical) in &extent.strides {
+        let inside = stripes.iter().any(|stripe| {
+            let offset = stripe.offset;
+            stripe.devid == devid && offset <= physical && physical < offset + length
+        });
+        if !inside {
+            problems.push(format!(
+                "devid {devid} physical {physical} is outside the stripes of chunk {start}"
+            ));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strides() {
+        let mut data = Vec::new();
+        for (devid, physical) in [(1_u64, 1_u64 << 30), (2, 3 << 28)] {
+            data.extend(devid.to_le_bytes());
+            data.extend(physical.to_le_bytes());
+        }
+        data.extend([0; 8]);
+        assert_eq!(parse_strides(&data), vec![(1, 1 << 30), (2, 3 << 28)]);
+    }
+}
@@ -0,0 +1,780 @@
+//! Reconstruction of the extent tree from the other trees, the offline
+//! equivalent of `btrfs check --init-extent-tree`, for when the extent tree
+//! is too damaged to repair item by item.
+//!
+//! Every tree but the extent tree is walked to find the tree blocks in use
+//! and what points to each, and every EXTENT_DATA item to find the data
+//! extents and their refs. A new tree is packed from the items describing
+//! them and a BLOCK_GROUP_ITEM per chunk, written to free metadata space,
+//! and the extent tree's ROOT_ITEM is pointed at it in place. The old tree's
+//! blocks are left untouched, so until the ROOT_ITEM is written nothing the
+//! filesystem uses has changed.
+
+use crate::address::*;
+use crate::backref::*;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::dump::fmt_treeid;
+use crate::edit::super_mirrors;
+use crate::items::item_as;
+use crate::repair::*;
+use crate::scrub::{check_tree_block, merge_ranges};
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+const NO_KEY: btrfs_disk_key = btrfs_disk_key {
+    objectid: 0,
+    item_type: BtrfsItemType::MIN,
+    offset: 0,
+};
+
+/// a tree block in use, as found by the walk
+struct UsedBlock {
+    owner: u64,
+    level: u8,
+    generation: u64,
+    first_key: btrfs_disk_key,
+    reloc: bool,
+    /// trees the block is reachable from
+    trees: Vec<u64>,
+    /// nodes pointing to the block
+    parents: Vec<u64>,
+    /// trees whose root the block is
+    root_of: Vec<u64>,
+}
+
+impl UsedBlock {
+    /// whether the block's children refer to it by bytenr rather than by
+    /// its owner: needed once it is shared with another tree, and always in
+    /// relocation trees
+    fn full_backref(&self) -> bool {
+        self.reloc || self.trees.iter().any(|&tree| tree != self.owner)
+    }
+}
+
+/// a data extent in use and the refs to it
+#[derive(Default)]
+struct UsedData {
+    length: u64,
+    generation: u64,
+    /// count per (root, inode, file offset less extent offset)
+    refs: BTreeMap<(u64, u64, u64), u32>,
+    /// count per leaf, for leaves with full backrefs
+    shared: BTreeMap<u64, u32>,
+}
+
+/// walk every tree but the extent and log trees, recording each block in use
+/// and how it is reached
+fn walk_trees(fs: &FsInfo) -> Result<BTreeMap<u64, UsedBlock>> {
+    let mut blocks = BTreeMap::<u64, UsedBlock>::new();
+    for (tree, root, root_level) in tree_roots(fs) {
+        if tree == BTRFS_EXTENT_TREE_OBJECTID || tree == BTRFS_TREE_LOG_OBJECTID {
+            continue;
+        }
+        let mut seen = HashSet::new();
+        let mut stack = vec![(root, None, root_level)];
+        while let Some((logical, parent, level)) = stack.pop() {
+            ensure!(!fs.cancel.is_cancelled(), "interrupted");
+            ensure!(
+                seen.insert(logical),
+                "block {logical} is referenced more than once in {}",
+                fmt_treeid(tree)
+            );
+            let block = load_virt_block(fs, logical)?;
+            if let Entry::Vacant(slot) = blocks.entry(logical) {
+                let problems = check_tree_block(fs, &block, logical, Some(level));
+                ensure!(
+                    problems.is_empty(),
+                    "block {logical} of {}: {}; the other trees must be sound to rebuild the extent tree",
+                    fmt_treeid(tree),
+                    problems.join(", ")
+                );
+                let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+                let first_key = match node_entries(&block).first() {
+                    Some(NodeEntry::Ptr(ptr)) => ptr.key,
+                    Some(NodeEntry::Item(item, _)) => item.key,
+                    None => NO_KEY,
+                };
+                slot.insert(UsedBlock {
+                    owner: header.owner,
+                    level,
+                    generation: header.generation,
+                    first_key,
+                    reloc: header.flags & BTRFS_HEADER_FLAG_RELOC != 0,
+                    trees: Vec::new(),
+                    parents: Vec::new(),
+                    root_of: Vec::new(),
+                });
+            }
+            let used = blocks.get_mut(&logical).unwrap();
+            if !used.trees.contains(&tree) {
+                used.trees.push(tree);
+            }
+            match parent {
+                None => used.root_of.push(tree),
+                Some(parent) if !used.parents.contains(&parent) => used.parents.push(parent),
+                Some(_) => {}
+            }
+            if level == 0 {
+                continue;
+            }
+            for entry in node_entries(&block).into_iter().rev() {
+                if let NodeEntry::Ptr(ptr) = entry {
+                    stack.push((ptr.blockptr, Some(logical), level - 1));
+                }
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+/// the data extents referenced from the EXTENT_DATA items of every leaf
+fn data_refs(fs: &FsInfo, blocks: &BTreeMap<u64, UsedBlock>) -> Result<BTreeMap<u64, UsedData>> {
+    let mut extents = BTreeMap::<u64, UsedData>::new();
+    for (&logical, used) in blocks {
+        if used.level != 0 {
+            continue;
+        }
+        let block = load_virt_block(fs, logical)?;
+        for entry in node_entries(&block) {
+            let NodeEntry::Item(item, Some(data)) = entry else {
+                continue;
+            };
+            if item.key.item_type != BtrfsItemType::EXTENT_DATA {
+                continue;
+            }
+            let Some(fe) = item_as::<btrfs_file_extent_item>(data) else {
+                continue;
+            };
+            let disk_bytenr = fe.disk_bytenr;
+            let disk_num_bytes = fe.disk_num_bytes;
+            let generation = fe.generation;
+            if fe.r#type == BTRFS_FILE_EXTENT_INLINE || disk_bytenr == 0 {
+                continue;
+            }
+            let extent = extents.entry(disk_bytenr).or_default();
+            ensure!(
+                extent.length == 0 || extent.length == disk_num_bytes,
+                "data extent {disk_bytenr} is referenced with lengths {} and {disk_num_bytes}",
+                extent.length
+            );
+            extent.length = disk_num_bytes;
+            if extent.generation == 0 || generation < extent.generation {
+                extent.generation = generation;
+            }
+            if used.full_backref() {
+                *extent.shared.entry(logical).or_default() += 1;
+            } else {
+                let key = item.key;
+                let offset = key.offset.wrapping_sub(fe.offset);
+                *extent
+                    .refs
+                    .entry((used.owner, key.objectid, offset))
+                    .or_default() += 1;
+            }
+        }
+    }
+    Ok(extents)
+}
+
+/// a ref, with the key it would have as its own item
+struct PendingRef {
+    item_type: BtrfsItemType,
+    /// the keyed item's offset, which also orders inline refs of one type
+    offset: u64,
+    /// the inline ref's payload after its type byte
+    inline: Vec<u8>,
+    /// the keyed item's data
+    keyed: Vec<u8>,
+}
+
+fn tree_block_ref(root: u64) -> PendingRef {
+    PendingRef {
+        item_type: BtrfsItemType::TREE_BLOCK_REF,
+        offset: root,
+        inline: root.to_le_bytes().to_vec(),
+        keyed: Vec::new(),
+    }
+}
+
+fn shared_block_ref(parent: u64) -> PendingRef {
+    PendingRef {
+        item_type: BtrfsItemType::SHARED_BLOCK_REF,
+        offset: parent,
+        inline: parent.to_le_bytes().to_vec(),
+        keyed: Vec::new(),
+    }
+}
+
+type Items = BTreeMap<(u64, u8, u64), (btrfs_disk_key, Vec<u8>)>;
+
+fn insert_item(
+    items: &mut Items,
+    objectid: u64,
+    item_type: BtrfsItemType,
+    offset: u64,
+    data: Vec<u8>,
+) {
+    let key = btrfs_disk_key {
+        objectid,
+        item_type,
+        offset,
+    };
+    items.insert((objectid, item_type as u8, offset), (key, data));
+}
+
+/// the extent item and any keyed refs for an extent. As many refs as fit
+/// are inlined, ordered by type and then by descending offset as the kernel
+/// expects.
+fn insert_extent(
+    items: &mut Items,
+    nodesize: usize,
+    key: btrfs_disk_key,
+    mut data: Vec<u8>,
+    mut refs: Vec<PendingRef>,
+) {
+    // as the kernel's BTRFS_MAX_EXTENT_ITEM_SIZE
+    let leaf_data_size = nodesize - std::mem::size_of::<btrfs_header>();
+    let max_size = (leaf_data_size >> 4) - std::mem::size_of::<btrfs_item>();
+    refs.sort_by(|a, b| {
+        (a.item_type as u8)
+            .cmp(&(b.item_type as u8))
+            .then(b.offset.cmp(&a.offset))
+    });
+    let mut keyed = Vec::new();
+    for r in refs {
+        if keyed.is_empty() && data.len() + 1 + r.inline.len() <= max_size {
+            data.push(r.item_type as u8);
+            data.extend_from_slice(&r.inline);
+        } else {
+            keyed.push(r);
+        }
+    }
+    let objectid = key.objectid;
+    items.insert((objectid, key.item_type as u8, key.offset), (key, data));
+    for r in keyed {
+        let mut offset = r.offset;
+        // hash collisions between data refs move on to the next free offset
+        while items.contains_key(&(objectid, r.item_type as u8, offset)) {
+            offset += 1;
+        }
+        insert_item(items, objectid, r.item_type, offset, r.keyed);
+    }
+}
+
+fn extent_item(refs: u64, generation: u64, flags: u64) -> Vec<u8> {
+    let ei = btrfs_extent_item {
+        refs,
+        generation,
+        flags,
+    };
+    as_bytes(&ei).to_vec()
+}
+
+/// the item for a tree block, skinny if the filesystem uses skinny metadata
+fn insert_tree_block(
+    items: &mut Items,
+    fs: &FsInfo,
+    logical: u64,
+    used: &UsedBlock,
+    refs: Vec<PendingRef>,
+) {
+    let nodesize = fs.master_sb.nodesize as usize;
+    let mut flags = BTRFS_EXTENT_FLAG_TREE_BLOCK;
+    if used.full_backref() {
+        flags |= BTRFS_BLOCK_FLAG_FULL_BACKREF;
+    }
+    let mut data = extent_item(refs.len() as u64, used.generation, flags);
+    let key = if fs.master_sb.incompat_flags & BTRFS_FEATURE_INCOMPAT_SKINNY_METADATA != 0 {
+        btrfs_disk_key {
+            objectid: logical,
+            item_type: BtrfsItemType::METADATA_ITEM,
+            offset: used.level as u64,
+        }
+    } else {
+        let info = btrfs_tree_block_info {
+            key: used.first_key,
+            level: used.level,
+        };
+        data.extend_from_slice(as_bytes(&info));
+        btrfs_disk_key {
+            objectid: logical,
+            item_type: BtrfsItemType::EXTENT_ITEM,
+            offset: nodesize as u64,
+        }
+    };
+    insert_extent(items, nodesize, key, data, refs);
+}
+
+/// items for every tree block and data extent in use
+fn extent_items(
+    fs: &FsInfo,
+    blocks: &BTreeMap<u64, UsedBlock>,
+    extents: &BTreeMap<u64, UsedData>,
+) -> Result<Items> {
+    let nodesize = fs.master_sb.nodesize as usize;
+    let mut items = Items::new();
+    for (&logical, used) in blocks {
+        let mut refs: Vec<PendingRef> = used
+            .root_of
+            .iter()
+            .map(|&tree| tree_block_ref(tree))
+            .collect();
+        let mut roots = BTreeSet::new();
+        for parent in &used.parents {
+            let parent_block = &blocks[parent];
+            if parent_block.full_backref() {
+                refs.push(shared_block_ref(*parent));
+            } else if roots.insert(parent_block.owner)
+                && !used.root_of.contains(&parent_block.owner)
+            {
+                refs.push(tree_block_ref(parent_block.owner));
+            } else {
+                bail!(
+                    "block {logical} is reached twice from {} without a shared parent",
+                    fmt_treeid(parent_block.owner)
+                );
+            }
+        }
+        insert_tree_block(&mut items, fs, logical, used, refs);
+    }
+    for (&logical, extent) in extents {
+        let mut refs = Vec::new();
+        let mut count = 0;
+        for (&(root, objectid, offset), &n) in &extent.refs {
+            let dref = btrfs_extent_data_ref {
+                root,
+                objectid,
+                offset,
+                count: n,
+            };
+            refs.push(PendingRef {
+                item_type: BtrfsItemType::EXTENT_DATA_REF,
+                offset: hash_extent_data_ref(root, objectid, offset),
+                inline: as_bytes(&dref).to_vec(),
+                keyed: as_bytes(&dref).to_vec(),
+            });
+            count += n as u64;
+        }
+        for (&parent, &n) in &extent.shared {
+            let mut inline = parent.to_le_bytes().to_vec();
+            inline.extend_from_slice(&n.to_le_bytes());
+            refs.push(PendingRef {
+                item_type: BtrfsItemType::SHARED_DATA_REF,
+                offset: parent,
+                inline,
+                keyed: n.to_le_bytes().to_vec(),
+            });
+            count += n as u64;
+        }
+        let key = btrfs_disk_key {
+            objectid: logical,
+            item_type: BtrfsItemType::EXTENT_ITEM,
+            offset: extent.length,
+        };
+        let data = extent_item(count, extent.generation, BTRFS_EXTENT_FLAG_DATA);
+        insert_extent(&mut items, nodesize, key, data, refs);
+    }
+    Ok(items)
+}
+
+/// node sized blocks of metadata chunks which hold nothing in use, nor any
+/// block of the old extent tree that can still be read, and whose copies
+/// avoid the superblocks
+fn free_metadata_blocks(
+    fs: &FsInfo,
+    blocks: &BTreeMap<u64, UsedBlock>,
+    extents: &BTreeMap<u64, UsedData>,
+) -> Vec<u64> {
+    let nodesize = fs.master_sb.nodesize as u64;
+    let mut used: Vec<(u64, u64)> = blocks.keys().map(|&b| (b, b + nodesize)).collect();
+    used.extend(extents.iter().map(|(&start, e)| (start, start + e.length)));
+    if let Some(old_root) = tree_root_offset(fs, BTRFS_EXTENT_TREE_OBJECTID) {
+        let mut seen = HashSet::new();
+        let mut stack = vec![old_root];
+        while let Some(logical) = stack.pop() {
+            if !seen.insert(logical) {
+                continue;
+            }
+            let Result::Ok(block) = load_virt_block_unverified(fs, logical) else {
+                continue;
+            };
+            if !check_tree_block(fs, &block, logical, None).is_empty() {
+                continue;
+            }
+            used.push((logical, logical + nodesize));
+            for entry in node_entries(&block) {
+                if let NodeEntry::Ptr(ptr) = entry {
+                    stack.push(ptr.blockptr);
+                }
+            }
+        }
+    }
+    let used = merge_ranges(used);
+    let superblocks: Vec<(u64, u64)> = super_mirrors(u64::MAX)
+        .into_iter()
+        .map(|offset| (offset, offset + BTRFS_SUPER_INFO_SIZE as u64))
+        .collect();
+    let mut free = Vec::new();
+    for ChunkInfo(key, chunk, stripes) in all_chunks(fs) {
+        let chunk_type = chunk.r#type;
+        if chunk_type & BTRFS_BLOCK_GROUP_METADATA == 0 {
+            continue;
+        }
+        // a seed's chunks are read-only
+        let on_seed = stripes.iter().any(|stripe| {
+            let devid = stripe.devid;
+            fs.devid_map.get(&devid).is_some_and(|dev| dev.seed)
+        });
+        if on_seed {
+            continue;
+        }
+        let end = key.offset + chunk.length;
+        let mut logical = key.offset.next_multiple_of(nodesize);
+        while logical + nodesize <= end {
+            // the first range in use ending after the start of this block
+            let i = used.partition_point(|&(_, used_end)| used_end <= logical);
+            if let Some(&(used_start, used_end)) = used.get(i) {
+                if used_start < logical + nodesize {
+                    logical = used_end.next_multiple_of(nodesize);
+                    continue;
+                }
+            }
+            // striped profiles can't be written a block at a time
+            let Result::Ok(copies) = block_copies(fs, logical, nodesize) else {
+                break;
+            };
+            let overlaps_superblock = copies.iter().any(|c| {
+                superblocks.iter().any(|&(sb_start, sb_end)| {
+                    c.physical < sb_end && c.physical + nodesize > sb_start
+                })
+            });
+            if !overlaps_superblock {
+                free.push(logical);
+            }
+            logical += nodesize;
+        }
+    }
+    free
+}
+
+/// where each item goes: the number of items in each leaf
+fn pack_leaves(items: &[(btrfs_disk_key, Vec<u8>)], nodesize: usize) -> Vec<usize> {
+    let space = nodesize - std::mem::size_of::<btrfs_header>();
+    let mut leaves = Vec::new();
+    let mut count = 0;
+    let mut used = 0;
+    for (_, data) in items {
+        let size = std::mem::size_of::<btrfs_item>() + data.len();
+        if used + size > space {
+            leaves.push(count);
+            count = 0;
+            used = 0;
+        }
+        count += 1;
+        used += size;
+    }
+    if count > 0 || leaves.is_empty() {
+        leaves.push(count);
+    }
+    leaves
+}
+
+/// the number of nodes at each level above leaves, bottom up
+fn node_levels(leaves: usize, nodesize: usize) -> Vec<usize> {
+    let per_node =
+        (nodesize - std::mem::size_of::<btrfs_header>()) / std::mem::size_of::<btrfs_key_ptr>();
+    let mut levels = Vec::new();
+    let mut below = leaves;
+    while below > 1 {
+        below = below.div_ceil(per_node);
+        levels.push(below);
+    }
+    levels
+}
+
+/// what rebuild_extent_tree found and wrote
+pub struct ExtentTreeRebuild {
+    pub tree_blocks: usize,
+    pub data_extents: usize,
+    pub block_groups: usize,
+    pub items: usize,
+    /// root, level and block count of the new tree
+    pub root: u64,
+    pub level: u8,
+    pub new_blocks: usize,
+    /// every copy written, the new tree's blocks and then the root tree leaf
+    /// holding the extent tree's ROOT_ITEM
+    pub copies: Vec<CopyRepair>,
+}
+
+/// write a new extent tree describing every block and data extent in use,
+/// and point the extent tree's ROOT_ITEM at it. The other trees must be
+/// intact and the log tree empty. Afterwards the free space cache or tree
+/// doesn't know the new tree's blocks are used, and must be cleared before
+/// the filesystem is next mounted read-write.
+pub fn rebuild_extent_tree(fs: &FsInfo, options: &RepairOptions) -> Result<ExtentTreeRebuild> {
+    let sb = &fs.master_sb;
+    ensure!(
+        sb.log_root == 0,
+        "the log tree must be replayed or zeroed before the extent tree is rebuilt"
+    );
+    ensure!(
+        sb.compat_ro_flags & BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE == 0
+            && sb.incompat_flags & BTRFS_FEATURE_INCOMPAT_EXTENT_TREE_V2 == 0,
+        "filesystems with a block group tree or extent tree v2 are not supported"
+    );
+    let nodesize = sb.nodesize as usize;
+    let generation = sb.generation;
+    let blocks = walk_trees(fs)?;
+    let extents = data_refs(fs, &blocks)?;
+    let base_items = extent_items(fs, &blocks, &extents)?;
+    let free = free_metadata_blocks(fs, &blocks, &extents);
+    let chunks = all_chunks(fs);
+
+    // the new tree's blocks need items too, so grow the tree until it holds
+    // the items for its own blocks
+    let mut new_blocks = 1;
+    let (items, leaves, levels) = loop {
+        ensure!(
+            new_blocks <= free.len(),
+            "{new_blocks} free metadata blocks are needed, only {} were found",
+            free.len()
+        );
+        let mut items = base_items.clone();
+        for &logical in &free[..new_blocks] {
+            // levels and first keys are filled in once the layout is known
+            let new_block = UsedBlock {
+                owner: BTRFS_EXTENT_TREE_OBJECTID,
+                level: 0,
+                generation,
+                first_key: NO_KEY,
+                reloc: false,
+                trees: vec![BTRFS_EXTENT_TREE_OBJECTID],
+                parents: Vec::new(),
+                root_of: Vec::new(),
+            };
+            let refs = vec![tree_block_ref(BTRFS_EXTENT_TREE_OBJECTID)];
+            insert_tree_block(&mut items, fs, logical, &new_block, refs);
+        }
+        for ChunkInfo(key, chunk, _stripes) in &chunks {
+            let start = key.offset;
+            let end = start + chunk.length;
+            let mut used_bytes = blocks.range(start..end).count() as u64 * nodesize as u64;
+            used_bytes += extents
+                .range(start..end)
+                .map(|(_, e)| e.length)
+                .sum::<u64>();
+            used_bytes += free[..new_blocks]
+                .iter()
+                .filter(|&&b| (start..end).contains(&b))
+                .count() as u64
+                * nodesize as u64;
+            let bg = btrfs_block_group_item {
+                used: used_bytes,
+                chunk_objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+                flags: chunk.r#type,
+            };
+            insert_item(
+                &mut items,
+                start,
+                BtrfsItemType::BLOCK_GROUP_ITEM,
+                chunk.length,
+                as_bytes(&bg).to_vec(),
+            );
+        }
+        let items: Vec<(btrfs_disk_key, Vec<u8>)> = items.into_values().collect();
+        let leaves = pack_leaves(&items, nodesize);
+        let levels = node_levels(leaves.len(), nodesize);
+        let needed = leaves.len() + levels.iter().sum::<usize>();
+        // every reserved block must be used, or its item would claim space
+        // the tree doesn't hold
+        if needed == new_blocks {
+            break (items, leaves, levels);
+        }
+        new_blocks = needed.max(new_blocks + 1);
+    };
+    let addresses = &free[..new_blocks];
+
+    // leaves take the first addresses, then each level of nodes in turn,
+    // each node taking an even share of the level below
+    let mut block_levels = vec![0_u8; leaves.len()];
+    let mut groups = Vec::new();
+    let mut below = leaves.len();
+    for (level, &count) in levels.iter().enumerate() {
+        block_levels.extend(std::iter::repeat_n(level as u8 + 1, count));
+        groups.push(
+            (0..count)
+                .map(|g| g * below / count..(g + 1) * below / count)
+                .collect::<Vec<_>>(),
+        );
+        below = count;
+    }
+
+    // the new blocks' own items are fixed up in place: the level is part of
+    // a skinny item's key, but changing it doesn't move the item
+    let skinny = sb.incompat_flags & BTRFS_FEATURE_INCOMPAT_SKINNY_METADATA != 0;
+    let index: BTreeMap<u64, usize> = addresses.iter().enumerate().map(|(i, &b)| (b, i)).collect();
+    let info_level =
+        std::mem::size_of::<btrfs_extent_item>() + std::mem::size_of::<btrfs_disk_key>();
+    let mut items = items;
+    for (key, data) in items.iter_mut() {
+        let objectid = key.objectid;
+        match (index.get(&objectid), key.item_type) {
+            (Some(&i), BtrfsItemType::METADATA_ITEM) => key.offset = block_levels[i] as u64,
+            (Some(&i), BtrfsItemType::EXTENT_ITEM) => data[info_level] = block_levels[i],
+            _ => {}
+        }
+    }
+    let mut first_keys = Vec::new();
+    let mut start = 0;
+    for &count in &leaves {
+        first_keys.push(items.get(start).map_or(NO_KEY, |(key, _)| *key));
+        start += count;
+    }
+    let mut level_start = 0;
+    for level_groups in &groups {
+        for group in level_groups {
+            first_keys.push(first_keys[level_start + group.start]);
+        }
+        level_start += level_groups.last().map_or(0, |g| g.end);
+    }
+    if !skinny {
+        for (key, data) in items.iter_mut() {
+            let objectid = key.objectid;
+            if let (Some(&i), BtrfsItemType::EXTENT_ITEM) = (index.get(&objectid), key.item_type) {
+                let info_key = std::mem::size_of::<btrfs_extent_item>();
+                data[info_key..info_level].copy_from_slice(as_bytes(&first_keys[i]));
+            }
+        }
+    }
+
+    let chunk_root = load_virt_block(fs, sb.chunk_root)?;
+    let chunk_tree_uuid = unsafe { &*(chunk_root.as_ptr() as *const btrfs_header) }.chunk_tree_uuid;
+    let header = |bytenr: u64| btrfs_header {
+        csum: [0; BTRFS_CSUM_SIZE],
+        fsid: fs.metadata_fsid(),
+        bytenr,
+        flags: BTRFS_HEADER_FLAG_WRITTEN | (BTRFS_MIXED_BACKREF_REV << BTRFS_BACKREF_REV_SHIFT),
+        chunk_tree_uuid,
+        generation,
+        owner: BTRFS_EXTENT_TREE_OBJECTID,
+        nritems: 0,
+        level: block_levels[index[&bytenr]],
+    };
+    let key_ptr = |i: usize| btrfs_key_ptr {
+        key: first_keys[i],
+        blockptr: addresses[i],
+        generation,
+    };
+    let mut built = Vec::new();
+    let mut start = 0;
+    for (i, &count) in leaves.iter().enumerate() {
+        let bytenr = addresses[i];
+        let block = build_leaf(&header(bytenr), &items[start..start + count], nodesize)
+            .ok_or_else(|| anyhow!("items don't fit in leaf {bytenr}"))?;
+        built.push((bytenr, block));
+        start += count;
+    }
+    let mut level_start = 0;
+    for level_groups in &groups {
+        let next_start = built.len();
+        for group in level_groups {
+            let bytenr = addresses[built.len()];
+            let ptrs: Vec<btrfs_key_ptr> =
+                group.clone().map(|j| key_ptr(level_start + j)).collect();
+            let block = build_node(&header(bytenr), &ptrs, nodesize)
+                .ok_or_else(|| anyhow!("pointers don't fit in node {bytenr}"))?;
+            built.push((bytenr, block));
+        }
+        level_start = next_start;
+    }
+    let root = addresses[new_blocks - 1];
+    let level = levels.len() as u8;
+
+    // find the ROOT_ITEM before anything is written, so a failure leaves the
+    // devices as they were
+    let search = key_range(
+        Some(BTRFS_EXTENT_TREE_OBJECTID),
+        Some(BtrfsItemType::ROOT_ITEM),
+        None,
+    );
+    let (leaf, slot) = search_range(fs, sb.root, search)
+        .find(|(item, ..)| item.key.item_type == BtrfsItemType::ROOT_ITEM)
+        .map(|(_, _, leaf, slot)| (leaf, slot))
+        .ok_or_else(|| anyhow!("the extent tree's ROOT_ITEM was not found"))?;
+    let mut root_leaf = load_virt_block(fs, leaf)?.to_vec();
+    let NodeEntry::Item(item, Some(_)) = node_entries(&root_leaf)[slot as usize] else {
+        bail!("the extent tree's ROOT_ITEM lies outside its leaf");
+    };
+    let item_start = std::mem::size_of::<btrfs_header>() + item.offset as usize;
+    let item_size = item.size as usize;
+    ensure!(
+        item_size >= std::mem::size_of::<btrfs_root_item>(),
+        "the extent tree's ROOT_ITEM is only {item_size} bytes"
+    );
+    let root_item = unsafe { &mut *(root_leaf[item_start..].as_mut_ptr() as *mut btrfs_root_item) };
+    root_item.bytenr = root;
+    root_item.level = level;
+    root_item.generation = generation;
+    root_item.generation_v2 = generation;
+    root_item.bytes_used = (new_blocks * nodesize) as u64;
+    root_item.drop_progress = NO_KEY;
+    root_item.drop_level = 0;
+
+    // the new blocks, then the leaf pointing at them, sealed on commit
+    let mut transaction = RepairTransaction::new(fs);
+    for (bytenr, block) in built {
+        transaction.stage_block(bytenr, block);
+    }
+    transaction.stage_block(leaf, root_leaf);
+    let copies = transaction.commit(options)?.copies;
+    Ok(ExtentTreeRebuild {
+        tree_blocks: blocks.len(),
+        data_extents: extents.len(),
+        block_groups: chunks.len(),
+        items: items.len(),
+        root,
+        level,
+        new_blocks,
+        copies,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_refs_overflow_to_keyed_items() {
+        let mut items = Items::new();
+        let key = btrfs_disk_key {
+            objectid: 1 << 20,
+            item_type: BtrfsItemType::METADATA_ITEM,
+            offset: 0,
+        };
+        let refs = (1..=30).map(shared_block_ref).collect();
+        insert_extent(&mut items, 4096, key, extent_item(30, 1, 0), refs);
+
+        // a 4K leaf allows 224 byte extent items: the 24 byte extent item and
+        // 22 refs, the highest parents first
+        let (_, data) = &items[&(1 << 20, BtrfsItemType::METADATA_ITEM as u8, 0)];
+        let (inline, rest) = parse_inline_refs(&data[std::mem::size_of::<btrfs_extent_item>()..]);
+        assert!(rest.is_none());
+        let expected: Vec<ExtentRef> = (9..=30)
+            .rev()
+            .map(|parent| ExtentRef::SharedBlock { parent })
+            .collect();
+        assert_eq!(inline, expected);
+        let keyed: Vec<u64> = items
+            .keys()
+            .filter(|k| k.1 == BtrfsItemType::SHARED_BLOCK_REF as u8)
+            .map(|k| k.2)
+            .collect();
+        assert_eq!(keyed, (1..=8).collect::<Vec<_>>());
+    }
+}
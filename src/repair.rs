@@ -0,0 +1,796 @@
+//! Repair of damaged copies on mirrored (DUP/RAID1*) chunks, by overwriting
+//! them with a copy that verifies, and of RAID5/6 strips by regenerating
+//! them from the rest of their full stripe.
+//!
+//! Lost csum tree leaves can also be regenerated from the data they cover.
+//!
+//! Every write to the devices goes through DeviceInfo::write_at, also from
+//! rebuild.rs and edit.rs, and each copy is saved to a backup file before
+//! it is overwritten. A RepairTransaction writes several blocks and the
+//! superblocks as one, checking and backing up everything first.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::edit::{sealed_super, super_mirrors, SuperCopy};
+use crate::items::item_as;
+use crate::raid56::*;
+use crate::scrub::*;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub struct RepairOptions {
+    /// report what would be repaired without writing anything
+    pub dry_run: bool,
+    /// where to save the damaged copies before they are overwritten
+    pub backup_dir: PathBuf,
+}
+
+/// a copy which was (or in a dry run, would be) overwritten
+pub struct CopyRepair {
+    pub logical: u64,
+    pub length: u64,
+    pub devid: u64,
+    pub physical: u64,
+    pub backup: Option<PathBuf>,
+}
+
+pub(crate) fn save_backup(dir: &Path, devid: u64, physical: u64, data: &[u8]) -> Result<PathBuf> {
+    let path = dir.join(format!("devid{devid}-{physical}.bin"));
+    // never replace an earlier backup, it may be the only record of the original
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .with_context(|| format!("cannot create backup {}", path.display()))?;
+    file.write_all(data)?;
+    file.sync_data()?;
+    Ok(path)
+}
+
+/// overwrite the bad copies of the tree block at logical, or of the data
+/// sector containing logical, with a copy that verifies. Tree blocks are
+/// verified as in scrub_metadata, data against the csum tree.
+pub fn repair_copies(
+    fs: &FsInfo,
+    logical: u64,
+    options: &RepairOptions,
+) -> Result<Vec<CopyRepair>> {
+    let ChunkInfo(_key, chunk, _stripes) = find_chunk(fs, logical)
+        .ok_or_else(|| anyhow!("logical {logical} is not within any chunk"))?;
+    let chunk_type = chunk.r#type;
+    let (logical, length, csum) = if chunk_type & BTRFS_BLOCK_GROUP_DATA != 0 {
+        let sectorsize = fs.master_sb.sectorsize as u64;
+        let logical = logical - logical % sectorsize;
+        let csum = data_csum(fs, logical)?.ok_or_else(|| {
+            anyhow!("data at {logical} has no checksum, so good and bad copies can't be told apart")
+        })?;
+        (logical, sectorsize, Some(csum))
+    } else {
+        let nodesize = fs.master_sb.nodesize as u64;
+        ensure!(
+            logical.is_multiple_of(nodesize),
+            "{logical} is not aligned to the node size {nodesize}"
+        );
+        (logical, nodesize, None)
+    };
+    let csum_type = fs.master_sb.csum_type;
+    let verifies = |block: &[u8]| match &csum {
+        Some(csum) => csum_data(block, csum_type)[..csum.len()] == csum[..],
+        None => check_tree_block(fs, block, logical, None).is_empty(),
+    };
+
+    let copies = block_copies(fs, logical, length)?;
+    let good = copies
+        .iter()
+        .filter_map(|c| c.data.as_deref())
+        .find(|block| verifies(block))
+        .ok_or_else(|| anyhow!("no copy of {logical} verifies, there is nothing to repair from"))?;
+    let mut repairs = Vec::new();
+    for copy in &copies {
+        let Some(block) = &copy.data else {
+            continue;
+        };
+        if verifies(block) {
+            continue;
+        }
+        let mut repair = CopyRepair {
+            logical,
+            length,
+            devid: copy.devid,
+            physical: copy.physical,
+            backup: None,
+        };
+        if !options.dry_run {
+            let dev = &fs.devid_map[&copy.devid];
+            repair.backup = Some(save_backup(
+                &options.backup_dir,
+                copy.devid,
+                copy.physical,
+                block,
+            )?);
+            dev.write_at(fs, copy.physical, good)?;
+        }
+        repairs.push(repair);
+    }
+    Ok(repairs)
+}
+
+#[derive(Default)]
+pub struct RepairReport {
+    pub repairs: Vec<CopyRepair>,
+    /// logical address and reason of each block that couldn't be repaired
+    pub failures: Vec<(u64, String)>,
+}
+
+/// run a metadata and data scrub and repair everything found damaged that
+/// has a good copy
+pub fn repair_from_scrub(fs: &FsInfo, options: &RepairOptions) -> Result<RepairReport> {
+    let mut targets = BTreeSet::new();
+    for block in scrub_metadata(fs).damaged {
+        targets.insert(block.logical);
+    }
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    for bad in scrub_data(fs, None)?.bad {
+        targets.extend((bad.logical..bad.logical + bad.length).step_by(sectorsize as usize));
+    }
+    let mut report = RepairReport::default();
+    for logical in targets {
+        match repair_copies(fs, logical, options) {
+            Result::Ok(r) => report.repairs.extend(r),
+            Err(e) => report.failures.push((logical, e.to_string())),
+        }
+    }
+    Ok(report)
+}
+
+/// regenerate the strip on devid of the RAID5/6 full stripe containing
+/// logical from the other strips, and overwrite it. Returns None if the
+/// strip already holds what would be written.
+pub fn rebuild_strip(
+    fs: &FsInfo,
+    logical: u64,
+    devid: u64,
+    options: &RepairOptions,
+) -> Result<Option<(StripRole, CopyRepair)>> {
+    let stripe = full_stripe(fs, logical)?;
+    let target = stripe
+        .strips
+        .iter()
+        .position(|s| s.devid == devid)
+        .ok_or_else(|| {
+            anyhow!(
+                "full stripe {} has no strip on devid {devid}",
+                stripe.logical
+            )
+        })?;
+    let strip = &stripe.strips[target];
+    let dev = fs
+        .devid_map
+        .get(&devid)
+        .ok_or_else(|| anyhow!("devid {devid} is missing"))?;
+    let contents = regenerate_strip(&stripe, target)?;
+    if strip.data.as_ref() == Some(&contents) {
+        return Ok(None);
+    }
+    let mut repair = CopyRepair {
+        logical: match strip.role {
+            StripRole::Data(logical) => logical,
+            StripRole::P | StripRole::Q => stripe.logical,
+        },
+        length: stripe.stripe_len,
+        devid,
+        physical: strip.physical,
+        backup: None,
+    };
+    if !options.dry_run {
+        if let Some(old) = &strip.data {
+            repair.backup = Some(save_backup(
+                &options.backup_dir,
+                devid,
+                strip.physical,
+                old,
+            )?);
+        }
+        dev.write_at(fs, strip.physical, &contents)?;
+    }
+    Ok(Some((strip.role, repair)))
+}
+
+/// write a tree block to every copy of logical whose device is present,
+/// saving each old copy first, as a transaction of the one block
+pub(crate) fn write_tree_block(
+    fs: &FsInfo,
+    logical: u64,
+    block: &[u8],
+    options: &RepairOptions,
+) -> Result<Vec<CopyRepair>> {
+    let mut transaction = RepairTransaction::new(fs);
+    transaction.stage_block(logical, block.to_vec());
+    Ok(transaction.commit(options)?.copies)
+}
+
+/// tree blocks and superblocks to be written together, e.g. a leaf, its
+/// parent and the superblocks. Nothing is written until commit, which
+/// seals and checks every block and reads every old copy first, so that a
+/// transaction which can't be written fails with the devices untouched, as
+/// does dropping one without committing.
+pub struct RepairTransaction<'a> {
+    fs: &'a FsInfo,
+    /// in the order they are written
+    blocks: Vec<(u64, Vec<u8>)>,
+    /// logical address to index in blocks
+    staged: HashMap<u64, usize>,
+    /// written before the tree blocks
+    leading_supers: Vec<btrfs_super_block>,
+    supers: Vec<btrfs_super_block>,
+    /// the fsid the blocks are being changed to, if they are
+    fsid: Option<BtrfsFsid>,
+}
+
+/// what a transaction wrote (or in a dry run, would write)
+#[derive(Default)]
+pub struct TransactionReport {
+    pub copies: Vec<CopyRepair>,
+    /// the leading superblocks' copies, then the others'
+    pub supers: Vec<SuperCopy>,
+}
+
+/// a write commit makes: the device, where on it, the old copy and the new
+type DeviceWrite<'b> = (&'b DeviceInfo, u64, Vec<u8>, &'b [u8]);
+
+/// a superblock copy commit writes, as a DeviceWrite with the new copy
+/// sealed for where it goes
+type SuperWrite<'b> = (&'b DeviceInfo, u64, Vec<u8>, Vec<u8>);
+
+impl<'a> RepairTransaction<'a> {
+    pub fn new(fs: &'a FsInfo) -> Self {
+        Self {
+            fs,
+            blocks: Vec::new(),
+            staged: HashMap::new(),
+            leading_supers: Vec::new(),
+            supers: Vec::new(),
+            fsid: None,
+        }
+    }
+
+    /// the tree block at logical as staged, read from the disk the first
+    /// time, to be edited in place. It is sealed on commit.
+    pub fn block(&mut self, logical: u64) -> Result<&mut Vec<u8>> {
+        if !self.staged.contains_key(&logical) {
+            let block = load_virt_block(self.fs, logical)?.to_vec();
+            self.stage_block(logical, block);
+        }
+        Ok(&mut self.blocks[self.staged[&logical]].1)
+    }
+
+    /// write block at logical, replacing whatever was staged for it. Blocks
+    /// are written in the order they were first staged.
+    pub fn stage_block(&mut self, logical: u64, block: Vec<u8>) {
+        match self.staged.get(&logical) {
+            Some(&index) => self.blocks[index].1 = block,
+            None => {
+                self.staged.insert(logical, self.blocks.len());
+                self.blocks.push((logical, block));
+            }
+        }
+    }
+
+    /// write sb to every mirror of the device with its devid, after all the
+    /// tree blocks
+    pub fn stage_super(&mut self, sb: btrfs_super_block) {
+        stage_super_in(&mut self.supers, sb);
+    }
+
+    /// write sb to every mirror of the device with its devid before any
+    /// tree block, e.g. flagged to show that a change is under way, for the
+    /// superblock staged with stage_super to replace once they are written
+    pub fn stage_leading_super(&mut self, sb: btrfs_super_block) {
+        stage_super_in(&mut self.leading_supers, sb);
+    }
+
+    /// check the staged blocks which carry fsid as they were before it was
+    /// set in them, for a change of the filesystem's fsid
+    pub fn set_fsid(&mut self, fsid: BtrfsFsid) {
+        self.fsid = Some(fsid);
+    }
+
+    /// what check_tree_block finds wrong with a staged, sealed block
+    fn problems(&self, logical: u64, block: &[u8]) -> Vec<String> {
+        let fs = self.fs;
+        let changed = self
+            .fsid
+            .filter(|&fsid| item_as::<btrfs_header>(block).is_some_and(|h| h.fsid == fsid));
+        if changed.is_none() {
+            return check_tree_block(fs, block, logical, None);
+        }
+        let mut before = block.to_vec();
+        unsafe { &mut *(before.as_mut_ptr() as *mut btrfs_header) }.fsid = fs.metadata_fsid();
+        seal_tree_block(&mut before, fs.master_sb.csum_type);
+        check_tree_block(fs, &before, logical, None)
+    }
+
+    /// every copy of the staged sbs, as writes
+    fn super_writes(&self, supers: &[btrfs_super_block]) -> Result<Vec<SuperWrite<'a>>> {
+        let fs = self.fs;
+        let mut writes = Vec::new();
+        for sb in supers {
+            let devid = sb.dev_item.devid;
+            let dev = fs
+                .devid_map
+                .get(&devid)
+                .ok_or_else(|| anyhow!("devid {devid} is missing; nothing was written"))?;
+            dev.ensure_writable()?;
+            for physical in super_mirrors(dev.file.len() as u64) {
+                let old = dev.read_at(physical, BTRFS_SUPER_INFO_SIZE)?;
+                writes.push((&**dev, physical, old, sealed_super(sb, physical)));
+            }
+        }
+        Ok(writes)
+    }
+
+    /// write everything staged, in three phases: every old copy is saved
+    /// to a backup and the backups synced, then the leading superblocks
+    /// are written, the tree blocks, and the other superblocks. Each write
+    /// is synced before the next. The copies of a block on a missing device
+    /// are passed over, but one which is present and can't be read fails
+    /// the transaction, as it would be left out of step with the others.
+    pub fn commit(mut self, options: &RepairOptions) -> Result<TransactionReport> {
+        let fs = self.fs;
+        if let Some(generation) = fs.pinned_generation {
+            ensure!(
+                self.blocks.is_empty(),
+                "the trees were read at generation {generation}, so nothing is written"
+            );
+        }
+        let csum_type = fs.master_sb.csum_type;
+        for (_, block) in &mut self.blocks {
+            seal_tree_block(block, csum_type);
+        }
+        for (logical, block) in &self.blocks {
+            let problems = self.problems(*logical, block);
+            ensure!(
+                problems.is_empty(),
+                "staged block {logical}: {}; nothing was written",
+                problems.join(", ")
+            );
+        }
+        let leading = self.super_writes(&self.leading_supers)?;
+        let trailing = self.super_writes(&self.supers)?;
+        let mut report = TransactionReport::default();
+        let mut block_writes: Vec<DeviceWrite> = Vec::new();
+        for (logical, block) in &self.blocks {
+            for copy in block_copies(fs, *logical, block.len() as u64)? {
+                let Some(dev) = fs.devid_map.get(&copy.devid) else {
+                    continue;
+                };
+                let old = copy.data.ok_or_else(|| {
+                    anyhow!(
+                        "the copy of {logical} on devid {} at {} can't be read, and would be \
+                         left out of step with the others; nothing was written",
+                        copy.devid,
+                        copy.physical
+                    )
+                })?;
+                dev.ensure_writable()?;
+                block_writes.push((dev, copy.physical, old, &block[..]));
+                report.copies.push(CopyRepair {
+                    logical: *logical,
+                    length: block.len() as u64,
+                    devid: copy.devid,
+                    physical: copy.physical,
+                    backup: None,
+                });
+            }
+        }
+        let super_copy = |(dev, physical, ..): &SuperWrite| SuperCopy {
+            devid: dev.devid,
+            physical: *physical,
+            backup: None,
+        };
+        report.supers = leading.iter().chain(&trailing).map(super_copy).collect();
+        if options.dry_run {
+            return Ok(report);
+        }
+
+        fn as_write<'b>((dev, physical, old, new): &'b SuperWrite) -> DeviceWrite<'b> {
+            (dev, *physical, old.clone(), &new[..])
+        }
+        let writes: Vec<DeviceWrite> = leading
+            .iter()
+            .map(as_write)
+            .chain(block_writes)
+            .chain(trailing.iter().map(as_write))
+            .collect();
+        // a superblock written twice is backed up once, as it was first
+        let mut saved: HashMap<(u64, u64), PathBuf> = HashMap::new();
+        let mut backups = Vec::new();
+        for (dev, physical, old, _) in &writes {
+            let backup = match saved.entry((dev.devid, *physical)) {
+                Entry::Occupied(entry) => entry.get().clone(),
+                Entry::Vacant(entry) => entry
+                    .insert(save_backup(&options.backup_dir, dev.devid, *physical, old)?)
+                    .clone(),
+            };
+            backups.push(backup);
+        }
+        // so that the backups' directory entries are on disk too
+        std::fs::File::open(&options.backup_dir)?.sync_all()?;
+        let (leading_backups, rest) = backups.split_at(leading.len());
+        let (block_backups, trailing_backups) = rest.split_at(report.copies.len());
+        for (copy, backup) in report.copies.iter_mut().zip(block_backups) {
+            copy.backup = Some(backup.clone());
+        }
+        let super_backups = leading_backups.iter().chain(trailing_backups);
+        for (copy, backup) in report.supers.iter_mut().zip(super_backups) {
+            copy.backup = Some(backup.clone());
+        }
+
+        for (logical, block) in &self.blocks {
+            fs.block_cache.remove(*logical);
+            if item_as::<btrfs_header>(block).map(|header| header.owner)
+                == Some(BTRFS_ROOT_TREE_OBJECTID)
+            {
+                fs.invalidate_roots();
+            }
+        }
+        for (written, (dev, physical, _, new)) in writes.iter().enumerate() {
+            dev.write_at(fs, *physical, new).with_context(|| {
+                format!(
+                    "{written} of {} writes were made, the old copies are saved in {}",
+                    writes.len(),
+                    options.backup_dir.display()
+                )
+            })?;
+        }
+        Ok(report)
+    }
+}
+
+/// stage sb in supers, in place of any staged for its device
+fn stage_super_in(supers: &mut Vec<btrfs_super_block>, sb: btrfs_super_block) {
+    let devid = sb.dev_item.devid;
+    supers.retain(|staged| staged.dev_item.devid != devid);
+    supers.push(sb);
+}
+
+/// a csum tree leaf which was (or would be) regenerated
+pub struct CsumLeafRebuild {
+    pub leaf: u64,
+    /// the data range the leaf's items cover, as given by its parent's keys
+    pub start: u64,
+    pub end: u64,
+    pub items: usize,
+    pub sectors: u64,
+    pub copies: Vec<CopyRepair>,
+}
+
+#[derive(Default)]
+pub struct CsumRebuild {
+    pub leaves: Vec<CsumLeafRebuild>,
+    /// damaged csum tree blocks within the range which can't be regenerated
+    pub failures: Vec<(u64, String)>,
+}
+
+/// a leaf of the csum tree which doesn't verify, and what its parent expects
+/// of it
+struct LostLeaf {
+    leaf: u64,
+    generation: u64,
+    /// None for the root
+    first_key: Option<btrfs_disk_key>,
+    end_key: Option<btrfs_disk_key>,
+}
+
+/// the data address a csum tree key stands for, when used as a bound
+fn csum_key_offset(key: &btrfs_disk_key) -> u64 {
+    let first = btrfs_disk_key {
+        objectid: BTRFS_EXTENT_CSUM_OBJECTID,
+        item_type: BtrfsItemType::EXTENT_CSUM,
+        offset: 0,
+    };
+    match cmp_key(key, &first) {
+        std::cmp::Ordering::Less => 0,
+        _ if key.objectid == BTRFS_EXTENT_CSUM_OBJECTID
+            && key.item_type == BtrfsItemType::EXTENT_CSUM =>
+        {
+            key.offset
+        }
+        _ => u64::MAX,
+    }
+}
+
+/// the leaves of the csum tree which don't verify. Damaged nodes, whose
+/// leaves can't be found, are added to failures.
+fn lost_csum_leaves(fs: &FsInfo, failures: &mut Vec<(u64, String)>) -> Result<Vec<LostLeaf>> {
+    let search = key_range(
+        Some(BTRFS_CSUM_TREE_OBJECTID),
+        Some(BtrfsItemType::ROOT_ITEM),
+        None,
+    );
+    let (root, root_level, root_generation) = search_range(fs, fs.master_sb.root, search)
+        .filter(|(item, ..)| item.key.item_type == BtrfsItemType::ROOT_ITEM)
+        .find_map(|(_, data, ..)| {
+            item_as::<btrfs_root_item>(&data).map(|r| (r.bytenr, r.level, r.generation))
+        })
+        .ok_or_else(|| anyhow!("csum tree not found"))?;
+    let mut lost = Vec::new();
+    let mut stack = vec![(root, root_level, root_generation, None, None)];
+    while let Some((logical, level, generation, first_key, end_key)) = stack.pop() {
+        let problems = match load_virt_block_unverified(fs, logical) {
+            Result::Ok(block) => check_tree_block(fs, &block, logical, Some(level)),
+            Err(e) => vec![e.to_string()],
+        };
+        if !problems.is_empty() {
+            if level == 0 {
+                lost.push(LostLeaf {
+                    leaf: logical,
+                    generation,
+                    first_key,
+                    end_key,
+                });
+            } else {
+                failures.push((
+                    logical,
+                    format!("level {level} node: {}", problems.join(", ")),
+                ));
+            }
+            continue;
+        }
+        if level == 0 {
+            continue;
+        }
+        let block = load_virt_block(fs, logical)?;
+        let ptrs: Vec<&btrfs_key_ptr> = node_entries(&block)
+            .into_iter()
+            .filter_map(|e| match e {
+                NodeEntry::Ptr(ptr) => Some(ptr),
+                NodeEntry::Item(..) => None,
+            })
+            .collect();
+        for (slot, ptr) in ptrs.iter().enumerate().rev() {
+            stack.push((
+                ptr.blockptr,
+                level - 1,
+                ptr.generation,
+                Some(ptr.key),
+                ptrs.get(slot + 1).map(|next| next.key).or(end_key),
+            ));
+        }
+    }
+    Ok(lost)
+}
+
+/// end of the csum item covering the data just before logical, if it runs
+/// on past logical
+fn previous_csum_end(fs: &FsInfo, csum_root: u64, logical: u64) -> Option<u64> {
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let csum_size = csum_size(fs.master_sb.csum_type) as u64;
+    let key = btrfs_disk_key {
+        objectid: BTRFS_EXTENT_CSUM_OBJECTID,
+        item_type: BtrfsItemType::EXTENT_CSUM,
+        offset: logical.checked_sub(1)?,
+    };
+    let (item, data, _, _) = BtrfsTreeIter::new(fs, csum_root, SearchKey::floor(key)).next()?;
+    let start = item.key.offset;
+    if item.key.objectid != BTRFS_EXTENT_CSUM_OBJECTID
+        || item.key.item_type != BtrfsItemType::EXTENT_CSUM
+        || start >= logical
+    {
+        return None;
+    }
+    let end = start + data.len() as u64 / csum_size * sectorsize;
+    (end > logical).then_some(end)
+}
+
+/// regenerate, in place, every csum tree leaf that doesn't verify and covers
+/// data between start and end. Each leaf gets the checksums of the data in
+/// the key range its parent gives it, computed from the first copy of each
+/// sector, so the data must be good. A leaf whose items don't fit in one
+/// block, or that is reached through a damaged node, can't be regenerated.
+pub fn rebuild_csums(
+    fs: &FsInfo,
+    start: u64,
+    end: u64,
+    options: &RepairOptions,
+) -> Result<CsumRebuild> {
+    ensure!(
+        !extent_tree_v2(fs),
+        "rebuilding the csum tree's global roots of extent tree v2 is not supported"
+    );
+    let csum_root = tree_root_offset(fs, BTRFS_CSUM_TREE_OBJECTID)
+        .ok_or_else(|| anyhow!("csum tree not found"))?;
+    let nodesize = fs.master_sb.nodesize as usize;
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let csum_type = fs.master_sb.csum_type;
+    let csum_size = csum_size(csum_type);
+    // as the kernel's MAX_CSUM_ITEMS
+    let leaf_data_size = nodesize - std::mem::size_of::<btrfs_header>();
+    let max_item_sectors =
+        ((leaf_data_size - 2 * std::mem::size_of::<btrfs_item>()) / csum_size - 1) as u64;
+
+    let mut rebuild = CsumRebuild::default();
+    let lost: Vec<LostLeaf> = lost_csum_leaves(fs, &mut rebuild.failures)?
+        .into_iter()
+        .filter(|l| {
+            l.first_key.map_or(0, |k| csum_key_offset(&k)) < end
+                && l.end_key.map_or(u64::MAX, |k| csum_key_offset(&k)) > start
+        })
+        .collect();
+    if lost.is_empty() {
+        return Ok(rebuild);
+    }
+    let chunk_root = load_virt_block(fs, fs.master_sb.chunk_root)?;
+    let chunk_tree_uuid = unsafe { &*(chunk_root.as_ptr() as *const btrfs_header) }.chunk_tree_uuid;
+    let datasum = datasum_ranges(fs)?;
+
+    'leaves: for leaf in lost {
+        let span_start = leaf.first_key.map_or(0, |k| csum_key_offset(&k));
+        let span_end = leaf.end_key.map_or(u64::MAX, |k| csum_key_offset(&k));
+        let from = previous_csum_end(fs, csum_root, span_start).unwrap_or(span_start);
+        let mut items: Vec<(btrfs_disk_key, Vec<u8>)> = Vec::new();
+        let mut sectors = 0;
+        for &(data_start, data_end) in &datasum {
+            let run_start = data_start.max(from).next_multiple_of(sectorsize);
+            let run_end = data_end.min(span_end);
+            let mut logical = run_start;
+            while logical < run_end {
+                let item_start = logical;
+                let item_end = run_end.min(logical + max_item_sectors * sectorsize);
+                let mut csums = Vec::new();
+                while logical < item_end {
+                    let copies = match block_copies(fs, logical, sectorsize) {
+                        Result::Ok(copies) => copies,
+                        Err(e) => {
+                            rebuild.failures.push((leaf.leaf, e.to_string()));
+                            continue 'leaves;
+                        }
+                    };
+                    let Some(sector) = copies.into_iter().find_map(|c| c.data) else {
+                        rebuild.failures.push((
+                            leaf.leaf,
+                            format!("no copy of data sector {logical} is present"),
+                        ));
+                        continue 'leaves;
+                    };
+                    csums.extend_from_slice(&csum_data(&sector, csum_type)[..csum_size]);
+                    logical += sectorsize;
+                    sectors += 1;
+                }
+                items.push((
+                    btrfs_disk_key {
+                        objectid: BTRFS_EXTENT_CSUM_OBJECTID,
+                        item_type: BtrfsItemType::EXTENT_CSUM,
+                        offset: item_start,
+                    },
+                    csums,
+                ));
+            }
+        }
+        // the parent's key pointer must still match the leaf's first key
+        if let Some(first_key) = leaf.first_key {
+            if items
+                .first()
+                .is_none_or(|(key, _)| cmp_key(key, &first_key) != std::cmp::Ordering::Equal)
+            {
+                rebuild.failures.push((
+                    leaf.leaf,
+                    format!(
+                        "no checksummed data starts at {span_start}, where the parent expects the leaf's first item"
+                    ),
+                ));
+                continue;
+            }
+        }
+        let header = btrfs_header {
+            csum: [0; BTRFS_CSUM_SIZE],
+            fsid: fs.metadata_fsid(),
+            bytenr: leaf.leaf,
+            flags: BTRFS_HEADER_FLAG_WRITTEN | (BTRFS_MIXED_BACKREF_REV << BTRFS_BACKREF_REV_SHIFT),
+            chunk_tree_uuid,
+            generation: leaf.generation,
+            owner: BTRFS_CSUM_TREE_OBJECTID,
+            nritems: 0,
+            level: 0,
+        };
+        let Some(mut block) = build_leaf(&header, &items, nodesize) else {
+            rebuild.failures.push((
+                leaf.leaf,
+                format!(
+                    "{} items of checksums for {sectors} sectors don't fit in one leaf",
+                    items.len()
+                ),
+            ));
+            continue;
+        };
+        seal_tree_block(&mut block, csum_type);
+        let copies = write_tree_block(fs, leaf.leaf, &block, options)?;
+        rebuild.leaves.push(CsumLeafRebuild {
+            leaf: leaf.leaf,
+            start: span_start,
+            end: span_end,
+            items: items.len(),
+            sectors,
+            copies,
+        });
+    }
+    Ok(rebuild)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::tests::{two_level_tree, NODESIZE};
+
+    #[test]
+    fn transaction() {
+        let mut fs = two_level_tree("transaction");
+        let dev = &fs.devid_map[&1];
+        // the fixture's file is gone once mapped, so writes go to a copy
+        let image = dev.read_at(0, dev.file.len()).unwrap();
+        std::fs::write(&dev.path, &image).unwrap();
+        let backup_dir = dev.path.with_extension("backups");
+        std::fs::create_dir(&backup_dir).unwrap();
+        let options = RepairOptions {
+            dry_run: false,
+            backup_dir: backup_dir.clone(),
+        };
+        let set_generation = |transaction: &mut RepairTransaction| {
+            let leaf = transaction.block(NODESIZE as u64).unwrap();
+            unsafe { &mut *(leaf.as_mut_ptr() as *mut btrfs_header) }.generation = 5;
+            let root = transaction.block(0).unwrap();
+            let at = std::mem::size_of::<btrfs_header>();
+            unsafe { &mut *(root[at..].as_mut_ptr() as *mut btrfs_key_ptr) }.generation = 5;
+        };
+
+        // a block which doesn't check out aborts the whole transaction
+        let mut transaction = RepairTransaction::new(&fs);
+        set_generation(&mut transaction);
+        transaction.stage_block(2 * NODESIZE as u64, image[..NODESIZE].to_vec());
+        let Err(error) = transaction.commit(&options) else {
+            panic!("a bad block was committed");
+        };
+        assert!(error.to_string().contains("nothing was written"), "{error}");
+        assert_eq!(std::fs::read(&dev.path).unwrap(), image);
+        assert_eq!(std::fs::read_dir(&backup_dir).unwrap().count(), 0);
+
+        let mut transaction = RepairTransaction::new(&fs);
+        set_generation(&mut transaction);
+        let report = transaction.commit(&options).unwrap();
+        assert_eq!(report.copies.len(), 2);
+        assert_eq!(report.copies[0].logical, NODESIZE as u64);
+        let written = std::fs::read(&dev.path).unwrap();
+        for copy in &report.copies {
+            let (start, end) = (
+                copy.physical as usize,
+                (copy.physical + copy.length) as usize,
+            );
+            let block = &written[start..end];
+            assert!(check_tree_block(&fs, block, copy.logical, None).is_empty());
+            assert_ne!(block, &image[start..end]);
+            let backup = std::fs::read(copy.backup.as_ref().unwrap()).unwrap();
+            assert_eq!(backup, &image[start..end]);
+        }
+        assert_eq!(written[2 * NODESIZE..], image[2 * NODESIZE..]);
+        let path = dev.path.clone();
+
+        // nor is a copy which is present but can't be read left stale
+        fs.quarantine.add_physical(1, 0..1);
+        let mut transaction = RepairTransaction::new(&fs);
+        transaction.stage_block(0, written[..NODESIZE].to_vec());
+        let Err(error) = transaction.commit(&options) else {
+            panic!("a block with an unreadable copy was committed");
+        };
+        assert!(error.to_string().contains("can't be read"), "{error}");
+        assert_eq!(std::fs::read(&path).unwrap(), written);
+        std::fs::remove_dir_all(&backup_dir).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,614 @@
+//! Extraction of files from a subvolume into a directory, for when the
+//! filesystem can't be mounted.
+//!
+//! Each data sector is read from the first copy matching its checksum (or
+//! the first copy which can be read, for data without checksums). Normally
+//! the first sector which can't be read that way ends the restore; with
+//! salvage the range is written as zeros and recorded in the file's damage
+//! map instead. Compressed data is decompressed; a compressed extent can
+//! only be decompressed whole, so one unreadable sector loses all of it.
+//!
+//! Directories, regular files and symlinks are restored with their
+//! permission bits and xattrs (POSIX ACLs among them); files sealed with
+//! fs-verity come out without it unless asked to seal them again. Hard links
+//! come out as separate copies, and device nodes, fifos, sockets and the
+//! subvolumes within the one restored are skipped. Off unix there are no
+//! permission bits to set nor symlinks to create, and off Linux xattrs
+//! aren't set; the report says so.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::btrfs_node::ItemData;
+use crate::compress::decompress;
+use crate::inode::{dir_entries, inode_item, lookup_path, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG};
+use crate::items::item_as;
+use crate::scrub::data_csum;
+use crate::structures::*;
+use crate::tree::*;
+use crate::verity::*;
+
+use anyhow::*;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub struct RestoreOptions {
+    /// the directory to restore into, created if need be; nothing in it is
+    /// overwritten
+    pub output_dir: PathBuf,
+    /// write what can't be read as zeros and go on, rather than stopping
+    pub salvage: bool,
+    /// set the xattrs of each file, ACLs and SELinux labels included
+    pub xattrs: bool,
+    /// enable fs-verity on the files which had it, with the same hash,
+    /// block size, salt and signature, rather than restoring them without it
+    pub verity: bool,
+}
+
+/// a file restored with the byte ranges which couldn't be read
+pub struct DamagedFile {
+    pub path: PathBuf,
+    /// start and end of each range lost, written as zeros
+    pub lost: Vec<(u64, u64)>,
+    /// why, once per distinct reason
+    pub reasons: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Restore {
+    pub files: u64,
+    pub directories: u64,
+    pub symlinks: u64,
+    /// the size of the files restored, lost ranges included
+    pub bytes: u64,
+    pub damaged: Vec<DamagedFile>,
+    /// what wasn't restored, and why
+    pub skipped: Vec<String>,
+    /// files which had fs-verity but were restored without it, and why
+    pub verity_stripped: Vec<(PathBuf, String)>,
+}
+
+/// struct fsverity_enable_arg
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct FsverityEnableArg {
+    version: u32,
+    hash_algorithm: u32,
+    block_size: u32,
+    salt_size: u32,
+    salt_ptr: u64,
+    sig_size: u32,
+    reserved1: u32,
+    sig_ptr: u64,
+    reserved2: [u64; 11],
+}
+#[cfg(target_os = "linux")]
+static_assertions::assert_eq_size!([u8; 128], FsverityEnableArg);
+
+/// _IOW('f', 133, struct fsverity_enable_arg)
+#[cfg(target_os = "linux")]
+const FS_IOC_ENABLE_VERITY: libc::c_ulong = 0x40806685;
+
+/// seal the file at path with fs-verity as desc describes. The file must
+/// not be open for writing.
+#[cfg(target_os = "linux")]
+fn enable_verity(path: &Path, desc: &VerityDescriptor) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let file = std::fs::File::open(path)?;
+    let arg = FsverityEnableArg {
+        version: 1,
+        hash_algorithm: desc.hash_algorithm as u32,
+        block_size: desc.block_size,
+        salt_size: desc.salt.len() as u32,
+        salt_ptr: desc.salt.as_ptr() as u64,
+        sig_size: desc.signature.len() as u32,
+        reserved1: 0,
+        sig_ptr: desc.signature.as_ptr() as u64,
+        reserved2: [0; 11],
+    };
+    let enabled = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_ENABLE_VERITY, &arg) };
+    if enabled != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    std::io::Result::Ok(())
+}
+
+/// fs-verity is Linux's own, so the file is left without it
+#[cfg(not(target_os = "linux"))]
+fn enable_verity(path: &Path, _desc: &VerityDescriptor) -> std::io::Result<()> {
+    log::warn!(
+        "{}: fs-verity can't be enabled on this platform",
+        path.display()
+    );
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "fs-verity is only available on Linux",
+    ))
+}
+
+/// the sector at logical from the first copy which matches its checksum,
+/// or why none could be read. Sectors of striped chunks (RAID0, RAID10 and
+/// RAID5/6), which block_copies doesn't map, are read with read_logical.
+fn read_sector(fs: &FsInfo, logical: u64) -> std::result::Result<Vec<u8>, String> {
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let csum_type = fs.master_sb.csum_type;
+    let expected = data_csum(fs, logical).map_err(|e| e.to_string())?;
+    let matches = |data: &[u8]| match &expected {
+        Some(expected) => csum_data(data, csum_type)[..expected.len()] == expected[..],
+        None => true,
+    };
+    let copies = match block_copies(fs, logical, sectorsize) {
+        Result::Ok(copies) => copies,
+        Err(_) => {
+            let data = read_logical(fs, logical, sectorsize).map_err(|e| format!("{e:#}"))?;
+            if !matches(&data) {
+                return Err(format!("sector {logical} doesn't match its checksum"));
+            }
+            return std::result::Result::Ok(data);
+        }
+    };
+    let mut readable = false;
+    for copy in copies {
+        let Some(data) = copy.data else {
+            continue;
+        };
+        readable = true;
+        if matches(&data) {
+            return std::result::Result::Ok(data);
+        }
+    }
+    Err(if readable {
+        format!("no copy of sector {logical} matches its checksum")
+    } else {
+        format!("no device holding sector {logical} is present")
+    })
+}
+
+/// the (file offset, item data) of each file extent item of an inode
+fn file_extents(fs: &FsInfo, tree_root: u64, inode: u64) -> Vec<(u64, ItemData)> {
+    let search = key_range(Some(inode), Some(BtrfsItemType::EXTENT_DATA), None);
+    search_range(fs, tree_root, search)
+        .filter(|(item, _, _, _)| item.key.item_type == BtrfsItemType::EXTENT_DATA)
+        .map(|(item, data, _, _)| (item.key.offset, data))
+        .collect()
+}
+
+const ZEROS: [u8; 4096] = [0; 4096];
+
+/// writes the data of a file in order, from its file extent items,
+/// recording what couldn't be read
+struct DataWriter<'a, W> {
+    fs: &'a FsInfo,
+    out: W,
+    salvage: bool,
+    /// the file's size, where extents running past it are cut off
+    size: u64,
+    /// the bytes written so far
+    pos: u64,
+    damage: DamagedFile,
+}
+
+impl<W: Write> DataWriter<'_, W> {
+    /// write zeros up to end, for a hole or what can't be read
+    fn zeros_to(&mut self, end: u64) -> Result<()> {
+        let end = end.min(self.size);
+        while self.pos < end {
+            let n = (end - self.pos).min(ZEROS.len() as u64);
+            self.out.write_all(&ZEROS[..n as usize])?;
+            self.pos += n;
+        }
+        Ok(())
+    }
+
+    /// write data which belongs at file offset at, less any part overlapping
+    /// what has been written already
+    fn put(&mut self, at: u64, data: &[u8]) -> Result<()> {
+        self.zeros_to(at)?;
+        let skip = (self.pos.max(at) - at).min(data.len() as u64) as usize;
+        let data = &data[skip..];
+        let n = data.len().min((self.size - self.pos) as usize);
+        self.out.write_all(&data[..n])?;
+        self.pos += n as u64;
+        Ok(())
+    }
+
+    fn lose(&mut self, start: u64, end: u64, reason: String) -> Result<()> {
+        ensure!(
+            self.salvage,
+            "{}: {reason}; --salvage restores the rest",
+            self.damage.path.display()
+        );
+        self.zeros_to(start)?;
+        let (start, end) = (start.max(self.pos), end.min(self.size));
+        if start >= end {
+            return Ok(());
+        }
+        match self.damage.lost.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => self.damage.lost.push((start, end)),
+        }
+        if !self.damage.reasons.contains(&reason) {
+            self.damage.reasons.push(reason);
+        }
+        self.zeros_to(end)
+    }
+
+    /// the file data from file_offset held by a file extent item
+    fn extent(&mut self, file_offset: u64, item: &[u8]) -> Result<()> {
+        let sectorsize = self.fs.master_sb.sectorsize as u64;
+        if item.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+            return Ok(());
+        }
+        let fe = unsafe { &*(item.as_ptr() as *const btrfs_file_extent_item) };
+        if fe.r#type == BTRFS_FILE_EXTENT_INLINE {
+            let ram_bytes = fe.ram_bytes;
+            let data = &item[BTRFS_FILE_EXTENT_INLINE_DATA_START..];
+            return match decompress(fe.compression, data, ram_bytes, sectorsize) {
+                Result::Ok(data) => self.put(file_offset, &data),
+                Err(e) => self.lose(file_offset, file_offset + ram_bytes, e.to_string()),
+            };
+        }
+        let Some(fe) = item_as::<btrfs_file_extent_item>(item) else {
+            return Ok(());
+        };
+        let (disk_bytenr, disk_num_bytes) = (fe.disk_bytenr, fe.disk_num_bytes);
+        let (offset, num_bytes) = (fe.offset, fe.num_bytes);
+        // holes and preallocated space read as zeros, which the next extent
+        // or the end of the file writes
+        if disk_bytenr == 0 || fe.r#type == BTRFS_FILE_EXTENT_PREALLOC {
+            return Ok(());
+        }
+        let file_end = file_offset + num_bytes;
+        if fe.compression != BTRFS_COMPRESS_NONE {
+            if disk_num_bytes > BTRFS_MAX_COMPRESSED {
+                let reason = format!(
+                    "compressed extent at {disk_bytenr} is {disk_num_bytes} bytes, more than one can be"
+                );
+                return self.lose(file_offset, file_end, reason);
+            }
+            // none of it can be decompressed without all of it
+            let mut stored = Vec::with_capacity(disk_num_bytes as usize);
+            for sector in (disk_bytenr..disk_bytenr + disk_num_bytes).step_by(sectorsize as usize) {
+                ensure!(!self.fs.cancel.is_cancelled(), "interrupted");
+                match read_sector(self.fs, sector) {
+                    std::result::Result::Ok(data) => stored.extend_from_slice(&data),
+                    Err(reason) => return self.lose(file_offset, file_end, reason),
+                }
+            }
+            return match decompress(fe.compression, &stored, fe.ram_bytes, sectorsize) {
+                Result::Ok(data) => {
+                    let start = (offset as usize).min(data.len());
+                    let end = (start + num_bytes as usize).min(data.len());
+                    self.put(file_offset, &data[start..end])
+                }
+                Err(e) => self.lose(file_offset, file_end, e.to_string()),
+            };
+        }
+        let (start, end) = (disk_bytenr + offset, disk_bytenr + offset + num_bytes);
+        let mut sector = start - (start - disk_bytenr) % sectorsize;
+        while sector < end && self.pos < self.size {
+            ensure!(!self.fs.cancel.is_cancelled(), "interrupted");
+            let (from, to) = (sector.max(start), (sector + sectorsize).min(end));
+            let at = file_offset + (from - start);
+            match read_sector(self.fs, sector) {
+                std::result::Result::Ok(data) => {
+                    self.put(at, &data[(from - sector) as usize..(to - sector) as usize])?
+                }
+                Err(reason) => self.lose(at, at + (to - from), reason)?,
+            }
+            sector += sectorsize;
+        }
+        Ok(())
+    }
+}
+
+/// write the data of a regular file to out, returning the ranges which
+/// couldn't be read (only with salvage; otherwise they are an error). path
+/// names the file in errors.
+pub(crate) fn write_file_data<W: Write>(
+    fs: &FsInfo,
+    tree_root: u64,
+    inode: u64,
+    size: u64,
+    out: W,
+    salvage: bool,
+    path: &Path,
+) -> Result<DamagedFile> {
+    let mut writer = DataWriter {
+        fs,
+        out,
+        salvage,
+        size,
+        pos: 0,
+        damage: DamagedFile {
+            path: path.to_path_buf(),
+            lost: Vec::new(),
+            reasons: Vec::new(),
+        },
+    };
+    for (file_offset, item) in file_extents(fs, tree_root, inode) {
+        writer.extent(file_offset, &item)?;
+    }
+    writer.zeros_to(size)?;
+    writer.out.flush()?;
+    Ok(writer.damage)
+}
+
+struct Restorer<'a> {
+    fs: &'a FsInfo,
+    tree_root: u64,
+    salvage: bool,
+    xattrs: bool,
+    verity: bool,
+    restore: Restore,
+    /// directories entered, against loops in a damaged tree
+    seen: HashSet<u64>,
+    /// what this platform can't restore, noted in skipped once each
+    #[cfg(not(target_os = "linux"))]
+    unsupported: HashSet<&'static str>,
+}
+
+impl Restorer<'_> {
+    fn skip(&mut self, path: &Path, reason: &str) -> Result<()> {
+        ensure!(self.salvage, "{}: {reason}", path.display());
+        self.restore
+            .skipped
+            .push(format!("{}: {reason}", path.display()));
+        Ok(())
+    }
+
+    /// note once per restore that something is never restored here
+    #[cfg(not(target_os = "linux"))]
+    fn unsupported(&mut self, what: &'static str) {
+        if self.unsupported.insert(what) {
+            self.restore
+                .skipped
+                .push(format!("{what} aren't restored on this platform"));
+        }
+    }
+
+    /// set the permission bits of mode on path
+    #[cfg(unix)]
+    fn set_mode(&mut self, path: &Path, mode: u32) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::Permissions::from_mode(mode & 0o7777);
+        std::fs::set_permissions(path, permissions)
+            .with_context(|| format!("cannot set the mode of {}", path.display()))
+    }
+
+    #[cfg(not(unix))]
+    fn set_mode(&mut self, _path: &Path, _mode: u32) -> Result<()> {
+        self.unsupported("permission bits");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn symlink(&mut self, target: &str, path: &Path) -> Result<()> {
+        std::os::unix::fs::symlink(target, path)
+            .with_context(|| format!("cannot create {}", path.display()))?;
+        self.restore.symlinks += 1;
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn symlink(&mut self, target: &str, path: &Path) -> Result<()> {
+        self.restore.skipped.push(format!(
+            "{}: symlink to {target:?}, which can't be created on this platform",
+            path.display()
+        ));
+        Ok(())
+    }
+
+    /// set the xattrs of an inode on the file restored at path; those which
+    /// can't be set (e.g. security ones without the privilege) are noted
+    #[cfg(target_os = "linux")]
+    fn restore_xattrs(&mut self, inode: u64, path: &Path) -> Result<()> {
+        use crate::items::parse_xattrs;
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let search = key_range(Some(inode), Some(BtrfsItemType::XATTR_ITEM), None);
+        for (item, data, _block_offset, _slot) in search_range(self.fs, self.tree_root, search) {
+            if item.key.item_type != BtrfsItemType::XATTR_ITEM {
+                continue;
+            }
+            for (name, value) in parse_xattrs(&data) {
+                let c_name = CString::new(name)?;
+                let set = unsafe {
+                    libc::lsetxattr(
+                        c_path.as_ptr(),
+                        c_name.as_ptr(),
+                        value.as_ptr() as *const libc::c_void,
+                        value.len(),
+                        0,
+                    )
+                };
+                if set != 0 {
+                    self.restore.skipped.push(format!(
+                        "{}: xattr {} not set: {}",
+                        path.display(),
+                        String::from_utf8_lossy(name),
+                        std::io::Error::last_os_error()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Linux xattr names don't carry over to other platforms' xattrs, if
+    /// they have them
+    #[cfg(not(target_os = "linux"))]
+    fn restore_xattrs(&mut self, _inode: u64, _path: &Path) -> Result<()> {
+        self.unsupported("xattrs");
+        Ok(())
+    }
+
+    /// enable fs-verity again on a restored file which had it, if asked to
+    /// and the data came back whole; otherwise note that it was stripped
+    fn restore_verity(&mut self, inode: u64, path: &Path, whole: bool) {
+        let why = match verity_descriptor(self.fs, self.tree_root, inode) {
+            Result::Ok(Some(desc)) if self.verity && whole => match enable_verity(path, &desc) {
+                std::result::Result::Ok(()) => return,
+                Err(e) => format!("cannot enable it: {e}"),
+            },
+            Result::Ok(Some(_)) if self.verity => String::from("data was lost"),
+            Result::Ok(Some(desc)) => format!(
+                "{} root hash {}",
+                fmt_hash_algorithm(desc.hash_algorithm),
+                hex::encode(&desc.root_hash)
+            ),
+            Result::Ok(None) => String::from("VERITY flag set, but no verity descriptor found"),
+            Err(e) => e.to_string(),
+        };
+        self.restore.verity_stripped.push((path.to_path_buf(), why));
+    }
+
+    fn restore_inode(&mut self, inode: u64, path: &Path) -> Result<()> {
+        ensure!(!self.fs.cancel.is_cancelled(), "interrupted");
+        let Some(inode_item) = inode_item(self.fs, self.tree_root, inode) else {
+            return self.skip(path, &format!("inode {inode} has no inode item"));
+        };
+        let mode = inode_item.mode;
+        let restored = match mode & S_IFMT {
+            S_IFDIR => {
+                if !self.seen.insert(inode) {
+                    return self.skip(path, "directory loop");
+                }
+                if !path.is_dir() {
+                    std::fs::create_dir(path)
+                        .with_context(|| format!("cannot create {}", path.display()))?;
+                }
+                self.restore.directories += 1;
+                for (name, location, _type) in dir_entries(self.fs, self.tree_root, inode) {
+                    let name = String::from_utf8_lossy(&name).into_owned();
+                    let child = path.join(&name);
+                    if location.item_type == BtrfsItemType::ROOT_ITEM {
+                        let subvol = location.objectid;
+                        self.restore.skipped.push(format!(
+                            "{}: subvolume {subvol}, to be restored by its id",
+                            child.display()
+                        ));
+                        continue;
+                    }
+                    self.restore_inode(location.objectid, &child)?;
+                }
+                self.set_mode(path, mode)?;
+                true
+            }
+            S_IFREG => {
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(path)
+                    .with_context(|| format!("cannot create {}", path.display()))?;
+                let size = inode_item.size;
+                let damage = write_file_data(
+                    self.fs,
+                    self.tree_root,
+                    inode,
+                    size,
+                    std::io::BufWriter::new(&file),
+                    self.salvage,
+                    path,
+                )?;
+                drop(file);
+                self.set_mode(path, mode)?;
+                self.restore.files += 1;
+                self.restore.bytes += size;
+                if inode_item.flags & BTRFS_INODE_RO_VERITY != 0 {
+                    self.restore_verity(inode, path, damage.lost.is_empty());
+                }
+                if !damage.lost.is_empty() {
+                    self.restore.damaged.push(damage);
+                }
+                true
+            }
+            S_IFLNK => {
+                let extents = file_extents(self.fs, self.tree_root, inode);
+                let target = extents.first().and_then(|(_, item)| {
+                    if item.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+                        return None;
+                    }
+                    let fe = unsafe { &*(item.as_ptr() as *const btrfs_file_extent_item) };
+                    (fe.r#type == BTRFS_FILE_EXTENT_INLINE)
+                        .then(|| &item[BTRFS_FILE_EXTENT_INLINE_DATA_START..])
+                });
+                let Some(target) = target else {
+                    return self.skip(path, "symlink without an inline target");
+                };
+                let target = String::from_utf8_lossy(target).into_owned();
+                self.symlink(&target, path)?;
+                cfg!(unix)
+            }
+            _ => {
+                self.restore.skipped.push(format!(
+                    "{}: not a file, directory or symlink (mode {mode:o})",
+                    path.display()
+                ));
+                false
+            }
+        };
+        if restored && self.xattrs {
+            self.restore_xattrs(inode, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// restore the file or directory at path (relative to the top of the
+/// subvolume, "" for all of it) into options.output_dir
+pub fn restore(fs: &FsInfo, subvol: u64, path: &str, options: &RestoreOptions) -> Result<Restore> {
+    let tree_root =
+        tree_root_offset(fs, subvol).ok_or_else(|| anyhow!("subvolume {subvol} not found"))?;
+    let inode = lookup_path(fs, tree_root, path)?;
+    let dir = &options.output_dir;
+    std::fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    let target = match path.rsplit('/').find(|c| !c.is_empty()) {
+        Some(name) => dir.join(name),
+        None => dir.clone(),
+    };
+    let mut restorer = Restorer {
+        fs,
+        tree_root,
+        salvage: options.salvage,
+        xattrs: options.xattrs,
+        verity: options.verity,
+        restore: Restore::default(),
+        seen: HashSet::new(),
+        #[cfg(not(target_os = "linux"))]
+        unsupported: HashSet::new(),
+    };
+    restorer.restore_inode(inode, &target)?;
+    Ok(restorer.restore)
+}
+
+/// write the contents of the regular file at path in a subvolume to out, as
+/// restore would write it, returning the ranges lost with salvage
+pub fn cat<W: Write>(
+    fs: &FsInfo,
+    subvol: u64,
+    path: &str,
+    out: W,
+    salvage: bool,
+) -> Result<DamagedFile> {
+    let tree_root =
+        tree_root_offset(fs, subvol).ok_or_else(|| anyhow!("subvolume {subvol} not found"))?;
+    let inode = lookup_path(fs, tree_root, path)?;
+    let inode_item = inode_item(fs, tree_root, inode)
+        .ok_or_else(|| anyhow!("{path}: inode {inode} has no inode item"))?;
+    let mode = inode_item.mode;
+    ensure!(
+        mode & S_IFMT == S_IFREG,
+        "{path} is not a regular file (mode {mode:o})"
+    );
+    write_file_data(
+        fs,
+        tree_root,
+        inode,
+        inode_item.size,
+        out,
+        salvage,
+        Path::new(path),
+    )
+}
@@ -0,0 +1,728 @@
+//! Offline scrub: verification of every copy of every reachable tree block,
+//! and of file data against the csum tree, for filesystems which can no
+//! longer be mounted to run a kernel scrub.
+
+use crate::address::*;
+use crate::backref::find_extent;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::items::item_as;
+use crate::mapped_file::Advice;
+use crate::parallel::*;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::mpsc::{channel, sync_channel};
+use std::sync::Mutex;
+
+/// problems found with one copy of a tree block, empty if it is good.
+/// expected_level is the level the parent pointer implies, if known.
+pub fn check_tree_block(
+    fs: &FsInfo,
+    block: &[u8],
+    logical: u64,
+    expected_level: Option<u8>,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    if block.len() < std::mem::size_of::<btrfs_header>() {
+        problems.push(format!("block is only {} bytes", block.len()));
+        return problems;
+    }
+    let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+    if header.csum != csum_data(&block[BTRFS_CSUM_SIZE..], fs.master_sb.csum_type) {
+        problems.push(String::from("checksum mismatch"));
+    }
+    let bytenr = header.bytenr;
+    if bytenr != logical {
+        problems.push(format!("header bytenr is {bytenr}"));
+    }
+    if !fs.is_tree_block_fsid(&{ header.fsid }) {
+        problems.push(format!("header fsid is {}", header.fsid));
+    }
+    let level = header.level;
+    if level >= BTRFS_MAX_LEVEL {
+        problems.push(format!("level {level} is impossible"));
+    } else if let Some(expected) = expected_level {
+        if level != expected {
+            problems.push(format!("level {level}, the parent expects {expected}"));
+        }
+    }
+    let nritems = header.nritems;
+    if node_entries(block).len() != nritems as usize {
+        problems.push(format!("nritems {nritems} overflows the block"));
+    }
+    problems
+}
+
+pub struct CopyStatus {
+    pub devid: u64,
+    pub physical: u64,
+    /// empty if the copy is good
+    pub problems: Vec<String>,
+}
+
+pub struct DamagedBlock {
+    pub logical: u64,
+    /// name of the tree the block was reached from, as in list_trees
+    pub tree: String,
+    pub copies: Vec<CopyStatus>,
+    /// whether any copy was good; if not the block's children weren't checked
+    pub recoverable: bool,
+}
+
+#[derive(Default)]
+pub struct MetadataScrub {
+    pub blocks: u64,
+    pub copies: u64,
+    pub damaged: Vec<DamagedBlock>,
+}
+
+/// visit every tree block reachable from the superblock and root tree once,
+/// reading all of its copies. Blocks shared between trees (e.g. by
+/// snapshots) are visited once, from the first tree reaching them. visit is
+/// given the tree name, the block's logical address, the level its parent
+/// implies and the copies (or the error finding them), and returns the copy
+/// to continue the walk through, if any.
+fn walk_tree_blocks(
+    fs: &FsInfo,
+    mut visit: impl FnMut(&str, u64, Option<u8>, Result<Vec<BlockCopy>>) -> Option<Vec<u8>>,
+) {
+    let nodesize = fs.master_sb.nodesize as u64;
+    let mut seen = HashSet::new();
+    for (tree, root) in list_trees(fs) {
+        let mut stack = vec![(root, None)];
+        while let Some((logical, expected_level)) = stack.pop() {
+            if fs.cancel.is_cancelled() {
+                return;
+            }
+            if !seen.insert(logical) {
+                continue;
+            }
+            let copies = block_copies(fs, logical, nodesize);
+            let Some(block) = visit(&tree, logical, expected_level, copies) else {
+                continue;
+            };
+            let level = unsafe { &*(block.as_ptr() as *const btrfs_header) }.level;
+            for entry in node_entries(&block).into_iter().rev() {
+                if let NodeEntry::Ptr(ptr) = entry {
+                    let child = ptr.blockptr;
+                    stack.push((child, level.checked_sub(1)));
+                }
+            }
+        }
+    }
+}
+
+/// the copies checked, the damage found, and the good copy to continue the
+/// walk through, if any, for one block of the metadata scrub
+fn scrub_block(
+    fs: &FsInfo,
+    tree: &str,
+    logical: u64,
+    expected_level: Option<u8>,
+    copies: Result<Vec<BlockCopy>>,
+) -> (u64, Option<DamagedBlock>, Option<Vec<u8>>) {
+    let copies = match copies {
+        Result::Ok(copies) => copies,
+        Err(e) => {
+            let damaged = DamagedBlock {
+                logical,
+                tree: tree.to_string(),
+                copies: vec![CopyStatus {
+                    devid: 0,
+                    physical: 0,
+                    problems: vec![e.to_string()],
+                }],
+                recoverable: false,
+            };
+            return (0, Some(damaged), None);
+        }
+    };
+    let checked = copies.len() as u64;
+    let mut good = None;
+    let mut statuses = Vec::new();
+    for copy in copies {
+        let problems = match &copy.data {
+            Some(block) => check_tree_block(fs, block, logical, expected_level),
+            None => vec![String::from("device missing")],
+        };
+        if problems.is_empty() && good.is_none() {
+            good = copy.data;
+        }
+        statuses.push(CopyStatus {
+            devid: copy.devid,
+            physical: copy.physical,
+            problems,
+        });
+    }
+    let damaged = statuses
+        .iter()
+        .any(|s| !s.problems.is_empty())
+        .then(|| DamagedBlock {
+            logical,
+            tree: tree.to_string(),
+            copies: statuses,
+            recoverable: good.is_some(),
+        });
+    (checked, damaged, good)
+}
+
+/// check every copy of every tree block reachable from the superblock and
+/// root tree. The walk continues below a block through any good copy of it.
+pub fn scrub_metadata(fs: &FsInfo) -> MetadataScrub {
+    let mut scrub = MetadataScrub::default();
+    walk_tree_blocks(fs, |tree, logical, expected_level, copies| {
+        let (copies, damaged, good) = scrub_block(fs, tree, logical, expected_level, copies);
+        scrub.blocks += 1;
+        scrub.copies += copies;
+        scrub.damaged.extend(damaged);
+        good
+    });
+    scrub
+}
+
+/// scrub_metadata with the blocks of each level of a tree checked on jobs
+/// threads (0 for one per CPU). The same blocks are found damaged, though
+/// not in the same order. Only with jobs 1 is the walk sequential.
+pub fn scrub_metadata_parallel(fs: &FsInfo, jobs: usize) -> Result<MetadataScrub> {
+    if jobs == 1 {
+        return Ok(scrub_metadata(fs));
+    }
+    let nodesize = fs.master_sb.nodesize as u64;
+    let roots: Vec<_> = list_trees(fs)
+        .into_iter()
+        .map(|(tree, root)| (tree, root, None))
+        .collect();
+    let mut scrub = MetadataScrub::default();
+    walk_levels(
+        fs,
+        jobs,
+        &roots,
+        |tree, logical, expected_level| {
+            let copies = block_copies(fs, logical, nodesize);
+            let (copies, damaged, good) = scrub_block(fs, tree, logical, expected_level, copies);
+            (
+                (copies, damaged),
+                good.as_deref().map(child_pointers).unwrap_or_default(),
+            )
+        },
+        |_, _, (copies, damaged)| {
+            scrub.blocks += 1;
+            scrub.copies += copies;
+            scrub.damaged.extend(damaged);
+        },
+        |_, _, _| {},
+    )?;
+    Ok(scrub)
+}
+
+/// one copy of a block whose copies are not identical
+pub struct MirrorCopy {
+    pub devid: u64,
+    pub physical: u64,
+    pub csum_ok: bool,
+    pub generation: u64,
+    /// bytes differing from the first copy, and the number of runs they form
+    pub differing_bytes: usize,
+    pub differing_runs: usize,
+    /// offsets within the block of the first and last differing byte
+    pub first_difference: Option<usize>,
+    pub last_difference: Option<usize>,
+}
+
+pub struct Divergence {
+    pub logical: u64,
+    pub tree: String,
+    pub copies: Vec<MirrorCopy>,
+}
+
+fn mirror_copy(fs: &FsInfo, copy: &BlockCopy, reference: &[u8]) -> Option<MirrorCopy> {
+    let block = copy.data.as_deref()?;
+    let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+    let mut differing_bytes = 0;
+    let mut differing_runs = 0;
+    let mut first_difference = None;
+    let mut last_difference = None;
+    let mut in_run = false;
+    for (i, (a, b)) in block.iter().zip(reference).enumerate() {
+        if a != b {
+            differing_bytes += 1;
+            if !in_run {
+                differing_runs += 1;
+            }
+            first_difference.get_or_insert(i);
+            last_difference = Some(i);
+        }
+        in_run = a != b;
+    }
+    Some(MirrorCopy {
+        devid: copy.devid,
+        physical: copy.physical,
+        csum_ok: header.csum == csum_data(&block[BTRFS_CSUM_SIZE..], fs.master_sb.csum_type),
+        generation: header.generation,
+        differing_bytes,
+        differing_runs,
+        first_difference,
+        last_difference,
+    })
+}
+
+/// every tree block whose copies on a DUP/RAID1* chunk are not byte for byte
+/// identical. The first readable copy is the reference the others are
+/// compared to; the walk continues through a copy with a valid checksum.
+pub fn mirror_divergence(fs: &FsInfo) -> Vec<Divergence> {
+    let mut divergent = Vec::new();
+    walk_tree_blocks(fs, |tree, logical, expected_level, copies| {
+        let copies = copies.ok()?;
+        let good = copies
+            .iter()
+            .filter_map(|c| c.data.as_deref())
+            .find(|block| check_tree_block(fs, block, logical, expected_level).is_empty())
+            .map(<[u8]>::to_vec);
+        let reference = copies.iter().find_map(|c| c.data.as_deref())?;
+        if copies
+            .iter()
+            .filter_map(|c| c.data.as_deref())
+            .any(|block| block != reference)
+        {
+            divergent.push(Divergence {
+                logical,
+                tree: tree.to_string(),
+                copies: copies
+                    .iter()
+                    .filter_map(|c| mirror_copy(fs, c, reference))
+                    .collect(),
+            });
+        }
+        good
+    });
+    divergent
+}
+
+/// a run of sectors on one copy whose data doesn't match the csum tree
+pub struct BadData {
+    pub logical: u64,
+    pub length: u64,
+    pub devid: u64,
+    pub physical: u64,
+    /// start of the data extent containing the sectors, if allocated
+    pub extent: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct DataScrub {
+    /// sectors with a checksum that were checked
+    pub sectors: u64,
+    /// sector copies that were checked
+    pub copies: u64,
+    pub bad: Vec<BadData>,
+    /// sectors that couldn't be checked, e.g. a missing device or striped chunk
+    pub errors: Vec<String>,
+}
+
+/// the checksum of the data sector at logical from the csum tree, if it has one
+pub fn data_csum(fs: &FsInfo, logical: u64) -> Result<Option<Vec<u8>>> {
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let csum_size = csum_size(fs.master_sb.csum_type);
+    let csum_root = global_root_for(fs, BTRFS_CSUM_TREE_OBJECTID, logical)
+        .ok_or_else(|| anyhow!("csum tree not found"))?;
+    let key = btrfs_disk_key {
+        objectid: BTRFS_EXTENT_CSUM_OBJECTID,
+        item_type: BtrfsItemType::EXTENT_CSUM,
+        offset: logical,
+    };
+    // the item at or left of the key is the csum item that could cover
+    // logical
+    let search = SearchKey::floor(key);
+    let Some((item, data, _, _)) = BtrfsTreeIter::new(fs, csum_root, search).next() else {
+        return Ok(None);
+    };
+    let start = item.key.offset;
+    if item.key.objectid != BTRFS_EXTENT_CSUM_OBJECTID
+        || item.key.item_type != BtrfsItemType::EXTENT_CSUM
+        || logical < start
+    {
+        return Ok(None);
+    }
+    let index = ((logical - start) / sectorsize) as usize;
+    Ok(data
+        .get(index * csum_size..(index + 1) * csum_size)
+        .map(|csum| csum.to_vec()))
+}
+
+/// start and end of every data extent referenced from the given subvolumes,
+/// sorted and merged
+fn subvol_extents(fs: &FsInfo, subvols: &[u64]) -> Result<Vec<(u64, u64)>> {
+    let mut ranges = Vec::new();
+    for subvol in subvols {
+        let root =
+            tree_root_offset(fs, *subvol).ok_or_else(|| anyhow!("subvolume {subvol} not found"))?;
+        let search = key_range(None, Some(BtrfsItemType::EXTENT_DATA), None);
+        for (item, data, _block_offset, _slot) in search_range(fs, root, search) {
+            if item.key.item_type != BtrfsItemType::EXTENT_DATA {
+                continue;
+            }
+            let Some(fe) = item_as::<btrfs_file_extent_item>(&data) else {
+                continue;
+            };
+            let disk_bytenr = fe.disk_bytenr;
+            if fe.r#type != BTRFS_FILE_EXTENT_INLINE && disk_bytenr != 0 {
+                ranges.push((disk_bytenr, disk_bytenr + fe.disk_num_bytes));
+            }
+        }
+    }
+    Ok(merge_ranges(ranges))
+}
+
+pub(crate) fn merge_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// start and end of the data the kernel keeps checksums for, sorted and
+/// merged: regular extents referenced from any subvolume or the data
+/// relocation tree by inodes without NODATASUM. Compressed extents are
+/// checksummed whole, others only the part referenced. Preallocated extents
+/// have no checksums until written.
+pub fn datasum_ranges(fs: &FsInfo) -> Result<Vec<(u64, u64)>> {
+    let mut roots: Vec<u64> = fs_trees(fs).into_iter().map(|(_, root)| root).collect();
+    roots.extend(tree_root_offset(fs, BTRFS_DATA_RELOC_TREE_OBJECTID));
+    let mut ranges = Vec::new();
+    for root in roots {
+        // inode items sort before the file extents of the same inode
+        let mut nodatasum = None;
+        for (item, data, _block_offset, _slot) in
+            search_range(fs, root, key_range(None, None, None))
+        {
+            let objectid = item.key.objectid;
+            match item.key.item_type {
+                BtrfsItemType::INODE_ITEM => {
+                    nodatasum = item_as::<btrfs_inode_item>(&data)
+                        .map(|inode| (objectid, inode.flags & BTRFS_INODE_NODATASUM != 0));
+                }
+                BtrfsItemType::EXTENT_DATA => {
+                    if nodatasum.is_some_and(|(inode, nodatasum)| inode == objectid && nodatasum) {
+                        continue;
+                    }
+                    let Some(fe) = item_as::<btrfs_file_extent_item>(&data) else {
+                        continue;
+                    };
+                    let disk_bytenr = fe.disk_bytenr;
+                    if fe.r#type != BTRFS_FILE_EXTENT_REG || disk_bytenr == 0 {
+                        continue;
+                    }
+                    if fe.compression != 0 {
+                        ranges.push((disk_bytenr, disk_bytenr + fe.disk_num_bytes));
+                    } else {
+                        let start = disk_bytenr + fe.offset;
+                        ranges.push((start, start + fe.num_bytes));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(merge_ranges(ranges))
+}
+
+/// the sectors with a checksum in the csum tree, or with subvols only those
+/// of data referenced by the subvolumes: (logical, expected checksum) of the
+/// sectors of each csum item in turn
+fn data_sectors<'a>(
+    fs: &'a FsInfo,
+    subvols: Option<&[u64]>,
+) -> Result<impl Iterator<Item = Vec<(u64, Vec<u8>)>> + 'a> {
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let csum_size = csum_size(fs.master_sb.csum_type);
+    let csum_roots = global_roots(fs, BTRFS_CSUM_TREE_OBJECTID);
+    ensure!(!csum_roots.is_empty(), "csum tree not found");
+    let selected = match subvols {
+        Some(subvols) => Some(subvol_extents(fs, subvols)?),
+        None => None,
+    };
+    let wanted = move |logical: u64| match &selected {
+        None => true,
+        Some(ranges) => {
+            let i = ranges.partition_point(|(_, end)| *end <= logical);
+            ranges.get(i).is_some_and(|(start, _)| *start <= logical)
+        }
+    };
+    let search = key_range(
+        Some(BTRFS_EXTENT_CSUM_OBJECTID),
+        Some(BtrfsItemType::EXTENT_CSUM),
+        None,
+    );
+    Ok(csum_roots
+        .into_iter()
+        .flat_map(move |(_, csum_root)| search_range(fs, csum_root, search))
+        .map(move |(item, data, _block_offset, _slot)| {
+            let start = item.key.offset;
+            data.chunks_exact(csum_size)
+                .enumerate()
+                .map(|(i, expected)| (start + i as u64 * sectorsize, expected.to_vec()))
+                .filter(|&(logical, _)| wanted(logical))
+                .collect()
+        }))
+}
+
+/// what the data scrub found for one sector
+struct SectorCheck {
+    logical: u64,
+    copies: u64,
+    /// (devid, physical) of each copy which doesn't match its checksum
+    bad: Vec<(u64, u64)>,
+    errors: Vec<String>,
+}
+
+fn check_sector(
+    fs: &FsInfo,
+    logical: u64,
+    expected: &[u8],
+    copies: Result<Vec<BlockCopy>>,
+) -> SectorCheck {
+    let csum_type = fs.master_sb.csum_type;
+    let mut check = SectorCheck {
+        logical,
+        copies: 0,
+        bad: Vec::new(),
+        errors: Vec::new(),
+    };
+    let copies = match copies {
+        Result::Ok(copies) => copies,
+        Err(e) => {
+            check.errors.push(format!("sector {logical}: {e}"));
+            return check;
+        }
+    };
+    for copy in copies {
+        let Some(sector) = copy.data else {
+            check
+                .errors
+                .push(format!("sector {logical}: devid {} is missing", copy.devid));
+            continue;
+        };
+        check.copies += 1;
+        if &csum_data(&sector, csum_type)[..expected.len()] != expected {
+            check.bad.push((copy.devid, copy.physical));
+        }
+    }
+    check
+}
+
+/// add a sector's check to the scrub, in logical order
+fn record_sector(scrub: &mut DataScrub, sectorsize: u64, check: SectorCheck) {
+    let logical = check.logical;
+    scrub.sectors += 1;
+    scrub.copies += check.copies;
+    scrub.errors.extend(check.errors);
+    for (devid, physical) in check.bad {
+        // extend the previous run if this sector continues it
+        if let Some(last) = scrub.bad.last_mut() {
+            if last.devid == devid
+                && last.logical + last.length == logical
+                && last.physical + last.length == physical
+            {
+                last.length += sectorsize;
+                continue;
+            }
+        }
+        scrub.bad.push(BadData {
+            logical,
+            length: sectorsize,
+            devid,
+            physical,
+            extent: None,
+        });
+    }
+}
+
+fn find_bad_extents(fs: &FsInfo, scrub: &mut DataScrub) -> Result<()> {
+    for bad in scrub.bad.iter_mut() {
+        bad.extent = find_extent(fs, bad.logical)?.map(|e| e.start);
+    }
+    Ok(())
+}
+
+/// verify every copy of every data sector with a checksum in the csum tree,
+/// or with subvols only the data referenced by those subvolumes
+pub fn scrub_data(fs: &FsInfo, subvols: Option<&[u64]>) -> Result<DataScrub> {
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let mut scrub = DataScrub::default();
+    for sectors in data_sectors(fs, subvols)? {
+        for (logical, expected) in sectors {
+            let copies = block_copies(fs, logical, sectorsize);
+            record_sector(
+                &mut scrub,
+                sectorsize,
+                check_sector(fs, logical, &expected, copies),
+            );
+        }
+    }
+    find_bad_extents(fs, &mut scrub)?;
+    Ok(scrub)
+}
+
+/// scrub_data as a pipeline: the csum tree is walked on this thread, readers
+/// threads read every copy of the sectors of each csum item (with pread,
+/// after advising the kernel to read the item's range ahead), jobs threads
+/// (0 for one per CPU) check them against their checksums, and the results
+/// are put back in order. Only with jobs 1 is the scrub sequential.
+pub fn scrub_data_parallel(
+    fs: &FsInfo,
+    subvols: Option<&[u64]>,
+    jobs: usize,
+    readers: usize,
+) -> Result<DataScrub> {
+    if jobs == 1 {
+        return scrub_data(fs, subvols);
+    }
+    let jobs = match jobs {
+        0 => std::thread::available_parallelism()?.get(),
+        jobs => jobs,
+    };
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    type Sectors = Vec<(u64, Vec<u8>)>;
+    type Read = Vec<(u64, Vec<u8>, Result<Vec<BlockCopy>>)>;
+    let (to_read, reading) = sync_channel::<(usize, Sectors)>(readers.max(1) * 2);
+    let (to_check, checking) = sync_channel::<(usize, Read)>(jobs * 2);
+    let (to_merge, merging) = channel::<(usize, Vec<SectorCheck>)>();
+    let (reading, checking) = (&Mutex::new(reading), &Mutex::new(checking));
+    let sectors = data_sectors(fs, subvols)?;
+    let mut scrub = std::thread::scope(|scope| {
+        for _ in 0..readers.max(1) {
+            let to_check = to_check.clone();
+            scope.spawn(move || {
+                while let Result::Ok((seq, sectors)) = reading.lock().unwrap().recv() {
+                    if let (Some(first), Some(last)) = (sectors.first(), sectors.last()) {
+                        let length = last.0 + sectorsize - first.0;
+                        advise_logical(fs, first.0, length, Advice::WillNeed, false);
+                    }
+                    let read = sectors
+                        .into_iter()
+                        .map(|(logical, expected)| {
+                            (logical, expected, block_copies(fs, logical, sectorsize))
+                        })
+                        .collect();
+                    if to_check.send((seq, read)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(to_check);
+        for _ in 0..jobs {
+            let to_merge = to_merge.clone();
+            scope.spawn(move || {
+                while let Result::Ok((seq, read)) = checking.lock().unwrap().recv() {
+                    let checks = read
+                        .into_iter()
+                        .map(|(logical, expected, copies)| {
+                            check_sector(fs, logical, &expected, copies)
+                        })
+                        .collect();
+                    if to_merge.send((seq, checks)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(to_merge);
+        let merger = scope.spawn(move || {
+            let mut scrub = DataScrub::default();
+            let mut waiting = BTreeMap::new();
+            let mut next = 0;
+            for (seq, checks) in merging {
+                waiting.insert(seq, checks);
+                while let Some(checks) = waiting.remove(&next) {
+                    for check in checks {
+                        record_sector(&mut scrub, sectorsize, check);
+                    }
+                    next += 1;
+                }
+            }
+            scrub
+        });
+        for (seq, sectors) in sectors.enumerate() {
+            if to_read.send((seq, sectors)).is_err() {
+                break;
+            }
+        }
+        drop(to_read);
+        merger.join().unwrap()
+    });
+    find_bad_extents(fs, &mut scrub)?;
+    Ok(scrub)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::tests::{two_level_tree, NODESIZE};
+
+    #[test]
+    fn tree_block_problems() {
+        let fs = two_level_tree("tree_block_problems");
+        let logical = NODESIZE as u64;
+        let good = load_virt_block(&fs, logical).unwrap().to_vec();
+        assert!(check_tree_block(&fs, &good, logical, Some(0)).is_empty());
+        let header =
+            |block: &mut Vec<u8>| unsafe { &mut *(block.as_mut_ptr() as *mut btrfs_header) };
+        let check = |edit: &dyn Fn(&mut Vec<u8>), seal: bool, expected_level| {
+            let mut block = good.clone();
+            edit(&mut block);
+            if seal {
+                seal_tree_block(&mut block, BtrfsCsumType::CRC32);
+            }
+            check_tree_block(&fs, &block, logical, expected_level)
+        };
+
+        assert_eq!(
+            check(&|block| block[NODESIZE - 1] ^= 1, false, None),
+            ["checksum mismatch"]
+        );
+        // a flipped generation is only caught by the checksum
+        assert_eq!(
+            check(&|block| header(block).generation ^= 1 << 40, false, None),
+            ["checksum mismatch"]
+        );
+        assert!(check(&|block| header(block).generation = 7, true, None).is_empty());
+        assert_eq!(
+            check(&|block| header(block).bytenr = 0, true, None),
+            ["header bytenr is 0"]
+        );
+        let fsid = BtrfsUuid([7; 16]);
+        assert_eq!(
+            check(&|block| header(block).fsid = fsid, true, None),
+            [format!("header fsid is {fsid}")]
+        );
+        assert_eq!(
+            check(&|block| header(block).level = BTRFS_MAX_LEVEL, true, None),
+            [format!("level {BTRFS_MAX_LEVEL} is impossible")]
+        );
+        assert_eq!(
+            check(&|_| {}, true, Some(1)),
+            ["level 0, the parent expects 1"]
+        );
+        assert_eq!(
+            check(&|block| header(block).nritems = 1000, true, None),
+            ["nritems 1000 overflows the block"]
+        );
+        assert_eq!(
+            check(&|block| header(block).bytenr = 0, false, None),
+            ["checksum mismatch", "header bytenr is 0"]
+        );
+        assert_eq!(
+            check_tree_block(&fs, &good[..16], logical, None),
+            ["block is only 16 bytes"]
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Line based query shell.
+//!
+//! Keeps the filesystem (and so the chunk cache) loaded between queries, which
+//! makes poking at a large filesystem much quicker than rerunning the tool for
+//! every lookup. Commands are read from stdin, so a script of queries can also
+//! be piped in.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::dump::*;
+use crate::flags::fmt_block_group_type;
+use crate::inspect::*;
+use crate::parse::*;
+use crate::structures::*;
+use crate::tree::*;
+use crate::units::fmt_size;
+
+use anyhow::*;
+use std::io::{BufRead, IsTerminal, Write};
+
+const HELP: &str = "\
+commands:
+  super                       dump the superblock
+  trees                       list the trees reachable from the root tree
+  tree <tree> [<min> [<max>]] dump the items in a tree, optionally only those
+                              between keys given as objectid,type,offset
+  df                          space allocated and used per block group profile
+  du                          data referenced by each subvolume
+  scrub                       verify every copy of every tree block
+  scrub data [<subvol>...]    verify every copy of data against the csum tree
+  scrub parity                verify the parity of RAID5/6 full stripes
+  check                       check every tree block against the tree-checker rules
+  census                      blocks of each tree reached, against the extent tree
+  orphans                     tree blocks which verify but are no longer reachable
+  mirrors                     list tree blocks whose mirrored copies differ
+  stats <tree>                node counts, leaf fill and item types of a tree
+  use <tree>                  set the tree searched by key (default FS_TREE)
+  key <oid> [<type> [<off>]]  show items matching a key in the current tree
+  block <bytenr>              dump a metadata block
+  block annotate <bytenr>     hexdump a metadata block with its structures marked
+  resolve <logical>           map a logical address to device offsets
+  inspect <logical>           report the chunk, extent, owning tree and files at an address
+  inspect <devid> <physical>  the same, starting from a device offset
+  inode show <subvol> <ino>   show every item of an inode and how much data is reachable
+  find-name <pattern>         find names in every fs tree (* and ? wildcards,
+                              otherwise substring match)
+  help                        show this message
+  quit                        leave the shell
+numbers may be decimal or 0x hex, trees may be given by name or id";
+
+struct Shell<'a> {
+    fs: &'a FsInfo,
+    tree: u64,
+}
+
+impl<'a> Shell<'a> {
+    fn tree_root(&self, tree: u64) -> Result<u64> {
+        tree_root_offset(self.fs, tree)
+            .ok_or_else(|| anyhow!("tree {} not found", fmt_treeid(tree)))
+    }
+
+    fn run_command(&mut self, words: &[&str]) -> Result<bool> {
+        match words {
+            [] => {}
+            ["help"] | ["?"] => println!("{HELP}"),
+            ["quit"] | ["exit"] => return Ok(false),
+            ["super"] => dump_sb(&self.fs.master_sb),
+            ["trees"] => {
+                for (name, bytenr) in list_trees(self.fs) {
+                    println!("{name} @ {bytenr}");
+                }
+            }
+            ["tree", tree, range @ ..] if range.len() <= 2 => {
+                let min_key = match range.first() {
+                    Some(key) => parse_key_str(key)?,
+                    None => key_range(None, None, None).min_key,
+                };
+                let max_key = match range.get(1) {
+                    Some(key) => parse_key_str(key)?,
+                    None => key_range(None, None, None).max_key,
+                };
+                dump_tree_range(self.fs, parse_treeid(tree)?, min_key, max_key)?;
+            }
+            ["df"] => dump_df(self.fs)?,
+            ["du"] => dump_du(self.fs, true)?,
+            ["scrub"] => dump_scrub_metadata(self.fs, 1)?,
+            ["scrub", "data"] => dump_scrub_data(self.fs, None, 1, 1)?,
+            ["scrub", "data", subvols @ ..] => {
+                let subvols = subvols
+                    .iter()
+                    .map(|s| parse_treeid(s))
+                    .collect::<Result<Vec<u64>>>()?;
+                dump_scrub_data(self.fs, Some(&subvols), 1, 1)?
+            }
+            ["scrub", "parity"] => dump_scrub_parity(self.fs)?,
+            ["check"] => dump_check(self.fs),
+            ["census"] => dump_census(self.fs, 1)?,
+            ["orphans"] => dump_orphans(self.fs),
+            ["mirrors"] => dump_mirror_divergence(self.fs),
+            ["stats", tree] => dump_tree_stats(self.fs, parse_treeid(tree)?)?,
+            ["use", tree] => {
+                let tree = parse_treeid(tree)?;
+                self.tree_root(tree)?;
+                self.tree = tree;
+                println!("using {}", fmt_treeid(tree));
+            }
+            ["key", objectid, rest @ ..] if rest.len() <= 2 => {
+                let objectid = parse_treeid(objectid)?;
+                let item_type = match rest.first() {
+                    Some(t) => Some(t.parse::<BtrfsItemType>()?),
+                    None => None,
+                };
+                let offset = match rest.get(1) {
+                    Some(o) => Some(parse_u64(o)?),
+                    None => None,
+                };
+                let root = self.tree_root(self.tree)?;
+                dump_tree_items(self.fs, root, key_range(Some(objectid), item_type, offset));
+            }
+            ["block", "annotate", bytenr] => dump_block_annotated(self.fs, parse_u64(bytenr)?)?,
+            ["block", bytenr] => dump_block(self.fs, parse_u64(bytenr)?)?,
+            ["resolve", logical] => {
+                let logical = parse_u64(logical)?;
+                let chunk = find_chunk(self.fs, logical)
+                    .ok_or_else(|| anyhow!("{logical} is not within any chunk"))?;
+                let start = chunk.0.offset;
+                let length = chunk.1.length;
+                let chunk_type = chunk.1.r#type;
+                println!(
+                    "chunk {start} length {} type {}",
+                    fmt_size(length),
+                    fmt_block_group_type(chunk_type)
+                );
+                for (offset, path) in virtual_offset_to_physical(self.fs, logical)? {
+                    println!("{} offset {offset}", path.display());
+                }
+            }
+            ["inspect", logical] => inspect_logical(self.fs, parse_u64(logical)?)?,
+            ["inspect", devid, physical] => {
+                inspect_physical(self.fs, parse_u64(devid)?, parse_u64(physical)?)?
+            }
+            ["inode", "show", subvol, inode] => {
+                dump_inode(self.fs, parse_treeid(subvol)?, parse_u64(inode)?)?
+            }
+            ["find-name", pattern] => dump_find_name(self.fs, pattern),
+            _ => bail!("unrecognised command, try help"),
+        }
+        Ok(true)
+    }
+}
+
+/// read commands from stdin until quit or end of input
+pub fn shell(fs: &FsInfo) -> Result<()> {
+    let mut shell = Shell {
+        fs,
+        tree: BTRFS_FS_TREE_OBJECTID,
+    };
+    let interactive = std::io::stdin().is_terminal();
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("btrfs> ");
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match shell.run_command(&words) {
+            Result::Ok(true) => {}
+            Result::Ok(false) => break,
+            Err(e) => println!("error: {e}"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,315 @@
+//! Space accounting: what is allocated to each block group type and profile
+//! and how much of it is used, and which subvolumes the used data belongs to.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::items::item_as;
+use crate::stats::tree_stats;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+pub(crate) const PROFILE_MASK: u64 = BTRFS_BLOCK_GROUP_RAID0
+    | BTRFS_BLOCK_GROUP_RAID1
+    | BTRFS_BLOCK_GROUP_DUP
+    | BTRFS_BLOCK_GROUP_RAID10
+    | BTRFS_BLOCK_GROUP_RAID5
+    | BTRFS_BLOCK_GROUP_RAID6
+    | BTRFS_BLOCK_GROUP_RAID1C3
+    | BTRFS_BLOCK_GROUP_RAID1C4;
+
+pub struct BlockGroup {
+    pub start: u64,
+    pub length: u64,
+    pub used: u64,
+    pub flags: u64,
+}
+
+/// every BLOCK_GROUP_ITEM, from the block group tree if the filesystem has
+/// one and the extent tree otherwise
+pub fn block_groups(fs: &FsInfo) -> Result<Vec<BlockGroup>> {
+    let compat_ro = fs.master_sb.compat_ro_flags;
+    let tree = if compat_ro & BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE != 0 {
+        BTRFS_BLOCK_GROUP_TREE_OBJECTID
+    } else {
+        BTRFS_EXTENT_TREE_OBJECTID
+    };
+    let root = tree_root_offset(fs, tree).ok_or_else(|| anyhow!("block group tree not found"))?;
+    let mut groups = Vec::new();
+    let search = key_range(None, Some(BtrfsItemType::BLOCK_GROUP_ITEM), None);
+    for (item, data, _block_offset, _slot) in search_range(fs, root, search) {
+        // the range above still includes other item types with objectids in between
+        if item.key.item_type != BtrfsItemType::BLOCK_GROUP_ITEM {
+            continue;
+        }
+        let Some(bg) = item_as::<btrfs_block_group_item>(&data) else {
+            continue;
+        };
+        groups.push(BlockGroup {
+            start: item.key.objectid,
+            length: item.key.offset,
+            used: bg.used,
+            flags: bg.flags,
+        });
+    }
+    Ok(groups)
+}
+
+/// bytes of each device stripe of a chunk, i.e. the length of its dev extents
+pub fn stripe_length(chunk: &btrfs_chunk) -> u64 {
+    let length = chunk.length;
+    let num_stripes = (chunk.num_stripes as u64).max(1);
+    let sub_stripes = (chunk.sub_stripes as u64).max(1);
+    let chunk_type = chunk.r#type;
+    match chunk_type & PROFILE_MASK {
+        BTRFS_BLOCK_GROUP_RAID0 => length / num_stripes,
+        BTRFS_BLOCK_GROUP_RAID10 => length * sub_stripes / num_stripes,
+        BTRFS_BLOCK_GROUP_RAID5 => length / num_stripes.saturating_sub(1).max(1),
+        BTRFS_BLOCK_GROUP_RAID6 => length / num_stripes.saturating_sub(2).max(1),
+        _ => length,
+    }
+}
+
+/// totals for the block groups of one type and profile
+#[derive(Default)]
+pub struct ProfileUsage {
+    pub block_groups: u64,
+    pub allocated: u64,
+    pub used: u64,
+    /// space taken on the devices, counting every copy and parity.
+    /// None if some chunk could not be found
+    pub raw: Option<u64>,
+}
+
+/// block group totals keyed by type and profile flags
+pub fn space_usage(fs: &FsInfo) -> Result<BTreeMap<u64, ProfileUsage>> {
+    let mut usage = BTreeMap::<u64, ProfileUsage>::new();
+    for bg in block_groups(fs)? {
+        let entry = usage.entry(bg.flags).or_insert_with(|| ProfileUsage {
+            raw: Some(0),
+            ..Default::default()
+        });
+        entry.block_groups += 1;
+        entry.allocated += bg.length;
+        entry.used += bg.used;
+        let raw = find_chunk(fs, bg.start).map(|ChunkInfo(_key, chunk, _stripes)| {
+            stripe_length(&chunk) * chunk.num_stripes as u64
+        });
+        entry.raw = entry.raw.zip(raw).map(|(total, raw)| total + raw);
+    }
+    Ok(usage)
+}
+
+/// data referenced by one subvolume
+#[derive(Default)]
+pub struct SubvolUsage {
+    pub subvol: u64,
+    /// on-disc size of every extent the subvolume references
+    pub referenced: u64,
+    /// the part of referenced not referenced by any other subvolume
+    pub exclusive: u64,
+    /// file data stored inline in the subvolume's leaves
+    pub inline: u64,
+}
+
+pub struct DataUsage {
+    pub subvols: Vec<SubvolUsage>,
+    /// on-disc size of the distinct extents referenced by any subvolume
+    pub referenced: u64,
+    /// data extents in the extent tree which no subvolume references
+    pub unreferenced: u64,
+    /// subvolumes whose trees could not be read completely
+    pub incomplete: Vec<u64>,
+}
+
+/// attribute data extents to subvolumes by walking the EXTENT_DATA items of
+/// every fs tree. Snapshots share tree blocks, so an extent shared that way
+/// counts towards every subvolume reaching it even though its backref only
+/// names the original. Data extents in the extent tree that no walk reached
+/// are reported as unreferenced.
+pub fn data_usage(fs: &FsInfo) -> Result<DataUsage> {
+    // disk_bytenr -> (disk_num_bytes, subvolumes referencing it)
+    let mut extents = HashMap::<u64, (u64, BTreeSet<u64>)>::new();
+    let mut subvols = Vec::new();
+    let mut incomplete = Vec::new();
+    for (subvol, root) in fs_trees(fs) {
+        let mut usage = SubvolUsage {
+            subvol,
+            ..Default::default()
+        };
+        // the search skips subtrees it can't read, so check the whole tree
+        if !tree_stats(fs, root).problems.is_empty() {
+            incomplete.push(subvol);
+        }
+        let search = key_range(None, Some(BtrfsItemType::EXTENT_DATA), None);
+        for (item, data, _block_offset, _slot) in search_range(fs, root, search) {
+            if item.key.item_type != BtrfsItemType::EXTENT_DATA
+                || data.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START
+            {
+                continue;
+            }
+            let fe = unsafe { &*(data.as_ptr() as *const btrfs_file_extent_item) };
+            if fe.r#type == BTRFS_FILE_EXTENT_INLINE {
+                usage.inline += (data.len() - BTRFS_FILE_EXTENT_INLINE_DATA_START) as u64;
+                continue;
+            }
+            let Some(fe) = item_as::<btrfs_file_extent_item>(&data) else {
+                continue;
+            };
+            let disk_bytenr = fe.disk_bytenr;
+            if disk_bytenr == 0 {
+                continue; // a hole
+            }
+            let entry = extents
+                .entry(disk_bytenr)
+                .or_insert_with(|| (fe.disk_num_bytes, BTreeSet::new()));
+            entry.1.insert(subvol);
+        }
+        subvols.push(usage);
+    }
+
+    let mut referenced = 0;
+    for (length, owners) in extents.values() {
+        referenced += length;
+        for usage in subvols.iter_mut() {
+            if owners.contains(&usage.subvol) {
+                usage.referenced += length;
+                if owners.len() == 1 {
+                    usage.exclusive += length;
+                }
+            }
+        }
+    }
+
+    let mut unreferenced = 0;
+    for (_, extent_root) in global_roots(fs, BTRFS_EXTENT_TREE_OBJECTID) {
+        let search = key_range(None, Some(BtrfsItemType::EXTENT_ITEM), None);
+        for (item, data, _block_offset, _slot) in search_range(fs, extent_root, search) {
+            let key = item.key;
+            let start = key.objectid;
+            if key.item_type != BtrfsItemType::EXTENT_ITEM || extents.contains_key(&start) {
+                continue;
+            }
+            let Some(ei) = item_as::<btrfs_extent_item>(&data) else {
+                continue;
+            };
+            if ei.flags & BTRFS_EXTENT_FLAG_DATA != 0 {
+                unreferenced += key.offset;
+            }
+        }
+    }
+    Ok(DataUsage {
+        subvols,
+        referenced,
+        unreferenced,
+        incomplete,
+    })
+}
+
+/// a relocation tree found in the root tree
+pub struct RelocTree {
+    /// BTRFS_TREE_RELOC_OBJECTID or BTRFS_DATA_RELOC_TREE_OBJECTID
+    pub objectid: u64,
+    /// for a TREE_RELOC tree, the subvolume whose blocks it relocates
+    pub offset: u64,
+    pub bytenr: u64,
+    pub level: u8,
+    pub generation: u64,
+    pub items: u64,
+    /// EXTENT_DATA items, which the data reloc tree only has while data
+    /// block groups are being relocated
+    pub file_extents: u64,
+}
+
+/// what the root tree records of a balance which hasn't finished
+pub struct BalanceStatus {
+    pub balance: Option<btrfs_balance_item>,
+    pub reloc_trees: Vec<RelocTree>,
+}
+
+/// the balance item of an interrupted or paused balance, and the relocation
+/// trees in the root tree
+pub fn balance_status(fs: &FsInfo) -> Result<BalanceStatus> {
+    let search = key_range(
+        Some(BTRFS_BALANCE_OBJECTID),
+        Some(BtrfsItemType::TEMPORARY_ITEM),
+        Some(0),
+    );
+    let balance = match search_range(fs, fs.master_sb.root, search).next() {
+        Some((_, data, ..)) => Some(*item_as::<btrfs_balance_item>(&data).ok_or_else(|| {
+            anyhow!(
+                "the balance item is only {} bytes, {} are needed",
+                data.len(),
+                std::mem::size_of::<btrfs_balance_item>()
+            )
+        })?),
+        None => None,
+    };
+    let mut reloc_trees = Vec::new();
+    for objectid in [BTRFS_TREE_RELOC_OBJECTID, BTRFS_DATA_RELOC_TREE_OBJECTID] {
+        let search = key_range(Some(objectid), Some(BtrfsItemType::ROOT_ITEM), None);
+        for (item, data, ..) in search_range(fs, fs.master_sb.root, search) {
+            let Some(root) = item_as::<btrfs_root_item>(&data) else {
+                bail!("root item {:?} is only {} bytes", { item.key }, data.len());
+            };
+            let stats = tree_stats(fs, root.bytenr);
+            reloc_trees.push(RelocTree {
+                objectid,
+                offset: item.key.offset,
+                bytenr: root.bytenr,
+                level: root.level,
+                generation: root.generation,
+                items: stats.item_types.values().sum(),
+                file_extents: stats
+                    .item_types
+                    .get(&BtrfsItemType::EXTENT_DATA)
+                    .copied()
+                    .unwrap_or(0),
+            });
+        }
+    }
+    Ok(BalanceStatus {
+        balance,
+        reloc_trees,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(length: u64, chunk_type: u64, num_stripes: u16, sub_stripes: u16) -> btrfs_chunk {
+        btrfs_chunk {
+            length,
+            owner: BTRFS_EXTENT_TREE_OBJECTID,
+            stripe_len: 65536,
+            r#type: chunk_type,
+            io_align: 4096,
+            io_width: 4096,
+            sector_size: 4096,
+            num_stripes,
+            sub_stripes,
+        }
+    }
+
+    #[test]
+    fn stripe_lengths() {
+        let gib = 1 << 30;
+        assert_eq!(
+            stripe_length(&chunk(gib, BTRFS_BLOCK_GROUP_DATA, 1, 1)),
+            gib
+        );
+        let dup = BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_DUP;
+        assert_eq!(stripe_length(&chunk(gib, dup, 2, 1)), gib);
+        let raid0 = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID0;
+        assert_eq!(stripe_length(&chunk(4 * gib, raid0, 4, 1)), gib);
+        let raid10 = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID10;
+        assert_eq!(stripe_length(&chunk(2 * gib, raid10, 4, 2)), gib);
+        let raid5 = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID5;
+        assert_eq!(stripe_length(&chunk(2 * gib, raid5, 3, 1)), gib);
+        let raid6 = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_RAID6;
+        assert_eq!(stripe_length(&chunk(2 * gib, raid6, 4, 1)), gib);
+    }
+}
@@ -0,0 +1,231 @@
+//! The v1 free space cache (space_cache=v1), which older filesystems keep
+//! instead of a free space tree: per block group a hidden file in the root
+//! tree, located by a header item at (FREE_SPACE_OBJECTID, 0, block group
+//! start).
+//!
+//! The file is a series of pages, each with a crc32c kept in an array at the
+//! start of the first page, then the generation the cache was written in.
+//! Free space entries follow, packed into pages without straddling them,
+//! and then for every bitmap entry a whole page of bitmap, a bit per sector
+//! set if the sector is free. The kernel writes as many pages as it has, so
+//! pages are taken to be 4KiB, as on x86.
+//!
+//! The kernel discards a cache rather than load it if anything is off: a
+//! bad checksum, a generation differing from the header's or the inode's,
+//! or a superblock cache_generation which isn't current.
+
+use crate::btrfs::*;
+use crate::free_space::{bitmap_ranges, merge_ranges};
+use crate::inode::inode_item;
+use crate::items::item_as;
+use crate::restore::write_file_data;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::path::Path;
+
+pub const CACHE_PAGE_SIZE: usize = 4096;
+
+/// a block group's cache file, as far as it could be read
+pub struct SpaceCache {
+    pub block_group: u64,
+    pub inode: u64,
+    /// the generation in the header item
+    pub generation: u64,
+    pub num_entries: u64,
+    pub num_bitmaps: u64,
+    /// the free (start, end) ranges the entries and bitmaps give, merged, or
+    /// None if the file couldn't be read
+    pub free: Option<Vec<(u64, u64)>>,
+    /// why the kernel would discard the cache instead of loading it
+    pub stale: Vec<String>,
+}
+
+/// the page of a cache file at index, once its checksum is verified
+fn cache_page(data: &[u8], num_pages: usize, index: usize) -> Result<&[u8]> {
+    let page = data
+        .get(index * CACHE_PAGE_SIZE..(index + 1) * CACHE_PAGE_SIZE)
+        .ok_or_else(|| anyhow!("page {index} is past the end of the file"))?;
+    let stored = u32::from_le_bytes(data[index * 4..index * 4 + 4].try_into().unwrap());
+    // the first page's checksum leaves out the checksums themselves
+    let start = if index == 0 { num_pages * 4 } else { 0 };
+    let csum = csum_data(&page[start..], BtrfsCsumType::CRC32);
+    let csum = u32::from_le_bytes(csum[..4].try_into().unwrap());
+    ensure!(
+        csum == stored,
+        "page {index} has checksum {csum:#x}, {stored:#x} recorded"
+    );
+    Ok(page)
+}
+
+/// decode a cache file holding num_entries entries, as the kernel loads it,
+/// into the free (start, end) ranges, merged
+pub fn parse_cache_file(
+    data: &[u8],
+    generation: u64,
+    num_entries: u64,
+    sectorsize: u64,
+) -> Result<Vec<(u64, u64)>> {
+    let num_pages = data.len().div_ceil(CACHE_PAGE_SIZE);
+    ensure!(
+        num_pages * 4 + 8 <= CACHE_PAGE_SIZE,
+        "{num_pages} pages are too many for their checksums to fit the first page"
+    );
+    let mut page = cache_page(data, num_pages, 0)?;
+    let mut at = num_pages * 4;
+    let file_generation = u64::from_le_bytes(page[at..at + 8].try_into().unwrap());
+    ensure!(
+        file_generation == generation,
+        "the file was written in generation {file_generation}, the header has {generation}"
+    );
+    at += 8;
+    let entry_size = std::mem::size_of::<btrfs_free_space_entry>();
+    let mut index = 0;
+    let mut ranges = Vec::new();
+    let mut bitmaps = Vec::new();
+    for _ in 0..num_entries {
+        if CACHE_PAGE_SIZE - at < entry_size {
+            index += 1;
+            page = cache_page(data, num_pages, index)?;
+            at = 0;
+        }
+        let entry = item_as::<btrfs_free_space_entry>(&page[at..]).unwrap();
+        at += entry_size;
+        let (offset, bytes) = (entry.offset, entry.bytes);
+        ensure!(bytes != 0, "entry at {offset} of 0 bytes");
+        match entry.r#type {
+            BTRFS_FREE_SPACE_EXTENT => ranges.push((offset, offset + bytes)),
+            BTRFS_FREE_SPACE_BITMAP => bitmaps.push(offset),
+            other => bail!("entry at {offset} of unknown type {other}"),
+        }
+    }
+    // each bitmap takes a page of its own, after the entries
+    for (n, offset) in bitmaps.into_iter().enumerate() {
+        let bitmap = cache_page(data, num_pages, index + 1 + n)?;
+        ranges.extend(bitmap_ranges(offset, sectorsize, bitmap));
+    }
+    ranges.sort();
+    Ok(merge_ranges(ranges))
+}
+
+/// every v1 cache in the root tree, read as the kernel would load it
+pub fn space_caches(fs: &FsInfo) -> Vec<SpaceCache> {
+    let root = fs.master_sb.root;
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let sb_generation = fs.master_sb.generation;
+    let cache_generation = fs.master_sb.cache_generation;
+    let free_space_tree =
+        fs.master_sb.compat_ro_flags & BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE != 0;
+    let mut caches = Vec::new();
+    let search = key_range(Some(BTRFS_FREE_SPACE_OBJECTID), None, None);
+    for (item, data, _block_offset, _slot) in search_range(fs, root, search) {
+        let block_group = item.key.offset;
+        let Some(header) = item_as::<btrfs_free_space_header>(&data) else {
+            continue;
+        };
+        let inode = header.location.objectid;
+        let mut cache = SpaceCache {
+            block_group,
+            inode,
+            generation: header.generation,
+            num_entries: header.num_entries,
+            num_bitmaps: header.num_bitmaps,
+            free: None,
+            stale: Vec::new(),
+        };
+        if free_space_tree {
+            cache
+                .stale
+                .push(String::from("the free space tree is used instead"));
+        } else if cache_generation != sb_generation {
+            cache.stale.push(format!(
+                "the superblock's cache_generation {cache_generation} isn't its generation {sb_generation}"
+            ));
+        }
+        let file = match inode_item(fs, root, inode) {
+            Some(inode_item) => {
+                let inode_generation = inode_item.generation;
+                if inode_generation != cache.generation {
+                    cache.stale.push(format!(
+                        "inode {inode} is of generation {inode_generation}, the header has {}",
+                        cache.generation
+                    ));
+                }
+                let mut file = Vec::new();
+                let path = format!("free space cache inode {inode}");
+                write_file_data(
+                    fs,
+                    root,
+                    inode,
+                    inode_item.size,
+                    &mut file,
+                    false,
+                    Path::new(&path),
+                )
+                .map(|_| file)
+            }
+            None => Err(anyhow!("inode {inode} has no inode item")),
+        };
+        let free = file.and_then(|file| {
+            if cache.num_entries == 0 {
+                return Ok(Vec::new());
+            }
+            parse_cache_file(&file, cache.generation, cache.num_entries, sectorsize)
+        });
+        match free {
+            Result::Ok(free) => cache.free = Some(free),
+            Err(e) => cache.stale.push(e.to_string()),
+        }
+        caches.push(cache);
+    }
+    caches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seal(file: &mut [u8], num_pages: usize) {
+        for index in 0..num_pages {
+            let start = if index == 0 { num_pages * 4 } else { 0 };
+            let page = &file[index * CACHE_PAGE_SIZE + start..(index + 1) * CACHE_PAGE_SIZE];
+            let csum = csum_data(page, BtrfsCsumType::CRC32);
+            file[index * 4..index * 4 + 4].copy_from_slice(&csum[..4]);
+        }
+    }
+
+    #[test]
+    fn cache_file() {
+        // two extent entries and a bitmap with sectors 2 and 3 free
+        let num_pages = 2;
+        let mut file = vec![0; num_pages * CACHE_PAGE_SIZE];
+        let mut at = num_pages * 4;
+        file[at..at + 8].copy_from_slice(&7u64.to_le_bytes());
+        at += 8;
+        for (offset, bytes, kind) in [
+            (1 << 20, 8192, BTRFS_FREE_SPACE_EXTENT),
+            (1 << 22, 4096, BTRFS_FREE_SPACE_BITMAP),
+            (1 << 21, 4096, BTRFS_FREE_SPACE_EXTENT),
+        ] {
+            file[at..at + 8].copy_from_slice(&(offset as u64).to_le_bytes());
+            file[at + 8..at + 16].copy_from_slice(&(bytes as u64).to_le_bytes());
+            file[at + 16] = kind;
+            at += 17;
+        }
+        file[CACHE_PAGE_SIZE] = 0b1100;
+        seal(&mut file, num_pages);
+        let free = parse_cache_file(&file, 7, 3, 4096).unwrap();
+        assert_eq!(
+            free,
+            vec![
+                (1 << 20, (1 << 20) + 8192),
+                (1 << 21, (1 << 21) + 4096),
+                ((1 << 22) + 8192, (1 << 22) + 16384)
+            ]
+        );
+        assert!(parse_cache_file(&file, 8, 3, 4096).is_err());
+        file[CACHE_PAGE_SIZE] = 0b1000;
+        assert!(parse_cache_file(&file, 7, 3, 4096).is_err());
+    }
+}
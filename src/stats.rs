@@ -0,0 +1,111 @@
+//! Statistics gathered by walking every node of a tree: its shape, how full
+//! its leaves are and what they contain.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::structures::*;
+
+use std::collections::{BTreeMap, HashSet};
+
+#[derive(Default)]
+pub struct TreeStats {
+    /// number of nodes at each level, leaves being level 0
+    pub nodes_per_level: BTreeMap<u8, u64>,
+    /// item headers plus item data across all leaves
+    pub leaf_bytes_used: u64,
+    /// space for items across all leaves, i.e. nodesize less the header
+    pub leaf_bytes_available: u64,
+    pub item_types: BTreeMap<BtrfsItemType, u64>,
+    pub item_data_bytes: u64,
+    /// (min, max) header generation of the nodes visited
+    pub generations: Option<(u64, u64)>,
+    /// key, data size and leaf of the largest item
+    pub largest_item: Option<(btrfs_disk_key, u32, u64)>,
+    /// blocks that could not be read or don't fit in the tree
+    pub problems: Vec<String>,
+}
+
+impl TreeStats {
+    pub fn nodes(&self) -> u64 {
+        self.nodes_per_level.values().sum()
+    }
+
+    pub fn items(&self) -> u64 {
+        self.item_types.values().sum()
+    }
+}
+
+/// walk the whole tree rooted at the block at root
+pub fn tree_stats(fs: &FsInfo, root: u64) -> TreeStats {
+    let mut stats = TreeStats::default();
+    let mut seen = HashSet::new();
+    visit(fs, root, None, &mut stats, &mut seen);
+    stats
+}
+
+fn visit(
+    fs: &FsInfo,
+    bytenr: u64,
+    expected_level: Option<u8>,
+    stats: &mut TreeStats,
+    seen: &mut HashSet<u64>,
+) {
+    if fs.cancel.is_cancelled() {
+        return;
+    }
+    if !seen.insert(bytenr) {
+        stats
+            .problems
+            .push(format!("block {bytenr} is referenced more than once"));
+        return;
+    }
+    let block = match load_virt_block(fs, bytenr) {
+        Result::Ok(block) => block,
+        Err(e) => {
+            stats.problems.push(format!("block {bytenr}: {e}"));
+            return;
+        }
+    };
+    let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+    let level = header.level;
+    let generation = header.generation;
+    if let Some(expected) = expected_level {
+        if level != expected {
+            stats.problems.push(format!(
+                "block {bytenr} has level {level}, its parent expects {expected}"
+            ));
+            return;
+        }
+    }
+    *stats.nodes_per_level.entry(level).or_default() += 1;
+    stats.generations = Some(match stats.generations {
+        None => (generation, generation),
+        Some((min, max)) => (min.min(generation), max.max(generation)),
+    });
+
+    if level == 0 {
+        stats.leaf_bytes_available += (block.len() - std::mem::size_of::<btrfs_header>()) as u64;
+    }
+    for entry in node_entries(&block) {
+        match entry {
+            NodeEntry::Ptr(ptr) => {
+                let child = ptr.blockptr;
+                visit(fs, child, level.checked_sub(1), stats, seen);
+            }
+            NodeEntry::Item(item, _data) => {
+                let key = item.key;
+                let size = item.size;
+                *stats.item_types.entry(key.item_type).or_default() += 1;
+                stats.item_data_bytes += size as u64;
+                stats.leaf_bytes_used += (std::mem::size_of::<btrfs_item>() as u64) + size as u64;
+                if stats
+                    .largest_item
+                    .is_none_or(|(_, largest, _)| size > largest)
+                {
+                    stats.largest_item = Some((key, size, bytenr));
+                }
+            }
+        }
+    }
+}
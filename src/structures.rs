@@ -1,6 +1,8 @@
 pub const BTRFS_CSUM_SIZE: usize = 32;
 pub const BTRFS_FSID_SIZE: usize = 16;
 pub const BTRFS_UUID_SIZE: usize = 16;
+/// btrfs trees are never deeper than this
+pub const BTRFS_MAX_LEVEL: u8 = 8;
 pub const BTRFS_SUPER_INFO_OFFSET: usize = 65536;
 pub const BTRFS_SUPER_INFO_SIZE: usize = 4096;
 
@@ -24,8 +26,11 @@ pub const BTRFS_QUOTA_TREE_OBJECTID: u64 = 8;
 pub const BTRFS_UUID_TREE_OBJECTID: u64 = 9;
 pub const BTRFS_FREE_SPACE_TREE_OBJECTID: u64 = 10;
 pub const BTRFS_BLOCK_GROUP_TREE_OBJECTID: u64 = 11;
+pub const BTRFS_RAID_STRIPE_TREE_OBJECTID: u64 = 12;
 
 pub const BTRFS_DEV_STATS_OBJECTID: u64 = 0;
+pub const BTRFS_DEV_ITEMS_OBJECTID: u64 = 1;
+pub const BTRFS_DEV_REPLACE_OBJECTID: u64 = 0;
 pub const BTRFS_BALANCE_OBJECTID: u64 = -4_i64 as u64;
 pub const BTRFS_ORPHAN_OBJECTID: u64 = -5_i64 as u64;
 pub const BTRFS_TREE_LOG_OBJECTID: u64 = -6_i64 as u64;
@@ -38,12 +43,16 @@ pub const BTRFS_FREE_INO_OBJECTID: u64 = -12_i64 as u64;
 pub const BTRFS_MULTIPLE_OBJECTIDS: u64 = -255_i64 as u64;
 
 pub const BTRFS_FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+/* the root directory of each fs tree, and the first objectid for inodes/subvolumes */
+pub const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+pub const BTRFS_LAST_FREE_OBJECTID: u64 = -256_i64 as u64;
 
 /*
   repr(u16) will not work on big-endian architectures. We could work around this with target_endian confg so that we declare these values with swapped bytes on big-endian systems. But I'm not going to write code I'm not going to test.
 */
 #[repr(u16)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[allow(dead_code, non_camel_case_types)]
 pub enum BtrfsCsumType {
     CRC32 = 0,
@@ -53,7 +62,8 @@ pub enum BtrfsCsumType {
 }
 
 #[repr(u8)]
-#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[allow(dead_code, non_camel_case_types)]
 pub enum BtrfsItemType {
     MIN = 0x00, //to facilitate searching through any possible byte value
@@ -88,6 +98,7 @@ pub enum BtrfsItemType {
     DEV_EXTENT = 0xcc,
     DEV_ITEM = 0xd8,
     CHUNK_ITEM = 0xe4,
+    RAID_STRIPE = 0xe6,
     QGROUP_STATUS = 0xf0,
     QGROUP_INFO = 0xf2,
     QGROUP_LIMIT = 0xf4,
@@ -101,6 +112,56 @@ pub enum BtrfsItemType {
     MAX = 0xff, //to facilitate searching through any possible byte value
 }
 
+impl BtrfsItemType {
+    /// every item type, in key order
+    pub const ALL: &'static [BtrfsItemType] = &[
+        BtrfsItemType::MIN,
+        BtrfsItemType::INODE_ITEM,
+        BtrfsItemType::INODE_REF,
+        BtrfsItemType::INODE_EXTREF,
+        BtrfsItemType::XATTR_ITEM,
+        BtrfsItemType::VERITY_DESC_ITEM,
+        BtrfsItemType::VERITY_MERKLE_ITEM,
+        BtrfsItemType::ORPHAN_ITEM,
+        BtrfsItemType::DIR_LOG_ITEM,
+        BtrfsItemType::DIR_LOG_INDEX,
+        BtrfsItemType::DIR_ITEM,
+        BtrfsItemType::DIR_INDEX,
+        BtrfsItemType::EXTENT_DATA,
+        BtrfsItemType::CSUM_ITEM,
+        BtrfsItemType::EXTENT_CSUM,
+        BtrfsItemType::ROOT_ITEM,
+        BtrfsItemType::ROOT_BACKREF,
+        BtrfsItemType::ROOT_REF,
+        BtrfsItemType::EXTENT_ITEM,
+        BtrfsItemType::METADATA_ITEM,
+        BtrfsItemType::TREE_BLOCK_REF,
+        BtrfsItemType::EXTENT_DATA_REF,
+        BtrfsItemType::EXTENT_REF_V0,
+        BtrfsItemType::SHARED_BLOCK_REF,
+        BtrfsItemType::SHARED_DATA_REF,
+        BtrfsItemType::BLOCK_GROUP_ITEM,
+        BtrfsItemType::FREE_SPACE_INFO,
+        BtrfsItemType::FREE_SPACE_EXTENT,
+        BtrfsItemType::FREE_SPACE_BITMAP,
+        BtrfsItemType::DEV_EXTENT,
+        BtrfsItemType::DEV_ITEM,
+        BtrfsItemType::CHUNK_ITEM,
+        BtrfsItemType::RAID_STRIPE,
+        BtrfsItemType::QGROUP_STATUS,
+        BtrfsItemType::QGROUP_INFO,
+        BtrfsItemType::QGROUP_LIMIT,
+        BtrfsItemType::QGROUP_RELATION,
+        BtrfsItemType::TEMPORARY_ITEM,
+        BtrfsItemType::PERSISTENT_ITEM,
+        BtrfsItemType::DEV_REPLACE,
+        BtrfsItemType::UUID_KEY_SUBVOL,
+        BtrfsItemType::UUID_KEY_RECEIVED_SUBVOL,
+        BtrfsItemType::STRING_ITEM,
+        BtrfsItemType::MAX,
+    ];
+}
+
 //type LE64 = endian_types::Endian<u64, endian_types::LittleEndian>;
 /// on-disc format is little-endian
 pub type LE16 = u16;
@@ -108,11 +169,51 @@ pub type LE32 = u32;
 pub type LE64 = u64;
 
 pub type BtrfsCsum = [u8; BTRFS_CSUM_SIZE];
-pub type BtrfsUuid = [u8; BTRFS_UUID_SIZE];
-pub type BtrfsFsid = [u8; BTRFS_FSID_SIZE];
+pub type BtrfsFsid = BtrfsUuid;
+
+/// a uuid as stored on disc. Displayed (and parsed, see parse.rs) in the
+/// usual 8-4-4-4-12 hex form.
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct BtrfsUuid(pub [u8; BTRFS_UUID_SIZE]);
+
+impl BtrfsUuid {
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; BTRFS_UUID_SIZE]
+    }
+}
+
+impl std::fmt::Display for BtrfsUuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let u = &self.0;
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            hex::encode(&u[0..4]),
+            hex::encode(&u[4..6]),
+            hex::encode(&u[6..8]),
+            hex::encode(&u[8..10]),
+            hex::encode(&u[10..])
+        )
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for BtrfsUuid {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl std::fmt::Debug for BtrfsUuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{self}")
+    }
+}
 
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_super_block {
     pub csum: BtrfsCsum,
     pub fsid: BtrfsFsid,
@@ -142,20 +243,137 @@ pub struct btrfs_super_block {
     pub chunk_root_level: u8,
     pub log_root_level: u8,
     pub dev_item: btrfs_dev_item,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_label"))]
     pub label: [u8; BTRFS_LABEL_SIZE],
     pub cache_generation: LE64,
     pub uuid_tree_generation: LE64,
     pub metadata_uuid: BtrfsFsid, //fsid vs uuid as per ctree.h
     pub nr_global_roots: LE64,
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub reserved: [LE64; 27],
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub sys_chunk_array: [u8; BTRFS_SYSTEM_CHUNK_ARRAY_SIZE],
     pub super_roots: [btrfs_root_backup; BTRFS_NUM_BACKUP_ROOTS],
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub padding: [u8; 565],
 }
 static_assertions::assert_eq_size!([u8; BTRFS_SUPER_INFO_SIZE], btrfs_super_block);
 
+/// the label up to its NUL terminator, as a string
+#[cfg(feature = "serde")]
+fn serialize_label<S: serde::Serializer>(
+    label: &[u8; BTRFS_LABEL_SIZE],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let len = label
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(BTRFS_LABEL_SIZE);
+    serializer.collect_str(&String::from_utf8_lossy(&label[..len]))
+}
+
+impl btrfs_super_block {
+    /// the label, which is NUL terminated unless it fills the field
+    pub fn label_str(&self) -> String {
+        let len = self
+            .label
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(BTRFS_LABEL_SIZE);
+        String::from_utf8_lossy(&self.label[..len]).into_owned()
+    }
+}
+
+/// leaves out the reserved space, padding and the raw sys_chunk_array (see
+/// SysChunkIter for its contents)
+impl std::fmt::Debug for btrfs_super_block {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let sb = *self;
+        let label_len = sb
+            .label
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(BTRFS_LABEL_SIZE);
+        f.debug_struct("btrfs_super_block")
+            .field("csum", &hex::encode(sb.csum))
+            .field("fsid", &{ sb.fsid })
+            .field("bytenr", &{ sb.bytenr })
+            .field("flags", &{ sb.flags })
+            .field("magic", &{ sb.magic })
+            .field("generation", &{ sb.generation })
+            .field("root", &{ sb.root })
+            .field("chunk_root", &{ sb.chunk_root })
+            .field("log_root", &{ sb.log_root })
+            .field("total_bytes", &{ sb.total_bytes })
+            .field("bytes_used", &{ sb.bytes_used })
+            .field("root_dir_object_id", &{ sb.root_dir_object_id })
+            .field("num_devices", &{ sb.num_devices })
+            .field("sectorsize", &{ sb.sectorsize })
+            .field("nodesize", &{ sb.nodesize })
+            .field("stripesize", &{ sb.stripesize })
+            .field("sys_chunk_array_size", &{ sb.sys_chunk_array_size })
+            .field("chunk_root_generation", &{ sb.chunk_root_generation })
+            .field("compat_flags", &{ sb.compat_flags })
+            .field("compat_ro_flags", &{ sb.compat_ro_flags })
+            .field("incompat_flags", &{ sb.incompat_flags })
+            .field("csum_type", &{ sb.csum_type })
+            .field("root_level", &sb.root_level)
+            .field("chunk_root_level", &sb.chunk_root_level)
+            .field("log_root_level", &sb.log_root_level)
+            .field("dev_item", &{ sb.dev_item })
+            .field("label", &String::from_utf8_lossy(&sb.label[..label_len]))
+            .field("cache_generation", &{ sb.cache_generation })
+            .field("uuid_tree_generation", &{ sb.uuid_tree_generation })
+            .field("metadata_uuid", &{ sb.metadata_uuid })
+            .field("nr_global_roots", &{ sb.nr_global_roots })
+            .field("super_roots", &{ sb.super_roots })
+            .finish()
+    }
+}
+
+/* btrfs_super_block.compat_ro_flags */
+pub const BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE: u64 = 1 << 0;
+pub const BTRFS_FEATURE_COMPAT_RO_FREE_SPACE_TREE_VALID: u64 = 1 << 1;
+pub const BTRFS_FEATURE_COMPAT_RO_VERITY: u64 = 1 << 2;
+pub const BTRFS_FEATURE_COMPAT_RO_BLOCK_GROUP_TREE: u64 = 1 << 3;
+
+/* btrfs_super_block.incompat_flags */
+pub const BTRFS_FEATURE_INCOMPAT_MIXED_BACKREF: u64 = 1 << 0;
+pub const BTRFS_FEATURE_INCOMPAT_DEFAULT_SUBVOL: u64 = 1 << 1;
+pub const BTRFS_FEATURE_INCOMPAT_MIXED_GROUPS: u64 = 1 << 2;
+pub const BTRFS_FEATURE_INCOMPAT_COMPRESS_LZO: u64 = 1 << 3;
+pub const BTRFS_FEATURE_INCOMPAT_COMPRESS_ZSTD: u64 = 1 << 4;
+pub const BTRFS_FEATURE_INCOMPAT_BIG_METADATA: u64 = 1 << 5;
+pub const BTRFS_FEATURE_INCOMPAT_EXTENDED_IREF: u64 = 1 << 6;
+pub const BTRFS_FEATURE_INCOMPAT_RAID56: u64 = 1 << 7;
+pub const BTRFS_FEATURE_INCOMPAT_SKINNY_METADATA: u64 = 1 << 8;
+pub const BTRFS_FEATURE_INCOMPAT_NO_HOLES: u64 = 1 << 9;
+pub const BTRFS_FEATURE_INCOMPAT_METADATA_UUID: u64 = 1 << 10;
+pub const BTRFS_FEATURE_INCOMPAT_RAID1C34: u64 = 1 << 11;
+pub const BTRFS_FEATURE_INCOMPAT_ZONED: u64 = 1 << 12;
+pub const BTRFS_FEATURE_INCOMPAT_EXTENT_TREE_V2: u64 = 1 << 13;
+pub const BTRFS_FEATURE_INCOMPAT_RAID_STRIPE_TREE: u64 = 1 << 14;
+pub const BTRFS_FEATURE_INCOMPAT_SIMPLE_QUOTA: u64 = 1 << 16;
+
+/* btrfs_super_block.flags and btrfs_header.flags */
+pub const BTRFS_HEADER_FLAG_WRITTEN: u64 = 1 << 0;
+pub const BTRFS_HEADER_FLAG_RELOC: u64 = 1 << 1;
+/* the backref revision is kept in the top byte of btrfs_header.flags */
+pub const BTRFS_BACKREF_REV_SHIFT: u64 = 56;
+pub const BTRFS_MIXED_BACKREF_REV: u64 = 1;
+pub const BTRFS_SUPER_FLAG_ERROR: u64 = 1 << 2;
+pub const BTRFS_SUPER_FLAG_SEEDING: u64 = 1 << 32;
+pub const BTRFS_SUPER_FLAG_METADUMP: u64 = 1 << 33;
+pub const BTRFS_SUPER_FLAG_METADUMP_V2: u64 = 1 << 34;
+pub const BTRFS_SUPER_FLAG_CHANGING_FSID: u64 = 1 << 35;
+pub const BTRFS_SUPER_FLAG_CHANGING_FSID_V2: u64 = 1 << 36;
+pub const BTRFS_SUPER_FLAG_CHANGING_BG_TREE: u64 = 1 << 38;
+pub const BTRFS_SUPER_FLAG_CHANGING_DATA_CSUM: u64 = 1 << 39;
+pub const BTRFS_SUPER_FLAG_CHANGING_META_CSUM: u64 = 1 << 40;
+
 #[repr(C, packed)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_root_backup {
     pub tree_root: LE64,
     pub tree_root_gen: LE64,
@@ -191,7 +409,8 @@ pub struct btrfs_root_backup {
 }
 
 #[repr(C, packed)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_dev_item {
     pub devid: LE64,
     pub total_bytes: LE64,
@@ -211,6 +430,8 @@ pub struct btrfs_dev_item {
 
 /* header is stored at the start of every tree node */
 #[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_header {
     pub csum: BtrfsCsum,
     pub fsid: BtrfsFsid,
@@ -226,6 +447,8 @@ pub struct btrfs_header {
 
 /* leaf nodes are full of btrfs_items, and data */
 #[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_item {
     pub key: btrfs_disk_key,
     pub offset: LE32, //counting starts at end of btrfs_header
@@ -234,6 +457,8 @@ pub struct btrfs_item {
 
 /* non-leaf nodes are full of btrfs_key_ptrs */
 #[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_key_ptr {
     pub key: btrfs_disk_key,
     pub blockptr: LE64,
@@ -242,6 +467,7 @@ pub struct btrfs_key_ptr {
 
 #[repr(C, packed)]
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_disk_key {
     pub objectid: LE64,
     pub item_type: BtrfsItemType,
@@ -257,7 +483,12 @@ impl std::fmt::Debug for btrfs_disk_key {
     }
 }
 
+/// the stripe length of every chunk the kernel makes
+pub const BTRFS_STRIPE_LEN: u64 = 64 * 1024;
+
 #[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_stripe {
     pub devid: LE64,
     pub offset: LE64,
@@ -265,6 +496,8 @@ pub struct btrfs_stripe {
 }
 
 #[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_chunk {
     pub length: LE64,
     pub owner: LE64,
@@ -278,12 +511,23 @@ pub struct btrfs_chunk {
 }
 
 #[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_timespec {
     pub sec: LE64,
     pub nsec: LE32,
 }
 
+/// as seconds.nanoseconds, or an RFC3339 date with --human
+impl std::fmt::Display for btrfs_timespec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", crate::units::fmt_time(self.sec, self.nsec))
+    }
+}
+
 #[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_inode_item {
     pub generation: LE64,
     pub transid: LE64,
@@ -304,9 +548,57 @@ pub struct btrfs_inode_item {
     pub mtime: btrfs_timespec,
     pub otime: btrfs_timespec,
 }
+pub const BTRFS_INODE_NODATASUM: u64 = 1 << 0;
+/* the read-only inode flags are kept in the upper 32 bits of flags */
+pub const BTRFS_INODE_RO_VERITY: u64 = 1 << 32;
+
+/// VERITY_DESC_ITEM at offset 0; the fs-verity descriptor itself follows in
+/// VERITY_DESC_ITEMs at offset 1 and on, and the Merkle tree in
+/// VERITY_MERKLE_ITEMs keyed by byte offset
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_verity_descriptor_item {
+    pub size: LE64,
+    pub reserved: [LE64; 2],
+    pub encryption: u8,
+}
+
+/// the descriptor fs-verity keeps for a file, followed by sig_size bytes of
+/// signature
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct fsverity_descriptor {
+    pub version: u8,
+    pub hash_algorithm: u8,
+    pub log_blocksize: u8,
+    pub salt_size: u8,
+    pub sig_size: LE32,
+    pub data_size: LE64,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_hex"))]
+    pub root_hash: [u8; 64],
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_hex"))]
+    pub salt: [u8; 32],
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub reserved: [u8; 144],
+}
+pub const FS_VERITY_HASH_ALG_SHA256: u8 = 1;
+pub const FS_VERITY_HASH_ALG_SHA512: u8 = 2;
+
+/// bytes as a hex string
+#[cfg(feature = "serde")]
+fn serialize_hex<S: serde::Serializer, const N: usize>(
+    bytes: &[u8; N],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&hex::encode(bytes))
+}
 
 /* there was an older version of this structure which I'm ignoring */
 #[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_root_item {
     pub inode: btrfs_inode_item,
     pub generation: LE64,
@@ -336,7 +628,18 @@ pub struct btrfs_root_item {
     pub __reserved: [LE64; 7],
 }
 
+/// a DIR_LOG_INDEX (or DIR_LOG_ITEM) item: the log holds every entry of the
+/// directory from the key offset to end inclusive
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_dir_log_item {
+    pub end: LE64,
+}
+
 #[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_root_ref {
     pub dirid: LE64,
     pub sequence: LE64,
@@ -345,8 +648,369 @@ pub struct btrfs_root_ref {
 }
 
 #[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct btrfs_extent_item {
-	pub refs: LE64,
-	pub generation: LE64,
-	pub flags: LE64,
+    pub refs: LE64,
+    pub generation: LE64,
+    pub flags: LE64,
+}
+pub const BTRFS_EXTENT_FLAG_DATA: u64 = 1 << 0;
+pub const BTRFS_EXTENT_FLAG_TREE_BLOCK: u64 = 1 << 1;
+/* the children of the tree block refer to it by bytenr, with shared refs */
+pub const BTRFS_BLOCK_FLAG_FULL_BACKREF: u64 = 1 << 8;
+
+/* follows btrfs_extent_item for non-skinny EXTENT_ITEMs describing tree blocks */
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_tree_block_info {
+    pub key: btrfs_disk_key,
+    pub level: u8,
+}
+
+/* inline backrefs follow the extent item. For TREE_BLOCK_REF and
+ * SHARED_BLOCK_REF the offset is the whole payload, for EXTENT_DATA_REF
+ * a btrfs_extent_data_ref overlaps the offset field, and SHARED_DATA_REF
+ * is followed by a btrfs_shared_data_ref */
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_extent_inline_ref {
+    pub r#type: u8,
+    pub offset: LE64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_extent_data_ref {
+    pub root: LE64,
+    pub objectid: LE64,
+    pub offset: LE64,
+    pub count: LE32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_shared_data_ref {
+    pub count: LE32,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_inode_ref {
+    pub index: LE64,
+    pub name_len: LE16,
+    /* the name follows here */
 }
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_inode_extref {
+    pub parent_objectid: LE64,
+    pub index: LE64,
+    pub name_len: LE16,
+    /* the name follows here */
+}
+
+/* used by DIR_ITEM, DIR_INDEX and XATTR_ITEM. name then data follow */
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_dir_item {
+    pub location: btrfs_disk_key,
+    pub transid: LE64,
+    pub data_len: LE16,
+    pub name_len: LE16,
+    pub r#type: u8,
+}
+
+pub const BTRFS_FT_UNKNOWN: u8 = 0;
+pub const BTRFS_FT_REG_FILE: u8 = 1;
+pub const BTRFS_FT_DIR: u8 = 2;
+pub const BTRFS_FT_CHRDEV: u8 = 3;
+pub const BTRFS_FT_BLKDEV: u8 = 4;
+pub const BTRFS_FT_FIFO: u8 = 5;
+pub const BTRFS_FT_SOCK: u8 = 6;
+pub const BTRFS_FT_SYMLINK: u8 = 7;
+pub const BTRFS_FT_XATTR: u8 = 8;
+
+pub const BTRFS_FILE_EXTENT_INLINE: u8 = 0;
+pub const BTRFS_FILE_EXTENT_REG: u8 = 1;
+pub const BTRFS_FILE_EXTENT_PREALLOC: u8 = 2;
+
+pub const BTRFS_COMPRESS_NONE: u8 = 0;
+pub const BTRFS_COMPRESS_ZLIB: u8 = 1;
+pub const BTRFS_COMPRESS_LZO: u8 = 2;
+pub const BTRFS_COMPRESS_ZSTD: u8 = 3;
+
+/* the most a compressed extent holds, before and after compression */
+pub const BTRFS_MAX_UNCOMPRESSED: u64 = 128 * 1024;
+pub const BTRFS_MAX_COMPRESSED: u64 = 128 * 1024;
+
+/* inline extents only have the fields up to and including type, the file
+ * data starts immediately afterwards */
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_file_extent_item {
+    pub generation: LE64,
+    pub ram_bytes: LE64,
+    pub compression: u8,
+    pub encryption: u8,
+    pub other_encoding: LE16,
+    pub r#type: u8,
+    pub disk_bytenr: LE64,
+    pub disk_num_bytes: LE64,
+    pub offset: LE64,
+    pub num_bytes: LE64,
+}
+pub const BTRFS_FILE_EXTENT_INLINE_DATA_START: usize = 21;
+
+/// the state of a device replace, the DEV_REPLACE item of the dev tree
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_dev_replace_item {
+    pub src_devid: LE64,
+    /// physical offset on the source below which everything was copied
+    pub cursor_left: LE64,
+    pub cursor_right: LE64,
+    pub cont_reading_from_srcdev_mode: LE64,
+    pub replace_state: LE64,
+    /// seconds since the epoch
+    pub time_started: LE64,
+    pub time_stopped: LE64,
+    pub num_write_errors: LE64,
+    pub num_uncorrectable_read_errors: LE64,
+}
+
+pub const BTRFS_DEV_REPLACE_ITEM_STATE_NEVER_STARTED: u64 = 0;
+pub const BTRFS_DEV_REPLACE_ITEM_STATE_STARTED: u64 = 1;
+pub const BTRFS_DEV_REPLACE_ITEM_STATE_FINISHED: u64 = 2;
+pub const BTRFS_DEV_REPLACE_ITEM_STATE_CANCELED: u64 = 3;
+pub const BTRFS_DEV_REPLACE_ITEM_STATE_SUSPENDED: u64 = 4;
+
+pub const BTRFS_DEV_REPLACE_ITEM_CONT_READING_FROM_SRCDEV_MODE_ALWAYS: u64 = 0;
+pub const BTRFS_DEV_REPLACE_ITEM_CONT_READING_FROM_SRCDEV_MODE_AVOID: u64 = 1;
+
+/// the devid of a replace's target device until the replace finishes
+pub const BTRFS_DEV_REPLACE_DEVID: u64 = 0;
+
+/// the error counters of the PERSISTENT_ITEM (BTRFS_DEV_STATS_OBJECTID,
+/// PERSISTENT_ITEM, devid) in the dev tree, in this order. Items written by
+/// older kernels may hold fewer.
+pub const BTRFS_DEV_STAT_WRITE_ERRS: usize = 0;
+pub const BTRFS_DEV_STAT_READ_ERRS: usize = 1;
+pub const BTRFS_DEV_STAT_FLUSH_ERRS: usize = 2;
+pub const BTRFS_DEV_STAT_CORRUPTION_ERRS: usize = 3;
+pub const BTRFS_DEV_STAT_GENERATION_ERRS: usize = 4;
+pub const BTRFS_DEV_STAT_VALUES_MAX: usize = 5;
+
+/// the filters of a balance for one block group type
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_balance_args {
+    /// profiles to balance, with BTRFS_BALANCE_ARGS_PROFILES
+    pub profiles: LE64,
+    /// percent used, or with BTRFS_BALANCE_ARGS_USAGE_RANGE usage_min in
+    /// the low and usage_max in the high 32 bits
+    pub usage: LE64,
+    pub devid: LE64,
+    /// physical range on devid
+    pub pstart: LE64,
+    pub pend: LE64,
+    /// logical range
+    pub vstart: LE64,
+    pub vend: LE64,
+    /// the profile to convert to, with BTRFS_BALANCE_ARGS_CONVERT
+    pub target: LE64,
+    pub flags: LE64,
+    /// the number of chunks, or with BTRFS_BALANCE_ARGS_LIMIT_RANGE
+    /// limit_min in the low and limit_max in the high 32 bits
+    pub limit: LE64,
+    pub stripes_min: LE32,
+    pub stripes_max: LE32,
+    pub unused: [LE64; 6],
+}
+
+/* quota tree items: QGROUP_STATUS at (0, 0), QGROUP_INFO and QGROUP_LIMIT
+ * at (0, qgroupid), and QGROUP_RELATION both ways between member and parent */
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_qgroup_status_item {
+    pub version: LE64,
+    pub generation: LE64,
+    pub flags: LE64,
+    /// the objectid the rescan has got to
+    pub rescan: LE64,
+    /* with simple quotas, enable_gen follows */
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_qgroup_info_item {
+    pub generation: LE64,
+    pub rfer: LE64,
+    pub rfer_cmpr: LE64,
+    pub excl: LE64,
+    pub excl_cmpr: LE64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_qgroup_limit_item {
+    pub flags: LE64,
+    pub max_rfer: LE64,
+    pub max_excl: LE64,
+    pub rsv_rfer: LE64,
+    pub rsv_excl: LE64,
+}
+
+/* a qgroupid is the level in the top 16 bits and the id below */
+pub const BTRFS_QGROUP_LEVEL_SHIFT: u64 = 48;
+
+pub const BTRFS_QGROUP_STATUS_FLAG_ON: u64 = 1 << 0;
+pub const BTRFS_QGROUP_STATUS_FLAG_RESCAN: u64 = 1 << 1;
+pub const BTRFS_QGROUP_STATUS_FLAG_INCONSISTENT: u64 = 1 << 2;
+pub const BTRFS_QGROUP_STATUS_FLAG_SIMPLE_MODE: u64 = 1 << 3;
+
+/// the FREE_SPACE_INFO of a block group, at (start, FREE_SPACE_INFO, length)
+/// in the free space tree. Its free space follows as FREE_SPACE_EXTENT items
+/// at (start, FREE_SPACE_EXTENT, length), or with USING_BITMAPS as
+/// FREE_SPACE_BITMAP items with a bit per sector, set if the sector is free.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_free_space_info {
+    pub extent_count: LE32,
+    pub flags: LE32,
+}
+
+pub const BTRFS_FREE_SPACE_USING_BITMAPS: u32 = 1 << 0;
+
+/* the v1 free space cache (space_cache=v1): a header item in the root tree
+ * at (FREE_SPACE_OBJECTID, 0, block group start) locating the inode of a
+ * hidden file holding the block group's free space entries and bitmaps */
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_free_space_header {
+    pub location: btrfs_disk_key,
+    pub generation: LE64,
+    pub num_entries: LE64,
+    pub num_bitmaps: LE64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_free_space_entry {
+    pub offset: LE64,
+    pub bytes: LE64,
+    pub r#type: u8,
+}
+
+pub const BTRFS_FREE_SPACE_EXTENT: u8 = 1;
+pub const BTRFS_FREE_SPACE_BITMAP: u8 = 2;
+
+/// a balance which was started and hasn't finished, the TEMPORARY_ITEM
+/// with objectid BTRFS_BALANCE_OBJECTID in the root tree
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_balance_item {
+    pub flags: LE64,
+    pub data: btrfs_balance_args,
+    pub meta: btrfs_balance_args,
+    pub sys: btrfs_balance_args,
+    pub unused: [LE64; 4],
+}
+
+pub const BTRFS_BALANCE_DATA: u64 = 1 << 0;
+pub const BTRFS_BALANCE_SYSTEM: u64 = 1 << 1;
+pub const BTRFS_BALANCE_METADATA: u64 = 1 << 2;
+pub const BTRFS_BALANCE_FORCE: u64 = 1 << 3;
+pub const BTRFS_BALANCE_RESUME: u64 = 1 << 4;
+
+pub const BTRFS_BALANCE_ARGS_PROFILES: u64 = 1 << 0;
+pub const BTRFS_BALANCE_ARGS_USAGE: u64 = 1 << 1;
+pub const BTRFS_BALANCE_ARGS_DEVID: u64 = 1 << 2;
+pub const BTRFS_BALANCE_ARGS_DRANGE: u64 = 1 << 3;
+pub const BTRFS_BALANCE_ARGS_VRANGE: u64 = 1 << 4;
+pub const BTRFS_BALANCE_ARGS_LIMIT: u64 = 1 << 5;
+pub const BTRFS_BALANCE_ARGS_LIMIT_RANGE: u64 = 1 << 6;
+pub const BTRFS_BALANCE_ARGS_STRIPES_RANGE: u64 = 1 << 7;
+pub const BTRFS_BALANCE_ARGS_CONVERT: u64 = 1 << 8;
+pub const BTRFS_BALANCE_ARGS_SOFT: u64 = 1 << 9;
+pub const BTRFS_BALANCE_ARGS_USAGE_RANGE: u64 = 1 << 10;
+
+/// the profile bit balance filters use for SINGLE, which has none in the
+/// block group flags
+pub const BTRFS_AVAIL_ALLOC_BIT_SINGLE: u64 = 1 << 48;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_dev_extent {
+    pub chunk_tree: LE64,
+    pub chunk_objectid: LE64,
+    pub chunk_offset: LE64,
+    pub length: LE64,
+    pub chunk_tree_uuid: BtrfsUuid,
+}
+
+/// where one copy (or strip) of a raid stripe extent is; a RAID_STRIPE item
+/// is an array of them
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_raid_stride {
+    pub devid: LE64,
+    pub physical: LE64,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct btrfs_block_group_item {
+    pub used: LE64,
+    pub chunk_objectid: LE64,
+    pub flags: LE64,
+}
+
+/* block group / chunk type flags */
+pub const BTRFS_BLOCK_GROUP_DATA: u64 = 1 << 0;
+pub const BTRFS_BLOCK_GROUP_SYSTEM: u64 = 1 << 1;
+pub const BTRFS_BLOCK_GROUP_METADATA: u64 = 1 << 2;
+pub const BTRFS_BLOCK_GROUP_RAID0: u64 = 1 << 3;
+pub const BTRFS_BLOCK_GROUP_RAID1: u64 = 1 << 4;
+pub const BTRFS_BLOCK_GROUP_DUP: u64 = 1 << 5;
+pub const BTRFS_BLOCK_GROUP_RAID10: u64 = 1 << 6;
+pub const BTRFS_BLOCK_GROUP_RAID5: u64 = 1 << 7;
+pub const BTRFS_BLOCK_GROUP_RAID6: u64 = 1 << 8;
+pub const BTRFS_BLOCK_GROUP_RAID1C3: u64 = 1 << 9;
+pub const BTRFS_BLOCK_GROUP_RAID1C4: u64 = 1 << 10;
+
+static_assertions::assert_eq_size!([u8; 160], btrfs_inode_item);
+static_assertions::assert_eq_size!([u8; 32], btrfs_qgroup_status_item);
+static_assertions::assert_eq_size!([u8; 40], btrfs_qgroup_info_item);
+static_assertions::assert_eq_size!([u8; 40], btrfs_qgroup_limit_item);
+static_assertions::assert_eq_size!([u8; 8], btrfs_free_space_info);
+static_assertions::assert_eq_size!([u8; 41], btrfs_free_space_header);
+static_assertions::assert_eq_size!([u8; 17], btrfs_free_space_entry);
+static_assertions::assert_eq_size!([u8; 16], btrfs_raid_stride);
+static_assertions::assert_eq_size!([u8; 25], btrfs_verity_descriptor_item);
+static_assertions::assert_eq_size!([u8; 256], fsverity_descriptor);
+static_assertions::assert_eq_size!([u8; 439], btrfs_root_item);
+static_assertions::assert_eq_size!([u8; 30], btrfs_dir_item);
+static_assertions::assert_eq_size!([u8; 53], btrfs_file_extent_item);
+static_assertions::assert_eq_size!([u8; 98], btrfs_dev_item);
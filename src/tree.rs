@@ -1,25 +1,111 @@
+use crate::address::{advise_logical, load_virt_block};
 use crate::btrfs::*;
 use crate::btrfs_node::*;
+use crate::mapped_file::Advice;
+use crate::parse::{parse_key_str, parse_u64};
 use crate::structures::*;
 
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use std::cmp::Ordering;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
 
 /// Functions/structures to search or iterate through a btrfs tree
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct NodeSearchOption {
     pub min_key: btrfs_disk_key,
     pub max_key: btrfs_disk_key,
-    // where there is no node exactly matching the key, if Ordering is Less, then the last node to the left
-    // of the search key will match. If Ordering is Greater, than the first node to the right of the search
-    // key will match.
-    // TODO:
+    // where there is no item exactly matching the key, if Ordering is Less, then the last item to the left
+    // of the search key will match. If Ordering is Greater, than the first item to the right of the search
+    // key will match. Equal matches neither, so that only the keys from min_key to max_key inclusive
+    // match; Greater for min_key and Less for max_key come to the same.
     pub min_match: Ordering,
     pub max_match: Ordering,
 }
 
-fn cmp_key(left: &btrfs_disk_key, right: &btrfs_disk_key) -> Ordering {
+impl NodeSearchOption {
+    /// every key from min_key to max_key inclusive
+    pub fn between(min_key: btrfs_disk_key, max_key: btrfs_disk_key) -> NodeSearchOption {
+        NodeSearchOption {
+            min_key,
+            max_key,
+            min_match: Ordering::Equal,
+            max_match: Ordering::Equal,
+        }
+    }
+
+    /// whether an item right of the range is still wanted, when none matched
+    /// max_key exactly
+    fn past_max(&self, last: Option<&btrfs_disk_key>) -> bool {
+        self.max_match == Ordering::Greater
+            && last.is_none_or(|key| cmp_key(key, &self.max_key) != Ordering::Equal)
+    }
+}
+
+/// the common searches, which BtrfsTreeIter::new takes in place of a
+/// NodeSearchOption
+#[derive(Clone, Debug)]
+pub enum SearchKey {
+    /// the item with this key, if there is one
+    Exact(btrfs_disk_key),
+    /// every item with a key in the range
+    Range(RangeInclusive<btrfs_disk_key>),
+    /// the item with this key, or else the last one to the left of it, e.g.
+    /// the extent or csum item which could cover an address
+    Floor(btrfs_disk_key),
+}
+
+impl SearchKey {
+    pub fn exact(key: btrfs_disk_key) -> SearchKey {
+        SearchKey::Exact(key)
+    }
+
+    pub fn range(range: RangeInclusive<btrfs_disk_key>) -> SearchKey {
+        SearchKey::Range(range)
+    }
+
+    pub fn floor(key: btrfs_disk_key) -> SearchKey {
+        SearchKey::Floor(key)
+    }
+}
+
+impl From<SearchKey> for NodeSearchOption {
+    fn from(key: SearchKey) -> NodeSearchOption {
+        match key {
+            SearchKey::Exact(key) => NodeSearchOption::between(key, key),
+            SearchKey::Range(range) => NodeSearchOption::between(*range.start(), *range.end()),
+            SearchKey::Floor(key) => NodeSearchOption {
+                min_match: Ordering::Less,
+                ..NodeSearchOption::between(key, key)
+            },
+        }
+    }
+}
+
+/// search options matching every key with the given fields, treating
+/// missing fields as wildcards
+pub fn key_range(
+    objectid: Option<u64>,
+    item_type: Option<BtrfsItemType>,
+    offset: Option<u64>,
+) -> NodeSearchOption {
+    NodeSearchOption::between(
+        btrfs_disk_key {
+            objectid: objectid.unwrap_or(0),
+            item_type: item_type.unwrap_or(BtrfsItemType::MIN),
+            offset: offset.unwrap_or(0),
+        },
+        btrfs_disk_key {
+            objectid: objectid.unwrap_or(u64::MAX),
+            item_type: item_type.unwrap_or(BtrfsItemType::MAX),
+            offset: offset.unwrap_or(u64::MAX),
+        },
+    )
+}
+
+pub fn cmp_key(left: &btrfs_disk_key, right: &btrfs_disk_key) -> Ordering {
     if left.objectid < right.objectid {
         Ordering::Less
     } else if left.objectid > right.objectid {
@@ -37,6 +123,145 @@ fn cmp_key(left: &btrfs_disk_key, right: &btrfs_disk_key) -> Ordering {
     }
 }
 
+/// a child block which doesn't match the key pointer that led to it, e.g. a
+/// stale block left behind by an interrupted write
+#[derive(Clone, Debug)]
+pub struct PointerMismatch {
+    pub parent: u64,
+    pub parent_level: u8,
+    pub blockptr: u64,
+    /// generation recorded in the key pointer
+    pub expected_generation: u64,
+    /// bytenr, generation and level from the child's header
+    pub bytenr: u64,
+    pub generation: u64,
+    pub level: u8,
+}
+
+impl PointerMismatch {
+    /// whether the child isn't one level below its parent. Following such a
+    /// pointer could lead back up the tree and round in a cycle.
+    pub fn wrong_level(&self) -> bool {
+        self.parent_level.checked_sub(1) != Some(self.level)
+    }
+}
+
+impl fmt::Display for PointerMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "block {} (from node {})", self.blockptr, self.parent)?;
+        if self.bytenr != self.blockptr {
+            write!(f, " has header bytenr {}", self.bytenr)?;
+        }
+        if self.generation != self.expected_generation {
+            write!(
+                f,
+                " has generation {}, its parent expects {}",
+                self.generation, self.expected_generation
+            )?;
+        }
+        if self.wrong_level() {
+            write!(
+                f,
+                " has level {} below a level {} node, its subtree is skipped",
+                self.level, self.parent_level
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// a BtrfsTreeIter's position, as BtrfsTreeIter::cursor takes it. As a
+/// string it is a token which can be saved and parsed back, e.g. so that a
+/// long extraction from a failing disk can restart where it left off.
+#[derive(Clone, Copy, Debug)]
+pub struct TreeCursor {
+    pub root: u64,
+    pub options: NodeSearchOption,
+    /// the key of the last item returned, None if none was
+    pub last_key: Option<btrfs_disk_key>,
+    /// the iteration reached the end of the range
+    pub finished: bool,
+}
+
+fn fmt_cursor_key(key: &btrfs_disk_key) -> String {
+    let (objectid, item_type, offset) = (key.objectid, key.item_type, key.offset);
+    format!("{objectid},{},{offset}", item_type as u8)
+}
+
+fn fmt_ordering(ordering: Ordering) -> &'static str {
+    match ordering {
+        Ordering::Less => "<",
+        Ordering::Equal => "=",
+        Ordering::Greater => ">",
+    }
+}
+
+fn parse_ordering(s: &str) -> anyhow::Result<Ordering> {
+    match s {
+        "<" => Ok(Ordering::Less),
+        "=" => Ok(Ordering::Equal),
+        ">" => Ok(Ordering::Greater),
+        _ => anyhow::bail!("unknown match {s:?}, expected <, = or >"),
+    }
+}
+
+/// root/min key/max key/min match/max match/last key, with keys as
+/// objectid,type,offset and the last key - before the first item or end
+/// once finished, e.g. 30408704/256,0,0/257,0,0/=/=/256,108,4096
+impl fmt::Display for TreeCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let options = &self.options;
+        let last = match (self.finished, &self.last_key) {
+            (true, _) => String::from("end"),
+            (false, None) => String::from("-"),
+            (false, Some(key)) => fmt_cursor_key(key),
+        };
+        write!(
+            f,
+            "{}/{}/{}/{}/{}/{last}",
+            self.root,
+            fmt_cursor_key(&options.min_key),
+            fmt_cursor_key(&options.max_key),
+            fmt_ordering(options.min_match),
+            fmt_ordering(options.max_match),
+        )
+    }
+}
+
+impl std::str::FromStr for TreeCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<TreeCursor> {
+        let fields: Vec<&str> = s.trim().split('/').collect();
+        let [root, min_key, max_key, min_match, max_match, last] = fields[..] else {
+            anyhow::bail!("cursor {s:?} should have 6 fields separated by /");
+        };
+        let options = NodeSearchOption {
+            min_key: parse_key_str(min_key)?,
+            max_key: parse_key_str(max_key)?,
+            min_match: parse_ordering(min_match)?,
+            max_match: parse_ordering(max_match)?,
+        };
+        anyhow::ensure!(
+            cmp_key(&options.min_key, &options.max_key) != Ordering::Greater,
+            "cursor {s:?} has a min key greater than its max key"
+        );
+        let last_key = match last {
+            "-" | "end" => None,
+            key => Some(parse_key_str(key)?),
+        };
+        Ok(TreeCursor {
+            root: parse_u64(root)?,
+            options,
+            last_key,
+            finished: last == "end",
+        })
+    }
+}
+
+/// how many leaves ahead of a search to ask the kernel to read in
+const READAHEAD_LEAVES: usize = 32;
+
 pub struct BtrfsTreeIter<'a> {
     fs: &'a FsInfo,
     root: LE64,
@@ -46,13 +271,21 @@ pub struct BtrfsTreeIter<'a> {
     // this to be fast, and it's ok if we have to do a slower operation to start
     // a new node. if we have to look up chunk addresses every next() it will be a bit
     // slow so we should save a reference to an entire block.
-    cur_leaf_node: Option<BtrfsLeafNodeIter<'a>>,
+    cur_leaf_node: Option<BtrfsLeafNodeIter>,
     cur_leaf_index: usize,
-    internal_node_stack: Vec<BtrfsInternalNodeIter<'a>>,
+    internal_node_stack: Vec<BtrfsInternalNodeIter>,
+    mismatches: Vec<PointerMismatch>,
+    /// the key of the last item returned
+    last_key: Option<btrfs_disk_key>,
+    /// an item right of max_key has been reached
+    finished: bool,
+    /// items up to this key were returned before the iterator was resumed
+    resumed_after: Option<btrfs_disk_key>,
 }
 
 impl<'a> BtrfsTreeIter<'a> {
-    pub fn new(fs: &FsInfo, root: LE64, options: NodeSearchOption) -> BtrfsTreeIter {
+    pub fn new(fs: &FsInfo, root: LE64, options: impl Into<NodeSearchOption>) -> BtrfsTreeIter<'_> {
+        let options = options.into();
         let objectid = options.min_key.objectid;
         let item_type = options.min_key.item_type;
         let offset = options.min_key.offset;
@@ -73,100 +306,496 @@ impl<'a> BtrfsTreeIter<'a> {
             cur_leaf_node: None,
             cur_leaf_index: 0,
             internal_node_stack: Vec::new(),
+            mismatches: Vec::new(),
+            last_key: None,
+            finished: false,
+            resumed_after: None,
+        }
+    }
+
+    /// where the iteration has got to, to carry on from with resume
+    pub fn cursor(&self) -> TreeCursor {
+        TreeCursor {
+            root: self.root,
+            options: self.options,
+            last_key: self.last_key,
+            finished: self.finished,
+        }
+    }
+
+    /// carry on an iteration from a cursor, after the last item it had
+    /// returned. The path down to it is searched for again, so the cursor
+    /// can come from another process, or the tree have been read from
+    /// another copy since.
+    pub fn resume(fs: &'a FsInfo, cursor: &TreeCursor) -> BtrfsTreeIter<'a> {
+        let mut options = cursor.options;
+        if let Some(last_key) = cursor.last_key {
+            // the item left of the range, if wanted, was returned already
+            options.min_key = last_key;
+            options.min_match = Ordering::Equal;
+        }
+        let mut iter = BtrfsTreeIter::new(fs, cursor.root, options);
+        iter.last_key = cursor.last_key;
+        iter.finished = cursor.finished;
+        iter.resumed_after = cursor.last_key;
+        iter
+    }
+
+    /// children met so far whose header didn't match their key pointer. They
+    /// are still descended into unless their level is wrong, and each is
+    /// also logged as a warning.
+    pub fn mismatches(&self) -> &[PointerMismatch] {
+        &self.mismatches
+    }
+
+    /// load the child block a key pointer of node parent points to,
+    /// checking its header against the pointer. None if the child can't be
+    /// read or is at the wrong level.
+    fn descend(
+        &mut self,
+        parent: &BtrfsInternalNodeIter,
+        ptr: &btrfs_key_ptr,
+    ) -> Option<BtrfsInternalNodeIter> {
+        let blockptr = ptr.blockptr;
+        let child = btrfs_internal_node(self.fs, blockptr)
+            .map_err(|e| warn!("cannot read block {blockptr}: {e}"))
+            .ok()?;
+        let header = child.header();
+        let mismatch = PointerMismatch {
+            parent: parent.block_offset,
+            parent_level: parent.header().level,
+            blockptr,
+            expected_generation: ptr.generation,
+            bytenr: header.bytenr,
+            generation: header.generation,
+            level: header.level,
+        };
+        if mismatch.bytenr == blockptr
+            && mismatch.generation == mismatch.expected_generation
+            && !mismatch.wrong_level()
+        {
+            if mismatch.level == 1 {
+                self.read_ahead(&child);
+            }
+            return Some(child);
+        }
+        warn!("{mismatch}");
+        let wrong_level = mismatch.wrong_level();
+        self.mismatches.push(mismatch);
+        (!wrong_level).then_some(child)
+    }
+
+    /// ask for the leaves of a level 1 node which the search will go on to
+    /// read to be read ahead, up to READAHEAD_LEAVES of them
+    fn read_ahead(&self, node: &BtrfsInternalNodeIter) {
+        let nodesize = self.fs.master_sb.nodesize as u64;
+        let mut ptrs = node.clone().peekable();
+        let mut advised = 0;
+        while let Some(ptr) = ptrs.next() {
+            if advised == READAHEAD_LEAVES
+                || cmp_key(&ptr.key, &self.options.max_key) == Ordering::Greater
+            {
+                break;
+            }
+            // leaves wholly to the left of the range aren't read
+            if ptrs
+                .peek()
+                .is_some_and(|next| cmp_key(&next.key, &self.options.min_key) != Ordering::Greater)
+            {
+                continue;
+            }
+            advise_logical(self.fs, ptr.blockptr, nodesize, Advice::WillNeed, true);
+            advised += 1;
         }
     }
 
     //Iterator trait helper function (maybe useful outside iterator with a bit of rework)
-    fn find_key(&self) -> Option<(Vec<BtrfsInternalNodeIter<'a>>, BtrfsLeafNodeIter<'a>)> {
-        let mut internal_node = btrfs_internal_node(self.fs, self.root).ok()?;
+    fn find_key(&mut self) -> Option<(Vec<BtrfsInternalNodeIter>, BtrfsLeafNodeIter)> {
+        let mut internal_node = btrfs_internal_node(self.fs, self.root)
+            .map_err(|e| warn!("cannot read tree root {}: {e}", self.root))
+            .ok()?;
+        let root_level = internal_node.header().level;
+        if root_level >= BTRFS_MAX_LEVEL {
+            warn!("tree root {} has impossible level {root_level}", self.root);
+            return None;
+        }
+        if root_level == 1 {
+            self.read_ahead(&internal_node);
+        }
         let mut node_stack = Vec::new();
         debug!("starting search at depth {}", internal_node.header().level);
-        //let header = load_virt::<btrfs_header>(self.fs, self.root).ok()?;
-        //TODO: binary search would be more efficient than iterating over every element in a node as
-        //btrfs nodes are wide in order to reduce tree depth.
         while internal_node.header().level != 0 {
-            // if our key is to the left of all we skip (nothing in this node)
-            // if our key is between we go down
-            // if our key is to the right of all we also go down
-            //
-            // if we are only searching for a single item, this is easy
-            let mut left_key;
-            let mut right_key;
-            loop {
-                left_key = internal_node.next();
-                if left_key.is_none() {
-                    break;
-                }
-                right_key = internal_node.peek();
+            // the last pointer whose key is not greater than min_key, as its
+            // subtree holds min_key or else the item left of it. If min_key is
+            // left of every key we go down the first.
+            internal_node.seek(&self.options.min_key);
+            let slot = internal_node.position();
+            let exact = internal_node
+                .peek()
+                .is_some_and(|ptr| cmp_key(&ptr.key, &self.options.min_key) == Ordering::Equal);
+            if !exact && slot > 0 {
+                internal_node.set_position(slot - 1);
+            }
+            let Some(ptr) = internal_node.next() else {
+                warn!("node {} holds no key pointers", internal_node.block_offset);
+                return None;
+            };
+            trace!(
+                "descending from node {} slot {} key {:?}",
+                internal_node.block_offset,
+                internal_node.position() - 1,
+                ptr.key
+            );
+            if cmp_key(&ptr.key, &self.options.max_key) == Ordering::Greater
+                && !self.options.past_max(None)
+            {
+                debug!("internal node is greater than search range");
+                return None;
+            }
+            let child = self.descend(&internal_node, &ptr)?;
+            node_stack.push(internal_node);
+            internal_node = child;
+        }
 
-                let lk = left_key.unwrap();
-                let btrfs_disk_key {
-                    objectid: lk_oid,
-                    item_type: lk_type,
-                    offset: lk_offset,
-                } = lk.key;
-                let cmp_min = cmp_key(&lk.key, &self.options.min_key);
-                let cmp_max = cmp_key(&lk.key, &self.options.max_key);
+        debug!("reached leaf node with path length {}", node_stack.len());
+        let mut leaf_node = internal_node.as_leaf_node();
+        leaf_node.seek(&self.options.min_key);
+        let slot = leaf_node.position();
+        // start from the item left of min_key, which matches if min_key
+        // doesn't
+        if self.options.min_match == Ordering::Less && slot > 0 {
+            leaf_node.set_position(slot - 1);
+        }
+        Some((node_stack, leaf_node))
+    }
+}
 
-                trace!(
-                    "Evaluating internal node key {} {:?} {}. min_key {:?}, max_key {:?}",
-                    lk_oid,
-                    lk_type,
-                    lk_offset,
-                    cmp_min,
-                    cmp_max,
-                );
+/// one node on the way from a tree's root down to a leaf
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathNode {
+    pub bytenr: u64,
+    pub level: u8,
+    /// the slot of the key pointer followed
+    pub slot: usize,
+}
 
-                match cmp_min {
-                    Ordering::Greater => match cmp_max {
-                        Ordering::Greater => {
-                            debug!("internal node is greater than search range");
-                            return None;
-                        }
-                        _ => {
-                            node_stack.push(internal_node);
-                            internal_node = btrfs_internal_node(self.fs, lk.blockptr).ok()?;
-                            break;
-                        }
-                    },
-                    Ordering::Equal => {
-                        node_stack.push(internal_node);
-                        internal_node = btrfs_internal_node(self.fs, lk.blockptr).ok()?;
-                        break;
-                    }
-                    Ordering::Less => match right_key {
-                        None => {
-                            trace!("right key is None");
-                            //if there is no key to the right then our key could be within the child nodes
-                            node_stack.push(internal_node);
-                            internal_node = btrfs_internal_node(self.fs, lk.blockptr).ok()?;
-                            break;
-                        }
-                        Some(rk) => {
-                            let cmp_rk = cmp_key(&rk.key, &self.options.min_key);
-                            trace!(
-                                "right {:?} is {cmp_rk:?} min_key {:?}",
-                                rk.key,
-                                self.options.min_key
-                            );
-                            if cmp_rk == Ordering::Greater {
-                                node_stack.push(internal_node);
-                                internal_node = btrfs_internal_node(self.fs, lk.blockptr).ok()?;
-                                break;
-                            }
-                            //otherwise we try the next key in the node
-                        }
-                    },
+/// that a tree block's header is that of the block at logical in this
+/// filesystem: its bytenr, and its fsid, which under the METADATA_UUID
+/// feature (after `btrfstune -m`) is the metadata_uuid rather than the fsid,
+/// or is a seed's
+pub fn check_header(fs: &FsInfo, header: &btrfs_header, logical: u64) -> anyhow::Result<()> {
+    let (bytenr, fsid) = (header.bytenr, header.fsid);
+    anyhow::ensure!(
+        bytenr == logical,
+        "tree block {logical} has header bytenr {bytenr}"
+    );
+    if fs.is_tree_block_fsid(&fsid) {
+        return Ok(());
+    }
+    let metadata_fsid = fs.metadata_fsid();
+    let metadata_uuid = fs.master_sb.metadata_uuid;
+    let hint = if fsid == fs.fsid {
+        ", the fsid, where the METADATA_UUID feature has tree blocks carry the metadata_uuid"
+    } else if fsid == metadata_uuid && !metadata_uuid.is_nil() {
+        ", the superblock's metadata_uuid, though the METADATA_UUID feature isn't set"
+    } else {
+        ""
+    };
+    anyhow::bail!("tree block {logical} has header fsid {fsid}{hint}, not {metadata_fsid}")
+}
+
+/// the nodes from the root down to the leaf where a key is or would go, as
+/// search_path finds them
+#[derive(Clone, Debug)]
+pub struct TreePath {
+    /// the internal nodes, root first
+    pub nodes: Vec<PathNode>,
+    pub leaf: u64,
+    /// the item's slot, or where it would be inserted
+    pub slot: usize,
+    pub found: bool,
+}
+
+/// descend from root to the leaf which holds, or would hold, key, as the
+/// kernel's btrfs_search_slot does. A key before the whole tree goes at the
+/// start of its first leaf. Unlike BtrfsTreeIter, which only yields items,
+/// this gives the nodes above the leaf, for edits which must fix up the key
+/// pointers to it.
+pub fn search_path(fs: &FsInfo, root: u64, key: &btrfs_disk_key) -> anyhow::Result<TreePath> {
+    let mut nodes: Vec<PathNode> = Vec::new();
+    let mut logical = root;
+    loop {
+        let block = load_virt_block(fs, logical)?;
+        let entries = node_entries(&block);
+        let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+        check_header(fs, header, logical)?;
+        let level = header.level;
+        if let Some(parent) = nodes.last() {
+            anyhow::ensure!(
+                parent.level.checked_sub(1) == Some(level),
+                "block {logical} has level {level} below a level {} node",
+                parent.level
+            );
+        } else {
+            anyhow::ensure!(
+                level < BTRFS_MAX_LEVEL,
+                "tree root {logical} has impossible level {level}"
+            );
+        }
+        let key_at = |slot: usize| match entries[slot] {
+            NodeEntry::Item(item, _) => item.key,
+            NodeEntry::Ptr(ptr) => ptr.key,
+        };
+        // the first slot whose key is not less than the one sought
+        let slot = seek_slot(entries.len(), key, key_at) as usize;
+        let found = slot < entries.len() && cmp_key(&key_at(slot), key) == Ordering::Equal;
+        if level == 0 {
+            return Ok(TreePath {
+                nodes,
+                leaf: logical,
+                slot,
+                found,
+            });
+        }
+        // the last pointer whose key is not greater than the one sought
+        let slot = if found { slot } else { slot.saturating_sub(1) };
+        let Some(NodeEntry::Ptr(ptr)) = entries.get(slot) else {
+            anyhow::bail!("node {logical} holds no key pointers");
+        };
+        nodes.push(PathNode {
+            bytenr: logical,
+            level,
+            slot,
+        });
+        logical = ptr.blockptr;
+    }
+}
+
+/// one block of a tree as TreeNodeIter yields it
+pub struct TreeNode {
+    pub bytenr: u64,
+    pub level: u8,
+    pub block: Arc<[u8]>,
+    /// the node pointing to it, with the slot of the pointer; None for the
+    /// root
+    pub parent: Option<PathNode>,
+}
+
+impl TreeNode {
+    pub fn header(&self) -> &btrfs_header {
+        unsafe { &*(self.block.as_ptr() as *const btrfs_header) }
+    }
+}
+
+/// every block of a tree, internal nodes as well as leaves, depth first
+/// with each node before its children, e.g. for a block census or to look
+/// at generations, which BtrfsTreeIter's items hide. Blocks which can't be
+/// read or aren't one level below their parent are skipped with their
+/// subtrees.
+pub struct TreeNodeIter<'a> {
+    fs: &'a FsInfo,
+    /// (bytenr, level the parent implies, parent and slot), next last
+    stack: Vec<(u64, Option<u8>, Option<PathNode>)>,
+    skipped: Vec<(u64, String)>,
+}
+
+impl<'a> TreeNodeIter<'a> {
+    pub fn new(fs: &'a FsInfo, root: u64) -> TreeNodeIter<'a> {
+        TreeNodeIter {
+            fs,
+            stack: vec![(root, None, None)],
+            skipped: Vec::new(),
+        }
+    }
+
+    /// (bytenr, reason) of the blocks skipped so far, each also logged as a
+    /// warning
+    pub fn skipped(&self) -> &[(u64, String)] {
+        &self.skipped
+    }
+}
+
+impl<'a> Iterator for TreeNodeIter<'a> {
+    type Item = TreeNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((bytenr, expected_level, parent)) = self.stack.pop() {
+            if self.fs.cancel.is_cancelled() {
+                return None;
+            }
+            let block = match load_virt_block(self.fs, bytenr) {
+                Ok(block) => block,
+                Err(e) => {
+                    warn!("cannot read block {bytenr}: {e}");
+                    self.skipped.push((bytenr, e.to_string()));
+                    continue;
+                }
+            };
+            let level = unsafe { &*(block.as_ptr() as *const btrfs_header) }.level;
+            let problem = match expected_level {
+                Some(expected) if level != expected => {
+                    Some(format!("level {level}, its parent expects {expected}"))
+                }
+                None if level >= BTRFS_MAX_LEVEL => Some(format!("impossible level {level}")),
+                _ => None,
+            };
+            if let Some(problem) = problem {
+                warn!("block {bytenr} has {problem}, its subtree is skipped");
+                self.skipped.push((bytenr, problem));
+                continue;
+            }
+            for (slot, entry) in node_entries(&block).into_iter().enumerate().rev() {
+                if let NodeEntry::Ptr(ptr) = entry {
+                    let parent = PathNode {
+                        bytenr,
+                        level,
+                        slot,
+                    };
+                    self.stack
+                        .push((ptr.blockptr, level.checked_sub(1), Some(parent)));
                 }
             }
+            return Some(TreeNode {
+                bytenr,
+                level,
+                block,
+                parent,
+            });
         }
+        None
+    }
+}
 
-        debug!("reached leaf node with path length {}", node_stack.len());
-        let leaf_node = internal_node.as_leaf_node();
-        Some((node_stack, leaf_node))
+/// the key of every item of a tree, in order, without finding or decoding
+/// the items' data as BtrfsTreeIter does, for key histograms or ordering
+/// checks over huge trees. Blocks are skipped as TreeNodeIter skips them.
+pub fn tree_keys(fs: &FsInfo, root: u64) -> impl Iterator<Item = btrfs_disk_key> + '_ {
+    TreeNodeIter::new(fs, root)
+        .filter(|node| node.level == 0)
+        .flat_map(|node| leaf_keys(&node.block).collect::<Vec<_>>())
+}
+
+/// what walk_tree does after a TreeVisitor callback
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Walk {
+    Continue,
+    /// leave out the node's children, or after an item the rest of its leaf
+    SkipSubtree,
+    /// end the walk
+    Stop,
+}
+
+/// the callbacks of walk_tree, each by default carrying on
+pub trait TreeVisitor {
+    /// every block, internal nodes and leaves, before its pointers or items
+    fn enter_node(&mut self, _node: &TreeNode) -> Walk {
+        Walk::Continue
+    }
+
+    /// an item of a leaf, with its data if that lies within the block
+    fn visit_item(
+        &mut self,
+        _leaf: &TreeNode,
+        _slot: usize,
+        _item: &btrfs_item,
+        _data: Option<&[u8]>,
+    ) -> Walk {
+        Walk::Continue
+    }
+
+    /// a block which can't be read or isn't one level below its parent,
+    /// whose subtree is skipped
+    fn error(&mut self, _bytenr: u64, _problem: &str) -> Walk {
+        Walk::Continue
     }
 }
 
+/// walk the tree rooted at root depth first, in key order, calling visitor
+/// for each node and item. Returns false if the walk was stopped, by the
+/// visitor or by the filesystem's cancellation token.
+pub fn walk_tree(fs: &FsInfo, root: u64, visitor: &mut impl TreeVisitor) -> bool {
+    walk_node(fs, root, None, visitor) != Walk::Stop
+}
+
+/// the block's subtree is skipped whatever the visitor says, unless it
+/// stops the walk
+fn walk_error(visitor: &mut impl TreeVisitor, bytenr: u64, problem: &str) -> Walk {
+    match visitor.error(bytenr, problem) {
+        Walk::Stop => Walk::Stop,
+        _ => Walk::Continue,
+    }
+}
+
+fn walk_node(
+    fs: &FsInfo,
+    bytenr: u64,
+    parent: Option<PathNode>,
+    visitor: &mut impl TreeVisitor,
+) -> Walk {
+    if fs.cancel.is_cancelled() {
+        return Walk::Stop;
+    }
+    let block = match load_virt_block(fs, bytenr) {
+        Ok(block) => block,
+        Err(e) => return walk_error(visitor, bytenr, &e.to_string()),
+    };
+    let level = unsafe { &*(block.as_ptr() as *const btrfs_header) }.level;
+    let problem = match parent {
+        Some(parent) if parent.level.checked_sub(1) != Some(level) => {
+            Some(format!("level {level} below a level {} node", parent.level))
+        }
+        None if level >= BTRFS_MAX_LEVEL => Some(format!("impossible level {level}")),
+        _ => None,
+    };
+    if let Some(problem) = problem {
+        return walk_error(visitor, bytenr, &problem);
+    }
+    let node = TreeNode {
+        bytenr,
+        level,
+        block,
+        parent,
+    };
+    match visitor.enter_node(&node) {
+        Walk::Continue => {}
+        Walk::SkipSubtree => return Walk::Continue,
+        Walk::Stop => return Walk::Stop,
+    }
+    for (slot, entry) in node_entries(&node.block).into_iter().enumerate() {
+        let walk = match entry {
+            NodeEntry::Ptr(ptr) => {
+                let parent = PathNode {
+                    bytenr,
+                    level,
+                    slot,
+                };
+                walk_node(fs, ptr.blockptr, Some(parent), visitor)
+            }
+            NodeEntry::Item(item, data) => visitor.visit_item(&node, slot, item, data),
+        };
+        match walk {
+            Walk::Continue => {}
+            Walk::SkipSubtree => break,
+            Walk::Stop => return Walk::Stop,
+        }
+    }
+    Walk::Continue
+}
+
+/// like BtrfsTreeIter::new, but only yielding items whose keys lie between
+/// min_key and max_key inclusive, whatever min_match and max_match say
+pub fn search_range(
+    fs: &FsInfo,
+    root: LE64,
+    options: impl Into<NodeSearchOption>,
+) -> impl Iterator<Item = <BtrfsTreeIter<'_> as Iterator>::Item> {
+    let options = options.into();
+    BtrfsTreeIter::new(fs, root, options).filter(move |(item, _, _, _)| {
+        cmp_key(&item.key, &options.min_key) != Ordering::Less
+            && cmp_key(&item.key, &options.max_key) != Ordering::Greater
+    })
+}
+
 /*
  * use cases:
  * - I want to find the leaf matching this exact key
@@ -175,9 +804,12 @@ impl<'a> BtrfsTreeIter<'a> {
  */
 
 impl<'a> Iterator for BtrfsTreeIter<'a> {
-    type Item = (&'a btrfs_item, &'a [u8], u64, u32);
+    type Item = (btrfs_item, ItemData, u64, u32);
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.finished || self.fs.cancel.is_cancelled() {
+            return None;
+        }
         if self.cur_leaf_node.is_none() {
             let (path, leaf_node) = self.find_key()?;
             self.cur_leaf_node = Some(leaf_node);
@@ -186,16 +818,13 @@ impl<'a> Iterator for BtrfsTreeIter<'a> {
         }
 
         if let Some(ln) = self.cur_leaf_node.as_mut() {
-            let mut left_leaf;
-            let mut right_leaf;
-            loop {
-                left_leaf = ln.next();
-                if left_leaf.is_none() {
-                    break;
+            while let Some(ll) = ln.next() {
+                if self
+                    .resumed_after
+                    .is_some_and(|after| cmp_key(&ll.0.key, &after) != Ordering::Greater)
+                {
+                    continue;
                 }
-                right_leaf = ln.peek();
-
-                let ll = left_leaf.unwrap();
                 let cmp_min = cmp_key(&ll.0.key, &self.options.min_key);
                 let cmp_max = cmp_key(&ll.0.key, &self.options.max_key);
                 trace!(
@@ -204,31 +833,34 @@ impl<'a> Iterator for BtrfsTreeIter<'a> {
                     cmp_min,
                     cmp_max
                 );
-                match cmp_min {
-                    Ordering::Greater => match cmp_max {
-                        Ordering::Greater => return None,
-                        _ => return Some(ll),
-                    },
-                    Ordering::Equal => return Some(ll),
-                    _ => match right_leaf {
-                        None => {
-                            trace!("right leaf was None");
-                            return Some(ll);
-                        }
-                        Some(rl) => {
-                            let cmp_rk = cmp_key(&rl.0.key, &self.options.min_key);
-                            trace!(
-                                "rk {:?} was {:?} min_key {:?}",
-                                rl.0.key,
-                                cmp_rk,
-                                self.options.min_key
-                            );
-                            if cmp_rk == Ordering::Greater {
-                                return Some(ll);
-                            }
+                if cmp_min == Ordering::Less {
+                    // only the last item left of min_key can match, and only
+                    // if there is none equal to it
+                    if self.options.min_match != Ordering::Less {
+                        continue;
+                    }
+                    if let Some(rl) = ln.peek() {
+                        let cmp_rk = cmp_key(&rl.0.key, &self.options.min_key);
+                        trace!(
+                            "rk {:?} was {:?} min_key {:?}",
+                            rl.0.key,
+                            cmp_rk,
+                            self.options.min_key
+                        );
+                        if cmp_rk != Ordering::Greater {
+                            continue;
                         }
-                    },
+                    } else {
+                        trace!("right leaf was None");
+                    }
+                } else if cmp_max == Ordering::Greater {
+                    self.finished = true;
+                    if !self.options.past_max(self.last_key.as_ref()) {
+                        return None;
+                    }
                 }
+                self.last_key = Some(ll.0.key);
+                return Some(ll);
             }
         }
         debug!("reached end of leaf nodes - opening parent node");
@@ -253,9 +885,14 @@ impl<'a> Iterator for BtrfsTreeIter<'a> {
         //(probably pushing back the node we just popped off, with iterator incremented)
         let mut internal_node = subtree_start.unwrap();
         while internal_node.header().level != 0 {
-            let child = internal_node.next()?; //every internal node has at least 1 entry
+            let ptr = internal_node.next()?; //every internal node has at least 1 entry
+            let child = self.descend(&internal_node, &ptr);
             self.internal_node_stack.push(internal_node);
-            internal_node = btrfs_internal_node(self.fs, child.blockptr).ok()?;
+            let Some(child) = child else {
+                // skip the subtree, carrying on from the parent's next pointer
+                return self.next();
+            };
+            internal_node = child;
         }
         let leaf_node = internal_node.as_leaf_node();
         self.cur_leaf_node = Some(leaf_node);
@@ -264,3 +901,315 @@ impl<'a> Iterator for BtrfsTreeIter<'a> {
         self.next()
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::block_cache::BlockCache;
+    use crate::cancel::CancellationToken;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, RwLock};
+
+    pub(crate) const NODESIZE: usize = 4096;
+
+    pub(crate) fn key(objectid: u64) -> btrfs_disk_key {
+        btrfs_disk_key {
+            objectid,
+            item_type: BtrfsItemType::INODE_ITEM,
+            offset: 0,
+        }
+    }
+
+    /// a filesystem on one device mapped 1:1 from logical 0, holding blocks
+    /// at 0, NODESIZE, 2 * NODESIZE...
+    pub(crate) fn test_fs(name: &str, blocks: &[Vec<u8>]) -> FsInfo {
+        let path = std::env::temp_dir().join(format!("{name}.{}", std::process::id()));
+        let mut image: Vec<u8> = blocks.concat();
+        image.resize(image.len().max(NODESIZE), 0);
+        std::fs::write(&path, &image).unwrap();
+        let file = crate::mapped_file::MappedFile::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut sb: btrfs_super_block = unsafe { std::mem::zeroed() };
+        sb.nodesize = NODESIZE as u32;
+        sb.sectorsize = NODESIZE as u32;
+        sb.csum_type = BtrfsCsumType::CRC32;
+        let mut chunk: btrfs_chunk = unsafe { std::mem::zeroed() };
+        chunk.length = image.len() as u64;
+        chunk.num_stripes = 1;
+        let stripe = btrfs_stripe {
+            devid: 1,
+            offset: 0,
+            dev_uuid: BtrfsUuid::default(),
+        };
+        let chunk_key = btrfs_disk_key {
+            objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+            item_type: BtrfsItemType::CHUNK_ITEM,
+            offset: 0,
+        };
+        let device = Arc::new(DeviceInfo {
+            path,
+            offset: 0,
+            file,
+            devid: 1,
+            dev_uuid: BtrfsUuid::default(),
+            seed: false,
+            sb_guessed: false,
+            generations_behind: 0,
+            zone_size: None,
+        });
+        FsInfo {
+            fsid: sb.fsid,
+            devid_map: HashMap::from([(1, Arc::clone(&device))]),
+            devuuid_map: HashMap::from([(device.dev_uuid, device)]),
+            master_sb: sb,
+            bootstrap_chunks: vec![ChunkInfo(chunk_key, chunk, vec![stripe])],
+            chunk_cache: RwLock::new(BTreeMap::new()),
+            seed_fsids: Vec::new(),
+            chunk_map: false,
+            cancel: CancellationToken::new(),
+            pinned_generation: None,
+            root_cache: RwLock::new(HashMap::new()),
+            block_cache: BlockCache::new(0, NODESIZE),
+            csum_policy: CsumPolicy::Strict,
+            quarantine: Default::default(),
+        }
+    }
+
+    /// a two level tree: a root at 0 over leaves at NODESIZE and 2 *
+    /// NODESIZE, holding the items of objectids 10, 20, 30 and 40, 50, 60
+    pub(crate) fn two_level_tree(name: &str) -> FsInfo {
+        let mut header: btrfs_header = unsafe { std::mem::zeroed() };
+        let mut leaves = Vec::new();
+        for (i, objectids) in [[10, 20, 30], [40, 50, 60]].iter().enumerate() {
+            header.bytenr = ((i + 1) * NODESIZE) as u64;
+            let items: Vec<_> = objectids
+                .iter()
+                .map(|&oid| (key(oid), vec![oid as u8; 8]))
+                .collect();
+            let mut leaf = build_leaf(&header, &items, NODESIZE).unwrap();
+            seal_tree_block(&mut leaf, BtrfsCsumType::CRC32);
+            leaves.push(leaf);
+        }
+        header.bytenr = 0;
+        header.level = 1;
+        let ptrs = [10, 40].map(|oid| btrfs_key_ptr {
+            key: key(oid),
+            blockptr: if oid == 10 { NODESIZE } else { 2 * NODESIZE } as u64,
+            generation: 0,
+        });
+        let mut root = build_node(&header, &ptrs, NODESIZE).unwrap();
+        seal_tree_block(&mut root, BtrfsCsumType::CRC32);
+        test_fs(name, &[root, leaves.remove(0), leaves.remove(0)])
+    }
+
+    fn objectids(fs: &FsInfo, options: impl Into<NodeSearchOption>) -> Vec<u64> {
+        BtrfsTreeIter::new(fs, 0, options)
+            .map(|(item, ..)| item.key.objectid)
+            .collect()
+    }
+
+    #[test]
+    fn header_fsids() {
+        let mut fs = two_level_tree("header_fsids");
+        let header = |fs: &FsInfo, logical| {
+            let block = load_virt_block(fs, logical).unwrap();
+            check_header(
+                fs,
+                unsafe { &*(block.as_ptr() as *const btrfs_header) },
+                logical,
+            )
+        };
+        assert!(header(&fs, NODESIZE as u64).is_ok());
+        assert!(check_header(&fs, unsafe { &std::mem::zeroed() }, 4096).is_err());
+        // after btrfstune -m the blocks carry the metadata_uuid, not the fsid
+        fs.fsid = BtrfsUuid([7; 16]);
+        fs.master_sb.fsid = fs.fsid;
+        assert!(header(&fs, 0).is_err());
+        fs.master_sb.incompat_flags |= BTRFS_FEATURE_INCOMPAT_METADATA_UUID;
+        assert!(header(&fs, 0).is_ok());
+        assert!(search_path(&fs, 0, &key(50)).unwrap().found);
+        fs.master_sb.metadata_uuid = BtrfsUuid([8; 16]);
+        let error = header(&fs, 0).unwrap_err().to_string();
+        assert!(!error.contains("METADATA_UUID"), "{error}");
+        assert!(search_path(&fs, 0, &key(50)).is_err());
+    }
+
+    #[test]
+    fn search_keys() {
+        let fs = two_level_tree("search_keys");
+        assert_eq!(objectids(&fs, SearchKey::exact(key(20))), [20]);
+        assert!(objectids(&fs, SearchKey::exact(key(25))).is_empty());
+        assert_eq!(objectids(&fs, SearchKey::floor(key(20))), [20]);
+        assert_eq!(objectids(&fs, SearchKey::floor(key(35))), [30]);
+        assert_eq!(objectids(&fs, SearchKey::floor(key(99))), [60]);
+        assert!(objectids(&fs, SearchKey::floor(key(5))).is_empty());
+        assert_eq!(
+            objectids(&fs, SearchKey::range(key(15)..=key(45))),
+            [20, 30, 40]
+        );
+        assert_eq!(objectids(&fs, SearchKey::range(key(0)..=key(99))).len(), 6);
+        assert!(objectids(&fs, SearchKey::range(key(61)..=key(99))).is_empty());
+    }
+
+    #[test]
+    fn tree_paths() {
+        let fs = two_level_tree("tree_paths");
+        let path = search_path(&fs, 0, &key(50)).unwrap();
+        let root = PathNode {
+            bytenr: 0,
+            level: 1,
+            slot: 1,
+        };
+        assert_eq!(path.nodes, [root]);
+        assert_eq!(
+            (path.leaf, path.slot, path.found),
+            (2 * NODESIZE as u64, 1, true)
+        );
+        let path = search_path(&fs, 0, &key(35)).unwrap();
+        assert_eq!(path.nodes[0].slot, 0);
+        assert_eq!(
+            (path.leaf, path.slot, path.found),
+            (NODESIZE as u64, 3, false)
+        );
+        let path = search_path(&fs, 0, &key(1)).unwrap();
+        assert_eq!(
+            (path.leaf, path.slot, path.found),
+            (NODESIZE as u64, 0, false)
+        );
+        let path = search_path(&fs, 0, &key(99)).unwrap();
+        assert_eq!(
+            (path.leaf, path.slot, path.found),
+            (2 * NODESIZE as u64, 3, false)
+        );
+    }
+
+    #[test]
+    fn resume_from_cursor() {
+        let fs = two_level_tree("resume_from_cursor");
+        let search = || SearchKey::floor(key(25));
+        let options = NodeSearchOption {
+            max_key: key(55),
+            ..search().into()
+        };
+        let mut iter = BtrfsTreeIter::new(&fs, 0, options);
+        assert_eq!({ iter.next().unwrap().0.key.objectid }, 20);
+        assert_eq!({ iter.next().unwrap().0.key.objectid }, 30);
+        let token = iter.cursor().to_string();
+        assert_eq!(token, "0/25,1,0/55,1,0/</=/30,1,0");
+        let cursor: TreeCursor = token.parse().unwrap();
+        let rest: Vec<u64> = BtrfsTreeIter::resume(&fs, &cursor)
+            .map(|(item, ..)| item.key.objectid)
+            .collect();
+        assert_eq!(rest, [40, 50]);
+        let mut iter = BtrfsTreeIter::resume(&fs, &cursor);
+        iter.by_ref().for_each(drop);
+        let cursor: TreeCursor = iter.cursor().to_string().parse().unwrap();
+        assert!(cursor.finished);
+        assert!(BtrfsTreeIter::resume(&fs, &cursor).next().is_none());
+        let fresh = BtrfsTreeIter::new(&fs, 0, search()).cursor();
+        let resumed = BtrfsTreeIter::resume(&fs, &fresh.to_string().parse().unwrap());
+        assert_eq!(
+            resumed
+                .map(|(item, ..)| item.key.objectid)
+                .collect::<Vec<_>>(),
+            [20]
+        );
+        assert!("0/1,1,0/0,1,0/=/=/-".parse::<TreeCursor>().is_err());
+    }
+
+    #[test]
+    fn tree_nodes() {
+        let fs = two_level_tree("tree_nodes");
+        let nodes: Vec<_> = TreeNodeIter::new(&fs, 0)
+            .map(|node| {
+                let parent = node.parent.map(|parent| (parent.bytenr, parent.slot));
+                (node.bytenr, node.level, parent, { node.header().nritems })
+            })
+            .collect();
+        let (leaf1, leaf2) = (NODESIZE as u64, 2 * NODESIZE as u64);
+        assert_eq!(
+            nodes,
+            [
+                (0, 1, None, 2),
+                (leaf1, 0, Some((0, 0)), 3),
+                (leaf2, 0, Some((0, 1)), 3)
+            ]
+        );
+        let mut iter = TreeNodeIter::new(&fs, 3 * NODESIZE as u64);
+        assert!(iter.next().is_none());
+        assert_eq!(iter.skipped().len(), 1);
+    }
+
+    #[test]
+    fn keys_only() {
+        let fs = two_level_tree("keys_only");
+        let objectids: Vec<u64> = tree_keys(&fs, 0).map(|key| key.objectid).collect();
+        assert_eq!(objectids, [10, 20, 30, 40, 50, 60]);
+        assert_eq!(leaf_keys(&load_virt_block(&fs, 0).unwrap()).count(), 0);
+    }
+
+    #[test]
+    fn visitor_walk() {
+        #[derive(Default)]
+        struct Visitor {
+            nodes: Vec<u64>,
+            objectids: Vec<u64>,
+            errors: Vec<u64>,
+        }
+        impl TreeVisitor for Visitor {
+            fn enter_node(&mut self, node: &TreeNode) -> Walk {
+                self.nodes.push(node.bytenr);
+                Walk::Continue
+            }
+
+            fn visit_item(
+                &mut self,
+                _leaf: &TreeNode,
+                _slot: usize,
+                item: &btrfs_item,
+                _data: Option<&[u8]>,
+            ) -> Walk {
+                self.objectids.push(item.key.objectid);
+                match item.key.objectid {
+                    20 => Walk::SkipSubtree,
+                    50 => Walk::Stop,
+                    _ => Walk::Continue,
+                }
+            }
+
+            fn error(&mut self, bytenr: u64, _problem: &str) -> Walk {
+                self.errors.push(bytenr);
+                Walk::Continue
+            }
+        }
+        let fs = two_level_tree("visitor_walk");
+        let mut visitor = Visitor::default();
+        assert!(!walk_tree(&fs, 0, &mut visitor));
+        assert_eq!(visitor.nodes, [0, NODESIZE as u64, 2 * NODESIZE as u64]);
+        assert_eq!(visitor.objectids, [10, 20, 40, 50]);
+        let mut visitor = Visitor::default();
+        assert!(walk_tree(&fs, NODESIZE as u64 * 3, &mut visitor));
+        assert_eq!(visitor.errors, [NODESIZE as u64 * 3]);
+    }
+
+    #[test]
+    fn match_orderings() {
+        let fs = two_level_tree("match_orderings");
+        let search = |min, max, min_match, max_match| {
+            let options = NodeSearchOption {
+                min_match,
+                max_match,
+                ..NodeSearchOption::between(key(min), key(max))
+            };
+            objectids(&fs, options)
+        };
+        use Ordering::*;
+        assert_eq!(search(15, 45, Less, Equal), [10, 20, 30, 40]);
+        assert_eq!(search(15, 45, Greater, Greater), [20, 30, 40, 50]);
+        assert_eq!(search(20, 40, Less, Greater), [20, 30, 40]);
+        assert_eq!(search(20, 35, Equal, Less), [20, 30]);
+        assert_eq!(search(1, 5, Less, Greater), [10]);
+        assert!(search(61, 70, Equal, Greater).is_empty());
+        assert_eq!(search(61, 70, Less, Greater), [60]);
+    }
+}
@@ -0,0 +1,432 @@
+//! What replaying the log tree would do, worked out without writing
+//! anything, to choose between mounting (which replays it) and zeroing it.
+//!
+//! The log root tree, at the superblock's log_root, holds a ROOT_ITEM per
+//! subvolume fsynced since the last commit, each the root of a log tree of
+//! the items fsync logged. Replay copies the logged inode items over the
+//! subvolume's, adds the names and directory entries logged, removes the
+//! entries of a logged directory's index ranges (its DIR_LOG_INDEX items)
+//! which the log lacks, replaces the logged file ranges and adds references
+//! to the data extents they point at, allocating those the extent tree
+//! doesn't have. Every log tree block stays pinned until replay commits.
+
+use crate::address::*;
+use crate::backref::find_extent;
+use crate::btrfs::*;
+use crate::btrfs_node::ItemData;
+use crate::dump::fmt_treeid;
+use crate::inode::*;
+use crate::items::item_as;
+use crate::stats::tree_stats;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+}
+
+pub struct LoggedInode {
+    pub inode: u64,
+    /// its paths before replay, if it exists
+    pub paths: Vec<String>,
+    /// the inode item in the subvolume, and the one replay puts in its place
+    pub before: Option<btrfs_inode_item>,
+    pub after: Option<btrfs_inode_item>,
+    /// (parent directory, name) of each link added or removed
+    pub names: Vec<(Change, u64, Vec<u8>)>,
+    /// (index, name, location) of each entry added to or removed from a
+    /// directory
+    pub entries: Vec<(Change, u64, Vec<u8>, btrfs_disk_key)>,
+    /// start and end of each file range replaced
+    pub ranges: Vec<(u64, u64)>,
+}
+
+pub struct LoggedExtent {
+    pub logical: u64,
+    pub length: u64,
+    /// the extent tree has it, so replay only adds a reference; otherwise
+    /// replay allocates it
+    pub in_extent_tree: bool,
+}
+
+pub struct SubvolLog {
+    pub subvol: u64,
+    /// root block of its log tree
+    pub log_root: u64,
+    pub blocks: u64,
+    pub inodes: Vec<LoggedInode>,
+    pub extents: Vec<LoggedExtent>,
+    /// bytes of data whose checksums are logged
+    pub csum_bytes: u64,
+}
+
+#[derive(Default)]
+pub struct LogReplay {
+    /// root block of the log root tree; None if there is no log
+    pub log_root: Option<u64>,
+    /// blocks of the log root tree itself
+    pub blocks: u64,
+    pub subvols: Vec<SubvolLog>,
+    /// what would make replay fail or go wrong
+    pub problems: Vec<String>,
+}
+
+impl LogReplay {
+    /// the log tree blocks pinned while replaying
+    pub fn pinned_blocks(&self) -> u64 {
+        self.blocks + self.subvols.iter().map(|s| s.blocks).sum::<u64>()
+    }
+}
+
+/// count the blocks of a log tree, noting those which can't be read. Replay
+/// reads the root block expecting the generation given, and fails if it
+/// has another.
+fn log_blocks(
+    fs: &FsInfo,
+    root: u64,
+    generation: u64,
+    what: &str,
+    problems: &mut Vec<String>,
+) -> u64 {
+    if let Result::Ok(block) = load_virt_block(fs, root) {
+        let header = unsafe { &*(block.as_ptr() as *const btrfs_header) };
+        let found = header.generation;
+        if found != generation {
+            problems.push(format!(
+                "{what}: root block {root} has generation {found}, replay expects {generation}"
+            ));
+        }
+    }
+    let stats = tree_stats(fs, root);
+    problems.extend(stats.problems.iter().map(|p| format!("{what}: {p}")));
+    stats.nodes()
+}
+
+/// the name and location of a DIR_INDEX item, which holds a single entry
+fn dir_index(data: &[u8]) -> Option<(Vec<u8>, btrfs_disk_key)> {
+    let di = item_as::<btrfs_dir_item>(data)?;
+    let start = std::mem::size_of::<btrfs_dir_item>();
+    let name = data.get(start..start + di.name_len as usize)?;
+    Some((name.to_vec(), di.location))
+}
+
+/// whether two directory entries have the same name and location
+fn same_entry(a: &(Vec<u8>, btrfs_disk_key), b: &(Vec<u8>, btrfs_disk_key)) -> bool {
+    a.0 == b.0 && cmp_key(&a.1, &b.1).is_eq()
+}
+
+/// the changes replay makes to one inode, from its items in the log
+fn replay_inode(
+    fs: &FsInfo,
+    subvol_root: u64,
+    inode: u64,
+    items: &[(btrfs_disk_key, ItemData)],
+    data_extents: &mut BTreeMap<u64, u64>,
+    problems: &mut Vec<String>,
+    what: &str,
+) -> LoggedInode {
+    let before = inode_item(fs, subvol_root, inode);
+    let mut logged = LoggedInode {
+        inode,
+        paths: match before {
+            Some(_) => inode_paths(fs, subvol_root, inode),
+            None => Vec::new(),
+        },
+        before,
+        after: None,
+        names: Vec::new(),
+        entries: Vec::new(),
+        ranges: Vec::new(),
+    };
+    let mut refs: BTreeMap<u64, Vec<Vec<u8>>> = BTreeMap::new();
+    let mut entries = BTreeMap::new();
+    let mut index_ranges = Vec::new();
+    for (key, data) in items {
+        let offset = key.offset;
+        match key.item_type {
+            BtrfsItemType::INODE_ITEM => {
+                logged.after = item_as::<btrfs_inode_item>(data).copied();
+                if logged.after.is_none() {
+                    problems.push(format!("{what}: inode {inode}: inode item too short"));
+                }
+            }
+            BtrfsItemType::INODE_REF => {
+                for (_index, name) in parse_inode_refs(data) {
+                    refs.entry(offset).or_default().push(name.to_vec());
+                }
+            }
+            BtrfsItemType::INODE_EXTREF => {
+                for (parent, _index, name) in parse_inode_extrefs(data) {
+                    refs.entry(parent).or_default().push(name.to_vec());
+                }
+            }
+            BtrfsItemType::DIR_INDEX => match dir_index(data) {
+                Some(entry) => {
+                    entries.insert(offset, entry);
+                }
+                None => problems.push(format!(
+                    "{what}: inode {inode}: dir index {offset} can't be parsed"
+                )),
+            },
+            BtrfsItemType::DIR_LOG_INDEX => match item_as::<btrfs_dir_log_item>(data) {
+                Some(dir_log) => index_ranges.push((offset, dir_log.end)),
+                None => problems.push(format!(
+                    "{what}: inode {inode}: dir log index {offset} too short"
+                )),
+            },
+            BtrfsItemType::EXTENT_DATA => {
+                if data.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+                    problems.push(format!(
+                        "{what}: inode {inode}: file extent at {offset} too short"
+                    ));
+                    continue;
+                }
+                let fe = unsafe { &*(data.as_ptr() as *const btrfs_file_extent_item) };
+                let len = if fe.r#type == BTRFS_FILE_EXTENT_INLINE {
+                    fe.ram_bytes
+                } else {
+                    let Some(fe) = item_as::<btrfs_file_extent_item>(data) else {
+                        problems.push(format!(
+                            "{what}: inode {inode}: file extent at {offset} too short"
+                        ));
+                        continue;
+                    };
+                    let (disk_bytenr, disk_num_bytes) = (fe.disk_bytenr, fe.disk_num_bytes);
+                    if disk_bytenr != 0 {
+                        let length = *data_extents.entry(disk_bytenr).or_insert(disk_num_bytes);
+                        if length != disk_num_bytes {
+                            problems.push(format!(
+                                "{what}: inode {inode}: extent {disk_bytenr} logged with lengths {length} and {disk_num_bytes}"
+                            ));
+                        }
+                    }
+                    fe.num_bytes
+                };
+                match logged.ranges.last_mut() {
+                    Some((_, end)) if *end == offset => *end += len,
+                    _ => logged.ranges.push((offset, offset + len)),
+                }
+            }
+            _ => {}
+        }
+    }
+    if logged.before.is_none() && logged.after.is_none() {
+        problems.push(format!(
+            "{what}: inode {inode} has items logged, but no inode item in the log or the subvolume"
+        ));
+    }
+
+    let linked = inode_parents(fs, subvol_root, inode);
+    for (parent, names) in &refs {
+        for name in names {
+            if !linked.contains(&(*parent, name.clone())) {
+                logged.names.push((Change::Added, *parent, name.clone()));
+            }
+        }
+    }
+    // the names under a parent the log has refs for are replaced by those
+    for (parent, name) in linked {
+        if refs
+            .get(&parent)
+            .is_some_and(|names| !names.contains(&name))
+        {
+            logged.names.push((Change::Removed, parent, name));
+        }
+    }
+
+    if entries.is_empty() && index_ranges.is_empty() {
+        return logged;
+    }
+    let mut existing = BTreeMap::new();
+    let search = key_range(Some(inode), Some(BtrfsItemType::DIR_INDEX), None);
+    for (item, data, _block_offset, _slot) in search_range(fs, subvol_root, search) {
+        if item.key.item_type != BtrfsItemType::DIR_INDEX {
+            continue;
+        }
+        if let Some(entry) = dir_index(&data) {
+            existing.insert(item.key.offset, entry);
+        }
+    }
+    for (index, entry) in &existing {
+        let in_range = index_ranges
+            .iter()
+            .any(|(start, end)| start <= index && index <= end);
+        let kept = match entries.get(index) {
+            Some(logged) => same_entry(logged, entry),
+            None => !in_range,
+        };
+        if !kept {
+            let (name, location) = entry.clone();
+            logged
+                .entries
+                .push((Change::Removed, *index, name, location));
+        }
+    }
+    for (index, entry) in entries {
+        if !existing
+            .get(&index)
+            .is_some_and(|existing| same_entry(existing, &entry))
+        {
+            let (name, location) = entry;
+            logged.entries.push((Change::Added, index, name, location));
+        }
+    }
+    logged
+}
+
+/// what replaying the log of one subvolume would do
+fn replay_subvol(
+    fs: &FsInfo,
+    subvol: u64,
+    subvol_root: u64,
+    root_item: &btrfs_root_item,
+    problems: &mut Vec<String>,
+) -> Result<SubvolLog> {
+    let what = format!("log tree of {}", fmt_treeid(subvol));
+    let log_root = root_item.bytenr;
+    let blocks = log_blocks(fs, log_root, root_item.generation, &what, problems);
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let csum_size = csum_size(fs.master_sb.csum_type);
+    let mut csum_bytes = 0;
+    let mut items: BTreeMap<u64, Vec<(btrfs_disk_key, ItemData)>> = BTreeMap::new();
+    for (item, data, _block_offset, _slot) in
+        search_range(fs, log_root, key_range(None, None, None))
+    {
+        let key = item.key;
+        if key.objectid == BTRFS_EXTENT_CSUM_OBJECTID {
+            if key.item_type == BtrfsItemType::EXTENT_CSUM {
+                csum_bytes += (data.len() / csum_size) as u64 * sectorsize;
+            }
+            continue;
+        }
+        items.entry(key.objectid).or_default().push((key, data));
+    }
+
+    let mut data_extents = BTreeMap::new();
+    let mut inodes = Vec::new();
+    for (inode, items) in &items {
+        ensure!(!fs.cancel.is_cancelled(), "interrupted");
+        let logged = replay_inode(
+            fs,
+            subvol_root,
+            *inode,
+            items,
+            &mut data_extents,
+            problems,
+            &what,
+        );
+        inodes.push(logged);
+    }
+    // entries added must lead to an inode, logged or already there
+    for logged in &inodes {
+        for (change, _index, name, location) in &logged.entries {
+            if *change == Change::Removed || location.item_type != BtrfsItemType::INODE_ITEM {
+                continue;
+            }
+            let target = location.objectid;
+            let logged_item = items.get(&target).is_some_and(|items| {
+                items
+                    .iter()
+                    .any(|(key, _)| key.item_type == BtrfsItemType::INODE_ITEM)
+            });
+            if !logged_item && inode_item(fs, subvol_root, target).is_none() {
+                problems.push(format!(
+                    "{what}: entry {} of directory {} leads to inode {target}, which has no inode item",
+                    String::from_utf8_lossy(name),
+                    logged.inode
+                ));
+            }
+        }
+    }
+
+    let mut extents = Vec::new();
+    for (logical, length) in data_extents {
+        let mut covering = |at: u64| match find_extent(fs, at) {
+            Result::Ok(extent) => extent.map(|e| (e.start, e.length)),
+            Err(e) => {
+                problems.push(format!("{what}: extent {logical}: {e}"));
+                None
+            }
+        };
+        let first = covering(logical);
+        let last = covering(logical + length - 1);
+        let in_extent_tree = first == Some((logical, length));
+        if !in_extent_tree {
+            if let Some((start, len)) = first.or(last) {
+                problems.push(format!(
+                    "{what}: extent {logical} length {length} overlaps extent {start} length {len} of the extent tree"
+                ));
+            } else if find_chunk(fs, logical).is_none() {
+                problems.push(format!(
+                    "{what}: extent {logical} length {length} is in no chunk"
+                ));
+            }
+        }
+        extents.push(LoggedExtent {
+            logical,
+            length,
+            in_extent_tree,
+        });
+    }
+    Ok(SubvolLog {
+        subvol,
+        log_root,
+        blocks,
+        inodes,
+        extents,
+        csum_bytes,
+    })
+}
+
+/// work out what replaying the log tree would change, and what about it
+/// would make replay fail or do damage
+pub fn simulate_replay(fs: &FsInfo) -> Result<LogReplay> {
+    let sb = &fs.master_sb;
+    let mut replay = LogReplay::default();
+    if sb.log_root == 0 {
+        return Ok(replay);
+    }
+    let log_root = sb.log_root;
+    replay.log_root = Some(log_root);
+    // the log is written in the transaction after the last one committed
+    replay.blocks = log_blocks(
+        fs,
+        log_root,
+        sb.generation + 1,
+        "log root tree",
+        &mut replay.problems,
+    );
+    let search = key_range(
+        Some(BTRFS_TREE_LOG_OBJECTID),
+        Some(BtrfsItemType::ROOT_ITEM),
+        None,
+    );
+    for (item, data, _block_offset, _slot) in search_range(fs, log_root, search) {
+        if item.key.item_type != BtrfsItemType::ROOT_ITEM {
+            continue;
+        }
+        let subvol = item.key.offset;
+        let Some(root_item) = item_as::<btrfs_root_item>(&data) else {
+            replay.problems.push(format!(
+                "log root tree: root item of the log of {} too short",
+                fmt_treeid(subvol)
+            ));
+            continue;
+        };
+        let Some(subvol_root) = tree_root_offset(fs, subvol) else {
+            replay.problems.push(format!(
+                "log root tree: a log tree for {}, which doesn't exist",
+                fmt_treeid(subvol)
+            ));
+            continue;
+        };
+        let log = replay_subvol(fs, subvol, subvol_root, root_item, &mut replay.problems)?;
+        replay.subvols.push(log);
+    }
+    Ok(replay)
+}
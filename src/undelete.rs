@@ -0,0 +1,480 @@
+//! Discovery of recently deleted files which may still be recovered.
+//!
+//! Deleting a file removes its items from the fs tree, its extents from the
+//! extent tree and its checksums from the csum tree, but nothing is
+//! overwritten until the space is allocated again. Three things are looked
+//! at: ORPHAN_ITEMs, marking files unlinked but not yet cleaned up, whose
+//! items are all still in the tree; fs tree leaves which no current root
+//! reaches (as `orphans` finds them), holding the items of files as they
+//! were a few generations ago; and the data extents those items point to,
+//! which are checked against any checksums that survived, in the csum tree
+//! or in unreachable csum tree leaves.
+
+use crate::address::*;
+use crate::btrfs::*;
+use crate::btrfs_node::*;
+use crate::census::find_orphans;
+use crate::inode::*;
+use crate::items::item_as;
+use crate::scrub::data_csum;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeletedSource {
+    /// an ORPHAN_ITEM in the tree, with the inode's items still there
+    OrphanItem,
+    /// items in unreachable leaves, the newest of this generation
+    OldLeaves(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtentState {
+    /// the data is in the leaf
+    Inline,
+    /// the extent is still allocated, to this file or (exactly) to a
+    /// snapshot or reflink sharing it
+    Allocated,
+    /// free, and every sector with a surviving checksum matches it
+    Verified,
+    /// free, but no checksum survived to tell whether it was overwritten
+    Unverified,
+    /// free, but sectors don't match their checksum or can't be read
+    Damaged,
+    /// part of the range has been allocated again
+    Reused,
+}
+
+impl ExtentState {
+    /// how likely the data is to be what the file held
+    pub fn confidence(self) -> f64 {
+        match self {
+            ExtentState::Inline | ExtentState::Allocated | ExtentState::Verified => 1.0,
+            ExtentState::Unverified => 0.5,
+            ExtentState::Damaged | ExtentState::Reused => 0.0,
+        }
+    }
+}
+
+pub struct DeletedExtent {
+    pub file_offset: u64,
+    /// the extent on disc, 0 for inline data
+    pub disk_bytenr: u64,
+    pub disk_num_bytes: u64,
+    /// the bytes of the file it holds
+    pub num_bytes: u64,
+    pub state: ExtentState,
+}
+
+pub struct DeletedFile {
+    pub tree: u64,
+    pub inode: u64,
+    pub source: DeletedSource,
+    /// the paths the file was linked at, as far as its parent directories
+    /// still exist
+    pub paths: Vec<String>,
+    /// the size and transid from the inode item, if one was found
+    pub size: Option<u64>,
+    pub transid: Option<u64>,
+    pub extents: Vec<DeletedExtent>,
+    /// the share of the file's bytes likely to be recovered, weighting each
+    /// extent by its state's confidence; bytes no extent was found for count
+    /// as lost
+    pub confidence: f64,
+}
+
+#[derive(Default)]
+pub struct UndeleteScan {
+    /// most recoverable first
+    pub files: Vec<DeletedFile>,
+    pub orphan_items: usize,
+    /// unreachable fs tree leaves read
+    pub old_leaves: usize,
+    pub errors: Vec<String>,
+}
+
+/// the items of one inode gathered from a tree or old leaves. Items are
+/// kept from the newest leaf holding each key.
+#[derive(Default)]
+struct InodeItems {
+    inode: Option<btrfs_inode_item>,
+    /// (parent, name)
+    refs: Vec<(u64, Vec<u8>)>,
+    /// file offset to item data
+    file_extents: BTreeMap<u64, Vec<u8>>,
+}
+
+impl InodeItems {
+    fn add(&mut self, key: &btrfs_disk_key, data: &[u8]) {
+        match key.item_type {
+            BtrfsItemType::INODE_ITEM if self.inode.is_none() => {
+                self.inode = item_as::<btrfs_inode_item>(data).copied();
+            }
+            BtrfsItemType::INODE_REF => {
+                for (_index, name) in parse_inode_refs(data) {
+                    let link = (key.offset, name.to_vec());
+                    if !self.refs.contains(&link) {
+                        self.refs.push(link);
+                    }
+                }
+            }
+            BtrfsItemType::INODE_EXTREF => {
+                for (parent, _index, name) in parse_inode_extrefs(data) {
+                    let link = (parent, name.to_vec());
+                    if !self.refs.contains(&link) {
+                        self.refs.push(link);
+                    }
+                }
+            }
+            BtrfsItemType::EXTENT_DATA => {
+                self.file_extents
+                    .entry(key.offset)
+                    .or_insert_with(|| data.to_vec());
+            }
+            _ => {}
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.inode
+            .is_some_and(|inode| inode.mode & S_IFMT == S_IFDIR)
+    }
+}
+
+/// what is known of the data extents and checksums now
+struct DataState {
+    /// start and end of every allocated data extent, sorted
+    allocated: Vec<(u64, u64)>,
+    /// checksums of sectors from unreachable csum tree leaves
+    old_csums: HashMap<u64, Vec<u8>>,
+}
+
+impl DataState {
+    fn state(
+        &self,
+        fs: &FsInfo,
+        disk_bytenr: u64,
+        disk_num_bytes: u64,
+        source: DeletedSource,
+    ) -> ExtentState {
+        let end = disk_bytenr + disk_num_bytes;
+        let i = self.allocated.partition_point(|(_, e)| *e <= disk_bytenr);
+        if let Some(&(start, e)) = self.allocated.get(i).filter(|(s, _)| *s < end) {
+            return if (start, e) == (disk_bytenr, end) || source == DeletedSource::OrphanItem {
+                ExtentState::Allocated
+            } else {
+                ExtentState::Reused
+            };
+        }
+        let sectorsize = fs.master_sb.sectorsize as u64;
+        let csum_type = fs.master_sb.csum_type;
+        let mut verified = 0;
+        for logical in (disk_bytenr..end).step_by(sectorsize as usize) {
+            let expected = match data_csum(fs, logical) {
+                Result::Ok(Some(csum)) => Some(csum),
+                _ => self.old_csums.get(&logical).cloned(),
+            };
+            let Some(expected) = expected else {
+                continue;
+            };
+            let sector = block_copies(fs, logical, sectorsize)
+                .ok()
+                .and_then(|copies| copies.into_iter().find_map(|copy| copy.data));
+            match sector {
+                Some(sector) if csum_data(&sector, csum_type)[..expected.len()] == expected[..] => {
+                    verified += 1
+                }
+                _ => return ExtentState::Damaged,
+            }
+        }
+        if verified > 0 {
+            ExtentState::Verified
+        } else {
+            ExtentState::Unverified
+        }
+    }
+}
+
+/// start and end of every data extent the extent tree records, sorted
+pub(crate) fn allocated_data_extents(fs: &FsInfo) -> Result<Vec<(u64, u64)>> {
+    let extent_roots = global_roots(fs, BTRFS_EXTENT_TREE_OBJECTID);
+    ensure!(!extent_roots.is_empty(), "extent tree not found");
+    let mut allocated = Vec::new();
+    let search = key_range(None, Some(BtrfsItemType::EXTENT_ITEM), None);
+    let items = extent_roots
+        .into_iter()
+        .flat_map(|(_, extent_root)| search_range(fs, extent_root, search));
+    for (item, data, _block_offset, _slot) in items {
+        let key = item.key;
+        if key.item_type != BtrfsItemType::EXTENT_ITEM {
+            continue;
+        }
+        if item_as::<btrfs_extent_item>(&data)
+            .is_some_and(|e| e.flags & BTRFS_EXTENT_FLAG_DATA != 0)
+        {
+            allocated.push((key.objectid, key.objectid + key.offset));
+        }
+    }
+    allocated.sort();
+    Ok(allocated)
+}
+
+/// (logical, checksum) of each sector the EXTENT_CSUM items of a csum tree
+/// leaf cover
+pub(crate) fn leaf_csums<'a>(fs: &FsInfo, block: &'a [u8]) -> Vec<(u64, &'a [u8])> {
+    let sectorsize = fs.master_sb.sectorsize as u64;
+    let csum_size = csum_size(fs.master_sb.csum_type);
+    let mut csums = Vec::new();
+    for entry in node_entries(block) {
+        let NodeEntry::Item(item, Some(data)) = entry else {
+            continue;
+        };
+        if item.key.item_type != BtrfsItemType::EXTENT_CSUM {
+            continue;
+        }
+        let start = item.key.offset;
+        for (i, csum) in data.chunks_exact(csum_size).enumerate() {
+            csums.push((start + i as u64 * sectorsize, csum));
+        }
+    }
+    csums
+}
+
+fn data_state(fs: &FsInfo, old_csum_leaves: &[Arc<[u8]>]) -> Result<DataState> {
+    let mut old_csums = HashMap::new();
+    // oldest first, so that the newest checksum of a sector is kept
+    for block in old_csum_leaves.iter().rev() {
+        for (logical, csum) in leaf_csums(fs, block) {
+            old_csums.insert(logical, csum.to_vec());
+        }
+    }
+    Ok(DataState {
+        allocated: allocated_data_extents(fs)?,
+        old_csums,
+    })
+}
+
+/// the share of a file's bytes likely to be recovered from its extents,
+/// given its size if the inode item was found. A file with no data is
+/// recovered whole if its inode item is.
+fn confidence(extents: &[DeletedExtent], size: Option<u64>) -> f64 {
+    let found: u64 = extents.iter().map(|e| e.num_bytes).sum();
+    let likely: f64 = extents
+        .iter()
+        .map(|e| e.num_bytes as f64 * e.state.confidence())
+        .sum();
+    let total = found.max(size.unwrap_or(0));
+    match (total, size) {
+        (0, Some(_)) => 1.0,
+        (0, None) => 0.0,
+        _ => likely / total as f64,
+    }
+}
+
+fn deleted_file(
+    fs: &FsInfo,
+    data: &DataState,
+    tree: u64,
+    tree_root: Option<u64>,
+    inode: u64,
+    items: &InodeItems,
+    source: DeletedSource,
+) -> DeletedFile {
+    let mut extents = Vec::new();
+    for (&file_offset, item) in &items.file_extents {
+        if item.len() < BTRFS_FILE_EXTENT_INLINE_DATA_START {
+            continue;
+        }
+        let fe = unsafe { &*(item.as_ptr() as *const btrfs_file_extent_item) };
+        if fe.r#type == BTRFS_FILE_EXTENT_INLINE {
+            extents.push(DeletedExtent {
+                file_offset,
+                disk_bytenr: 0,
+                disk_num_bytes: 0,
+                num_bytes: fe.ram_bytes,
+                state: ExtentState::Inline,
+            });
+            continue;
+        }
+        let Some(fe) = item_as::<btrfs_file_extent_item>(item) else {
+            continue;
+        };
+        // holes and preallocated space hold nothing to recover
+        if fe.disk_bytenr == 0 || fe.r#type == BTRFS_FILE_EXTENT_PREALLOC {
+            continue;
+        }
+        extents.push(DeletedExtent {
+            file_offset,
+            disk_bytenr: fe.disk_bytenr,
+            disk_num_bytes: fe.disk_num_bytes,
+            num_bytes: fe.num_bytes,
+            state: data.state(fs, fe.disk_bytenr, fe.disk_num_bytes, source),
+        });
+    }
+    let size = items.inode.map(|inode| inode.size);
+    let confidence = confidence(&extents, size);
+    let mut paths = Vec::new();
+    for (parent, name) in &items.refs {
+        let name = String::from_utf8_lossy(name);
+        let parents = match tree_root {
+            Some(root) => inode_paths(fs, root, *parent),
+            None => Vec::new(),
+        };
+        if parents.is_empty() {
+            paths.push(format!("?/<inode {parent}>/{name}"));
+        }
+        for parent_path in parents {
+            paths.push(format!("{parent_path}/{name}"));
+        }
+    }
+    DeletedFile {
+        tree,
+        inode,
+        source,
+        paths,
+        size,
+        transid: items.inode.map(|inode| inode.transid),
+        extents,
+        confidence,
+    }
+}
+
+/// whether an inode has an inode item in the tree
+fn inode_exists(fs: &FsInfo, tree_root: u64, inode: u64) -> bool {
+    let search = key_range(Some(inode), Some(BtrfsItemType::INODE_ITEM), Some(0));
+    search_range(fs, tree_root, search).next().is_some()
+}
+
+fn is_fs_tree(tree: u64) -> bool {
+    tree == BTRFS_FS_TREE_OBJECTID
+        || (BTRFS_FIRST_FREE_OBJECTID..=BTRFS_LAST_FREE_OBJECTID).contains(&tree)
+}
+
+/// list the files which ORPHAN_ITEMs and unreachable fs tree leaves show
+/// were deleted, with how much of each is likely to be recovered
+pub fn find_deleted(fs: &FsInfo) -> Result<UndeleteScan> {
+    let mut scan = UndeleteScan::default();
+    let trees: HashMap<u64, u64> = fs_trees(fs).into_iter().collect();
+
+    let orphans = find_orphans(fs);
+    scan.errors = orphans.errors;
+    let mut old_leaves: Vec<_> = orphans.orphans.iter().filter(|o| o.level == 0).collect();
+    old_leaves.sort_by_key(|o| std::cmp::Reverse(o.generation));
+    let mut fs_leaves = Vec::new();
+    let mut csum_leaves = Vec::new();
+    for orphan in old_leaves {
+        let block = match load_virt_block(fs, orphan.logical) {
+            Result::Ok(block) => block,
+            Err(e) => {
+                scan.errors.push(format!("block {}: {e}", orphan.logical));
+                continue;
+            }
+        };
+        if orphan.owner == BTRFS_CSUM_TREE_OBJECTID {
+            csum_leaves.push(block);
+        } else if is_fs_tree(orphan.owner) {
+            fs_leaves.push((orphan.owner, orphan.generation, block));
+        }
+    }
+    let data = data_state(fs, &csum_leaves)?;
+
+    for (&tree, &root) in &trees {
+        let search = key_range(
+            Some(BTRFS_ORPHAN_OBJECTID),
+            Some(BtrfsItemType::ORPHAN_ITEM),
+            None,
+        );
+        let inodes: Vec<u64> = search_range(fs, root, search)
+            .map(|(item, _, _, _)| item.key.offset)
+            .collect();
+        for inode in inodes {
+            scan.orphan_items += 1;
+            let mut items = InodeItems::default();
+            for (item, item_data, _, _) in
+                search_range(fs, root, key_range(Some(inode), None, None))
+            {
+                items.add(&item.key, &item_data);
+            }
+            if !items.is_dir() {
+                let source = DeletedSource::OrphanItem;
+                let file = deleted_file(fs, &data, tree, Some(root), inode, &items, source);
+                scan.files.push(file);
+            }
+        }
+    }
+
+    // (tree, inode) to the inode's items and the newest generation seen
+    let mut candidates: BTreeMap<(u64, u64), (u64, InodeItems)> = BTreeMap::new();
+    let mut exists = HashMap::new();
+    for (tree, generation, block) in &fs_leaves {
+        scan.old_leaves += 1;
+        for entry in node_entries(block) {
+            let NodeEntry::Item(item, Some(item_data)) = entry else {
+                continue;
+            };
+            let key = item.key;
+            if key.objectid < BTRFS_FIRST_FREE_OBJECTID || key.objectid > BTRFS_LAST_FREE_OBJECTID {
+                continue;
+            }
+            let deleted = *exists.entry((*tree, key.objectid)).or_insert_with(|| {
+                !trees
+                    .get(tree)
+                    .is_some_and(|root| inode_exists(fs, *root, key.objectid))
+            });
+            if deleted {
+                let (_, items) = candidates
+                    .entry((*tree, key.objectid))
+                    .or_insert_with(|| (*generation, InodeItems::default()));
+                items.add(&key, item_data);
+            }
+        }
+    }
+    for ((tree, inode), (generation, items)) in &candidates {
+        if items.is_dir() || (items.inode.is_none() && items.file_extents.is_empty()) {
+            continue;
+        }
+        let source = DeletedSource::OldLeaves(*generation);
+        let root = trees.get(tree).copied();
+        scan.files
+            .push(deleted_file(fs, &data, *tree, root, *inode, items, source));
+    }
+    scan.files.sort_by(|a, b| {
+        b.confidence
+            .total_cmp(&a.confidence)
+            .then((a.tree, a.inode).cmp(&(b.tree, b.inode)))
+    });
+    Ok(scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extent(num_bytes: u64, state: ExtentState) -> DeletedExtent {
+        DeletedExtent {
+            file_offset: 0,
+            disk_bytenr: 0,
+            disk_num_bytes: num_bytes,
+            num_bytes,
+            state,
+        }
+    }
+
+    #[test]
+    fn confidence_by_bytes() {
+        let extents = [
+            extent(4096, ExtentState::Verified),
+            extent(4096, ExtentState::Unverified),
+            extent(8192, ExtentState::Reused),
+        ];
+        assert_eq!(confidence(&extents, Some(16384)), 0.375);
+        // bytes no extent was found for count as lost
+        assert_eq!(confidence(&extents[..1], Some(16384)), 0.25);
+        assert_eq!(confidence(&[], Some(0)), 1.0);
+        assert_eq!(confidence(&[], None), 0.0);
+    }
+}
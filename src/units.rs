@@ -0,0 +1,106 @@
+//! Formatting of byte counts and timestamps, either raw or (with --human)
+//! as binary-prefixed sizes and RFC3339 dates.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static HUMAN: AtomicBool = AtomicBool::new(false);
+
+/// switch all dump output between raw numbers and human readable units
+pub fn set_human(human: bool) {
+    HUMAN.store(human, Ordering::Relaxed);
+}
+
+pub fn human() -> bool {
+    HUMAN.load(Ordering::Relaxed)
+}
+
+/// a byte count, e.g. 1.50GiB in human mode
+pub fn fmt_size(bytes: u64) -> String {
+    if human() {
+        human_size(bytes)
+    } else {
+        bytes.to_string()
+    }
+}
+
+/// a btrfs_timespec, e.g. 2023-04-01T12:00:00.000000000Z in human mode
+pub fn fmt_time(sec: u64, nsec: u32) -> String {
+    if human() {
+        rfc3339(sec, nsec)
+    } else {
+        format!("{sec}.{nsec:09}")
+    }
+}
+
+const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+pub fn human_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.2}{}", UNITS[unit])
+}
+
+/// seconds since the epoch as a UTC date. Times too far in the future to be
+/// real (e.g. garbage in a corrupt inode) are shown raw.
+pub fn rfc3339(sec: u64, nsec: u32) -> String {
+    // beyond 9999-12-31 the year no longer fits in four digits
+    if sec >= 253_402_300_800 {
+        return format!("{sec}.{nsec:09}(invalid date)");
+    }
+    let days = (sec / 86400) as i64;
+    let secs = sec % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{nsec:09}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// proleptic Gregorian (year, month, day) of a count of days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(human_size(0), "0B");
+        assert_eq!(human_size(1023), "1023B");
+        assert_eq!(human_size(16384), "16.00KiB");
+        assert_eq!(human_size(3 << 29), "1.50GiB");
+        assert_eq!(human_size(2 << 40), "2.00TiB");
+        assert_eq!(human_size(u64::MAX), "16.00EiB");
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(rfc3339(0, 0), "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(rfc3339(951_782_400, 5), "2000-02-29T00:00:00.000000005Z");
+        assert_eq!(
+            rfc3339(1_700_000_000, 123_456_789),
+            "2023-11-14T22:13:20.123456789Z"
+        );
+        assert!(rfc3339(u64::MAX, 0).ends_with("(invalid date)"));
+    }
+}
@@ -0,0 +1,164 @@
+//! fs-verity state of files: the descriptor giving the hash algorithm, block
+//! size, salt and Merkle tree root hash (and any signature) a verity file was
+//! sealed with.
+
+use crate::btrfs::*;
+use crate::items::item_as;
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+
+/// what an fs-verity descriptor says, with the salt, root hash and signature
+/// cut to their lengths
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VerityDescriptor {
+    pub version: u8,
+    pub hash_algorithm: u8,
+    pub block_size: u32,
+    pub data_size: u64,
+    pub salt: Vec<u8>,
+    pub root_hash: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+pub fn fmt_hash_algorithm(algorithm: u8) -> String {
+    match algorithm {
+        FS_VERITY_HASH_ALG_SHA256 => String::from("sha256"),
+        FS_VERITY_HASH_ALG_SHA512 => String::from("sha512"),
+        _ => format!("unknown hash algorithm {algorithm}"),
+    }
+}
+
+/// the length of a digest of the hash algorithm, as much of root_hash as is
+/// used
+fn digest_size(algorithm: u8) -> Option<usize> {
+    match algorithm {
+        FS_VERITY_HASH_ALG_SHA256 => Some(32),
+        FS_VERITY_HASH_ALG_SHA512 => Some(64),
+        _ => None,
+    }
+}
+
+/// parse the bytes of an fs-verity descriptor (the struct, then the
+/// signature)
+pub fn parse_descriptor(data: &[u8]) -> Option<VerityDescriptor> {
+    let desc = item_as::<fsverity_descriptor>(data)?;
+    let start = std::mem::size_of::<fsverity_descriptor>();
+    let signature = data.get(start..start + desc.sig_size as usize)?;
+    let log_blocksize = desc.log_blocksize;
+    let salt = desc.salt;
+    let salt_size = desc.salt_size as usize;
+    if log_blocksize >= 32 || salt_size > salt.len() {
+        return None;
+    }
+    let root_hash = desc.root_hash;
+    let digest_size = digest_size(desc.hash_algorithm).unwrap_or(root_hash.len());
+    Some(VerityDescriptor {
+        version: desc.version,
+        hash_algorithm: desc.hash_algorithm,
+        block_size: 1 << log_blocksize,
+        data_size: desc.data_size,
+        salt: salt[..salt_size].to_vec(),
+        root_hash: root_hash[..digest_size].to_vec(),
+        signature: signature.to_vec(),
+    })
+}
+
+impl std::fmt::Display for VerityDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "version {} {} block size {} data size {} root hash {} salt {}",
+            self.version,
+            fmt_hash_algorithm(self.hash_algorithm),
+            self.block_size,
+            self.data_size,
+            hex::encode(&self.root_hash),
+            if self.salt.is_empty() {
+                String::from("none")
+            } else {
+                hex::encode(&self.salt)
+            }
+        )?;
+        if !self.signature.is_empty() {
+            write!(f, ", signed ({} byte signature)", self.signature.len())?;
+        }
+        std::fmt::Result::Ok(())
+    }
+}
+
+/// the fs-verity descriptor of an inode, or None if it has none. The
+/// descriptor may be split over several items.
+pub fn verity_descriptor(
+    fs: &FsInfo,
+    tree_root: u64,
+    inode: u64,
+) -> Result<Option<VerityDescriptor>> {
+    let mut size = None;
+    let mut bytes = Vec::new();
+    let search = key_range(Some(inode), Some(BtrfsItemType::VERITY_DESC_ITEM), None);
+    for (item, data, _block_offset, _slot) in search_range(fs, tree_root, search) {
+        if item.key.offset == 0 {
+            let desc_item = item_as::<btrfs_verity_descriptor_item>(&data)
+                .ok_or_else(|| anyhow!("inode {inode}: verity descriptor item too short"))?;
+            let encryption = desc_item.encryption;
+            ensure!(
+                encryption == 0,
+                "inode {inode}: verity descriptor has unknown encryption {encryption}"
+            );
+            size = Some(desc_item.size as usize);
+            continue;
+        }
+        let at = item.key.offset as usize - 1;
+        ensure!(
+            at == bytes.len(),
+            "inode {inode}: verity descriptor bytes {}..{at} are missing",
+            bytes.len()
+        );
+        bytes.extend_from_slice(&data);
+    }
+    let Some(size) = size else {
+        ensure!(
+            bytes.is_empty(),
+            "inode {inode}: verity descriptor without its size item"
+        );
+        return Ok(None);
+    };
+    ensure!(
+        bytes.len() >= size,
+        "inode {inode}: verity descriptor of {size} bytes, but only {} found",
+        bytes.len()
+    );
+    parse_descriptor(&bytes[..size])
+        .map(Some)
+        .ok_or_else(|| anyhow!("inode {inode}: verity descriptor can't be parsed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptor() {
+        let mut data = vec![0; std::mem::size_of::<fsverity_descriptor>()];
+        data[0] = 1;
+        data[1] = FS_VERITY_HASH_ALG_SHA256;
+        data[2] = 12;
+        data[3] = 4;
+        data[4..8].copy_from_slice(&3u32.to_le_bytes());
+        data[8..16].copy_from_slice(&8400u64.to_le_bytes());
+        data[16..80].fill(0xab);
+        data[80..84].copy_from_slice(b"salt");
+        data.extend_from_slice(b"sig");
+        let desc = parse_descriptor(&data).unwrap();
+        assert_eq!(desc.block_size, 4096);
+        assert_eq!(desc.data_size, 8400);
+        assert_eq!(desc.root_hash, vec![0xab; 32]);
+        assert_eq!(desc.salt, b"salt");
+        assert_eq!(desc.signature, b"sig");
+        // the signature cut off
+        assert_eq!(parse_descriptor(&data[..257]), None);
+    }
+}
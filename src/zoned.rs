@@ -0,0 +1,216 @@
+//! Zoned devices (host managed SMR disks and ZNS SSDs), whose zones are
+//! only written sequentially, at a write pointer, and only reset whole.
+//!
+//! A zoned filesystem can't rewrite its superblocks in place, so each copy
+//! is a log in a pair of zones: the first at offset 0, the others in the
+//! zones at 512GiB and 4TiB. Each commit appends a superblock to one zone
+//! of the pair, and when it fills up the other is written while the full
+//! one is reset. A copy's bytenr is still that of the regular copy at 64KiB,
+//! 64MiB or 256GiB. Chunks are made of whole zones, and keep clear of the
+//! superblock zones.
+
+use crate::btrfs::*;
+use crate::check::CheckProblem;
+use crate::edit::super_mirrors;
+use crate::flags::fmt_block_group_type;
+use crate::items::item_as;
+use crate::mapped_file::MappedFile;
+use crate::space::{stripe_length, PROFILE_MASK};
+use crate::structures::*;
+use crate::tree::*;
+
+use anyhow::*;
+
+/// where the zone pair of each superblock copy is
+pub const BTRFS_SB_LOG_OFFSETS: [u64; BTRFS_SUPER_MIRROR_MAX] = [0, 512 << 30, 4 << 40];
+/// zones in the log of each superblock copy
+pub const BTRFS_NR_SB_LOG_ZONES: u64 = 2;
+
+/// the starts of the zone pairs of the superblock copies which fit on a
+/// device of len bytes
+pub fn zoned_super_mirrors(len: u64, zone_size: u64) -> Vec<u64> {
+    BTRFS_SB_LOG_OFFSETS
+        .iter()
+        .map(|&offset| offset / zone_size * zone_size)
+        .filter(|&start| start + BTRFS_NR_SB_LOG_ZONES * zone_size <= len)
+        .collect()
+}
+
+/// the largest power of two dividing every value, or None if they are all 0
+fn common_alignment(values: impl Iterator<Item = u64>) -> Option<u64> {
+    let all = values.fold(0, |all, value| all | value);
+    (all != 0).then(|| 1 << all.trailing_zeros())
+}
+
+/// the zone size of a zoned filesystem, going by the chunks in its
+/// superblock, whose stripes are each zone aligned and whole zones. This
+/// is the zone size unless by chance they are all aligned to more.
+pub fn infer_zone_size(sb: &btrfs_super_block) -> Option<u64> {
+    common_alignment(
+        SysChunkIter::new(sb).flat_map(|ChunkInfo(_, chunk, stripes)| {
+            let length = stripe_length(&chunk);
+            stripes
+                .into_iter()
+                .map(|stripe| stripe.offset)
+                .chain([length])
+                .collect::<Vec<u64>>()
+        }),
+    )
+}
+
+/// the zone size of the device mapped as mf if it holds a zoned filesystem:
+/// what the device reports or was given, or if it seems to have a zoned
+/// filesystem's superblock at the start of its first zone, what that
+/// superblock suggests. If the first zone has been reset, the zone size has
+/// to be given.
+pub fn device_zone_size(mf: &MappedFile) -> Option<u64> {
+    if let Some(zone_size) = mf.zone_size() {
+        return Some(zone_size);
+    }
+    [0, BTRFS_SUPER_INFO_OFFSET]
+        .into_iter()
+        .filter_map(|offset| load_sb_at(mf, offset).ok())
+        .find(|sb| sb.incompat_flags & BTRFS_FEATURE_INCOMPAT_ZONED != 0)
+        .and_then(|sb| infer_zone_size(&sb))
+}
+
+/// the newest superblock in a log zone with its offset. Superblocks are
+/// appended to a zone from its start and nothing follows them, so the last
+/// is the one before the first slot without btrfs magic.
+fn zone_log_head(mf: &MappedFile, start: u64, zone_size: u64) -> Result<(u64, btrfs_super_block)> {
+    let slots: Vec<u64> = (0..zone_size / BTRFS_SUPER_INFO_SIZE as u64).collect();
+    let written = slots.partition_point(|&slot| {
+        let offset = start + slot * BTRFS_SUPER_INFO_SIZE as u64;
+        // a slot which can't be mapped is taken as unwritten, and
+        // load_sb_at reports the error if the head is next to it
+        mf.at::<btrfs_super_block>(offset as usize)
+            .is_ok_and(|sb| sb.magic == BTRFS_MAGIC)
+    }) as u64;
+    ensure!(written > 0, "the zone at {start} is empty");
+    let physical = start + (written - 1) * BTRFS_SUPER_INFO_SIZE as u64;
+    Ok((physical, load_sb_at(mf, physical as usize)?))
+}
+
+/// the newest superblock of each copy which fits on a zoned device, with
+/// where it was found, as super_copies gives them
+pub fn zoned_super_copies(
+    mf: &MappedFile,
+    zone_size: u64,
+) -> Vec<(u64, Result<btrfs_super_block>)> {
+    let regular = super_mirrors(u64::MAX);
+    zoned_super_mirrors(mf.len() as u64, zone_size)
+        .into_iter()
+        .zip(regular)
+        .map(|(start, bytenr)| {
+            let heads: Vec<_> = (0..BTRFS_NR_SB_LOG_ZONES)
+                .map(|zone| zone_log_head(mf, start + zone * zone_size, zone_size))
+                .collect();
+            let newest = heads
+                .iter()
+                .filter_map(|head| head.as_ref().ok())
+                .max_by_key(|(_, sb)| sb.generation);
+            let Some(&(physical, sb)) = newest else {
+                let error = heads.into_iter().next().unwrap().unwrap_err();
+                return (
+                    start,
+                    Err(error.context("no superblock in either log zone")),
+                );
+            };
+            let sb_bytenr = sb.bytenr;
+            if sb_bytenr != bytenr {
+                let error = anyhow!("bytenr {sb_bytenr} is not that of copy at {bytenr}");
+                return (physical, Err(error));
+            }
+            (physical, Result::Ok(sb))
+        })
+        .collect()
+}
+
+/// the rules the kernel holds a zoned filesystem's chunks to: stripes of
+/// whole zones, which leave the superblock zones alone, and no profiles or
+/// block group types it can't write sequentially. Devices of unknown zone
+/// size only have the latter checked.
+pub fn check_zoned(fs: &FsInfo) -> Vec<CheckProblem> {
+    let mut problems = Vec::new();
+    let search = key_range(
+        Some(BTRFS_FIRST_CHUNK_TREE_OBJECTID),
+        Some(BtrfsItemType::CHUNK_ITEM),
+        None,
+    );
+    for (item, data, block_offset, slot) in search_range(fs, fs.master_sb.chunk_root, search) {
+        let logical = item.key.offset;
+        let mut problem = |message: String| {
+            problems.push(CheckProblem {
+                tree: BTRFS_CHUNK_TREE_OBJECTID,
+                block: block_offset,
+                slot: Some(slot as usize),
+                message: format!("chunk {logical}: {message}"),
+            })
+        };
+        let Some(chunk) = item_as::<btrfs_chunk>(&data) else {
+            continue;
+        };
+        let flags = chunk.r#type;
+        if flags & (BTRFS_BLOCK_GROUP_RAID5 | BTRFS_BLOCK_GROUP_RAID6) != 0 {
+            problem(format!(
+                "{} can't be written sequentially on zoned devices",
+                fmt_block_group_type(flags & PROFILE_MASK)
+            ));
+        }
+        let mixed = BTRFS_BLOCK_GROUP_DATA | BTRFS_BLOCK_GROUP_METADATA;
+        if flags & mixed == mixed {
+            problem(String::from(
+                "mixed block groups aren't allowed on zoned devices",
+            ));
+        }
+        let length = stripe_length(chunk);
+        let stripes = data[std::mem::size_of::<btrfs_chunk>()..]
+            .chunks_exact(std::mem::size_of::<btrfs_stripe>())
+            .take(chunk.num_stripes as usize)
+            .filter_map(item_as::<btrfs_stripe>);
+        for stripe in stripes {
+            let (devid, offset) = (stripe.devid, stripe.offset);
+            let Some(dev) = fs.devid_map.get(&devid) else {
+                continue;
+            };
+            let Some(zone_size) = dev.zone_size else {
+                continue;
+            };
+            if !offset.is_multiple_of(zone_size) || !length.is_multiple_of(zone_size) {
+                problem(format!(
+                    "stripe on devid {devid} at {offset} of {length} bytes isn't made of whole {zone_size} byte zones"
+                ));
+            }
+            for start in zoned_super_mirrors(dev.file.len() as u64, zone_size) {
+                let end = start + BTRFS_NR_SB_LOG_ZONES * zone_size;
+                if offset < end && start < offset + length {
+                    problem(format!(
+                        "stripe on devid {devid} at {offset} overlaps the superblock zones at {start}"
+                    ));
+                }
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sb_zones() {
+        let zone = 256 << 20;
+        assert_eq!(
+            zoned_super_mirrors(8 << 40, zone),
+            vec![0, 512 << 30, 4 << 40]
+        );
+        assert_eq!(zoned_super_mirrors(1 << 40, zone), vec![0, 512 << 30]);
+        assert_eq!(zoned_super_mirrors(zone, zone), Vec::<u64>::new());
+        assert_eq!(
+            common_alignment([2 * zone, zone, 5 * zone].into_iter()),
+            Some(zone)
+        );
+        assert_eq!(common_alignment([0].into_iter()), None);
+    }
+}
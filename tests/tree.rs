@@ -14,8 +14,8 @@ fn default_btrfs_dev_item() -> btrfs_dev_item {
         dev_group: 0,
         seek_speed: 0,
         bandwidth: 9,
-        uuid: [0; BTRFS_UUID_SIZE],
-        fsid: [0; BTRFS_FSID_SIZE],
+        uuid: BtrfsUuid::default(),
+        fsid: BtrfsUuid::default(),
     }
 }
 
@@ -50,7 +50,7 @@ fn default_btrfs_root_backup() -> btrfs_root_backup {
 fn default_btrfs_superblock() -> btrfs_super_block {
     btrfs_super_block {
         csum: [0; BTRFS_CSUM_SIZE],
-        fsid: [0; BTRFS_FSID_SIZE],
+        fsid: BtrfsUuid::default(),
         bytenr: 0,
         flags: 0,
         magic: 0,
@@ -80,7 +80,7 @@ fn default_btrfs_superblock() -> btrfs_super_block {
         label: [0; BTRFS_LABEL_SIZE],
         cache_generation: 0,
         uuid_tree_generation: 0,
-        metadata_uuid: [0; BTRFS_FSID_SIZE],
+        metadata_uuid: BtrfsUuid::default(),
         nr_global_roots: 0,
         reserved: [0; 27],
         sys_chunk_array: [0; BTRFS_SYSTEM_CHUNK_ARRAY_SIZE],
@@ -90,7 +90,20 @@ fn default_btrfs_superblock() -> btrfs_super_block {
 }
 
 #[test]
+#[allow(unused_variables, unused_assignments)]
 fn zero_depth_tree() {
     let mut sb = default_btrfs_superblock();
     sb.num_devices = 1;
 }
+
+#[test]
+fn superblock_debug() {
+    let mut sb = default_btrfs_superblock();
+    sb.generation = 17;
+    sb.label[..4].copy_from_slice(b"test");
+    let s = format!("{sb:?}");
+    assert!(s.contains("generation: 17"));
+    assert!(s.contains("label: \"test\""));
+    assert!(s.contains("sector_size: 512"));
+    assert!(!s.contains("sys_chunk_array:"));
+}